@@ -0,0 +1,498 @@
+//! Typed async client for the meda REST API (`meda serve`).
+//!
+//! One method per endpoint of the API's OpenAPI document, taking and
+//! returning the bodies in [`models`]:
+//!
+//! ```no_run
+//! use meda_client::{models::ImageRunRequest, Client};
+//!
+//! # async fn example() -> Result<(), meda_client::Error> {
+//! let client = Client::new("https://meda.example.com:7777")?.with_token("s3cret");
+//! let run = client
+//!     .run_image(&ImageRunRequest {
+//!         image: "ubuntu:latest".to_string(),
+//!         name: Some("ci-1".to_string()),
+//!         ..Default::default()
+//!     })
+//!     .await?;
+//! println!("{}", run.message);
+//! for vm in client.list_vms(&Default::default()).await?.vms {
+//!     println!("{} {}", vm.name, vm.state);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A failed request is an [`Error::Api`] carrying the server's
+//! [`models::ApiError`] and HTTP status, plus `Retry-After` when the
+//! server is out of capacity. The two console endpoints are WebSockets;
+//! the client gives their URLs ([`Client::vm_console_url`]) for use with
+//! any WebSocket library.
+//!
+//! Creates, pulls and pushes can take minutes. Their `*_async` variants
+//! return a [`models::Job`] at once instead; poll it with
+//! [`Client::get_job`] until [`models::Job::is_finished`], then read the
+//! request's response from its `result`.
+//!
+//! The meda crate's tests check [`ENDPOINTS`] and the models against
+//! the server's OpenAPI document, so the client can't fall behind it.
+
+pub mod models;
+
+use models::*;
+use reqwest::{Method, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+
+/// Every endpoint the client covers, as `(method, path)` in the OpenAPI
+/// document's form.
+pub const ENDPOINTS: &[(&str, &str)] = &[
+    ("GET", "/api/v1/vms"),
+    ("POST", "/api/v1/vms"),
+    ("GET", "/api/v1/vms/{name}"),
+    ("DELETE", "/api/v1/vms/{name}"),
+    ("POST", "/api/v1/vms/{name}/start"),
+    ("POST", "/api/v1/vms/{name}/stop"),
+    ("POST", "/api/v1/vms/{name}/restart"),
+    ("GET", "/api/v1/vms/{name}/ip"),
+    ("POST", "/api/v1/vms/{name}/port-forward"),
+    ("GET", "/api/v1/vms/{name}/console"),
+    ("GET", "/api/v1/vms/{name}/console/ws"),
+    ("GET", "/api/v1/vms/{name}/logs"),
+    ("GET", "/api/v1/vms/{name}/serial"),
+    ("GET", "/api/v1/images"),
+    ("POST", "/api/v1/images"),
+    ("GET", "/api/v1/images/{image}"),
+    ("DELETE", "/api/v1/images/{image}"),
+    ("POST", "/api/v1/images/pull"),
+    ("POST", "/api/v1/images/push"),
+    ("POST", "/api/v1/images/tag"),
+    ("POST", "/api/v1/images/prune"),
+    ("POST", "/api/v1/images/run"),
+    ("GET", "/api/v1/capacity"),
+    ("GET", "/api/v1/system"),
+    ("GET", "/api/v1/jobs/{id}"),
+    ("GET", "/api/v1/health"),
+    ("GET", "/metrics"),
+    ("GET", "/api/v1/metrics"),
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid API URL '{0}': {1}")]
+    InvalidUrl(String, String),
+
+    #[error("{0}")]
+    Http(#[from] reqwest::Error),
+
+    /// The server answered with an error status.
+    #[error("{error}")]
+    Api {
+        status: StatusCode,
+        error: ApiError,
+        /// Seconds the server asked to wait before retrying (503 when
+        /// it's out of capacity).
+        retry_after: Option<u64>,
+    },
+
+    #[error("unexpected response from {0}: {1}")]
+    Decode(String, String),
+}
+
+impl fmt::Display for ApiError {
+    /// The error, with the server's `details.message` when it gave one.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self
+            .details
+            .as_ref()
+            .and_then(|d| d.get("message"))
+            .and_then(|m| m.as_str())
+        {
+            Some(message) if message != self.error => write!(f, "{}: {}", self.error, message),
+            _ => f.write_str(&self.error),
+        }
+    }
+}
+
+/// Query of the endpoints that take none.
+const NO_QUERY: &[(&str, &str)] = &[];
+
+/// Query running a request in the background.
+const ASYNC: &[(&str, &str)] = &[("async", "true")];
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A meda API server.
+#[derive(Debug, Clone)]
+pub struct Client {
+    base: Url,
+    token: Option<String>,
+    http: reqwest::Client,
+}
+
+impl Client {
+    /// Client for the API at `url`, e.g. `http://127.0.0.1:7777`; a path
+    /// is kept, for servers behind a reverse proxy.
+    pub fn new(url: &str) -> Result<Self> {
+        let invalid = |why: &str| Error::InvalidUrl(url.to_string(), why.to_string());
+        let mut base = Url::parse(url).map_err(|e| invalid(&e.to_string()))?;
+        if !matches!(base.scheme(), "http" | "https") || base.host_str().is_none() {
+            return Err(invalid(
+                "expected http://host[:port] or https://host[:port]",
+            ));
+        }
+        base.set_query(None);
+        base.set_fragment(None);
+        Ok(Self {
+            base,
+            token: None,
+            http: reqwest::Client::new(),
+        })
+    }
+
+    /// Send `token` as `Authorization: Bearer`, for servers started with
+    /// `MEDA_API_TOKEN`.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Send requests with `http`, e.g. one with a proxy or timeouts.
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// The server's URL for `segments`, each one escaped (image
+    /// references contain `/` and `:`).
+    pub fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base.clone();
+        url.path_segments_mut()
+            .expect("http(s) URLs have a path")
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    fn api(&self, segments: &[&str]) -> Url {
+        let mut all = vec!["api", "v1"];
+        all.extend_from_slice(segments);
+        self.url(&all)
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        query: &(impl Serialize + ?Sized),
+        body: Option<&(impl Serialize + ?Sized)>,
+    ) -> Result<reqwest::Response> {
+        let mut request = self.http.request(method, url).query(query);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = request.send().await?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        Err(error_from(response).await)
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        segments: &[&str],
+        query: &(impl Serialize + ?Sized),
+        body: Option<&(impl Serialize + ?Sized)>,
+    ) -> Result<T> {
+        let url = self.api(segments);
+        let response = self.send(method, url.clone(), query, body).await?;
+        let text = response.text().await?;
+        serde_json::from_str(&text).map_err(|e| Error::Decode(url.to_string(), e.to_string()))
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self,
+        segments: &[&str],
+        query: &(impl Serialize + ?Sized),
+    ) -> Result<T> {
+        self.call(Method::GET, segments, query, None::<&()>).await
+    }
+
+    async fn post<T: DeserializeOwned>(
+        &self,
+        segments: &[&str],
+        body: &impl Serialize,
+    ) -> Result<T> {
+        self.call(Method::POST, segments, NO_QUERY, Some(body))
+            .await
+    }
+
+    /// `body` posted with `?async=true`; the server answers with a job.
+    async fn post_async(&self, segments: &[&str], body: &impl Serialize) -> Result<Job> {
+        self.call(Method::POST, segments, ASYNC, Some(body)).await
+    }
+
+    async fn delete<T: DeserializeOwned>(&self, segments: &[&str]) -> Result<T> {
+        self.call(Method::DELETE, segments, NO_QUERY, None::<&()>)
+            .await
+    }
+
+    /// `GET /api/v1/vms`
+    pub async fn list_vms(&self, query: &VmListQuery) -> Result<VmListResponse> {
+        self.get(&["vms"], query).await
+    }
+
+    /// `POST /api/v1/vms`
+    pub async fn create_vm(&self, request: &VmCreateRequest) -> Result<VmResponse> {
+        self.post(&["vms"], request).await
+    }
+
+    /// `POST /api/v1/vms?async=true`: poll the job with [`Client::get_job`].
+    pub async fn create_vm_async(&self, request: &VmCreateRequest) -> Result<Job> {
+        self.post_async(&["vms"], request).await
+    }
+
+    /// `GET /api/v1/vms/{name}`
+    pub async fn get_vm(&self, name: &str) -> Result<VmDetailResponse> {
+        self.get(&["vms", name], NO_QUERY).await
+    }
+
+    /// `DELETE /api/v1/vms/{name}`
+    pub async fn delete_vm(&self, name: &str) -> Result<VmResponse> {
+        self.delete(&["vms", name]).await
+    }
+
+    /// `POST /api/v1/vms/{name}/start`
+    pub async fn start_vm(&self, name: &str) -> Result<VmResponse> {
+        self.call(Method::POST, &["vms", name, "start"], NO_QUERY, None::<&()>)
+            .await
+    }
+
+    /// `POST /api/v1/vms/{name}/stop`
+    pub async fn stop_vm(&self, name: &str, query: &VmStopQuery) -> Result<VmResponse> {
+        self.call(Method::POST, &["vms", name, "stop"], query, None::<&()>)
+            .await
+    }
+
+    /// `POST /api/v1/vms/{name}/restart`
+    pub async fn restart_vm(&self, name: &str, query: &VmStopQuery) -> Result<VmResponse> {
+        self.call(Method::POST, &["vms", name, "restart"], query, None::<&()>)
+            .await
+    }
+
+    /// `GET /api/v1/vms/{name}/ip`
+    pub async fn get_vm_ip(&self, name: &str) -> Result<VmIpResponse> {
+        self.get(&["vms", name, "ip"], NO_QUERY).await
+    }
+
+    /// `POST /api/v1/vms/{name}/port-forward`
+    pub async fn port_forward(
+        &self,
+        name: &str,
+        request: &PortForwardRequest,
+    ) -> Result<VmResponse> {
+        self.post(&["vms", name, "port-forward"], request).await
+    }
+
+    /// `GET /api/v1/vms/{name}/logs`: the last `lines` (default 200)
+    /// lines of the VM's Cloud Hypervisor log.
+    pub async fn get_vm_logs(&self, name: &str, lines: Option<usize>) -> Result<VmLogsResponse> {
+        self.get(&["vms", name, "logs"], &[("lines", lines)]).await
+    }
+
+    /// `GET /api/v1/vms/{name}/serial`: the last `tail` (default 200)
+    /// lines the VM printed on its serial console.
+    pub async fn get_vm_serial(&self, name: &str, tail: Option<usize>) -> Result<VmSerialResponse> {
+        self.get(&["vms", name, "serial"], &[("tail", tail)]).await
+    }
+
+    /// WebSocket URL of `GET /api/v1/vms/{name}/console`, with the token
+    /// as `access_token` since WebSocket clients often can't set headers.
+    pub fn vm_console_url(&self, name: &str) -> Url {
+        self.websocket(self.api(&["vms", name, "console"]))
+    }
+
+    /// WebSocket URL of `GET /api/v1/vms/{name}/console/ws`.
+    pub fn vm_console_ws_url(&self, name: &str) -> Url {
+        self.websocket(self.api(&["vms", name, "console", "ws"]))
+    }
+
+    fn websocket(&self, mut url: Url) -> Url {
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        url.set_scheme(scheme).expect("http(s) URLs can be ws(s)");
+        if let Some(token) = &self.token {
+            url.query_pairs_mut().append_pair("access_token", token);
+        }
+        url
+    }
+
+    /// `GET /api/v1/images`
+    pub async fn list_images(&self) -> Result<ImageListResponse> {
+        self.get(&["images"], NO_QUERY).await
+    }
+
+    /// `POST /api/v1/images`
+    pub async fn create_image(&self, request: &ImageCreateRequest) -> Result<VmResponse> {
+        self.post(&["images"], request).await
+    }
+
+    /// `GET /api/v1/images/{image}`: the image's manifest, lineage and
+    /// disk digests, as `meda image inspect --json` prints them.
+    pub async fn inspect_image(&self, image: &str) -> Result<serde_json::Value> {
+        self.get(&["images", image], NO_QUERY).await
+    }
+
+    /// `DELETE /api/v1/images/{image}`
+    pub async fn remove_image(&self, image: &str) -> Result<VmResponse> {
+        self.delete(&["images", image]).await
+    }
+
+    /// `POST /api/v1/images/pull`
+    pub async fn pull_image(&self, request: &ImagePullRequest) -> Result<VmResponse> {
+        self.post(&["images", "pull"], request).await
+    }
+
+    /// `POST /api/v1/images/pull?async=true`
+    pub async fn pull_image_async(&self, request: &ImagePullRequest) -> Result<Job> {
+        self.post_async(&["images", "pull"], request).await
+    }
+
+    /// `POST /api/v1/images/push`
+    pub async fn push_image(&self, request: &ImagePushRequest) -> Result<VmResponse> {
+        self.post(&["images", "push"], request).await
+    }
+
+    /// `POST /api/v1/images/push?async=true`
+    pub async fn push_image_async(&self, request: &ImagePushRequest) -> Result<Job> {
+        self.post_async(&["images", "push"], request).await
+    }
+
+    /// `POST /api/v1/images/tag`
+    pub async fn tag_image(&self, request: &ImageTagRequest) -> Result<VmResponse> {
+        self.post(&["images", "tag"], request).await
+    }
+
+    /// `POST /api/v1/images/prune`
+    pub async fn prune_images(&self, request: &ImagePruneRequest) -> Result<VmResponse> {
+        self.post(&["images", "prune"], request).await
+    }
+
+    /// `POST /api/v1/images/run`
+    pub async fn run_image(&self, request: &ImageRunRequest) -> Result<VmResponse> {
+        self.post(&["images", "run"], request).await
+    }
+
+    /// `POST /api/v1/images/run?async=true`
+    pub async fn run_image_async(&self, request: &ImageRunRequest) -> Result<Job> {
+        self.post_async(&["images", "run"], request).await
+    }
+
+    /// `GET /api/v1/capacity`: the host's admission budget and what's
+    /// committed against it.
+    pub async fn get_capacity(&self) -> Result<serde_json::Value> {
+        self.get(&["capacity"], NO_QUERY).await
+    }
+
+    /// `GET /api/v1/system`: CPUs, memory, free disk, KVM and VM counts.
+    pub async fn get_system_info(&self) -> Result<SystemInfo> {
+        self.get(&["system"], NO_QUERY).await
+    }
+
+    /// `GET /api/v1/jobs/{id}`: a background request's progress and,
+    /// once [`Job::is_finished`], its response.
+    pub async fn get_job(&self, id: &str) -> Result<Job> {
+        self.get(&["jobs", id], NO_QUERY).await
+    }
+
+    /// `GET /api/v1/health`
+    pub async fn health(&self) -> Result<HealthResponse> {
+        self.get(&["health"], NO_QUERY).await
+    }
+
+    /// `GET /api/v1/metrics` (also served at `/metrics`): OpenMetrics text.
+    pub async fn metrics(&self) -> Result<String> {
+        let url = self.api(&["metrics"]);
+        let response = self.send(Method::GET, url, NO_QUERY, None::<&()>).await?;
+        Ok(response.text().await?)
+    }
+}
+
+/// The [`Error::Api`] for a failed response. Bodies that aren't an
+/// `ApiError` (a proxy's error page, say) become one with the status as
+/// the error.
+async fn error_from(response: reqwest::Response) -> Error {
+    let status = response.status();
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+    let body = response.text().await.unwrap_or_default();
+    Error::Api {
+        status,
+        error: parse_error(status, &body),
+        retry_after,
+    }
+}
+
+fn parse_error(status: StatusCode, body: &str) -> ApiError {
+    serde_json::from_str(body).unwrap_or_else(|_| ApiError {
+        error: match body.trim() {
+            "" => status.to_string(),
+            body => format!("{}: {}", status, body),
+        },
+        code: format!("HTTP_{}", status.as_u16()),
+        details: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_urls() {
+        let client = Client::new("https://meda.example.com:7777/").unwrap();
+        assert_eq!(
+            client.api(&["vms", "web", "start"]).as_str(),
+            "https://meda.example.com:7777/api/v1/vms/web/start"
+        );
+        assert_eq!(
+            client
+                .api(&["images", "ghcr.io/cirunlabs/ubuntu:latest"])
+                .as_str(),
+            "https://meda.example.com:7777/api/v1/images/ghcr.io%2Fcirunlabs%2Fubuntu:latest"
+        );
+        let proxied = Client::new("http://10.0.0.5/meda")
+            .unwrap()
+            .with_token("t&k");
+        assert_eq!(
+            proxied.vm_console_url("web").as_str(),
+            "ws://10.0.0.5/meda/api/v1/vms/web/console?access_token=t%26k"
+        );
+        assert!(Client::new("ssh://host").is_err());
+        assert!(Client::new("meda.example.com").is_err());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let error = parse_error(
+            StatusCode::NOT_FOUND,
+            r#"{"error":"Failed to start VM","code":"VM_NOT_FOUND","details":{"message":"VM web does not exist"}}"#,
+        );
+        assert_eq!(error.code, "VM_NOT_FOUND");
+        assert_eq!(
+            error.to_string(),
+            "Failed to start VM: VM web does not exist"
+        );
+
+        let error = parse_error(StatusCode::BAD_GATEWAY, "upstream down\n");
+        assert_eq!(error.code, "HTTP_502");
+        assert_eq!(error.to_string(), "502 Bad Gateway: upstream down");
+    }
+}
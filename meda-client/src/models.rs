@@ -0,0 +1,477 @@
+//! Request and response bodies of the meda API, as in its OpenAPI
+//! document (`/api/v1/openapi.json` on a running `meda serve`).
+//!
+//! Requests implement `Default`, so set what you need and leave the
+//! rest: `VmCreateRequest { name: "web".into(), memory: Some("2G".into()), ..Default::default() }`.
+//! Optional fields are sent as `null`, which the server reads as unset.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Request to create a new VM
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmCreateRequest {
+    /// Name of the VM
+    pub name: String,
+    /// Path to a user-data file on the server
+    pub user_data: Option<String>,
+    /// Extra SSH public keys for the `cirun` user (key strings or paths on
+    /// the server); disables password login
+    pub ssh_keys: Vec<String>,
+    /// Labels recorded on the VM, e.g. `{"ci": "true"}`
+    pub labels: BTreeMap<String, String>,
+    /// Environment variables for the guest, in /etc/meda/env
+    pub env: BTreeMap<String, String>,
+    /// Like `env`, but in /etc/meda/secret-env, readable by root only,
+    /// and shown as `<redacted>` when listing the VM
+    pub secret_env: BTreeMap<String, String>,
+    /// Server files to copy into the guest, as "/host/path:/guest/path[:MODE[:OWNER]]"
+    pub secrets: Vec<String>,
+    /// Storage pool for the VM dir (see MEDA_STORAGE_POOLS)
+    pub storage: Option<String>,
+    /// Block traffic to and from other VMs
+    pub isolate: bool,
+    /// Outbound policy (e.g. "allow:10.0.5.0/24,deny:10.0.0.0/8") or a
+    /// named policy from the server's egress-policies file
+    pub egress: Option<String>,
+    /// Host interface to pin the VM's outbound NAT to (e.g. "eth1")
+    pub egress_interface: Option<String>,
+    /// Entropy source of the virtio-rng device (e.g. "/dev/hwrng"), or "none"
+    pub rng: Option<String>,
+    /// "nat" (default), "bridged" or "user"
+    pub network: Option<String>,
+    /// Existing host bridge to attach the VM to when `network` is "bridged"
+    pub bridge: Option<String>,
+    /// Delete any VM with this name first
+    pub force: bool,
+    /// Memory size (e.g., 1G, 2048M, 512M)
+    pub memory: Option<String>,
+    /// Number of CPUs
+    pub cpus: Option<u8>,
+    /// Back guest memory with a shared mapping
+    pub shared_memory: bool,
+    /// Back guest memory with the host's hugepage pool
+    pub hugepages: bool,
+    /// Fault in all guest memory at boot
+    pub prefault: bool,
+    /// Add a balloon device that returns freed guest memory to the host
+    pub balloon: bool,
+    /// Serve cloud-init data over HTTP instead of attaching a seed ISO
+    pub no_iso: bool,
+    /// Host CPUs to pin the VM to (e.g. "0-3" or "2,4-7")
+    pub cpu_affinity: Option<String>,
+    /// Boot with room for this many vCPUs, for hotplug
+    pub max_cpus: Option<u8>,
+    /// Boot with room for this much memory (e.g. 16G), for hotplug
+    pub max_memory: Option<String>,
+    /// Disk size (e.g., 10G, 20G, 5120M)
+    pub disk: Option<String>,
+    /// VFIO device paths for PCI passthrough
+    pub devices: Vec<String>,
+    /// Reset the root disk to the image on every start
+    pub immutable_root: bool,
+    /// Size of a persistent data disk to attach (e.g., 20G)
+    pub data_disk: Option<String>,
+    /// Guest mount point of the data disk (default /data)
+    pub data_mount: Option<String>,
+    /// Host directories to share over virtiofs, as "/host/path:/guest/path[:ro]"
+    pub mounts: Vec<String>,
+    /// Add a vsock device for a guest agent
+    pub vsock: bool,
+    /// Server-side path of a kernel to boot directly instead of the image's
+    pub kernel: Option<String>,
+    /// Server-side path of an initramfs to load with `kernel`
+    pub initramfs: Option<String>,
+    /// Kernel command line for `kernel`
+    pub cmdline: Option<String>,
+}
+
+/// Result of an operation on a VM or an image
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VmResponse {
+    /// Success status
+    pub success: bool,
+    /// Response message
+    pub message: String,
+    /// The VM, for operations that made one
+    #[serde(default)]
+    pub vm: Option<VmInfo>,
+}
+
+/// VM information
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmInfo {
+    /// VM name
+    pub name: String,
+    /// VM state: creating, stopped, starting, running, stopping or error
+    pub state: String,
+    /// VM IP address
+    pub ip: String,
+    /// Number of vCPUs
+    pub vcpus: String,
+    /// Memory allocation
+    pub memory: String,
+    /// Disk size
+    pub disk: String,
+    /// Attached VFIO devices
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// Creation time
+    pub created: String,
+    /// Labels set at create/run time
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Guest environment variables, secret values shown as `<redacted>`
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Storage pool the VM dir lives on
+    #[serde(default)]
+    pub storage: String,
+}
+
+/// Filters for [`crate::Client::list_vms`]; all given filters must match
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmListQuery {
+    /// Comma-separated label selectors: `key=value` or bare `key`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// VM state, e.g. `running`, `stopped` or `error`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+}
+
+/// Options for [`crate::Client::stop_vm`] and [`crate::Client::restart_vm`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmStopQuery {
+    /// Seconds to wait for the guest to power off before killing it
+    /// (default `MEDA_STOP_TIMEOUT` on the server, or 30)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// Kill the VM without pressing its ACPI power button
+    pub force: bool,
+}
+
+/// VM list response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmListResponse {
+    /// List of VMs
+    pub vms: Vec<VmInfo>,
+    /// Total count
+    pub count: usize,
+}
+
+/// Detailed VM information
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VmDetailResponse {
+    /// VM name
+    pub name: String,
+    /// VM state
+    pub state: String,
+    /// VM IP address, once it has one
+    pub ip: Option<String>,
+    /// Everything else `meda get` reports
+    pub details: Option<serde_json::Value>,
+}
+
+/// A VM's host-routable address
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmIpResponse {
+    /// VM name
+    pub vm: String,
+    /// IP address
+    pub ip: String,
+}
+
+/// The tail of a VM's Cloud Hypervisor log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmLogsResponse {
+    /// VM name
+    pub vm: String,
+    /// Last lines of ch.log
+    pub log: String,
+}
+
+/// The tail of a VM's serial console output
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmSerialResponse {
+    /// VM name
+    pub vm: String,
+    /// Last lines of serial output
+    pub serial: String,
+}
+
+/// Port forwarding request
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortForwardRequest {
+    /// Host port
+    pub host_port: u16,
+    /// Guest port
+    pub guest_port: u16,
+}
+
+/// Image list response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageListResponse {
+    /// List of images
+    pub images: Vec<ImageInfo>,
+    /// Total count
+    pub count: usize,
+}
+
+/// Image information
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageInfo {
+    /// Image name
+    pub name: String,
+    /// Image tag
+    pub tag: String,
+    /// Registry
+    pub registry: String,
+    /// Image size
+    pub size: String,
+    /// Creation timestamp
+    pub created: String,
+    /// Registry manifest digest, when known
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+/// How [`ImageCreateRequest::live`] copies a running VM's disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LiveMode {
+    /// Pause the VM, reflink-copy its rootfs, resume (crash-consistent)
+    Crash,
+    /// Also fsfreeze the guest's root filesystem over SSH while copying
+    Freeze,
+}
+
+/// Request to create a new image
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageCreateRequest {
+    /// Image name
+    pub name: String,
+    /// Image tag
+    pub tag: String,
+    /// Registry URL
+    pub registry: Option<String>,
+    /// Organization/namespace
+    pub org: Option<String>,
+    /// Create from existing VM instead of base image
+    pub from_vm: Option<String>,
+    /// Copy a running `from_vm` without stopping it
+    pub live: Option<LiveMode>,
+}
+
+impl Default for ImageCreateRequest {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            tag: "latest".to_string(),
+            registry: None,
+            org: None,
+            from_vm: None,
+            live: None,
+        }
+    }
+}
+
+/// Request to pull an image
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImagePullRequest {
+    /// Image name with optional tag
+    pub image: String,
+    /// Registry URL
+    pub registry: Option<String>,
+    /// Organization/namespace
+    pub org: Option<String>,
+    /// Only fetch these artifacts, e.g. `["base_image"]`
+    pub artifacts: Vec<String>,
+}
+
+/// Request to push an image
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImagePushRequest {
+    /// Local image name
+    pub name: String,
+    /// Target image name with tag
+    pub image: String,
+    /// Registry URL
+    pub registry: Option<String>,
+    /// Dry run - don't actually push
+    pub dry_run: bool,
+    /// Annotate the image with its provenance
+    pub provenance: bool,
+    /// Sign the pushed image and attach the signature
+    pub sign: bool,
+    /// More tags for the pushed image in the same repository
+    pub also_tags: Vec<String>,
+}
+
+/// Request to tag an image
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageTagRequest {
+    /// Existing image name with tag
+    pub source: String,
+    /// New image name with tag
+    pub target: String,
+    /// Registry URL
+    pub registry: Option<String>,
+    /// Organization/namespace
+    pub org: Option<String>,
+}
+
+/// Request to prune images
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImagePruneRequest {
+    /// Remove all images, including ones VMs were created from
+    pub all: bool,
+    /// Only remove images at least this old (e.g. "7d", "12h")
+    pub older_than: Option<String>,
+    /// Remove the images; without it, only report what would go
+    pub force: bool,
+}
+
+/// Request to run VM from image
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageRunRequest {
+    /// Image reference
+    pub image: String,
+    /// VM name (default: image name + timestamp)
+    pub name: Option<String>,
+    /// Registry URL
+    pub registry: Option<String>,
+    /// Organization/namespace
+    pub org: Option<String>,
+    /// Path to a user-data file on the server
+    pub user_data: Option<String>,
+    /// Extra SSH public keys for the `cirun` user (key strings or paths on
+    /// the server); disables password login
+    pub ssh_keys: Vec<String>,
+    /// Labels recorded on the VM, e.g. `{"ci": "true"}`
+    pub labels: BTreeMap<String, String>,
+    /// Environment variables for the guest, in /etc/meda/env
+    pub env: BTreeMap<String, String>,
+    /// Like `env`, but in /etc/meda/secret-env, readable by root only,
+    /// and shown as `<redacted>` when listing the VM
+    pub secret_env: BTreeMap<String, String>,
+    /// Server files to copy into the guest, as "/host/path:/guest/path[:MODE[:OWNER]]"
+    pub secrets: Vec<String>,
+    /// Storage pool for the VM dir (see MEDA_STORAGE_POOLS)
+    pub storage: Option<String>,
+    /// Block traffic to and from other VMs
+    pub isolate: bool,
+    /// Outbound policy or a named policy from the server's egress-policies file
+    pub egress: Option<String>,
+    /// Host interface to pin the VM's outbound NAT to (e.g. "eth1")
+    pub egress_interface: Option<String>,
+    /// Entropy source of the virtio-rng device (e.g. "/dev/hwrng"), or "none"
+    pub rng: Option<String>,
+    /// Don't start the VM, just create it
+    pub no_start: bool,
+    /// Memory size
+    pub memory: Option<String>,
+    /// Number of CPUs
+    pub cpus: Option<u8>,
+    /// Disk size
+    pub disk: Option<String>,
+    /// VFIO device paths for PCI passthrough
+    pub devices: Vec<String>,
+    /// Reset the root disk to the image on every start
+    pub immutable_root: bool,
+    /// Size of a persistent data disk to attach (e.g., 20G)
+    pub data_disk: Option<String>,
+    /// Guest mount point of the data disk (default /data)
+    pub data_mount: Option<String>,
+    /// Delete the VM this long after creation (e.g., 90m, 12h)
+    pub ttl: Option<String>,
+}
+
+/// Error body of every failed request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiError {
+    /// Error message
+    pub error: String,
+    /// Error code, e.g. `VM_NOT_FOUND`
+    pub code: String,
+    /// Additional details, usually `{"message": ...}`
+    #[serde(default)]
+    pub details: Option<serde_json::Value>,
+}
+
+/// A request run in the background (the `*_async` methods)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    /// Job ID
+    pub id: String,
+    /// The request, e.g. "POST /api/v1/vms"
+    pub operation: String,
+    /// queued, running, succeeded, failed or interrupted
+    pub status: String,
+    /// When the job was submitted (RFC 3339)
+    pub created_at: String,
+    /// When the status last changed (RFC 3339)
+    pub updated_at: String,
+    /// Latest progress event: phase, artifact, bytes_done, bytes_total, done
+    #[serde(default)]
+    pub progress: Option<serde_json::Value>,
+    /// HTTP status the request finished with
+    #[serde(default)]
+    pub result_status: Option<u16>,
+    /// Response body the request finished with
+    #[serde(default)]
+    pub result: Option<serde_json::Value>,
+}
+
+impl Job {
+    /// Whether the job is over: succeeded, failed or interrupted.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.status.as_str(), "queued" | "running")
+    }
+}
+
+/// Host system information
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SystemInfo {
+    /// Host name
+    pub hostname: String,
+    /// Host architecture, by its OCI name (amd64, arm64)
+    pub arch: String,
+    /// meda version
+    pub version: String,
+    /// Logical CPUs
+    pub cpus: u32,
+    /// Physical memory, in bytes
+    pub memory_total_bytes: u64,
+    /// Memory available to new guests without swapping, in bytes
+    pub memory_available_bytes: u64,
+    /// Filesystem holding the VM dirs
+    pub vm_root: DiskSpace,
+    /// Filesystem holding base images and hypervisor binaries
+    pub asset_dir: DiskSpace,
+    /// Whether /dev/kvm is there for cloud-hypervisor to use
+    pub kvm: bool,
+    /// VMs currently running
+    pub running_vms: usize,
+    /// All VMs, in any state
+    pub total_vms: usize,
+}
+
+/// Free space of a filesystem meda uses
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskSpace {
+    pub path: String,
+    /// Space available to meda, in bytes
+    pub free_bytes: u64,
+}
+
+/// Health check response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthResponse {
+    /// Service status
+    pub status: String,
+    /// Service version
+    pub version: String,
+    /// Current timestamp (RFC 3339)
+    pub timestamp: String,
+}
@@ -278,7 +278,7 @@ pub fn parse_size_gb(s: &str) -> u64 {
     }
 }
 
-fn env_u64(key: &str, default: u64) -> u64 {
+pub(crate) fn env_u64(key: &str, default: u64) -> u64 {
     match env::var(key) {
         Ok(s) => s.trim().parse::<u64>().unwrap_or_else(|_| {
             log::warn!("{key}='{s}' is not a u64; using default {default}");
@@ -0,0 +1,311 @@
+//! vsock device and the guest agent channel over it.
+//!
+//! `meda create --vsock` gives a VM a virtio-vsock device. Cloud
+//! Hypervisor exposes it on the host as a Unix socket, `vsock.sock` in
+//! the VM dir: a client connects, sends `CONNECT <port>\n`, gets
+//! `OK <n>\n` back and from then on talks to whatever listens on that
+//! vsock port in the guest. No network, SSH key or guest address is
+//! involved, so it works before the guest has an IP and when its
+//! networking is broken.
+//!
+//! An agent in the guest listening on [`AGENT_PORT`] answers one
+//! request per connection, one JSON line each way (see docs/AGENT.md
+//! for the protocol and a reference agent):
+//!
+//! - `{"op":"status"}`: boot state as systemd sees it (`running`,
+//!   `starting`, `degraded`, ...);
+//! - `{"op":"ip"}`: the guest's addresses;
+//! - `{"op":"exec","command":"..."}`: run a shell command, with its
+//!   exit code and output.
+//!
+//! `meda exec` and `meda get` use the agent when the VM has one and
+//! fall back to SSH (or leave the fields out) when it doesn't. Once a
+//! command has reached the agent `meda exec` never falls back, so it
+//! can't run twice.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+/// Marker file in a VM dir: the VM has a vsock device. Holds its CID.
+pub const VSOCK_FILE: &str = "vsock";
+
+/// CH's host end of the vsock device, in the VM dir.
+pub const VSOCK_SOCKET: &str = "vsock.sock";
+
+/// Every VM has its own host socket, so all of them can use the first
+/// guest CID.
+const GUEST_CID: u32 = 3;
+
+/// Guest vsock port the agent listens on.
+pub const AGENT_PORT: u32 = 1024;
+
+/// Budget for `status` and `ip`; `exec` gets its own.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn is_enabled(vm_dir: &Path) -> bool {
+    vm_dir.join(VSOCK_FILE).exists()
+}
+
+pub fn enable(vm_dir: &Path) -> Result<()> {
+    fs::write(vm_dir.join(VSOCK_FILE), GUEST_CID.to_string())?;
+    Ok(())
+}
+
+/// `--vsock` arguments for the launch spec; none for VMs without the
+/// device.
+pub fn ch_args(vm_dir: &Path) -> Vec<String> {
+    if !is_enabled(vm_dir) {
+        return Vec::new();
+    }
+    vec![
+        "--vsock".to_string(),
+        format!(
+            "cid={},socket={}",
+            GUEST_CID,
+            vm_dir.join(VSOCK_SOCKET).display()
+        ),
+    ]
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Request {
+    Status,
+    Ip,
+    Exec { command: String },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Response {
+    #[serde(default)]
+    pub ok: bool,
+    pub error: Option<String>,
+    /// `status`: systemd's `is-system-running`.
+    pub boot: Option<String>,
+    /// `ip`: global addresses of the guest, without prefix length.
+    #[serde(default)]
+    pub ips: Vec<String>,
+    /// `exec`:
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
+}
+
+/// A connection to the agent of the VM in `vm_dir`, past the vsock
+/// `CONNECT` handshake. Nothing has been sent to the agent yet.
+fn connect(vm_dir: &Path, timeout: Duration) -> Result<UnixStream> {
+    let socket = vm_dir.join(VSOCK_SOCKET);
+    if !is_enabled(vm_dir) || !socket.exists() {
+        return Err(Error::Other(
+            "VM has no vsock device (create it with --vsock)".to_string(),
+        ));
+    }
+    let mut stream = UnixStream::connect(&socket)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    writeln!(stream, "CONNECT {}", AGENT_PORT)?;
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.starts_with("OK ") {
+        return Err(Error::Other(format!(
+            "no guest agent listening on vsock port {}",
+            AGENT_PORT
+        )));
+    }
+    Ok(stream)
+}
+
+/// Send `request` over `stream`, from [`connect`], and read the reply.
+fn send(mut stream: UnixStream, request: &Request, timeout: Duration) -> Result<Response> {
+    writeln!(stream, "{}", serde_json::to_string(request)?)?;
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => Error::Other(format!(
+                "guest agent did not answer within {}s",
+                timeout.as_secs()
+            )),
+            _ => e.into(),
+        })?;
+    let response: Response = serde_json::from_str(&line)
+        .map_err(|e| Error::Other(format!("bad reply from guest agent: {}", e)))?;
+    if !response.ok {
+        return Err(Error::Other(format!(
+            "guest agent: {}",
+            response.error.as_deref().unwrap_or("request failed")
+        )));
+    }
+    Ok(response)
+}
+
+/// Send `request` to the agent of the VM in `vm_dir`.
+pub fn query(vm_dir: &Path, request: &Request, timeout: Duration) -> Result<Response> {
+    send(connect(vm_dir, timeout)?, request, timeout)
+}
+
+/// Boot state and addresses from the agent, for `meda get`; `None`
+/// when the VM has no agent answering.
+pub fn details(vm_dir: &Path) -> Option<(String, Vec<String>)> {
+    if !is_enabled(vm_dir) {
+        return None;
+    }
+    let status = query(vm_dir, &Request::Status, QUERY_TIMEOUT).ok()?;
+    let ips = query(vm_dir, &Request::Ip, QUERY_TIMEOUT)
+        .map(|r| r.ips)
+        .unwrap_or_default();
+    Some((status.boot.unwrap_or_default(), ips))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExecResult {
+    pub vm: String,
+    /// `agent` or `ssh`.
+    pub via: &'static str,
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// `meda exec`: run `command` in VM `name` through its agent, or over
+/// SSH when it has none or the agent can't be reached. Returns the
+/// command's exit code.
+pub fn exec(
+    config: &Config,
+    name: &str,
+    command: &str,
+    timeout: Duration,
+    json: bool,
+) -> Result<i32> {
+    let vm_dir = config.vm_dir(name);
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+    if !crate::vm::check_vm_running(config, name)? {
+        return Err(Error::Other(format!("VM {} is not running", name)));
+    }
+
+    let request = Request::Exec {
+        command: command.to_string(),
+    };
+    let result = match connect(&vm_dir, timeout) {
+        // The command may already be running: no SSH retry from here on.
+        Ok(stream) => send(stream, &request, timeout).map(|response| ExecResult {
+            vm: name.to_string(),
+            via: "agent",
+            exit_code: response.exit_code.unwrap_or(-1),
+            stdout: response.stdout,
+            stderr: response.stderr,
+        })?,
+        Err(e) => {
+            if is_enabled(&vm_dir) {
+                log::debug!("guest agent of {} unavailable, using SSH: {}", name, e);
+            }
+            let ip = crate::vm::get_routable_ip(config, name)?;
+            let output = crate::ssh::guest_command(config, &ip, command)?;
+            ExecResult {
+                vm: name.to_string(),
+                via: "ssh",
+                exit_code: output.status.code().unwrap_or(-1),
+                stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }
+        }
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        print!("{}", result.stdout);
+        eprint!("{}", result.stderr);
+    }
+    Ok(result.exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use tempfile::TempDir;
+
+    /// Plays CH's vsock socket and an agent behind it.
+    fn fake_agent(vm_dir: &Path, reply: &'static str) -> std::thread::JoinHandle<String> {
+        let listener = UnixListener::bind(vm_dir.join(VSOCK_SOCKET)).unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut connect = String::new();
+            reader.read_line(&mut connect).unwrap();
+            assert_eq!(connect, format!("CONNECT {}\n", AGENT_PORT));
+            stream.write_all(b"OK 1073741824\n").unwrap();
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            stream.write_all(reply.as_bytes()).unwrap();
+            request
+        })
+    }
+
+    #[test]
+    fn test_ch_args() {
+        let dir = TempDir::new().unwrap();
+        assert!(ch_args(dir.path()).is_empty());
+        enable(dir.path()).unwrap();
+        assert_eq!(
+            ch_args(dir.path()),
+            [
+                "--vsock".to_string(),
+                format!("cid=3,socket={}/vsock.sock", dir.path().display())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_query() {
+        let dir = TempDir::new().unwrap();
+        assert!(query(dir.path(), &Request::Status, QUERY_TIMEOUT).is_err());
+        enable(dir.path()).unwrap();
+
+        let agent = fake_agent(
+            dir.path(),
+            "{\"ok\":true,\"exit_code\":3,\"stdout\":\"hi\\n\"}\n",
+        );
+        let request = Request::Exec {
+            command: "echo hi; exit 3".to_string(),
+        };
+        let response = query(dir.path(), &request, QUERY_TIMEOUT).unwrap();
+        assert_eq!(response.exit_code, Some(3));
+        assert_eq!(response.stdout, "hi\n");
+        assert_eq!(
+            agent.join().unwrap(),
+            "{\"op\":\"exec\",\"command\":\"echo hi; exit 3\"}\n"
+        );
+
+        fs::remove_file(dir.path().join(VSOCK_SOCKET)).unwrap();
+        let agent = fake_agent(dir.path(), "{\"ok\":false,\"error\":\"unknown op\"}\n");
+        let err = query(dir.path(), &Request::Ip, QUERY_TIMEOUT).unwrap_err();
+        assert!(err.to_string().contains("unknown op"));
+        agent.join().unwrap();
+
+        // An agent that takes the request and never answers.
+        fs::remove_file(dir.path().join(VSOCK_SOCKET)).unwrap();
+        let listener = UnixListener::bind(dir.path().join(VSOCK_SOCKET)).unwrap();
+        let agent = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"OK 1073741824\n").unwrap();
+            std::thread::sleep(Duration::from_millis(500));
+        });
+        let err = query(dir.path(), &Request::Status, Duration::from_millis(100)).unwrap_err();
+        assert!(err.to_string().contains("did not answer"), "{}", err);
+        agent.join().unwrap();
+    }
+}
@@ -1,18 +1,33 @@
 use axum::{
-    routing::{delete, get, post},
+    extract::Request,
+    http::HeaderValue,
+    middleware::{self, Next},
+    response::{Redirect, Response},
+    routing::{get, post},
     Router,
 };
 use std::sync::Arc;
 use tower::ServiceBuilder;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
-use utoipa::OpenApi;
+use tracing::Instrument;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
 
 use crate::admission::{Admission, Budget};
 use crate::config::Config;
 use crate::host_capacity;
 
+pub mod auth;
 pub mod handlers;
+pub mod jobs;
+pub mod limits;
 pub mod models;
+#[cfg(feature = "web-ui")]
+pub mod ui;
+
+use auth::ApiAuth;
+use jobs::Jobs;
+use limits::OpLimits;
 
 pub use handlers::*;
 
@@ -26,10 +41,16 @@ pub struct AppState {
     /// burst load races: N handlers all read pre-burst committed=0,
     /// all admit, host OOMs. (Observed 2026-05-16.)
     pub admission: Arc<Admission>,
+    /// Bearer tokens accepted by the auth middleware (empty = open).
+    pub auth: Arc<ApiAuth>,
+    /// Caps on concurrent creates / pulls / pushes (429 beyond them).
+    pub limits: Arc<OpLimits>,
+    /// Requests run in the background with `?async=true`.
+    pub jobs: Arc<Jobs>,
 }
 
 /// Create the main API router with all endpoints
-pub fn create_router(config: Arc<Config>, host: &str, port: u16) -> Router {
+pub fn create_router(config: Arc<Config>, host: &str, port: u16) -> crate::error::Result<Router> {
     // When binding to 0.0.0.0, we want to allow the swagger UI to use the browser's current host
     // This way it will work whether accessed via localhost, VM IP, or any other accessible address
     let base_url = if host == "0.0.0.0" {
@@ -58,38 +79,161 @@ pub fn create_router(config: Arc<Config>, host: &str, port: u16) -> Router {
         budget.reserve_disk_gb,
     );
 
+    let auth = ApiAuth::from_env(&config.ch_home)?;
+    if auth.enabled() {
+        log::info!("API token auth enabled ({} token(s))", auth.token_count());
+    } else if host == "127.0.0.1" || host == "localhost" || host == "::1" {
+        log::warn!(
+            "API auth disabled: set MEDA_API_TOKEN or MEDA_API_TOKENS_FILE to require a token"
+        );
+    } else {
+        log::warn!(
+            "API auth disabled while listening on {}: anyone who can reach this port can create and delete VMs. Set MEDA_API_TOKEN or MEDA_API_TOKENS_FILE.",
+            host
+        );
+    }
+
+    let limits = OpLimits::from_env();
+    let (creates, pulls, pushes) = limits.maximums();
+    log::info!(
+        "concurrency limits (0 = unlimited): creates={}, pulls={}, pushes={}",
+        creates,
+        pulls,
+        pushes
+    );
+
+    let jobs = Jobs::open(jobs::dir(&config.ch_home));
+
     let state = AppState {
         config,
         admission: Admission::new(budget),
+        auth: Arc::new(auth),
+        limits: Arc::new(limits),
+        jobs: Arc::new(jobs),
     };
 
-    Router::new()
+    let router = Router::new()
         // VM management endpoints
         .route("/api/v1/vms", get(list_vms).post(create_vm))
         .route("/api/v1/vms/:name", get(get_vm).delete(delete_vm))
         .route("/api/v1/vms/:name/start", post(start_vm))
         .route("/api/v1/vms/:name/stop", post(stop_vm))
+        .route("/api/v1/vms/:name/restart", post(restart_vm))
         .route("/api/v1/vms/:name/ip", get(get_vm_ip))
         .route("/api/v1/vms/:name/port-forward", post(port_forward))
+        .route("/api/v1/vms/:name/console", get(vm_console))
+        .route("/api/v1/vms/:name/console/ws", get(vm_console_ws))
+        .route("/api/v1/vms/:name/logs", get(get_vm_logs))
+        .route("/api/v1/vms/:name/serial", get(get_vm_serial))
         // Image management endpoints
         .route("/api/v1/images", get(list_images).post(create_image))
-        .route("/api/v1/images/:image", delete(remove_image))
+        .route(
+            "/api/v1/images/:image",
+            get(inspect_image).delete(remove_image),
+        )
         .route("/api/v1/images/pull", post(pull_image))
         .route("/api/v1/images/push", post(push_image))
+        .route("/api/v1/images/tag", post(tag_image))
         .route("/api/v1/images/prune", post(prune_images))
         .route("/api/v1/images/run", post(run_from_image))
         // Admission capacity (read-only)
         .route("/api/v1/capacity", get(get_capacity))
+        .route("/api/v1/system", get(get_system_info))
+        // Background requests (?async=true)
+        .route("/api/v1/jobs/:id", get(get_job))
         // Health check
         .route("/api/v1/health", get(health_check))
-        // Swagger UI with dynamic OpenAPI spec
-        .merge(create_swagger_ui(&base_url))
+        // Prometheus scrape target
+        .route("/metrics", get(metrics))
+        .route("/api/v1/metrics", get(api_metrics))
+        // Swagger UI with dynamic OpenAPI spec; /docs is the short alias
+        .route(
+            "/docs",
+            get(|| async { Redirect::permanent("/swagger-ui/") }),
+        )
+        .merge(create_swagger_ui(&base_url));
+    #[cfg(feature = "web-ui")]
+    let router = router.merge(ui::routes());
+
+    Ok(router
+        // Runs after auth, so unauthenticated requests never take a slot.
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            limits::limit_operations,
+        ))
+        // Outside the limits, so a background request queues for its slot.
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            jobs::run_in_background,
+        ))
+        // Inside CORS so browser preflights are answered without a token.
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_token,
+        ))
         .layer(
             ServiceBuilder::new()
-                .layer(TraceLayer::new_for_http())
+                // The default span logs the whole URI, and with it any
+                // `?access_token=`; the path is enough.
+                .layer(
+                    TraceLayer::new_for_http().make_span_with(|request: &Request| {
+                        tracing::debug_span!(
+                            "request",
+                            method = %request.method(),
+                            path = %request.uri().path(),
+                        )
+                    }),
+                )
                 .layer(CorsLayer::permissive()),
         )
-        .with_state(state)
+        // Outermost, so even rejected requests get an ID.
+        .layer(middleware::from_fn(trace_request))
+        .with_state(state))
+}
+
+/// Middleware: handle the request in an `op` span of its own (see
+/// `crate::logging`) and return its ID as `X-Request-Id`.
+async fn trace_request(request: Request, next: Next) -> Response {
+    let id = crate::logging::op_id();
+    let span = tracing::info_span!(
+        "op",
+        id = %id,
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+    let mut response = next.run(request).instrument(span).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert("x-request-id", value);
+    }
+    response
+}
+
+/// Resolves on SIGTERM (systemd/docker stop) or Ctrl-C, so `axum::serve`
+/// can stop accepting connections and let in-flight requests finish
+/// instead of being killed mid-`create`.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::warn!("failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                log::warn!("failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    log::info!("shutdown signal received, draining in-flight requests");
 }
 
 /// OpenAPI documentation
@@ -102,16 +246,28 @@ pub fn create_router(config: Arc<Config>, host: &str, port: u16) -> Router {
         handlers::delete_vm,
         handlers::start_vm,
         handlers::stop_vm,
+        handlers::restart_vm,
         handlers::get_vm_ip,
         handlers::port_forward,
+        handlers::vm_console,
+        handlers::vm_console_ws,
+        handlers::get_vm_logs,
+        handlers::get_vm_serial,
         handlers::list_images,
         handlers::create_image,
+        handlers::inspect_image,
         handlers::remove_image,
         handlers::pull_image,
         handlers::push_image,
+        handlers::tag_image,
         handlers::prune_images,
         handlers::run_from_image,
+        handlers::get_capacity,
+        handlers::get_system_info,
+        handlers::get_job,
         handlers::health_check,
+        handlers::metrics,
+        handlers::api_metrics,
     ),
     components(
         schemas(
@@ -123,15 +279,22 @@ pub fn create_router(config: Arc<Config>, host: &str, port: u16) -> Router {
             models::PortForwardRequest,
             models::ImageListResponse,
             models::ImageCreateRequest,
+            crate::image::LiveMode,
             models::ImagePullRequest,
             models::ImagePushRequest,
+            models::ImageTagRequest,
             models::ImagePruneRequest,
             models::ImageRunRequest,
             models::ImageInfo,
             models::ApiError,
+            crate::system_info::SystemInfo,
+            crate::system_info::DiskSpace,
+            models::Job,
             models::HealthResponse,
         )
     ),
+    modifiers(&BearerAuth),
+    security(("bearer" = [])),
     tags(
         (name = "VMs", description = "Virtual Machine management operations"),
         (name = "Images", description = "VM Image management operations"),
@@ -153,6 +316,20 @@ pub fn create_router(config: Arc<Config>, host: &str, port: u16) -> Router {
 )]
 pub struct ApiDoc;
 
+/// Registers the `bearer` security scheme so Swagger UI offers an
+/// "Authorize" button for `MEDA_API_TOKEN`.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
 /// Create Swagger UI with dynamic OpenAPI spec
 fn create_swagger_ui(base_url: &str) -> Router<AppState> {
     let mut openapi = ApiDoc::openapi();
@@ -173,3 +350,91 @@ fn create_swagger_ui(base_url: &str) -> Router<AppState> {
         .url("/api/v1/openapi.json", openapi)
         .into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use serde_json::{json, Value};
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_client_covers_openapi_paths() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let documented: BTreeSet<(String, String)> = doc["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .flat_map(|(path, item)| {
+                item.as_object()
+                    .unwrap()
+                    .keys()
+                    .map(move |method| (method.to_uppercase(), path.clone()))
+            })
+            .collect();
+        let covered: BTreeSet<(String, String)> = meda_client::ENDPOINTS
+            .iter()
+            .map(|(method, path)| (method.to_string(), path.to_string()))
+            .collect();
+        assert_eq!(documented, covered);
+    }
+
+    /// `T` must read an object with every property of `schema`, and write
+    /// back exactly those properties.
+    fn check_schema<T: Serialize + DeserializeOwned>(doc: &Value, schema: &str) {
+        let properties = doc["components"]["schemas"][schema]["properties"]
+            .as_object()
+            .unwrap_or_else(|| panic!("no schema {}", schema));
+        let sample: serde_json::Map<String, Value> = properties
+            .iter()
+            .map(|(name, property)| {
+                let value = match property["type"].as_str() {
+                    _ if property["nullable"] == true => Value::Null,
+                    Some("string") => json!(""),
+                    Some("boolean") => json!(false),
+                    Some("integer") | Some("number") => json!(0),
+                    Some("array") => json!([]),
+                    Some("object") => json!({}),
+                    _ => Value::Null,
+                };
+                (name.clone(), value)
+            })
+            .collect();
+        let parsed: T = serde_json::from_value(Value::Object(sample))
+            .unwrap_or_else(|e| panic!("meda-client can't read {}: {}", schema, e));
+        let written: BTreeSet<String> = serde_json::to_value(parsed)
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        let documented: BTreeSet<String> = properties.keys().cloned().collect();
+        assert_eq!(written, documented, "meda-client's {} has drifted", schema);
+    }
+
+    #[test]
+    fn test_client_models_match_openapi_schemas() {
+        use meda_client::models as client;
+
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        check_schema::<client::VmCreateRequest>(&doc, "VmCreateRequest");
+        check_schema::<client::VmResponse>(&doc, "VmResponse");
+        check_schema::<client::VmListResponse>(&doc, "VmListResponse");
+        check_schema::<client::VmDetailResponse>(&doc, "VmDetailResponse");
+        check_schema::<client::VmInfo>(&doc, "VmInfo");
+        check_schema::<client::PortForwardRequest>(&doc, "PortForwardRequest");
+        check_schema::<client::ImageListResponse>(&doc, "ImageListResponse");
+        check_schema::<client::ImageCreateRequest>(&doc, "ImageCreateRequest");
+        check_schema::<client::ImagePullRequest>(&doc, "ImagePullRequest");
+        check_schema::<client::ImagePushRequest>(&doc, "ImagePushRequest");
+        check_schema::<client::ImageTagRequest>(&doc, "ImageTagRequest");
+        check_schema::<client::ImagePruneRequest>(&doc, "ImagePruneRequest");
+        check_schema::<client::ImageRunRequest>(&doc, "ImageRunRequest");
+        check_schema::<client::ImageInfo>(&doc, "ImageInfo");
+        check_schema::<client::ApiError>(&doc, "ApiError");
+        check_schema::<client::HealthResponse>(&doc, "HealthResponse");
+        check_schema::<client::Job>(&doc, "Job");
+    }
+}
@@ -0,0 +1,249 @@
+//! Bearer-token authentication for the REST API.
+//!
+//! Tokens come from `MEDA_API_TOKEN` (a single token) and/or a tokens
+//! file — `MEDA_API_TOKENS_FILE`, defaulting to `api-tokens` in the
+//! config dir (`~/.meda` or `~/.config/meda`) —
+//! holding one token per line (`#` comments and blank lines ignored).
+//! Clients send `Authorization: Bearer <token>`. Browsers can't set
+//! headers on a WebSocket, so upgrade requests may pass the token as
+//! `?access_token=<token>` instead.
+//!
+//! With no tokens configured the server stays open, as before, and
+//! logs a warning at startup; configure a token before binding to
+//! anything but loopback.
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::{models::ApiError, AppState};
+
+/// Routes reachable without a token: liveness probes, the API docs and
+/// the web UI's static assets (which ask for a token themselves).
+const PUBLIC_PATHS: &[&str] = &["/api/v1/health", "/api/v1/openapi.json", "/docs", "/ui"];
+const PUBLIC_PREFIXES: &[&str] = &["/swagger-ui", "/ui/"];
+
+#[derive(Debug, Default)]
+pub struct ApiAuth {
+    tokens: HashSet<String>,
+}
+
+impl ApiAuth {
+    /// Load tokens from the environment and the tokens file under `ch_home`.
+    pub fn from_env(ch_home: &Path) -> crate::error::Result<Self> {
+        let mut tokens = HashSet::new();
+        if let Ok(token) = env::var("MEDA_API_TOKEN") {
+            let token = token.trim();
+            if !token.is_empty() {
+                tokens.insert(token.to_string());
+            }
+        }
+
+        let (path, explicit) = match env::var("MEDA_API_TOKENS_FILE") {
+            Ok(p) => (PathBuf::from(p), true),
+            Err(_) => (ch_home.join("api-tokens"), false),
+        };
+        match fs::read_to_string(&path) {
+            Ok(body) => tokens.extend(parse_tokens_file(&body)),
+            // A missing default file just means "not configured"; a
+            // missing file the user pointed us at is a mistake.
+            Err(e) if explicit => {
+                return Err(crate::error::Error::Other(format!(
+                    "failed to read MEDA_API_TOKENS_FILE {}: {}",
+                    path.display(),
+                    e
+                )))
+            }
+            Err(_) => {}
+        }
+
+        Ok(Self { tokens })
+    }
+
+    #[cfg(test)]
+    pub fn with_tokens(tokens: &[&str]) -> Self {
+        Self {
+            tokens: tokens.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    pub fn token_count(&self) -> usize {
+        self.tokens.len()
+    }
+
+    fn accepts(&self, presented: &str) -> bool {
+        // Compare against every token without short-circuiting so the
+        // response time doesn't reveal which (if any) prefix matched.
+        self.tokens.iter().fold(false, |ok, t| {
+            ok | constant_time_eq(t.as_bytes(), presented.as_bytes())
+        })
+    }
+}
+
+fn parse_tokens_file(body: &str) -> impl Iterator<Item = String> + '_ {
+    body.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn is_public(path: &str) -> bool {
+    PUBLIC_PATHS.contains(&path) || PUBLIC_PREFIXES.iter().any(|p| path.starts_with(p))
+}
+
+fn auth_error(status: StatusCode, error: &str, code: &str) -> Response {
+    let mut response = (
+        status,
+        Json(ApiError {
+            error: error.to_string(),
+            code: code.to_string(),
+            details: None,
+        }),
+    )
+        .into_response();
+    if status == StatusCode::UNAUTHORIZED {
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static("Bearer realm=\"meda\""),
+        );
+    }
+    response
+}
+
+/// Middleware: 401 when no bearer token is presented, 403 when the
+/// token isn't one of ours.
+pub async fn require_token(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !state.auth.enabled() || is_public(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    match presented_token(&request) {
+        None => auth_error(
+            StatusCode::UNAUTHORIZED,
+            "Missing bearer token",
+            "UNAUTHORIZED",
+        ),
+        Some(token) if state.auth.accepts(&token) => next.run(request).await,
+        Some(_) => auth_error(StatusCode::FORBIDDEN, "Invalid API token", "FORBIDDEN"),
+    }
+}
+
+/// The `access_token` query parameter of a WebSocket upgrade.
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: String,
+}
+
+/// The bearer token, or for a WebSocket upgrade the `access_token`
+/// query parameter, percent-decoded.
+fn presented_token(request: &Request) -> Option<String> {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+    if let Some(bearer) = bearer {
+        return Some(bearer.to_string());
+    }
+    let upgrade = request
+        .headers()
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    if !upgrade {
+        return None;
+    }
+    Query::<AccessToken>::try_from_uri(request.uri())
+        .ok()
+        .map(|Query(query)| query.access_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tokens_file() {
+        let tokens: Vec<String> =
+            parse_tokens_file("# ci runners\nabc\n\n  def  \n#old\n").collect();
+        assert_eq!(tokens, vec!["abc".to_string(), "def".to_string()]);
+    }
+
+    #[test]
+    fn test_accepts() {
+        let auth = ApiAuth::with_tokens(&["s3cret", "other"]);
+        assert!(auth.enabled());
+        assert!(auth.accepts("s3cret"));
+        assert!(auth.accepts("other"));
+        assert!(!auth.accepts("s3cre"));
+        assert!(!auth.accepts(""));
+        assert!(!ApiAuth::default().enabled());
+    }
+
+    #[test]
+    fn test_presented_token() {
+        let request = |uri: &str, headers: &[(&str, &str)]| {
+            let mut builder = Request::builder().uri(uri);
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            builder.body(axum::body::Body::empty()).unwrap()
+        };
+        let bearer = request("/api/v1/vms", &[("authorization", "Bearer abc")]);
+        assert_eq!(presented_token(&bearer).as_deref(), Some("abc"));
+
+        let ws = "/api/v1/vms/a/console/ws?x=1&access_token=s3cret";
+        let upgrade = request(ws, &[("upgrade", "websocket")]);
+        assert_eq!(presented_token(&upgrade).as_deref(), Some("s3cret"));
+        // Browsers percent-encode the token in the URL.
+        let encoded = request(
+            "/api/v1/vms/a/console/ws?access_token=a%2Bb%2F%3Dc",
+            &[("upgrade", "websocket")],
+        );
+        assert_eq!(presented_token(&encoded).as_deref(), Some("a+b/=c"));
+        // Only upgrades take the token from the URL.
+        assert_eq!(presented_token(&request(ws, &[])), None);
+        assert_eq!(
+            presented_token(&request(
+                "/api/v1/vms/a/console/ws",
+                &[("upgrade", "websocket")]
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn test_public_paths() {
+        assert!(is_public("/api/v1/health"));
+        assert!(is_public("/swagger-ui/index.html"));
+        assert!(is_public("/docs"));
+        assert!(is_public("/ui/app.js"));
+        assert!(!is_public("/uix"));
+        assert!(!is_public("/api/v1/vms"));
+        assert!(!is_public("/metrics"));
+    }
+}
@@ -1,61 +1,288 @@
 use axum::{
-    extract::{Path, State},
+    extract::{ws::WebSocketUpgrade, Path, Query, State},
     http::{HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Json, Response},
 };
 use log::{error, info};
+use std::collections::BTreeMap;
 
 use super::{models::*, AppState};
 use crate::admission::{self, AdmissionDenied, Committed, VmRequest};
-use crate::{image, vm};
+use crate::labels::VmFilter;
+use crate::{console, image, vm};
 
 /// List all VMs
 #[utoipa::path(
     get,
     path = "/api/v1/vms",
+    params(VmListQuery),
     responses(
         (status = 200, description = "List of VMs", body = VmListResponse),
+        (status = 400, description = "Invalid filter", body = ApiError),
         (status = 500, description = "Internal server error", body = ApiError)
     ),
     tag = "VMs"
 )]
 pub async fn list_vms(
     State(state): State<AppState>,
+    Query(query): Query<VmListQuery>,
 ) -> Result<Json<VmListResponse>, (StatusCode, Json<ApiError>)> {
-    match vm::list(&state.config, true).await {
-        Ok(_) => {
-            // Since vm::list prints JSON, we need to capture it differently
-            // For now, let's implement a direct approach
-            match get_vm_list(&state.config).await {
-                Ok(vms) => Ok(Json(VmListResponse {
-                    count: vms.len(),
-                    vms,
-                })),
-                Err(e) => {
-                    error!("Failed to list VMs: {}", e);
-                    Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(ApiError {
-                            error: "Failed to list VMs".to_string(),
-                            code: "VM_LIST_ERROR".to_string(),
-                            details: Some(serde_json::json!({"message": e.to_string()})),
-                        }),
-                    ))
-                }
-            }
+    let filters = vm_list_filters(&query).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ApiError {
+                error: "Invalid filter".to_string(),
+                code: "INVALID_FILTER".to_string(),
+                details: Some(serde_json::json!({"message": e.to_string()})),
+            }),
+        )
+    })?;
+    match vm::collect_vms(&state.config) {
+        Ok(vms) => {
+            let vms: Vec<VmInfo> = vm::filter_vms(vms, &filters)
+                .into_iter()
+                .map(Into::into)
+                .collect();
+            Ok(Json(VmListResponse {
+                count: vms.len(),
+                vms,
+            }))
         }
         Err(e) => {
             error!("Failed to list VMs: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
+            Err(e.api_error("Failed to list VMs", "VM_LIST_ERROR"))
+        }
+    }
+}
+
+/// `data_disk`/`data_mount` request fields; bad values are a 400.
+fn resolve_data_disk(
+    size: Option<&str>,
+    mount: Option<&str>,
+) -> Result<Option<crate::immutable::DataDisk>, (StatusCode, Json<ApiError>)> {
+    size.map(|s| crate::immutable::DataDisk::new(s, mount))
+        .transpose()
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
                 Json(ApiError {
-                    error: "Failed to list VMs".to_string(),
-                    code: "VM_LIST_ERROR".to_string(),
+                    error: "Invalid data disk".to_string(),
+                    code: "INVALID_DATA_DISK".to_string(),
                     details: Some(serde_json::json!({"message": e.to_string()})),
                 }),
-            ))
+            )
+        })
+}
+
+/// `mounts` request field, limited to directories under
+/// `MEDA_MOUNT_ROOTS`.
+fn resolve_mounts(
+    mounts: &[String],
+) -> Result<Vec<crate::virtiofs::Share>, (StatusCode, Json<ApiError>)> {
+    mounts
+        .iter()
+        .map(|m| crate::virtiofs::Share::parse(m))
+        .collect::<Result<_, String>>()
+        .and_then(crate::virtiofs::confine)
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ApiError {
+                    error: "Invalid mount".to_string(),
+                    code: "INVALID_MOUNT".to_string(),
+                    details: Some(serde_json::json!({"message": e})),
+                }),
+            )
+        })
+}
+
+/// `cpu_affinity` request field.
+fn resolve_cpu_affinity(
+    cpu_affinity: Option<&str>,
+) -> Result<Option<crate::cpu_affinity::CpuSet>, (StatusCode, Json<ApiError>)> {
+    cpu_affinity
+        .map(crate::cpu_affinity::CpuSet::parse)
+        .transpose()
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ApiError {
+                    error: "Invalid CPU affinity".to_string(),
+                    code: "INVALID_CPU_AFFINITY".to_string(),
+                    details: Some(serde_json::json!({"message": e})),
+                }),
+            )
+        })
+}
+
+/// `max_cpus` and `max_memory` request fields.
+fn resolve_headroom(
+    max_cpus: Option<u8>,
+    max_memory: Option<String>,
+) -> Result<crate::hotplug::Headroom, (StatusCode, Json<ApiError>)> {
+    crate::hotplug::Headroom::new(max_cpus, max_memory).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ApiError {
+                error: "Invalid hotplug headroom".to_string(),
+                code: "INVALID_HOTPLUG".to_string(),
+                details: Some(serde_json::json!({"message": e.to_string()})),
+            }),
+        )
+    })
+}
+
+/// `kernel`, `initramfs` and `cmdline` request fields.
+fn resolve_boot(
+    request: &VmCreateRequest,
+) -> Result<Option<crate::boot::DirectBoot>, (StatusCode, Json<ApiError>)> {
+    let Some(kernel) = &request.kernel else {
+        if request.initramfs.is_some() || request.cmdline.is_some() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(ApiError {
+                    error: "Invalid kernel".to_string(),
+                    code: "INVALID_KERNEL".to_string(),
+                    details: Some(
+                        serde_json::json!({"message": "initramfs and cmdline require kernel"}),
+                    ),
+                }),
+            ));
         }
-    }
+        return Ok(None);
+    };
+    crate::boot::DirectBoot::new(
+        std::path::Path::new(kernel),
+        request.initramfs.as_deref().map(std::path::Path::new),
+        request.cmdline.as_deref(),
+    )
+    .map(Some)
+    .map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ApiError {
+                error: "Invalid kernel".to_string(),
+                code: "INVALID_KERNEL".to_string(),
+                details: Some(serde_json::json!({"message": e.to_string()})),
+            }),
+        )
+    })
+}
+
+/// `egress` request field as a policy; a bad spec or unknown policy
+/// name is the caller's mistake.
+fn resolve_egress(
+    config: &crate::config::Config,
+    egress: Option<&str>,
+) -> Result<Option<crate::egress::EgressPolicy>, (StatusCode, Json<ApiError>)> {
+    egress
+        .map(|e| crate::egress::EgressPolicy::resolve(config, e))
+        .transpose()
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ApiError {
+                    error: "Invalid egress policy".to_string(),
+                    code: "INVALID_EGRESS".to_string(),
+                    details: Some(serde_json::json!({"message": e.to_string()})),
+                }),
+            )
+        })
+}
+
+/// `rng` request field.
+fn resolve_rng(
+    rng: Option<&str>,
+) -> Result<Option<crate::rng::RngSource>, (StatusCode, Json<ApiError>)> {
+    rng.map(crate::rng::RngSource::parse)
+        .transpose()
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ApiError {
+                    error: "Invalid RNG source".to_string(),
+                    code: "INVALID_RNG_SOURCE".to_string(),
+                    details: Some(serde_json::json!({"message": e})),
+                }),
+            )
+        })
+}
+
+/// `env` and `secret_env` request fields.
+fn resolve_env(
+    env: &BTreeMap<String, String>,
+    secret_env: &BTreeMap<String, String>,
+) -> Result<crate::guest_env::GuestEnv, (StatusCode, Json<ApiError>)> {
+    crate::guest_env::GuestEnv::new(env.clone(), secret_env.clone()).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ApiError {
+                error: "Invalid environment variable".to_string(),
+                code: "INVALID_ENV".to_string(),
+                details: Some(serde_json::json!({"message": e.to_string()})),
+            }),
+        )
+    })
+}
+
+/// `secrets` request field, limited to files under `MEDA_SECRET_ROOTS`.
+/// Files are read when the VM is provisioned.
+fn resolve_secrets(
+    secrets: &[String],
+) -> Result<Vec<crate::secrets::Secret>, (StatusCode, Json<ApiError>)> {
+    secrets
+        .iter()
+        .map(|s| crate::secrets::Secret::parse(s))
+        .collect::<Result<_, String>>()
+        .and_then(crate::secrets::confine)
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ApiError {
+                    error: "Invalid secret".to_string(),
+                    code: "INVALID_SECRET".to_string(),
+                    details: Some(serde_json::json!({"message": e})),
+                }),
+            )
+        })
+}
+
+/// `ttl` request field, in seconds.
+fn resolve_ttl(ttl: Option<&str>) -> Result<Option<u64>, (StatusCode, Json<ApiError>)> {
+    ttl.map(crate::ttl::parse_ttl).transpose().map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ApiError {
+                error: "Invalid TTL".to_string(),
+                code: "INVALID_TTL".to_string(),
+                details: Some(serde_json::json!({"message": e})),
+            }),
+        )
+    })
+}
+
+/// `egress_interface` request field, checked against the host's
+/// interfaces.
+fn resolve_egress_interface(
+    iface: Option<&str>,
+) -> Result<Option<String>, (StatusCode, Json<ApiError>)> {
+    iface
+        .map(|iface| {
+            crate::uplink::parse_interface(iface)
+                .map_err(crate::error::Error::Other)
+                .and_then(|iface| crate::uplink::check_interface(&iface).map(|_| iface))
+        })
+        .transpose()
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ApiError {
+                    error: "Invalid egress interface".to_string(),
+                    code: "INVALID_EGRESS_INTERFACE".to_string(),
+                    details: Some(serde_json::json!({"message": e.to_string()})),
+                }),
+            )
+        })
 }
 
 /// Create a new VM
@@ -63,10 +290,13 @@ pub async fn list_vms(
     post,
     path = "/api/v1/vms",
     request_body = VmCreateRequest,
+    params(("async" = Option<bool>, Query, description = "Run in the background and answer 202 with a job")),
     responses(
         (status = 201, description = "VM created successfully", body = VmResponse),
+        (status = 202, description = "Accepted as a background job", body = Job),
         (status = 400, description = "Bad request", body = ApiError),
         (status = 409, description = "VM already exists", body = ApiError),
+        (status = 429, description = "Too many concurrent operations of this kind", body = ApiError),
         (status = 500, description = "Internal server error", body = ApiError)
     ),
     tag = "VMs"
@@ -76,35 +306,34 @@ pub async fn create_vm(
     Json(request): Json<VmCreateRequest>,
 ) -> Result<Json<VmResponse>, (StatusCode, Json<ApiError>)> {
     info!("Creating VM: {}", request.name);
-
-    // Handle force delete if VM exists
-    if request.force {
-        let vm_dir = state.config.vm_dir(&request.name);
-        if vm_dir.exists() {
-            if vm::check_vm_running(&state.config, &request.name).unwrap_or(false) {
-                if let Err(e) = vm::stop(&state.config, &request.name, true).await {
-                    error!("Failed to stop existing VM: {}", e);
-                    return Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(ApiError {
-                            error: "Failed to stop existing VM".to_string(),
-                            code: "VM_STOP_ERROR".to_string(),
-                            details: Some(serde_json::json!({"message": e.to_string()})),
-                        }),
-                    ));
-                }
-            }
-            if let Err(e) = vm::delete(&state.config, &request.name, true).await {
-                error!("Failed to delete existing VM: {}", e);
-                return Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
+    let egress = resolve_egress(&state.config, request.egress.as_deref())?;
+    let egress_interface = resolve_egress_interface(request.egress_interface.as_deref())?;
+    let rng = resolve_rng(request.rng.as_deref())?;
+    let data_disk = resolve_data_disk(request.data_disk.as_deref(), request.data_mount.as_deref())?;
+    let shares = resolve_mounts(&request.mounts)?;
+    let boot = resolve_boot(&request)?;
+    let cpu_affinity = resolve_cpu_affinity(request.cpu_affinity.as_deref())?;
+    let headroom = resolve_headroom(request.max_cpus, request.max_memory.clone())?;
+    let env = resolve_env(&request.env, &request.secret_env)?;
+    let secrets = resolve_secrets(&request.secrets)?;
+    let network =
+        crate::bridge::NetworkMode::new(request.network.as_deref(), request.bridge.as_deref())
+            .map_err(|e| {
+                (
+                    StatusCode::BAD_REQUEST,
                     Json(ApiError {
-                        error: "Failed to delete existing VM".to_string(),
-                        code: "VM_DELETE_ERROR".to_string(),
+                        error: "Invalid network mode".to_string(),
+                        code: "INVALID_NETWORK".to_string(),
                         details: Some(serde_json::json!({"message": e.to_string()})),
                     }),
-                ));
-            }
+                )
+            })?;
+
+    // Like `meda create --force`: replace whatever VM has the name.
+    if request.force {
+        if let Err(e) = vm::delete_existing(&state.config, &request.name, true).await {
+            error!("Failed to delete existing VM: {}", e);
+            return Err(e.api_error("Failed to delete existing VM", "VM_DELETE_ERROR"));
         }
     }
 
@@ -115,12 +344,36 @@ pub async fn create_vm(
         request.cpus,
         request.disk.as_deref(),
         request.devices,
-    );
+    )
+    .with_storage(request.storage.clone())
+    .with_isolation(request.isolate)
+    .with_egress(egress)
+    .with_egress_interface(egress_interface)
+    .with_rng(rng)
+    .with_network(network)
+    .with_memory_backing(crate::memory_backing::MemoryBacking {
+        shared: request.shared_memory,
+        hugepages: request.hugepages,
+        prefault: request.prefault,
+    })
+    .with_balloon(request.balloon)
+    .with_cpu_affinity(cpu_affinity)
+    .with_headroom(headroom)
+    .with_immutable_root(request.immutable_root)
+    .with_data_disk(data_disk)
+    .with_shares(shares)
+    .with_env(env)
+    .with_secrets(secrets)
+    .with_vsock(request.vsock)
+    .with_no_iso(request.no_iso)
+    .with_boot(boot);
 
     match vm::create(
         &state.config,
         &request.name,
         request.user_data.as_deref(),
+        &request.ssh_keys,
+        &request.labels,
         &resources,
         true,
     )
@@ -136,20 +389,8 @@ pub async fn create_vm(
         }
         Err(e) => {
             error!("Failed to create VM: {}", e);
-            let status_code = if e.to_string().contains("already exists") {
-                StatusCode::CONFLICT
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
-            };
 
-            Err((
-                status_code,
-                Json(ApiError {
-                    error: "Failed to create VM".to_string(),
-                    code: "VM_CREATE_ERROR".to_string(),
-                    details: Some(serde_json::json!({"message": e.to_string()})),
-                }),
-            ))
+            Err(e.api_error("Failed to create VM", "VM_CREATE_ERROR"))
         }
     }
 }
@@ -172,40 +413,11 @@ pub async fn get_vm(
     State(state): State<AppState>,
     Path(name): Path<String>,
 ) -> Result<Json<VmDetailResponse>, (StatusCode, Json<ApiError>)> {
-    match vm::get(&state.config, &name, true).await {
-        Ok(_) => {
-            // We need to get the VM details differently since get() prints JSON
-            match get_vm_details(&state.config, &name).await {
-                Ok(vm_detail) => Ok(Json(vm_detail)),
-                Err(e) => {
-                    error!("Failed to get VM details: {}", e);
-                    Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(ApiError {
-                            error: "Failed to get VM details".to_string(),
-                            code: "VM_GET_ERROR".to_string(),
-                            details: Some(serde_json::json!({"message": e.to_string()})),
-                        }),
-                    ))
-                }
-            }
-        }
+    match vm::vm_details(&state.config, &name) {
+        Ok(details) => Ok(Json(details.into())),
         Err(e) => {
             error!("Failed to get VM: {}", e);
-            let status_code = if e.to_string().contains("not found") {
-                StatusCode::NOT_FOUND
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
-            };
-
-            Err((
-                status_code,
-                Json(ApiError {
-                    error: "Failed to get VM".to_string(),
-                    code: "VM_NOT_FOUND".to_string(),
-                    details: Some(serde_json::json!({"message": e.to_string()})),
-                }),
-            ))
+            Err(e.api_error("Failed to get VM", "VM_GET_ERROR"))
         }
     }
 }
@@ -239,20 +451,8 @@ pub async fn delete_vm(
         }
         Err(e) => {
             error!("Failed to delete VM: {}", e);
-            let status_code = if e.to_string().contains("not found") {
-                StatusCode::NOT_FOUND
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
-            };
 
-            Err((
-                status_code,
-                Json(ApiError {
-                    error: "Failed to delete VM".to_string(),
-                    code: "VM_DELETE_ERROR".to_string(),
-                    details: Some(serde_json::json!({"message": e.to_string()})),
-                }),
-            ))
+            Err(e.api_error("Failed to delete VM", "VM_DELETE_ERROR"))
         }
     }
 }
@@ -287,22 +487,8 @@ pub async fn start_vm(
         }
         Err(e) => {
             error!("Failed to start VM: {}", e);
-            let status_code = if e.to_string().contains("not found") {
-                StatusCode::NOT_FOUND
-            } else if e.to_string().contains("already running") {
-                StatusCode::CONFLICT
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
-            };
 
-            Err((
-                status_code,
-                Json(ApiError {
-                    error: "Failed to start VM".to_string(),
-                    code: "VM_START_ERROR".to_string(),
-                    details: Some(serde_json::json!({"message": e.to_string()})),
-                }),
-            ))
+            Err(e.api_error("Failed to start VM", "VM_START_ERROR"))
         }
     }
 }
@@ -312,7 +498,8 @@ pub async fn start_vm(
     post,
     path = "/api/v1/vms/{name}/stop",
     params(
-        ("name" = String, Path, description = "VM name")
+        ("name" = String, Path, description = "VM name"),
+        VmStopQuery
     ),
     responses(
         (status = 200, description = "VM stopped successfully", body = VmResponse),
@@ -325,8 +512,10 @@ pub async fn start_vm(
 pub async fn stop_vm(
     State(state): State<AppState>,
     Path(name): Path<String>,
+    Query(query): Query<VmStopQuery>,
 ) -> Result<Json<VmResponse>, (StatusCode, Json<ApiError>)> {
-    match vm::stop(&state.config, &name, true).await {
+    let options = vm::StopOptions::from_flags(&state.config, query.timeout, query.force);
+    match vm::stop_with(&state.config, &name, options, true).await {
         Ok(_) => {
             info!("Successfully stopped VM: {}", name);
             Ok(Json(VmResponse {
@@ -337,22 +526,46 @@ pub async fn stop_vm(
         }
         Err(e) => {
             error!("Failed to stop VM: {}", e);
-            let status_code = if e.to_string().contains("not found") {
-                StatusCode::NOT_FOUND
-            } else if e.to_string().contains("not running") {
-                StatusCode::CONFLICT
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
-            };
 
-            Err((
-                status_code,
-                Json(ApiError {
-                    error: "Failed to stop VM".to_string(),
-                    code: "VM_STOP_ERROR".to_string(),
-                    details: Some(serde_json::json!({"message": e.to_string()})),
-                }),
-            ))
+            Err(e.api_error("Failed to stop VM", "VM_STOP_ERROR"))
+        }
+    }
+}
+
+/// Restart a VM, keeping its IP
+#[utoipa::path(
+    post,
+    path = "/api/v1/vms/{name}/restart",
+    params(
+        ("name" = String, Path, description = "VM name"),
+        VmStopQuery
+    ),
+    responses(
+        (status = 200, description = "VM restarted successfully", body = VmResponse),
+        (status = 404, description = "VM not found", body = ApiError),
+        (status = 500, description = "Internal server error", body = ApiError)
+    ),
+    tag = "VMs"
+)]
+pub async fn restart_vm(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<VmStopQuery>,
+) -> Result<Json<VmResponse>, (StatusCode, Json<ApiError>)> {
+    let options = vm::StopOptions::from_flags(&state.config, query.timeout, query.force);
+    match vm::restart(&state.config, &name, options, true).await {
+        Ok(_) => {
+            info!("Successfully restarted VM: {}", name);
+            Ok(Json(VmResponse {
+                success: true,
+                message: format!("Successfully restarted VM: {}", name),
+                vm: None,
+            }))
+        }
+        Err(e) => {
+            error!("Failed to restart VM: {}", e);
+
+            Err(e.api_error("Failed to restart VM", "VM_RESTART_ERROR"))
         }
     }
 }
@@ -383,33 +596,14 @@ pub async fn get_vm_ip(
                 Ok(ip) => Ok(Json(serde_json::json!({"vm": name, "ip": ip}))),
                 Err(e) => {
                     error!("Failed to get VM IP: {}", e);
-                    Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(ApiError {
-                            error: "Failed to get VM IP".to_string(),
-                            code: "VM_IP_ERROR".to_string(),
-                            details: Some(serde_json::json!({"message": e.to_string()})),
-                        }),
-                    ))
+                    Err(e.api_error("Failed to get VM IP", "VM_IP_ERROR"))
                 }
             }
         }
         Err(e) => {
             error!("Failed to get VM IP: {}", e);
-            let status_code = if e.to_string().contains("not found") {
-                StatusCode::NOT_FOUND
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
-            };
 
-            Err((
-                status_code,
-                Json(ApiError {
-                    error: "Failed to get VM IP".to_string(),
-                    code: "VM_IP_ERROR".to_string(),
-                    details: Some(serde_json::json!({"message": e.to_string()})),
-                }),
-            ))
+            Err(e.api_error("Failed to get VM IP", "VM_IP_ERROR"))
         }
     }
 }
@@ -450,14 +644,132 @@ pub async fn port_forward(
         }
         Err(e) => {
             error!("Failed to set up port forwarding: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError {
-                    error: "Failed to set up port forwarding".to_string(),
-                    code: "PORT_FORWARD_ERROR".to_string(),
-                    details: Some(serde_json::json!({"message": e.to_string()})),
-                }),
-            ))
+            Err(e.api_error("Failed to set up port forwarding", "PORT_FORWARD_ERROR"))
+        }
+    }
+}
+
+/// Attach to a VM's serial console over WebSocket
+///
+/// Upgrades to a WebSocket bridged to the guest's ttyS0. Guest output
+/// arrives as binary frames; text or binary frames sent by the client
+/// are written to the guest as keystrokes.
+#[utoipa::path(
+    get,
+    path = "/api/v1/vms/{name}/console",
+    params(
+        ("name" = String, Path, description = "VM name")
+    ),
+    responses(
+        (status = 101, description = "Switching to the WebSocket protocol"),
+        (status = 404, description = "VM not found", body = ApiError),
+        (status = 409, description = "VM is not running", body = ApiError),
+        (status = 500, description = "Internal server error", body = ApiError)
+    ),
+    tag = "VMs"
+)]
+pub async fn vm_console(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    // Connect before upgrading so failures come back as a normal
+    // HTTP error instead of a WebSocket that closes immediately.
+    match console::connect(&state.config, &name).await {
+        Ok(stream) => {
+            info!("Console attached to VM: {}", name);
+            ws.on_upgrade(move |socket| console::bridge(socket, stream))
+        }
+        Err(e) => {
+            error!("Failed to attach console: {}", e);
+            e.api_error("Failed to attach console", "CONSOLE_ERROR")
+                .into_response()
+        }
+    }
+}
+
+/// Attach a browser terminal to a VM's serial console
+///
+/// Same as `/console`, for xterm.js and other browser terminals, which
+/// can't send an `Authorization` header: with auth enabled, pass the
+/// token as `?access_token=`.
+#[utoipa::path(
+    get,
+    path = "/api/v1/vms/{name}/console/ws",
+    params(
+        ("name" = String, Path, description = "VM name"),
+        ("access_token" = Option<String>, Query, description = "API token, for clients that can't set headers")
+    ),
+    responses(
+        (status = 101, description = "Switching to the WebSocket protocol"),
+        (status = 404, description = "VM not found", body = ApiError),
+        (status = 409, description = "VM is not running", body = ApiError),
+        (status = 500, description = "Internal server error", body = ApiError)
+    ),
+    tag = "VMs"
+)]
+pub async fn vm_console_ws(
+    state: State<AppState>,
+    name: Path<String>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    vm_console(state, name, ws).await
+}
+
+/// Get the tail of a VM's cloud-hypervisor log
+#[utoipa::path(
+    get,
+    path = "/api/v1/vms/{name}/logs",
+    params(
+        ("name" = String, Path, description = "VM name"),
+        VmLogsQuery
+    ),
+    responses(
+        (status = 200, description = "Last lines of ch.log", body = serde_json::Value),
+        (status = 404, description = "VM not found", body = ApiError),
+        (status = 500, description = "Internal server error", body = ApiError)
+    ),
+    tag = "VMs"
+)]
+pub async fn get_vm_logs(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<VmLogsQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<ApiError>)> {
+    match vm::log_tail(&state.config, &name, query.lines.unwrap_or(200)) {
+        Ok(log) => Ok(Json(serde_json::json!({"vm": name, "log": log}))),
+        Err(e) => {
+            error!("Failed to read VM log: {}", e);
+            Err(e.api_error("Failed to read VM log", "VM_LOGS_ERROR"))
+        }
+    }
+}
+
+/// Get the tail of what a VM printed on its serial console
+#[utoipa::path(
+    get,
+    path = "/api/v1/vms/{name}/serial",
+    params(
+        ("name" = String, Path, description = "VM name"),
+        VmSerialQuery
+    ),
+    responses(
+        (status = 200, description = "Last lines of serial output", body = serde_json::Value),
+        (status = 404, description = "VM not found", body = ApiError),
+        (status = 500, description = "Internal server error", body = ApiError)
+    ),
+    tag = "VMs"
+)]
+pub async fn get_vm_serial(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<VmSerialQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<ApiError>)> {
+    match console::dump(&state.config, &name, query.tail.unwrap_or(200)) {
+        Ok(serial) => Ok(Json(serde_json::json!({"vm": name, "serial": serial}))),
+        Err(e) => {
+            error!("Failed to read VM serial output: {}", e);
+            Err(e.api_error("Failed to read VM serial output", "VM_SERIAL_ERROR"))
         }
     }
 }
@@ -477,24 +789,17 @@ pub async fn port_forward(
 pub async fn list_images(
     State(state): State<AppState>,
 ) -> Result<Json<ImageListResponse>, (StatusCode, Json<ApiError>)> {
-    match image::list(&state.config, true).await {
-        Ok(_) => {
-            // For now return empty list - implement proper image listing
+    match image::collect_images(&state.config) {
+        Ok(images) => {
+            let images: Vec<ImageInfo> = images.into_iter().map(ImageInfo::from).collect();
             Ok(Json(ImageListResponse {
-                images: vec![],
-                count: 0,
+                count: images.len(),
+                images,
             }))
         }
         Err(e) => {
             error!("Failed to list images: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError {
-                    error: "Failed to list images".to_string(),
-                    code: "IMAGE_LIST_ERROR".to_string(),
-                    details: Some(serde_json::json!({"message": e.to_string()})),
-                }),
-            ))
+            Err(e.api_error("Failed to list images", "IMAGE_LIST_ERROR"))
         }
     }
 }
@@ -515,8 +820,11 @@ pub async fn create_image(
     State(state): State<AppState>,
     Json(request): Json<ImageCreateRequest>,
 ) -> Result<Json<VmResponse>, (StatusCode, Json<ApiError>)> {
-    let default_registry = request.registry.as_deref().unwrap_or("ghcr.io");
-    let default_org = request.org.as_deref().unwrap_or("cirunlabs");
+    let default_registry = request
+        .registry
+        .as_deref()
+        .unwrap_or(&state.config.default_registry);
+    let default_org = request.org.as_deref().unwrap_or(&state.config.default_org);
 
     let result = if let Some(vm_name) = request.from_vm {
         image::create_from_vm(
@@ -526,6 +834,7 @@ pub async fn create_image(
             &request.tag,
             default_registry,
             default_org,
+            request.live,
             true,
         )
         .await
@@ -558,14 +867,46 @@ pub async fn create_image(
         }
         Err(e) => {
             error!("Failed to create image: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError {
-                    error: "Failed to create image".to_string(),
-                    code: "IMAGE_CREATE_ERROR".to_string(),
-                    details: Some(serde_json::json!({"message": e.to_string()})),
-                }),
-            ))
+            Err(e.api_error("Failed to create image", "IMAGE_CREATE_ERROR"))
+        }
+    }
+}
+
+/// Inspect a local image
+///
+/// The image's manifest with each artifact's size, presence and SHA256,
+/// its metadata and chunking details, as `meda image inspect --json`
+/// prints it.
+#[utoipa::path(
+    get,
+    path = "/api/v1/images/{image}",
+    params(
+        ("image" = String, Path, description = "Image reference, URL-encoded (e.g. ghcr.io%2Fcirunlabs%2Fubuntu%3Alatest)")
+    ),
+    responses(
+        (status = 200, description = "Image details", body = serde_json::Value),
+        (status = 404, description = "Image not found", body = ApiError),
+        (status = 500, description = "Internal server error", body = ApiError)
+    ),
+    tag = "Images"
+)]
+pub async fn inspect_image(
+    State(state): State<AppState>,
+    Path(image_name): Path<String>,
+) -> Result<Json<image::ImageInspect>, (StatusCode, Json<ApiError>)> {
+    // Hashing a multi-GB disk the first time takes a while.
+    let config = state.config.clone();
+    let span = tracing::Span::current();
+    let result = tokio::task::spawn_blocking(move || {
+        span.in_scope(|| image::inspect_image(&config, &image_name, None, None))
+    })
+    .await
+    .map_err(|e| crate::error::Error::Other(e.to_string()));
+    match result.and_then(|r| r) {
+        Ok(report) => Ok(Json(report)),
+        Err(e) => {
+            error!("Failed to inspect image: {}", e);
+            Err(e.api_error("Failed to inspect image", "IMAGE_INSPECT_ERROR"))
         }
     }
 }
@@ -580,6 +921,7 @@ pub async fn create_image(
     responses(
         (status = 200, description = "Image removed successfully", body = VmResponse),
         (status = 404, description = "Image not found", body = ApiError),
+        (status = 409, description = "Image in use by a run or push", body = ApiError),
         (status = 500, description = "Internal server error", body = ApiError)
     ),
     tag = "Images"
@@ -599,14 +941,7 @@ pub async fn remove_image(
         }
         Err(e) => {
             error!("Failed to remove image: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError {
-                    error: "Failed to remove image".to_string(),
-                    code: "IMAGE_REMOVE_ERROR".to_string(),
-                    details: Some(serde_json::json!({"message": e.to_string()})),
-                }),
-            ))
+            Err(e.api_error("Failed to remove image", "IMAGE_REMOVE_ERROR"))
         }
     }
 }
@@ -616,9 +951,12 @@ pub async fn remove_image(
     post,
     path = "/api/v1/images/pull",
     request_body = ImagePullRequest,
+    params(("async" = Option<bool>, Query, description = "Run in the background and answer 202 with a job")),
     responses(
         (status = 200, description = "Image pulled successfully", body = VmResponse),
+        (status = 202, description = "Accepted as a background job", body = Job),
         (status = 400, description = "Bad request", body = ApiError),
+        (status = 429, description = "Too many concurrent operations of this kind", body = ApiError),
         (status = 500, description = "Internal server error", body = ApiError)
     ),
     tag = "Images"
@@ -627,15 +965,27 @@ pub async fn pull_image(
     State(state): State<AppState>,
     Json(request): Json<ImagePullRequest>,
 ) -> Result<Json<VmResponse>, (StatusCode, Json<ApiError>)> {
-    match image::pull(
-        &state.config,
-        &request.image,
-        request.registry.as_deref(),
-        request.org.as_deref(),
-        true,
-    )
-    .await
-    {
+    let result = if request.artifacts.is_empty() {
+        image::pull(
+            &state.config,
+            &request.image,
+            request.registry.as_deref(),
+            request.org.as_deref(),
+            true,
+        )
+        .await
+    } else {
+        image::pull_artifacts(
+            &state.config,
+            &request.image,
+            request.registry.as_deref(),
+            request.org.as_deref(),
+            &request.artifacts,
+            true,
+        )
+        .await
+    };
+    match result {
         Ok(_) => {
             info!("Successfully pulled image: {}", request.image);
             Ok(Json(VmResponse {
@@ -646,14 +996,7 @@ pub async fn pull_image(
         }
         Err(e) => {
             error!("Failed to pull image: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError {
-                    error: "Failed to pull image".to_string(),
-                    code: "IMAGE_PULL_ERROR".to_string(),
-                    details: Some(serde_json::json!({"message": e.to_string()})),
-                }),
-            ))
+            Err(e.api_error("Failed to pull image", "IMAGE_PULL_ERROR"))
         }
     }
 }
@@ -663,9 +1006,12 @@ pub async fn pull_image(
     post,
     path = "/api/v1/images/push",
     request_body = ImagePushRequest,
+    params(("async" = Option<bool>, Query, description = "Run in the background and answer 202 with a job")),
     responses(
         (status = 200, description = "Image pushed successfully", body = VmResponse),
+        (status = 202, description = "Accepted as a background job", body = Job),
         (status = 400, description = "Bad request", body = ApiError),
+        (status = 429, description = "Too many concurrent operations of this kind", body = ApiError),
         (status = 500, description = "Internal server error", body = ApiError)
     ),
     tag = "Images"
@@ -680,6 +1026,11 @@ pub async fn push_image(
         &request.image,
         request.registry.as_deref(),
         request.dry_run,
+        image::PushOptions {
+            provenance: request.provenance,
+            sign: request.sign,
+            also_tags: request.also_tags.clone(),
+        },
         true,
     )
     .await
@@ -694,14 +1045,47 @@ pub async fn push_image(
         }
         Err(e) => {
             error!("Failed to push image: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError {
-                    error: "Failed to push image".to_string(),
-                    code: "IMAGE_PUSH_ERROR".to_string(),
-                    details: Some(serde_json::json!({"message": e.to_string()})),
-                }),
-            ))
+            Err(e.api_error("Failed to push image", "IMAGE_PUSH_ERROR"))
+        }
+    }
+}
+
+/// Tag an image
+#[utoipa::path(
+    post,
+    path = "/api/v1/images/tag",
+    request_body = ImageTagRequest,
+    responses(
+        (status = 200, description = "Image tagged successfully", body = VmResponse),
+        (status = 404, description = "Source image not found", body = ApiError),
+        (status = 409, description = "Target image already exists, or the source is being removed", body = ApiError),
+        (status = 500, description = "Internal server error", body = ApiError)
+    ),
+    tag = "Images"
+)]
+pub async fn tag_image(
+    State(state): State<AppState>,
+    Json(request): Json<ImageTagRequest>,
+) -> Result<Json<VmResponse>, (StatusCode, Json<ApiError>)> {
+    match image::tag(
+        &state.config,
+        &request.source,
+        &request.target,
+        request.registry.as_deref(),
+        request.org.as_deref(),
+        true,
+    ) {
+        Ok(_) => {
+            info!("Tagged image {} as {}", request.source, request.target);
+            Ok(Json(VmResponse {
+                success: true,
+                message: format!("Tagged {} as {}", request.source, request.target),
+                vm: None,
+            }))
+        }
+        Err(e) => {
+            error!("Failed to tag image: {}", e);
+            Err(e.api_error("Failed to tag image", "IMAGE_TAG_ERROR"))
         }
     }
 }
@@ -713,6 +1097,8 @@ pub async fn push_image(
     request_body = ImagePruneRequest,
     responses(
         (status = 200, description = "Images pruned successfully", body = VmResponse),
+        (status = 400, description = "Invalid older_than duration", body = ApiError),
+        (status = 409, description = "An image is in use by a run or push", body = ApiError),
         (status = 500, description = "Internal server error", body = ApiError)
     ),
     tag = "Images"
@@ -721,25 +1107,41 @@ pub async fn prune_images(
     State(state): State<AppState>,
     Json(request): Json<ImagePruneRequest>,
 ) -> Result<Json<VmResponse>, (StatusCode, Json<ApiError>)> {
-    match image::prune(&state.config, request.all, request.force, true).await {
-        Ok(_) => {
-            info!("Successfully pruned images");
+    let older_than = match request.older_than.as_deref() {
+        Some(d) => Some(crate::util::parse_duration_secs(d).ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ApiError {
+                    error: "Invalid older_than".to_string(),
+                    code: "INVALID_DURATION".to_string(),
+                    details: Some(serde_json::json!({
+                        "message": format!("'{}' is not a duration like 7d, 12h or 30m", d)
+                    })),
+                }),
+            )
+        })?),
+        None => None,
+    };
+    let config = state.config.clone();
+    let span = tracing::Span::current();
+    let pruned = tokio::task::spawn_blocking(move || {
+        span.in_scope(|| image::prune_images(&config, request.all, older_than, request.force))
+    })
+    .await
+    .map_err(|e| crate::error::Error::Other(e.to_string()))
+    .and_then(|r| r);
+    match pruned {
+        Ok(report) => {
+            info!("{}", report.message);
             Ok(Json(VmResponse {
                 success: true,
-                message: "Successfully pruned images".to_string(),
+                message: report.message,
                 vm: None,
             }))
         }
         Err(e) => {
             error!("Failed to prune images: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError {
-                    error: "Failed to prune images".to_string(),
-                    code: "IMAGE_PRUNE_ERROR".to_string(),
-                    details: Some(serde_json::json!({"message": e.to_string()})),
-                }),
-            ))
+            Err(e.api_error("Failed to prune images", "IMAGE_PRUNE_ERROR"))
         }
     }
 }
@@ -749,9 +1151,12 @@ pub async fn prune_images(
     post,
     path = "/api/v1/images/run",
     request_body = ImageRunRequest,
+    params(("async" = Option<bool>, Query, description = "Run in the background and answer 202 with a job")),
     responses(
         (status = 201, description = "VM created and optionally started from image", body = VmResponse),
+        (status = 202, description = "Accepted as a background job", body = Job),
         (status = 400, description = "Bad request", body = ApiError),
+        (status = 429, description = "Too many concurrent operations of this kind", body = ApiError),
         (status = 500, description = "Internal server error", body = ApiError)
     ),
     tag = "Images"
@@ -760,13 +1165,51 @@ pub async fn run_from_image(
     State(state): State<AppState>,
     Json(request): Json<ImageRunRequest>,
 ) -> Response {
+    let egress = match resolve_egress(&state.config, request.egress.as_deref()) {
+        Ok(egress) => egress,
+        Err(e) => return e.into_response(),
+    };
+    let egress_interface = match resolve_egress_interface(request.egress_interface.as_deref()) {
+        Ok(egress_interface) => egress_interface,
+        Err(e) => return e.into_response(),
+    };
+    let rng = match resolve_rng(request.rng.as_deref()) {
+        Ok(rng) => rng,
+        Err(e) => return e.into_response(),
+    };
+    let ttl = match resolve_ttl(request.ttl.as_deref()) {
+        Ok(ttl) => ttl,
+        Err(e) => return e.into_response(),
+    };
+    let env = match resolve_env(&request.env, &request.secret_env) {
+        Ok(env) => env,
+        Err(e) => return e.into_response(),
+    };
+    let secrets = match resolve_secrets(&request.secrets) {
+        Ok(secrets) => secrets,
+        Err(e) => return e.into_response(),
+    };
+    let data_disk =
+        match resolve_data_disk(request.data_disk.as_deref(), request.data_mount.as_deref()) {
+            Ok(data_disk) => data_disk,
+            Err(e) => return e.into_response(),
+        };
     let resources = vm::VmResources::from_config_with_overrides(
         &state.config,
         request.memory.as_deref(),
         request.cpus,
         request.disk.as_deref(),
         request.devices.clone(),
-    );
+    )
+    .with_storage(request.storage.clone())
+    .with_isolation(request.isolate)
+    .with_egress(egress)
+    .with_egress_interface(egress_interface)
+    .with_rng(rng)
+    .with_immutable_root(request.immutable_root)
+    .with_data_disk(data_disk)
+    .with_env(env)
+    .with_secrets(secrets);
 
     // Admission control: strict no-overcommit. If the host can't take
     // another VM of this size we return 503 + Retry-After instead of
@@ -782,12 +1225,12 @@ pub async fn run_from_image(
         Ok(c) => c,
         Err(e) => {
             error!("Failed to read committed resources: {e}");
-            return api_error_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to read committed resources",
-                "ADMISSION_PROBE_ERROR",
-                Some(serde_json::json!({"message": e.to_string()})),
-            );
+            return e
+                .api_error(
+                    "Failed to read committed resources",
+                    "ADMISSION_PROBE_ERROR",
+                )
+                .into_response();
         }
     };
     // Atomic try_reserve sees other concurrent handlers' in-flight
@@ -807,21 +1250,26 @@ pub async fn run_from_image(
         }
     };
 
+    let cold = request.no_start || resources.needs_cold_boot(&state.config);
     let options = image::RunOptions {
         vm_name: request.name.as_deref(),
         registry: request.registry.as_deref(),
         org: request.org.as_deref(),
         user_data_path: request.user_data.as_deref(),
+        ssh_keys: &request.ssh_keys,
+        labels: &request.labels,
         no_start: request.no_start,
         resources,
+        ttl,
     };
 
     // The CLI's `meda run` defaults to the snapshot/restore fast path
     // (~120ms return, ~1.3s sshd) and only falls back to cold-boot
     // cloud-init when `--no-start` is passed (snapshot/restore implies
-    // running, so there's nothing to "not start"). Mirror that here so
-    // API consumers get the same speed without an extra endpoint.
-    let result = if request.no_start {
+    // running, so there's nothing to "not start") or the VM needs disks
+    // a template clone can't have. Mirror that here so API consumers
+    // get the same speed without an extra endpoint.
+    let result = if cold {
         image::run_from_image(&state.config, &request.image, options, true)
             .await
             .map(|_| serde_json::Value::Null)
@@ -852,33 +1300,12 @@ pub async fn run_from_image(
         }
         Err(e) => {
             error!("Failed to run VM from image: {}", e);
-            api_error_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to run VM from image",
-                "IMAGE_RUN_ERROR",
-                Some(serde_json::json!({"message": e.to_string()})),
-            )
+            e.api_error("Failed to run VM from image", "IMAGE_RUN_ERROR")
+                .into_response()
         }
     }
 }
 
-fn api_error_response(
-    status: StatusCode,
-    error: &str,
-    code: &str,
-    details: Option<serde_json::Value>,
-) -> Response {
-    (
-        status,
-        Json(ApiError {
-            error: error.to_string(),
-            code: code.to_string(),
-            details,
-        }),
-    )
-        .into_response()
-}
-
 /// Build a 503 response for an admission denial, including a
 /// `Retry-After: 10` header so polite clients can back off rather than
 /// hammer the host. The body uses the existing `ApiError` shape so
@@ -899,19 +1326,62 @@ fn admission_denied_response(denied: &AdmissionDenied) -> Response {
 /// don't pressure host RAM). Disk counts everything on-disk — qcow2
 /// overlays grow until deletion, even stopped VMs occupy real bytes.
 async fn current_committed(config: &crate::config::Config) -> crate::error::Result<Committed> {
-    let vms = get_vm_list(config).await?;
-    let mut c = Committed::default();
-    for v in vms {
-        let mem_gb = admission::parse_size_gb(&v.memory);
-        let disk_gb = admission::parse_size_gb(&v.disk);
-        let cpu: u32 = v.vcpus.trim().parse().unwrap_or(0);
-        c.disk_gb = c.disk_gb.saturating_add(disk_gb);
-        if v.state == "running" {
-            c.mem_gb = c.mem_gb.saturating_add(mem_gb);
-            c.cpu = c.cpu.saturating_add(cpu);
-        }
-    }
-    Ok(c)
+    Ok(crate::host_capacity::committed(
+        &vm::collect_vms_with_templates(config)?,
+        None,
+    ))
+}
+
+/// `GET /api/v1/system` — host facts for schedulers: CPUs, memory and
+/// disk as the kernel reports them now, KVM, and VM counts.
+#[utoipa::path(
+    get,
+    path = "/api/v1/system",
+    responses(
+        (status = 200, description = "Host system information", body = crate::system_info::SystemInfo),
+        (status = 500, description = "Internal server error", body = ApiError)
+    ),
+    tag = "System"
+)]
+pub async fn get_system_info(
+    State(state): State<AppState>,
+) -> Result<Json<crate::system_info::SystemInfo>, (StatusCode, Json<ApiError>)> {
+    let config = state.config.clone();
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || span.in_scope(|| crate::system_info::collect(&config)))
+        .await
+        .map_err(|e| crate::error::Error::Other(e.to_string()))
+        .and_then(|info| info)
+        .map(Json)
+        .map_err(|e| e.api_error("Failed to read system information", "SYSTEM_INFO_ERROR"))
+}
+
+/// `GET /api/v1/jobs/{id}` — progress of a request run with
+/// `?async=true`, and its response once it finished.
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs/{id}",
+    params(("id" = String, Path, description = "Job ID")),
+    responses(
+        (status = 200, description = "Job status", body = Job),
+        (status = 404, description = "Job not found", body = ApiError)
+    ),
+    tag = "System"
+)]
+pub async fn get_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Job>, (StatusCode, Json<ApiError>)> {
+    state.jobs.get(&id).map(Json).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            Json(ApiError {
+                error: format!("Job '{}' not found", id),
+                code: "JOB_NOT_FOUND".to_string(),
+                details: None,
+            }),
+        )
+    })
 }
 
 /// `GET /api/v1/capacity` — what's the host's admission budget vs.
@@ -929,16 +1399,9 @@ async fn current_committed(config: &crate::config::Config) -> crate::error::Resu
 pub async fn get_capacity(
     State(state): State<AppState>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, Json<ApiError>)> {
-    let committed = current_committed(&state.config).await.map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ApiError {
-                error: "Failed to read committed resources".to_string(),
-                code: "CAPACITY_PROBE_ERROR".to_string(),
-                details: Some(serde_json::json!({"message": e.to_string()})),
-            }),
-        )
-    })?;
+    let committed = current_committed(&state.config)
+        .await
+        .map_err(|e| e.api_error("Failed to read committed resources", "CAPACITY_PROBE_ERROR"))?;
     let b = &state.admission.budget;
     // `committed` from disk + the admission's in-flight reservations
     // gives the actual capacity picture. Without summing in-flight,
@@ -946,6 +1409,8 @@ pub async fn get_capacity(
     // free slots that are about to be filled by spawns still inside
     // their reservation window).
     let in_flight = state.admission.in_flight();
+    let subnets = crate::subnets::usage(&state.config)
+        .map_err(|e| e.api_error("Failed to read subnet leases", "CAPACITY_PROBE_ERROR"))?;
     let effective_committed = Committed {
         mem_gb: committed.mem_gb.saturating_add(in_flight.mem_gb),
         cpu: committed.cpu.saturating_add(in_flight.cpu),
@@ -976,10 +1441,24 @@ pub async fn get_capacity(
             "mem_gb": b.mem_available_gb(effective_committed.mem_gb),
             "cpu":    b.cpu_available(effective_committed.cpu),
             "disk_gb": b.disk_available_gb(effective_committed.disk_gb),
-        }
+        },
+        "subnets": subnets,
+        "operations": operations(&state),
     })))
 }
 
+/// Running and maximum concurrent creates / pulls / pushes (max 0 =
+/// unlimited).
+fn operations(state: &AppState) -> serde_json::Value {
+    let (creates, pulls, pushes) = state.limits.running();
+    let (max_creates, max_pulls, max_pushes) = state.limits.maximums();
+    serde_json::json!({
+        "creates": {"running": creates, "max": max_creates},
+        "pulls":   {"running": pulls,   "max": max_pulls},
+        "pushes":  {"running": pushes,  "max": max_pushes},
+    })
+}
+
 /// Extract the {vm, host} portion of a `run_instant_capture` summary
 /// into the API's `VmInfo` shape so HTTP callers get the routable IP
 /// without a follow-up `GET /vms/{name}`. Returns `None` for the
@@ -998,6 +1477,9 @@ fn vm_info_from_run_summary(summary: &serde_json::Value) -> Option<VmInfo> {
         disk: String::new(),
         devices: Vec::new(),
         created: String::new(),
+        labels: Default::default(),
+        env: Default::default(),
+        storage: String::new(),
     })
 }
 
@@ -1018,164 +1500,63 @@ pub async fn health_check() -> Json<HealthResponse> {
     })
 }
 
-// Helper functions to get data without JSON printing
-async fn get_vm_list(config: &crate::config::Config) -> crate::error::Result<Vec<VmInfo>> {
-    use std::fs;
-
-    config.ensure_dirs()?;
-
-    if !config.vm_root.exists() {
-        return Ok(vec![]);
-    }
-
-    let mut vms = Vec::new();
-
-    for entry in fs::read_dir(&config.vm_root)? {
-        let entry = entry?;
-        let path = entry.path();
-
-        if path.is_dir() {
-            let name = path.file_name().unwrap().to_string_lossy().to_string();
-            let state = if vm::check_vm_running(config, &name)? {
-                "running".to_string()
-            } else {
-                "stopped".to_string()
-            };
-
-            // Use the same priority as `meda list`/`meda ip`: prefer
-            // the host-routable netns IP, fall back to the legacy baked
-            // guest IP. cirun-agent (and any other API consumer) needs
-            // an IP it can SSH to from the host — the baked guest IP
-            // is reachable only from inside the VM's netns.
-            let ip = vm::get_routable_ip(config, &name).unwrap_or_else(|_| "N/A".to_string());
-
-            // Read stored resource information or fall back to defaults
-            let vm_dir = config.vm_dir(&name);
-            let vcpus = fs::read_to_string(vm_dir.join("cpus"))
-                .unwrap_or_else(|_| config.cpus.to_string())
-                .trim()
-                .to_string();
-            let memory = fs::read_to_string(vm_dir.join("memory"))
-                .unwrap_or_else(|_| config.mem.clone())
-                .trim()
-                .to_string();
-            let disk = fs::read_to_string(vm_dir.join("disk_size"))
-                .unwrap_or_else(|_| config.disk_size.clone())
-                .trim()
-                .to_string();
-            let devices = fs::read_to_string(vm_dir.join("devices"))
-                .map(|c| {
-                    c.lines()
-                        .filter(|l| !l.is_empty())
-                        .map(String::from)
-                        .collect()
-                })
-                .unwrap_or_default();
-
-            // Get creation time from directory metadata
-            let created = match fs::metadata(&path) {
-                Ok(metadata) => {
-                    if let Ok(created_time) = metadata.created() {
-                        if let Ok(since_epoch) = created_time.duration_since(std::time::UNIX_EPOCH)
-                        {
-                            crate::util::format_timestamp(since_epoch.as_secs())
-                        } else {
-                            "unknown".to_string()
-                        }
-                    } else {
-                        "unknown".to_string()
-                    }
-                }
-                Err(_) => "unknown".to_string(),
-            };
-
-            vms.push(VmInfo {
-                name,
-                state,
-                ip,
-                vcpus,
-                memory,
-                disk,
-                devices,
-                created,
-            });
+/// Prometheus / OpenMetrics scrape endpoint
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses(
+        (status = 200, description = "OpenMetrics text exposition", content_type = "application/openmetrics-text")
+    ),
+    tag = "System"
+)]
+pub async fn metrics(State(state): State<AppState>) -> Response {
+    // Collection shells out to `ps` per VM; keep it off the runtime.
+    let config = state.config.clone();
+    let span = tracing::Span::current();
+    match tokio::task::spawn_blocking(move || span.in_scope(|| crate::metrics::render(&config)))
+        .await
+    {
+        Ok(body) => (
+            [(
+                axum::http::header::CONTENT_TYPE,
+                HeaderValue::from_static(crate::metrics::CONTENT_TYPE),
+            )],
+            body,
+        )
+            .into_response(),
+        Err(e) => {
+            error!("metrics collection failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
-
-    Ok(vms)
 }
 
-async fn get_vm_details(
-    config: &crate::config::Config,
-    name: &str,
-) -> crate::error::Result<VmDetailResponse> {
-    let vm_dir = config.vm_dir(name);
-
-    if !vm_dir.exists() {
-        return Err(crate::error::Error::VmNotFound(name.to_string()));
-    }
-
-    let state = if vm::check_vm_running(config, name)? {
-        "running".to_string()
-    } else {
-        "stopped".to_string()
-    };
-
-    let ip = vm::get_routable_ip(config, name).ok();
-
-    // Collect additional details
-    let mut details = serde_json::Map::new();
-
-    // Add network info
-    if let Ok(subnet) = std::fs::read_to_string(vm_dir.join("subnet")) {
-        details.insert(
-            "subnet".to_string(),
-            serde_json::Value::String(subnet.trim().to_string()),
-        );
-    }
+/// `/metrics` under the versioned API prefix
+#[utoipa::path(
+    get,
+    path = "/api/v1/metrics",
+    responses(
+        (status = 200, description = "OpenMetrics text exposition", content_type = "application/openmetrics-text")
+    ),
+    tag = "System"
+)]
+pub async fn api_metrics(state: State<AppState>) -> Response {
+    metrics(state).await
+}
 
-    if let Ok(mac) = std::fs::read_to_string(vm_dir.join("mac")) {
-        details.insert(
-            "mac".to_string(),
-            serde_json::Value::String(mac.trim().to_string()),
-        );
+// Helper functions to get data without JSON printing
+/// Same selectors as `meda list --filter`, from `?label=a=b,c&state=running`.
+fn vm_list_filters(query: &VmListQuery) -> crate::error::Result<Vec<VmFilter>> {
+    let mut filters = Vec::new();
+    for selector in query.label.iter().flat_map(|l| l.split(',')) {
+        if !selector.is_empty() {
+            filters.push(format!("label={}", selector).parse()?);
+        }
     }
-
-    if let Ok(tap) = std::fs::read_to_string(vm_dir.join("tapdev")) {
-        details.insert(
-            "tap_device".to_string(),
-            serde_json::Value::String(tap.trim().to_string()),
-        );
+    if let Some(state) = &query.state {
+        filters.push(VmFilter::State(state.clone()));
     }
-
-    // Add VM resource info
-    let memory = std::fs::read_to_string(vm_dir.join("memory"))
-        .unwrap_or_else(|_| config.mem.clone())
-        .trim()
-        .to_string();
-    let disk_size = std::fs::read_to_string(vm_dir.join("disk_size"))
-        .unwrap_or_else(|_| config.disk_size.clone())
-        .trim()
-        .to_string();
-
-    details.insert("memory".to_string(), serde_json::Value::String(memory));
-    details.insert(
-        "disk_size".to_string(),
-        serde_json::Value::String(disk_size),
-    );
-
-    // Add VM directory path
-    details.insert(
-        "vm_dir".to_string(),
-        serde_json::Value::String(vm_dir.to_string_lossy().to_string()),
-    );
-
-    Ok(VmDetailResponse {
-        name: name.to_string(),
-        state,
-        ip,
-        details: Some(serde_json::Value::Object(details)),
-    })
+    Ok(filters)
 }
 
 #[cfg(test)]
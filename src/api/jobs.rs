@@ -0,0 +1,391 @@
+//! Background jobs for the long API operations (`?async=true`).
+//!
+//! Creating a VM can block a request for minutes while a base image
+//! downloads. With `?async=true`, `POST /api/v1/vms`, `/images/run`,
+//! `/images/pull` and `/images/push` answer 202 straight away with a
+//! job, and the request runs in the background: it waits for a slot
+//! under the concurrency limits instead of getting 429, and
+//! `GET /api/v1/jobs/{id}` reports its latest progress event and, once
+//! done, the status and body the request would have answered with.
+//!
+//! Jobs are kept as JSON files in `<config dir>/jobs`, so clients can
+//! poll again after the server restarts. A job still queued or running
+//! when the server stopped can't be resumed and comes back
+//! `interrupted`. Finished jobs are forgotten after a day, checked on
+//! start and whenever a new job is created.
+//!
+//! At most `MEDA_API_MAX_JOBS` (default 64, 0 for no limit) jobs can
+//! be queued or running at once; past that, `?async=true` requests get
+//! 429 like the synchronous ones that find no slot.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::{limits::Operation, models::Job, AppState};
+use crate::progress;
+use tracing::Instrument;
+
+pub const QUEUED: &str = "queued";
+pub const RUNNING: &str = "running";
+pub const SUCCEEDED: &str = "succeeded";
+pub const FAILED: &str = "failed";
+pub const INTERRUPTED: &str = "interrupted";
+
+/// How long finished jobs are kept.
+const RETENTION: chrono::Duration = chrono::Duration::days(1);
+
+/// Unfinished jobs allowed unless `MEDA_API_MAX_JOBS` says otherwise.
+const DEFAULT_MAX_JOBS: u64 = 64;
+
+/// Largest response body a job keeps.
+const MAX_RESULT: usize = 16 * 1024 * 1024;
+
+struct Entry {
+    job: Job,
+    progress: progress::Latest,
+}
+
+pub struct Jobs {
+    dir: PathBuf,
+    entries: Mutex<HashMap<String, Entry>>,
+    /// Most jobs queued or running at once; 0 for no limit.
+    max_unfinished: u64,
+}
+
+/// The job a background request belongs to, in its extensions.
+#[derive(Clone)]
+pub struct JobHandle {
+    id: String,
+    jobs: Arc<Jobs>,
+}
+
+impl JobHandle {
+    /// The request got its slot and is being handled.
+    pub fn started(&self) {
+        self.jobs
+            .update(&self.id, |job| job.status = RUNNING.to_string());
+    }
+}
+
+fn is_finished(job: &Job) -> bool {
+    job.status != QUEUED && job.status != RUNNING
+}
+
+fn is_expired(job: &Job, now: chrono::DateTime<Utc>) -> bool {
+    is_finished(job) && now - job.updated_at > RETENTION
+}
+
+impl Jobs {
+    /// The jobs kept in `dir`. Jobs left unfinished by a previous server
+    /// are marked interrupted; old finished ones are removed.
+    pub fn open(dir: PathBuf) -> Self {
+        let jobs = Self {
+            dir,
+            entries: Mutex::new(HashMap::new()),
+            max_unfinished: crate::admission::env_u64("MEDA_API_MAX_JOBS", DEFAULT_MAX_JOBS),
+        };
+        let now = Utc::now();
+        for path in fs::read_dir(&jobs.dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|e| e == "json"))
+        {
+            let Some(mut job) = fs::read_to_string(&path)
+                .ok()
+                .and_then(|body| serde_json::from_str::<Job>(&body).ok())
+            else {
+                continue;
+            };
+            if is_expired(&job, now) {
+                fs::remove_file(&path).ok();
+                continue;
+            }
+            if !is_finished(&job) {
+                job.status = INTERRUPTED.to_string();
+                job.updated_at = now;
+                jobs.save(&job);
+            }
+            jobs.insert(job);
+        }
+        jobs
+    }
+
+    fn insert(&self, job: Job) {
+        let entry = Entry {
+            job,
+            progress: Default::default(),
+        };
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.insert(entry.job.id.clone(), entry);
+        }
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn save(&self, job: &Job) {
+        let write = || -> crate::error::Result<()> {
+            fs::create_dir_all(&self.dir)?;
+            let path = self.path(&job.id);
+            let tmp = path.with_extension("json.tmp");
+            fs::write(&tmp, serde_json::to_string_pretty(job)?)?;
+            fs::rename(&tmp, &path)?;
+            Ok(())
+        };
+        if let Err(e) = write() {
+            log::warn!("Failed to save job {}: {}", job.id, e);
+        }
+    }
+
+    /// Drop the finished jobs past [`RETENTION`], with their files.
+    fn prune(&self) {
+        let now = Utc::now();
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        entries.retain(|id, entry| {
+            let expired = is_expired(&entry.job, now);
+            if expired {
+                fs::remove_file(self.path(id)).ok();
+            }
+            !expired
+        });
+    }
+
+    /// A new queued job for `operation`; `None` when as many jobs as
+    /// allowed are already queued or running.
+    fn create(self: &Arc<Self>, operation: String) -> Option<(JobHandle, Job)> {
+        self.prune();
+        let mut entries = self.entries.lock().ok()?;
+        let unfinished = entries.values().filter(|e| !is_finished(&e.job)).count() as u64;
+        if self.max_unfinished > 0 && unfinished >= self.max_unfinished {
+            return None;
+        }
+        let now = Utc::now();
+        let job = Job {
+            id: uuid::Uuid::new_v4().to_string(),
+            operation,
+            status: QUEUED.to_string(),
+            created_at: now,
+            updated_at: now,
+            progress: None,
+            result_status: None,
+            result: None,
+        };
+        self.save(&job);
+        entries.insert(
+            job.id.clone(),
+            Entry {
+                job: job.clone(),
+                progress: Default::default(),
+            },
+        );
+        let handle = JobHandle {
+            id: job.id.clone(),
+            jobs: self.clone(),
+        };
+        Some((handle, job))
+    }
+
+    fn progress(&self, id: &str) -> progress::Latest {
+        self.entries
+            .lock()
+            .ok()
+            .and_then(|entries| entries.get(id).map(|e| e.progress.clone()))
+            .unwrap_or_default()
+    }
+
+    fn update(&self, id: &str, change: impl FnOnce(&mut Job)) {
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        let Some(entry) = entries.get_mut(id) else {
+            return;
+        };
+        change(&mut entry.job);
+        entry.job.updated_at = Utc::now();
+        entry.job.progress = entry.progress.lock().ok().and_then(|p| p.clone());
+        self.save(&entry.job);
+    }
+
+    /// Job `id` as it is now, with its latest progress.
+    pub fn get(&self, id: &str) -> Option<Job> {
+        let entries = self.entries.lock().ok()?;
+        let entry = entries.get(id)?;
+        let mut job = entry.job.clone();
+        if let Some(progress) = entry.progress.lock().ok().and_then(|p| p.clone()) {
+            job.progress = Some(progress);
+        }
+        Some(job)
+    }
+}
+
+/// Whether the request asked to run in the background.
+fn wants_async(query: Option<&str>) -> bool {
+    query.unwrap_or_default().split('&').any(|pair| {
+        matches!(
+            pair.split_once('=').unwrap_or((pair, "true")),
+            ("async", "true" | "1")
+        )
+    })
+}
+
+/// Middleware: run `?async=true` requests for the long operations as
+/// jobs, answering 202 with the job. Sits outside `limit_operations`,
+/// which queues job requests rather than turning them away.
+pub async fn run_in_background(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if Operation::of(request.method(), request.uri().path()).is_none()
+        || !wants_async(request.uri().query())
+    {
+        return next.run(request).await;
+    }
+    let operation = format!("{} {}", request.method(), request.uri().path());
+    let Some((handle, job)) = state.jobs.create(operation) else {
+        log::warn!("rejecting background request: too many unfinished jobs");
+        return super::limits::too_many_requests(
+            "Too many queued or running jobs; retry later".to_string(),
+        );
+    };
+    log::info!("Job {}: {}", job.id, job.operation);
+    request.extensions_mut().insert(handle);
+
+    let jobs = state.jobs.clone();
+    let id = job.id.clone();
+    // The job's logs stay in the request's span.
+    tokio::spawn(
+        async move {
+            let response = progress::record(jobs.progress(&id), next.run(request)).await;
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), MAX_RESULT)
+                .await
+                .unwrap_or_default();
+            let result = serde_json::from_slice(&body).unwrap_or_else(|_| {
+                serde_json::Value::String(String::from_utf8_lossy(&body).into())
+            });
+            let outcome = if status.is_success() {
+                SUCCEEDED
+            } else {
+                FAILED
+            };
+            log::info!("Job {} {} ({})", id, outcome, status);
+            jobs.update(&id, |job| {
+                job.status = outcome.to_string();
+                job.result_status = Some(status.as_u16());
+                job.result = Some(result);
+            });
+        }
+        .in_current_span(),
+    );
+
+    let location = format!("/api/v1/jobs/{}", job.id);
+    (
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, location)],
+        Json(job),
+    )
+        .into_response()
+}
+
+/// Jobs directory of a server whose config dir is `ch_home`.
+pub fn dir(ch_home: &Path) -> PathBuf {
+    ch_home.join("jobs")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_wants_async() {
+        assert!(wants_async(Some("async=true")));
+        assert!(wants_async(Some("force=1&async=1")));
+        assert!(wants_async(Some("async")));
+        assert!(!wants_async(Some("async=false")));
+        assert!(!wants_async(Some("asynchronous=true")));
+        assert!(!wants_async(None));
+    }
+
+    #[test]
+    fn test_jobs_survive_restart() {
+        let dir = TempDir::new().unwrap();
+        let jobs = Arc::new(Jobs::open(dir.path().to_path_buf()));
+        let (running, _) = jobs.create("POST /api/v1/vms".to_string()).unwrap();
+        running.started();
+        let (done, _) = jobs.create("POST /api/v1/images/pull".to_string()).unwrap();
+        jobs.update(&done.id, |job| {
+            job.status = SUCCEEDED.to_string();
+            job.result_status = Some(200);
+        });
+        assert_eq!(jobs.get(&running.id).unwrap().status, RUNNING);
+
+        // An old finished job is dropped on the next start.
+        let (old, _) = jobs.create("POST /api/v1/images/push".to_string()).unwrap();
+        jobs.update(&old.id, |job| job.status = FAILED.to_string());
+        let mut stale = jobs.get(&old.id).unwrap();
+        stale.updated_at -= RETENTION + chrono::Duration::minutes(1);
+        jobs.save(&stale);
+
+        let reopened = Jobs::open(dir.path().to_path_buf());
+        assert_eq!(reopened.get(&running.id).unwrap().status, INTERRUPTED);
+        let done = reopened.get(&done.id).unwrap();
+        assert_eq!(done.status, SUCCEEDED);
+        assert_eq!(done.result_status, Some(200));
+        assert!(reopened.get(&old.id).is_none());
+        assert!(!jobs.path(&old.id).exists());
+    }
+
+    #[test]
+    fn test_jobs_pruned_while_running() {
+        let dir = TempDir::new().unwrap();
+        let jobs = Arc::new(Jobs::open(dir.path().to_path_buf()));
+        let (old, _) = jobs.create("POST /api/v1/images/pull".to_string()).unwrap();
+        jobs.update(&old.id, |job| job.status = SUCCEEDED.to_string());
+        let (queued, _) = jobs.create("POST /api/v1/vms".to_string()).unwrap();
+        for id in [&old.id, &queued.id] {
+            jobs.entries
+                .lock()
+                .unwrap()
+                .get_mut(id)
+                .unwrap()
+                .job
+                .updated_at -= RETENTION + chrono::Duration::minutes(1);
+        }
+
+        jobs.create("POST /api/v1/vms".to_string()).unwrap();
+        assert!(jobs.get(&old.id).is_none());
+        assert!(!jobs.path(&old.id).exists());
+        // Unfinished jobs stay, however old.
+        assert_eq!(jobs.get(&queued.id).unwrap().status, QUEUED);
+    }
+
+    #[test]
+    fn test_unfinished_job_cap() {
+        let dir = TempDir::new().unwrap();
+        let mut jobs = Jobs::open(dir.path().to_path_buf());
+        jobs.max_unfinished = 2;
+        let jobs = Arc::new(jobs);
+        let (first, _) = jobs.create("POST /api/v1/vms".to_string()).unwrap();
+        jobs.create("POST /api/v1/vms".to_string()).unwrap();
+        assert!(jobs.create("POST /api/v1/vms".to_string()).is_none());
+
+        jobs.update(&first.id, |job| job.status = SUCCEEDED.to_string());
+        assert!(jobs.create("POST /api/v1/vms".to_string()).is_some());
+    }
+}
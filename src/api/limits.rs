@@ -0,0 +1,276 @@
+//! Concurrency limits for the expensive API operations.
+//!
+//! VM creation, image pulls and image pushes each get a semaphore so a
+//! burst of CI jobs can't start more of them at once than a small host
+//! can take. Limits come from `MEDA_API_MAX_CREATES` (default 4),
+//! `MEDA_API_MAX_PULLS` and `MEDA_API_MAX_PUSHES` (default 2 each);
+//! 0 disables a limit.
+//!
+//! A request that finds its operation at the limit waits up to
+//! `MEDA_API_QUEUE_WAIT_SECS` (default 0) for a slot, then gets 429
+//! with `Retry-After`. Unlike admission (503), this is not "the host is
+//! full" but "come back in a moment".
+//!
+//! Requests run in the background with `?async=true` queue for a slot
+//! for as long as it takes instead; how many may be queued or running
+//! at once is capped by `MEDA_API_MAX_JOBS` (see `jobs`).
+//!
+//! Running operations are counted whether limited or not and reported
+//! by `GET /api/v1/capacity` (and the web UI's jobs panel).
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::{jobs::JobHandle, models::ApiError, AppState};
+use crate::admission::env_u64;
+
+const RETRY_AFTER_SECS: &str = "5";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Create,
+    Pull,
+    Push,
+}
+
+impl Operation {
+    /// Which limited operation (if any) a request performs.
+    pub(super) fn of(method: &Method, path: &str) -> Option<Self> {
+        if method != Method::POST {
+            return None;
+        }
+        match path {
+            "/api/v1/vms" | "/api/v1/images/run" => Some(Self::Create),
+            "/api/v1/images/pull" => Some(Self::Pull),
+            "/api/v1/images/push" => Some(Self::Push),
+            _ => None,
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Self::Create => 0,
+            Self::Pull => 1,
+            Self::Push => 2,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Create => "VM creations",
+            Self::Pull => "image pulls",
+            Self::Push => "image pushes",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct OpLimits {
+    create: Option<Arc<Semaphore>>,
+    pull: Option<Arc<Semaphore>>,
+    push: Option<Arc<Semaphore>>,
+    max: [u64; 3],
+    running: [AtomicU64; 3],
+    queue_wait: Duration,
+}
+
+/// Counts an operation as running until dropped.
+struct Running<'a>(&'a AtomicU64);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl OpLimits {
+    pub fn new(max_creates: u64, max_pulls: u64, max_pushes: u64, queue_wait: Duration) -> Self {
+        let sem = |n: u64| (n > 0).then(|| Arc::new(Semaphore::new(n as usize)));
+        Self {
+            create: sem(max_creates),
+            pull: sem(max_pulls),
+            push: sem(max_pushes),
+            max: [max_creates, max_pulls, max_pushes],
+            running: Default::default(),
+            queue_wait,
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(
+            env_u64("MEDA_API_MAX_CREATES", 4),
+            env_u64("MEDA_API_MAX_PULLS", 2),
+            env_u64("MEDA_API_MAX_PUSHES", 2),
+            Duration::from_secs(env_u64("MEDA_API_QUEUE_WAIT_SECS", 0)),
+        )
+    }
+
+    /// `(creates, pulls, pushes)`; 0 means unlimited.
+    pub fn maximums(&self) -> (u64, u64, u64) {
+        (self.max[0], self.max[1], self.max[2])
+    }
+
+    /// `(creates, pulls, pushes)` currently being handled.
+    pub fn running(&self) -> (u64, u64, u64) {
+        let [c, pl, ps] = &self.running;
+        (
+            c.load(Ordering::Relaxed),
+            pl.load(Ordering::Relaxed),
+            ps.load(Ordering::Relaxed),
+        )
+    }
+
+    fn track(&self, op: Operation) -> Running<'_> {
+        let counter = &self.running[op.index()];
+        counter.fetch_add(1, Ordering::Relaxed);
+        Running(counter)
+    }
+
+    fn semaphore(&self, op: Operation) -> Option<&Arc<Semaphore>> {
+        match op {
+            Operation::Create => self.create.as_ref(),
+            Operation::Pull => self.pull.as_ref(),
+            Operation::Push => self.push.as_ref(),
+        }
+    }
+
+    /// Take a slot for `op`, waiting up to the queue timeout. `Ok(None)`
+    /// means the operation is unlimited; `Err(())` means no slot freed up.
+    pub async fn acquire(&self, op: Operation) -> Result<Option<OwnedSemaphorePermit>, ()> {
+        let Some(sem) = self.semaphore(op) else {
+            return Ok(None);
+        };
+        if let Ok(permit) = sem.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+        if self.queue_wait.is_zero() {
+            return Err(());
+        }
+        match tokio::time::timeout(self.queue_wait, sem.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(Some(permit)),
+            _ => Err(()),
+        }
+    }
+
+    /// Take a slot for `op`, however long that takes.
+    pub async fn wait(&self, op: Operation) -> Option<OwnedSemaphorePermit> {
+        let sem = self.semaphore(op)?;
+        sem.clone().acquire_owned().await.ok()
+    }
+}
+
+/// Middleware: hold a slot of the request's operation for as long as
+/// the handler runs; 429 when none is available. Background requests
+/// (see `jobs`) wait for one instead.
+pub async fn limit_operations(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(op) = Operation::of(request.method(), request.uri().path()) else {
+        return next.run(request).await;
+    };
+    if let Some(job) = request.extensions().get::<JobHandle>().cloned() {
+        let _permit = state.limits.wait(op).await;
+        let _running = state.limits.track(op);
+        job.started();
+        return next.run(request).await;
+    }
+    match state.limits.acquire(op).await {
+        Ok(_permit) => {
+            let _running = state.limits.track(op);
+            next.run(request).await
+        }
+        Err(()) => {
+            log::warn!("rejecting request: too many concurrent {}", op.as_str());
+            too_many_requests(format!("Too many concurrent {}; retry later", op.as_str()))
+        }
+    }
+}
+
+/// 429 with `Retry-After`.
+pub(super) fn too_many_requests(error: String) -> Response {
+    let mut response = (
+        StatusCode::TOO_MANY_REQUESTS,
+        Json(ApiError {
+            error,
+            code: "TOO_MANY_REQUESTS".to_string(),
+            details: None,
+        }),
+    )
+        .into_response();
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::from_static(RETRY_AFTER_SECS),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operation_of() {
+        assert_eq!(
+            Operation::of(&Method::POST, "/api/v1/vms"),
+            Some(Operation::Create)
+        );
+        assert_eq!(
+            Operation::of(&Method::POST, "/api/v1/images/run"),
+            Some(Operation::Create)
+        );
+        assert_eq!(
+            Operation::of(&Method::POST, "/api/v1/images/pull"),
+            Some(Operation::Pull)
+        );
+        assert_eq!(Operation::of(&Method::GET, "/api/v1/vms"), None);
+        assert_eq!(Operation::of(&Method::POST, "/api/v1/vms/a/start"), None);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_limits() {
+        let limits = OpLimits::new(1, 0, 1, Duration::ZERO);
+        let first = limits.acquire(Operation::Create).await.unwrap();
+        assert!(first.is_some());
+        assert!(limits.acquire(Operation::Create).await.is_err());
+        // Unlimited operations never block.
+        assert!(limits.acquire(Operation::Pull).await.unwrap().is_none());
+        // Limits are per operation.
+        assert!(limits.acquire(Operation::Push).await.is_ok());
+        drop(first);
+        assert!(limits.acquire(Operation::Create).await.is_ok());
+    }
+
+    #[test]
+    fn test_running() {
+        let limits = OpLimits::new(1, 0, 1, Duration::ZERO);
+        let create = limits.track(Operation::Create);
+        let pulls = [limits.track(Operation::Pull), limits.track(Operation::Pull)];
+        assert_eq!(limits.running(), (1, 2, 0));
+        drop(create);
+        drop(pulls);
+        assert_eq!(limits.running(), (0, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_acquire_queues() {
+        let limits = Arc::new(OpLimits::new(1, 1, 1, Duration::from_secs(5)));
+        let held = limits.acquire(Operation::Pull).await.unwrap();
+        let waiter = {
+            let limits = limits.clone();
+            tokio::spawn(async move { limits.acquire(Operation::Pull).await.is_ok() })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(held);
+        assert!(waiter.await.unwrap());
+    }
+}
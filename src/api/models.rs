@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
-use utoipa::ToSchema;
+use std::collections::BTreeMap;
+use utoipa::{IntoParams, ToSchema};
 
 /// Request to create a new VM
 #[derive(Debug, Deserialize, ToSchema)]
@@ -8,6 +9,40 @@ pub struct VmCreateRequest {
     pub name: String,
     /// Path to user-data file (optional)
     pub user_data: Option<String>,
+    /// Extra SSH public keys for the `cirun` user (key strings or paths on
+    /// the server); disables password login
+    #[serde(default)]
+    pub ssh_keys: Vec<String>,
+    /// Labels recorded on the VM, e.g. `{"ci": "true"}`
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Environment variables for the guest, in /etc/meda/env
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Like `env`, but in /etc/meda/secret-env, readable by root only,
+    /// and shown as `<redacted>` when listing the VM
+    #[serde(default)]
+    pub secret_env: BTreeMap<String, String>,
+    /// Server files to copy into the guest, as "/host/path:/guest/path[:MODE[:OWNER]]"
+    #[serde(default)]
+    pub secrets: Vec<String>,
+    /// Storage pool for the VM dir (optional; see MEDA_STORAGE_POOLS)
+    pub storage: Option<String>,
+    /// Block traffic to and from other VMs
+    #[serde(default)]
+    pub isolate: bool,
+    /// Outbound policy (e.g. "allow:10.0.5.0/24,deny:10.0.0.0/8") or a
+    /// named policy from the server's egress-policies file
+    pub egress: Option<String>,
+    /// Host interface to pin the VM's outbound NAT to (e.g. "eth1")
+    pub egress_interface: Option<String>,
+    /// Entropy source of the virtio-rng device (e.g. "/dev/hwrng"), or
+    /// "none"; defaults to the server's MEDA_RNG_SOURCE
+    pub rng: Option<String>,
+    /// "nat" (default), "bridged" or "user" (no sudo, networked through passt)
+    pub network: Option<String>,
+    /// Existing host bridge to attach the VM to when `network` is "bridged"
+    pub bridge: Option<String>,
     /// Force create (delete if exists)
     #[serde(default)]
     pub force: bool,
@@ -15,11 +50,51 @@ pub struct VmCreateRequest {
     pub memory: Option<String>,
     /// Number of CPUs
     pub cpus: Option<u8>,
+    /// Back guest memory with a shared mapping
+    #[serde(default)]
+    pub shared_memory: bool,
+    /// Back guest memory with the host's hugepage pool
+    #[serde(default)]
+    pub hugepages: bool,
+    /// Fault in all guest memory at boot
+    #[serde(default)]
+    pub prefault: bool,
+    /// Add a balloon device that returns freed guest memory to the host
+    #[serde(default)]
+    pub balloon: bool,
+    /// Serve cloud-init data over HTTP instead of attaching a seed ISO
+    #[serde(default)]
+    pub no_iso: bool,
+    /// Host CPUs to pin the VM to (e.g. "0-3" or "2,4-7")
+    pub cpu_affinity: Option<String>,
+    /// Boot with room for this many vCPUs, for hotplug
+    pub max_cpus: Option<u8>,
+    /// Boot with room for this much memory (e.g. 16G), for hotplug
+    pub max_memory: Option<String>,
     /// Disk size (e.g., 10G, 20G, 5120M)
     pub disk: Option<String>,
     /// VFIO device paths for PCI passthrough
     #[serde(default)]
     pub devices: Vec<String>,
+    /// Reset the root disk to the image on every start
+    #[serde(default)]
+    pub immutable_root: bool,
+    /// Size of a persistent data disk to attach (e.g., 20G)
+    pub data_disk: Option<String>,
+    /// Guest mount point of the data disk (default /data)
+    pub data_mount: Option<String>,
+    /// Host directories to share over virtiofs, as "/host/path:/guest/path[:ro]"
+    #[serde(default)]
+    pub mounts: Vec<String>,
+    /// Add a vsock device for a guest agent
+    #[serde(default)]
+    pub vsock: bool,
+    /// Server-side path of a kernel to boot directly instead of the image's
+    pub kernel: Option<String>,
+    /// Server-side path of an initramfs to load with `kernel`
+    pub initramfs: Option<String>,
+    /// Kernel command line for `kernel` (default "console=ttyS0 root=/dev/vda1 rw")
+    pub cmdline: Option<String>,
 }
 
 /// VM response information
@@ -38,7 +113,7 @@ pub struct VmResponse {
 pub struct VmInfo {
     /// VM name
     pub name: String,
-    /// VM state (running, stopped)
+    /// VM state: creating, stopped, starting, running, stopping or error
     pub state: String,
     /// VM IP address
     pub ip: String,
@@ -52,6 +127,46 @@ pub struct VmInfo {
     pub devices: Vec<String>,
     /// Creation time
     pub created: String,
+    /// Labels set at create/run time
+    pub labels: BTreeMap<String, String>,
+    /// Guest environment variables, secret values shown as `<redacted>`
+    pub env: BTreeMap<String, String>,
+    /// Storage pool the VM dir lives on
+    pub storage: String,
+}
+
+/// Filters for `GET /api/v1/vms`; all given filters must match
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct VmListQuery {
+    /// Comma-separated label selectors: `key=value` or bare `key`
+    pub label: Option<String>,
+    /// VM state, e.g. `running`, `stopped` or `error`
+    pub state: Option<String>,
+}
+
+/// Options for `POST /api/v1/vms/{name}/stop` and `/restart`
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct VmStopQuery {
+    /// Seconds to wait for the guest to power off before killing it
+    /// (default `MEDA_STOP_TIMEOUT`, or 30)
+    pub timeout: Option<u64>,
+    /// Kill the VM without pressing its ACPI power button
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Options for `GET /api/v1/vms/{name}/logs`
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct VmLogsQuery {
+    /// Number of trailing lines to return (default 200)
+    pub lines: Option<usize>,
+}
+
+/// Options for `GET /api/v1/vms/{name}/serial`
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct VmSerialQuery {
+    /// Number of trailing lines to return (default 200)
+    pub tail: Option<usize>,
 }
 
 /// VM list response
@@ -107,6 +222,8 @@ pub struct ImageInfo {
     pub size: String,
     /// Creation timestamp
     pub created: String,
+    /// Registry manifest digest, when known
+    pub digest: Option<String>,
 }
 
 /// Request to create a new image
@@ -123,6 +240,8 @@ pub struct ImageCreateRequest {
     pub org: Option<String>,
     /// Create from existing VM instead of base image
     pub from_vm: Option<String>,
+    /// Copy a running `from_vm` without stopping it: `crash` or `freeze`
+    pub live: Option<crate::image::LiveMode>,
 }
 
 /// Request to pull an image
@@ -134,6 +253,9 @@ pub struct ImagePullRequest {
     pub registry: Option<String>,
     /// Organization/namespace (optional)
     pub org: Option<String>,
+    /// Only fetch these artifacts, e.g. `["base_image"]` (optional)
+    #[serde(default)]
+    pub artifacts: Vec<String>,
 }
 
 /// Request to push an image
@@ -148,15 +270,41 @@ pub struct ImagePushRequest {
     /// Dry run - don't actually push
     #[serde(default)]
     pub dry_run: bool,
+    /// Annotate the image with its provenance (source VM, base image
+    /// digest, build time, git commit)
+    #[serde(default)]
+    pub provenance: bool,
+    /// Sign the pushed image with cosign and attach the signature
+    #[serde(default)]
+    pub sign: bool,
+    /// More tags for the pushed image in the same repository
+    #[serde(default)]
+    pub also_tags: Vec<String>,
+}
+
+/// Request to tag an image
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ImageTagRequest {
+    /// Existing image name with tag
+    pub source: String,
+    /// New image name with tag
+    pub target: String,
+    /// Registry URL (optional)
+    pub registry: Option<String>,
+    /// Organization/namespace (optional)
+    pub org: Option<String>,
 }
 
 /// Request to prune images
 #[derive(Debug, Deserialize, ToSchema)]
 pub struct ImagePruneRequest {
-    /// Remove all images (not just unused ones)
+    /// Remove all images, including ones VMs were created from
     #[serde(default)]
     pub all: bool,
-    /// Don't prompt for confirmation
+    /// Only remove images at least this old (e.g. "7d", "12h")
+    #[serde(default)]
+    pub older_than: Option<String>,
+    /// Remove the images; without it, only report what would go
     #[serde(default)]
     pub force: bool,
 }
@@ -174,6 +322,36 @@ pub struct ImageRunRequest {
     pub org: Option<String>,
     /// Path to user-data file (optional)
     pub user_data: Option<String>,
+    /// Extra SSH public keys for the `cirun` user (key strings or paths on
+    /// the server); disables password login
+    #[serde(default)]
+    pub ssh_keys: Vec<String>,
+    /// Labels recorded on the VM, e.g. `{"ci": "true"}`
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Environment variables for the guest, in /etc/meda/env
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Like `env`, but in /etc/meda/secret-env, readable by root only,
+    /// and shown as `<redacted>` when listing the VM
+    #[serde(default)]
+    pub secret_env: BTreeMap<String, String>,
+    /// Server files to copy into the guest, as "/host/path:/guest/path[:MODE[:OWNER]]"
+    #[serde(default)]
+    pub secrets: Vec<String>,
+    /// Storage pool for the VM dir (optional; see MEDA_STORAGE_POOLS)
+    pub storage: Option<String>,
+    /// Block traffic to and from other VMs
+    #[serde(default)]
+    pub isolate: bool,
+    /// Outbound policy (e.g. "allow:10.0.5.0/24,deny:10.0.0.0/8") or a
+    /// named policy from the server's egress-policies file
+    pub egress: Option<String>,
+    /// Host interface to pin the VM's outbound NAT to (e.g. "eth1")
+    pub egress_interface: Option<String>,
+    /// Entropy source of the virtio-rng device (e.g. "/dev/hwrng"), or
+    /// "none"; defaults to the server's MEDA_RNG_SOURCE
+    pub rng: Option<String>,
     /// Don't start the VM, just create it
     #[serde(default)]
     pub no_start: bool,
@@ -186,6 +364,15 @@ pub struct ImageRunRequest {
     /// VFIO device paths for PCI passthrough
     #[serde(default)]
     pub devices: Vec<String>,
+    /// Reset the root disk to the image on every start
+    #[serde(default)]
+    pub immutable_root: bool,
+    /// Size of a persistent data disk to attach (e.g., 20G)
+    pub data_disk: Option<String>,
+    /// Guest mount point of the data disk (default /data)
+    pub data_mount: Option<String>,
+    /// Delete the VM this long after creation (e.g., 90m, 12h)
+    pub ttl: Option<String>,
 }
 
 /// Generic API error response
@@ -199,6 +386,29 @@ pub struct ApiError {
     pub details: Option<serde_json::Value>,
 }
 
+/// A request run in the background (`?async=true`)
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Job {
+    /// Job ID
+    pub id: String,
+    /// The request, e.g. "POST /api/v1/vms"
+    pub operation: String,
+    /// queued, running, succeeded, failed or interrupted
+    pub status: String,
+    /// When the job was submitted
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// When the status last changed
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    /// Latest progress event: phase, artifact, bytes_done, bytes_total, done
+    #[schema(value_type = Option<Object>)]
+    pub progress: Option<serde_json::Value>,
+    /// HTTP status the request finished with
+    pub result_status: Option<u16>,
+    /// Response body the request finished with
+    #[schema(value_type = Option<Object>)]
+    pub result: Option<serde_json::Value>,
+}
+
 /// Health check response
 #[derive(Debug, Serialize, ToSchema)]
 pub struct HealthResponse {
@@ -226,6 +436,20 @@ impl From<crate::vm::VmInfo> for VmInfo {
             disk: vm_info.disk,
             devices: vm_info.devices,
             created: vm_info.created,
+            labels: vm_info.labels,
+            env: vm_info.env,
+            storage: vm_info.storage,
+        }
+    }
+}
+
+impl From<crate::vm::VmDetailedInfo> for VmDetailResponse {
+    fn from(details: crate::vm::VmDetailedInfo) -> Self {
+        Self {
+            name: details.name,
+            state: details.state,
+            ip: details.ip,
+            details: details.details,
         }
     }
 }
@@ -239,6 +463,7 @@ impl From<crate::image::ImageInfo> for ImageInfo {
             registry: image_info.registry,
             size: image_info.size,
             created: image_info.created,
+            digest: image_info.digest,
         }
     }
 }
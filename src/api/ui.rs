@@ -0,0 +1,62 @@
+//! Web dashboard served at `/ui` (the `web-ui` feature, on by default).
+//!
+//! A single static page, embedded in the binary, listing VMs, images,
+//! running operations and VM logs, with start/stop/restart/delete buttons and a
+//! serial console for running VMs. It
+//! only talks to the REST API, so it needs nothing the API doesn't
+//! already offer; with token auth enabled the page asks for a token and
+//! keeps it in the browser's local storage.
+
+use axum::{
+    http::header,
+    response::{IntoResponse, Redirect},
+    routing::get,
+    Router,
+};
+
+use super::AppState;
+
+const INDEX_HTML: &str = include_str!("ui/index.html");
+const APP_JS: &str = include_str!("ui/app.js");
+const STYLE_CSS: &str = include_str!("ui/style.css");
+
+fn asset(content_type: &'static str, body: &'static str) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, content_type)], body)
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/ui", get(|| async { Redirect::permanent("/ui/") }))
+        .route(
+            "/ui/",
+            get(|| async { asset("text/html; charset=utf-8", INDEX_HTML) }),
+        )
+        .route(
+            "/ui/app.js",
+            get(|| async { asset("text/javascript; charset=utf-8", APP_JS) }),
+        )
+        .route(
+            "/ui/style.css",
+            get(|| async { asset("text/css; charset=utf-8", STYLE_CSS) }),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assets_linked() {
+        // The page loads its script and stylesheet by these paths.
+        assert!(INDEX_HTML.contains("src=\"app.js\""));
+        assert!(INDEX_HTML.contains("href=\"style.css\""));
+        for endpoint in [
+            "/api/v1/vms",
+            "/api/v1/images",
+            "/api/v1/capacity",
+            "/console/ws",
+        ] {
+            assert!(APP_JS.contains(endpoint), "{endpoint}");
+        }
+    }
+}
@@ -0,0 +1,284 @@
+// meda dashboard: polls the REST API and renders VMs, jobs and images.
+"use strict";
+
+const TOKEN_KEY = "meda-api-token";
+const REFRESH_MS = 5000;
+
+const $ = (id) => document.getElementById(id);
+let logsVm = null;
+let consoleSocket = null;
+
+// Keys the console sends as terminal input sequences.
+const CONSOLE_KEYS = {
+  Enter: "\r",
+  Backspace: "\x7f",
+  Tab: "\t",
+  Escape: "\x1b",
+  ArrowUp: "\x1b[A",
+  ArrowDown: "\x1b[B",
+  ArrowRight: "\x1b[C",
+  ArrowLeft: "\x1b[D",
+  Home: "\x1b[H",
+  End: "\x1b[F",
+  Delete: "\x1b[3~",
+};
+
+async function api(method, path) {
+  const headers = {};
+  const token = localStorage.getItem(TOKEN_KEY);
+  if (token) {
+    headers["Authorization"] = "Bearer " + token;
+  }
+  const response = await fetch(path, { method, headers });
+  if (response.status === 401 || response.status === 403) {
+    localStorage.removeItem(TOKEN_KEY);
+    showSignIn(true);
+    throw new Error("API token required");
+  }
+  const body = await response.json().catch(() => ({}));
+  if (!response.ok) {
+    throw new Error(body.error || response.statusText);
+  }
+  return body;
+}
+
+function showSignIn(needed) {
+  $("token-form").hidden = !needed;
+  $("sign-out").hidden = needed || !localStorage.getItem(TOKEN_KEY);
+}
+
+function cell(row, text, className) {
+  const td = row.insertCell();
+  td.textContent = text ?? "";
+  if (className) {
+    td.className = className;
+  }
+  return td;
+}
+
+function button(parent, label, onClick, className) {
+  const b = document.createElement("button");
+  b.textContent = label;
+  if (className) {
+    b.className = className;
+  }
+  b.addEventListener("click", onClick);
+  parent.appendChild(b);
+}
+
+async function action(method, path, busy) {
+  $("status").textContent = busy;
+  try {
+    await api(method, path);
+    $("status").textContent = "";
+  } catch (e) {
+    $("status").textContent = e.message;
+  }
+  refresh();
+}
+
+function renderVms(vms) {
+  const body = $("vms");
+  body.replaceChildren();
+  for (const vm of vms) {
+    const row = body.insertRow();
+    const path = "/api/v1/vms/" + encodeURIComponent(vm.name);
+    cell(row, vm.name);
+    cell(row, vm.state, vm.state);
+    cell(row, vm.ip);
+    cell(row, vm.vcpus);
+    cell(row, vm.memory);
+    cell(row, vm.disk);
+    const actions = cell(row, "", "actions");
+    if (vm.state === "running") {
+      button(actions, "Console", () => openConsole(vm.name));
+      button(actions, "Stop", () => action("POST", path + "/stop", "Stopping " + vm.name + "…"));
+      button(actions, "Restart", () => action("POST", path + "/restart", "Restarting " + vm.name + "…"));
+    } else {
+      button(actions, "Start", () => action("POST", path + "/start", "Starting " + vm.name + "…"));
+    }
+    button(actions, "Logs", () => showLogs(vm.name));
+    button(actions, "Delete", () => {
+      if (confirm("Delete VM " + vm.name + "?")) {
+        action("DELETE", path, "Deleting " + vm.name + "…");
+      }
+    }, "danger");
+  }
+}
+
+function renderJobs(capacity) {
+  const body = $("jobs");
+  body.replaceChildren();
+  for (const [name, op] of Object.entries(capacity.operations || {})) {
+    const row = body.insertRow();
+    cell(row, name);
+    cell(row, op.running);
+    cell(row, op.max === 0 ? "unlimited" : op.max);
+  }
+  const a = capacity.available;
+  $("capacity").textContent = a
+    ? `Available: ${a.cpu} CPUs, ${a.mem_gb} GiB memory, ${a.disk_gb} GiB disk, ${capacity.subnets.free} subnets`
+    : "";
+}
+
+function renderImages(images) {
+  const body = $("images");
+  body.replaceChildren();
+  for (const image of images) {
+    const row = body.insertRow();
+    cell(row, image.name);
+    cell(row, image.tag);
+    cell(row, image.registry);
+    cell(row, image.size);
+    cell(row, image.created);
+  }
+}
+
+async function showLogs(name) {
+  logsVm = name;
+  $("logs-section").hidden = false;
+  $("logs-vm").textContent = name;
+  await refreshLogs();
+}
+
+async function refreshLogs() {
+  if (!logsVm) {
+    return;
+  }
+  try {
+    const body = await api("GET", "/api/v1/vms/" + encodeURIComponent(logsVm) + "/logs?lines=200");
+    $("logs").textContent = body.log || "(empty)";
+  } catch (e) {
+    $("logs").textContent = e.message;
+  }
+}
+
+function openConsole(name) {
+  closeConsole();
+  $("console-section").hidden = false;
+  $("console-vm").textContent = name;
+  const term = $("console");
+  term.textContent = "";
+
+  const scheme = location.protocol === "https:" ? "wss:" : "ws:";
+  let url = scheme + "//" + location.host + "/api/v1/vms/" + encodeURIComponent(name) + "/console/ws";
+  const token = localStorage.getItem(TOKEN_KEY);
+  if (token) {
+    url += "?access_token=" + encodeURIComponent(token);
+  }
+  const socket = new WebSocket(url);
+  socket.binaryType = "arraybuffer";
+  const decoder = new TextDecoder();
+  socket.onmessage = (event) => {
+    const data = typeof event.data === "string" ? event.data : decoder.decode(event.data, { stream: true });
+    writeConsole(term, data);
+  };
+  socket.onclose = () => {
+    if (consoleSocket === socket) {
+      writeConsole(term, "\n[console closed]\n");
+      consoleSocket = null;
+    }
+  };
+  consoleSocket = socket;
+  term.focus();
+}
+
+function closeConsole() {
+  if (consoleSocket) {
+    const socket = consoleSocket;
+    consoleSocket = null;
+    socket.close();
+  }
+  $("console-section").hidden = true;
+}
+
+// A plain-text terminal: escape sequences are dropped, a carriage
+// return restarts the line and backspace erases. Enough for a login
+// prompt and a shell; embed xterm.js against the same endpoint for more.
+function writeConsole(term, text) {
+  let content = term.textContent;
+  const clean = text
+    .replace(/\x1b\[[0-9;?]*[ -\/]*[@-~]/g, "")
+    .replace(/\x1b[()][A-Za-z0-9]|\x1b[=>78]/g, "")
+    .replace(/\r\n/g, "\n");
+  for (const ch of clean) {
+    if (ch === "\r") {
+      content = content.slice(0, content.lastIndexOf("\n") + 1);
+    } else if (ch === "\b") {
+      content = content.slice(0, -1);
+    } else if (ch === "\n" || ch >= " ") {
+      content += ch;
+    }
+  }
+  term.textContent = content.slice(-200000);
+  term.scrollTop = term.scrollHeight;
+}
+
+function consoleInput(event) {
+  let data = CONSOLE_KEYS[event.key];
+  if (!data && event.ctrlKey && event.key.length === 1) {
+    const code = event.key.toUpperCase().charCodeAt(0);
+    if (code >= 64 && code < 96) {
+      data = String.fromCharCode(code - 64);
+    }
+  } else if (!data && event.key.length === 1 && !event.metaKey && !event.altKey) {
+    data = event.key;
+  }
+  return data;
+}
+
+async function refresh() {
+  try {
+    const [vms, images, capacity] = await Promise.all([
+      api("GET", "/api/v1/vms"),
+      api("GET", "/api/v1/images"),
+      api("GET", "/api/v1/capacity"),
+    ]);
+    renderVms(vms.vms);
+    renderImages(images.images);
+    renderJobs(capacity);
+    showSignIn(false);
+  } catch (e) {
+    $("status").textContent = e.message;
+  }
+  refreshLogs();
+}
+
+$("token-form").addEventListener("submit", (event) => {
+  event.preventDefault();
+  localStorage.setItem(TOKEN_KEY, $("token").value.trim());
+  $("token").value = "";
+  $("status").textContent = "";
+  refresh();
+});
+
+$("sign-out").addEventListener("click", () => {
+  localStorage.removeItem(TOKEN_KEY);
+  showSignIn(true);
+});
+
+$("console").addEventListener("keydown", (event) => {
+  const data = consoleInput(event);
+  if (data && consoleSocket && consoleSocket.readyState === WebSocket.OPEN) {
+    event.preventDefault();
+    consoleSocket.send(data);
+  }
+});
+
+$("console").addEventListener("paste", (event) => {
+  const text = event.clipboardData.getData("text");
+  if (text && consoleSocket && consoleSocket.readyState === WebSocket.OPEN) {
+    event.preventDefault();
+    consoleSocket.send(text.replace(/\r?\n/g, "\r"));
+  }
+});
+
+$("console-close").addEventListener("click", closeConsole);
+
+$("logs-close").addEventListener("click", () => {
+  logsVm = null;
+  $("logs-section").hidden = true;
+});
+
+refresh();
+setInterval(refresh, REFRESH_MS);
@@ -0,0 +1,650 @@
+//! `meda apply` — converge this host's VMs to a manifest, GitOps style.
+//!
+//! The manifest is YAML (JSON works too) listing the same units as the
+//! file `meda up` takes:
+//!
+//! ```yaml
+//! group: web
+//! vms:
+//!   - name: web-1
+//!     image: ubuntu:latest
+//!     memory: 2G
+//!     cpus: 2
+//!     labels: { role: web }
+//!   - name: db
+//!     disk: 40G
+//!     isolate: true
+//!     start: false
+//! ```
+//!
+//! The manifest owns the VMs whose `group` file names its group (the
+//! file stem when it sets none). `apply` diffs them against the units
+//! and:
+//!
+//! - creates the units that don't exist,
+//! - updates in place what can change on an existing VM: memory, vCPUs,
+//!   a bigger disk (stopping the VM meanwhile), labels and `start`,
+//! - replaces (deletes and recreates) a VM whose image, user-data, SSH
+//!   keys, storage pool, devices or networking changed, or whose disk
+//!   would have to shrink,
+//! - deletes the VMs of the group the manifest no longer lists.
+//!
+//! Applying an unchanged manifest again changes nothing. Each VM dir
+//! keeps the unit it was last applied from in `applied.json`, which is
+//! what the create-time settings are compared with; VMs `meda up` made
+//! have none, so only their image is checked until the first apply. A
+//! VM named like a unit but outside the group is never touched: the
+//! plan fails instead. `--dry-run` prints the plan without changing
+//! anything.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::labels::{Labels, VmMetadata};
+use crate::up::{self, FailedUnit, UnitSpec};
+use crate::util::parse_size_bytes;
+use crate::{image, vm};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+/// File in a VM dir with the unit it was last applied from.
+pub const APPLIED_FILE: &str = "applied.json";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Group owning the VMs. Defaults to the file stem.
+    pub group: Option<String>,
+    /// The VMs the group should have; none deletes all of them.
+    #[serde(default)]
+    pub vms: Vec<UnitSpec>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Self> {
+        let body = fs::read_to_string(path)?;
+        let manifest: Self = serde_yaml::from_str(&body)
+            .map_err(|e| Error::Other(format!("Invalid manifest {}: {}", path.display(), e)))?;
+        if manifest
+            .group
+            .as_deref()
+            .is_some_and(|g| g.trim().is_empty())
+        {
+            return Err(Error::Other("manifest group can't be empty".to_string()));
+        }
+        up::validate_units(&manifest.vms)?;
+        Ok(manifest)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Create,
+    Update,
+    Replace,
+    Delete,
+    Unchanged,
+}
+
+impl Action {
+    /// Order changes are made in: deletions first free their resources.
+    const ORDER: [Self; 5] = [
+        Self::Delete,
+        Self::Replace,
+        Self::Create,
+        Self::Update,
+        Self::Unchanged,
+    ];
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Self::Create => "+",
+            Self::Update => "~",
+            Self::Replace => "-/+",
+            Self::Delete => "-",
+            Self::Unchanged => "=",
+        }
+    }
+}
+
+/// What an update does to an existing VM.
+#[derive(Debug, Default, PartialEq)]
+struct Update {
+    memory: Option<String>,
+    cpus: Option<u8>,
+    disk: Option<String>,
+    labels: Option<Labels>,
+    running: bool,
+    start: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Change {
+    pub name: String,
+    pub action: Action,
+    /// What differs, e.g. `memory: 2G -> 4G`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diff: Vec<String>,
+    #[serde(skip)]
+    update: Update,
+}
+
+impl Change {
+    fn new(name: &str, action: Action, diff: Vec<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            action,
+            diff,
+            update: Update::default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApplySummary {
+    pub group: String,
+    pub dry_run: bool,
+    pub changes: Vec<Change>,
+    pub failed: Vec<FailedUnit>,
+}
+
+/// A unit with the defaults it is created with filled in.
+#[derive(Debug, Clone)]
+struct Desired {
+    unit: UnitSpec,
+    /// Full reference, as recorded in the VM's metadata.
+    image: Option<String>,
+    memory: String,
+    cpus: u8,
+    disk: String,
+}
+
+impl Desired {
+    fn new(config: &Config, unit: &UnitSpec) -> Result<Self> {
+        let image = unit
+            .image
+            .as_deref()
+            .map(|name| {
+                let registry = unit.registry.as_deref().unwrap_or(&config.default_registry);
+                let org = unit.org.as_deref().unwrap_or(&config.default_org);
+                image::ImageRef::parse(name, registry, org).map(|r| r.url())
+            })
+            .transpose()?;
+        Ok(Self {
+            unit: unit.clone(),
+            image,
+            memory: unit.memory.clone().unwrap_or_else(|| config.mem.clone()),
+            cpus: unit.cpus.unwrap_or(config.cpus as u8),
+            disk: unit
+                .disk
+                .clone()
+                .unwrap_or_else(|| config.disk_size.clone()),
+        })
+    }
+}
+
+/// An existing VM, as far as the plan is concerned.
+#[derive(Debug, Clone, Default)]
+struct Observed {
+    name: String,
+    group: Option<String>,
+    running: bool,
+    memory: String,
+    cpus: String,
+    disk: String,
+    labels: Labels,
+    image: Option<String>,
+    applied: Option<UnitSpec>,
+}
+
+fn observe(config: &Config) -> Result<Vec<Observed>> {
+    Ok(vm::collect_vms(config)?
+        .into_iter()
+        .map(|info| {
+            let vm_dir = config.vm_dir(&info.name);
+            Observed {
+                group: up::read_group(&vm_dir),
+                running: info.state == "running",
+                image: VmMetadata::load(&vm_dir).ok().and_then(|m| m.image),
+                applied: fs::read_to_string(vm_dir.join(APPLIED_FILE))
+                    .ok()
+                    .and_then(|body| serde_json::from_str(&body).ok()),
+                name: info.name,
+                memory: info.memory,
+                cpus: info.vcpus,
+                disk: info.disk,
+                labels: info.labels,
+            }
+        })
+        .collect())
+}
+
+/// Sizes compare by value, so `2G` matches `2048M`.
+fn same_size(a: &str, b: &str) -> bool {
+    match (parse_size_bytes(a), parse_size_bytes(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn show_labels(labels: &Labels) -> String {
+    if labels.is_empty() {
+        return "(none)".to_string();
+    }
+    labels
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Why `vm` can't become `desired` without being recreated.
+fn replace_reasons(desired: &Desired, vm: &Observed) -> Vec<String> {
+    let mut diff = Vec::new();
+    if desired.image != vm.image {
+        let show = |image: &Option<String>| image.clone().unwrap_or("(base image)".to_string());
+        diff.push(format!(
+            "image: {} -> {}",
+            show(&vm.image),
+            show(&desired.image)
+        ));
+    }
+    if let (Some(want), Some(have)) = (parse_size_bytes(&desired.disk), parse_size_bytes(&vm.disk))
+    {
+        if want < have {
+            diff.push(format!(
+                "disk: {} -> {} (disks can't shrink)",
+                vm.disk, desired.disk
+            ));
+        }
+    }
+    if let Some(applied) = &vm.applied {
+        let unit = &desired.unit;
+        let fields = [
+            ("user_data", applied.user_data != unit.user_data),
+            ("ssh_keys", applied.ssh_keys != unit.ssh_keys),
+            ("storage", applied.storage != unit.storage),
+            ("devices", applied.devices != unit.devices),
+            ("isolate", applied.isolate != unit.isolate),
+            ("egress", applied.egress != unit.egress),
+            (
+                "network",
+                applied.network != unit.network || applied.bridge != unit.bridge,
+            ),
+        ];
+        diff.extend(
+            fields
+                .iter()
+                .filter(|(_, changed)| *changed)
+                .map(|(field, _)| format!("{} changed", field)),
+        );
+    }
+    diff
+}
+
+/// What to change on `vm` in place to make it `desired`.
+fn update_for(desired: &Desired, vm: &Observed) -> (Vec<String>, Update) {
+    let mut diff = Vec::new();
+    let mut update = Update {
+        running: vm.running,
+        start: desired.unit.start,
+        ..Default::default()
+    };
+    if !same_size(&desired.memory, &vm.memory) {
+        diff.push(format!("memory: {} -> {}", vm.memory, desired.memory));
+        update.memory = Some(desired.memory.clone());
+    }
+    if vm.cpus != desired.cpus.to_string() {
+        diff.push(format!("cpus: {} -> {}", vm.cpus, desired.cpus));
+        update.cpus = Some(desired.cpus);
+    }
+    if !same_size(&desired.disk, &vm.disk) {
+        diff.push(format!("disk: {} -> {}", vm.disk, desired.disk));
+        update.disk = Some(desired.disk.clone());
+    }
+    if vm.labels != desired.unit.labels {
+        diff.push(format!(
+            "labels: {} -> {}",
+            show_labels(&vm.labels),
+            show_labels(&desired.unit.labels)
+        ));
+        update.labels = Some(desired.unit.labels.clone());
+    }
+    if vm.running != desired.unit.start {
+        diff.push(
+            if desired.unit.start {
+                "state: stopped -> running"
+            } else {
+                "state: running -> stopped"
+            }
+            .to_string(),
+        );
+    }
+    (diff, update)
+}
+
+/// The changes that turn the group's VMs in `observed` into `desired`,
+/// in manifest order, then the deletions.
+fn plan(group: &str, desired: &[Desired], observed: &[Observed]) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    for want in desired {
+        let name = &want.unit.name;
+        let Some(vm) = observed.iter().find(|vm| &vm.name == name) else {
+            changes.push(Change::new(name, Action::Create, Vec::new()));
+            continue;
+        };
+        if vm.group.as_deref() != Some(group) {
+            return Err(Error::Other(format!(
+                "VM {} already exists outside group '{}'; delete it or rename the unit",
+                name, group
+            )));
+        }
+        let reasons = replace_reasons(want, vm);
+        if !reasons.is_empty() {
+            changes.push(Change::new(name, Action::Replace, reasons));
+            continue;
+        }
+        let (diff, update) = update_for(want, vm);
+        let action = if diff.is_empty() {
+            Action::Unchanged
+        } else {
+            Action::Update
+        };
+        changes.push(Change {
+            name: name.clone(),
+            action,
+            diff,
+            update,
+        });
+    }
+    let listed: HashSet<&str> = desired.iter().map(|d| d.unit.name.as_str()).collect();
+    let mut removed: Vec<&Observed> = observed
+        .iter()
+        .filter(|vm| vm.group.as_deref() == Some(group) && !listed.contains(vm.name.as_str()))
+        .collect();
+    removed.sort_by(|a, b| a.name.cmp(&b.name));
+    changes.extend(
+        removed
+            .into_iter()
+            .map(|vm| Change::new(&vm.name, Action::Delete, Vec::new())),
+    );
+    Ok(changes)
+}
+
+async fn update_vm(config: &Config, name: &str, update: &Update) -> Result<()> {
+    if let Some(labels) = &update.labels {
+        crate::labels::write_labels(&config.vm_dir(name), labels)?;
+    }
+    let mut running = update.running;
+    if update.memory.is_some() || update.cpus.is_some() || update.disk.is_some() {
+        // Disks only grow while the VM is stopped.
+        if update.disk.is_some() && running {
+            vm::stop(config, name, false).await?;
+            running = false;
+        }
+        vm::resize(
+            config,
+            name,
+            update.memory.as_deref(),
+            update.cpus,
+            update.disk.as_deref(),
+            false,
+        )
+        .await?;
+    }
+    if update.start && !running {
+        vm::start(config, name, false).await?;
+    } else if !update.start && running {
+        vm::stop(config, name, false).await?;
+    }
+    Ok(())
+}
+
+async fn execute(
+    config: &Config,
+    group: &str,
+    change: &Change,
+    unit: Option<&UnitSpec>,
+) -> Result<()> {
+    match (change.action, unit) {
+        (Action::Delete, _) => return vm::delete(config, &change.name, false).await,
+        (Action::Create, Some(unit)) => up::create_unit(config, group, unit).await?,
+        (Action::Replace, Some(unit)) => {
+            vm::delete(config, &change.name, false).await?;
+            up::create_unit(config, group, unit).await?;
+        }
+        (Action::Update, Some(_)) => update_vm(config, &change.name, &change.update).await?,
+        (Action::Unchanged, Some(_)) => {}
+        (_, None) => unreachable!("only deletions have no unit"),
+    }
+    fs::write(
+        config.vm_dir(&change.name).join(APPLIED_FILE),
+        serde_json::to_string_pretty(&unit)?,
+    )?;
+    Ok(())
+}
+
+fn counts(changes: &[Change]) -> String {
+    let count = |action| changes.iter().filter(|c| c.action == action).count();
+    format!(
+        "{} to create, {} to update, {} to replace, {} to delete, {} unchanged",
+        count(Action::Create),
+        count(Action::Update),
+        count(Action::Replace),
+        count(Action::Delete),
+        count(Action::Unchanged)
+    )
+}
+
+fn print_plan(group: &str, changes: &[Change]) {
+    println!("Plan for group '{}': {}", group, counts(changes));
+    for change in changes.iter().filter(|c| c.action != Action::Unchanged) {
+        if change.diff.is_empty() {
+            println!("  {} {}", change.action.symbol(), change.name);
+        } else {
+            println!(
+                "  {} {} ({})",
+                change.action.symbol(),
+                change.name,
+                change.diff.join("; ")
+            );
+        }
+    }
+}
+
+pub async fn apply(config: &Config, file: &Path, dry_run: bool, json: bool) -> Result<()> {
+    let manifest = Manifest::load(file)?;
+    let group = up::group_name(manifest.group.as_deref(), file);
+    let desired = manifest
+        .vms
+        .iter()
+        .map(|unit| Desired::new(config, unit))
+        .collect::<Result<Vec<_>>>()?;
+    let changes = plan(&group, &desired, &observe(config)?)?;
+    if !json {
+        print_plan(&group, &changes);
+    }
+
+    let mut summary = ApplySummary {
+        group,
+        dry_run,
+        changes,
+        failed: Vec::new(),
+    };
+    if !dry_run {
+        let unit = |name: &str| manifest.vms.iter().find(|u| u.name == name);
+        let building: Vec<UnitSpec> = summary
+            .changes
+            .iter()
+            .filter(|c| matches!(c.action, Action::Create | Action::Replace))
+            .filter_map(|c| unit(&c.name).cloned())
+            .collect();
+        if !building.is_empty() {
+            up::prepare(config, &building).await?;
+        }
+        for action in Action::ORDER {
+            for change in summary.changes.iter().filter(|c| c.action == action) {
+                if !json && action != Action::Unchanged {
+                    info!("{} {}", change.action.symbol(), change.name);
+                }
+                if let Err(e) = execute(config, &summary.group, change, unit(&change.name)).await {
+                    summary.failed.push(FailedUnit {
+                        name: change.name.clone(),
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    } else if !summary.failed.is_empty() {
+        println!("Failed:");
+        for failed in &summary.failed {
+            println!("  {}: {}", failed.name, failed.error);
+        }
+    }
+
+    if summary.failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Other(format!(
+            "{} of {} changes to group '{}' failed",
+            summary.failed.len(),
+            summary
+                .changes
+                .iter()
+                .filter(|c| c.action != Action::Unchanged)
+                .count(),
+            summary.group
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn unit(name: &str) -> UnitSpec {
+        serde_yaml::from_str(&format!("name: {}", name)).unwrap()
+    }
+
+    fn desired(unit: UnitSpec) -> Desired {
+        Desired {
+            image: unit
+                .image
+                .as_ref()
+                .map(|i| format!("ghcr.io/cirunlabs/{}", i)),
+            memory: unit.memory.clone().unwrap_or("1G".to_string()),
+            cpus: unit.cpus.unwrap_or(2),
+            disk: unit.disk.clone().unwrap_or("10G".to_string()),
+            unit,
+        }
+    }
+
+    fn observed(name: &str, group: &str) -> Observed {
+        Observed {
+            name: name.to_string(),
+            group: Some(group.to_string()),
+            running: true,
+            memory: "1G".to_string(),
+            cpus: "2".to_string(),
+            disk: "10G".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_manifest_load() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("web.yaml");
+        fs::write(
+            &path,
+            "vms:\n  - name: web-1\n    image: ubuntu:latest\n    labels: {role: web}\n  - name: db\n    start: false\n",
+        )
+        .unwrap();
+        let manifest = Manifest::load(&path).unwrap();
+        assert!(manifest.group.is_none());
+        assert_eq!(manifest.vms.len(), 2);
+        assert_eq!(manifest.vms[0].labels["role"], "web");
+        assert!(!manifest.vms[1].start);
+
+        fs::write(&path, r#"{"group": "web", "vms": []}"#).unwrap();
+        assert!(Manifest::load(&path).unwrap().vms.is_empty());
+
+        fs::write(
+            &path,
+            "vms:\n  - name: a\n    image: ubuntu:latest\n    network: user\n",
+        )
+        .unwrap();
+        assert!(Manifest::load(&path).is_err());
+        fs::write(&path, "vms:\n  - name: a\n    memroy: 2G\n").unwrap();
+        assert!(Manifest::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_plan() {
+        let mut web = unit("web");
+        web.memory = Some("2048M".to_string());
+        web.labels.insert("role".to_string(), "web".to_string());
+        let mut db = unit("db");
+        db.image = Some("postgres:16".to_string());
+        let cache = unit("cache");
+        let worker = unit("worker");
+        let wanted = [web, db, cache, worker].map(desired);
+
+        let mut observed_web = observed("web", "prod");
+        observed_web.memory = "2G".to_string();
+        observed_web.running = false;
+        let mut observed_cache = observed("cache", "prod");
+        observed_cache.applied = Some(unit("cache"));
+        let observed = [
+            observed_web,
+            observed("db", "prod"),
+            observed_cache,
+            observed("old", "prod"),
+            observed("other", "dev"),
+        ];
+
+        let changes = plan("prod", &wanted, &observed).unwrap();
+        let actions: Vec<(&str, Action)> = changes
+            .iter()
+            .map(|c| (c.name.as_str(), c.action))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                ("web", Action::Update),
+                ("db", Action::Replace),
+                ("cache", Action::Unchanged),
+                ("worker", Action::Create),
+                ("old", Action::Delete),
+            ]
+        );
+        assert_eq!(
+            changes[0].diff,
+            ["labels: (none) -> role=web", "state: stopped -> running"]
+        );
+        assert_eq!(changes[0].update.memory, None);
+        assert!(changes[0].update.start && !changes[0].update.running);
+        assert_eq!(
+            changes[1].diff,
+            ["image: (base image) -> ghcr.io/cirunlabs/postgres:16"]
+        );
+
+        // Settings only set at create time force a replace once recorded.
+        let mut isolated = unit("cache");
+        isolated.isolate = true;
+        let changes = plan("prod", &[desired(isolated)], &observed[2..3]).unwrap();
+        assert_eq!(changes[0].action, Action::Replace);
+        assert_eq!(changes[0].diff, ["isolate changed"]);
+
+        // Never take over a VM from another group.
+        assert!(plan("prod", &[desired(unit("other"))], &observed).is_err());
+    }
+}
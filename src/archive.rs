@@ -0,0 +1,484 @@
+//! `meda image save` / `meda image load`: move images between hosts
+//! as a single file, for air-gapped hosts no registry can reach.
+//!
+//! An archive is a tar of the image dir, zstd-compressed when its name
+//! ends in `.zst`. Files past the chunking threshold go in as chunks
+//! plus their index, the same layout `meda push` uses, so `load` checks
+//! every chunk and the reassembled file against the recorded digests
+//! before the image shows up in `meda images`. Links are refused, and
+//! the manifest may only name plain files inside the archive.
+
+use crate::chunking::{sha256_file, FileChunker};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::image::{ImageManifest, ImageRef, ImageResult};
+use log::info;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Level for compressed archives; they are written once and usually
+/// carried over slow media, so a bit more effort than on push pays off.
+const ZSTD_LEVEL: i32 = 6;
+
+fn wants_zstd(output: &Path) -> bool {
+    matches!(
+        output.extension().and_then(|e| e.to_str()),
+        Some("zst" | "zstd")
+    )
+}
+
+/// Add the files under `dir` to `builder` below `prefix`, chunking the
+/// large ones through `staging`.
+fn append_tree<W: Write>(
+    builder: &mut tar::Builder<W>,
+    chunker: &FileChunker,
+    dir: &Path,
+    prefix: &Path,
+    staging: &Path,
+) -> Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<std::io::Result<_>>()?;
+    entries.sort();
+    for path in entries {
+        let name = prefix.join(path.file_name().unwrap_or_default());
+        if path.is_dir() {
+            append_tree(builder, chunker, &path, &name, staging)?;
+        } else if path.file_name() == Some(crate::image::DIGEST_CACHE.as_ref()) {
+            // Keyed by local mtimes; rebuilt on the other side.
+        } else if chunker.should_chunk_file(&path)? {
+            chunker.chunk_file(&path, staging, true)?;
+            let mut chunks: Vec<PathBuf> = fs::read_dir(staging)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<std::io::Result<_>>()?;
+            chunks.sort();
+            for chunk in chunks {
+                let chunk_name = prefix.join(chunk.file_name().unwrap_or_default());
+                builder.append_path_with_name(&chunk, chunk_name)?;
+                fs::remove_file(&chunk)?;
+            }
+        } else {
+            builder.append_path_with_name(&path, name)?;
+        }
+    }
+    Ok(())
+}
+
+/// `meda image save`: write local image `image` to `output`.
+pub fn save(
+    config: &Config,
+    image: &str,
+    output: &Path,
+    registry: Option<&str>,
+    org: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let image_ref = ImageRef::parse(
+        image,
+        registry.unwrap_or(&config.default_registry),
+        org.unwrap_or(&config.default_org),
+    )?;
+    let image_dir = image_ref.local_dir(config);
+    let _lock = crate::image::lock_image_shared(&image_dir, &image_ref.url())?;
+    let manifest =
+        ImageManifest::load(&image_dir).map_err(|_| Error::ImageNotFound(image_ref.url()))?;
+    if manifest.metadata.contains_key("partial") {
+        return Err(Error::Other(format!(
+            "Image {} is only partially pulled; run `meda pull {}` first",
+            image_ref.url(),
+            image
+        )));
+    }
+    if output.exists() {
+        return Err(Error::Other(format!("{} already exists", output.display())));
+    }
+
+    if !json {
+        info!("Saving {} to {}", image_ref.url(), output.display());
+    }
+    let staging = tempfile::Builder::new()
+        .prefix("image-save-")
+        .tempdir_in(&config.asset_dir)?;
+    let chunker = FileChunker::with_config(config.chunking.clone());
+    let file = BufWriter::new(File::create(output)?);
+    let written = if wants_zstd(output) {
+        zstd::stream::Encoder::new(file, ZSTD_LEVEL)
+            .map_err(Error::from)
+            .and_then(|encoder| {
+                let mut builder = tar::Builder::new(encoder);
+                append_tree(
+                    &mut builder,
+                    &chunker,
+                    &image_dir,
+                    Path::new(""),
+                    staging.path(),
+                )?;
+                builder.into_inner()?.finish()?.flush()?;
+                Ok(())
+            })
+    } else {
+        let mut builder = tar::Builder::new(file);
+        append_tree(
+            &mut builder,
+            &chunker,
+            &image_dir,
+            Path::new(""),
+            staging.path(),
+        )
+        .and_then(|_| Ok(builder.into_inner()?.flush()?))
+    };
+    if let Err(e) = written {
+        let _ = fs::remove_file(output);
+        return Err(e);
+    }
+
+    let message = format!("Saved {} to {}", image_ref.url(), output.display());
+    if json {
+        let result = ImageResult {
+            success: true,
+            message,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!("{}", message);
+    }
+    Ok(())
+}
+
+/// Put back every chunked file under `dir`, checking it against its
+/// index.
+fn reassemble_tree(chunker: &FileChunker, dir: &Path) -> Result<()> {
+    for (_, (metadata, chunks)) in chunker.detect_chunks(dir)? {
+        let expected = metadata.sha256.clone().ok_or_else(|| {
+            Error::CorruptArtifact(format!(
+                "{}: chunk index missing",
+                metadata.original_filename
+            ))
+        })?;
+        if chunks.len() != metadata.total_chunks {
+            return Err(Error::CorruptArtifact(format!(
+                "{}: found {} of {} chunks",
+                metadata.original_filename,
+                chunks.len(),
+                metadata.total_chunks
+            )));
+        }
+        let path = dir.join(&metadata.original_filename);
+        chunker.reassemble_chunks(&chunks, &metadata, &path, true)?;
+        let digest = sha256_file(&path)?;
+        if digest != expected {
+            return Err(Error::CorruptArtifact(format!(
+                "{}: expected sha256 {}, got {}",
+                metadata.original_filename, expected, digest
+            )));
+        }
+        chunker.cleanup_chunks(&chunks)?;
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            reassemble_tree(chunker, &path)?;
+        }
+    }
+    Ok(())
+}
+
+/// The ref an archive's manifest names. The parts become directories
+/// under the image store, so anything that could step out of it is
+/// refused.
+fn manifest_ref(manifest: &ImageManifest) -> Result<ImageRef> {
+    let parts = [
+        &manifest.registry,
+        &manifest.org,
+        &manifest.name,
+        &manifest.tag,
+    ];
+    if parts
+        .iter()
+        .any(|p| p.is_empty() || *p == "." || *p == ".." || p.contains(['/', '\\', '\0']))
+    {
+        return Err(Error::InvalidImageName(format!(
+            "{}/{}/{}:{}",
+            manifest.registry, manifest.org, manifest.name, manifest.tag
+        )));
+    }
+    Ok(ImageRef {
+        registry: manifest.registry.clone(),
+        org: manifest.org.clone(),
+        name: manifest.name.clone(),
+        tag: manifest.tag.clone(),
+        digest: None,
+    })
+}
+
+/// Artifacts are opened relative to the image dir, so each must be a
+/// plain file name of a regular file in `dir`.
+fn check_artifact(dir: &Path, file: &str) -> std::result::Result<(), String> {
+    let mut components = Path::new(file).components();
+    let plain = matches!(components.next(), Some(std::path::Component::Normal(_)))
+        && components.next().is_none()
+        && !file.contains(['/', '\\', '\0']);
+    if !plain {
+        return Err(format!("artifact {} is not a plain file name", file));
+    }
+    match fs::symlink_metadata(dir.join(file)) {
+        Ok(meta) if meta.file_type().is_file() => Ok(()),
+        Ok(_) => Err(format!("artifact {} is not a regular file", file)),
+        Err(_) => Err(format!("artifact {} is missing", file)),
+    }
+}
+
+/// `meda image load`: add the image in `archive` to the local store,
+/// under the name it was saved with.
+pub fn load(config: &Config, archive: &Path, json: bool) -> Result<()> {
+    let mut file = BufReader::new(
+        File::open(archive)
+            .map_err(|e| Error::Other(format!("Cannot read {}: {}", archive.display(), e)))?,
+    );
+    let mut magic = [0u8; 4];
+    let n = file.read(&mut magic)?;
+    let head = std::io::Cursor::new(magic[..n].to_vec()).chain(file);
+    let reader: Box<dyn Read> = if magic[..n] == ZSTD_MAGIC {
+        Box::new(zstd::stream::Decoder::new(head)?)
+    } else {
+        Box::new(head)
+    };
+
+    if !json {
+        info!("Loading {}", archive.display());
+    }
+    fs::create_dir_all(&config.asset_dir)?;
+    let work = tempfile::Builder::new()
+        .prefix("image-load-")
+        .tempdir_in(&config.asset_dir)?;
+    let mut tar = tar::Archive::new(reader);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        // A link could point an artifact anywhere on the host.
+        let kind = entry.header().entry_type();
+        if kind.is_symlink() || kind.is_hard_link() {
+            return Err(Error::CorruptArtifact(format!(
+                "{}: {} is a link; image archives hold only files",
+                archive.display(),
+                entry.path()?.display()
+            )));
+        }
+        // unpack_in refuses entries that would land outside `work`.
+        entry.unpack_in(work.path())?;
+    }
+    reassemble_tree(
+        &FileChunker::with_config(config.chunking.clone()),
+        work.path(),
+    )?;
+
+    let manifest = ImageManifest::load(work.path()).map_err(|_| {
+        Error::Other(format!(
+            "{} is not an image archive (no manifest.json)",
+            archive.display()
+        ))
+    })?;
+    for file in manifest.artifacts.values() {
+        check_artifact(work.path(), file)
+            .map_err(|e| Error::CorruptArtifact(format!("{}: {}", archive.display(), e)))?;
+    }
+    let image_ref = manifest_ref(&manifest)?;
+    let image_dir = image_ref.local_dir(config);
+    if image_dir.exists() {
+        return Err(Error::ImageAlreadyExists(image_ref.url()));
+    }
+    // Saved with an older meda: load() upgraded it in memory.
+    manifest.save(work.path())?;
+    if let Some(parent) = image_dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(work.path(), &image_dir)?;
+
+    let message = format!("Loaded {} from {}", image_ref.url(), archive.display());
+    if json {
+        let result = ImageResult {
+            success: true,
+            message,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!("{}", message);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    fn test_config(dir: &Path) -> Config {
+        let mut config = Config::new().unwrap();
+        config.asset_dir = dir.join("assets");
+        config.chunking.min_chunk_threshold = 1024;
+        config.chunking.small_chunk_size = 1000;
+        config
+    }
+
+    fn write_image(config: &Config, name: &str) -> PathBuf {
+        let image_ref =
+            ImageRef::parse(name, &config.default_registry, &config.default_org).unwrap();
+        let dir = image_ref.local_dir(config);
+        fs::create_dir_all(&dir).unwrap();
+        let disk: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("base.raw"), &disk).unwrap();
+        fs::write(dir.join("user-data"), "#cloud-config\n").unwrap();
+        let mut artifacts = HashMap::new();
+        artifacts.insert("base_image".to_string(), "base.raw".to_string());
+        artifacts.insert("user-data".to_string(), "user-data".to_string());
+        ImageManifest {
+            schema_version: crate::schema::IMAGE_SCHEMA_VERSION,
+            name: image_ref.name.clone(),
+            tag: image_ref.tag.clone(),
+            registry: image_ref.registry.clone(),
+            org: image_ref.org.clone(),
+            artifacts,
+            metadata: HashMap::new(),
+            created: 1,
+            history: Vec::new(),
+        }
+        .save(&dir)
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        for archive_name in ["golden.tar.zst", "golden.tar"] {
+            let tmp = TempDir::new().unwrap();
+            let source = test_config(&tmp.path().join("a"));
+            let target = test_config(&tmp.path().join("b"));
+            let dir = write_image(&source, "golden:v1");
+            let archive = tmp.path().join(archive_name);
+
+            save(&source, "golden:v1", &archive, None, None, true).unwrap();
+            assert!(save(&source, "golden:v1", &archive, None, None, true).is_err());
+
+            load(&target, &archive, true).unwrap();
+            let loaded =
+                ImageRef::parse("golden:v1", &target.default_registry, &target.default_org)
+                    .unwrap()
+                    .local_dir(&target);
+            assert_eq!(
+                fs::read(loaded.join("base.raw")).unwrap(),
+                fs::read(dir.join("base.raw")).unwrap()
+            );
+            assert!(loaded.join("user-data").exists());
+            assert!(!loaded.join("base.raw.chunk.000").exists());
+            assert_eq!(ImageManifest::load(&loaded).unwrap().name, "golden");
+
+            let err = load(&target, &archive, true).unwrap_err();
+            assert!(matches!(err, Error::ImageAlreadyExists(_)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_load_rejects_escaping_manifest() {
+        for (field, value) in [("org", "../../../escape"), ("tag", ".."), ("name", "/tmp")] {
+            let tmp = TempDir::new().unwrap();
+            let source = test_config(&tmp.path().join("a"));
+            let dir = write_image(&source, "golden:v1");
+            let mut manifest: serde_json::Value =
+                serde_json::from_str(&fs::read_to_string(dir.join("manifest.json")).unwrap())
+                    .unwrap();
+            manifest[field] = value.into();
+            fs::write(dir.join("manifest.json"), manifest.to_string()).unwrap();
+            let archive = tmp.path().join("golden.tar");
+            save(&source, "golden:v1", &archive, None, None, true).unwrap();
+
+            let target = test_config(&tmp.path().join("b"));
+            let err = load(&target, &archive, true).unwrap_err();
+            assert!(matches!(err, Error::InvalidImageName(_)), "{}", err);
+            assert!(!tmp.path().join("escape").exists());
+            assert!(!target.asset_dir.join("images").exists());
+        }
+    }
+
+    #[test]
+    fn test_load_rejects_escaping_artifacts() {
+        for value in ["/etc/hostname", "../base.raw", "sub/../base.raw"] {
+            let tmp = TempDir::new().unwrap();
+            let source = test_config(&tmp.path().join("a"));
+            let dir = write_image(&source, "golden:v1");
+            let mut manifest: serde_json::Value =
+                serde_json::from_str(&fs::read_to_string(dir.join("manifest.json")).unwrap())
+                    .unwrap();
+            manifest["artifacts"]["base_image"] = value.into();
+            fs::write(dir.join("manifest.json"), manifest.to_string()).unwrap();
+            let archive = tmp.path().join("golden.tar");
+            save(&source, "golden:v1", &archive, None, None, true).unwrap();
+
+            let target = test_config(&tmp.path().join("b"));
+            let err = load(&target, &archive, true).unwrap_err();
+            assert!(err.to_string().contains("plain file name"), "{}", err);
+            assert!(!target.asset_dir.join("images").exists());
+        }
+    }
+
+    #[test]
+    fn test_load_rejects_links() {
+        let tmp = TempDir::new().unwrap();
+        let source = test_config(&tmp.path().join("a"));
+        let dir = write_image(&source, "golden:v1");
+        let archive = tmp.path().join("golden.tar");
+        let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+        builder
+            .append_path_with_name(dir.join("manifest.json"), "manifest.json")
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder
+            .append_link(&mut header, "base.raw", "/etc/hostname")
+            .unwrap();
+        builder.into_inner().unwrap().flush().unwrap();
+
+        let target = test_config(&tmp.path().join("b"));
+        let err = load(&target, &archive, true).unwrap_err();
+        assert!(err.to_string().contains("is a link"), "{}", err);
+        assert!(!target.asset_dir.join("images").exists());
+
+        let work = TempDir::new().unwrap();
+        std::os::unix::fs::symlink("/etc/hostname", work.path().join("base.raw")).unwrap();
+        assert!(check_artifact(work.path(), "base.raw").is_err());
+        fs::write(work.path().join("user-data"), "").unwrap();
+        assert!(check_artifact(work.path(), "user-data").is_ok());
+    }
+
+    #[test]
+    fn test_load_rejects_corrupt_chunk() {
+        let tmp = TempDir::new().unwrap();
+        let source = test_config(&tmp.path().join("a"));
+        write_image(&source, "golden:v1");
+        let archive = tmp.path().join("golden.tar");
+        save(&source, "golden:v1", &archive, None, None, true).unwrap();
+
+        // Flip a byte inside the first chunk's data.
+        let mut bytes = fs::read(&archive).unwrap();
+        let offset = tar::Archive::new(bytes.as_slice())
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap())
+            .find(|e| e.path().unwrap().ends_with("base.raw.chunk.000"))
+            .unwrap()
+            .raw_file_position() as usize;
+        bytes[offset + 10] ^= 0xff;
+        fs::write(&archive, bytes).unwrap();
+
+        let target = test_config(&tmp.path().join("b"));
+        assert!(load(&target, &archive, true).is_err());
+        let loaded = ImageRef::parse("golden:v1", &target.default_registry, &target.default_org)
+            .unwrap()
+            .local_dir(&target);
+        assert!(!loaded.exists());
+    }
+}
@@ -0,0 +1,306 @@
+//! Files bootstrap downloads into the asset dir: the base image, the
+//! firmware, cloud-hypervisor, ch-remote and ORAS.
+//!
+//! Each has its upstream URL plus optional mirrors, tried in order when
+//! the upstream fails (GitHub release downloads regularly flake in some
+//! CI regions), from `MEDA_<ASSET>_MIRRORS`, a comma-separated list of
+//! full URLs. Downloads resume with range requests (see
+//! `util::download_with_mirrors`). The URL that actually served each
+//! asset is recorded in `sources.json` in the asset dir, with when it was
+//! fetched and its ETag (see `base_image` for what uses that), and shown
+//! by `meda system info`.
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::util::{download_with_mirrors, Downloaded};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SOURCES_FILE: &str = "sources.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Asset {
+    BaseImage,
+    Firmware,
+    CloudHypervisor,
+    ChRemote,
+    Oras,
+}
+
+impl Asset {
+    pub const ALL: [Asset; 5] = [
+        Asset::BaseImage,
+        Asset::Firmware,
+        Asset::CloudHypervisor,
+        Asset::ChRemote,
+        Asset::Oras,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Asset::BaseImage => "base-image",
+            Asset::Firmware => "firmware",
+            Asset::CloudHypervisor => "cloud-hypervisor",
+            Asset::ChRemote => "ch-remote",
+            Asset::Oras => "oras",
+        }
+    }
+
+    fn mirrors_var(self) -> &'static str {
+        match self {
+            Asset::BaseImage => "MEDA_OS_MIRRORS",
+            Asset::Firmware => "MEDA_FW_MIRRORS",
+            Asset::CloudHypervisor => "MEDA_CH_MIRRORS",
+            Asset::ChRemote => "MEDA_CR_MIRRORS",
+            Asset::Oras => "MEDA_ORAS_MIRRORS",
+        }
+    }
+
+    fn primary_url(self, config: &Config) -> &str {
+        match self {
+            Asset::BaseImage => &config.os_url,
+            Asset::Firmware => &config.fw_url,
+            Asset::CloudHypervisor => &config.ch_url,
+            Asset::ChRemote => &config.cr_url,
+            Asset::Oras => &config.oras_url,
+        }
+    }
+
+    /// Where the installed asset lives once bootstrap is done.
+    pub fn path(self, config: &Config) -> &Path {
+        match self {
+            Asset::BaseImage => &config.base_raw,
+            Asset::Firmware => &config.fw_bin,
+            Asset::CloudHypervisor => &config.ch_bin,
+            Asset::ChRemote => &config.cr_bin,
+            Asset::Oras => &config.oras_bin,
+        }
+    }
+
+    /// Upstream URL first, then the configured mirrors.
+    pub fn urls(self, config: &Config) -> Vec<String> {
+        let mut urls = vec![self.primary_url(config).to_string()];
+        if let Some(mirrors) = config.asset_mirrors.get(self.name()) {
+            urls.extend(mirrors.iter().cloned());
+        }
+        urls
+    }
+}
+
+/// Mirror lists keyed by asset name, read through `var` (the
+/// environment outside tests).
+pub fn mirrors_from_env(var: impl Fn(&str) -> Option<String>) -> BTreeMap<String, Vec<String>> {
+    Asset::ALL
+        .iter()
+        .filter_map(|asset| {
+            let mirrors = parse_mirrors(&var(asset.mirrors_var())?);
+            (!mirrors.is_empty()).then(|| (asset.name().to_string(), mirrors))
+        })
+        .collect()
+}
+
+fn parse_mirrors(spec: &str) -> Vec<String> {
+    spec.split([',', ' ', '\n'])
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Where an asset was downloaded from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Source {
+    pub url: String,
+    pub fetched_at: chrono::DateTime<chrono::Utc>,
+    /// ETag or Last-Modified the file was served with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validator: Option<String>,
+    /// Last time upstream was found to still serve the same file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+fn sources_path(config: &Config) -> PathBuf {
+    config.asset_dir.join(SOURCES_FILE)
+}
+
+pub fn load_sources(config: &Config) -> BTreeMap<String, Source> {
+    fs::read_to_string(sources_path(config))
+        .ok()
+        .and_then(|body| serde_json::from_str(&body).ok())
+        .unwrap_or_default()
+}
+
+fn save_sources(config: &Config, sources: &BTreeMap<String, Source>) -> Result<()> {
+    let path = sources_path(config);
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(sources)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+fn record_source(config: &Config, asset: Asset, download: &Downloaded) -> Result<()> {
+    let mut sources = load_sources(config);
+    sources.insert(
+        asset.name().to_string(),
+        Source {
+            url: download.url.clone(),
+            fetched_at: chrono::Utc::now(),
+            validator: download.validator.clone(),
+            checked_at: None,
+        },
+    );
+    save_sources(config, &sources)
+}
+
+/// Record that upstream still serves the copy of `asset` we have.
+pub fn mark_checked(config: &Config, asset: Asset) -> Result<()> {
+    let mut sources = load_sources(config);
+    if let Some(source) = sources.get_mut(asset.name()) {
+        source.checked_at = Some(chrono::Utc::now());
+        save_sources(config, &sources)?;
+    }
+    Ok(())
+}
+
+/// Download `asset` to `dest` (its installed path, or a file the caller
+/// converts or unpacks into it) from the first URL that serves it.
+pub async fn fetch(config: &Config, asset: Asset, dest: &Path) -> Result<()> {
+    config.require_network(&format!("Downloading {}", asset.name()))?;
+    let urls = asset.urls(config);
+    let download = download_with_mirrors(&urls, dest).await?;
+    if download.url != urls[0] {
+        info!("{} served by mirror {}", asset.name(), download.url);
+    }
+    record_source(config, asset, &download)
+}
+
+#[derive(Debug, Serialize)]
+struct AssetStatus {
+    name: &'static str,
+    path: PathBuf,
+    present: bool,
+    source: Option<Source>,
+    mirrors: Vec<String>,
+}
+
+/// `meda system info`: each bootstrap asset, whether it is installed and
+/// which URL served it.
+pub fn system_info_command(config: &Config, json: bool) -> Result<()> {
+    let mut sources = load_sources(config);
+    let assets: Vec<AssetStatus> = Asset::ALL
+        .iter()
+        .map(|&asset| {
+            let path = match asset {
+                Asset::BaseImage => crate::base_image::current(config),
+                _ => asset.path(config).to_path_buf(),
+            };
+            AssetStatus {
+                name: asset.name(),
+                present: path.exists(),
+                path,
+                source: sources.remove(asset.name()),
+                mirrors: asset.urls(config).split_off(1),
+            }
+        })
+        .collect();
+
+    if json {
+        let report = serde_json::json!({
+            "asset_dir": config.asset_dir,
+            "vm_root": config.vm_root,
+            "assets": assets,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!("Assets:  {}", config.asset_dir.display());
+    println!("VMs:     {}", config.vm_root.display());
+    println!();
+    println!("{:<18} {:<8} {:<8} SOURCE", "ASSET", "PRESENT", "MIRRORS");
+    for asset in &assets {
+        let source = match &asset.source {
+            Some(source) => format!("{} ({})", source.url, source.fetched_at.format("%Y-%m-%d")),
+            None if asset.present => "unknown".to_string(),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:<18} {:<8} {:<8} {}",
+            asset.name,
+            if asset.present { "yes" } else { "no" },
+            asset.mirrors.len(),
+            source
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_mirrors_from_env() {
+        let mirrors = mirrors_from_env(|var| match var {
+            "MEDA_CH_MIRRORS" => Some(
+                "https://mirror.example/ch-static, https://cache.internal/ch-static".to_string(),
+            ),
+            "MEDA_FW_MIRRORS" => Some(" ".to_string()),
+            _ => None,
+        });
+        assert_eq!(mirrors.len(), 1);
+        assert_eq!(
+            mirrors["cloud-hypervisor"],
+            vec![
+                "https://mirror.example/ch-static",
+                "https://cache.internal/ch-static"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_urls_and_sources() {
+        let dir = TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.asset_dir = dir.path().to_path_buf();
+        config.asset_mirrors.insert(
+            "oras".to_string(),
+            vec!["https://mirror.example/oras.tar.gz".to_string()],
+        );
+        assert_eq!(
+            Asset::Oras.urls(&config),
+            vec![
+                config.oras_url.clone(),
+                "https://mirror.example/oras.tar.gz".to_string()
+            ]
+        );
+        assert_eq!(Asset::Firmware.urls(&config), vec![config.fw_url.clone()]);
+
+        assert!(load_sources(&config).is_empty());
+        let download = |url: &str| Downloaded {
+            url: url.to_string(),
+            validator: Some("\"abc\"".to_string()),
+        };
+        record_source(
+            &config,
+            Asset::Oras,
+            &download("https://mirror.example/oras.tar.gz"),
+        )
+        .unwrap();
+        record_source(&config, Asset::Firmware, &download(&config.fw_url)).unwrap();
+        mark_checked(&config, Asset::Firmware).unwrap();
+        mark_checked(&config, Asset::BaseImage).unwrap();
+        let sources = load_sources(&config);
+        assert_eq!(sources["oras"].url, "https://mirror.example/oras.tar.gz");
+        assert_eq!(sources["oras"].validator.as_deref(), Some("\"abc\""));
+        assert!(sources["oras"].checked_at.is_none());
+        assert_eq!(sources["firmware"].url, config.fw_url);
+        assert!(sources["firmware"].checked_at.is_some());
+        assert!(!sources.contains_key("base-image"));
+    }
+}
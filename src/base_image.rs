@@ -0,0 +1,229 @@
+//! The Ubuntu base image new VMs are created from, and keeping it fresh.
+//!
+//! Bootstrap downloads the upstream cloud image once and converts it to
+//! raw. Upstream rebuilds it every few weeks, so a host that never
+//! downloads it again keeps creating VMs from an ever older build. With
+//! `MEDA_IMAGE_MAX_AGE` (e.g. `14d`) bootstrap checks upstream again once
+//! the image is that old, and `meda bootstrap --refresh` checks now. The
+//! check compares the ETag (or Last-Modified) recorded at download with
+//! what upstream serves, and only downloads when they differ.
+//!
+//! A refreshed image goes to a new file, `ubuntu-base-<time>.raw`, named
+//! in `base-image` in the asset dir. qcow2 VMs keep reading the version
+//! they were created from, which backs their root disk, so an old version
+//! is only removed once no VM or image disk is backed by it.
+
+use crate::assets::{self, Asset};
+use crate::config::Config;
+use crate::error::Result;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// File in the asset dir naming the current version; absent until the
+/// first refresh, when it is `Config::base_raw`.
+const CURRENT_FILE: &str = "base-image";
+
+/// The base image new VMs are created from.
+pub fn current(config: &Config) -> PathBuf {
+    match fs::read_to_string(config.asset_dir.join(CURRENT_FILE)) {
+        Ok(name) if !name.trim().is_empty() => config.asset_dir.join(name.trim()),
+        _ => config.base_raw.clone(),
+    }
+}
+
+/// Download the upstream image and convert it to a raw disk at `dest`.
+pub async fn download(config: &Config, dest: &Path) -> Result<()> {
+    let tmp_file = config.asset_dir.join("img.qcow2");
+    assets::fetch(config, Asset::BaseImage, &tmp_file).await?;
+
+    crate::util::ensure_dependency("qemu-img", "qemu-utils")?;
+
+    info!("Converting to raw format");
+    crate::qemu_img::convert(&tmp_file, "qcow2", dest, "raw", false).await?;
+    crate::util::resize_raw_disk(dest, &config.disk_size).await?;
+    fs::remove_file(&tmp_file).ok();
+    Ok(())
+}
+
+/// Is an image last known to match upstream at `checked` due a check?
+fn is_stale(checked: Option<DateTime<Utc>>, max_age: Option<Duration>, now: DateTime<Utc>) -> bool {
+    let Some(max_age) = max_age else {
+        return false;
+    };
+    match checked {
+        Some(checked) => (now - checked).to_std().is_ok_and(|age| age >= max_age),
+        None => true,
+    }
+}
+
+/// When the current image was last fetched or found up to date. Images
+/// from before `sources.json` go by the file's modification time.
+fn last_checked(config: &Config) -> Option<DateTime<Utc>> {
+    match assets::load_sources(config).remove(Asset::BaseImage.name()) {
+        Some(source) => Some(
+            source
+                .checked_at
+                .map_or(source.fetched_at, |checked| checked.max(source.fetched_at)),
+        ),
+        None => fs::metadata(current(config))
+            .and_then(|m| m.modified())
+            .ok()
+            .map(DateTime::<Utc>::from),
+    }
+}
+
+/// Check upstream for a newer base image when the current one is older
+/// than `MEDA_IMAGE_MAX_AGE`, or always with `force`, and download it if
+/// there is one. Without `force`, a failed check only warns, and in
+/// offline mode none is made, so an offline host still creates VMs from
+/// the image it has.
+pub async fn refresh(config: &Config, force: bool) -> Result<()> {
+    if !force
+        && (config.offline || !is_stale(last_checked(config), config.image_max_age, Utc::now()))
+    {
+        return Ok(());
+    }
+    config.require_network("Checking for a newer base image")?;
+    info!("Checking for a newer base image");
+    let recorded = assets::load_sources(config).remove(Asset::BaseImage.name());
+    if let Some(source) = &recorded {
+        match crate::util::remote_validator(&source.url).await {
+            Ok(Some(remote)) if source.validator.as_ref() == Some(&remote) => {
+                info!("Base image is up to date");
+                assets::mark_checked(config, Asset::BaseImage)?;
+                prune(config);
+                return Ok(());
+            }
+            Ok(_) => {}
+            Err(e) if !force => {
+                warn!("Could not check for a newer base image: {}", e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let name = format!("ubuntu-base-{}.raw", Utc::now().format("%Y%m%d%H%M%S"));
+    info!("Downloading a newer base image");
+    download(config, &config.asset_dir.join(&name)).await?;
+    let pointer = config.asset_dir.join(CURRENT_FILE);
+    let tmp = pointer.with_extension("tmp");
+    fs::write(&tmp, &name)?;
+    fs::rename(&tmp, &pointer)?;
+    info!("New VMs are created from {}", name);
+    prune(config);
+    Ok(())
+}
+
+/// Every file some VM or image disk is (transitively) backed by.
+fn backing_files(config: &Config) -> HashSet<PathBuf> {
+    fn walk(dir: &Path, depth: usize, found: &mut HashSet<PathBuf>) {
+        for path in fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|e| e.path())
+        {
+            if path.is_dir() {
+                if depth > 0 {
+                    walk(&path, depth - 1, found);
+                }
+            } else {
+                for backing in crate::util::backing_chain(&path) {
+                    found.insert(fs::canonicalize(&backing).unwrap_or(backing));
+                }
+            }
+        }
+    }
+
+    let mut found = HashSet::new();
+    // VM disks sit at the top of their dirs; image tags are
+    // <registry>/<org>/<name>/<tag>.
+    walk(&config.vm_root, 1, &mut found);
+    walk(&config.asset_dir.join("images"), 4, &mut found);
+    found
+}
+
+/// Base image versions other than the current one.
+fn old_versions(config: &Config) -> Vec<PathBuf> {
+    let current = current(config);
+    fs::read_dir(&config.asset_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| e.path())
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            (name.starts_with("ubuntu-base-") && name.ends_with(".raw")) || path == &config.base_raw
+        })
+        .filter(|path| path != &current && path.is_file())
+        .collect()
+}
+
+/// Remove old base image versions no disk is backed by any more.
+pub fn prune(config: &Config) {
+    let in_use = backing_files(config);
+    for old in old_versions(config) {
+        let canonical = fs::canonicalize(&old).unwrap_or_else(|_| old.clone());
+        if in_use.contains(&canonical) {
+            continue;
+        }
+        match fs::remove_file(&old) {
+            Ok(()) => info!("Removed old base image {}", old.display()),
+            Err(e) => warn!("Failed to remove old base image {}: {}", old.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_is_stale() {
+        let now = Utc::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let days_ago = |n: i64| Some(now - chrono::Duration::days(n));
+
+        assert!(!is_stale(days_ago(100), None, now));
+        assert!(is_stale(None, Some(day * 14), now));
+        assert!(!is_stale(days_ago(3), Some(day * 14), now));
+        assert!(is_stale(days_ago(14), Some(day * 14), now));
+        // A clock that went backwards is not a reason to download.
+        assert!(!is_stale(days_ago(-1), Some(day), now));
+    }
+
+    #[test]
+    fn test_current_and_prune() {
+        let dir = TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.asset_dir = dir.path().join("assets");
+        config.vm_root = dir.path().join("vms");
+        config.base_raw = config.asset_dir.join("ubuntu-base.raw");
+        fs::create_dir_all(&config.asset_dir).unwrap();
+        fs::create_dir_all(&config.vm_root).unwrap();
+        fs::write(&config.base_raw, b"old").unwrap();
+        assert_eq!(current(&config), config.base_raw);
+
+        let newer = config.asset_dir.join("ubuntu-base-20260101000000.raw");
+        fs::write(&newer, b"new").unwrap();
+        fs::write(
+            config.asset_dir.join(CURRENT_FILE),
+            "ubuntu-base-20260101000000.raw\n",
+        )
+        .unwrap();
+        assert_eq!(current(&config), newer);
+        assert_eq!(old_versions(&config), vec![config.base_raw.clone()]);
+
+        // Nothing is backed by the old version, so it goes; the current
+        // one always stays.
+        prune(&config);
+        assert!(!config.base_raw.exists());
+        assert!(newer.exists());
+    }
+}
@@ -0,0 +1,303 @@
+//! `meda start/stop/delete` on several VMs at once.
+//!
+//! VMs are picked by name, by glob (`ci-*`, `web-?`), with `--all`, or
+//! with the `--filter`s `meda list` takes; filters narrow down whatever
+//! the other selectors picked, or every VM if there are none. A single
+//! plain name behaves exactly as before. Anything else runs the
+//! operation on up to `--parallel` VMs at a time and reports each VM's
+//! outcome, failing if any VM did.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::labels::VmFilter;
+use crate::vm::{self, StopOptions};
+use serde::Serialize;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::Instrument;
+
+/// Which VMs a command acts on.
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    pub names: Vec<String>,
+    pub all: bool,
+    pub filters: Vec<VmFilter>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Operation {
+    Start,
+    Stop(StopOptions),
+    Delete,
+}
+
+impl Operation {
+    fn verb(self) -> &'static str {
+        match self {
+            Self::Start => "start",
+            Self::Stop(_) => "stop",
+            Self::Delete => "delete",
+        }
+    }
+
+    async fn run(self, config: &Config, name: &str, json: bool) -> Result<()> {
+        match self {
+            Self::Start => vm::start(config, name, json).await,
+            Self::Stop(options) => vm::stop_with(config, name, options, json).await,
+            Self::Delete => vm::delete(config, name, json).await,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Outcome {
+    pub vm: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// `*` matches any run of characters, `?` any one.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    fn go(p: &[char], n: &[char]) -> bool {
+        match p.split_first() {
+            None => n.is_empty(),
+            Some(('*', rest)) => (0..=n.len()).any(|i| go(rest, &n[i..])),
+            Some(('?', rest)) => !n.is_empty() && go(rest, &n[1..]),
+            Some((c, rest)) => n.first() == Some(c) && go(rest, &n[1..]),
+        }
+    }
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    go(&p, &n)
+}
+
+fn is_glob(name: &str) -> bool {
+    name.contains(['*', '?'])
+}
+
+impl Selection {
+    /// A single plain name, which keeps the one-VM behavior and output.
+    pub fn single(&self) -> Option<&str> {
+        match self.names.as_slice() {
+            [name] if !self.all && self.filters.is_empty() && !is_glob(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Anything other than plain names: `--all`, filters or globs.
+    pub fn is_bulk(&self) -> bool {
+        self.all || !self.filters.is_empty() || self.names.iter().any(|n| is_glob(n))
+    }
+
+    /// Names of the selected VMs, in the order given (globs and `--all`
+    /// in directory order). Plain names are kept even if no such VM
+    /// exists, so they are reported as failures.
+    pub fn resolve(&self, config: &Config) -> Result<Vec<String>> {
+        self.resolve_in(vm::collect_vms(config)?)
+    }
+
+    /// [`Self::resolve`] against `vms`, e.g. another host's VMs.
+    pub fn resolve_in(&self, vms: Vec<vm::VmInfo>) -> Result<Vec<String>> {
+        if self.names.is_empty() && !self.all && self.filters.is_empty() {
+            return Err(Error::Other(
+                "Name at least one VM, or select VMs with --all or --filter".to_string(),
+            ));
+        }
+        let mut picked: Vec<String> = Vec::new();
+        let mut add = |name: &str| {
+            if !picked.iter().any(|p| p == name) {
+                picked.push(name.to_string());
+            }
+        };
+        if self.all || (self.names.is_empty() && !self.filters.is_empty()) {
+            vms.iter().for_each(|vm| add(&vm.name));
+        }
+        for name in &self.names {
+            if is_glob(name) {
+                vms.iter()
+                    .filter(|vm| glob_match(name, &vm.name))
+                    .for_each(|vm| add(&vm.name));
+            } else {
+                add(name);
+            }
+        }
+
+        if !self.filters.is_empty() {
+            let matching: Vec<String> = vm::filter_vms(vms, &self.filters)
+                .into_iter()
+                .map(|vm| vm.name)
+                .collect();
+            picked.retain(|name| matching.contains(name));
+        }
+        Ok(picked)
+    }
+
+    /// Deleting more than plainly named VMs needs `--force`.
+    pub fn confirm_delete(&self, force: bool) -> Result<()> {
+        if self.is_bulk() && !force {
+            return Err(Error::Other(
+                "Deleting VMs with --all, --filter or a glob needs --force".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Run `operation` on every VM in `selection`, `parallel` at a time.
+pub async fn run(
+    config: &Config,
+    selection: &Selection,
+    operation: Operation,
+    parallel: usize,
+    json: bool,
+) -> Result<()> {
+    if let Some(name) = selection.single() {
+        return operation.run(config, name, json).await;
+    }
+    let names = selection.resolve(config)?;
+    let config = config.clone();
+    run_each(names, operation.verb(), parallel, json, move |name| {
+        let config = config.clone();
+        // Per-VM messages would interleave; the summary reports them.
+        async move { operation.run(&config, &name, false).await }
+    })
+    .await
+}
+
+/// Run `op` on each of `names`, `parallel` at a time, and report each
+/// VM's outcome; `verb` names the operation in the summary.
+pub async fn run_each<F, Fut>(
+    names: Vec<String>,
+    verb: &str,
+    parallel: usize,
+    json: bool,
+    op: F,
+) -> Result<()>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    if names.is_empty() {
+        return Err(Error::Other("No VMs match".to_string()));
+    }
+
+    let semaphore = Arc::new(Semaphore::new(parallel.max(1)));
+    let mut handles = Vec::new();
+    for name in names {
+        let semaphore = semaphore.clone();
+        let operation = op(name.clone());
+        handles.push(tokio::spawn(
+            async move {
+                let _permit = semaphore.acquire_owned().await.ok();
+                let result = operation.await;
+                if !json {
+                    match &result {
+                        Ok(()) => println!("✅ {}", name),
+                        Err(e) => println!("❌ {}: {}", name, e),
+                    }
+                }
+                Outcome {
+                    vm: name,
+                    success: result.is_ok(),
+                    error: result.err().map(|e| e.to_string()),
+                }
+            }
+            .in_current_span(),
+        ));
+    }
+
+    let mut outcomes = Vec::new();
+    for handle in handles {
+        outcomes.push(
+            handle
+                .await
+                .map_err(|e| Error::Other(format!("{} task panicked: {}", verb, e)))?,
+        );
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&outcomes)?);
+    }
+    let failed = outcomes.iter().filter(|o| !o.success).count();
+    if failed > 0 {
+        return Err(Error::Other(format!(
+            "Failed to {} {} of {} VMs",
+            verb,
+            failed,
+            outcomes.len()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("ci-*", "ci-1"));
+        assert!(glob_match("ci-*", "ci-"));
+        assert!(glob_match("*-db", "prod-db"));
+        assert!(glob_match("web-?", "web-2"));
+        assert!(!glob_match("web-?", "web-12"));
+        assert!(!glob_match("ci-*", "dev-ci-1"));
+        assert!(glob_match("*", "anything"));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.vm_root = dir.path().to_path_buf();
+        for name in ["ci-1", "ci-2", "web", "__tpl_ubuntu", ".migrate-ci-3"] {
+            std::fs::create_dir(config.vm_dir(name)).unwrap();
+        }
+        crate::labels::write_labels(
+            &config.vm_dir("ci-2"),
+            &[("pool".to_string(), "gpu".to_string())]
+                .into_iter()
+                .collect(),
+        )
+        .unwrap();
+
+        let select = |names: &[&str], all: bool, filters: &[&str]| Selection {
+            names: names.iter().map(|n| n.to_string()).collect(),
+            all,
+            filters: filters.iter().map(|f| f.parse().unwrap()).collect(),
+        };
+        let sorted = |mut v: Vec<String>| {
+            v.sort();
+            v
+        };
+
+        assert_eq!(select(&["web"], false, &[]).single(), Some("web"));
+        assert_eq!(select(&["ci-*"], false, &[]).single(), None);
+        assert_eq!(
+            sorted(select(&["ci-*"], false, &[]).resolve(&config).unwrap()),
+            ["ci-1", "ci-2"]
+        );
+        assert_eq!(
+            sorted(select(&[], true, &[]).resolve(&config).unwrap()),
+            ["ci-1", "ci-2", "web"]
+        );
+        assert_eq!(
+            sorted(select(&["*"], false, &[]).resolve(&config).unwrap()),
+            ["ci-1", "ci-2", "web"]
+        );
+        assert_eq!(
+            select(&[], false, &["label=pool=gpu"])
+                .resolve(&config)
+                .unwrap(),
+            ["ci-2"]
+        );
+        assert_eq!(
+            select(&["web", "gone", "web"], false, &[])
+                .resolve(&config)
+                .unwrap(),
+            ["web", "gone"]
+        );
+        assert!(select(&[], false, &[]).resolve(&config).is_err());
+    }
+}
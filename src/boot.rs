@@ -0,0 +1,172 @@
+//! Direct kernel boot.
+//!
+//! VMs normally boot the image's own kernel through hypervisor-fw.
+//! `meda create --kernel` (and `meda run --kernel`) instead has Cloud
+//! Hypervisor load a kernel from the host, with an optional initramfs
+//! and command line, which is what kernel developers want when testing
+//! a build against a real root disk. The choice is recorded in the VM
+//! dir and turned into `--kernel`/`--initramfs`/`--cmdline` in the
+//! VM's launch spec.
+//!
+//! The files are used where they are, not copied, so rebuilding the
+//! kernel and restarting the VM boots the new build.
+
+use crate::doctor::{Finding, Status};
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File in a VM dir with its direct boot settings; absent for firmware boot.
+pub const BOOT_FILE: &str = "boot.json";
+
+/// Root on the first partition of the root disk (the layout of the
+/// Ubuntu cloud images), kernel messages on the serial socket.
+pub const DEFAULT_CMDLINE: &str = "console=ttyS0 root=/dev/vda1 rw";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DirectBoot {
+    pub kernel: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initramfs: Option<PathBuf>,
+    pub cmdline: String,
+}
+
+fn existing_file(path: &Path, what: &str) -> Result<PathBuf> {
+    let resolved = fs::canonicalize(path)
+        .map_err(|e| Error::Other(format!("{} {}: {}", what, path.display(), e)))?;
+    if !resolved.is_file() {
+        return Err(Error::Other(format!(
+            "{} {} is not a file",
+            what,
+            path.display()
+        )));
+    }
+    Ok(resolved)
+}
+
+impl DirectBoot {
+    /// Check the files exist (paths are made absolute, as CH runs in
+    /// the VM dir) and the command line is a single line.
+    pub fn new(kernel: &Path, initramfs: Option<&Path>, cmdline: Option<&str>) -> Result<Self> {
+        let cmdline = cmdline.unwrap_or(DEFAULT_CMDLINE).trim().to_string();
+        if let Some(c) = cmdline.chars().find(|c| c.is_control()) {
+            return Err(Error::Other(format!(
+                "Kernel command line must not contain {:?}",
+                c
+            )));
+        }
+        Ok(Self {
+            kernel: existing_file(kernel, "Kernel")?,
+            initramfs: initramfs
+                .map(|p| existing_file(p, "Initramfs"))
+                .transpose()?,
+            cmdline,
+        })
+    }
+
+    pub fn load(vm_dir: &Path) -> Option<Self> {
+        let body = fs::read_to_string(vm_dir.join(BOOT_FILE)).ok()?;
+        serde_json::from_str(&body).ok()
+    }
+
+    pub fn save(&self, vm_dir: &Path) -> Result<()> {
+        fs::write(vm_dir.join(BOOT_FILE), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Are the files still there? They live outside meda's control.
+    pub fn host_finding(&self) -> Finding {
+        let missing: Vec<String> = std::iter::once(&self.kernel)
+            .chain(&self.initramfs)
+            .filter(|p| !p.is_file())
+            .map(|p| p.display().to_string())
+            .collect();
+        if missing.is_empty() {
+            Finding::new(
+                "boot",
+                Status::Ok,
+                format!("direct kernel boot from {}", self.kernel.display()),
+            )
+        } else {
+            Finding::new(
+                "boot",
+                Status::Fail,
+                format!("kernel boot files missing: {}", missing.join(", ")),
+            )
+        }
+    }
+}
+
+/// CH boot arguments for the VM in `vm_dir`: its kernel, initramfs and
+/// command line, or `firmware` for VMs without direct boot.
+pub fn ch_args(vm_dir: &Path, firmware: &Path) -> Vec<String> {
+    let Some(boot) = DirectBoot::load(vm_dir) else {
+        return vec!["--kernel".to_string(), firmware.display().to_string()];
+    };
+    let mut args = vec!["--kernel".to_string(), boot.kernel.display().to_string()];
+    if let Some(initramfs) = &boot.initramfs {
+        args.push("--initramfs".to_string());
+        args.push(initramfs.display().to_string());
+    }
+    args.push("--cmdline".to_string());
+    args.push(boot.cmdline);
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_new_validates() {
+        let dir = TempDir::new().unwrap();
+        let kernel = dir.path().join("vmlinux");
+        fs::write(&kernel, b"").unwrap();
+
+        let boot = DirectBoot::new(&kernel, None, None).unwrap();
+        assert_eq!(boot.cmdline, DEFAULT_CMDLINE);
+        assert!(boot.kernel.is_absolute());
+
+        assert!(DirectBoot::new(&dir.path().join("missing"), None, None).is_err());
+        assert!(DirectBoot::new(dir.path(), None, None).is_err());
+        assert!(DirectBoot::new(&kernel, Some(&dir.path().join("initrd")), None).is_err());
+        assert!(DirectBoot::new(&kernel, None, Some("root=/dev/vda1\ninit=/bin/sh")).is_err());
+        let quoted = DirectBoot::new(&kernel, None, Some("root=/dev/vda1 dyndbg=\"+p\"")).unwrap();
+        assert_eq!(quoted.cmdline, "root=/dev/vda1 dyndbg=\"+p\"");
+    }
+
+    #[test]
+    fn test_ch_args() {
+        let dir = TempDir::new().unwrap();
+        let firmware = Path::new("/assets/hypervisor-fw");
+        assert_eq!(
+            ch_args(dir.path(), firmware),
+            ["--kernel", "/assets/hypervisor-fw"]
+        );
+
+        let kernel = dir.path().join("bzImage");
+        let initramfs = dir.path().join("initrd.img");
+        fs::write(&kernel, b"").unwrap();
+        fs::write(&initramfs, b"").unwrap();
+        let boot = DirectBoot::new(&kernel, Some(&initramfs), Some("console=ttyS0 quiet")).unwrap();
+        boot.save(dir.path()).unwrap();
+        assert_eq!(DirectBoot::load(dir.path()), Some(boot.clone()));
+        assert_eq!(
+            ch_args(dir.path(), firmware),
+            [
+                "--kernel".to_string(),
+                boot.kernel.display().to_string(),
+                "--initramfs".to_string(),
+                boot.initramfs.unwrap().display().to_string(),
+                "--cmdline".to_string(),
+                "console=ttyS0 quiet".to_string(),
+            ]
+        );
+
+        fs::remove_file(&kernel).unwrap();
+        let finding = DirectBoot::load(dir.path()).unwrap().host_finding();
+        assert_eq!(finding.status, Status::Fail);
+    }
+}
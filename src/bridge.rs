@@ -0,0 +1,207 @@
+//! Bridged networking (`meda create --network bridged --bridge br0`).
+//!
+//! By default a VM sits behind the host: its tap lives in a per-VM
+//! netns with its own /24, the host NATs its traffic out and DNATs the
+//! netns IP in. A bridged VM's tap is instead attached to an existing
+//! host bridge, so the guest is a peer on the LAN: it gets its address
+//! from the LAN's DHCP server and other machines reach it directly.
+//! meda sets up no netns, subnet, NAT or iptables rules for it and only
+//! removes the tap when the VM is deleted.
+//!
+//! The bridge name is recorded in the VM dir, which is how every later
+//! step (start, delete, `meda ip`, clones) knows which layout the VM
+//! uses.
+
+use crate::error::{Error, Result};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// File in a VM dir naming the bridge its tap is attached to.
+pub const BRIDGE_FILE: &str = "bridge";
+
+/// How a VM's NIC is connected.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum NetworkMode {
+    /// Behind the host: per-VM netns, NAT and DNAT.
+    #[default]
+    Nat,
+    /// Tap attached to this existing host bridge.
+    Bridged(String),
+    /// NIC from passt, without root (see `usernet`).
+    User,
+}
+
+impl NetworkMode {
+    /// Mode from `--network` (default `nat`) and `--bridge`.
+    pub fn new(mode: Option<&str>, bridge: Option<&str>) -> Result<Self> {
+        match (mode.unwrap_or("nat"), bridge) {
+            ("nat", None) => Ok(Self::Nat),
+            ("nat", Some(_)) => Err(Error::Other(
+                "a bridge only applies to --network bridged".to_string(),
+            )),
+            ("bridged", Some(bridge)) => {
+                parse_bridge_name(bridge).map_err(Error::Other)?;
+                Ok(Self::Bridged(bridge.to_string()))
+            }
+            ("bridged", None) => Err(Error::Other(
+                "--network bridged needs --bridge <name>".to_string(),
+            )),
+            ("user", None) => Ok(Self::User),
+            ("user", Some(_)) => Err(Error::Other(
+                "a bridge only applies to --network bridged".to_string(),
+            )),
+            (other, _) => Err(Error::Other(format!(
+                "unknown network mode '{}' (expected nat, bridged or user)",
+                other
+            ))),
+        }
+    }
+
+    /// The mode the VM in `vm_dir` was created with.
+    pub fn load(vm_dir: &Path) -> Self {
+        match bridge_of(vm_dir) {
+            Some(bridge) => Self::Bridged(bridge),
+            None if crate::usernet::is_enabled(vm_dir) => Self::User,
+            None => Self::Nat,
+        }
+    }
+
+    pub fn bridge(&self) -> Option<&str> {
+        match self {
+            Self::Nat | Self::User => None,
+            Self::Bridged(bridge) => Some(bridge),
+        }
+    }
+}
+
+/// Value parser for `--bridge`: a Linux interface name.
+pub fn parse_bridge_name(s: &str) -> std::result::Result<String, String> {
+    let valid = !s.is_empty()
+        && s.len() <= 15
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!("invalid bridge name '{}'", s))
+    }
+}
+
+/// Fail unless `bridge` exists on the host and is a bridge.
+pub fn check_bridge(bridge: &str) -> Result<()> {
+    if Path::new("/sys/class/net")
+        .join(bridge)
+        .join("bridge")
+        .exists()
+    {
+        Ok(())
+    } else {
+        Err(Error::Other(format!(
+            "bridge {} does not exist on this host; create it first (e.g. with netplan or `ip link add {} type bridge`)",
+            bridge, bridge
+        )))
+    }
+}
+
+/// Bridge the VM's tap is attached to, if it is bridged.
+pub fn bridge_of(vm_dir: &Path) -> Option<String> {
+    fs::read_to_string(vm_dir.join(BRIDGE_FILE))
+        .ok()
+        .map(|b| b.trim().to_string())
+        .filter(|b| !b.is_empty())
+}
+
+pub fn record(vm_dir: &Path, bridge: &str) -> Result<()> {
+    fs::write(vm_dir.join(BRIDGE_FILE), bridge)?;
+    Ok(())
+}
+
+/// Shell lines for the launch spec's setup (run as root) that create
+/// `tap` if it is missing, e.g. after a host reboot, and attach it to
+/// `bridge`.
+pub fn tap_commands(tap: &str, bridge: &str) -> String {
+    format!(
+        "ip link show {tap} >/dev/null 2>&1 || ip tuntap add {tap} mode tap\n\
+         ip link set {tap} master {bridge}\n\
+         ip link set {tap} up\n"
+    )
+}
+
+/// Address the guest took on the LAN, as seen in the host's neighbour
+/// table for the bridge. None until the host has exchanged traffic
+/// with the guest.
+pub fn guest_ip(vm_dir: &Path) -> Option<String> {
+    let bridge = bridge_of(vm_dir)?;
+    let mac = fs::read_to_string(vm_dir.join("mac")).ok()?;
+    let output = Command::new("ip")
+        .args(["-4", "neigh", "show", "dev", &bridge])
+        .output()
+        .ok()?;
+    parse_neighbours(&String::from_utf8_lossy(&output.stdout), mac.trim())
+}
+
+/// `ip neigh show dev <bridge>` lines are `<ip> lladdr <mac> <state>`.
+fn parse_neighbours(neighbours: &str, mac: &str) -> Option<String> {
+    neighbours.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [ip, "lladdr", lladdr, state @ ..]
+                if lladdr.eq_ignore_ascii_case(mac) && !state.contains(&"FAILED") =>
+            {
+                Some(ip.to_string())
+            }
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_network_mode() {
+        assert_eq!(NetworkMode::new(None, None).unwrap(), NetworkMode::Nat);
+        assert_eq!(
+            NetworkMode::new(Some("bridged"), Some("br0")).unwrap(),
+            NetworkMode::Bridged("br0".to_string())
+        );
+        assert!(NetworkMode::new(Some("bridged"), None).is_err());
+        assert!(NetworkMode::new(Some("nat"), Some("br0")).is_err());
+        assert!(NetworkMode::new(Some("macvtap"), None).is_err());
+        assert_eq!(
+            NetworkMode::new(Some("user"), None).unwrap(),
+            NetworkMode::User
+        );
+        assert!(NetworkMode::new(Some("user"), Some("br0")).is_err());
+        assert!(NetworkMode::new(Some("bridged"), Some("br0; reboot")).is_err());
+        assert!(parse_bridge_name("a-very-long-bridge-name").is_err());
+    }
+
+    #[test]
+    fn test_bridge_of_and_tap_commands() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(bridge_of(dir.path()), None);
+        record(dir.path(), "br0").unwrap();
+        assert_eq!(bridge_of(dir.path()).as_deref(), Some("br0"));
+
+        let script = tap_commands("tap-abc", "br0");
+        assert!(script.contains("ip tuntap add tap-abc mode tap"));
+        assert!(script.contains("ip link set tap-abc master br0"));
+        assert!(!script.contains('\''));
+    }
+
+    #[test]
+    fn test_parse_neighbours() {
+        let neighbours = "192.168.1.1 lladdr 00:11:22:33:44:55 REACHABLE\n\
+                          192.168.1.40 lladdr 52:54:00:aa:bb:cc FAILED\n\
+                          192.168.1.41 lladdr 52:54:00:AA:BB:CC STALE\n";
+        assert_eq!(
+            parse_neighbours(neighbours, "52:54:00:aa:bb:cc").as_deref(),
+            Some("192.168.1.41")
+        );
+        assert_eq!(parse_neighbours(neighbours, "52:54:00:00:00:01"), None);
+    }
+}
@@ -0,0 +1,386 @@
+//! `meda build` — images from a declarative Medafile, without packer.
+//!
+//! The Medafile is JSON:
+//!
+//! ```json
+//! {
+//!   "from": "ubuntu:latest",
+//!   "image": "web:v1",
+//!   "disk": "20G",
+//!   "steps": [
+//!     { "packages": ["nginx"] },
+//!     { "file": { "src": "nginx.conf", "dest": "/etc/nginx/nginx.conf" } },
+//!     { "run": "sudo systemctl enable nginx" }
+//!   ]
+//! }
+//! ```
+//!
+//! meda boots a throwaway VM from `from` (the base cloud image when
+//! omitted), waits for cloud-init, and applies the steps in order over
+//! SSH as `cirun`: `packages` apt-installs, `file` copies a file next to
+//! the Medafile into the guest, `run` runs a shell command (prefix it
+//! with `sudo` for root). It then wipes the VM's identity, as the runner
+//! image recipe does, turns its disk into `image` and deletes the VM.
+//!
+//! A failed build deletes its VM too, unless `--keep-on-failure` leaves
+//! it for inspection.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::image::{self, ImageRef};
+use crate::vm;
+use crate::wait::{self, Stage};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Run before the disk is captured, so every VM made from the image runs
+/// cloud-init and gets its own identity.
+const FORGET_IDENTITY: &str = "sudo apt-get clean && \
+     sudo rm -rf /var/lib/apt/lists/* && \
+     sudo truncate -s 0 /etc/machine-id && \
+     sudo rm -f /etc/ssh/ssh_host_* && \
+     sudo cloud-init clean --logs --seed && \
+     sync";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Medafile {
+    /// Image to build from; omit to build from the base cloud image.
+    pub from: Option<String>,
+    /// Image to produce, `name[:tag]` or a full reference.
+    pub image: String,
+    pub memory: Option<String>,
+    pub cpus: Option<u8>,
+    pub disk: Option<String>,
+    #[serde(default)]
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum Step {
+    /// apt packages to install, optionally pinned (`nginx=1.24.*`).
+    Packages(Vec<String>),
+    /// A local file to copy into the guest.
+    File(FileStep),
+    /// A shell command run as `cirun`.
+    Run(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileStep {
+    /// Path on the host, relative to the Medafile's directory.
+    pub src: PathBuf,
+    /// Absolute path in the guest; parent directories are created.
+    pub dest: String,
+    /// Octal permissions (default 0644).
+    #[serde(default = "default_mode")]
+    pub mode: String,
+}
+
+fn default_mode() -> String {
+    "0644".to_string()
+}
+
+#[derive(Debug, Serialize)]
+pub struct BuildResult {
+    pub image: String,
+    pub steps: usize,
+    pub elapsed_secs: f64,
+}
+
+impl Medafile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let body = fs::read_to_string(path)
+            .map_err(|e| Error::Other(format!("Failed to read {}: {}", path.display(), e)))?;
+        let medafile: Self = serde_json::from_str(&body)
+            .map_err(|e| Error::Other(format!("Invalid Medafile {}: {}", path.display(), e)))?;
+        medafile.validate()?;
+        Ok(medafile)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (i, step) in self.steps.iter().enumerate() {
+            let invalid = |why: String| Error::Other(format!("step {}: {}", i + 1, why));
+            match step {
+                Step::Packages(packages) => {
+                    if packages.is_empty() {
+                        return Err(invalid("no packages listed".to_string()));
+                    }
+                    if let Some(bad) = packages.iter().find(|p| !is_package(p)) {
+                        return Err(invalid(format!("invalid package name '{}'", bad)));
+                    }
+                }
+                Step::File(file) => {
+                    if !file.dest.starts_with('/') || file.dest.contains('\'') {
+                        return Err(invalid(format!(
+                            "dest '{}' must be an absolute path without single quotes",
+                            file.dest
+                        )));
+                    }
+                    let octal = file.mode.chars().all(|c| ('0'..='7').contains(&c));
+                    if !octal || !(3..=4).contains(&file.mode.len()) {
+                        return Err(invalid(format!("invalid mode '{}'", file.mode)));
+                    }
+                }
+                Step::Run(command) => {
+                    if command.trim().is_empty() {
+                        return Err(invalid("empty command".to_string()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// apt package names, with an optional `=version` or `:arch`. Keeps
+/// them from doubling as shell syntax.
+fn is_package(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.:=~*".contains(c))
+}
+
+impl Step {
+    /// What the step runs in the guest.
+    fn command(&self) -> String {
+        match self {
+            Self::Packages(packages) => format!(
+                "sudo env DEBIAN_FRONTEND=noninteractive apt-get update -q && \
+                 sudo env DEBIAN_FRONTEND=noninteractive apt-get install -y -q {}",
+                packages.join(" ")
+            ),
+            Self::File(file) => format!(
+                "sudo mkdir -p \"$(dirname '{dest}')\" && sudo tee '{dest}' >/dev/null && sudo chmod {mode} '{dest}'",
+                dest = file.dest,
+                mode = file.mode
+            ),
+            Self::Run(command) => command.clone(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Self::Packages(packages) => format!("packages: {}", packages.join(" ")),
+            Self::File(file) => format!("file: {} -> {}", file.src.display(), file.dest),
+            Self::Run(command) => format!("run: {}", command),
+        }
+    }
+}
+
+/// Build the image `path` describes. Fails if it exists unless `force`;
+/// `timeout` bounds the build VM's boot and cloud-init.
+pub async fn build(
+    config: &Config,
+    path: &Path,
+    timeout: Duration,
+    force: bool,
+    keep_on_failure: bool,
+    json: bool,
+) -> Result<()> {
+    let medafile = Medafile::load(path)?;
+    let context = path.parent().unwrap_or(Path::new("."));
+    let target = ImageRef::parse(
+        &medafile.image,
+        &config.default_registry,
+        &config.default_org,
+    )?;
+    if target.local_dir(config).join("manifest.json").exists() && !force {
+        return Err(Error::Other(format!(
+            "{} already exists; pass --force to rebuild it",
+            target.url()
+        )));
+    }
+
+    let started = Instant::now();
+    let build_vm = format!("medafile-build-{}", std::process::id());
+    if !json {
+        info!("Building {} in VM {}", target.url(), build_vm);
+    }
+    let built = run(
+        config, &medafile, context, &build_vm, &target, timeout, json,
+    )
+    .await;
+    if let Err(e) = built {
+        if keep_on_failure {
+            return Err(Error::Other(format!(
+                "{}; VM {} is left for inspection (`meda delete {}` when done)",
+                e, build_vm, build_vm
+            )));
+        }
+        if config.vm_dir(&build_vm).exists() {
+            vm::delete(config, &build_vm, true).await.ok();
+        }
+        return Err(e);
+    }
+
+    let result = BuildResult {
+        image: target.url(),
+        steps: medafile.steps.len(),
+        elapsed_secs: (started.elapsed().as_secs_f64() * 10.0).round() / 10.0,
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!(
+            "Built {} from {} step(s) in {:.1}s",
+            result.image, result.steps, result.elapsed_secs
+        );
+    }
+    Ok(())
+}
+
+async fn run(
+    config: &Config,
+    medafile: &Medafile,
+    context: &Path,
+    build_vm: &str,
+    target: &ImageRef,
+    timeout: Duration,
+    json: bool,
+) -> Result<()> {
+    let resources = vm::VmResources::from_config_with_overrides(
+        config,
+        medafile.memory.as_deref(),
+        medafile.cpus,
+        medafile.disk.as_deref(),
+        Vec::new(),
+    );
+    match &medafile.from {
+        Some(from) => {
+            let options = image::RunOptions {
+                vm_name: Some(build_vm),
+                registry: None,
+                org: None,
+                user_data_path: None,
+                ssh_keys: &[],
+                labels: &Default::default(),
+                no_start: false,
+                resources,
+                ttl: None,
+            };
+            image::run_from_image(config, from, options, json).await?;
+        }
+        None => {
+            vm::create(
+                config,
+                build_vm,
+                None,
+                &[],
+                &Default::default(),
+                &resources,
+                json,
+            )
+            .await?;
+            vm::start(config, build_vm, json).await?;
+        }
+    }
+
+    let host = wait::until(config, build_vm, Stage::CloudInit, timeout)
+        .await?
+        .ip;
+    for (i, step) in medafile.steps.iter().enumerate() {
+        if !json {
+            info!(
+                "Step {}/{}: {}",
+                i + 1,
+                medafile.steps.len(),
+                step.describe()
+            );
+        }
+        let applied = match step {
+            Step::File(file) => crate::ssh::guest_exec_with_input(
+                config,
+                &host,
+                &step.command(),
+                &context.join(&file.src),
+            ),
+            _ => crate::ssh::guest_exec(config, &host, &step.command()),
+        };
+        applied.map_err(|e| Error::Other(format!("step {} failed: {}", i + 1, e)))?;
+    }
+    crate::ssh::guest_exec(config, &host, FORGET_IDENTITY)?;
+
+    image::create_from_vm(
+        config,
+        build_vm,
+        &target.name,
+        &target.tag,
+        &target.registry,
+        &target.org,
+        None,
+        json,
+    )
+    .await?;
+    vm::delete(config, build_vm, json).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(body: &str) -> Result<Medafile> {
+        let medafile: Medafile =
+            serde_json::from_str(body).map_err(|e| Error::Other(e.to_string()))?;
+        medafile.validate()?;
+        Ok(medafile)
+    }
+
+    #[test]
+    fn test_parse_medafile() {
+        let medafile = parse(
+            r#"{
+                "from": "ubuntu:latest",
+                "image": "web:v1",
+                "steps": [
+                    { "packages": ["nginx", "curl=8.5.*"] },
+                    { "file": { "src": "site.conf", "dest": "/etc/nginx/sites-enabled/site" } },
+                    { "run": "sudo systemctl enable nginx" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(medafile.from.as_deref(), Some("ubuntu:latest"));
+        assert_eq!(
+            medafile.steps[1],
+            Step::File(FileStep {
+                src: PathBuf::from("site.conf"),
+                dest: "/etc/nginx/sites-enabled/site".to_string(),
+                mode: "0644".to_string(),
+            })
+        );
+        assert!(medafile.steps[0]
+            .command()
+            .ends_with("apt-get install -y -q nginx curl=8.5.*"));
+        assert_eq!(
+            medafile.steps[1].command(),
+            "sudo mkdir -p \"$(dirname '/etc/nginx/sites-enabled/site')\" && \
+             sudo tee '/etc/nginx/sites-enabled/site' >/dev/null && \
+             sudo chmod 0644 '/etc/nginx/sites-enabled/site'"
+        );
+        assert_eq!(medafile.steps[2].command(), "sudo systemctl enable nginx");
+    }
+
+    #[test]
+    fn test_parse_medafile_rejects() {
+        for body in [
+            r#"{ "steps": [] }"#,
+            r#"{ "image": "x", "steps": [{ "packages": ["nginx; reboot"] }] }"#,
+            r#"{ "image": "x", "steps": [{ "packages": [] }] }"#,
+            r#"{ "image": "x", "steps": [{ "file": { "src": "a", "dest": "etc/a" } }] }"#,
+            r#"{ "image": "x", "steps": [{ "file": { "src": "a", "dest": "/a'b" } }] }"#,
+            r#"{ "image": "x", "steps": [{ "file": { "src": "a", "dest": "/a", "mode": "u+x" } }] }"#,
+            r#"{ "image": "x", "steps": [{ "run": " " }] }"#,
+            r#"{ "image": "x", "steps": [{ "shell": "ls" }] }"#,
+        ] {
+            assert!(parse(body).is_err(), "{}", body);
+        }
+    }
+}
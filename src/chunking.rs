@@ -1,10 +1,24 @@
+use crate::compression::Compression;
 use crate::error::{Error, Result};
+use crate::progress::Progress;
 use log::info;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{BufWriter, Read, Write};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Suffix of the index pushed next to a file's chunks
+/// (`base.raw.chunk.index`): its [`ChunkMetadata`], with digests.
+const INDEX_SUFFIX: &str = ".chunk.index";
+
+/// Read/write granularity inside a chunk, so workers don't each hold a
+/// whole (up to 500MB) chunk in memory.
+const IO_BLOCK: usize = 8 * 1024 * 1024;
 
 /// Configuration for file chunking
 #[derive(Clone, Debug)]
@@ -27,6 +41,10 @@ pub struct ChunkingConfig {
     pub oras_push_concurrency: Option<u32>,
     /// ORAS pull concurrency (defaults to oras_concurrency)
     pub oras_pull_concurrency: Option<u32>,
+    /// Chunks written, read back or verified at once
+    pub workers: usize,
+    /// Compression applied to artifacts before they are chunked and pushed
+    pub compression: Compression,
 }
 
 impl Default for ChunkingConfig {
@@ -41,6 +59,8 @@ impl Default for ChunkingConfig {
             oras_concurrency: 10,                          // 10 concurrent transfers by default
             oras_push_concurrency: None,                   // Use oras_concurrency
             oras_pull_concurrency: None,                   // Use oras_concurrency
+            workers: 4,
+            compression: Compression::None,
         }
     }
 }
@@ -64,7 +84,11 @@ pub struct ChunkMetadata {
     pub total_chunks: usize,
     pub chunk_size: u64,
     pub total_size: u64,
+    /// SHA256 of the whole file
     pub sha256: Option<String>,
+    /// SHA256 of each chunk, by index
+    #[serde(default)]
+    pub chunk_sha256: Vec<String>,
 }
 
 /// Information about a single chunk
@@ -98,7 +122,7 @@ impl FileChunker {
     }
 
     /// Determine the appropriate chunk size for a file
-    fn get_chunk_size(&self, file_size: u64) -> u64 {
+    pub fn get_chunk_size(&self, file_size: u64) -> u64 {
         if file_size >= self.config.large_file_threshold {
             self.config.large_chunk_size
         } else if file_size >= self.config.medium_file_threshold {
@@ -108,7 +132,8 @@ impl FileChunker {
         }
     }
 
-    /// Split a large file into chunks
+    /// Split a large file into chunks, `workers` at a time, recording
+    /// each chunk's SHA256 in an index written next to them.
     pub fn chunk_file(
         &self,
         file_path: &Path,
@@ -140,56 +165,79 @@ impl FileChunker {
 
         // Create output directory if it doesn't exist
         fs::create_dir_all(output_dir)?;
-
-        let mut source_file = File::open(file_path)?;
-        let mut chunks = Vec::new();
-        let mut buffer = vec![0u8; chunk_size as usize];
-
-        for chunk_index in 0..total_chunks {
-            let chunk_filename = format!("{}.chunk.{:03}", filename, chunk_index);
-            let chunk_path = output_dir.join(&chunk_filename);
-
-            // Read chunk data
-            let bytes_to_read =
-                std::cmp::min(chunk_size, file_size - (chunk_index as u64 * chunk_size));
-            buffer.resize(bytes_to_read as usize, 0);
-
-            source_file.read_exact(&mut buffer[..bytes_to_read as usize])?;
-
-            // Write chunk file
-            let mut chunk_file = File::create(&chunk_path)?;
-            chunk_file.write_all(&buffer[..bytes_to_read as usize])?;
-            chunk_file.flush()?;
-
-            chunks.push(ChunkInfo {
-                chunk_path,
-                chunk_index,
-                chunk_size: bytes_to_read,
+        let events = Mutex::new(Progress::bytes("chunk", &filename, Some(file_size)));
+        let done = AtomicU64::new(0);
+
+        let source_file = File::open(file_path)?;
+        let chunks: Vec<ChunkInfo> = (0..total_chunks)
+            .map(|chunk_index| {
+                let offset = chunk_index as u64 * chunk_size;
+                ChunkInfo {
+                    chunk_path: output_dir.join(format!("{}.chunk.{:03}", filename, chunk_index)),
+                    chunk_index,
+                    chunk_size: chunk_size.min(file_size - offset),
+                }
+            })
+            .collect();
+
+        let (file_digest, chunk_sha256) = std::thread::scope(|scope| {
+            // The whole-file digest needs one sequential pass; run it
+            // alongside the chunk writers instead of after them.
+            let whole = scope.spawn(|| sha256_file(file_path));
+            let digests = self.for_each_chunk(&chunks, |chunk| {
+                let mut chunk_file = BufWriter::new(File::create(&chunk.chunk_path)?);
+                let digest = copy_range(
+                    &source_file,
+                    chunk.chunk_index as u64 * chunk_size,
+                    chunk.chunk_size,
+                    |block| {
+                        chunk_file.write_all(block)?;
+                        let total = done.fetch_add(block.len() as u64, Ordering::Relaxed);
+                        events.lock().unwrap().update(total + block.len() as u64);
+                        Ok(())
+                    },
+                )?;
+                chunk_file.flush()?;
+
+                if !json {
+                    info!(
+                        "📦 Created chunk {}/{}: {} ({:.2} MB)",
+                        chunk.chunk_index + 1,
+                        total_chunks,
+                        chunk.chunk_path.file_name().unwrap().to_string_lossy(),
+                        chunk.chunk_size as f64 / 1024.0 / 1024.0
+                    );
+                }
+                Ok(digest)
             });
+            let whole = whole
+                .join()
+                .map_err(|_| Error::Other("Hashing thread panicked".to_string()));
+            (whole.and_then(|r| r), digests)
+        });
 
-            if !json {
-                info!(
-                    "📦 Created chunk {}/{}: {} ({:.2} MB)",
-                    chunk_index + 1,
-                    total_chunks,
-                    chunk_filename,
-                    bytes_to_read as f64 / 1024.0 / 1024.0
-                );
-            }
-        }
+        events.into_inner().unwrap().finish(Some(file_size));
 
         let metadata = ChunkMetadata {
             original_filename: filename.to_string(),
             total_chunks,
             chunk_size,
             total_size: file_size,
-            sha256: None, // TODO: Calculate SHA256 if needed
+            sha256: Some(file_digest?),
+            chunk_sha256: chunk_sha256?,
         };
+        fs::write(
+            index_path(output_dir, &metadata.original_filename),
+            serde_json::to_string_pretty(&metadata)?,
+        )?;
 
         Ok((metadata, chunks))
     }
 
-    /// Reassemble chunks back into the original file
+    /// Reassemble chunks back into the original file, `workers` at a
+    /// time. When `metadata` has chunk digests (from the index pushed
+    /// with them), every chunk is verified and a mismatch fails the
+    /// reassembly, leaving no output file behind.
     pub fn reassemble_chunks(
         &self,
         chunks: &[ChunkInfo],
@@ -206,22 +254,19 @@ impl FileChunker {
         }
 
         // Sort chunks by index to ensure correct order
-        let mut sorted_chunks: Vec<_> = chunks.iter().collect();
+        let mut sorted_chunks: Vec<_> = chunks.to_vec();
         sorted_chunks.sort_by_key(|chunk| chunk.chunk_index);
 
         // Verify we have all chunks
         if sorted_chunks.len() != metadata.total_chunks {
-            return Err(Error::Other(format!(
-                "Missing chunks: expected {}, found {}",
+            return Err(Error::CorruptArtifact(format!(
+                "missing chunks of {}: expected {}, found {}",
+                metadata.original_filename,
                 metadata.total_chunks,
                 sorted_chunks.len()
             )));
         }
 
-        // Create output file
-        let mut output_file = BufWriter::new(File::create(output_path)?);
-        let mut total_written = 0u64;
-
         for (i, chunk_info) in sorted_chunks.iter().enumerate() {
             if chunk_info.chunk_index != i {
                 return Err(Error::Other(format!(
@@ -236,51 +281,147 @@ impl FileChunker {
                     chunk_info.chunk_path.display()
                 )));
             }
-
-            // Copy chunk data to output file
-            let mut chunk_file = File::open(&chunk_info.chunk_path)?;
-            let mut buffer = vec![0u8; chunk_info.chunk_size as usize];
-            chunk_file.read_exact(&mut buffer)?;
-
-            output_file.write_all(&buffer)?;
-            total_written += chunk_info.chunk_size;
-
-            if !json {
-                info!(
-                    "📝 Wrote chunk {}/{} ({:.2} MB)",
-                    i + 1,
-                    metadata.total_chunks,
-                    chunk_info.chunk_size as f64 / 1024.0 / 1024.0
-                );
-            }
         }
 
-        output_file.flush()?;
-
         // Verify total size matches
-        if total_written != metadata.total_size {
+        let total_size: u64 = sorted_chunks.iter().map(|c| c.chunk_size).sum();
+        if total_size != metadata.total_size {
             return Err(Error::Other(format!(
-                "Size mismatch after reassembly: expected {}, got {}",
-                metadata.total_size, total_written
+                "Size mismatch: chunks add up to {} bytes, expected {}",
+                total_size, metadata.total_size
             )));
         }
 
+        let result = self.write_chunks(&sorted_chunks, metadata, output_path, json);
+        if result.is_err() {
+            let _ = fs::remove_file(output_path);
+        }
+        result?;
+
         if !json {
             info!(
                 "✅ Successfully reassembled file: {:.2} MB",
-                total_written as f64 / 1024.0 / 1024.0
+                total_size as f64 / 1024.0 / 1024.0
             );
         }
 
         Ok(())
     }
 
+    fn write_chunks(
+        &self,
+        chunks: &[ChunkInfo],
+        metadata: &ChunkMetadata,
+        output_path: &Path,
+        json: bool,
+    ) -> Result<()> {
+        let output_file = File::create(output_path)?;
+        output_file.set_len(metadata.total_size)?;
+        let offsets: Vec<u64> = chunks
+            .iter()
+            .scan(0u64, |offset, c| {
+                let start = *offset;
+                *offset += c.chunk_size;
+                Some(start)
+            })
+            .collect();
+        let events = Mutex::new(Progress::bytes(
+            "reassemble",
+            &metadata.original_filename,
+            Some(metadata.total_size),
+        ));
+        let done = AtomicU64::new(0);
+
+        self.for_each_chunk(chunks, |chunk| {
+            let chunk_file = File::open(&chunk.chunk_path)?;
+            let mut offset = offsets[chunk.chunk_index];
+            let digest = copy_range(&chunk_file, 0, chunk.chunk_size, |block| {
+                output_file.write_all_at(block, offset)?;
+                offset += block.len() as u64;
+                let total = done.fetch_add(block.len() as u64, Ordering::Relaxed);
+                events.lock().unwrap().update(total + block.len() as u64);
+                Ok(())
+            })?;
+
+            if let Some(expected) = metadata.chunk_sha256.get(chunk.chunk_index) {
+                if *expected != digest {
+                    return Err(Error::CorruptArtifact(format!(
+                        "chunk {}/{} of {}: expected sha256 {}, got {}",
+                        chunk.chunk_index + 1,
+                        metadata.total_chunks,
+                        metadata.original_filename,
+                        expected,
+                        digest
+                    )));
+                }
+            }
+
+            if !json {
+                info!(
+                    "📝 Wrote chunk {}/{} ({:.2} MB)",
+                    chunk.chunk_index + 1,
+                    metadata.total_chunks,
+                    chunk.chunk_size as f64 / 1024.0 / 1024.0
+                );
+            }
+            Ok(())
+        })?;
+
+        output_file.sync_all()?;
+        events.into_inner().unwrap().finish(Some(done.into_inner()));
+        Ok(())
+    }
+
+    /// Run `f` over `chunks` on up to `workers` threads, returning the
+    /// results by chunk index, or the first error.
+    fn for_each_chunk<T: Send>(
+        &self,
+        chunks: &[ChunkInfo],
+        f: impl Fn(&ChunkInfo) -> Result<T> + Sync,
+    ) -> Result<Vec<T>> {
+        let next = AtomicUsize::new(0);
+        let results: Mutex<Vec<Option<Result<T>>>> =
+            Mutex::new((0..chunks.len()).map(|_| None).collect());
+        let workers = self.config.workers.clamp(1, chunks.len().max(1));
+        std::thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(chunk) = chunks.get(i) else {
+                        break;
+                    };
+                    let result = f(chunk);
+                    let failed = result.is_err();
+                    results.lock().unwrap()[i] = Some(result);
+                    if failed {
+                        // Stop handing out chunks; others finish theirs.
+                        next.store(chunks.len(), Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+        results
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .collect::<Result<Vec<T>>>()
+            .and_then(|r| {
+                if r.len() == chunks.len() {
+                    Ok(r)
+                } else {
+                    Err(Error::Other("Chunk worker exited early".to_string()))
+                }
+            })
+    }
+
     /// Detect chunk files in a directory and group them by original file
     pub fn detect_chunks(
         &self,
         scan_dir: &Path,
     ) -> Result<HashMap<String, (ChunkMetadata, Vec<ChunkInfo>)>> {
         let mut chunk_groups: HashMap<String, Vec<ChunkInfo>> = HashMap::new();
+        let mut indexes: HashMap<String, ChunkMetadata> = HashMap::new();
 
         // Scan directory for chunk files
         if !scan_dir.exists() {
@@ -294,6 +435,16 @@ impl FileChunker {
             if path.is_file() {
                 let filename = path.file_name().unwrap().to_string_lossy();
 
+                if let Some(original) = filename.strip_suffix(INDEX_SUFFIX) {
+                    let metadata =
+                        serde_json::from_str(&fs::read_to_string(&path)?).map_err(|e| {
+                            Error::Other(format!("Invalid chunk index {}: {}", filename, e))
+                        })?;
+                    indexes.insert(original.to_string(), metadata);
+                    chunk_groups.entry(original.to_string()).or_default();
+                    continue;
+                }
+
                 // Check if this looks like a chunk file: "filename.chunk.XXX"
                 if let Some(chunk_info) = self.parse_chunk_filename(&filename, &path)? {
                     chunk_groups
@@ -319,13 +470,17 @@ impl FileChunker {
                 0
             };
 
-            let metadata = ChunkMetadata {
+            // Images pushed with an index say how many chunks there
+            // should be and what they hash to; older ones only have
+            // what was found.
+            let metadata = indexes.remove(&original_filename).unwrap_or(ChunkMetadata {
                 original_filename: original_filename.clone(),
                 total_chunks,
                 chunk_size,
                 total_size,
                 sha256: None,
-            };
+                chunk_sha256: Vec::new(),
+            });
 
             result.insert(original_filename, (metadata, chunks));
         }
@@ -361,17 +516,244 @@ impl FileChunker {
         Ok(None)
     }
 
-    /// Clean up temporary chunk files
+    /// Clean up temporary chunk files and their index
     pub fn cleanup_chunks(&self, chunks: &[ChunkInfo]) -> Result<()> {
         for chunk in chunks {
             if chunk.chunk_path.exists() {
                 fs::remove_file(&chunk.chunk_path)?;
             }
+            let name = chunk.chunk_path.file_name().unwrap().to_string_lossy();
+            if let (Some(dir), Some(pos)) = (chunk.chunk_path.parent(), name.rfind(".chunk.")) {
+                let index = index_path(dir, &name[..pos]);
+                if index.exists() {
+                    fs::remove_file(index)?;
+                }
+            }
         }
         Ok(())
     }
 }
 
+/// Where the index of `original_filename`'s chunks lives in `dir`.
+pub fn index_path(dir: &Path, original_filename: &str) -> PathBuf {
+    dir.join(format!("{}{}", original_filename, INDEX_SUFFIX))
+}
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let file = File::open(path)?;
+    copy_range(&file, 0, file.metadata()?.len(), |_| Ok(()))
+}
+
+/// Read `len` bytes of `file` from `offset` in [`IO_BLOCK`] pieces,
+/// handing each to `sink`; returns their SHA256 (hex).
+fn copy_range(
+    file: &File,
+    offset: u64,
+    len: u64,
+    mut sink: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; IO_BLOCK.min(len as usize)];
+    let mut pos = 0u64;
+    while pos < len {
+        let n = IO_BLOCK.min((len - pos) as usize);
+        file.read_exact_at(&mut buffer[..n], offset + pos)?;
+        hasher.update(&buffer[..n]);
+        sink(&buffer[..n])?;
+        pos += n as u64;
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Clap value parser for `--chunk-size`.
+pub fn parse_chunk_size(s: &str) -> std::result::Result<u64, String> {
+    crate::util::parse_size_bytes(s)
+        .filter(|&b| b > 0)
+        .ok_or_else(|| format!("invalid size '{}' (expected e.g. 512M)", s))
+}
+
+/// One file handled by `meda chunk` / `meda assemble`.
+#[derive(Serialize, Debug)]
+pub struct ChunkReport {
+    pub file: String,
+    pub path: PathBuf,
+    pub size_bytes: u64,
+    pub chunks: usize,
+    pub sha256: Option<String>,
+    pub verified: bool,
+}
+
+/// `meda chunk`: split any file (no size threshold) into `output_dir`,
+/// default `<file>.chunks`, with an index carrying its digests, so it
+/// can be carried over by hand and put back with `meda assemble`.
+pub fn chunk_command(
+    config: ChunkingConfig,
+    file: &Path,
+    output_dir: Option<&Path>,
+    chunk_size: Option<u64>,
+    json: bool,
+) -> Result<()> {
+    if !file.is_file() {
+        return Err(Error::Other(format!("{} is not a file", file.display())));
+    }
+    let output_dir = match output_dir {
+        Some(dir) => dir.to_path_buf(),
+        None => PathBuf::from(format!("{}.chunks", file.display())),
+    };
+    let mut config = ChunkingConfig {
+        min_chunk_threshold: 0,
+        ..config
+    };
+    if let Some(size) = chunk_size {
+        if size == 0 {
+            return Err(Error::Other("Chunk size must be above zero".to_string()));
+        }
+        config.small_chunk_size = size;
+        config.medium_chunk_size = size;
+        config.large_chunk_size = size;
+    }
+
+    let (metadata, _) = FileChunker::with_config(config).chunk_file(file, &output_dir, json)?;
+    let report = ChunkReport {
+        file: metadata.original_filename,
+        path: output_dir,
+        size_bytes: metadata.total_size,
+        chunks: metadata.total_chunks,
+        sha256: metadata.sha256,
+        verified: true,
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!(
+            "Chunked {} into {} chunk(s) in {}",
+            report.file,
+            report.chunks,
+            report.path.display()
+        );
+        println!("sha256: {}", report.sha256.as_deref().unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// `meda assemble`: put back every file chunked into `dir`, into
+/// `output_dir` (default: the current dir), checking each chunk and the
+/// whole file against the index. With `verify_only`, nothing is written.
+pub fn assemble_command(
+    config: ChunkingConfig,
+    dir: &Path,
+    output_dir: Option<&Path>,
+    verify_only: bool,
+    json: bool,
+) -> Result<()> {
+    let chunker = FileChunker::with_config(config);
+    let mut groups: Vec<_> = chunker.detect_chunks(dir)?.into_values().collect();
+    if groups.is_empty() {
+        return Err(Error::Other(format!(
+            "No chunks found in {}",
+            dir.display()
+        )));
+    }
+    groups.sort_by(|a, b| a.0.original_filename.cmp(&b.0.original_filename));
+    let output_dir = output_dir.unwrap_or(Path::new("."));
+
+    let mut reports = Vec::new();
+    for (metadata, chunks) in groups {
+        let expected = metadata.sha256.clone().ok_or_else(|| {
+            Error::Other(format!(
+                "No chunk index for {} in {}; cannot verify it",
+                metadata.original_filename,
+                dir.display()
+            ))
+        })?;
+        if chunks.len() != metadata.total_chunks {
+            return Err(Error::CorruptArtifact(format!(
+                "{}: found {} of {} chunks",
+                metadata.original_filename,
+                chunks.len(),
+                metadata.total_chunks
+            )));
+        }
+
+        let path = if verify_only {
+            dir.join(&metadata.original_filename)
+        } else {
+            output_dir.join(&metadata.original_filename)
+        };
+        let digest = if verify_only {
+            verify_chunks(&metadata, &chunks)?
+        } else {
+            if path.exists() {
+                return Err(Error::Other(format!("{} already exists", path.display())));
+            }
+            fs::create_dir_all(output_dir)?;
+            chunker.reassemble_chunks(&chunks, &metadata, &path, json)?;
+            sha256_file(&path)?
+        };
+        if digest != expected {
+            if !verify_only {
+                let _ = fs::remove_file(&path);
+            }
+            return Err(Error::CorruptArtifact(format!(
+                "{}: expected sha256 {}, got {}",
+                metadata.original_filename, expected, digest
+            )));
+        }
+
+        reports.push(ChunkReport {
+            file: metadata.original_filename,
+            path,
+            size_bytes: metadata.total_size,
+            chunks: metadata.total_chunks,
+            sha256: Some(digest),
+            verified: true,
+        });
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    } else {
+        for report in &reports {
+            if verify_only {
+                println!("✅ {}: {} chunk(s) verified", report.file, report.chunks);
+            } else {
+                println!(
+                    "✅ Assembled {} ({} chunk(s), sha256 verified)",
+                    report.path.display(),
+                    report.chunks
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hash `chunks` in order, checking each against the index; returns the
+/// whole file's SHA256.
+fn verify_chunks(metadata: &ChunkMetadata, chunks: &[ChunkInfo]) -> Result<String> {
+    let mut whole = Sha256::new();
+    for chunk in chunks {
+        let file = File::open(&chunk.chunk_path)?;
+        let digest = copy_range(&file, 0, chunk.chunk_size, |block| {
+            whole.update(block);
+            Ok(())
+        })?;
+        if let Some(expected) = metadata.chunk_sha256.get(chunk.chunk_index) {
+            if *expected != digest {
+                return Err(Error::CorruptArtifact(format!(
+                    "chunk {}/{} of {}: expected sha256 {}, got {}",
+                    chunk.chunk_index + 1,
+                    metadata.total_chunks,
+                    metadata.original_filename,
+                    expected,
+                    digest
+                )));
+            }
+        }
+    }
+    Ok(format!("{:x}", whole.finalize()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -468,6 +850,58 @@ mod tests {
         assert_eq!(reassembled_data, test_data);
     }
 
+    #[test]
+    fn test_reassemble_verifies_chunk_digests() {
+        let temp_dir = TempDir::new().unwrap();
+        let chunker = FileChunker::with_config(ChunkingConfig {
+            min_chunk_threshold: 1024,
+            small_chunk_size: 1024,
+            ..Default::default()
+        });
+
+        let test_data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let source_file = temp_dir.path().join("base.raw");
+        std::fs::write(&source_file, &test_data).unwrap();
+        let chunk_dir = temp_dir.path().join("chunks");
+        let (metadata, _) = chunker.chunk_file(&source_file, &chunk_dir, true).unwrap();
+        assert_eq!(metadata.total_chunks, 5);
+        assert_eq!(metadata.chunk_sha256.len(), 5);
+        assert_eq!(
+            metadata.sha256.as_deref(),
+            Some(format!("{:x}", Sha256::digest(&test_data)).as_str())
+        );
+
+        // The index travels with the chunks and is what a pull sees.
+        let detected = chunker.detect_chunks(&chunk_dir).unwrap();
+        let (pulled, chunks) = detected.get("base.raw").unwrap();
+        assert_eq!(pulled.chunk_sha256, metadata.chunk_sha256);
+        let output = temp_dir.path().join("out.raw");
+        chunker
+            .reassemble_chunks(chunks, pulled, &output, true)
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), test_data);
+
+        // Same size, different bytes.
+        std::fs::write(&chunks[3].chunk_path, vec![0u8; 1024]).unwrap();
+        let err = chunker
+            .reassemble_chunks(chunks, pulled, &output, true)
+            .unwrap_err();
+        assert!(matches!(err, Error::CorruptArtifact(_)), "{err}");
+        assert!(err.to_string().contains("chunk 4/5"), "{err}");
+        assert!(!output.exists());
+
+        // A missing chunk is caught even though the rest look complete.
+        std::fs::remove_file(&chunks[4].chunk_path).unwrap();
+        let detected = chunker.detect_chunks(&chunk_dir).unwrap();
+        let (pulled, chunks) = detected.get("base.raw").unwrap();
+        assert!(chunker
+            .reassemble_chunks(chunks, pulled, &output, true)
+            .is_err());
+
+        chunker.cleanup_chunks(chunks).unwrap();
+        assert!(!index_path(&chunk_dir, "base.raw").exists());
+    }
+
     #[test]
     fn test_parse_chunk_filename() {
         let temp_dir = TempDir::new().unwrap();
@@ -505,4 +939,58 @@ mod tests {
         assert_eq!(metadata.total_chunks, 3);
         assert_eq!(chunks.len(), 3);
     }
+
+    #[test]
+    fn test_chunk_and_assemble_commands() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("notes.bin");
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+
+        let chunks = temp_dir.path().join("out");
+        chunk_command(
+            ChunkingConfig::default(),
+            &source,
+            Some(&chunks),
+            Some(4096),
+            true,
+        )
+        .unwrap();
+        assert!(chunks.join("notes.bin.chunk.002").exists());
+        assert!(index_path(&chunks, "notes.bin").exists());
+
+        let restored = temp_dir.path().join("restored");
+        assemble_command(
+            ChunkingConfig::default(),
+            &chunks,
+            Some(&restored),
+            true,
+            true,
+        )
+        .unwrap();
+        assert!(!restored.exists());
+        assemble_command(
+            ChunkingConfig::default(),
+            &chunks,
+            Some(&restored),
+            false,
+            true,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(restored.join("notes.bin")).unwrap(), data);
+        // Never overwrites.
+        assert!(assemble_command(
+            ChunkingConfig::default(),
+            &chunks,
+            Some(&restored),
+            false,
+            true
+        )
+        .is_err());
+
+        // A missing chunk is caught before anything is written.
+        std::fs::remove_file(chunks.join("notes.bin.chunk.001")).unwrap();
+        let err = assemble_command(ChunkingConfig::default(), &chunks, None, true, true);
+        assert!(matches!(err, Err(Error::CorruptArtifact(_))));
+    }
 }
@@ -1,4 +1,9 @@
-use clap::{Parser, Subcommand};
+use crate::image::LiveMode;
+use crate::labels::VmFilter;
+use crate::output::OutputFormat;
+use crate::progress::ProgressMode;
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(author, version, about = "Cloud-Hypervisor VM Manager", long_about = None)]
@@ -7,6 +12,19 @@ pub struct Cli {
     #[arg(long, global = true)]
     pub json: bool,
 
+    /// Progress reporting: `human` bars, or `json` events (one per line on stderr)
+    #[arg(long, global = true, value_enum, default_value = "human")]
+    pub progress: ProgressMode,
+
+    /// Never use the network: fail at once when something isn't cached locally
+    #[arg(long, global = true)]
+    pub offline: bool,
+
+    /// Run the command against this context's API instead of this host
+    /// (see `meda context`); also read from MEDA_CONTEXT
+    #[arg(long, global = true, value_name = "NAME")]
+    pub context: Option<String>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -21,6 +39,64 @@ pub enum Commands {
         /// Path to user-data file (optional)
         user_data: Option<String>,
 
+        /// Authorize an SSH public key for `cirun` (path or key string,
+        /// repeatable); disables password login. Also read from MEDA_SSH_PUBKEY
+        #[arg(long = "ssh-key")]
+        ssh_key: Vec<String>,
+
+        /// Label the VM (key=value, repeatable); select with `meda list --filter label=key=value`
+        #[arg(long = "label", value_parser = crate::labels::parse_label)]
+        label: Vec<(String, String)>,
+
+        /// Set an environment variable in the guest (KEY=VALUE, repeatable),
+        /// in /etc/meda/env and login shells
+        #[arg(long = "env", value_name = "KEY=VALUE", value_parser = crate::guest_env::parse_var)]
+        env: Vec<(String, String)>,
+
+        /// Like --env, but in /etc/meda/secret-env, readable by root only;
+        /// `meda get` and `meda list` show the value as <redacted>
+        #[arg(long = "secret-env", value_name = "KEY=VALUE", value_parser = crate::guest_env::parse_var)]
+        secret_env: Vec<(String, String)>,
+
+        /// Copy a host file into the guest on first boot (repeatable), e.g.
+        /// key.pem:/etc/runner/key.pem:0600:runner; mode defaults to 0600
+        #[arg(long, value_name = "HOST:GUEST[:MODE[:OWNER]]", value_parser = crate::secrets::Secret::parse)]
+        secret: Vec<crate::secrets::Secret>,
+
+        /// Storage pool for the VM dir (from MEDA_STORAGE_POOLS; default: vm_root)
+        #[arg(long)]
+        storage: Option<String>,
+
+        /// Block traffic to and from other VMs (outbound internet still
+        /// works); MEDA_ISOLATE=1 makes this the default
+        #[arg(long)]
+        isolate: bool,
+
+        /// Outbound policy, first match wins (e.g. allow:10.0.5.0/24,deny:10.0.0.0/8),
+        /// or the name of a policy in <config dir>/egress-policies
+        #[arg(long, value_name = "POLICY")]
+        egress: Option<String>,
+
+        /// Send the VM's outbound NAT traffic out of this host interface
+        /// (e.g. eth1) instead of the host's default route
+        #[arg(long, value_name = "IFACE", value_parser = crate::uplink::parse_interface)]
+        egress_interface: Option<String>,
+
+        /// Entropy source of the VM's virtio-rng device, or `none` for no
+        /// device [default: $MEDA_RNG_SOURCE or /dev/urandom]
+        #[arg(long, value_name = "SOURCE", value_parser = crate::rng::RngSource::parse)]
+        rng: Option<crate::rng::RngSource>,
+
+        /// nat (default): behind the host; bridged: on the LAN via --bridge,
+        /// with the guest's address from the LAN's DHCP server; user:
+        /// user-mode networking through passt, without sudo
+        #[arg(long, value_name = "MODE", value_parser = ["nat", "bridged", "user"])]
+        network: Option<String>,
+
+        /// Existing host bridge to attach the VM's tap to (with --network bridged)
+        #[arg(long, value_name = "NAME", value_parser = crate::bridge::parse_bridge_name)]
+        bridge: Option<String>,
+
         /// Force create (delete if exists)
         #[arg(short, long)]
         force: bool,
@@ -33,6 +109,37 @@ pub enum Commands {
         #[arg(long)]
         cpus: Option<u8>,
 
+        /// Back guest memory with a shared mapping (needed by vhost-user
+        /// devices such as virtiofs)
+        #[arg(long)]
+        shared_memory: bool,
+
+        /// Back guest memory with the host's hugepage pool, which must have
+        /// enough free pages when the VM starts (see `meda doctor`)
+        #[arg(long)]
+        hugepages: bool,
+
+        /// Fault in all guest memory at boot
+        #[arg(long)]
+        prefault: bool,
+
+        /// Add a balloon device, so memory the guest frees goes back to
+        /// the host and `ch-remote resize --balloon` can reclaim more
+        #[arg(long)]
+        balloon: bool,
+
+        /// Pin the VM to these host CPUs (e.g. 0-3 or 2,4-7)
+        #[arg(long, value_name = "CPUS", value_parser = crate::cpu_affinity::CpuSet::parse)]
+        cpu_affinity: Option<crate::cpu_affinity::CpuSet>,
+
+        /// Boot with room for this many vCPUs, for `meda hotplug`
+        #[arg(long, value_name = "N")]
+        max_cpus: Option<u8>,
+
+        /// Boot with room for this much memory (e.g. 16G), for `meda hotplug`
+        #[arg(long, value_name = "SIZE")]
+        max_memory: Option<String>,
+
         /// Disk size (e.g., 10G, 20G, 5120M)
         #[arg(long)]
         disk: Option<String>,
@@ -40,39 +147,197 @@ pub enum Commands {
         /// VFIO device path for PCI passthrough (repeatable, e.g., /sys/bus/pci/devices/0000:01:00.0)
         #[arg(long)]
         device: Vec<String>,
+
+        /// Use a thin qcow2 overlay on the shared base image for the root
+        /// disk, overriding MEDA_DISK_FORMAT=raw
+        #[arg(long)]
+        cow: bool,
+
+        /// Reset the root disk to the image on every start; keep state
+        /// on a --data-disk instead
+        #[arg(long)]
+        immutable_root: bool,
+
+        /// Attach a persistent data disk of this size (e.g., 20G),
+        /// formatted and mounted by cloud-init
+        #[arg(long, value_name = "SIZE")]
+        data_disk: Option<String>,
+
+        /// Where the data disk is mounted in the guest
+        #[arg(long, value_name = "PATH", requires = "data_disk")]
+        data_mount: Option<String>,
+
+        /// Share a host directory with the guest over virtiofs
+        /// (repeatable, e.g. /srv/src:/mnt/src, or /srv/src:/mnt/src:ro
+        /// read-only); needs virtiofsd
+        #[arg(long, value_name = "HOST:GUEST[:ro]", value_parser = crate::virtiofs::Share::parse)]
+        mount: Vec<crate::virtiofs::Share>,
+
+        /// Add a vsock device, so `meda exec` and `meda get` can talk to
+        /// a guest agent without SSH (see docs/AGENT.md)
+        #[arg(long)]
+        vsock: bool,
+
+        /// Serve cloud-init data to the guest over HTTP instead of
+        /// attaching a seed ISO; the guest takes its address over DHCP
+        /// (needs dnsmasq)
+        #[arg(long)]
+        no_iso: bool,
+
+        #[command(flatten)]
+        boot: DirectBootArgs,
     },
 
     /// List all VMs
-    List,
+    List {
+        /// Output format: json, jsonpath='{.field}' or template='{{.field}}'
+        #[arg(short = 'o', long = "output")]
+        output: Option<OutputFormat>,
+
+        /// Only list matching VMs: label=key[=value] or state=running|stopped|error|creating|starting|stopping (repeatable, all must match)
+        #[arg(long)]
+        filter: Vec<VmFilter>,
+    },
 
     /// Get VM details
     Get {
         /// Name of the VM
         name: String,
+
+        /// Output format: json, jsonpath='{.field}' or template='{{.field}}'
+        #[arg(short = 'o', long = "output")]
+        output: Option<OutputFormat>,
     },
 
     /// Get VM IP address
     Ip {
         /// Name of the VM
         name: String,
+        /// Check that the guest answers on --port; exit 0 if it does, 2
+        /// if it has an address but doesn't answer, 3 if it has no
+        /// address yet
+        #[arg(long)]
+        check: bool,
+        /// Port --check connects to
+        #[arg(long, default_value_t = 22, requires = "check")]
+        port: u16,
     },
 
-    /// Start a VM
-    Start {
+    /// Print what a VM printed on its serial console, e.g. to see why
+    /// cloud-init failed
+    ConsoleDump {
         /// Name of the VM
         name: String,
+
+        /// Number of trailing lines to print
+        #[arg(long, default_value_t = 200)]
+        tail: usize,
     },
 
-    /// Stop a VM
+    /// Wait until a VM is ready: has an address (ip), answers SSH (ssh),
+    /// or has finished cloud-init (cloud-init)
+    Wait {
+        /// Name of the VM
+        name: String,
+
+        /// Readiness stage to wait for
+        #[arg(long = "for", value_enum, default_value = "ssh")]
+        stage: crate::wait::Stage,
+
+        /// Seconds to wait before giving up
+        #[arg(long, default_value_t = 300)]
+        timeout: u64,
+    },
+
+    /// Run a shell command in a VM, through its guest agent if it has
+    /// one and over SSH otherwise; exits with the command's exit code
+    Exec {
+        /// Name of the VM
+        name: String,
+
+        /// Seconds to wait for the command through the agent
+        #[arg(long, default_value_t = 300)]
+        timeout: u64,
+
+        /// Command to run (e.g., meda exec web -- systemctl status nginx)
+        #[arg(trailing_var_arg = true, required = true)]
+        command: Vec<String>,
+    },
+
+    /// Start VMs
+    Start {
+        #[command(flatten)]
+        vms: VmSelection,
+    },
+
+    /// Stop VMs: press their ACPI power button, then kill them if the
+    /// guest hasn't powered off in time
     Stop {
+        #[command(flatten)]
+        vms: VmSelection,
+        /// Seconds to wait for the guest to shut down (default
+        /// MEDA_STOP_TIMEOUT, or 30)
+        #[arg(long)]
+        timeout: Option<u64>,
+        /// Kill the VM without asking the guest to shut down
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Restart a VM (stop as `meda stop` does, then start); it keeps its IP
+    Restart {
         /// Name of the VM
         name: String,
+        /// Seconds to wait for the guest to shut down (default
+        /// MEDA_STOP_TIMEOUT, or 30)
+        #[arg(long)]
+        timeout: Option<u64>,
+        /// Kill the VM without asking the guest to shut down
+        #[arg(long)]
+        force: bool,
     },
 
-    /// Delete a VM
+    /// Delete VMs
     Delete {
+        #[command(flatten)]
+        vms: VmSelection,
+
+        /// Confirm deleting with --all, --filter or a glob
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Change a VM's memory, CPUs and/or disk size
+    Resize {
+        /// Name of the VM
+        name: String,
+
+        /// New memory size (e.g., 4G, 2048M)
+        #[arg(long)]
+        memory: Option<String>,
+
+        /// New number of CPUs
+        #[arg(long)]
+        cpus: Option<u8>,
+
+        /// New disk size; disks can only grow (e.g., 20G)
+        #[arg(long)]
+        disk: Option<String>,
+    },
+
+    /// Change a running VM's vCPUs and/or memory without a reboot, up to
+    /// the --max-cpus / --max-memory it was created with
+    Hotplug {
         /// Name of the VM
         name: String,
+
+        /// New number of vCPUs
+        #[arg(long)]
+        cpus: Option<u8>,
+
+        /// New memory size; memory can only grow (e.g., 4G)
+        #[arg(long)]
+        memory: Option<String>,
     },
 
     /// Forward host port to guest port
@@ -89,16 +354,31 @@ pub enum Commands {
 
     /// Pull an image from a registry
     Pull {
-        /// Image name with optional tag (e.g., ubuntu-noble:latest)
-        image: String,
+        /// Image names with optional tags (e.g., ubuntu-noble:latest)
+        #[arg(required_unless_present = "file")]
+        images: Vec<String>,
 
-        /// Registry URL (default: ghcr.io)
+        /// Also pull the images listed in this file, one per line (`-`
+        /// for stdin)
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+
+        /// How many images to pull at once when pulling several
+        #[arg(long, default_value = "3")]
+        parallel: usize,
+
+        /// Registry URL (default: $MEDA_DEFAULT_REGISTRY or ghcr.io)
         #[arg(long)]
         registry: Option<String>,
 
         /// Organization/namespace (default: trycua)
         #[arg(long)]
         org: Option<String>,
+
+        /// Only fetch these artifacts (e.g., base_image,user-data); one
+        /// image only
+        #[arg(long, value_delimiter = ',', conflicts_with = "file")]
+        artifacts: Vec<String>,
     },
 
     /// Push an image to a registry
@@ -109,28 +389,99 @@ pub enum Commands {
         /// Target image name with tag (e.g., my-registry/my-image:v1.0)
         image: String,
 
-        /// Registry URL (default: ghcr.io)
+        /// Registry URL (default: $MEDA_DEFAULT_REGISTRY or ghcr.io)
         #[arg(long)]
         registry: Option<String>,
 
         /// Dry run - don't actually push
         #[arg(long)]
         dry_run: bool,
+
+        /// Annotate the image with where it came from: source VM, base
+        /// image digest, build time and the git commit from CI
+        #[arg(long)]
+        provenance: bool,
+
+        /// Sign the pushed image with cosign (key from $MEDA_SIGNING_KEY,
+        /// keyless with $SIGSTORE_ID_TOKEN or in GitHub Actions otherwise)
+        /// and attach the signature to it
+        #[arg(long)]
+        sign: bool,
+
+        /// Also tag the pushed image with this tag in the same repository
+        /// (repeatable); artifacts are uploaded once
+        #[arg(long = "also-tag", value_name = "TAG")]
+        also_tag: Vec<String>,
+    },
+
+    /// Store credentials for a registry, used by push and pull
+    Login {
+        /// Registry host (e.g., ghcr.io, registry.example.com:5000, docker.io)
+        registry: String,
+
+        /// Username
+        #[arg(short, long)]
+        username: String,
+
+        /// Password or token (visible in shell history; prefer --password-stdin)
+        #[arg(short, long, conflicts_with = "password_stdin")]
+        password: Option<String>,
+
+        /// Read the password or token from stdin
+        #[arg(long)]
+        password_stdin: bool,
+    },
+
+    /// Remove the stored credentials for a registry
+    Logout {
+        /// Registry host
+        registry: String,
     },
 
     /// List cached images
-    Images,
+    Images {
+        /// Output format: json, jsonpath='{.field}' or template='{{.field}}'
+        #[arg(short = 'o', long = "output")]
+        output: Option<OutputFormat>,
+
+        /// Show each image's registry manifest digest
+        #[arg(long)]
+        digests: bool,
+    },
+
+    /// Create another tag for a local image without copying it
+    Tag {
+        /// Existing image name and tag (e.g., ubuntu:latest)
+        source: String,
+
+        /// New name and tag (e.g., ubuntu:golden)
+        target: String,
+
+        /// Registry URL for both images (default: $MEDA_DEFAULT_REGISTRY or ghcr.io)
+        #[arg(long)]
+        registry: Option<String>,
+
+        /// Organization/namespace for both images (default: $MEDA_DEFAULT_ORG or cirunlabs)
+        #[arg(long)]
+        org: Option<String>,
+    },
+
+    /// Inspect local images
+    Image {
+        #[command(subcommand)]
+        command: ImageCommands,
+    },
 
     /// Remove a specific image
     Rmi {
         /// Image name and tag (e.g., ubuntu:latest, ubuntu)
         image: String,
 
-        /// Registry URL (default: ghcr.io)
+        /// Registry URL (default: $MEDA_DEFAULT_REGISTRY or ghcr.io)
         #[arg(long)]
         registry: Option<String>,
 
-        /// Organization/namespace (default: cirunlabs)
+        /// Organization/namespace (default: $MEDA_DEFAULT_ORG or cirunlabs)
         #[arg(long)]
         org: Option<String>,
 
@@ -141,10 +492,14 @@ pub enum Commands {
 
     /// Remove unused images
     Prune {
-        /// Remove all images (not just unused ones)
+        /// Remove all images, including ones VMs were created from
         #[arg(long)]
         all: bool,
 
+        /// Only remove images at least this old (e.g. 7d, 12h, 30m)
+        #[arg(long, value_name = "DURATION")]
+        older_than: Option<String>,
+
         /// Don't prompt for confirmation
         #[arg(short, long)]
         force: bool,
@@ -159,17 +514,66 @@ pub enum Commands {
         #[arg(short, long, default_value = "latest")]
         tag: String,
 
-        /// Registry URL (default: ghcr.io)
+        /// Registry URL (default: $MEDA_DEFAULT_REGISTRY or ghcr.io)
         #[arg(long)]
         registry: Option<String>,
 
-        /// Organization/namespace (default: cirunlabs)
+        /// Organization/namespace (default: $MEDA_DEFAULT_ORG or cirunlabs)
         #[arg(long)]
         org: Option<String>,
 
         /// Create from existing VM instead of base image
         #[arg(long)]
         from_vm: Option<String>,
+
+        /// Copy a running --from-vm without stopping it: `crash` (default;
+        /// pause + reflink) or `freeze` (also fsfreeze the guest over SSH)
+        #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "crash", requires = "from_vm")]
+        live: Option<LiveMode>,
+    },
+
+    /// Build an image from a Medafile: boot a throwaway VM, apply the
+    /// file's steps over SSH and turn its disk into the image
+    Build {
+        /// Path to the Medafile
+        #[arg(short, long, default_value = "Medafile")]
+        file: PathBuf,
+
+        /// Seconds the build VM gets to boot and finish cloud-init
+        #[arg(long, default_value_t = 600)]
+        timeout: u64,
+
+        /// Rebuild even if the image already exists
+        #[arg(long)]
+        force: bool,
+
+        /// Leave the build VM for inspection if a step fails
+        #[arg(long)]
+        keep_on_failure: bool,
+    },
+
+    /// Build GitHub-runner-ready images from meda's built-in recipe
+    RunnerImage {
+        #[command(subcommand)]
+        command: RunnerImageCommands,
+    },
+
+    /// Import a Docker/OCI container image as a bootable VM image
+    ///
+    /// Layers are flattened onto the Ubuntu base disk, which supplies the
+    /// kernel and bootloader. Missing init/cloud-init/sshd are installed
+    /// with apt-get when the container has it.
+    ImportOci {
+        /// Container image (e.g., docker.io/library/ubuntu:24.04, alpine:3.20)
+        source: String,
+
+        /// Local image name to create (e.g., ubuntu-ct:latest)
+        #[arg(long)]
+        name: String,
+
+        /// Don't install missing packages (init, cloud-init, sshd) into the rootfs
+        #[arg(long)]
+        no_install: bool,
     },
 
     /// Run a VM from an image — classic cold-boot path (~27s). Use
@@ -187,11 +591,11 @@ pub enum Commands {
         #[arg(short, long)]
         name: Option<String>,
 
-        /// Registry URL (default: ghcr.io)
+        /// Registry URL (default: $MEDA_DEFAULT_REGISTRY or ghcr.io)
         #[arg(long)]
         registry: Option<String>,
 
-        /// Organization/namespace (default: cirunlabs)
+        /// Organization/namespace (default: $MEDA_DEFAULT_ORG or cirunlabs)
         #[arg(long)]
         org: Option<String>,
 
@@ -199,6 +603,54 @@ pub enum Commands {
         #[arg(long)]
         user_data: Option<String>,
 
+        /// Authorize an SSH public key for `cirun` (path or key string,
+        /// repeatable); disables password login. Also read from MEDA_SSH_PUBKEY
+        #[arg(long = "ssh-key")]
+        ssh_key: Vec<String>,
+
+        /// Label the VM (key=value, repeatable); select with `meda list --filter label=key=value`
+        #[arg(long = "label", value_parser = crate::labels::parse_label)]
+        label: Vec<(String, String)>,
+
+        /// Set an environment variable in the guest (KEY=VALUE, repeatable),
+        /// in /etc/meda/env and login shells
+        #[arg(long = "env", value_name = "KEY=VALUE", value_parser = crate::guest_env::parse_var)]
+        env: Vec<(String, String)>,
+
+        /// Like --env, but in /etc/meda/secret-env, readable by root only;
+        /// `meda get` and `meda list` show the value as <redacted>
+        #[arg(long = "secret-env", value_name = "KEY=VALUE", value_parser = crate::guest_env::parse_var)]
+        secret_env: Vec<(String, String)>,
+
+        /// Copy a host file into the guest on first boot (repeatable), e.g.
+        /// key.pem:/etc/runner/key.pem:0600:runner; mode defaults to 0600
+        #[arg(long, value_name = "HOST:GUEST[:MODE[:OWNER]]", value_parser = crate::secrets::Secret::parse)]
+        secret: Vec<crate::secrets::Secret>,
+
+        /// Storage pool for the VM dir (from MEDA_STORAGE_POOLS; default: vm_root)
+        #[arg(long)]
+        storage: Option<String>,
+
+        /// Block traffic to and from other VMs (outbound internet still
+        /// works); MEDA_ISOLATE=1 makes this the default
+        #[arg(long)]
+        isolate: bool,
+
+        /// Outbound policy, first match wins (e.g. allow:10.0.5.0/24,deny:10.0.0.0/8),
+        /// or the name of a policy in <config dir>/egress-policies
+        #[arg(long, value_name = "POLICY")]
+        egress: Option<String>,
+
+        /// Send the VM's outbound NAT traffic out of this host interface
+        /// (e.g. eth1) instead of the host's default route
+        #[arg(long, value_name = "IFACE", value_parser = crate::uplink::parse_interface)]
+        egress_interface: Option<String>,
+
+        /// Entropy source of the VM's virtio-rng device, or `none` for no
+        /// device [default: $MEDA_RNG_SOURCE or /dev/urandom]
+        #[arg(long, value_name = "SOURCE", value_parser = crate::rng::RngSource::parse)]
+        rng: Option<crate::rng::RngSource>,
+
         /// Don't start the VM, just create it
         #[arg(long)]
         no_start: bool,
@@ -219,6 +671,30 @@ pub enum Commands {
         #[arg(long)]
         device: Vec<String>,
 
+        /// Use a thin qcow2 overlay on the shared base image for the root
+        /// disk, overriding MEDA_DISK_FORMAT=raw
+        #[arg(long)]
+        cow: bool,
+
+        /// Reset the root disk to the image on every start; keep state
+        /// on a --data-disk instead
+        #[arg(long)]
+        immutable_root: bool,
+
+        /// Attach a persistent data disk of this size (e.g., 20G),
+        /// formatted and mounted by cloud-init
+        #[arg(long, value_name = "SIZE")]
+        data_disk: Option<String>,
+
+        /// Where the data disk is mounted in the guest
+        #[arg(long, value_name = "PATH", requires = "data_disk")]
+        data_mount: Option<String>,
+
+        /// Delete the VM this long after it is created (e.g. 90m, 12h);
+        /// `meda reap` or `meda serve --reap-interval` does the deleting
+        #[arg(long, value_name = "DURATION", value_parser = crate::ttl::parse_ttl)]
+        ttl: Option<u64>,
+
         /// Skip the auto-template fast path and cold-boot as before.
         #[arg(long)]
         cold: bool,
@@ -226,39 +702,279 @@ pub enum Commands {
         /// After the VM is ready, exec into it with ssh. The VM
         /// keeps running after you exit the shell; clean it up
         /// with `meda delete <vm_name>`.
-        #[arg(long)]
+        #[arg(long, conflicts_with = "no_start")]
         ssh: bool,
+
+        #[command(flatten)]
+        boot: DirectBootArgs,
+    },
+
+    /// Create a group of VMs described in a JSON file, in parallel
+    Up {
+        /// Path to the group file
+        #[arg(short, long)]
+        file: PathBuf,
+
+        /// How many VMs to create at once
+        #[arg(long, default_value = "4")]
+        parallel: usize,
+
+        /// If any VM fails, delete every VM this run created
+        #[arg(long)]
+        rollback_on_failure: bool,
+    },
+
+    /// Create, update and delete VMs to match a YAML manifest
+    Apply {
+        /// Path to the manifest
+        #[arg(short, long)]
+        file: PathBuf,
+
+        /// Print the plan without changing anything
+        #[arg(long)]
+        dry_run: bool,
     },
 
-    /// Clean up orphaned TAP devices
+    /// Attach, list and remove a VM's extra data disks
+    Disk {
+        #[command(subcommand)]
+        command: DiskCommands,
+    },
+
+    /// Inspect and troubleshoot VM networking
+    Network {
+        #[command(subcommand)]
+        command: NetworkCommands,
+    },
+
+    /// Check that this host can run its VMs as configured
+    Doctor,
+
+    /// Download the base image, firmware and hypervisor binaries if missing
+    Bootstrap {
+        /// Check upstream for a newer base image now
+        #[arg(long)]
+        refresh: bool,
+    },
+
+    /// Show or install the external binaries meda runs
+    Deps {
+        #[command(subcommand)]
+        command: DepsCommands,
+    },
+
+    /// Show host-level meda state
+    System {
+        #[command(subcommand)]
+        command: SystemCommands,
+    },
+
+    /// Clean up orphaned TAP devices (alias for `meda network prune`)
     Cleanup {
         /// Show what would be cleaned up without actually doing it
         #[arg(long)]
         dry_run: bool,
     },
 
-    /// Snapshot a running VM to its own dir (for fast restore later)
-    Snapshot {
+    /// Move ~/.meda to the XDG config, cache and data dirs
+    MigrateDirs {
+        /// Show what would be moved without moving it
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Upgrade image and VM manifests written by older meda versions
+    MigrateState {
+        /// Show what would be upgraded without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Move a VM to another host: stop it, copy it over SSH and start it
+    /// there if it was running (cold migration)
+    Migrate {
         /// Name of the VM
         name: String,
+
+        /// Destination host, ssh://[user@]host[:port]; it needs meda and
+        /// passwordless sudo, like this host
+        #[arg(long)]
+        to: String,
+
+        /// meda on the destination, if it isn't on the PATH there
+        #[arg(long, default_value = "meda")]
+        remote_meda: String,
+
+        /// Keep the (stopped) VM here once the destination has it
+        #[arg(long)]
+        keep_source: bool,
+    },
+
+    /// Receive a migrated VM as a tar on stdin (run by `meda migrate`
+    /// over SSH)
+    #[command(hide = true)]
+    MigrateReceive {
+        name: String,
+
+        /// Only check that the VM could be received, and print this
+        /// host's meda SSH key
+        #[arg(long)]
+        check: bool,
+
+        /// Start the VM once received
+        #[arg(long)]
+        start: bool,
+
+        /// Disk size of the VM, for --check
+        #[arg(long, default_value = "0")]
+        disk: String,
+
+        /// Memory of the VM, for --check with --start
+        #[arg(long, default_value = "0")]
+        memory: String,
+
+        /// vCPUs of the VM, for --check with --start
+        #[arg(long, default_value_t = 0)]
+        cpus: u8,
+    },
+
+    /// Snapshot a running VM to its own dir (for fast restore later), or
+    /// manage its snapshot history
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Snapshot {
+        /// Name of the VM
+        #[arg(required = true)]
+        name: Option<String>,
+
+        #[command(subcommand)]
+        command: Option<SnapshotCommands>,
     },
 
     /// Restore a VM from its snapshot (~500ms vs ~27s cold boot)
     Restore {
         /// Name of the VM
         name: String,
+
+        /// Give the restored guest a new subnet, MAC, tap and hostname
+        /// instead of the snapshot's (e.g. for clones of a template)
+        #[arg(long)]
+        new_identity: bool,
+
+        /// Roll back to this retained snapshot, disk included, instead of
+        /// the current one (see `meda snapshot list`)
+        #[arg(long)]
+        snapshot: Option<String>,
     },
 
     /// List VMs that have a snapshot (i.e. are ready to fast-restore)
     Templates,
 
-    /// Clone a snapshotted VM into a new one (fast-restore ready)
+    /// Clone a VM into a new one with its own subnet, MAC and hostname
+    ///
+    /// Sources with a snapshot are cloned fast-restore ready; others (or
+    /// with --disk) get a copy of their stopped disk and a fresh cloud-init
     Clone {
-        /// Source VM (must have a snapshot)
+        /// Source VM
         template: String,
 
         /// Name of the new VM
         new_name: String,
+
+        /// Storage pool for the clone (from MEDA_STORAGE_POOLS; default: vm_root)
+        #[arg(long)]
+        storage: Option<String>,
+
+        /// Copy the disk even if the source has a snapshot
+        #[arg(long)]
+        disk: bool,
+
+        /// Overlay the source disk instead of copying it (the source must
+        /// then stay unchanged); implies --disk
+        #[arg(long)]
+        cow: bool,
+
+        /// Start the clone; implies --disk
+        #[arg(long)]
+        start: bool,
+    },
+
+    /// Show host resources and VM subnets committed vs. available
+    Capacity,
+
+    /// Show VM, memory and disk usage against the MEDA_MAX_* quotas
+    Quota,
+
+    /// Show host CPUs, memory, free disk, KVM availability and running VMs
+    /// (same as `meda system host`)
+    SystemInfo,
+
+    /// Stop and delete VMs whose `run --ttl` has expired
+    Reap {
+        /// Only list the expired VMs
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Split a file into chunks plus an index of their digests (reverse
+    /// with `meda assemble`), e.g. to carry an image over by hand
+    Chunk {
+        /// File to chunk
+        file: PathBuf,
+
+        /// Directory for the chunks (default: <file>.chunks)
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+
+        /// Chunk size, e.g. 512M (default: 100M-500M depending on file size)
+        #[arg(long, value_parser = crate::chunking::parse_chunk_size)]
+        chunk_size: Option<u64>,
+    },
+
+    /// Reassemble files chunked by `meda chunk` and verify their digests
+    Assemble {
+        /// Directory holding the chunks and their index
+        dir: PathBuf,
+
+        /// Directory to write the files to (default: current dir)
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+
+        /// Only verify the chunks; write nothing
+        #[arg(long)]
+        verify: bool,
+    },
+
+    /// Show CPU, memory, disk and network usage of one VM or all of them
+    Stats {
+        /// VM name (default: all VMs)
+        name: Option<String>,
+
+        /// Seconds to measure CPU usage over
+        #[arg(long, default_value = "1")]
+        interval: u64,
+    },
+
+    /// Show how much disk the assets dir, each cached image and each VM
+    /// use (allocated, and once sparse and qcow2 disks are fully written)
+    Du {
+        /// Order rows by
+        #[arg(long, value_enum, default_value_t)]
+        sort: crate::disk_usage::SortKey,
+    },
+
+    /// Print OpenMetrics/Prometheus stats (same payload as the API's /metrics)
+    Metrics {
+        /// Collect once and exit (for cron + node_exporter textfile collector)
+        #[arg(long)]
+        once: bool,
+
+        /// Seconds between refreshes when not using --once
+        #[arg(long, default_value = "15")]
+        interval: u64,
+
+        /// Write to this file (atomically) instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
     },
 
     /// Start REST API server
@@ -267,8 +983,384 @@ pub enum Commands {
         #[arg(long, short, default_value = "7777")]
         port: u16,
 
-        /// Host to bind to (default: 127.0.0.1)
-        #[arg(long, default_value = "127.0.0.1")]
-        host: String,
+        /// Address to bind to (default: 127.0.0.1)
+        #[arg(long, alias = "host", default_value = "127.0.0.1")]
+        bind: String,
+
+        /// Also reap VMs whose `run --ttl` has expired this often (e.g. 1m)
+        #[arg(long, value_name = "DURATION", value_parser = crate::ttl::parse_ttl)]
+        reap_interval: Option<u64>,
+    },
+
+    /// Manage remote contexts: other hosts' `meda serve` APIs that
+    /// commands can run against
+    Context {
+        #[command(subcommand)]
+        command: ContextCommands,
+    },
+
+    /// Print a shell completion script (e.g. `meda completions bash > /etc/bash_completion.d/meda`)
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+
+    /// List VM names, for the completion scripts
+    #[command(hide = true)]
+    CompleteVms,
+
+    /// Log and serve a VM's serial console; started with the VM
+    #[command(hide = true)]
+    SerialRelay { vm_dir: PathBuf },
+
+    /// Serve a VM's cloud-init data on ADDR; started with the VM
+    #[command(hide = true)]
+    NocloudServe { vm_dir: PathBuf, addr: String },
+}
+
+#[derive(Subcommand)]
+pub enum ImageCommands {
+    /// Show the images an image was derived from, with their creation
+    /// times, source VMs and registry digests
+    History {
+        /// Image name and tag (e.g., golden:v2)
+        image: String,
+
+        /// Registry URL (default: $MEDA_DEFAULT_REGISTRY or ghcr.io)
+        #[arg(long)]
+        registry: Option<String>,
+
+        /// Organization/namespace (default: $MEDA_DEFAULT_ORG or cirunlabs)
+        #[arg(long)]
+        org: Option<String>,
+    },
+    /// Show an image's manifest: artifacts with sizes and digests,
+    /// metadata, creation time and chunking details
+    Inspect {
+        /// Image name and tag (e.g., ubuntu:latest)
+        image: String,
+
+        /// Registry URL (default: $MEDA_DEFAULT_REGISTRY or ghcr.io)
+        #[arg(long)]
+        registry: Option<String>,
+
+        /// Organization/namespace (default: $MEDA_DEFAULT_ORG or cirunlabs)
+        #[arg(long)]
+        org: Option<String>,
+    },
+    /// Import a qcow2 or raw disk (e.g. built with packer or
+    /// virt-builder) as a local image
+    Import {
+        /// Path to the disk image
+        path: std::path::PathBuf,
+
+        /// Local image name to create (e.g., mycustom:v1)
+        #[arg(long)]
+        name: String,
+
+        /// OS recorded in the image metadata (e.g., debian)
+        #[arg(long)]
+        os: Option<String>,
+
+        /// Architecture recorded in the image metadata (default: the host's)
+        #[arg(long)]
+        arch: Option<String>,
+    },
+    /// Write an image to a single archive file, for hosts without
+    /// registry access (zstd-compressed if the name ends in .zst)
+    Save {
+        /// Image name and tag (e.g., ubuntu:latest)
+        image: String,
+
+        /// Archive to write (e.g., image.tar.zst)
+        #[arg(short, long)]
+        output: std::path::PathBuf,
+
+        /// Registry URL (default: $MEDA_DEFAULT_REGISTRY or ghcr.io)
+        #[arg(long)]
+        registry: Option<String>,
+
+        /// Organization/namespace (default: $MEDA_DEFAULT_ORG or cirunlabs)
+        #[arg(long)]
+        org: Option<String>,
+    },
+    /// Add an image from an archive written by `meda image save`
+    Load {
+        /// Archive to read (e.g., image.tar.zst)
+        archive: std::path::PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SnapshotCommands {
+    /// List a VM's current and retained snapshots
+    List {
+        /// Name of the VM
+        vm: String,
+    },
+
+    /// Set or show how many snapshots a VM keeps
+    Policy {
+        #[command(subcommand)]
+        command: SnapshotPolicyCommands,
+    },
+
+    /// Remove retained snapshots the VMs' policies don't keep, and report
+    /// the space reclaimed (e.g. from cron)
+    Gc {
+        /// Only this VM (default: every VM with a policy)
+        vm: Option<String>,
+
+        /// Show what would be removed without removing it
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SnapshotPolicyCommands {
+    /// Keep a history of the VM's snapshots, pruned by `meda snapshot gc`;
+    /// all counts 0 removes the policy and the history
+    Set {
+        /// Name of the VM
+        vm: String,
+
+        /// Keep the newest N snapshots
+        #[arg(long, default_value_t = 0)]
+        keep_last: u32,
+
+        /// Keep the newest snapshot of each of the last N days
+        #[arg(long, default_value_t = 0)]
+        keep_daily: u32,
+
+        /// Keep the newest snapshot of each of the last N weeks
+        #[arg(long, default_value_t = 0)]
+        keep_weekly: u32,
+
+        /// Keep the newest snapshot of each of the last N months
+        #[arg(long, default_value_t = 0)]
+        keep_monthly: u32,
+    },
+
+    /// Show the VM's retention policy
+    Show {
+        /// Name of the VM
+        vm: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DiskCommands {
+    /// Create a disk and attach it to a VM, live if it is running
+    Add {
+        /// Name of the VM
+        vm: String,
+
+        /// Disk size (e.g., 20G)
+        #[arg(long)]
+        size: String,
+
+        /// Disk name, also its serial in the guest (default: data1, data2, ...)
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Disk format: raw (sparse) or qcow2
+        #[arg(long, default_value = "raw")]
+        format: String,
+    },
+
+    /// List a VM's extra disks
+    List {
+        /// Name of the VM
+        vm: String,
+    },
+
+    /// Detach a disk from a VM and delete it
+    Remove {
+        /// Name of the VM
+        vm: String,
+
+        /// Name of the disk
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum NetworkCommands {
+    /// Show tap state, routes and the iptables rules meda owns for a
+    /// VM, and report any drift from what meda set up
+    Inspect {
+        /// Name of the VM
+        name: String,
+    },
+
+    /// Remove tap devices, veths, netns and iptables rules left behind
+    /// by VMs that no longer exist
+    Prune {
+        /// Show what would be removed without removing it
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+/// VMs picked by name, glob, `--all` or `--filter` (see `batch`).
+#[derive(Args)]
+pub struct VmSelection {
+    /// Names of the VMs, or globs such as 'ci-*'
+    pub names: Vec<String>,
+
+    /// Every VM
+    #[arg(long)]
+    pub all: bool,
+
+    /// Only VMs matching: label=key[=value] or state=running|stopped|error|creating|starting|stopping (repeatable, all must match)
+    #[arg(long)]
+    pub filter: Vec<VmFilter>,
+
+    /// How many VMs to act on at once
+    #[arg(long, default_value_t = 4)]
+    pub parallel: usize,
+}
+
+impl VmSelection {
+    pub fn selection(&self) -> crate::batch::Selection {
+        crate::batch::Selection {
+            names: self.names.clone(),
+            all: self.all,
+            filters: self.filter.clone(),
+        }
+    }
+}
+
+/// Boot a kernel from the host instead of the image's (see `boot`).
+#[derive(Args)]
+pub struct DirectBootArgs {
+    /// Kernel image to boot directly (e.g. a vmlinux or bzImage build),
+    /// skipping the firmware and the image's bootloader
+    #[arg(long, value_name = "PATH")]
+    pub kernel: Option<PathBuf>,
+
+    /// Initramfs to load with --kernel
+    #[arg(long, value_name = "PATH", requires = "kernel")]
+    pub initramfs: Option<PathBuf>,
+
+    /// Kernel command line for --kernel
+    /// [default: console=ttyS0 root=/dev/vda1 rw]
+    #[arg(long, value_name = "ARGS", requires = "kernel")]
+    pub cmdline: Option<String>,
+}
+
+impl DirectBootArgs {
+    pub fn direct_boot(&self) -> crate::error::Result<Option<crate::boot::DirectBoot>> {
+        self.kernel
+            .as_deref()
+            .map(|kernel| {
+                crate::boot::DirectBoot::new(
+                    kernel,
+                    self.initramfs.as_deref(),
+                    self.cmdline.as_deref(),
+                )
+            })
+            .transpose()
+    }
+}
+
+#[derive(Args)]
+pub struct RunnerImageArgs {
+    /// Image name; tags are `<runner version>-r<recipe revision>`
+    #[arg(long, default_value = "runner")]
+    pub name: String,
+
+    /// actions/runner release to install
+    #[arg(long, default_value = crate::runner_image::DEFAULT_RUNNER_VERSION, value_parser = crate::runner_image::parse_runner_version)]
+    pub runner_version: String,
+
+    /// Registry URL (default: $MEDA_DEFAULT_REGISTRY or ghcr.io)
+    #[arg(long)]
+    pub registry: Option<String>,
+
+    /// Organization/namespace (default: $MEDA_DEFAULT_ORG or cirunlabs)
+    #[arg(long)]
+    pub org: Option<String>,
+
+    /// Give up on a build VM that hasn't powered off after this many minutes
+    #[arg(long, default_value = "45")]
+    pub timeout_minutes: u64,
+}
+
+#[derive(Subcommand)]
+pub enum RunnerImageCommands {
+    /// Build the runner image for the current recipe
+    Build {
+        #[command(flatten)]
+        args: RunnerImageArgs,
+
+        /// Rebuild even if the tag already exists
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// Build the runner image if the current recipe's tag is missing, and
+    /// point `:latest` at it
+    Update {
+        #[command(flatten)]
+        args: RunnerImageArgs,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DepsCommands {
+    /// List cloud-hypervisor, ch-remote and ORAS with their versions
+    List,
+    /// Install ORAS into the asset dir, replacing the current one
+    Oras {
+        /// Release to install (default MEDA_ORAS_VERSION, or 1.2.3)
+        #[arg(long, value_parser = crate::oras::parse_version_arg)]
+        version: Option<crate::oras::Version>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ContextCommands {
+    /// Add a context, or replace the one with this name
+    Add {
+        /// Name of the context
+        name: String,
+
+        /// Base URL of the host's API (e.g. https://meda.example.com:7777)
+        #[arg(long)]
+        url: String,
+
+        /// API token (visible in shell history; prefer --token-stdin)
+        #[arg(long, conflicts_with = "token_stdin")]
+        token: Option<String>,
+
+        /// Read the API token from stdin
+        #[arg(long)]
+        token_stdin: bool,
+    },
+    /// List contexts; `*` marks the active one
+    List,
+    /// Run later commands against a context (`default` for this host)
+    Use {
+        /// Name of the context
+        name: String,
     },
+    /// Remove a context
+    #[command(alias = "rm")]
+    Remove {
+        /// Name of the context
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SystemCommands {
+    /// Show the bootstrap assets, whether they are installed and which
+    /// URL or mirror served each one
+    Info,
+
+    /// Show host CPUs, memory, free disk, KVM availability and running VMs
+    /// (same as `meda system-info`)
+    Host,
 }
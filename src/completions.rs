@@ -0,0 +1,177 @@
+//! `meda completions <shell>`: tab completion scripts.
+//!
+//! Subcommands and flags come from clap_complete. On top of that, the
+//! bash, zsh and fish scripts complete VM names for the commands in
+//! `VM_COMMANDS` by running the hidden `meda complete-vms`, which lists
+//! `vm_root`; PowerShell gets the static script only.
+
+use crate::cli::Cli;
+use clap::CommandFactory;
+use clap_complete::Shell;
+
+/// Subcommands whose first argument is an existing VM (for `start`,
+/// `stop` and `delete`, every argument).
+const VM_COMMANDS: &[&str] = &[
+    "get",
+    "ip",
+    "exec",
+    "wait",
+    "start",
+    "stop",
+    "restart",
+    "delete",
+    "resize",
+    "port-forward",
+    "snapshot",
+    "restore",
+    "clone",
+    "stats",
+    "console-dump",
+    "hotplug",
+    "migrate",
+];
+
+pub fn script(shell: Shell) -> String {
+    let mut buf = Vec::new();
+    clap_complete::generate(shell, &mut Cli::command(), "meda", &mut buf);
+    let script = String::from_utf8_lossy(&buf).into_owned();
+    match shell {
+        Shell::Bash => script + &bash_vms(),
+        Shell::Zsh => zsh_vms(&script),
+        Shell::Fish => script + &fish_vms(),
+        _ => script,
+    }
+}
+
+/// Wrap clap's `_meda`: complete a VM name for the first positional
+/// argument of a VM command, and for any of `start`, `stop` and
+/// `delete`; defer to `_meda` for everything else.
+fn bash_vms() -> String {
+    format!(
+        r#"
+_meda_with_vms() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" cmd="" cmd_at=0 i
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        if [[ "${{COMP_WORDS[i]}}" != -* ]]; then
+            cmd="${{COMP_WORDS[i]}}"
+            cmd_at=$i
+            break
+        fi
+    done
+    if [[ $cmd_at -gt 0 && "$cur" != -* && " {commands} " == *" $cmd "* ]]; then
+        if [[ " start stop delete " != *" $cmd "* ]]; then
+            for ((i = cmd_at + 1; i < COMP_CWORD; i++)); do
+                [[ "${{COMP_WORDS[i]}}" != -* ]] && {{ _meda "$@"; return; }}
+            done
+        fi
+        COMPREPLY=($(compgen -W "$(meda complete-vms 2>/dev/null)" -- "$cur"))
+        return 0
+    fi
+    _meda "$@"
+}}
+complete -F _meda_with_vms -o bashdefault -o default meda
+"#,
+        commands = VM_COMMANDS.join(" ")
+    )
+}
+
+fn fish_vms() -> String {
+    format!(
+        "complete -c meda -n \"__fish_seen_subcommand_from {}\" -f -a \"(meda complete-vms 2>/dev/null)\"\n",
+        VM_COMMANDS.join(" ")
+    )
+}
+
+/// Point the first positional argument of each VM command at
+/// `_meda_vms` instead of `_default`, and define `_meda_vms`.
+fn zsh_vms(script: &str) -> String {
+    let mut out = String::with_capacity(script.len() + 256);
+    let mut pending = false;
+    for line in script.lines() {
+        let mut line = line.to_string();
+        if let Some(label) = line.strip_prefix('(').and_then(|l| l.strip_suffix(')')) {
+            pending = VM_COMMANDS.contains(&label);
+        } else if pending
+            && (line.starts_with("':") || line.starts_with("'*:"))
+            && line.ends_with(":_default' \\")
+        {
+            line = line.replacen(":_default' \\", ":_meda_vms' \\", 1);
+            pending = false;
+        }
+        if line.starts_with("if [ \"$funcstack[1]\" = \"_meda\" ]") {
+            out.push_str(
+                r#"(( $+functions[_meda_vms] )) ||
+_meda_vms() {
+    local -a vms
+    vms=(${(f)"$(meda complete-vms 2>/dev/null)"})
+    _describe -t vms 'VM' vms
+}
+
+"#,
+            );
+        }
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vm_commands_exist() {
+        let cli = Cli::command();
+        for name in VM_COMMANDS {
+            let sub = cli
+                .find_subcommand(name)
+                .unwrap_or_else(|| panic!("no subcommand {}", name));
+            assert!(
+                sub.get_positionals().next().is_some(),
+                "{} takes no VM",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_vm_commands_cover_the_cli() {
+        // `create` names a VM that doesn't exist yet.
+        for sub in Cli::command().get_subcommands() {
+            let takes_vm = sub
+                .get_positionals()
+                .next()
+                .and_then(|arg| arg.get_help())
+                .map(|help| help.to_string())
+                .is_some_and(|help| {
+                    help == "Name of the VM" || help.starts_with("Names of the VMs")
+                });
+            if takes_vm && sub.get_name() != "create" {
+                assert!(
+                    VM_COMMANDS.contains(&sub.get_name()),
+                    "{} is missing from VM_COMMANDS",
+                    sub.get_name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_scripts_complete_vms() {
+        let bash = script(Shell::Bash);
+        assert!(bash.contains("complete -F _meda_with_vms"));
+        assert!(bash.contains(" exec wait start stop "));
+
+        let zsh = script(Shell::Zsh);
+        assert!(zsh.contains("':name -- Name of the VM:_meda_vms'"));
+        assert!(zsh.contains("':template -- Source VM:_meda_vms'"));
+        // Only the first positional: the clone's new name is free-form.
+        assert!(zsh.contains("':new_name -- Name of the new VM:_default'"));
+        assert!(zsh.contains("_meda_vms() {"));
+        assert!(zsh.contains("'*::names -- Names of the VMs"));
+        assert!(!zsh.contains("or globs such as '\\''ci-*'\\'':_default'"));
+
+        assert!(script(Shell::Fish).contains("__fish_seen_subcommand_from get ip exec wait start"));
+    }
+}
@@ -0,0 +1,184 @@
+//! Optional zstd compression of image artifacts on `meda push`.
+//!
+//! Raw disk images are mostly zeroes, so compressing them before upload
+//! cuts push and pull times by a large factor. With
+//! `MEDA_IMAGE_COMPRESSION=zstd` every artifact is pushed as
+//! `<file>.zst` with a `+zstd` media type suffix (e.g.
+//! `application/vnd.cirunlabs.meda.base-image.v1+zstd`), compressed as
+//! it is uploaded and chunked like any other large file. Pulls decompress `.zst` files after
+//! reassembling chunks, whatever the local setting, so compressed and
+//! uncompressed images can be mixed freely.
+
+use crate::error::{Error, Result};
+use crate::progress::Progress;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Suffix of compressed artifact files.
+pub const ZSTD_EXTENSION: &str = "zst";
+
+/// Media type suffix of compressed layers.
+pub const ZSTD_MEDIA_SUFFIX: &str = "+zstd";
+
+/// Level used when `MEDA_IMAGE_COMPRESSION_LEVEL` is unset.
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd {
+        level: i32,
+    },
+}
+
+impl Compression {
+    /// `MEDA_IMAGE_COMPRESSION` / `MEDA_IMAGE_COMPRESSION_LEVEL` values.
+    /// The level must be within zstd's 1-19 range.
+    pub fn parse(kind: &str, level: Option<&str>) -> Option<Self> {
+        match kind.trim().to_ascii_lowercase().as_str() {
+            "none" | "" => Some(Self::None),
+            "zstd" => {
+                let level = match level {
+                    Some(l) => l.trim().parse().ok().filter(|l| (1..=19).contains(l))?,
+                    None => DEFAULT_ZSTD_LEVEL,
+                };
+                Some(Self::Zstd { level })
+            }
+            _ => None,
+        }
+    }
+
+    /// Appended to an artifact's media type.
+    pub fn media_suffix(&self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Zstd { .. } => ZSTD_MEDIA_SUFFIX,
+        }
+    }
+}
+
+/// Counts bytes read through it, for progress.
+struct Counted<'a, R> {
+    inner: R,
+    read: u64,
+    events: &'a mut Progress,
+}
+
+impl<R: Read> Read for Counted<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        self.events.update(self.read);
+        Ok(n)
+    }
+}
+
+/// Replace every `<file>.zst` in `dir` (and, with `recursive`, below
+/// it) by the decompressed `<file>`. Returns the decompressed paths.
+pub fn decompress_all(dir: &Path, recursive: bool) -> Result<Vec<PathBuf>> {
+    let mut done = Vec::new();
+    if !dir.is_dir() {
+        return Ok(done);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if recursive {
+                done.extend(decompress_all(&path, true)?);
+            }
+            continue;
+        }
+        if path.extension().and_then(|e| e.to_str()) != Some(ZSTD_EXTENSION) {
+            continue;
+        }
+        let target = path.with_extension("");
+        if let Err(e) = decompress_file(&path, &target) {
+            let _ = fs::remove_file(&target);
+            return Err(e);
+        }
+        fs::remove_file(&path)?;
+        done.push(target);
+    }
+    Ok(done)
+}
+
+fn decompress_file(src: &Path, dst: &Path) -> Result<()> {
+    let name = dst.file_name().unwrap_or_default().to_string_lossy();
+    let mut events = Progress::bytes("decompress", &name, None);
+    let decoder = zstd::stream::Decoder::new(File::open(src)?)?;
+    let mut reader = Counted {
+        inner: decoder,
+        read: 0,
+        events: &mut events,
+    };
+    let mut out = BufWriter::new(File::create(dst)?);
+    io::copy(&mut reader, &mut out).map_err(|e| {
+        Error::CorruptArtifact(format!(
+            "{}: zstd decompression failed: {}",
+            src.display(),
+            e
+        ))
+    })?;
+    let written = reader.read;
+    out.flush()?;
+    events.finish(Some(written));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// What `meda push` uploads for `src`, written to `dst`.
+    fn compress_file(src: &Path, dst: &Path, level: i32) -> u64 {
+        zstd::stream::copy_encode(File::open(src).unwrap(), File::create(dst).unwrap(), level)
+            .unwrap();
+        fs::metadata(dst).unwrap().len()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Compression::parse("none", None), Some(Compression::None));
+        assert_eq!(
+            Compression::parse("ZSTD", None),
+            Some(Compression::Zstd { level: 3 })
+        );
+        assert_eq!(
+            Compression::parse("zstd", Some("19")),
+            Some(Compression::Zstd { level: 19 })
+        );
+        assert_eq!(Compression::parse("zstd", Some("0")), None);
+        assert_eq!(Compression::parse("gzip", None), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let raw = dir.path().join("base.raw");
+        let mut data = vec![0u8; 4 * 1024 * 1024];
+        data[1000..1010].copy_from_slice(b"bootsector");
+        fs::write(&raw, &data).unwrap();
+
+        let sub = dir.path().join("layers");
+        fs::create_dir(&sub).unwrap();
+        let compressed = sub.join("base.raw.zst");
+        let size = compress_file(&raw, &compressed, 3);
+        assert!(size < data.len() as u64 / 100);
+
+        assert!(decompress_all(dir.path(), false).unwrap().is_empty());
+        let done = decompress_all(dir.path(), true).unwrap();
+        assert_eq!(done, vec![sub.join("base.raw")]);
+        assert_eq!(fs::read(sub.join("base.raw")).unwrap(), data);
+        assert!(!compressed.exists());
+
+        fs::write(&compressed, b"not zstd").unwrap();
+        assert!(matches!(
+            decompress_all(&sub, false),
+            Err(Error::CorruptArtifact(_))
+        ));
+        assert!(compressed.exists());
+    }
+}
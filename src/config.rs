@@ -1,10 +1,84 @@
 use crate::chunking::ChunkingConfig;
+use crate::compression::Compression;
 use crate::error::{Error, Result};
+use crate::layout::{DirLayout, LayoutDirs};
+use crate::storage::{PlacementPolicy, StoragePool};
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Registry of image names that don't name one.
+pub const DEFAULT_REGISTRY: &str = "ghcr.io";
+
+/// Org (namespace) of image names that don't name one.
+pub const DEFAULT_ORG: &str = "cirunlabs";
+
+/// Settings file in the config dir. Environment variables win over it.
+pub const SETTINGS_FILE: &str = "config.json";
+
+/// Grace period for guests to power off before `meda stop` kills them.
+const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// On-disk format of a VM's root disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiskFormat {
+    /// Thin qcow2 overlay backed by the shared base image. Instant to
+    /// create and only stores blocks the guest writes.
+    Qcow2,
+    /// Full private copy of the base image. Slower to provision and
+    /// uses the whole disk up front, but has no backing-file dependency.
+    Raw,
+}
+
+impl DiskFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "qcow2" | "cow" => Some(Self::Qcow2),
+            "raw" => Some(Self::Raw),
+            _ => None,
+        }
+    }
+
+    /// Value for Cloud Hypervisor's `--disk image_type=`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Qcow2 => "qcow2",
+            Self::Raw => "raw",
+        }
+    }
+
+    pub fn rootfs_name(&self) -> &'static str {
+        match self {
+            Self::Qcow2 => "rootfs.qcow2",
+            Self::Raw => "rootfs.raw",
+        }
+    }
+
+    /// Find the root disk in a VM dir, whichever format it was created in.
+    pub fn detect(vm_dir: &Path) -> Option<(PathBuf, Self)> {
+        [Self::Qcow2, Self::Raw]
+            .into_iter()
+            .map(|f| (vm_dir.join(f.rootfs_name()), f))
+            .find(|(p, _)| p.exists())
+    }
+
+    /// `--disk` value for the root disk in a launch spec.
+    pub fn ch_disk_arg(&self, rootfs: &Path) -> String {
+        match self {
+            Self::Qcow2 => format!(
+                "path={},image_type=qcow2,backing_files=on",
+                rootfs.display()
+            ),
+            Self::Raw => format!("path={},image_type=raw", rootfs.display()),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Config {
+    /// Config dir: SSH keys, API tokens (`~/.meda` or `~/.config/meda`).
     pub ch_home: PathBuf,
     pub asset_dir: PathBuf,
     pub vm_root: PathBuf,
@@ -13,29 +87,117 @@ pub struct Config {
     pub ch_url: String,
     pub cr_url: String,
     pub oras_url: String,
+    /// Fallback URLs per bootstrap asset, by asset name (see `assets`).
+    pub asset_mirrors: BTreeMap<String, Vec<String>>,
     pub base_raw: PathBuf,
     pub fw_bin: PathBuf,
     pub ch_bin: PathBuf,
     pub cr_bin: PathBuf,
+    /// Where meda installs its own ORAS.
     pub oras_bin: PathBuf,
+    /// ORAS release meda installs (`MEDA_ORAS_VERSION`).
+    pub oras_version: crate::oras::Version,
+    /// An ORAS to use instead of the managed one (`MEDA_ORAS_BIN`).
+    pub oras_override: Option<PathBuf>,
     pub cpus: usize,
     pub mem: String,
     pub disk_size: String,
+    pub disk_format: DiskFormat,
     pub chunking: ChunkingConfig,
+    /// Extra places VM dirs can live besides `vm_root` (see `storage`).
+    pub storage_pools: Vec<StoragePool>,
+    pub placement: PlacementPolicy,
+    /// Isolate every new VM from the others (`MEDA_ISOLATE`), as if
+    /// each was created with `--isolate`.
+    pub isolate: bool,
+    /// Give new VMs their address over DHCP from a per-VM dnsmasq
+    /// (`MEDA_DHCP`; see `dhcp`).
+    pub dhcp: bool,
+    /// How long `meda stop` waits for the guest to power off after the
+    /// ACPI power button before killing it (`MEDA_STOP_TIMEOUT`, seconds).
+    pub stop_timeout: Duration,
+    /// Age after which bootstrap checks upstream for a newer base image
+    /// (`MEDA_IMAGE_MAX_AGE`, e.g. `14d`; see `base_image`). `None`
+    /// never checks by itself.
+    pub image_max_age: Option<Duration>,
+    /// Check host resources before create/start (`MEDA_PREFLIGHT`; see
+    /// `preflight`).
+    pub preflight: bool,
+    /// The /24s VMs' subnets come from (`MEDA_SUBNET_POOL`; see
+    /// `subnets`).
+    pub subnet_pool: crate::subnets::SubnetPool,
+    /// Entropy source of new VMs' virtio-rng device (`MEDA_RNG_SOURCE`;
+    /// see `rng`).
+    pub rng_source: crate::rng::RngSource,
+    /// Where VM created/deleted records go (`MEDA_INVENTORY_COMMAND`,
+    /// `MEDA_INVENTORY_URL`; see `inventory`).
+    pub inventory: crate::inventory::Exporters,
+    /// Registry and org for image names without one
+    /// (`MEDA_DEFAULT_REGISTRY`, `MEDA_DEFAULT_ORG`, or `default_registry`
+    /// and `default_org` in [`SETTINGS_FILE`]).
+    pub default_registry: String,
+    pub default_org: String,
+    /// Fail instead of fetching anything over the network (`--offline`,
+    /// `MEDA_OFFLINE`); see [`Config::require_network`].
+    pub offline: bool,
+    /// Proxy for meda's HTTP traffic (`MEDA_PROXY` and `NO_PROXY`, or
+    /// `proxy` and `no_proxy` in [`SETTINGS_FILE`]; see `proxy`).
+    pub proxy: crate::proxy::ProxySettings,
+    /// Host-wide caps on VMs, memory and disk (`MEDA_MAX_VMS`,
+    /// `MEDA_MAX_TOTAL_MEMORY`, `MEDA_MAX_TOTAL_DISK`; see `quota`).
+    pub quota: crate::quota::Limits,
+}
+
+/// What [`SETTINGS_FILE`] can set.
+#[derive(Debug, Default, Deserialize)]
+struct FileSettings {
+    default_registry: Option<String>,
+    default_org: Option<String>,
+    proxy: Option<String>,
+    no_proxy: Option<String>,
+}
+
+impl FileSettings {
+    /// A missing file is empty; an unreadable one is warned about and
+    /// ignored, like invalid environment values.
+    fn load(path: &Path) -> Self {
+        let Ok(content) = std::fs::read_to_string(path) else {
+            return Self::default();
+        };
+        serde_json::from_str(&content).unwrap_or_else(|e| {
+            log::warn!("Ignoring {}: {}", path.display(), e);
+            Self::default()
+        })
+    }
+}
+
+/// Environment value of `var`, else the settings file's, else `default`.
+fn setting(var: &str, file: Option<String>, default: &str) -> String {
+    env::var(var)
+        .ok()
+        .or(file)
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| default.to_string())
 }
 
 impl Config {
     pub fn new() -> Result<Self> {
         let home = dirs::home_dir().ok_or_else(|| Error::HomeDirNotFound)?;
-        let ch_home = home.join(".meda");
+        let layout = DirLayout::detect(&home, env::var("MEDA_LAYOUT").ok().as_deref());
+        let defaults = LayoutDirs::resolve(layout, &home, |var| env::var(var).ok());
+
+        let ch_home = env::var("MEDA_CONFIG_DIR")
+            .map(PathBuf::from)
+            .unwrap_or(defaults.config);
 
         let asset_dir = env::var("MEDA_ASSET_DIR")
             .map(PathBuf::from)
-            .unwrap_or_else(|_| ch_home.join("assets"));
+            .unwrap_or(defaults.assets);
 
         let vm_root = env::var("MEDA_VM_DIR")
             .map(PathBuf::from)
-            .unwrap_or_else(|_| ch_home.join("vms"));
+            .unwrap_or(defaults.vms);
 
         let os_url = env::var("MEDA_OS_URL").unwrap_or_else(|_| {
             "https://cloud-images.ubuntu.com/jammy/current/jammy-server-cloudimg-amd64.img"
@@ -44,13 +206,30 @@ impl Config {
         let fw_url = "https://github.com/cloud-hypervisor/rust-hypervisor-firmware/releases/latest/download/hypervisor-fw".to_string();
         let ch_url = "https://github.com/cloud-hypervisor/cloud-hypervisor/releases/latest/download/cloud-hypervisor-static".to_string();
         let cr_url = "https://github.com/cloud-hypervisor/cloud-hypervisor/releases/latest/download/ch-remote-static".to_string();
-        let oras_url = "https://github.com/oras-project/oras/releases/download/v1.2.3/oras_1.2.3_linux_amd64.tar.gz".to_string();
+        let oras_version = env::var("MEDA_ORAS_VERSION")
+            .map(|v| {
+                crate::oras::Version::parse(&v).unwrap_or_else(|| {
+                    log::warn!(
+                        "Ignoring invalid MEDA_ORAS_VERSION '{}' (expected e.g. {})",
+                        v,
+                        crate::oras::DEFAULT_VERSION
+                    );
+                    crate::oras::DEFAULT_VERSION
+                })
+            })
+            .unwrap_or(crate::oras::DEFAULT_VERSION);
+        let oras_url = crate::oras::release_url(oras_version);
+        let asset_mirrors = crate::assets::mirrors_from_env(|var| env::var(var).ok());
 
         let base_raw = asset_dir.join("ubuntu-base.raw");
         let fw_bin = asset_dir.join("hypervisor-fw");
         let ch_bin = asset_dir.join("cloud-hypervisor");
         let cr_bin = asset_dir.join("ch-remote");
         let oras_bin = asset_dir.join("oras");
+        let oras_override = env::var("MEDA_ORAS_BIN")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .map(|v| crate::oras::resolve_bin(v.trim()));
 
         let cpus = env::var("MEDA_CPUS")
             .map(|v| v.parse().unwrap_or(2))
@@ -58,6 +237,17 @@ impl Config {
 
         let mem = env::var("MEDA_MEM").unwrap_or_else(|_| "1024M".to_string());
         let disk_size = env::var("MEDA_DISK_SIZE").unwrap_or_else(|_| "10G".to_string());
+        let disk_format = env::var("MEDA_DISK_FORMAT")
+            .map(|v| {
+                DiskFormat::parse(&v).unwrap_or_else(|| {
+                    log::warn!(
+                        "Ignoring invalid MEDA_DISK_FORMAT '{}' (expected qcow2 or raw)",
+                        v
+                    );
+                    DiskFormat::Qcow2
+                })
+            })
+            .unwrap_or(DiskFormat::Qcow2);
 
         // Initialize chunking configuration with environment variable overrides
         let mut chunking = ChunkingConfig::default();
@@ -81,6 +271,100 @@ impl Config {
             }
         }
 
+        if let Ok(workers) = env::var("MEDA_CHUNK_WORKERS") {
+            if let Ok(parsed) = workers.parse::<usize>() {
+                chunking.workers = parsed.clamp(1, 32);
+            }
+        }
+
+        if let Ok(kind) = env::var("MEDA_IMAGE_COMPRESSION") {
+            let level = env::var("MEDA_IMAGE_COMPRESSION_LEVEL").ok();
+            match Compression::parse(&kind, level.as_deref()) {
+                Some(compression) => chunking.compression = compression,
+                None => log::warn!(
+                    "Ignoring invalid MEDA_IMAGE_COMPRESSION '{}' / MEDA_IMAGE_COMPRESSION_LEVEL {:?} (expected zstd or none, level 1-19)",
+                    kind,
+                    level
+                ),
+            }
+        }
+
+        let storage_pools = match env::var("MEDA_STORAGE_POOLS") {
+            Ok(spec) => crate::storage::parse_pools(&spec)?,
+            Err(_) => Vec::new(),
+        };
+        let placement = env::var("MEDA_STORAGE_POLICY")
+            .map(|v| {
+                PlacementPolicy::parse(&v).unwrap_or_else(|| {
+                    log::warn!(
+                        "Ignoring invalid MEDA_STORAGE_POLICY '{}' (expected default, free-space or size)",
+                        v
+                    );
+                    PlacementPolicy::Default
+                })
+            })
+            .unwrap_or_default();
+
+        let isolate = env::var("MEDA_ISOLATE")
+            .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        let dhcp = env::var("MEDA_DHCP")
+            .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        let offline = env::var("MEDA_OFFLINE")
+            .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        let stop_timeout = env::var("MEDA_STOP_TIMEOUT")
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_STOP_TIMEOUT);
+        let image_max_age = env::var("MEDA_IMAGE_MAX_AGE").ok().and_then(|v| {
+            let secs = crate::util::parse_duration_secs(&v);
+            if secs.is_none() {
+                log::warn!("Ignoring MEDA_IMAGE_MAX_AGE '{}' (expected e.g. 14d)", v);
+            }
+            secs.map(Duration::from_secs)
+        });
+        let preflight = env::var("MEDA_PREFLIGHT")
+            .map(|v| {
+                !matches!(
+                    v.trim().to_ascii_lowercase().as_str(),
+                    "0" | "false" | "no" | "off"
+                )
+            })
+            .unwrap_or(true);
+        let rng_source = env::var("MEDA_RNG_SOURCE")
+            .map(|v| {
+                crate::rng::RngSource::parse(&v).unwrap_or_else(|e| {
+                    log::warn!("Ignoring MEDA_RNG_SOURCE: {}", e);
+                    crate::rng::RngSource::default()
+                })
+            })
+            .unwrap_or_default();
+        let subnet_pool = env::var("MEDA_SUBNET_POOL")
+            .map(|v| {
+                crate::subnets::SubnetPool::parse(&v).unwrap_or_else(|e| {
+                    log::warn!("Ignoring MEDA_SUBNET_POOL: {}", e);
+                    crate::subnets::SubnetPool::default()
+                })
+            })
+            .unwrap_or_default();
+        let inventory = crate::inventory::Exporters::from_env(|var| env::var(var).ok());
+        let quota = crate::quota::Limits::from_env(|var| env::var(var).ok());
+        let settings = FileSettings::load(&ch_home.join(SETTINGS_FILE));
+        let default_registry = setting(
+            "MEDA_DEFAULT_REGISTRY",
+            settings.default_registry,
+            DEFAULT_REGISTRY,
+        );
+        let default_org = setting("MEDA_DEFAULT_ORG", settings.default_org, DEFAULT_ORG);
+        let proxy = crate::proxy::ProxySettings::resolve(
+            |var| env::var(var).ok(),
+            settings.proxy,
+            settings.no_proxy,
+        );
+
         Ok(Self {
             ch_home,
             asset_dir,
@@ -90,15 +374,34 @@ impl Config {
             ch_url,
             cr_url,
             oras_url,
+            asset_mirrors,
             base_raw,
             fw_bin,
             ch_bin,
             cr_bin,
             oras_bin,
+            oras_version,
+            oras_override,
             cpus,
             mem,
             disk_size,
+            disk_format,
             chunking,
+            storage_pools,
+            placement,
+            isolate,
+            dhcp,
+            stop_timeout,
+            image_max_age,
+            preflight,
+            subnet_pool,
+            rng_source,
+            inventory,
+            default_registry,
+            default_org,
+            offline,
+            proxy,
+            quota,
         })
     }
 
@@ -110,6 +413,19 @@ impl Config {
         self.ch_home.join("ssh")
     }
 
+    /// Fail fast when offline, for an operation that would fetch
+    /// `what` over the network, rather than wait on a download that can't
+    /// finish.
+    pub fn require_network(&self, what: &str) -> Result<()> {
+        if self.offline {
+            return Err(Error::Other(format!(
+                "{} needs the network, but meda is offline (--offline or MEDA_OFFLINE)",
+                what
+            )));
+        }
+        Ok(())
+    }
+
     pub fn ensure_dirs(&self) -> Result<()> {
         std::fs::create_dir_all(&self.ch_home)?;
         std::fs::create_dir_all(&self.asset_dir)?;
@@ -135,6 +451,7 @@ mod tests {
         let saved_mem = env::var("MEDA_MEM").ok();
         let saved_disk_size = env::var("MEDA_DISK_SIZE").ok();
         let saved_os_url = env::var("MEDA_OS_URL").ok();
+        env::set_var("MEDA_LAYOUT", "legacy");
 
         // Remove all env vars to test defaults
         env::remove_var("MEDA_ASSET_DIR");
@@ -150,6 +467,13 @@ mod tests {
         assert!(config.asset_dir.ends_with("assets"));
         assert!(config.vm_root.ends_with("vms"));
         assert_eq!(config.cpus, 2);
+
+        env::set_var("MEDA_LAYOUT", "xdg");
+        let xdg = Config::new().unwrap();
+        assert!(xdg.ch_home.ends_with("meda"));
+        assert!(xdg.asset_dir.ends_with("meda/assets"));
+        assert!(xdg.vm_root.ends_with("meda/vms"));
+        env::remove_var("MEDA_LAYOUT");
         assert_eq!(config.mem, "1024M");
         assert_eq!(config.disk_size, "10G");
         assert_eq!(
@@ -219,6 +543,91 @@ mod tests {
         env::remove_var("MEDA_OS_URL");
     }
 
+    #[test]
+    #[serial]
+    fn test_offline() {
+        env::remove_var("MEDA_OFFLINE");
+        assert!(Config::new().unwrap().require_network("Pulling").is_ok());
+
+        env::set_var("MEDA_OFFLINE", "1");
+        let err = Config::new()
+            .unwrap()
+            .require_network("Pulling ghcr.io/cirunlabs/ubuntu:22.04")
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Pulling ghcr.io/cirunlabs/ubuntu:22.04 needs the network"));
+
+        env::remove_var("MEDA_OFFLINE");
+    }
+
+    #[test]
+    #[serial]
+    fn test_disk_format_from_env() {
+        let saved = env::var("MEDA_DISK_FORMAT").ok();
+
+        env::remove_var("MEDA_DISK_FORMAT");
+        assert_eq!(Config::new().unwrap().disk_format, DiskFormat::Qcow2);
+
+        env::set_var("MEDA_DISK_FORMAT", "raw");
+        assert_eq!(Config::new().unwrap().disk_format, DiskFormat::Raw);
+
+        env::set_var("MEDA_DISK_FORMAT", "bogus");
+        assert_eq!(Config::new().unwrap().disk_format, DiskFormat::Qcow2);
+
+        match saved {
+            Some(val) => env::set_var("MEDA_DISK_FORMAT", val),
+            None => env::remove_var("MEDA_DISK_FORMAT"),
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_default_registry_and_org() {
+        let temp_dir = TempDir::new().unwrap();
+        env::set_var("MEDA_CONFIG_DIR", temp_dir.path());
+        env::remove_var("MEDA_DEFAULT_REGISTRY");
+        env::remove_var("MEDA_DEFAULT_ORG");
+
+        let config = Config::new().unwrap();
+        assert_eq!(config.default_registry, DEFAULT_REGISTRY);
+        assert_eq!(config.default_org, DEFAULT_ORG);
+
+        std::fs::write(
+            temp_dir.path().join(SETTINGS_FILE),
+            r#"{"default_registry": "registry.example.com", "default_org": "platform"}"#,
+        )
+        .unwrap();
+        env::set_var("MEDA_DEFAULT_ORG", "ci-team");
+        let config = Config::new().unwrap();
+        assert_eq!(config.default_registry, "registry.example.com");
+        assert_eq!(config.default_org, "ci-team");
+
+        env::remove_var("MEDA_DEFAULT_ORG");
+        env::remove_var("MEDA_CONFIG_DIR");
+    }
+
+    #[test]
+    fn test_disk_format_detect_and_args() {
+        let temp_dir = TempDir::new().unwrap();
+        assert!(DiskFormat::detect(temp_dir.path()).is_none());
+
+        std::fs::write(temp_dir.path().join("rootfs.raw"), b"").unwrap();
+        let (path, fmt) = DiskFormat::detect(temp_dir.path()).unwrap();
+        assert_eq!(fmt, DiskFormat::Raw);
+        assert_eq!(
+            fmt.ch_disk_arg(&path),
+            format!("path={},image_type=raw", path.display())
+        );
+
+        std::fs::write(temp_dir.path().join("rootfs.qcow2"), b"").unwrap();
+        let (path, fmt) = DiskFormat::detect(temp_dir.path()).unwrap();
+        assert_eq!(fmt, DiskFormat::Qcow2);
+        assert!(fmt
+            .ch_disk_arg(&path)
+            .ends_with("image_type=qcow2,backing_files=on"));
+    }
+
     #[test]
     #[serial]
     fn test_vm_dir() {
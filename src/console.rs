@@ -0,0 +1,329 @@
+//! Serial console access.
+//!
+//! VMs are started with `--serial socket=<vmdir>/serial-ch.sock`, so
+//! cloud-hypervisor listens on a unix socket carrying the guest's
+//! ttyS0. CH drops output while no one is connected, so a relay (see
+//! [`relay`]) started with the VM stays connected to it: it appends
+//! everything the guest prints to `serial.log`, which `meda console-dump`
+//! and `GET /api/v1/vms/{name}/serial` read, and serves the console on
+//! `serial.sock`. [`connect`] opens that socket and [`bridge`] pumps
+//! bytes between it and a WebSocket for `GET /api/v1/vms/{name}/console`.
+//! VMs created before the relay have CH on `serial.sock` itself, and no
+//! log.
+//!
+//! The console serves one client at a time; a second connection waits
+//! until the first one disconnects.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::util::run_command;
+use crate::vm;
+use axum::extract::ws::{Message, WebSocket};
+use futures_util::{SinkExt, StreamExt};
+use log::debug;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+
+/// The console clients connect to.
+pub const SERIAL_SOCKET: &str = "serial.sock";
+/// Where CH serves the guest's ttyS0 to the relay.
+pub const CH_SERIAL_SOCKET: &str = "serial-ch.sock";
+/// Guest serial output, then `serial.log.1` once it passes
+/// [`SERIAL_LOG_MAX`].
+pub const SERIAL_LOG: &str = "serial.log";
+const SERIAL_LOG_MAX: u64 = 1024 * 1024;
+
+/// How long the relay waits for CH's socket.
+const CH_SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn serial_socket_path(config: &Config, name: &str) -> PathBuf {
+    config.vm_dir(name).join(SERIAL_SOCKET)
+}
+
+/// Open the serial socket of a running VM.
+pub async fn connect(config: &Config, name: &str) -> Result<UnixStream> {
+    if !config.vm_dir(name).exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+    if !vm::check_vm_running(config, name)? {
+        return Err(Error::VmNotRunning(name.to_string()));
+    }
+    let path = serial_socket_path(config, name);
+    if !path.exists() {
+        return Err(Error::Other(format!(
+            "VM {} has no serial socket; it was started before console support — restart it with `meda stop {} && meda start {}`",
+            name, name, name
+        )));
+    }
+    match UnixStream::connect(&path).await {
+        Ok(stream) => Ok(stream),
+        // CH runs as root (under `sudo ip netns exec`), so the socket
+        // may still be root-only. Relax it once, like api.sock.
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            run_command("sudo", &["chmod", "0666", path.to_str().unwrap()]).await?;
+            Ok(UnixStream::connect(&path).await?)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Start the serial relay of the VM in `vm_dir`, whose CH was just
+/// started with [`CH_SERIAL_SOCKET`]. The relay outlives meda, like CH,
+/// and exits when CH does.
+pub fn start_relay(vm_dir: &Path) -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let mut child = Command::new(exe)
+        .arg("serial-relay")
+        .arg(vm_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::from(fs::File::create(
+            vm_dir.join("serial-relay.log"),
+        )?))
+        .spawn()
+        .map_err(|e| Error::CommandFailed(format!("spawn serial relay: {}", e)))?;
+    // Reaped whenever it exits, if this process is still around then
+    // (`meda serve`).
+    std::thread::spawn(move || child.wait());
+    Ok(())
+}
+
+/// Is the VM in `vm_dir` launched with its serial on
+/// [`CH_SERIAL_SOCKET`], rather than from before the relay?
+pub fn uses_relay(vm_dir: &Path) -> bool {
+    crate::launch::LaunchSpec::load(vm_dir)
+        .is_some_and(|spec| spec.args.iter().any(|arg| arg.ends_with(CH_SERIAL_SOCKET)))
+}
+
+/// Append guest output to `serial.log`, moving it to `serial.log.1`
+/// once it is full.
+struct SerialLog {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+}
+
+impl SerialLog {
+    fn open(vm_dir: &Path) -> Result<Self> {
+        let path = vm_dir.join(SERIAL_LOG);
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file, size })
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        if self.size >= SERIAL_LOG_MAX {
+            fs::rename(&self.path, self.path.with_extension("log.1"))?;
+            self.file = fs::File::create(&self.path)?;
+            self.size = 0;
+        }
+        self.file.write_all(bytes)?;
+        self.size += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+enum Event {
+    Guest(std::io::Result<usize>),
+    Client(std::io::Result<usize>),
+    Accepted(std::io::Result<UnixStream>),
+}
+
+/// Stay connected to CH's serial socket in `vm_dir` until CH closes it,
+/// logging the guest's output and serving it to one console client at
+/// a time on [`SERIAL_SOCKET`].
+pub async fn relay(vm_dir: &Path) -> Result<()> {
+    let deadline = Instant::now() + CH_SOCKET_TIMEOUT;
+    let ch_socket = vm_dir.join(CH_SERIAL_SOCKET);
+    let mut guest = loop {
+        match UnixStream::connect(&ch_socket).await {
+            Ok(stream) => break stream,
+            Err(e) if Instant::now() > deadline => return Err(e.into()),
+            // A root CH's socket may be root-only; see `connect`.
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                run_command("sudo", &["chmod", "0666", ch_socket.to_str().unwrap()]).await?;
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    let socket = vm_dir.join(SERIAL_SOCKET);
+    fs::remove_file(&socket).ok();
+    let listener = UnixListener::bind(&socket)?;
+    let mut log = SerialLog::open(vm_dir)?;
+    let mut client: Option<UnixStream> = None;
+    let mut guest_buf = [0u8; 4096];
+    let mut client_buf = [0u8; 4096];
+
+    loop {
+        let event = tokio::select! {
+            r = guest.read(&mut guest_buf) => Event::Guest(r),
+            r = listener.accept(), if client.is_none() => Event::Accepted(r.map(|(s, _)| s)),
+            r = async { client.as_mut().unwrap().read(&mut client_buf).await }, if client.is_some() => Event::Client(r),
+        };
+        match event {
+            Event::Guest(Ok(0)) | Event::Guest(Err(_)) => break,
+            Event::Guest(Ok(n)) => {
+                if let Err(e) = log.write(&guest_buf[..n]) {
+                    debug!("serial log write failed: {}", e);
+                }
+                if let Some(stream) = client.as_mut() {
+                    if stream.write_all(&guest_buf[..n]).await.is_err() {
+                        client = None;
+                    }
+                }
+            }
+            Event::Accepted(Ok(stream)) => client = Some(stream),
+            Event::Accepted(Err(e)) => debug!("serial accept failed: {}", e),
+            Event::Client(Ok(0)) | Event::Client(Err(_)) => client = None,
+            Event::Client(Ok(n)) => guest.write_all(&client_buf[..n]).await?,
+        }
+    }
+    fs::remove_file(&socket).ok();
+    Ok(())
+}
+
+/// The last `lines` lines the guest printed on its serial console.
+pub fn dump(config: &Config, name: &str, lines: usize) -> Result<String> {
+    let vm_dir = config.vm_dir(name);
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+    let log = vm_dir.join(SERIAL_LOG);
+    if !log.exists() {
+        return Err(Error::Other(format!(
+            "VM {} has no serial log: it has not been started yet, or was created before serial logging",
+            name
+        )));
+    }
+    let mut bytes = fs::read(log.with_extension("log.1")).unwrap_or_default();
+    bytes.extend(fs::read(&log)?);
+    Ok(tail(&String::from_utf8_lossy(&bytes), lines))
+}
+
+/// Last `lines` lines of terminal output, without carriage returns.
+fn tail(output: &str, lines: usize) -> String {
+    let all: Vec<&str> = output.lines().map(|l| l.trim_end_matches('\r')).collect();
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
+
+/// Copy guest output to the WebSocket as binary frames and client
+/// frames (text or binary) to the guest until either side closes.
+pub async fn bridge(socket: WebSocket, stream: UnixStream) {
+    let (mut ws_tx, mut ws_rx) = socket.split();
+    let (mut serial_rx, mut serial_tx) = stream.into_split();
+
+    let mut to_client = tokio::spawn(async move {
+        let mut buf = [0u8; 4096];
+        loop {
+            match serial_rx.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if ws_tx
+                        .send(Message::Binary(buf[..n].to_vec()))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = ws_tx.send(Message::Close(None)).await;
+    });
+
+    let mut to_guest = tokio::spawn(async move {
+        while let Some(Ok(msg)) = ws_rx.next().await {
+            let bytes = match msg {
+                Message::Text(text) => text.into_bytes(),
+                Message::Binary(data) => data,
+                Message::Close(_) => break,
+                Message::Ping(_) | Message::Pong(_) => continue,
+            };
+            if serial_tx.write_all(&bytes).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // Whichever direction ends first tears down the other.
+    tokio::select! {
+        _ = &mut to_client => to_guest.abort(),
+        _ = &mut to_guest => to_client.abort(),
+    }
+    debug!("console session closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_connect_errors() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.vm_root = temp_dir.path().to_path_buf();
+
+        assert!(matches!(
+            connect(&config, "missing").await,
+            Err(Error::VmNotFound(_))
+        ));
+
+        std::fs::create_dir_all(config.vm_dir("stopped")).unwrap();
+        assert!(matches!(
+            connect(&config, "stopped").await,
+            Err(Error::VmNotRunning(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_relay_and_dump() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.vm_root = temp_dir.path().to_path_buf();
+        let vm_dir = config.vm_dir("vm");
+        fs::create_dir_all(&vm_dir).unwrap();
+        assert!(dump(&config, "vm", 10).is_err());
+
+        // Stands in for CH.
+        let ch = UnixListener::bind(vm_dir.join(CH_SERIAL_SOCKET)).unwrap();
+        let relay_dir = vm_dir.clone();
+        let relay = tokio::spawn(async move { relay(&relay_dir).await });
+        let (mut guest, _) = ch.accept().await.unwrap();
+
+        // Printed with no one attached: only logged.
+        guest.write_all(b"booting\r\n").await.unwrap();
+        while fs::read(vm_dir.join(SERIAL_LOG))
+            .unwrap_or_default()
+            .is_empty()
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut client = UnixStream::connect(vm_dir.join(SERIAL_SOCKET))
+            .await
+            .unwrap();
+        client.write_all(b"root\n").await.unwrap();
+        let mut typed = [0u8; 5];
+        guest.read_exact(&mut typed).await.unwrap();
+        assert_eq!(&typed, b"root\n");
+        guest.write_all(b"login ok\r\n").await.unwrap();
+        let mut echoed = [0u8; 10];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"login ok\r\n");
+
+        // CH going away ends the relay.
+        drop(guest);
+        relay.await.unwrap().unwrap();
+        assert!(!vm_dir.join(SERIAL_SOCKET).exists());
+        assert_eq!(dump(&config, "vm", 10).unwrap(), "booting\nlogin ok");
+        assert_eq!(dump(&config, "vm", 1).unwrap(), "login ok");
+    }
+}
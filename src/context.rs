@@ -0,0 +1,337 @@
+//! Remote contexts: other hosts' `meda serve` APIs, by name.
+//!
+//! `meda context add prod --url https://meda.example.com:7777 --token-stdin`
+//! records a context in `contexts.toml` in the config dir, readable only
+//! by the user since it holds API tokens:
+//!
+//! ```toml
+//! current = "prod"
+//!
+//! [contexts.prod]
+//! url = "https://meda.example.com:7777"
+//! token = "..."
+//! ```
+//!
+//! `meda --context prod list` (or `MEDA_CONTEXT=prod`) runs one command
+//! against a context, and `meda context use prod` makes it the default
+//! for every command. `default` is this host, as with docker contexts:
+//! `meda context use default` goes back to running VMs locally. While a
+//! context is active, commands are sent to its API (see `remote`).
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// File in the config dir holding the contexts.
+pub const CONTEXTS_FILE: &str = "contexts.toml";
+
+/// The local host, which can't be redefined.
+pub const DEFAULT: &str = "default";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Context {
+    /// Base URL of the API, e.g. `https://meda.example.com:7777`.
+    pub url: String,
+    /// Bearer token, for servers started with `MEDA_API_TOKEN`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ContextsFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    current: Option<String>,
+    #[serde(default)]
+    contexts: BTreeMap<String, Context>,
+}
+
+/// The context a command runs against, if not this host.
+#[derive(Debug, Clone)]
+pub struct Active {
+    pub name: String,
+    pub context: Context,
+}
+
+#[derive(Debug, Serialize)]
+struct ContextListing {
+    name: String,
+    url: String,
+    current: bool,
+}
+
+fn path(config: &Config) -> PathBuf {
+    config.ch_home.join(CONTEXTS_FILE)
+}
+
+fn load(path: &Path) -> Result<ContextsFile> {
+    match fs::read_to_string(path) {
+        Ok(body) => toml::from_str(&body)
+            .map_err(|e| Error::Other(format!("Invalid {}: {}", path.display(), e))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ContextsFile::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save(path: &Path, file: &ContextsFile) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let body = toml::to_string(file)
+        .map_err(|e| Error::Other(format!("Failed to write {}: {}", path.display(), e)))?;
+    let tmp = path.with_extension("toml.tmp");
+    fs::write(&tmp, body)?;
+    fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    if !valid {
+        return Err(Error::Other(format!(
+            "Invalid context name '{}': use letters, digits, '-', '_' and '.'",
+            name
+        )));
+    }
+    if name == DEFAULT {
+        return Err(Error::Other(format!(
+            "'{}' is this host and can't be redefined",
+            DEFAULT
+        )));
+    }
+    Ok(())
+}
+
+/// `url` without a trailing slash, if it's an http(s) URL.
+fn normalize_url(url: &str) -> Result<String> {
+    let parsed = reqwest::Url::parse(url)
+        .map_err(|e| Error::Other(format!("Invalid context URL '{}': {}", url, e)))?;
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+        return Err(Error::Other(format!(
+            "Invalid context URL '{}': expected http://host[:port] or https://host[:port]",
+            url
+        )));
+    }
+    Ok(url.trim_end_matches('/').to_string())
+}
+
+/// Which context `requested` (from `--context`, else `MEDA_CONTEXT`, else
+/// the file's `current`) names; `None` for this host.
+fn select(file: &ContextsFile, requested: Option<&str>) -> Result<Option<Active>> {
+    let Some(name) = requested.or(file.current.as_deref()) else {
+        return Ok(None);
+    };
+    if name == DEFAULT {
+        return Ok(None);
+    }
+    match file.contexts.get(name) {
+        Some(context) => Ok(Some(Active {
+            name: name.to_string(),
+            context: context.clone(),
+        })),
+        None => Err(Error::Other(format!(
+            "No context named '{}' (see `meda context list`)",
+            name
+        ))),
+    }
+}
+
+/// The context commands run against: `--context`, `MEDA_CONTEXT`, or the
+/// one `meda context use` picked. `None` means this host.
+pub fn active(config: &Config, flag: Option<&str>) -> Result<Option<Active>> {
+    let env = std::env::var("MEDA_CONTEXT")
+        .ok()
+        .filter(|v| !v.trim().is_empty());
+    let requested = flag.map(str::to_string).or(env);
+    select(&load(&path(config))?, requested.as_deref())
+}
+
+pub fn add(
+    config: &Config,
+    name: &str,
+    url: &str,
+    token: Option<String>,
+    json: bool,
+) -> Result<()> {
+    check_name(name)?;
+    let context = Context {
+        url: normalize_url(url)?,
+        token: token.filter(|t| !t.is_empty()),
+    };
+    let path = path(config);
+    let mut file = load(&path)?;
+    let url = context.url.clone();
+    let replaced = file.contexts.insert(name.to_string(), context).is_some();
+    save(&path, &file)?;
+
+    let message = format!(
+        "{} context {} ({})",
+        if replaced { "Updated" } else { "Added" },
+        name,
+        url
+    );
+    if json {
+        let result = serde_json::json!({"success": true, "message": message});
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        println!("{}", message);
+    }
+    Ok(())
+}
+
+pub fn remove(config: &Config, name: &str, json: bool) -> Result<()> {
+    let path = path(config);
+    let mut file = load(&path)?;
+    if file.contexts.remove(name).is_none() {
+        return Err(Error::Other(format!("No context named '{}'", name)));
+    }
+    if file.current.as_deref() == Some(name) {
+        file.current = None;
+    }
+    save(&path, &file)?;
+
+    let message = format!("Removed context {}", name);
+    if json {
+        let result = serde_json::json!({"success": true, "message": message});
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        println!("{}", message);
+    }
+    Ok(())
+}
+
+/// Make `name` the context for commands without `--context`.
+pub fn use_context(config: &Config, name: &str, json: bool) -> Result<()> {
+    let path = path(config);
+    let mut file = load(&path)?;
+    if name != DEFAULT && !file.contexts.contains_key(name) {
+        return Err(Error::Other(format!(
+            "No context named '{}' (see `meda context list`)",
+            name
+        )));
+    }
+    file.current = (name != DEFAULT).then(|| name.to_string());
+    save(&path, &file)?;
+
+    let message = format!("Now using context {}", name);
+    if json {
+        let result = serde_json::json!({"success": true, "message": message});
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        println!("{}", message);
+    }
+    Ok(())
+}
+
+/// Contexts, `default` first; tokens are never printed.
+pub fn list(config: &Config, flag: Option<&str>, json: bool) -> Result<()> {
+    let file = load(&path(config))?;
+    let current = active(config, flag)?
+        .map(|a| a.name)
+        .unwrap_or_else(|| DEFAULT.to_string());
+    let mut listing = vec![ContextListing {
+        name: DEFAULT.to_string(),
+        url: "(this host)".to_string(),
+        current: current == DEFAULT,
+    }];
+    listing.extend(file.contexts.iter().map(|(name, context)| ContextListing {
+        name: name.clone(),
+        url: context.url.clone(),
+        current: *name == current,
+    }));
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&listing)?);
+    } else {
+        let width = listing.iter().map(|c| c.name.len()).max().unwrap_or(4);
+        println!("  {:<width$} url", "name", width = width);
+        for context in listing {
+            println!(
+                "{} {:<width$} {}",
+                if context.current { "*" } else { " " },
+                context.name,
+                context.url,
+                width = width
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let file: ContextsFile = toml::from_str(
+            r#"
+            current = "prod"
+
+            [contexts.prod]
+            url = "https://meda.example.com:7777"
+            token = "secret"
+
+            [contexts.lab]
+            url = "http://10.0.0.5:7777"
+            "#,
+        )
+        .unwrap();
+
+        let active = select(&file, None).unwrap().unwrap();
+        assert_eq!(active.name, "prod");
+        assert_eq!(active.context.token.as_deref(), Some("secret"));
+        assert_eq!(
+            select(&file, Some("lab")).unwrap().unwrap().context.url,
+            "http://10.0.0.5:7777"
+        );
+        assert!(select(&file, Some("default")).unwrap().is_none());
+        assert!(select(&file, Some("staging")).is_err());
+        assert!(select(&ContextsFile::default(), None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONTEXTS_FILE);
+        let mut file = ContextsFile {
+            current: Some("prod".to_string()),
+            ..Default::default()
+        };
+        file.contexts.insert(
+            "prod".to_string(),
+            Context {
+                url: normalize_url("https://meda.example.com:7777/").unwrap(),
+                token: None,
+            },
+        );
+        save(&path, &file).unwrap();
+
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded.current.as_deref(), Some("prod"));
+        assert_eq!(loaded.contexts["prod"].url, "https://meda.example.com:7777");
+    }
+
+    #[test]
+    fn test_check_name_and_url() {
+        assert!(check_name("prod-eu.1").is_ok());
+        assert!(check_name("default").is_err());
+        assert!(check_name("a b").is_err());
+        assert!(check_name("").is_err());
+        assert!(normalize_url("ssh://host").is_err());
+        assert!(normalize_url("meda.example.com").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! Pinning a VM to host CPUs.
+//!
+//! `meda create --cpu-affinity 0-3` runs the VM's cloud-hypervisor
+//! process under `taskset -c 0-3`, so its vCPU and device threads (which
+//! inherit the mask) stay on those CPUs and off the rest of the host.
+//! Latency-sensitive guests pair it with `--hugepages` and with
+//! `isolcpus` on the host kernel command line. The set is recorded in
+//! the VM dir and in the VM's launch spec.
+
+use crate::doctor::{Finding, Status};
+use crate::error::Result;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// File in a VM dir with its CPU list; absent for unpinned VMs.
+pub const CPU_AFFINITY_FILE: &str = "cpu-affinity";
+
+const ONLINE_CPUS: &str = "/sys/devices/system/cpu/online";
+
+/// Largest CPU number accepted, well above any host meda runs on.
+const MAX_CPU: u32 = 4095;
+
+/// A set of host CPUs in the kernel's list format, e.g. `0-3,8`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuSet(BTreeSet<u32>);
+
+impl CpuSet {
+    /// clap value parser for `--cpu-affinity`.
+    pub fn parse(list: &str) -> std::result::Result<Self, String> {
+        let invalid = || format!("invalid CPU list '{}' (expected e.g. 0-3,8)", list);
+        let mut cpus = BTreeSet::new();
+        for part in list.trim().split(',') {
+            let (first, last) = match part.split_once('-') {
+                Some((a, b)) => (a, b),
+                None => (part, part),
+            };
+            let first: u32 = first.trim().parse().map_err(|_| invalid())?;
+            let last: u32 = last.trim().parse().map_err(|_| invalid())?;
+            if first > last || last > MAX_CPU {
+                return Err(invalid());
+            }
+            cpus.extend(first..=last);
+        }
+        Ok(Self(cpus))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn load(vm_dir: &Path) -> Option<Self> {
+        let list = fs::read_to_string(vm_dir.join(CPU_AFFINITY_FILE)).ok()?;
+        Self::parse(&list).ok()
+    }
+
+    pub fn save(&self, vm_dir: &Path) -> Result<()> {
+        fs::write(vm_dir.join(CPU_AFFINITY_FILE), self.to_string())?;
+        Ok(())
+    }
+
+    /// Can this host honor the set, and does it fit `vcpus`?
+    pub fn host_finding(&self, vcpus: Option<usize>) -> Finding {
+        let online = fs::read_to_string(ONLINE_CPUS)
+            .ok()
+            .and_then(|list| Self::parse(&list).ok());
+        if let Some(online) = online {
+            let offline = Self(self.0.difference(&online.0).copied().collect());
+            if !offline.is_empty() {
+                return Finding::new(
+                    "cpu-affinity",
+                    Status::Fail,
+                    format!(
+                        "pinned to CPUs {} but {} not online on this host (online: {})",
+                        self, offline, online
+                    ),
+                );
+            }
+        }
+        if crate::util::check_dependency("taskset").is_err() {
+            return Finding::new(
+                "cpu-affinity",
+                Status::Fail,
+                "taskset not found; install the util-linux package",
+            );
+        }
+        match vcpus {
+            Some(vcpus) if vcpus > self.len() => Finding::new(
+                "cpu-affinity",
+                Status::Warn,
+                format!(
+                    "{} vCPUs share {} pinned CPUs ({}), so they will contend",
+                    vcpus,
+                    self.len(),
+                    self
+                ),
+            ),
+            _ => Finding::new(
+                "cpu-affinity",
+                Status::Ok,
+                format!("pinned to CPUs {}", self),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for CpuSet {
+    /// Back to list format with runs collapsed: `0,1,2,3,8` is `0-3,8`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        for &cpu in &self.0 {
+            match ranges.last_mut() {
+                Some((_, last)) if *last + 1 == cpu => *last = cpu,
+                _ => ranges.push((cpu, cpu)),
+            }
+        }
+        let parts: Vec<String> = ranges
+            .iter()
+            .map(|&(a, b)| {
+                if a == b {
+                    a.to_string()
+                } else {
+                    format!("{}-{}", a, b)
+                }
+            })
+            .collect();
+        f.write_str(&parts.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_and_display() {
+        let cpus = CpuSet::parse("0-3,8").unwrap();
+        assert_eq!(cpus.len(), 5);
+        assert_eq!(cpus.to_string(), "0-3,8");
+        assert_eq!(CpuSet::parse("3,1,2, 0").unwrap().to_string(), "0-3");
+        assert_eq!(CpuSet::parse("5").unwrap().to_string(), "5");
+        assert_eq!(CpuSet::parse("0-63\n").unwrap().len(), 64);
+
+        for bad in ["", "a", "3-1", "0-", "1,,2", "-1", "0-99999"] {
+            assert!(CpuSet::parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(CpuSet::load(dir.path()), None);
+        let cpus = CpuSet::parse("2,3").unwrap();
+        cpus.save(dir.path()).unwrap();
+        assert_eq!(CpuSet::load(dir.path()), Some(cpus));
+    }
+}
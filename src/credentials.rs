@@ -0,0 +1,417 @@
+//! Registry credentials for push and pull.
+//!
+//! `meda login <registry>` stores a username and password (or token)
+//! in `auth.json` in the config dir, in the layout of Docker's
+//! `config.json` (`{"auths": {"<registry>": {"auth": "<base64 user:pass>"}}}`),
+//! readable only by the user. For a registry, meda uses, in order:
+//!
+//! 1. what `meda login` stored for it;
+//! 2. an `auths` entry in Docker's config (`$DOCKER_CONFIG/config.json`
+//!    or `~/.docker/config.json`); credential helpers aren't run;
+//! 3. for ghcr.io, `GITHUB_TOKEN` with username `token`, as before. It
+//!    isn't sent anywhere else: other registries would just reject it,
+//!    and shouldn't see a GitHub token.
+//!
+//! Passwords never reach the logs: [`Credentials`] prints without its
+//! password, and [`redact_command`] masks it in command lines.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use base64::Engine;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// File in the config dir holding `meda login` credentials.
+pub const AUTH_FILE: &str = "auth.json";
+
+#[derive(Clone, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+impl Credentials {
+    /// `--username`/`--password` for ORAS.
+    pub fn oras_args(&self) -> [&str; 4] {
+        ["--username", &self.username, "--password", &self.password]
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AuthFile {
+    #[serde(default)]
+    auths: BTreeMap<String, AuthEntry>,
+    /// Anything else (Docker's `credsStore`, ...), kept as it was.
+    #[serde(flatten)]
+    other: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AuthEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auth: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+impl AuthEntry {
+    fn credentials(&self) -> Option<Credentials> {
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            return Some(Credentials {
+                username: username.clone(),
+                password: password.clone(),
+            });
+        }
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(self.auth.as_deref()?.trim())
+            .ok()?;
+        let (username, password) = String::from_utf8(decoded)
+            .ok()?
+            .split_once(':')
+            .map(|(u, p)| (u.to_string(), p.to_string()))?;
+        Some(Credentials { username, password })
+    }
+}
+
+/// Key a registry is stored under: host (and port) only, with Docker
+/// Hub's aliases folded into `docker.io`.
+pub fn normalize_registry(registry: &str) -> String {
+    let host = registry
+        .trim()
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .split('/')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match host.as_str() {
+        "index.docker.io" | "registry-1.docker.io" | "registry.hub.docker.com" => {
+            "docker.io".to_string()
+        }
+        _ => host,
+    }
+}
+
+/// Registry part of `registry/org/name:tag`.
+pub fn registry_of(reference: &str) -> &str {
+    reference.split('/').next().unwrap_or_default()
+}
+
+fn auth_path(config: &Config) -> PathBuf {
+    config.ch_home.join(AUTH_FILE)
+}
+
+fn docker_config_path() -> Option<PathBuf> {
+    match std::env::var_os("DOCKER_CONFIG") {
+        Some(dir) => Some(PathBuf::from(dir).join("config.json")),
+        None => dirs::home_dir().map(|h| h.join(".docker").join("config.json")),
+    }
+}
+
+fn read_auth_file(path: &Path) -> Result<AuthFile> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map_err(|e| Error::Other(format!("Cannot parse {}: {}", path.display(), e))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(AuthFile::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn find_in(path: &Path, registry: &str) -> Option<Credentials> {
+    let file = read_auth_file(path).ok()?;
+    file.auths
+        .iter()
+        .find(|(key, _)| normalize_registry(key) == registry)
+        .and_then(|(_, entry)| entry.credentials())
+}
+
+/// Credentials for `registry`, or `None` to go anonymous.
+pub fn lookup(config: &Config, registry: &str) -> Option<Credentials> {
+    let registry = normalize_registry(registry);
+    if let Some(credentials) = find_in(&auth_path(config), &registry) {
+        debug!("Using meda login credentials for {}", registry);
+        return Some(credentials);
+    }
+    if let Some(credentials) = docker_config_path().and_then(|p| find_in(&p, &registry)) {
+        debug!("Using Docker credentials for {}", registry);
+        return Some(credentials);
+    }
+    if registry != "ghcr.io" {
+        return None;
+    }
+    std::env::var("GITHUB_TOKEN").ok().map(|token| {
+        debug!("Using GITHUB_TOKEN for {}", registry);
+        Credentials {
+            username: "token".to_string(),
+            password: token,
+        }
+    })
+}
+
+fn write_auth_file(path: &Path, file: &AuthFile) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(file)?)?;
+    fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+pub fn store(config: &Config, registry: &str, credentials: &Credentials) -> Result<()> {
+    let path = auth_path(config);
+    let mut file = read_auth_file(&path)?;
+    let auth = base64::engine::general_purpose::STANDARD
+        .encode(format!("{}:{}", credentials.username, credentials.password));
+    file.auths.insert(
+        normalize_registry(registry),
+        AuthEntry {
+            auth: Some(auth),
+            ..Default::default()
+        },
+    );
+    write_auth_file(&path, &file)
+}
+
+/// Forget `registry`'s stored credentials; `false` if there were none.
+pub fn remove(config: &Config, registry: &str) -> Result<bool> {
+    let path = auth_path(config);
+    let mut file = read_auth_file(&path)?;
+    let registry = normalize_registry(registry);
+    let before = file.auths.len();
+    file.auths
+        .retain(|key, _| normalize_registry(key) != registry);
+    if file.auths.len() == before {
+        return Ok(false);
+    }
+    write_auth_file(&path, &file)?;
+    Ok(true)
+}
+
+/// Log in to `registry`'s v2 API, as `docker login` does: directly for
+/// Basic auth, through its token service for Bearer auth.
+async fn verify(registry: &str, credentials: &Credentials) -> Result<()> {
+    let host = if registry == "docker.io" {
+        "registry-1.docker.io"
+    } else {
+        registry
+    };
+    let client = crate::proxy::client()?;
+    let url = format!("https://{}/v2/", host);
+    let response = client.get(&url).send().await?;
+    if response.status().is_success() {
+        return Ok(());
+    }
+    let challenge = response
+        .headers()
+        .get("www-authenticate")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let request = match crate::oci::parse_bearer_challenge(&challenge) {
+        Some(params) => {
+            let mut query = Vec::new();
+            if let Some(service) = params.get("service") {
+                query.push(("service", service.clone()));
+            }
+            client.get(&params["realm"]).query(&query)
+        }
+        None => client.get(&url),
+    };
+    let status = request
+        .basic_auth(&credentials.username, Some(&credentials.password))
+        .send()
+        .await?
+        .status();
+    if status.is_success() {
+        Ok(())
+    } else if status == reqwest::StatusCode::UNAUTHORIZED
+        || status == reqwest::StatusCode::FORBIDDEN
+    {
+        Err(Error::Other(format!(
+            "Login to {} failed: wrong username or password",
+            registry
+        )))
+    } else {
+        Err(Error::Other(format!(
+            "Login to {} failed: HTTP status {}",
+            registry, status
+        )))
+    }
+}
+
+#[derive(Serialize)]
+pub struct LoginResult {
+    pub registry: String,
+    pub username: String,
+    pub success: bool,
+}
+
+/// `meda login`: check `credentials` against `registry` and store them.
+pub async fn login(
+    config: &Config,
+    registry: &str,
+    credentials: Credentials,
+    json: bool,
+) -> Result<()> {
+    let registry = normalize_registry(registry);
+    if registry.is_empty() {
+        return Err(Error::Other("Registry must not be empty".to_string()));
+    }
+    config.require_network(&format!("Logging in to {}", registry))?;
+    verify(&registry, &credentials).await?;
+    store(config, &registry, &credentials)?;
+    if json {
+        let result = LoginResult {
+            registry,
+            username: credentials.username,
+            success: true,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        println!(
+            "✅ Logged in to {} as {} (saved to {})",
+            registry,
+            credentials.username,
+            auth_path(config).display()
+        );
+    }
+    Ok(())
+}
+
+/// `meda logout`.
+pub fn logout(config: &Config, registry: &str, json: bool) -> Result<()> {
+    let registry = normalize_registry(registry);
+    let removed = remove(config, &registry)?;
+    if json {
+        let result = LoginResult {
+            registry,
+            username: String::new(),
+            success: removed,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else if removed {
+        println!("✅ Removed credentials for {}", registry);
+    } else {
+        println!("No credentials stored for {}", registry);
+    }
+    Ok(())
+}
+
+/// `cmd` for logging, with the value of every `--password` replaced by
+/// `***`.
+pub fn redact_command(cmd: &std::process::Command) -> String {
+    let mut out = format!("{:?}", cmd.get_program());
+    let mut hide = false;
+    for arg in cmd.get_args() {
+        out.push(' ');
+        if hide {
+            out.push_str("\"***\"");
+        } else {
+            out.push_str(&format!("{:?}", arg));
+        }
+        hide = arg == "--password" || arg == "-p";
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn test_config(dir: &Path) -> Config {
+        let mut config = Config::new().unwrap();
+        config.ch_home = dir.join(".meda");
+        config
+    }
+
+    #[test]
+    fn test_normalize_registry() {
+        assert_eq!(
+            normalize_registry("https://index.docker.io/v1/"),
+            "docker.io"
+        );
+        assert_eq!(normalize_registry("GHCR.io"), "ghcr.io");
+        assert_eq!(
+            normalize_registry("registry.local:5000/team"),
+            "registry.local:5000"
+        );
+        assert_eq!(registry_of("ghcr.io/cirunlabs/ubuntu:latest"), "ghcr.io");
+    }
+
+    #[test]
+    fn test_store_lookup_remove() {
+        let dir = TempDir::new().unwrap();
+        let config = test_config(dir.path());
+        let credentials = Credentials {
+            username: "ci".to_string(),
+            password: "s3cr:et".to_string(),
+        };
+        store(&config, "https://registry.local:5000/", &credentials).unwrap();
+        assert_eq!(lookup(&config, "registry.local:5000"), Some(credentials));
+
+        let path = auth_path(&config);
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        let raw = fs::read_to_string(&path).unwrap();
+        assert!(!raw.contains("s3cr:et"));
+
+        assert!(remove(&config, "registry.local:5000").unwrap());
+        assert!(!remove(&config, "registry.local:5000").unwrap());
+        assert!(find_in(&path, "registry.local:5000").is_none());
+    }
+
+    #[test]
+    fn test_docker_style_entries() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.json");
+        fs::write(
+            &path,
+            r#"{"auths":{"https://index.docker.io/v1/":{"auth":"dXNlcjpwYXNz"},
+                "quay.io":{"username":"bot","password":"pw"}},"credsStore":"desktop"}"#,
+        )
+        .unwrap();
+        let hub = find_in(&path, "docker.io").unwrap();
+        assert_eq!(
+            (hub.username.as_str(), hub.password.as_str()),
+            ("user", "pass")
+        );
+        assert_eq!(find_in(&path, "quay.io").unwrap().username, "bot");
+        assert!(find_in(&path, "ghcr.io").is_none());
+    }
+
+    #[test]
+    fn test_redaction() {
+        let credentials = Credentials {
+            username: "ci".to_string(),
+            password: "hunter2".to_string(),
+        };
+        assert!(!format!("{:?}", credentials).contains("hunter2"));
+
+        let mut cmd = std::process::Command::new("oras");
+        cmd.args(["pull", "ghcr.io/x/y:latest"]);
+        cmd.args(credentials.oras_args());
+        let logged = redact_command(&cmd);
+        assert!(!logged.contains("hunter2"));
+        assert!(logged.contains("\"--username\" \"ci\" \"--password\" \"***\""));
+    }
+}
@@ -0,0 +1,174 @@
+//! DHCP guest addressing (`MEDA_DHCP=1`).
+//!
+//! By default the guest's address comes from the cloud-init
+//! network-config meda writes: `192.168.X.2/24` on the NIC with the VM's
+//! MAC. Images that ignore network-config (their own netplan, no
+//! cloud-init networking, other distros) never bring that address up
+//! and the VM is unreachable. In DHCP mode meda also runs a dnsmasq on
+//! the VM's tap, inside its netns when it has one, and the
+//! network-config asks for DHCP. The VM's MAC is pinned to `.2`, so the
+//! netns DNAT and port forwards keep working however the guest
+//! configures its NIC, and `meda ip` reads the lease to tell whether the
+//! guest has taken its address yet.
+//!
+//! Every VM has its own tap and /24, so there is one dnsmasq per VM. It
+//! is started as root before CH (see `launch`) and stopped with the VM.
+
+use crate::error::Result;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Marker file in a VM dir: the VM gets its address over DHCP.
+pub const DHCP_FILE: &str = "dhcp";
+
+/// dnsmasq's lease file, in the VM dir.
+pub const LEASE_FILE: &str = "dnsmasq.leases";
+
+const PID_FILE: &str = "dnsmasq.pid";
+
+const NAMESERVERS: [&str; 2] = ["8.8.8.8", "1.1.1.1"];
+
+pub fn is_enabled(vm_dir: &Path) -> bool {
+    vm_dir.join(DHCP_FILE).exists()
+}
+
+pub fn enable(vm_dir: &Path) -> Result<()> {
+    fs::write(vm_dir.join(DHCP_FILE), "")?;
+    Ok(())
+}
+
+/// Cloud-init network-config for the NIC with `mac` on `subnet`.
+pub fn network_config(mac: &str, subnet: &str, dhcp: bool) -> String {
+    let addressing = if dhcp {
+        "    dhcp4: true\n".to_string()
+    } else {
+        format!(
+            "    addresses: [{subnet}.2/24]\n    gateway4: {subnet}.1\n    nameservers:\n      addresses: [{}]\n",
+            NAMESERVERS.join(", ")
+        )
+    };
+    format!(
+        "version: 2\nethernets:\n  ens4:\n    match:\n       macaddress: {mac}\n    set-name: ens4\n{addressing}"
+    )
+}
+
+/// Shell lines for the launch spec's setup (run as root) that (re)start
+/// the VM's dnsmasq on `tap`, inside `netns` if given. Empty for VMs not
+/// in DHCP mode.
+pub fn start_commands(
+    vm_dir: &Path,
+    netns: Option<&str>,
+    tap: &str,
+    subnet: &str,
+    mac: &str,
+) -> String {
+    if !is_enabled(vm_dir) {
+        return String::new();
+    }
+    let vmdir = vm_dir.display();
+    let exec = netns
+        .map(|ns| format!("ip netns exec {} ", ns))
+        .unwrap_or_default();
+    format!(
+        r#"if [ -f "{vmdir}/{pid}" ]; then kill "$(cat "{vmdir}/{pid}")" 2>/dev/null; rm -f "{vmdir}/{pid}"; fi
+{exec}dnsmasq --conf-file=/dev/null --port=0 --bind-interfaces --interface={tap} --except-interface=lo \
+  --dhcp-authoritative --dhcp-range={subnet}.2,{subnet}.254,255.255.255.0,12h --dhcp-host={mac},{subnet}.2 \
+  --dhcp-option=option:router,{subnet}.1 --dhcp-option=option:dns-server,{dns} \
+  --dhcp-leasefile="{vmdir}/{leases}" --pid-file="{vmdir}/{pid}"
+chmod 0644 "{vmdir}/{pid}"
+"#,
+        pid = PID_FILE,
+        leases = LEASE_FILE,
+        dns = NAMESERVERS.join(","),
+    )
+}
+
+/// Stop the VM's dnsmasq, if it has one running.
+pub fn stop(vm_dir: &Path) {
+    let pid_file = vm_dir.join(PID_FILE);
+    let Some(pid) = fs::read_to_string(&pid_file)
+        .ok()
+        .and_then(|p| p.trim().parse::<u32>().ok())
+    else {
+        return;
+    };
+    // Started as root by the launch setup.
+    let _ = Command::new("sudo")
+        .args(["kill", &pid.to_string()])
+        .output();
+    fs::remove_file(pid_file).ok();
+}
+
+/// Address leased to the VM's MAC, if the guest has asked for one.
+pub fn lease_ip(vm_dir: &Path) -> Option<String> {
+    let mac = fs::read_to_string(vm_dir.join("mac")).ok()?;
+    let leases = fs::read_to_string(vm_dir.join(LEASE_FILE)).ok()?;
+    parse_lease(&leases, mac.trim())
+}
+
+/// dnsmasq lease lines are `<expiry> <mac> <ip> <hostname> <client-id>`.
+fn parse_lease(leases: &str, mac: &str) -> Option<String> {
+    leases.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, lease_mac, ip, ..] if lease_mac.eq_ignore_ascii_case(mac) => Some(ip.to_string()),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_network_config() {
+        let static_config = network_config("52:54:00:aa:bb:cc", "192.168.7", false);
+        assert!(static_config.contains("addresses: [192.168.7.2/24]"));
+        assert!(static_config.contains("gateway4: 192.168.7.1"));
+        assert!(!static_config.contains("dhcp4"));
+
+        let dhcp_config = network_config("52:54:00:aa:bb:cc", "192.168.7", true);
+        assert!(dhcp_config.contains("macaddress: 52:54:00:aa:bb:cc"));
+        assert!(dhcp_config.contains("dhcp4: true"));
+        assert!(!dhcp_config.contains("addresses"));
+    }
+
+    #[test]
+    fn test_start_commands() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(
+            start_commands(dir.path(), Some("meda-abc"), "tap0", "192.168.7", "m"),
+            ""
+        );
+        enable(dir.path()).unwrap();
+        let script = start_commands(
+            dir.path(),
+            Some("meda-abc"),
+            "tap0",
+            "192.168.7",
+            "52:54:00:aa:bb:cc",
+        );
+        assert!(script.contains("ip netns exec meda-abc dnsmasq"));
+        assert!(script.contains("--interface=tap0"));
+        assert!(script.contains("--dhcp-host=52:54:00:aa:bb:cc,192.168.7.2"));
+        assert!(!script.contains('\''));
+        assert!(!start_commands(dir.path(), None, "tap0", "192.168.7", "m").contains("netns"));
+    }
+
+    #[test]
+    fn test_lease_ip() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("mac"), "52:54:00:AA:BB:CC\n").unwrap();
+        assert_eq!(lease_ip(dir.path()), None);
+        fs::write(
+            dir.path().join(LEASE_FILE),
+            "1760000000 52:54:00:11:22:33 192.168.7.9 other *\n\
+             1760000000 52:54:00:aa:bb:cc 192.168.7.2 ubuntu 01:52:54:00:aa:bb:cc\n",
+        )
+        .unwrap();
+        assert_eq!(lease_ip(dir.path()).as_deref(), Some("192.168.7.2"));
+    }
+}
@@ -0,0 +1,262 @@
+//! Where meda's disk space goes, for `meda du`.
+//!
+//! Reports the assets dir (base images, firmware, binaries), every
+//! cached image tag and every VM with two sizes: `used`, the blocks the
+//! files take on the host (what `du` says), and `size`, what they would
+//! take fully written — apparent file sizes, with qcow2 disks at their
+//! virtual size from `qemu-img info`. The gap is what sparse and
+//! copy-on-write disks can still grow into.
+//!
+//! Like `du`, a file hard-linked from several places (image tags share
+//! layers that way) is counted where it is seen first.
+
+use crate::config::Config;
+use crate::error::Result;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Assets,
+    Image,
+    Vm,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Assets => "assets",
+            Self::Image => "image",
+            Self::Vm => "vm",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SortKey {
+    /// Most space used first
+    #[default]
+    Used,
+    /// Largest fully-written size first
+    Size,
+    Name,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Usage {
+    pub kind: Kind,
+    pub name: String,
+    pub path: PathBuf,
+    /// Bytes allocated on the host.
+    pub used_bytes: u64,
+    /// Bytes once every file is fully written.
+    pub size_bytes: u64,
+}
+
+/// Files already counted, by device and inode.
+type Seen = HashSet<(u64, u64)>;
+
+/// Add up everything under `dir` not yet in `seen`, skipping `skip`.
+/// Symlinks are not followed.
+fn measure(dir: &Path, skip: &[&Path], seen: &mut Seen) -> (u64, u64) {
+    let mut used = 0;
+    let mut size = 0;
+    let Ok(entries) = fs::read_dir(dir) else {
+        return (0, 0);
+    };
+    for path in entries.flatten().map(|e| e.path()) {
+        if skip.contains(&path.as_path()) {
+            continue;
+        }
+        let Ok(meta) = fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if meta.is_dir() {
+            let (u, s) = measure(&path, skip, seen);
+            used += u;
+            size += s;
+        } else if meta.is_file() && seen.insert((meta.dev(), meta.ino())) {
+            used += meta.blocks() * 512;
+            size += match path.extension().and_then(|e| e.to_str()) {
+                Some("qcow2") => crate::util::disk_virtual_size(&path).unwrap_or(meta.len()),
+                _ => meta.len(),
+            };
+        }
+    }
+    (used, size)
+}
+
+fn usage(kind: Kind, name: String, path: PathBuf, skip: &[&Path], seen: &mut Seen) -> Usage {
+    let (used_bytes, size_bytes) = measure(&path, skip, seen);
+    Usage {
+        kind,
+        name,
+        path,
+        used_bytes,
+        size_bytes,
+    }
+}
+
+/// Image tag dirs under `images`: `<registry>/<org>/<name>/<tag>`,
+/// named as `meda images` shows them.
+fn image_dirs(images: &Path) -> Vec<(String, PathBuf)> {
+    let subdirs = |dir: &Path| -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect();
+        dirs.sort();
+        dirs
+    };
+    let file_name = |p: &Path| {
+        p.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string()
+    };
+    let mut tags = Vec::new();
+    for registry in subdirs(images) {
+        for org in subdirs(&registry) {
+            for name in subdirs(&org) {
+                for tag in subdirs(&name) {
+                    tags.push((
+                        format!(
+                            "{}/{}/{}:{}",
+                            file_name(&registry).replace('_', "."),
+                            file_name(&org),
+                            file_name(&name),
+                            file_name(&tag)
+                        ),
+                        tag,
+                    ));
+                }
+            }
+        }
+    }
+    tags
+}
+
+/// Usage of the assets dir, each cached image and each VM.
+pub fn collect(config: &Config) -> Result<Vec<Usage>> {
+    let images = config.asset_dir.join("images");
+    let mut seen = Seen::new();
+    let mut all = Vec::new();
+
+    // VMs first: a VM's disk hard-linked from an image counts as the VM's.
+    for vm in crate::vm::collect_vms_with_templates(config)? {
+        let path = config.vm_dir(&vm.name);
+        all.push(usage(Kind::Vm, vm.name, path, &[], &mut seen));
+    }
+    for (name, path) in image_dirs(&images) {
+        all.push(usage(Kind::Image, name, path, &[], &mut seen));
+    }
+    // The VM and image dirs may live inside the assets dir.
+    all.push(usage(
+        Kind::Assets,
+        "assets".to_string(),
+        config.asset_dir.clone(),
+        &[&images, &config.vm_root],
+        &mut seen,
+    ));
+    Ok(all)
+}
+
+pub fn sort(usage: &mut [Usage], key: SortKey) {
+    match key {
+        SortKey::Used => usage.sort_by(|a, b| b.used_bytes.cmp(&a.used_bytes)),
+        SortKey::Size => usage.sort_by(|a, b| b.size_bytes.cmp(&a.size_bytes)),
+        SortKey::Name => usage.sort_by(|a, b| a.name.cmp(&b.name)),
+    }
+}
+
+pub fn du_command(config: &Config, sort_by: SortKey, json: bool) -> Result<()> {
+    let mut usage = collect(config)?;
+    sort(&mut usage, sort_by);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&usage)?);
+        return Ok(());
+    }
+    let human = |bytes: u64| crate::stats::human_bytes(Some(bytes));
+    println!("{:<7} {:<40} {:>10} {:>10}", "KIND", "NAME", "USED", "SIZE");
+    for u in &usage {
+        println!(
+            "{:<7} {:<40} {:>10} {:>10}",
+            u.kind.to_string(),
+            u.name,
+            human(u.used_bytes),
+            human(u.size_bytes)
+        );
+    }
+    println!(
+        "{:<7} {:<40} {:>10} {:>10}",
+        "",
+        "TOTAL",
+        human(usage.iter().map(|u| u.used_bytes).sum()),
+        human(usage.iter().map(|u| u.size_bytes).sum())
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_measure() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::create_dir_all(root.join("skipped")).unwrap();
+        fs::write(root.join("a/one"), vec![1u8; 10_000]).unwrap();
+        fs::write(root.join("a/b/two"), vec![1u8; 5]).unwrap();
+        fs::write(root.join("skipped/three"), vec![1u8; 7]).unwrap();
+        fs::hard_link(root.join("a/one"), root.join("link")).unwrap();
+        // Sparse: a size but (almost) no blocks.
+        fs::File::create(root.join("sparse.raw"))
+            .unwrap()
+            .set_len(1 << 30)
+            .unwrap();
+
+        let mut seen = Seen::new();
+        let (used, size) = measure(root, &[&root.join("skipped")], &mut seen);
+        assert_eq!(size, 10_000 + 5 + (1 << 30));
+        assert!((10_000..1 << 30).contains(&used));
+
+        // Everything was seen, so only the skipped dir is left to count.
+        assert_eq!(measure(root, &[], &mut seen).1, 7);
+    }
+
+    #[test]
+    fn test_image_dirs_and_sort() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("ghcr_io/cirunlabs/ubuntu/22.04")).unwrap();
+        let tags = image_dirs(dir.path());
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].0, "ghcr.io/cirunlabs/ubuntu:22.04");
+
+        let entry = |name: &str, used, size| Usage {
+            kind: Kind::Vm,
+            name: name.to_string(),
+            path: PathBuf::new(),
+            used_bytes: used,
+            size_bytes: size,
+        };
+        let mut usage = vec![entry("a", 1, 30), entry("b", 20, 20), entry("c", 3, 3)];
+        sort(&mut usage, SortKey::Used);
+        assert_eq!(usage[0].name, "b");
+        sort(&mut usage, SortKey::Size);
+        assert_eq!(usage[0].name, "a");
+        sort(&mut usage, SortKey::Name);
+        assert_eq!(usage[2].name, "c");
+    }
+}
@@ -0,0 +1,370 @@
+//! Extra data disks: `meda disk add/list/remove`.
+//!
+//! Each disk is a file in the VM's `disks/` dir, `<name>.raw` (sparse)
+//! or `<name>.qcow2`. The file is the whole record: the launch spec's
+//! disks are rewritten from the dir whenever a disk comes or goes, so
+//! disks persist across restarts. On a running VM they are
+//! also hotplugged with `ch-remote add-disk` / `remove-device`.
+//!
+//! The guest sees each disk as `/dev/disk/by-id/virtio-<name>`. Unlike
+//! `--data-disk` nothing formats or mounts it; that's up to the guest.
+
+use crate::config::{Config, DiskFormat};
+use crate::error::{Error, Result};
+use crate::launch::LaunchSpec;
+use crate::util::parse_size_bytes;
+use crate::vm::VmResult;
+use log::{info, warn};
+use serde::Serialize;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+pub const DISKS_DIR: &str = "disks";
+
+/// virtio-blk serials are at most 20 bytes, and the name is the serial.
+const MAX_NAME_LEN: usize = 20;
+
+#[derive(Debug, Serialize)]
+pub struct Disk {
+    pub name: String,
+    pub format: &'static str,
+    /// Virtual size in bytes, when known.
+    pub size: Option<u64>,
+    pub path: PathBuf,
+    #[serde(skip)]
+    disk_format: DiskFormat,
+}
+
+impl Disk {
+    /// Cloud Hypervisor `--disk` / `add-disk` value.
+    fn ch_arg(&self, quote: bool) -> String {
+        let q = if quote { "\"" } else { "" };
+        format!(
+            "path={q}{}{q},image_type={},serial={},id={}",
+            self.path.display(),
+            self.disk_format.as_str(),
+            self.name,
+            device_id(&self.name)
+        )
+    }
+}
+
+fn device_id(name: &str) -> String {
+    format!("meda-{}", name)
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::Other(format!(
+            "Invalid disk name '{}' (letters, digits, - and _, at most {} characters)",
+            name, MAX_NAME_LEN
+        )))
+    }
+}
+
+/// Extra disks of the VM in `vm_dir`, by name.
+pub fn list(vm_dir: &Path) -> Vec<Disk> {
+    let mut disks: Vec<Disk> = fs::read_dir(vm_dir.join(DISKS_DIR))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            let name = path.file_stem()?.to_str()?.to_string();
+            let disk_format = DiskFormat::parse(path.extension()?.to_str()?)?;
+            let size = match disk_format {
+                DiskFormat::Raw => fs::metadata(&path).ok().map(|m| m.len()),
+                DiskFormat::Qcow2 => crate::util::disk_virtual_size(&path),
+            };
+            Some(Disk {
+                name,
+                format: disk_format.as_str(),
+                size,
+                path,
+                disk_format,
+            })
+        })
+        .collect();
+    disks.sort_by(|a, b| a.name.cmp(&b.name));
+    disks
+}
+
+/// Extra `--disk` values for the launch spec.
+pub fn disk_values(vm_dir: &Path) -> Vec<String> {
+    list(vm_dir).iter().map(|disk| disk.ch_arg(false)).collect()
+}
+
+/// Extra `--disk` values for a legacy start script (leading space included).
+fn disk_args(vm_dir: &Path) -> String {
+    let mut args = String::new();
+    for disk in list(vm_dir) {
+        args.push(' ');
+        args.push_str(&disk.ch_arg(true));
+    }
+    args
+}
+
+/// Replace the extra disks on the `--disk` line of start script `body`
+/// with the ones now in `vm_dir`.
+fn rewrite_disk_line(body: &str, vm_dir: &Path) -> String {
+    let marker = format!(" path=\"{}/", vm_dir.join(DISKS_DIR).display());
+    let args = disk_args(vm_dir);
+    let mut out = String::with_capacity(body.len() + args.len());
+    for line in body.lines() {
+        if line.trim_start().starts_with("--disk ") {
+            let line = line.strip_suffix(" \\").unwrap_or(line);
+            let line = line.find(&marker).map_or(line, |at| &line[..at]);
+            out.push_str(line);
+            out.push_str(&args);
+            out.push_str(" \\");
+        } else {
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// `--disk` values of a launch spec with the extra disks replaced by
+/// the ones now in `vm_dir`.
+fn with_extra_disks(disks: &[String], vm_dir: &Path) -> Vec<String> {
+    let prefix = format!("path={}/", vm_dir.join(DISKS_DIR).display());
+    disks
+        .iter()
+        .filter(|d| !d.starts_with(&prefix))
+        .cloned()
+        .chain(disk_values(vm_dir))
+        .collect()
+}
+
+fn update_launch(vm_dir: &Path) -> Result<()> {
+    if let Some(mut spec) = LaunchSpec::load(vm_dir) {
+        spec.disks = with_extra_disks(&spec.disks, vm_dir);
+        return spec.save(vm_dir);
+    }
+    let path = vm_dir.join(crate::launch::LEGACY_START_SCRIPT);
+    if path.exists() {
+        let body = fs::read_to_string(&path)?;
+        fs::write(&path, rewrite_disk_line(&body, vm_dir))?;
+    }
+    Ok(())
+}
+
+async fn ch_remote(config: &Config, vm_dir: &Path, args: &[&str]) -> Result<()> {
+    let sock = vm_dir.join("api.sock");
+    let mut all = vec!["--api-socket", sock.to_str().unwrap()];
+    all.extend(args);
+    crate::util::run_command(&config.cr_bin.to_string_lossy(), &all).await
+}
+
+fn report(message: String, json: bool) -> Result<()> {
+    if json {
+        let result = VmResult {
+            success: true,
+            message,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!("{}", message);
+    }
+    Ok(())
+}
+
+/// Create a `size` disk for VM `vm` and attach it, live if the VM is
+/// running. Without a name the disk is `data1`, `data2`, ...
+pub async fn add(
+    config: &Config,
+    vm: &str,
+    size: &str,
+    name: Option<&str>,
+    format: DiskFormat,
+    json: bool,
+) -> Result<()> {
+    let _lock = crate::vm::lock(config, vm, "disk add")?;
+    let vm_dir = config.vm_dir(vm);
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(vm.to_string()));
+    }
+    let bytes = parse_size_bytes(size)
+        .filter(|&b| b > 0)
+        .ok_or_else(|| Error::Other(format!("Invalid disk size '{}' (expected e.g. 20G)", size)))?;
+    let existing = list(&vm_dir);
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => (1..)
+            .map(|n| format!("data{}", n))
+            .find(|n| existing.iter().all(|d| &d.name != n))
+            .unwrap(),
+    };
+    validate_name(&name)?;
+    if existing.iter().any(|d| d.name == name) {
+        return Err(Error::Other(format!(
+            "VM {} already has a disk named {}",
+            vm, name
+        )));
+    }
+
+    let dir = vm_dir.join(DISKS_DIR);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("{}.{}", name, format.as_str()));
+    match format {
+        // Sparse: blocks are only allocated as the guest writes them.
+        DiskFormat::Raw => File::create(&path)?.set_len(bytes)?,
+        DiskFormat::Qcow2 => {
+            crate::util::run_command(
+                "qemu-img",
+                &["create", "-q", "-f", "qcow2", path.to_str().unwrap(), size],
+            )
+            .await?
+        }
+    }
+    update_launch(&vm_dir)?;
+
+    let disk = Disk {
+        name: name.clone(),
+        format: format.as_str(),
+        size: Some(bytes),
+        path,
+        disk_format: format,
+    };
+    let when = if crate::vm::check_vm_running(config, vm)? {
+        match ch_remote(config, &vm_dir, &["add-disk", &disk.ch_arg(false)]).await {
+            Ok(()) => "attached live",
+            Err(e) => {
+                warn!("hotplug of disk {} into {} failed: {}", name, vm, e);
+                "attached on next start (hotplug failed)"
+            }
+        }
+    } else {
+        "attached on next start"
+    };
+    report(
+        format!(
+            "Added {} disk {} to VM {} as /dev/disk/by-id/virtio-{}: {}",
+            size, name, vm, name, when
+        ),
+        json,
+    )
+}
+
+/// Detach (live if running) and delete disk `name` of VM `vm`.
+pub async fn remove(config: &Config, vm: &str, name: &str, json: bool) -> Result<()> {
+    let _lock = crate::vm::lock(config, vm, "disk remove")?;
+    let vm_dir = config.vm_dir(vm);
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(vm.to_string()));
+    }
+    let disk = list(&vm_dir)
+        .into_iter()
+        .find(|d| d.name == name)
+        .ok_or_else(|| Error::Other(format!("VM {} has no disk named {}", vm, name)))?;
+
+    if crate::vm::check_vm_running(config, vm)? {
+        ch_remote(config, &vm_dir, &["remove-device", &device_id(name)])
+            .await
+            .map_err(|e| {
+                Error::Other(format!(
+                    "Could not detach disk {} from running VM {} ({}); stop the VM and retry",
+                    name, vm, e
+                ))
+            })?;
+    }
+    fs::remove_file(&disk.path)?;
+    update_launch(&vm_dir)?;
+    report(format!("Removed disk {} from VM {}", name, vm), json)
+}
+
+pub fn list_command(config: &Config, vm: &str, json: bool) -> Result<()> {
+    let vm_dir = config.vm_dir(vm);
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(vm.to_string()));
+    }
+    let disks = list(&vm_dir);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&disks)?);
+        return Ok(());
+    }
+    if disks.is_empty() {
+        println!("VM {} has no extra disks", vm);
+        return Ok(());
+    }
+    println!("{:<20} {:<8} {:>10}  PATH", "NAME", "FORMAT", "SIZE");
+    for disk in &disks {
+        let size = disk.size.map_or("-".to_string(), |b| {
+            format!("{:.1}G", b as f64 / (1u64 << 30) as f64)
+        });
+        println!(
+            "{:<20} {:<8} {:>10}  {}",
+            disk.name,
+            disk.format,
+            size,
+            disk.path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("data1").is_ok());
+        assert!(validate_name("cache_ci-2").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("a/b").is_err());
+        assert!(validate_name("x,serial=y").is_err());
+        assert!(validate_name(&"a".repeat(21)).is_err());
+    }
+
+    #[test]
+    fn test_rewrite_disk_line() {
+        let dir = TempDir::new().unwrap();
+        let vm_dir = dir.path();
+        let script = format!(
+            "#!/bin/bash\n  ch \\\n    --disk path=/r.raw,image_type=raw path=\"{0}/ci.iso\" \\\n    --net tap=t0 \\\n",
+            vm_dir.display()
+        );
+
+        fs::create_dir_all(vm_dir.join(DISKS_DIR)).unwrap();
+        File::create(vm_dir.join("disks/data1.raw"))
+            .unwrap()
+            .set_len(1024)
+            .unwrap();
+        let with_disk = rewrite_disk_line(&script, vm_dir);
+        assert!(with_disk.contains(&format!(
+            "ci.iso\" path=\"{}/disks/data1.raw\",image_type=raw,serial=data1,id=meda-data1 \\\n",
+            vm_dir.display()
+        )));
+        // Rewriting is idempotent.
+        assert_eq!(rewrite_disk_line(&with_disk, vm_dir), with_disk);
+
+        let disks = list(vm_dir);
+        assert_eq!(disks.len(), 1);
+        assert_eq!(disks[0].size, Some(1024));
+
+        let spec_disks = vec!["path=/r.raw,image_type=raw".to_string()];
+        let with_spec_disk = with_extra_disks(&spec_disks, vm_dir);
+        assert_eq!(
+            with_spec_disk[1],
+            format!(
+                "path={}/disks/data1.raw,image_type=raw,serial=data1,id=meda-data1",
+                vm_dir.display()
+            )
+        );
+        assert_eq!(with_extra_disks(&with_spec_disk, vm_dir), with_spec_disk);
+
+        fs::remove_file(vm_dir.join("disks/data1.raw")).unwrap();
+        assert_eq!(rewrite_disk_line(&with_disk, vm_dir), script);
+        assert_eq!(with_extra_disks(&with_spec_disk, vm_dir), spec_disks);
+    }
+}
@@ -0,0 +1,135 @@
+//! `meda doctor`: check that this host can run its VMs the way they are
+//! configured, and say what to change when it can't. The host checks
+//! (KVM, binaries, free memory and disk) live in `preflight`, which
+//! also runs them before create/start.
+//!
+//! Each check yields findings: `ok`, `warn` (works, but not as well as
+//! it could) or `fail` (a VM won't start). Any failure makes the command
+//! exit non-zero so it can gate CI runner setup.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::memory_backing::{self, MemoryBacking};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub check: &'static str,
+    pub status: Status,
+    pub message: String,
+}
+
+impl Finding {
+    pub fn new(check: &'static str, status: Status, message: impl Into<String>) -> Self {
+        Self {
+            check,
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+/// THP mode, the hugepage pool, and whether each VM's memory backing
+/// can be honored.
+fn memory_findings(config: &Config) -> Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    findings.push(match memory_backing::thp_mode().as_deref() {
+        Some("never") => Finding::new(
+            "thp",
+            Status::Warn,
+            "transparent hugepages are disabled, so guest memory uses 4 KiB pages; \
+             `echo madvise | sudo tee /sys/kernel/mm/transparent_hugepage/enabled` lets cloud-hypervisor use them",
+        ),
+        Some(mode) => Finding::new("thp", Status::Ok, format!("transparent hugepages: {}", mode)),
+        None => Finding::new(
+            "thp",
+            Status::Warn,
+            "the kernel has no transparent hugepage support",
+        ),
+    });
+
+    let pool = memory_backing::host_hugepages();
+    if let Some(pool) = &pool {
+        findings.push(Finding::new(
+            "hugepages",
+            Status::Ok,
+            format!(
+                "{} of {} {} KiB hugepages free",
+                pool.free,
+                pool.total,
+                pool.page_bytes / 1024
+            ),
+        ));
+    }
+
+    for vm in crate::vm::collect_vms(config)? {
+        let backing = MemoryBacking::load(&config.vm_dir(&vm.name));
+        if backing.is_default() {
+            continue;
+        }
+        if vm.state == "running" {
+            findings.push(Finding::new(
+                "memory-backing",
+                Status::Ok,
+                format!(
+                    "{}: running with {} memory",
+                    vm.name,
+                    backing.options().join(", ")
+                ),
+            ));
+            continue;
+        }
+        let memory_bytes = crate::util::parse_size_bytes(&vm.memory).unwrap_or(0);
+        let problems = backing.problems(memory_bytes, pool.as_ref());
+        findings.push(if problems.is_empty() {
+            Finding::new(
+                "memory-backing",
+                Status::Ok,
+                format!("{}: host can back {} as configured", vm.name, vm.memory),
+            )
+        } else {
+            Finding::new(
+                "memory-backing",
+                Status::Fail,
+                format!("{}: {}", vm.name, problems.join("; ")),
+            )
+        });
+    }
+    Ok(findings)
+}
+
+pub fn doctor_command(config: &Config, json: bool) -> Result<()> {
+    let mut findings = crate::preflight::host_findings(config);
+    findings.extend(memory_findings(config)?);
+    findings.extend(crate::rng::guest_findings(config)?);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&findings)?);
+    } else {
+        for finding in &findings {
+            let mark = match finding.status {
+                Status::Ok => "ok  ",
+                Status::Warn => "warn",
+                Status::Fail => "FAIL",
+            };
+            println!("[{}] {:<15} {}", mark, finding.check, finding.message);
+        }
+    }
+
+    let failed = findings.iter().filter(|f| f.status == Status::Fail).count();
+    if failed > 0 {
+        return Err(Error::Other(format!(
+            "meda doctor found {} problem(s)",
+            failed
+        )));
+    }
+    Ok(())
+}
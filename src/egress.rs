@@ -0,0 +1,269 @@
+//! Per-VM egress policy: which destinations a VM may open connections to.
+//!
+//! A policy is an ordered list of `allow:<dest>` / `deny:<dest>` entries,
+//! where `<dest>` is an IPv4 address, a CIDR block or `all`; the first
+//! entry matching a new outbound connection decides, and anything no
+//! entry matches is allowed:
+//!
+//! ```text
+//! meda run ubuntu --egress allow:10.0.5.0/24,deny:10.0.0.0/8,deny:192.168.0.0/16
+//! meda run ubuntu --egress allow:10.0.0.53,deny:all
+//! ```
+//!
+//! Policies used by many VMs can be named in `egress-policies` in the
+//! config dir, one `name = spec` per line, and passed as `--egress name`.
+//!
+//! The policy becomes a host iptables chain `meda-eg-<link>` jumped to
+//! from `FORWARD` for traffic leaving the VM's tap or netns veth, and
+//! from `INPUT` for traffic it sends the host itself, whose addresses
+//! would otherwise be reachable past a `deny:all`. Allowed traffic
+//! returns, so `--isolate` drops still apply; denied traffic is
+//! rejected. Replies on established connections (port forwards into the
+//! VM) and DHCP are never blocked.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+/// Policy a VM was created with, in `EgressPolicy::parse` syntax.
+pub const EGRESS_FILE: &str = "egress";
+
+/// Named policies, in the config dir.
+const POLICIES_FILE: &str = "egress-policies";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EgressAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EgressRule {
+    pub action: EgressAction,
+    /// Normalized CIDR (`10.0.0.0/8`, `1.2.3.4/32`); `None` for `all`.
+    pub dest: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EgressPolicy {
+    pub rules: Vec<EgressRule>,
+}
+
+impl EgressPolicy {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = |why: String| Error::Other(format!("Invalid egress policy '{spec}': {why}"));
+        let mut rules = Vec::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (action, dest) = entry
+                .split_once(':')
+                .ok_or_else(|| invalid(format!("'{entry}' is not allow:<dest> or deny:<dest>")))?;
+            let action = match action.trim() {
+                "allow" => EgressAction::Allow,
+                "deny" => EgressAction::Deny,
+                other => return Err(invalid(format!("unknown action '{other}'"))),
+            };
+            let dest = match dest.trim() {
+                "all" => None,
+                cidr => Some(normalize_cidr(cidr).ok_or_else(|| {
+                    invalid(format!("'{cidr}' is not an IPv4 address, CIDR or 'all'"))
+                })?),
+            };
+            rules.push(EgressRule { action, dest });
+        }
+        if rules.is_empty() {
+            return Err(invalid("no rules".to_string()));
+        }
+        Ok(Self { rules })
+    }
+
+    /// `--egress` value: an inline spec, or the name of a policy in
+    /// `egress-policies`.
+    pub fn resolve(config: &Config, value: &str) -> Result<Self> {
+        if value.contains(':') {
+            return Self::parse(value);
+        }
+        let path = config.ch_home.join(POLICIES_FILE);
+        let body = fs::read_to_string(&path).unwrap_or_default();
+        match named_policy(&body, value.trim()) {
+            Some(spec) => Self::parse(spec),
+            None => Err(Error::Other(format!(
+                "No egress policy named '{}' in {}",
+                value,
+                path.display()
+            ))),
+        }
+    }
+
+    pub fn to_spec(&self) -> String {
+        self.rules
+            .iter()
+            .map(|r| {
+                let action = match r.action {
+                    EgressAction::Allow => "allow",
+                    EgressAction::Deny => "deny",
+                };
+                format!("{}:{}", action, r.dest.as_deref().unwrap_or("all"))
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    pub fn load(vm_dir: &Path) -> Option<Self> {
+        let spec = fs::read_to_string(vm_dir.join(EGRESS_FILE)).ok()?;
+        Self::parse(spec.trim()).ok()
+    }
+
+    pub fn save(&self, vm_dir: &Path) -> Result<()> {
+        fs::write(vm_dir.join(EGRESS_FILE), self.to_spec())?;
+        Ok(())
+    }
+
+    /// Rules of the VM's chain, as `iptables -S` prints them.
+    pub fn chain_rules(&self, link: &str) -> Vec<String> {
+        let chain = chain_name(link);
+        let mut rules = vec![
+            format!("-A {chain} -m conntrack --ctstate RELATED,ESTABLISHED -j RETURN"),
+            format!("-A {chain} -p udp -m udp --dport 67 -j RETURN"),
+        ];
+        for rule in &self.rules {
+            let dest = rule
+                .dest
+                .as_deref()
+                .map(|d| format!(" -d {d}"))
+                .unwrap_or_default();
+            let target = match rule.action {
+                EgressAction::Allow => "RETURN",
+                EgressAction::Deny => "REJECT --reject-with icmp-net-prohibited",
+            };
+            rules.push(format!("-A {chain}{dest} -j {target}"));
+        }
+        rules
+    }
+
+    /// Shell lines (re)building the chain and hooking it into FORWARD
+    /// and INPUT ahead of the VM's ACCEPT rules.
+    pub fn script(&self, link: &str) -> String {
+        let chain = chain_name(link);
+        let mut script = format!("iptables -w -N {chain} 2>/dev/null || iptables -w -F {chain}\n");
+        for rule in self.chain_rules(link) {
+            script.push_str(&format!("iptables -w {rule}\n"));
+        }
+        for jump in jump_rules(link) {
+            script.push_str(&format!(
+                "iptables -w -C {jump} 2>/dev/null || iptables -w -I {jump}\n"
+            ));
+        }
+        script
+    }
+}
+
+/// Chain holding the policy of the VM behind `link`; at most 23 chars,
+/// within iptables' 28.
+pub fn chain_name(link: &str) -> String {
+    format!("meda-eg-{link}")
+}
+
+/// The rules sending the VM's outbound traffic through its chain: what
+/// the host routes for it, and what it sends the host.
+pub fn jump_rules(link: &str) -> [String; 2] {
+    let chain = chain_name(link);
+    [
+        format!("FORWARD -i {link} -j {chain}"),
+        format!("INPUT -i {link} -j {chain}"),
+    ]
+}
+
+/// Shell lines removing a VM's chain, whether or not it has one.
+pub fn removal_script(link: &str) -> String {
+    let chain = chain_name(link);
+    let mut script = String::new();
+    for jump in jump_rules(link) {
+        script.push_str(&format!("iptables -w -D {jump} 2>/dev/null || true\n"));
+    }
+    script.push_str(&format!(
+        "iptables -w -F {chain} 2>/dev/null || true\n\
+         iptables -w -X {chain} 2>/dev/null || true\n"
+    ));
+    script
+}
+
+/// `a.b.c.d[/n]` with host bits cleared, as iptables prints it back.
+fn normalize_cidr(s: &str) -> Option<String> {
+    let (addr, prefix) = match s.split_once('/') {
+        Some((addr, prefix)) => (addr, prefix.parse::<u32>().ok().filter(|p| *p <= 32)?),
+        None => (s, 32),
+    };
+    let addr: Ipv4Addr = addr.parse().ok()?;
+    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+    Some(format!(
+        "{}/{}",
+        Ipv4Addr::from(u32::from(addr) & mask),
+        prefix
+    ))
+}
+
+fn named_policy<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    body.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| l.split_once('='))
+        .find(|(n, _)| n.trim() == name)
+        .map(|(_, spec)| spec.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let policy = EgressPolicy::parse("allow:10.1.2.3/8, deny:192.168.1.7,deny:all").unwrap();
+        assert_eq!(
+            policy.to_spec(),
+            "allow:10.0.0.0/8,deny:192.168.1.7/32,deny:all"
+        );
+        assert_eq!(EgressPolicy::parse(&policy.to_spec()).unwrap(), policy);
+
+        assert!(EgressPolicy::parse("").is_err());
+        assert!(EgressPolicy::parse("block:all").is_err());
+        assert!(EgressPolicy::parse("deny:10.0.0.0/33").is_err());
+        assert!(EgressPolicy::parse("deny:example.com").is_err());
+        assert!(EgressPolicy::parse("10.0.0.0/8").is_err());
+        assert_eq!(normalize_cidr("0.0.0.0/0").as_deref(), Some("0.0.0.0/0"));
+    }
+
+    #[test]
+    fn test_chain_rules() {
+        let policy = EgressPolicy::parse("allow:10.0.5.0/24,deny:10.0.0.0/8").unwrap();
+        assert_eq!(
+            policy.chain_rules("vmh-abc123"),
+            vec![
+                "-A meda-eg-vmh-abc123 -m conntrack --ctstate RELATED,ESTABLISHED -j RETURN",
+                "-A meda-eg-vmh-abc123 -p udp -m udp --dport 67 -j RETURN",
+                "-A meda-eg-vmh-abc123 -d 10.0.5.0/24 -j RETURN",
+                "-A meda-eg-vmh-abc123 -d 10.0.0.0/8 -j REJECT --reject-with icmp-net-prohibited",
+            ]
+        );
+        let script = policy.script("vmh-abc123");
+        assert!(script.contains("iptables -w -I FORWARD -i vmh-abc123 -j meda-eg-vmh-abc123\n"));
+        // Traffic to the host's own addresses goes through the policy too.
+        assert!(script.ends_with("iptables -w -I INPUT -i vmh-abc123 -j meda-eg-vmh-abc123\n"));
+        let removal = removal_script("vmh-abc123");
+        assert!(removal.contains("-D FORWARD -i vmh-abc123 -j meda-eg-vmh-abc123"));
+        assert!(removal.contains("-D INPUT -i vmh-abc123 -j meda-eg-vmh-abc123"));
+        assert!(chain_name("vmh-abc123").len() <= 28);
+    }
+
+    #[test]
+    fn test_named_policy() {
+        let body = "# internal networks\ninternal-only = deny:10.0.0.0/8,deny:172.16.0.0/12\n\nlocked=deny:all\n";
+        assert_eq!(
+            named_policy(body, "internal-only"),
+            Some("deny:10.0.0.0/8,deny:172.16.0.0/12")
+        );
+        assert_eq!(named_policy(body, "locked"), Some("deny:all"));
+        assert_eq!(named_policy(body, "missing"), None);
+    }
+}
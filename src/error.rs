@@ -1,3 +1,6 @@
+use crate::api::models::ApiError;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Json, Response};
 use std::io;
 use thiserror::Error;
 
@@ -17,6 +20,9 @@ pub enum Error {
     #[error("VM {0} is already running")]
     VmAlreadyRunning(String),
 
+    #[error("VM {0} is locked by another operation: {1}")]
+    VmLocked(String, String),
+
     #[error("VM {0} is not running")]
     VmNotRunning(String),
 
@@ -47,6 +53,184 @@ pub enum Error {
     #[error("Image not found: {0}")]
     ImageNotFound(String),
 
+    #[error("Image {0} already exists; remove it with `meda rmi` first")]
+    ImageAlreadyExists(String),
+
+    #[error("Image {0} is in use: {1}")]
+    ImageInUse(String, String),
+
+    #[error("Corrupt artifact: {0}")]
+    CorruptArtifact(String),
+
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
     #[error("{0}")]
     Other(String),
 }
+
+impl Error {
+    /// HTTP status the REST API answers with for this error.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::VmNotFound(_) | Error::ImageNotFound(_) => StatusCode::NOT_FOUND,
+            Error::VmAlreadyExists(_)
+            | Error::VmAlreadyRunning(_)
+            | Error::VmLocked(..)
+            | Error::VmNotRunning(_)
+            | Error::ImageAlreadyExists(_)
+            | Error::ImageInUse(..)
+            | Error::QuotaExceeded(_) => StatusCode::CONFLICT,
+            Error::InvalidImageName(_) => StatusCode::BAD_REQUEST,
+            Error::DownloadFailed(..) | Error::Http(_) | Error::CorruptArtifact(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            Error::Io(_)
+            | Error::CommandFailed(_)
+            | Error::NetworkConfigMissing(_)
+            | Error::HomeDirNotFound
+            | Error::JsonParseFailed(_)
+            | Error::DependencyNotFound(_)
+            | Error::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine-readable `ApiError.code` for this error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Io(_) => "IO_ERROR",
+            Error::VmAlreadyExists(_) => "VM_ALREADY_EXISTS",
+            Error::VmNotFound(_) => "VM_NOT_FOUND",
+            Error::VmAlreadyRunning(_) => "VM_ALREADY_RUNNING",
+            Error::VmLocked(..) => "VM_LOCKED",
+            Error::VmNotRunning(_) => "VM_NOT_RUNNING",
+            Error::DownloadFailed(..) => "DOWNLOAD_FAILED",
+            Error::CommandFailed(_) => "COMMAND_FAILED",
+            Error::NetworkConfigMissing(_) => "NETWORK_CONFIG_MISSING",
+            Error::HomeDirNotFound => "HOME_DIR_NOT_FOUND",
+            Error::JsonParseFailed(_) => "JSON_PARSE_FAILED",
+            Error::DependencyNotFound(_) => "DEPENDENCY_NOT_FOUND",
+            Error::Http(_) => "HTTP_ERROR",
+            Error::InvalidImageName(_) => "INVALID_IMAGE_NAME",
+            Error::ImageNotFound(_) => "IMAGE_NOT_FOUND",
+            Error::ImageAlreadyExists(_) => "IMAGE_ALREADY_EXISTS",
+            Error::ImageInUse(..) => "IMAGE_IN_USE",
+            Error::CorruptArtifact(_) => "CORRUPT_ARTIFACT",
+            Error::QuotaExceeded(_) => "QUOTA_EXCEEDED",
+            Error::Other(_) => "INTERNAL_ERROR",
+        }
+    }
+
+    /// API error for a failed handler: `error` says what failed, the
+    /// status comes from the variant, and `code` is the variant's code
+    /// unless this is a plain internal error, which keeps the handler's
+    /// own `fallback_code` (e.g. `VM_START_ERROR`).
+    pub fn api_error(&self, error: &str, fallback_code: &str) -> (StatusCode, Json<ApiError>) {
+        let status = self.status_code();
+        let code = if status == StatusCode::INTERNAL_SERVER_ERROR {
+            fallback_code
+        } else {
+            self.code()
+        };
+        (
+            status,
+            Json(ApiError {
+                error: error.to_string(),
+                code: code.to_string(),
+                details: Some(serde_json::json!({"message": self.to_string()})),
+            }),
+        )
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        (
+            self.status_code(),
+            Json(ApiError {
+                error: self.to_string(),
+                code: self.code().to_string(),
+                details: None,
+            }),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn every_variant() -> Vec<Error> {
+        vec![
+            Error::Io(io::Error::other("disk")),
+            Error::VmAlreadyExists("vm".into()),
+            Error::VmNotFound("vm".into()),
+            Error::VmAlreadyRunning("vm".into()),
+            Error::VmLocked("vm".into(), "meda stop (pid 1)".into()),
+            Error::VmNotRunning("vm".into()),
+            Error::DownloadFailed("url".into(), "timeout".into()),
+            Error::CommandFailed("ip".into()),
+            Error::NetworkConfigMissing("vm".into()),
+            Error::HomeDirNotFound,
+            Error::JsonParseFailed(serde_json::from_str::<()>("x").unwrap_err()),
+            Error::DependencyNotFound("oras".into()),
+            Error::Http(reqwest::Client::new().get("::").build().unwrap_err()),
+            Error::InvalidImageName("x".into()),
+            Error::ImageNotFound("img".into()),
+            Error::ImageAlreadyExists("img".into()),
+            Error::ImageInUse("img".into(), "running".into()),
+            Error::CorruptArtifact("base.raw".into()),
+            Error::QuotaExceeded("MEDA_MAX_VMS".into()),
+            Error::Other("boom".into()),
+        ]
+    }
+
+    #[test]
+    fn test_status_codes() {
+        let expected = [
+            (StatusCode::INTERNAL_SERVER_ERROR, "IO_ERROR"),
+            (StatusCode::CONFLICT, "VM_ALREADY_EXISTS"),
+            (StatusCode::NOT_FOUND, "VM_NOT_FOUND"),
+            (StatusCode::CONFLICT, "VM_ALREADY_RUNNING"),
+            (StatusCode::CONFLICT, "VM_LOCKED"),
+            (StatusCode::CONFLICT, "VM_NOT_RUNNING"),
+            (StatusCode::BAD_GATEWAY, "DOWNLOAD_FAILED"),
+            (StatusCode::INTERNAL_SERVER_ERROR, "COMMAND_FAILED"),
+            (StatusCode::INTERNAL_SERVER_ERROR, "NETWORK_CONFIG_MISSING"),
+            (StatusCode::INTERNAL_SERVER_ERROR, "HOME_DIR_NOT_FOUND"),
+            (StatusCode::INTERNAL_SERVER_ERROR, "JSON_PARSE_FAILED"),
+            (StatusCode::INTERNAL_SERVER_ERROR, "DEPENDENCY_NOT_FOUND"),
+            (StatusCode::BAD_GATEWAY, "HTTP_ERROR"),
+            (StatusCode::BAD_REQUEST, "INVALID_IMAGE_NAME"),
+            (StatusCode::NOT_FOUND, "IMAGE_NOT_FOUND"),
+            (StatusCode::CONFLICT, "IMAGE_ALREADY_EXISTS"),
+            (StatusCode::CONFLICT, "IMAGE_IN_USE"),
+            (StatusCode::BAD_GATEWAY, "CORRUPT_ARTIFACT"),
+            (StatusCode::CONFLICT, "QUOTA_EXCEEDED"),
+            (StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_ERROR"),
+        ];
+        let variants = every_variant();
+        assert_eq!(variants.len(), expected.len());
+        for (e, (status, code)) in variants.into_iter().zip(expected) {
+            assert_eq!(e.status_code(), status, "{:?}", e);
+            assert_eq!(e.code(), code, "{:?}", e);
+            assert_eq!(e.into_response().status(), status);
+        }
+    }
+
+    #[test]
+    fn test_api_error_fallback_code() {
+        let (status, Json(body)) =
+            Error::VmNotFound("vm".into()).api_error("Failed to start VM", "VM_START_ERROR");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.code, "VM_NOT_FOUND");
+        assert_eq!(body.error, "Failed to start VM");
+
+        let (status, Json(body)) =
+            Error::Other("boom".into()).api_error("Failed to start VM", "VM_START_ERROR");
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body.code, "VM_START_ERROR");
+        assert_eq!(body.details.unwrap()["message"], "boom");
+    }
+}
@@ -0,0 +1,294 @@
+//! Job configuration passed into guests as environment variables
+//! (`meda run --env KEY=VALUE`, `--secret-env KEY=VALUE`).
+//!
+//! The variables go to cloud-init vendor-data `write_files`, like the
+//! data disk and virtiofs mounts, so they apply whatever user-data the
+//! VM has: `/etc/meda/env` holds `KEY='value'` lines (a shell fragment
+//! and a systemd `EnvironmentFile`), and `/etc/profile.d/meda-env.sh`
+//! exports them into login shells. `--secret-env` variables go to
+//! `/etc/meda/secret-env` instead, which only root can read, and the
+//! VM's vendor-data is then owner-only on the host like it is for
+//! `--secret` files. Cloud-init only runs on first boot,
+//! so VMs with variables are cold-booted instead of cloned from a
+//! template that already ran it.
+//!
+//! The VM's `metadata.json` records the variables for `meda get` and
+//! `meda list`, with the values of `--secret-env` ones replaced by
+//! [`REDACTED`].
+
+use crate::error::{Error, Result};
+use base64::Engine;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+/// Variables, as `KEY='value'` lines, in the guest.
+pub const GUEST_ENV_FILE: &str = "/etc/meda/env";
+
+/// Secret variables, like [`GUEST_ENV_FILE`] but root-only, in the guest.
+pub const GUEST_SECRET_ENV_FILE: &str = "/etc/meda/secret-env";
+
+/// Login shell hook exporting the variables, in the guest.
+const PROFILE_SCRIPT: &str = "/etc/profile.d/meda-env.sh";
+
+/// What `meda get` and `meda list` show for a secret value.
+pub const REDACTED: &str = "<redacted>";
+
+/// A file cloud-init writes in the guest on first boot.
+pub struct GuestFile<'a> {
+    pub path: &'a str,
+    pub permissions: &'a str,
+    /// `user` or `user:group`; root without one.
+    pub owner: Option<&'a str>,
+    pub content: &'a [u8],
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GuestEnv {
+    vars: BTreeMap<String, String>,
+    secret: BTreeSet<String>,
+}
+
+/// `--env` / `--secret-env` value: `KEY=VALUE`, with a shell variable name.
+pub fn parse_var(s: &str) -> std::result::Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid variable '{}' (expected KEY=VALUE)", s))?;
+    check_key(key)?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn check_key(key: &str) -> std::result::Result<(), String> {
+    let mut chars = key.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "invalid variable name '{}' (use letters, digits and '_', not starting with a digit)",
+            key
+        ))
+    }
+}
+
+impl GuestEnv {
+    /// Plain and secret variables; a name given as both is secret.
+    pub fn new(
+        vars: impl IntoIterator<Item = (String, String)>,
+        secret: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let mut env = Self::default();
+        for (key, value) in vars {
+            check_key(&key).map_err(Error::Other)?;
+            env.vars.insert(key, value);
+        }
+        for (key, value) in secret {
+            check_key(&key).map_err(Error::Other)?;
+            env.secret.insert(key.clone());
+            env.vars.insert(key, value);
+        }
+        if env.vars.values().any(|v| v.contains('\0')) {
+            return Err(Error::Other(
+                "Environment values can't contain NUL bytes".to_string(),
+            ));
+        }
+        Ok(env)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+
+    /// The variables as recorded in metadata.json: secrets redacted.
+    pub fn redacted(&self) -> BTreeMap<String, String> {
+        self.vars
+            .iter()
+            .map(|(key, value)| {
+                let shown = if self.secret.contains(key) {
+                    REDACTED
+                } else {
+                    value
+                };
+                (key.clone(), shown.to_string())
+            })
+            .collect()
+    }
+
+    /// Body of [`GUEST_ENV_FILE`], or of [`GUEST_SECRET_ENV_FILE`]
+    /// when `secret`.
+    fn env_file(&self, secret: bool) -> String {
+        self.vars
+            .iter()
+            .filter(|(key, _)| self.secret.contains(*key) == secret)
+            .map(|(key, value)| format!("{}='{}'\n", key, value.replace('\'', "'\\''")))
+            .collect::<Vec<_>>()
+            .concat()
+    }
+}
+
+/// Record a new VM's variables and have cloud-init write them.
+pub fn prepare(vm_dir: &Path, env: &GuestEnv) -> Result<()> {
+    if env.is_empty() {
+        return Ok(());
+    }
+    let mut metadata = crate::labels::VmMetadata::load(vm_dir)?;
+    metadata.env = env.redacted();
+    metadata.save(vm_dir)?;
+
+    let env_file = env.env_file(false);
+    let secret_file = env.env_file(true);
+    // Only root's login shells can read the secret ones.
+    let profile = format!(
+        "set -a\n. {env}\n[ -r {secret} ] && . {secret}\nset +a\n",
+        env = GUEST_ENV_FILE,
+        secret = GUEST_SECRET_ENV_FILE
+    );
+    let mut files = vec![
+        GuestFile {
+            path: GUEST_ENV_FILE,
+            permissions: "0644",
+            owner: None,
+            content: env_file.as_bytes(),
+        },
+        GuestFile {
+            path: PROFILE_SCRIPT,
+            permissions: "0644",
+            owner: None,
+            content: profile.as_bytes(),
+        },
+    ];
+    if !env.secret.is_empty() {
+        files.push(GuestFile {
+            path: GUEST_SECRET_ENV_FILE,
+            permissions: "0600",
+            owner: None,
+            content: secret_file.as_bytes(),
+        });
+        // Restrict the file before the values go in.
+        let vendor_data = vm_dir.join(crate::immutable::VENDOR_DATA);
+        if !vendor_data.exists() {
+            fs::write(&vendor_data, "")?;
+        }
+        crate::secrets::restrict(&vendor_data)?;
+    }
+    add_files(vm_dir, &files)
+}
+
+/// Add `files` to the VM's vendor-data `write_files`. Contents go in
+/// base64, so they needn't be valid YAML or even text.
+pub fn add_files(vm_dir: &Path, files: &[GuestFile]) -> Result<()> {
+    let path = vm_dir.join(crate::immutable::VENDOR_DATA);
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    fs::write(&path, with_files(&existing, files))?;
+    Ok(())
+}
+
+/// `existing` vendor-data with `files` at the top of its `write_files`
+/// list, which is started right after the header when there is none:
+/// the `mounts` list others append to must stay the last key.
+fn with_files(existing: &str, files: &[GuestFile]) -> String {
+    let entries = files
+        .iter()
+        .map(|file| {
+            // write_files runs before cloud-init creates users; `defer`
+            // waits for them.
+            let owner = file
+                .owner
+                .map(|owner| format!("    owner: {}\n    defer: true\n", owner))
+                .unwrap_or_default();
+            format!(
+                "  - path: {}\n    permissions: '{}'\n{}    encoding: b64\n    content: {}\n",
+                file.path,
+                file.permissions,
+                owner,
+                base64::engine::general_purpose::STANDARD.encode(file.content)
+            )
+        })
+        .collect::<Vec<_>>()
+        .concat();
+    let body = existing.strip_prefix("#cloud-config\n").unwrap_or(existing);
+    match body.split_once("write_files:\n") {
+        Some((before, after)) if before.is_empty() || before.ends_with('\n') => format!(
+            "#cloud-config\n{}write_files:\n{}{}",
+            before, entries, after
+        ),
+        _ => format!("#cloud-config\nwrite_files:\n{}{}", entries, body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn var(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn test_parse_var() {
+        assert_eq!(parse_var("CI=true"), Ok(var("CI", "true")));
+        assert_eq!(parse_var("URL=a=b"), Ok(var("URL", "a=b")));
+        assert_eq!(parse_var("_X="), Ok(var("_X", "")));
+        assert!(parse_var("CI").is_err());
+        assert!(parse_var("1X=y").is_err());
+        assert!(parse_var("A-B=y").is_err());
+        assert!(parse_var("=y").is_err());
+    }
+
+    #[test]
+    fn test_prepare() {
+        let dir = TempDir::new().unwrap();
+        // Vendor-data of a data disk, which virtiofs appends mounts to.
+        let disk = "#cloud-config\nfs_setup:\n  - label: meda-data\nmounts:\n  - [LABEL=meda-data, /data]\n";
+        fs::write(dir.path().join(crate::immutable::VENDOR_DATA), disk).unwrap();
+
+        let env = GuestEnv::new(
+            [var("JOB", "it's 1"), var("TOKEN", "plain")],
+            [var("TOKEN", "s3cret")],
+        )
+        .unwrap();
+        assert_eq!(env.env_file(false), "JOB='it'\\''s 1'\n");
+        assert_eq!(env.env_file(true), "TOKEN='s3cret'\n");
+        prepare(dir.path(), &env).unwrap();
+
+        let metadata = crate::labels::VmMetadata::load(dir.path()).unwrap();
+        assert_eq!(metadata.env["JOB"], "it's 1");
+        assert_eq!(metadata.env["TOKEN"], REDACTED);
+
+        let vendor = fs::read_to_string(dir.path().join(crate::immutable::VENDOR_DATA)).unwrap();
+        assert!(vendor.starts_with("#cloud-config\nwrite_files:\n  - path: /etc/meda/env\n"));
+        assert!(vendor.ends_with("mounts:\n  - [LABEL=meda-data, /data]\n"));
+        assert!(!vendor.contains("s3cret"));
+        for secret in [false, true] {
+            let encoded = base64::engine::general_purpose::STANDARD.encode(env.env_file(secret));
+            assert!(vendor.contains(&encoded));
+        }
+        assert!(vendor.contains("  - path: /etc/meda/secret-env\n    permissions: '0600'\n"));
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(dir.path().join(crate::immutable::VENDOR_DATA))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Later files join the same list.
+        let extra = GuestFile {
+            path: "/etc/x",
+            permissions: "0600",
+            owner: None,
+            content: b"x",
+        };
+        add_files(dir.path(), &[extra]).unwrap();
+        let vendor = fs::read_to_string(dir.path().join(crate::immutable::VENDOR_DATA)).unwrap();
+        assert_eq!(vendor.matches("write_files:").count(), 1);
+        assert!(vendor.starts_with("#cloud-config\nwrite_files:\n  - path: /etc/x\n"));
+        assert_eq!(vendor.matches("  - path: ").count(), 4);
+
+        assert!(GuestEnv::new([var("BAD-KEY", "x")], []).is_err());
+        assert!(GuestEnv::new([var("K", "a\0b")], []).is_err());
+    }
+}
@@ -8,7 +8,14 @@
 //! reflexively denies new requests rather than over-accept on a bad
 //! probe. The reasoning is the same as the admission module: better
 //! 503s than an OOM-kill that drags down the user's systemd session.
+//!
+//! `meda capacity` prints the same budget from the CLI, along with
+//! subnet pool utilization.
 
+use crate::admission::{self, Budget, Committed};
+use crate::config::Config;
+use crate::error::Result;
+use crate::vm::VmInfo;
 use std::fs;
 use std::path::Path;
 
@@ -34,6 +41,29 @@ pub fn total_mem_gb() -> u64 {
     0
 }
 
+/// MemAvailable from /proc/meminfo in bytes: what new guests can get
+/// without pushing the host into swap.
+pub fn available_mem_bytes() -> Option<u64> {
+    meminfo_bytes("MemAvailable:")
+}
+
+/// MemTotal from /proc/meminfo in bytes, unrounded.
+pub fn total_mem_bytes() -> Option<u64> {
+    meminfo_bytes("MemTotal:")
+}
+
+fn meminfo_bytes(key: &str) -> Option<u64> {
+    let body = fs::read_to_string("/proc/meminfo").ok()?;
+    let kb: u64 = body
+        .lines()
+        .find_map(|line| line.strip_prefix(key))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
+
 /// Number of logical CPUs visible to this process. Uses
 /// `num_cpus::get()` via the standard library on Linux (relies on
 /// /sys/devices/system/cpu/online). Falls back to 1 if the syscall
@@ -70,3 +100,93 @@ pub fn total_disk_gb(vm_root: &Path) -> u64 {
         Err(_) => 0,
     }
 }
+
+/// The admission budget of this host.
+pub fn budget(config: &Config) -> Budget {
+    Budget::new(total_mem_gb(), total_cpu(), total_disk_gb(&config.vm_root))
+}
+
+/// What `vms` commit against the budget, leaving out `except`: disk for
+/// every VM, memory and vCPUs for running ones.
+pub fn committed(vms: &[VmInfo], except: Option<&str>) -> Committed {
+    let mut committed = Committed::default();
+    for vm in vms.iter().filter(|vm| Some(vm.name.as_str()) != except) {
+        committed.disk_gb = committed
+            .disk_gb
+            .saturating_add(admission::parse_size_gb(&vm.disk));
+        if vm.state == "running" {
+            committed.mem_gb = committed
+                .mem_gb
+                .saturating_add(admission::parse_size_gb(&vm.memory));
+            committed.cpu = committed
+                .cpu
+                .saturating_add(vm.vcpus.trim().parse().unwrap_or(0));
+        }
+    }
+    committed
+}
+
+/// `meda capacity`: the admission budget against what existing VMs
+/// commit (API requests still inside their reservation window are not
+/// visible here; `GET /api/v1/capacity` includes them), plus how many
+/// VM subnets are left.
+pub fn capacity_command(config: &Config, json: bool) -> Result<()> {
+    let budget = budget(config);
+    let committed = committed(&crate::vm::collect_vms_with_templates(config)?, None);
+    let subnets = crate::subnets::usage(config)?;
+
+    if json {
+        let report = serde_json::json!({
+            "total": {
+                "mem_gb": budget.total_mem_gb,
+                "cpu": budget.total_cpu,
+                "disk_gb": budget.total_disk_gb,
+            },
+            "reserve": {
+                "mem_gb": budget.reserve_mem_gb,
+                "cpu": budget.reserve_cpu,
+                "disk_gb": budget.reserve_disk_gb,
+            },
+            "committed": {
+                "mem_gb": committed.mem_gb,
+                "cpu": committed.cpu,
+                "disk_gb": committed.disk_gb,
+            },
+            "available": {
+                "mem_gb": budget.mem_available_gb(committed.mem_gb),
+                "cpu": budget.cpu_available(committed.cpu),
+                "disk_gb": budget.disk_available_gb(committed.disk_gb),
+            },
+            "subnets": subnets,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!(
+        "Memory:  {}/{} GiB committed, {} GiB available ({} GiB reserved)",
+        committed.mem_gb,
+        budget.total_mem_gb,
+        budget.mem_available_gb(committed.mem_gb),
+        budget.reserve_mem_gb
+    );
+    println!(
+        "CPUs:    {}/{} committed, {} available ({} reserved)",
+        committed.cpu,
+        budget.total_cpu,
+        budget.cpu_available(committed.cpu),
+        budget.reserve_cpu
+    );
+    println!(
+        "Disk:    {}/{} GiB committed, {} GiB available ({} GiB reserved)",
+        committed.disk_gb,
+        budget.total_disk_gb,
+        budget.disk_available_gb(committed.disk_gb),
+        budget.reserve_disk_gb
+    );
+    println!(
+        "Subnets: {}/{} leased, {} free",
+        subnets.leased, subnets.total, subnets.free
+    );
+    Ok(())
+}
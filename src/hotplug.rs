@@ -0,0 +1,255 @@
+//! Live vCPU and memory changes: `meda hotplug <vm> --cpus 4 --memory 4G`.
+//!
+//! Cloud Hypervisor can only add vCPUs and memory a VM was booted with
+//! room for, so the headroom is chosen at create time
+//! (`meda create --max-cpus 8 --max-memory 16G`) and recorded in the VM
+//! dir; the launch spec turns it into `--cpus boot=N,max=M` and a
+//! `--memory hotplug_size`. `meda hotplug` then resizes the running VM
+//! with `ch-remote resize` and records the new values like `meda resize`
+//! does, so `get`, `list` and the next start see them without a reboot.
+//!
+//! vCPUs can go up or down, up to the most the VM was last started
+//! with room for, which is recorded each start. Memory only grows: ACPI
+//! hotplug can't take it back from a running guest.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::util::parse_size_bytes;
+use crate::vm;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// File in a VM dir with its hotplug headroom; absent without one.
+pub const HOTPLUG_FILE: &str = "hotplug.json";
+
+/// Most vCPUs and memory a VM can be hotplugged up to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Headroom {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_cpus: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_memory: Option<String>,
+    /// Most vCPUs Cloud Hypervisor was last started with: `max_cpus`, or
+    /// the boot count without one. Later `meda resize`s don't change it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub booted_cpus: Option<u8>,
+}
+
+fn memory_bytes(memory: &str) -> Result<u64> {
+    parse_size_bytes(memory)
+        .ok_or_else(|| Error::Other(format!("Invalid memory size '{}'", memory)))
+}
+
+impl Headroom {
+    pub fn new(max_cpus: Option<u8>, max_memory: Option<String>) -> Result<Self> {
+        if let Some(max) = &max_memory {
+            memory_bytes(max)?;
+        }
+        Ok(Self {
+            max_cpus,
+            max_memory,
+            booted_cpus: None,
+        })
+    }
+
+    /// Record that the VM in `vm_dir` was started with room for `cpus`.
+    pub fn record_boot(vm_dir: &Path, cpus: u8) -> Result<()> {
+        let mut headroom = Self::load(vm_dir).unwrap_or_default();
+        headroom.booted_cpus = Some(cpus);
+        headroom.save(vm_dir)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max_cpus.is_none() && self.max_memory.is_none()
+    }
+
+    /// Does the headroom leave room for a VM booted with `cpus` and `memory`?
+    pub fn check(&self, cpus: u8, memory: &str) -> Result<()> {
+        if let Some(max) = self.max_cpus.filter(|max| *max < cpus) {
+            return Err(Error::Other(format!(
+                "--max-cpus {} is less than the {} vCPUs the VM boots with",
+                max, cpus
+            )));
+        }
+        if let Some(max) = &self.max_memory {
+            if memory_bytes(max)? < memory_bytes(memory)? {
+                return Err(Error::Other(format!(
+                    "--max-memory {} is less than the {} the VM boots with",
+                    max, memory
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load(vm_dir: &Path) -> Option<Self> {
+        let body = fs::read_to_string(vm_dir.join(HOTPLUG_FILE)).ok()?;
+        serde_json::from_str(&body).ok()
+    }
+
+    pub fn save(&self, vm_dir: &Path) -> Result<()> {
+        fs::write(
+            vm_dir.join(HOTPLUG_FILE),
+            serde_json::to_string_pretty(self)?,
+        )?;
+        Ok(())
+    }
+}
+
+/// CH's `hotplug_size`: what `max_memory` adds to `memory`, in MiB;
+/// `None` when it adds nothing.
+pub fn hotplug_size(memory: &str, max_memory: &str) -> Option<String> {
+    let extra = parse_size_bytes(max_memory)?.checked_sub(parse_size_bytes(memory)?)?;
+    let mib = extra >> 20;
+    (mib > 0).then(|| format!("{}M", mib))
+}
+
+/// Whether the running VM `name` can be resized live to `cpus` and/or
+/// `memory`, within the headroom it was created with.
+pub(crate) fn check_live(
+    config: &Config,
+    name: &str,
+    cpus: Option<u8>,
+    memory: Option<&str>,
+) -> Result<()> {
+    let headroom = Headroom::load(&config.vm_dir(name)).unwrap_or_default();
+
+    if let Some(cpus) = cpus {
+        let max = match headroom.max_cpus.or(headroom.booted_cpus) {
+            Some(max) => max,
+            None => vm::get_vm_cpus(config, name)?.parse().unwrap_or(1),
+        };
+        if cpus == 0 || cpus > max {
+            return Err(Error::Other(format!(
+                "VM {} can run 1 to {} vCPUs; recreate it with a higher --max-cpus for more",
+                name, max
+            )));
+        }
+    }
+    if let Some(memory) = memory {
+        let current = vm::get_vm_memory(config, name)?;
+        let max = headroom.max_memory.as_deref().unwrap_or(&current);
+        let bytes = memory_bytes(memory)?;
+        if bytes < memory_bytes(&current)? {
+            return Err(Error::Other(format!(
+                "Memory can only grow while VM {} runs (it has {}); use `meda resize` and restart it to shrink",
+                name, current
+            )));
+        }
+        if bytes > memory_bytes(max)? {
+            return Err(Error::Other(format!(
+                "VM {} can grow to at most {} of memory; recreate it with a higher --max-memory for more",
+                name, max
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Resize the running VM in `vm_dir` to `cpus` and/or `memory` with
+/// `ch-remote resize`.
+pub(crate) async fn live_resize(
+    config: &Config,
+    vm_dir: &Path,
+    cpus: Option<u8>,
+    memory: Option<&str>,
+) -> Result<()> {
+    let sock = vm_dir.join("api.sock");
+    let cpus_str = cpus.map(|c| c.to_string());
+    let mut args = vec!["--api-socket", sock.to_str().unwrap(), "resize"];
+    if let Some(c) = &cpus_str {
+        args.extend(["--cpus", c.as_str()]);
+    }
+    if let Some(m) = memory {
+        args.extend(["--memory", m]);
+    }
+    crate::util::run_command(&config.cr_bin.to_string_lossy(), &args)
+        .await
+        .map_err(|e| Error::Other(format!("Cloud Hypervisor refused the resize: {}", e)))
+}
+
+/// `memory 4G / 2 vCPUs`, for messages; at least one must be given.
+pub(crate) fn describe(cpus: Option<u8>, memory: Option<&str>) -> String {
+    match (memory, cpus) {
+        (Some(m), Some(c)) => format!("memory {} / {} vCPUs", m, c),
+        (Some(m), None) => format!("memory {}", m),
+        (None, Some(c)) => format!("{} vCPUs", c),
+        (None, None) => unreachable!(),
+    }
+}
+
+/// Resize the running VM `name` to `cpus` and/or `memory`, within the
+/// headroom it was created with.
+pub async fn hotplug(
+    config: &Config,
+    name: &str,
+    cpus: Option<u8>,
+    memory: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let _lock = vm::lock(config, name, "hotplug")?;
+    let vm_dir = config.vm_dir(name);
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+    if cpus.is_none() && memory.is_none() {
+        return Err(Error::Other(
+            "Nothing to hotplug: pass --cpus and/or --memory".to_string(),
+        ));
+    }
+    if !vm::check_vm_running(config, name)? {
+        return Err(Error::VmNotRunning(name.to_string()));
+    }
+    check_live(config, name, cpus, memory)?;
+    let _quota = crate::quota::before_grow(config, name, memory, cpus, None)?;
+    live_resize(config, &vm_dir, cpus, memory).await?;
+    vm::record_resources(&vm_dir, cpus, memory)?;
+
+    let message = format!("Hotplugged VM {}: {}", name, describe(cpus, memory));
+    if json {
+        let result = vm::VmResult {
+            success: true,
+            message,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!("{}", message);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_headroom() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(Headroom::load(dir.path()), None);
+        let headroom = Headroom::new(Some(8), Some("16G".to_string())).unwrap();
+        headroom.save(dir.path()).unwrap();
+        assert_eq!(Headroom::load(dir.path()), Some(headroom.clone()));
+
+        assert!(headroom.check(8, "16G").is_ok());
+        assert!(headroom.check(9, "1G").is_err());
+        assert!(headroom.check(2, "32G").is_err());
+        assert!(Headroom::new(None, Some("lots".to_string())).is_err());
+        assert!(Headroom::default().is_empty());
+
+        Headroom::record_boot(dir.path(), 8).unwrap();
+        let booted = Headroom::load(dir.path()).unwrap();
+        assert_eq!(booted.booted_cpus, Some(8));
+        assert_eq!(booted.max_cpus, Some(8));
+
+        let bare = TempDir::new().unwrap();
+        Headroom::record_boot(bare.path(), 4).unwrap();
+        assert!(Headroom::load(bare.path()).unwrap().is_empty());
+
+        assert_eq!(hotplug_size("2G", "16G"), Some("14336M".to_string()));
+        assert_eq!(hotplug_size("2G", "2G"), None);
+        assert_eq!(hotplug_size("4G", "2G"), None);
+    }
+}
@@ -1,23 +1,31 @@
 use crate::chunking::{ChunkInfo, ChunkMetadata, FileChunker};
-use crate::config::Config;
+use crate::compression::ZSTD_MEDIA_SUFFIX;
+use crate::config::{Config, DiskFormat};
 use crate::error::{Error, Result};
-// Note: download_file will be used when implementing actual registry pulling
+use crate::lock::FileLock;
+use crate::progress::Progress;
 use crate::vm;
 use log::info;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::env;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use tracing::Instrument;
 
 pub struct RunOptions<'a> {
     pub vm_name: Option<&'a str>,
     pub registry: Option<&'a str>,
     pub org: Option<&'a str>,
     pub user_data_path: Option<&'a str>,
+    /// Extra authorized keys (paths or key strings) for the default user-data
+    pub ssh_keys: &'a [String],
+    /// Labels recorded in the VM's metadata.json
+    pub labels: &'a crate::labels::Labels,
     pub no_start: bool,
     pub resources: crate::vm::VmResources,
+    /// Seconds until `meda reap` deletes the VM
+    pub ttl: Option<u64>,
 }
 
 #[derive(Serialize)]
@@ -27,6 +35,8 @@ pub struct ImageInfo {
     pub registry: String,
     pub size: String,
     pub created: String,
+    /// Registry manifest digest, when known
+    pub digest: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -35,8 +45,104 @@ pub struct ImageResult {
     pub message: String,
 }
 
+/// How `create-image --live` captures the disk of a running VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LiveMode {
+    /// Pause the VM, reflink-copy its rootfs, resume (crash-consistent)
+    Crash,
+    /// Also fsfreeze the guest's root filesystem over SSH while copying
+    Freeze,
+}
+
+impl LiveMode {
+    /// Value recorded as the `consistency` metadata key.
+    fn consistency(self) -> &'static str {
+        match self {
+            LiveMode::Crash => "crash-consistent",
+            LiveMode::Freeze => "fs-frozen",
+        }
+    }
+}
+
+/// Freeze `/` in the guest and hold it frozen until a line (or EOF) on
+/// stdin, then thaw it from the same root shell: a new login would hang
+/// writing to the frozen filesystem. A watchdog thaws it after two
+/// minutes in case the session hangs, so the guest can't be left hung on
+/// writes indefinitely. The thaw kills the watchdog, or reports `expired`
+/// if it already fired, since the copy then wasn't taken frozen.
+const GUEST_FREEZE: &str =
+    "sudo sh -c 'nohup sh -c \"sleep 120; fsfreeze -u /\" >/dev/null 2>&1 & w=$!; \
+     fsfreeze -f / && echo frozen && read _; \
+     if kill $w 2>/dev/null; then fsfreeze -u / && echo thawed; else echo expired; fi'";
+
+/// A guest with `/` frozen by a session running [`GUEST_FREEZE`].
+struct FrozenGuest {
+    session: std::process::Child,
+    stdout: std::io::BufReader<std::process::ChildStdout>,
+}
+
+impl FrozenGuest {
+    fn freeze(config: &Config, host: &str) -> Result<Self> {
+        Self::attach(crate::ssh::guest_spawn(config, host, GUEST_FREEZE)?)
+    }
+
+    /// Wait for `session` to report the freeze.
+    fn attach(mut session: std::process::Child) -> Result<Self> {
+        let stdout = session.stdout.take().expect("piped stdout");
+        let mut guest = Self {
+            session,
+            stdout: std::io::BufReader::new(stdout),
+        };
+        if guest.expect("frozen").is_err() {
+            return Err(Error::CommandFailed(guest.finish()));
+        }
+        Ok(guest)
+    }
+
+    fn expect(&mut self, word: &str) -> Result<()> {
+        use std::io::BufRead;
+        let mut line = String::new();
+        self.stdout.read_line(&mut line)?;
+        if line.trim() == "expired" {
+            return Err(Error::CommandFailed(
+                "the freeze expired and the guest thawed before the copy finished".to_string(),
+            ));
+        }
+        if line.trim() != word {
+            return Err(Error::CommandFailed(format!(
+                "guest did not report '{}'",
+                word
+            )));
+        }
+        Ok(())
+    }
+
+    /// End the session, returning what it printed to stderr.
+    fn finish(mut self) -> String {
+        drop(self.session.stdin.take());
+        let output = self.session.wait_with_output();
+        output
+            .map(|o| String::from_utf8_lossy(&o.stderr).trim().to_string())
+            .unwrap_or_else(|e| e.to_string())
+    }
+
+    fn thaw(mut self) -> Result<()> {
+        use std::io::Write;
+        if let Some(stdin) = self.session.stdin.as_mut() {
+            stdin.write_all(b"\n").ok();
+        }
+        let thawed = self.expect("thawed");
+        let stderr = self.finish();
+        thawed.map_err(|e| Error::CommandFailed(format!("{}: {}", e, stderr)))
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ImageManifest {
+    /// Format version (see `schema`); 0 when written before versioning
+    #[serde(default)]
+    pub schema_version: u32,
     pub name: String,
     pub tag: String,
     pub registry: String,
@@ -44,6 +150,26 @@ pub struct ImageManifest {
     pub artifacts: HashMap<String, String>, // artifact_type -> file_path
     pub metadata: HashMap<String, String>,
     pub created: u64,
+    /// Images this one was derived from, parent first (see `meda image history`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<ImageAncestor>,
+}
+
+/// One step in an image's lineage, captured when `create-image` built
+/// a child from a VM that ran this image.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ImageAncestor {
+    pub image: String,
+    /// Registry manifest digest, when the image was pulled or imported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// When the ancestor was built or pulled; `None` if it was already
+    /// gone locally when the child was created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+    /// VM the ancestor was itself created from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_vm: Option<String>,
 }
 
 pub struct ImageRef {
@@ -51,6 +177,10 @@ pub struct ImageRef {
     pub org: String,
     pub name: String,
     pub tag: String,
+    /// `sha256:<hex>` of a `name@sha256:<hex>` reference. Such an image
+    /// is stored under a tag named after the digest (see `digest_tag`),
+    /// since a tag can move but a digest can't.
+    pub digest: Option<String>,
 }
 
 impl ImageRef {
@@ -72,22 +202,39 @@ impl ImageRef {
             _ => return Err(Error::InvalidImageName(image.to_string())),
         };
 
+        let (name_tag, digest) = match name_tag.split_once('@') {
+            Some((name_tag, digest)) if is_digest(digest) => (name_tag, Some(digest.to_string())),
+            Some(_) => return Err(Error::InvalidImageName(image.to_string())),
+            None => (name_tag, None),
+        };
+
         let (name, tag) = if let Some(idx) = name_tag.find(':') {
             (&name_tag[..idx], &name_tag[idx + 1..])
         } else {
             (name_tag, "latest")
         };
+        // The digest pins the image; a tag next to it is only a label.
+        let tag = digest.as_deref().map_or(tag.to_string(), digest_tag);
 
         Ok(ImageRef {
             registry: registry.to_string(),
             org: org.to_string(),
             name: name.to_string(),
-            tag: tag.to_string(),
+            tag,
+            digest,
         })
     }
 
+    /// `registry/org/name`, without tag or digest.
+    pub fn repository(&self) -> String {
+        format!("{}/{}/{}", self.registry, self.org, self.name)
+    }
+
     pub fn url(&self) -> String {
-        format!("{}/{}/{}:{}", self.registry, self.org, self.name, self.tag)
+        match &self.digest {
+            Some(digest) => format!("{}@{}", self.repository(), digest),
+            None => format!("{}:{}", self.repository(), self.tag),
+        }
     }
 
     pub fn local_dir(&self, config: &Config) -> PathBuf {
@@ -101,6 +248,18 @@ impl ImageRef {
     }
 }
 
+/// `sha256:` followed by 64 lowercase hex digits.
+fn is_digest(s: &str) -> bool {
+    s.strip_prefix("sha256:").is_some_and(|hex| {
+        hex.len() == 64 && hex.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+    })
+}
+
+/// Local tag of an image pulled by `digest`: `sha256-<hex>`.
+fn digest_tag(digest: &str) -> String {
+    digest.replace(':', "-")
+}
+
 impl ImageManifest {
     pub fn load(image_dir: &Path) -> Result<Self> {
         let manifest_path = image_dir.join("manifest.json");
@@ -109,8 +268,26 @@ impl ImageManifest {
         }
 
         let content = fs::read_to_string(manifest_path)?;
-        let manifest: ImageManifest = serde_json::from_str(&content)?;
-        Ok(manifest)
+        let mut doc: serde_json::Value = serde_json::from_str(&content)?;
+        crate::schema::upgrade_image(image_dir, &mut doc)?;
+        Ok(serde_json::from_value(doc)?)
+    }
+
+    /// Registry manifest digest, when the image was pulled or imported.
+    pub fn digest(&self) -> Option<&String> {
+        self.metadata
+            .get("digest")
+            .or_else(|| self.metadata.get("oci_digest"))
+    }
+
+    /// This image as a history entry of an image derived from it.
+    fn ancestor_entry(&self, image: String) -> ImageAncestor {
+        ImageAncestor {
+            image,
+            digest: self.digest().cloned(),
+            created: Some(self.created),
+            source_vm: self.metadata.get("source_vm").cloned(),
+        }
     }
 
     pub fn save(&self, image_dir: &Path) -> Result<()> {
@@ -122,6 +299,52 @@ impl ImageManifest {
     }
 }
 
+/// Lock file guarding a tag directory. It lives next to the tag dir
+/// (`<name>/.<tag>.lock`) rather than inside it so that it outlives
+/// `remove_dir_all` of the tag while the remover still holds it.
+fn tag_lock_path(tag_dir: &Path) -> PathBuf {
+    let tag = tag_dir
+        .file_name()
+        .map(|t| t.to_string_lossy().to_string())
+        .unwrap_or_default();
+    tag_dir.with_file_name(format!(".{}.lock", tag))
+}
+
+/// Reader lock for `run`/`push`: many readers may share an image, but
+/// not while `rmi`/`prune` is deleting it.
+pub(crate) fn lock_image_shared(tag_dir: &Path, label: &str) -> Result<FileLock> {
+    let lock = match FileLock::try_shared(&tag_lock_path(tag_dir)) {
+        Ok(Some(lock)) => lock,
+        Ok(None) => {
+            return Err(Error::ImageInUse(
+                label.to_string(),
+                "it is being removed by another meda process".to_string(),
+            ))
+        }
+        // Name dir gone: the image was removed before we got here.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(Error::ImageNotFound(label.to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    // We may have won the lock just after a remover dropped it.
+    if !tag_dir.exists() {
+        return Err(Error::ImageNotFound(label.to_string()));
+    }
+    Ok(lock)
+}
+
+/// Writer lock for `rmi`/`prune`. Fails instead of waiting so a long
+/// `meda run` doesn't leave the remover hanging.
+fn lock_image_exclusive(tag_dir: &Path, label: &str) -> Result<FileLock> {
+    FileLock::try_exclusive(&tag_lock_path(tag_dir))?.ok_or_else(|| {
+        Error::ImageInUse(
+            label.to_string(),
+            "a run or push is reading it; retry once it finishes".to_string(),
+        )
+    })
+}
+
 /// Create an image from the current base Ubuntu image + binaries
 pub async fn create_base_image(
     config: &Config,
@@ -143,6 +366,7 @@ pub async fn create_base_image(
         org: org.to_string(),
         name: name.to_string(),
         tag: tag.to_string(),
+        digest: None,
     };
 
     let image_dir = image_ref.local_dir(config);
@@ -152,9 +376,10 @@ pub async fn create_base_image(
     let mut artifacts = HashMap::new();
 
     // Copy base raw image
-    if config.base_raw.exists() {
+    let base_image = crate::base_image::current(config);
+    if base_image.exists() {
         let image_raw = image_dir.join("base.raw");
-        fs::copy(&config.base_raw, &image_raw)?;
+        fs::copy(&base_image, &image_raw)?;
         artifacts.insert("base_image".to_string(), "base.raw".to_string());
     }
 
@@ -188,6 +413,7 @@ pub async fn create_base_image(
 
     // Create manifest
     let manifest = ImageManifest {
+        schema_version: crate::schema::IMAGE_SCHEMA_VERSION,
         name: name.to_string(),
         tag: tag.to_string(),
         registry: registry.to_string(),
@@ -198,6 +424,7 @@ pub async fn create_base_image(
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap_or_default()
             .as_secs(),
+        history: Vec::new(),
     };
 
     manifest.save(&image_dir)?;
@@ -224,8 +451,8 @@ pub async fn pull(
     org: Option<&str>,
     json: bool,
 ) -> Result<()> {
-    let default_registry = registry.unwrap_or("ghcr.io");
-    let default_org = org.unwrap_or("cirunlabs");
+    let default_registry = registry.unwrap_or(&config.default_registry);
+    let default_org = org.unwrap_or(&config.default_org);
 
     let image_ref = ImageRef::parse(image, default_registry, default_org)?;
 
@@ -234,46 +461,67 @@ pub async fn pull(
         println!("📥 Pulling image: {}", image_ref.url());
     }
 
+    let message = if pull_ref(config, &image_ref, json).await? {
+        format!("Successfully pulled image {}", image_ref.url())
+    } else {
+        format!("Image {} already exists locally", image_ref.url())
+    };
+
+    if json {
+        let result = ImageResult {
+            success: true,
+            message,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        println!("✅ {}", message);
+    }
+
+    Ok(())
+}
+
+/// Is `image_ref` complete locally? A partial pull (see
+/// `pull_artifacts`) doesn't count: the whole image is fetched over it.
+fn is_pulled(config: &Config, image_ref: &ImageRef) -> bool {
     let image_dir = image_ref.local_dir(config);
+    image_dir.exists()
+        && ImageManifest::load(&image_dir).is_ok_and(|m| !m.metadata.contains_key("partial"))
+}
 
-    // Check if image already exists locally
-    if image_dir.exists() && ImageManifest::load(&image_dir).is_ok() {
-        let message = format!("Image {} already exists locally", image_ref.url());
-        if json {
-            let result = ImageResult {
-                success: true,
-                message,
-            };
-            println!("{}", serde_json::to_string_pretty(&result)?);
-        } else {
-            println!("✅ {}", message);
-        }
-        return Ok(());
+/// Fetch `image_ref` unless it is already complete locally; false if it
+/// was. Prints nothing when `quiet`.
+async fn pull_ref(config: &Config, image_ref: &ImageRef, quiet: bool) -> Result<bool> {
+    if is_pulled(config, image_ref) {
+        return Ok(false);
     }
+    config.require_network(&format!("Pulling {}", image_ref.url()))?;
+    let image_dir = image_ref.local_dir(config);
 
     // Ensure ORAS is available
-    let oras_path = ensure_oras_available(config).await?;
+    let oras = crate::oras::ensure(config).await?;
 
-    // Create temporary directory for downloaded artifacts
-    let temp_dir = std::env::temp_dir().join(format!(
-        "meda-pull-{}",
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs()
-    ));
-    fs::create_dir_all(&temp_dir)?;
+    // Create temporary directory for downloaded artifacts, unique per
+    // pull so concurrent pulls (`meda pull -f`) don't share one
+    let temp_guard = tempfile::Builder::new().prefix("meda-pull-").tempdir()?;
+    let temp_dir = temp_guard.path().to_path_buf();
 
     let image_ref_str = image_ref.url();
 
-    // Get GitHub token for authentication (optional for public images)
-    let github_token = env::var("GITHUB_TOKEN").ok();
+    // Credentials are optional for public images
+    let credentials = crate::credentials::lookup(config, &image_ref.registry);
+
+    // A manifest list: pull this host's image from it.
+    let platform_digest = platform_manifest(config, &oras, image_ref).await?;
+    let pull_ref_str = match &platform_digest {
+        Some(digest) => format!("{}@{}", image_ref.repository(), digest),
+        None => image_ref_str.clone(),
+    };
 
     // Use ORAS to pull artifacts to temp directory with enhanced concurrency
-    let mut cmd = std::process::Command::new(&oras_path);
+    let mut cmd = oras.command();
     cmd.args([
         "pull",
-        &image_ref_str,
+        &pull_ref_str,
         "--output",
         temp_dir.to_str().unwrap(),
         "--allow-path-traversal",
@@ -284,7 +532,7 @@ pub async fn pull(
     // Set working directory to temp dir to ensure relative downloads
     cmd.current_dir(&temp_dir);
 
-    if !json {
+    if !quiet {
         println!(
             "🔽 ORAS pulling with {}x concurrency to: {}",
             config.chunking.get_pull_concurrency(),
@@ -293,35 +541,34 @@ pub async fn pull(
     }
 
     // Add authentication if available
-    if let Some(ref token) = github_token {
-        cmd.args(["--username", "token", "--password", token]);
+    if let Some(credentials) = &credentials {
+        cmd.args(credentials.oras_args());
     }
 
-    // Add progress and performance flags
-    if !json {
-        cmd.arg("--verbose");
-        println!("🔄 Downloading artifacts with ORAS...");
+    let mut transfer = Progress::bytes("oras-pull", &image_ref_str, None);
 
-        // Use spawn to show real-time progress
-        let mut child = cmd.spawn()?;
-        let status = child.wait()?;
-
-        if !status.success() {
-            fs::remove_dir_all(&temp_dir).ok();
-            return Err(Error::Other("ORAS pull failed".to_string()));
+    let echo = !quiet && !crate::progress::json_mode();
+    if echo {
+        println!("🔄 Downloading artifacts with ORAS...");
+    }
+    oras.output_args(&mut cmd, echo);
+    let pulled = run_oras(&mut cmd, "pull", echo, |line| {
+        if line.starts_with("Downloaded") {
+            if let Ok(size) = calculate_directory_size(&temp_dir) {
+                transfer.update(size);
+            }
         }
-    } else {
-        cmd.arg("--no-tty");
-        let output = cmd.output()?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            fs::remove_dir_all(&temp_dir).ok();
-            return Err(Error::Other(format!(
-                "ORAS pull failed:\nSTDOUT: {}\nSTDERR: {}",
-                stdout, stderr
-            )));
+    })
+    .await;
+    if let Err(e) = pulled {
+        fs::remove_dir_all(&temp_dir).ok();
+        return Err(e);
+    }
+    transfer.finish(calculate_directory_size(&temp_dir).ok());
+    if let Some(digest) = platform_digest.as_ref().or(image_ref.digest.as_ref()) {
+        verify_pulled(config, &oras, &pull_ref_str, digest, &temp_dir).await?;
+        if !quiet {
+            println!("🔒 Verified content against {}", digest);
         }
     }
 
@@ -330,10 +577,14 @@ pub async fn pull(
 
     // First try temp directory where ORAS might have downloaded files
     let mut found_artifacts = false;
-    if convert_oras_artifacts_to_meda(&temp_dir, &image_dir, &image_ref, json)
-        .await
-        .is_ok()
-    {
+    let converted = convert_oras_artifacts_to_meda(&temp_dir, &image_dir, image_ref, quiet).await;
+    if let Err(e @ Error::CorruptArtifact(_)) = converted {
+        // The download is bad; the fallbacks below would only find
+        // stale or partial files.
+        fs::remove_dir_all(&temp_dir).ok();
+        return Err(e);
+    }
+    if converted.is_ok() {
         found_artifacts = true;
     } else {
         // ORAS may have restored files to their original absolute paths from push time
@@ -345,10 +596,10 @@ pub async fn pull(
                     let dir_name = path.file_name().unwrap().to_string_lossy();
                     // Look for directories matching meda-push-chunks-* pattern
                     if dir_name.starts_with("meda-push-chunks-") {
-                        if !json {
+                        if !quiet {
                             println!("🔍 Found ORAS chunks in temp directory: {}", path.display());
                         }
-                        if convert_oras_artifacts_to_meda(&path, &image_dir, &image_ref, json)
+                        if convert_oras_artifacts_to_meda(&path, &image_dir, image_ref, quiet)
                             .await
                             .is_ok()
                         {
@@ -366,14 +617,14 @@ pub async fn pull(
     if !found_artifacts {
         // Check if ORAS downloaded directly to the correct tag-based directory structure
         if image_dir.exists() {
-            if !json {
+            if !quiet {
                 println!(
                     "📁 Found ORAS artifacts in tag directory: {}",
                     image_dir.display()
                 );
             }
             // The files are already in the correct location, just create a manifest
-            create_manifest_from_tag_directory(&image_dir, &image_ref, json).await?;
+            create_manifest_from_tag_directory(&image_dir, image_ref, quiet).await?;
             found_artifacts = true;
         } else {
             // ORAS downloads to absolute paths with SHA256 digests, need to find them
@@ -392,7 +643,7 @@ pub async fn pull(
                 let registry_dir = assets_base.join(image_ref.registry.replace(".", "_"));
                 let org_dir = registry_dir.join(&image_ref.org);
 
-                if !json {
+                if !quiet {
                     println!("🔍 Searching for ORAS downloads in {}", org_dir.display());
                 }
 
@@ -424,15 +675,15 @@ pub async fn pull(
             }
 
             if let Some(source_dir) = found_source_dir {
-                if !json {
+                if !quiet {
                     println!("📁 Found ORAS artifacts in: {}", source_dir.display());
                 }
                 // Convert from the SHA256 directory to our tag-based directory
-                convert_oras_artifacts_to_meda(&source_dir, &image_dir, &image_ref, json).await?;
+                convert_oras_artifacts_to_meda(&source_dir, &image_dir, image_ref, quiet).await?;
                 found_artifacts = true;
             } else {
                 // No SHA256 directory found, this shouldn't happen with ORAS downloads
-                if !json {
+                if !quiet {
                     println!("⚠️  No SHA256 artifact directory found, this may indicate an issue with ORAS download");
                 }
                 return Err(Error::Other(
@@ -451,8 +702,474 @@ pub async fn pull(
     // Clean up temp files
     fs::remove_dir_all(&temp_dir).ok();
 
-    let message = format!("Successfully pulled image {}", image_ref.url());
+    // Best effort for tags: derived images list it in `meda image
+    // history`, and `meda images --digests` shows it.
+    let digest = match &image_ref.digest {
+        Some(digest) => Some(digest.clone()),
+        None => resolve_digest(config, &oras, &image_ref_str).await,
+    };
+    if let Some(digest) = digest {
+        if let Ok(mut manifest) = ImageManifest::load(&image_dir) {
+            manifest.metadata.insert("digest".to_string(), digest);
+            manifest.save(&image_dir)?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Images listed one per line; blank lines and `#` comments are skipped.
+pub fn read_image_list(path: &Path) -> Result<Vec<String>> {
+    let body = if path == Path::new("-") {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        fs::read_to_string(path)
+            .map_err(|e| Error::Other(format!("reading {}: {}", path.display(), e)))?
+    };
+    Ok(parse_image_list(&body))
+}
+
+fn parse_image_list(body: &str) -> Vec<String> {
+    body.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum PullStatus {
+    Pulled,
+    Present,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+struct PullOutcome {
+    image: String,
+    status: PullStatus,
+    seconds: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// `meda pull` with several images: pull up to `parallel` at once and
+/// report on all of them, failing if any failed.
+pub async fn pull_many(
+    config: &Config,
+    images: &[String],
+    registry: Option<&str>,
+    org: Option<&str>,
+    parallel: usize,
+    json: bool,
+) -> Result<()> {
+    let mut refs: Vec<ImageRef> = Vec::new();
+    for image in images {
+        let image_ref = ImageRef::parse(
+            image,
+            registry.unwrap_or(&config.default_registry),
+            org.unwrap_or(&config.default_org),
+        )?;
+        if !refs.iter().any(|r| r.url() == image_ref.url()) {
+            refs.push(image_ref);
+        }
+    }
+    if refs.is_empty() {
+        return Err(Error::Other("no images to pull".to_string()));
+    }
+    // Install ORAS once up front rather than racing to download it.
+    if !refs.iter().all(|r| is_pulled(config, r)) {
+        crate::oras::ensure(config).await?;
+    }
+    if !json {
+        println!(
+            "📥 Pulling {} images ({} at a time)",
+            refs.len(),
+            parallel.max(1)
+        );
+    }
+
+    let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(parallel.max(1)));
+    let mut handles = Vec::new();
+    for image_ref in refs {
+        let config = config.clone();
+        let semaphore = semaphore.clone();
+        handles.push(tokio::spawn(
+            crate::progress::inherit(async move {
+                let _permit = semaphore.acquire_owned().await.ok();
+                let started = std::time::Instant::now();
+                let result = pull_ref(&config, &image_ref, true).await;
+                let (status, error) = match result {
+                    Ok(true) => (PullStatus::Pulled, None),
+                    Ok(false) => (PullStatus::Present, None),
+                    Err(e) => (PullStatus::Failed, Some(e.to_string())),
+                };
+                let outcome = PullOutcome {
+                    image: image_ref.url(),
+                    status,
+                    seconds: started.elapsed().as_secs(),
+                    error,
+                };
+                if !json {
+                    match &outcome.status {
+                        PullStatus::Pulled => {
+                            println!("✅ {} ({}s)", outcome.image, outcome.seconds)
+                        }
+                        PullStatus::Present => println!("✅ {} (already present)", outcome.image),
+                        PullStatus::Failed => println!(
+                            "❌ {}: {}",
+                            outcome.image,
+                            outcome.error.as_deref().unwrap_or_default()
+                        ),
+                    }
+                }
+                outcome
+            })
+            .in_current_span(),
+        ));
+    }
+
+    let mut outcomes = Vec::new();
+    for handle in handles {
+        outcomes.push(
+            handle
+                .await
+                .map_err(|e| Error::Other(format!("pull task panicked: {}", e)))?,
+        );
+    }
+    let count =
+        |status: fn(&PullStatus) -> bool| outcomes.iter().filter(|o| status(&o.status)).count();
+    let pulled = count(|s| matches!(s, PullStatus::Pulled));
+    let present = count(|s| matches!(s, PullStatus::Present));
+    let failed = count(|s| matches!(s, PullStatus::Failed));
+
+    if json {
+        let summary = serde_json::json!({
+            "success": failed == 0,
+            "pulled": pulled,
+            "present": present,
+            "failed": failed,
+            "images": outcomes,
+        });
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    } else {
+        println!(
+            "\n📊 {} pulled, {} already present, {} failed",
+            pulled, present, failed
+        );
+    }
+
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(Error::Other(format!(
+            "{} of {} images failed to pull",
+            failed,
+            outcomes.len()
+        )))
+    }
+}
+
+/// Media type prefix of every layer `meda push` uploads.
+const ARTIFACT_MEDIA_PREFIX: &str = "application/vnd.cirunlabs.meda.";
+
+/// Artifact names are `base_image` in manifests but `base-image` in
+/// media types; compare them in the dashed form.
+fn normalize_artifact(name: &str) -> String {
+    name.replace('_', "-")
+}
+
+/// Artifact a pushed layer belongs to, from its media type:
+/// `...meda.base-image.v1`, `...meda.base-image-chunk.v1` and their
+/// `+zstd` variants all map to `base-image`.
+fn layer_artifact(media_type: &str) -> Option<String> {
+    let name = media_type
+        .strip_prefix(ARTIFACT_MEDIA_PREFIX)?
+        .trim_end_matches(ZSTD_MEDIA_SUFFIX)
+        .strip_suffix(".v1")?;
+    let name = name.strip_suffix("-chunk").unwrap_or(name);
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Manifest digest `reference` currently resolves to in its registry.
+async fn resolve_digest(
+    config: &Config,
+    oras: &crate::oras::Oras,
+    reference: &str,
+) -> Option<String> {
+    if !oras.has_resolve() {
+        return None;
+    }
+    let mut cmd = oras.command();
+    cmd.args(["resolve", reference]);
+    oras_auth_args(config, &mut cmd, reference);
+    let output = run_oras(&mut cmd, "resolve", false, |_| {}).await.ok()?;
+    let digest = output.stdout.trim().to_string();
+    digest.starts_with("sha256:").then_some(digest)
+}
+
+/// The manifest (or index) `reference` names, exactly as the registry
+/// sent it, checked against `digest` if given.
+async fn fetch_manifest(
+    config: &Config,
+    oras: &crate::oras::Oras,
+    reference: &str,
+    digest: Option<&str>,
+) -> Result<Vec<u8>> {
+    let manifest_file = tempfile::NamedTempFile::new()?;
+    let mut cmd = oras.command();
+    cmd.args(["manifest", "fetch", "--output"])
+        .arg(manifest_file.path())
+        .arg(reference);
+    oras_auth_args(config, &mut cmd, reference);
+    run_oras(&mut cmd, "manifest fetch", false, |_| {}).await?;
+    let body = fs::read(manifest_file.path())?;
+    if let Some(digest) = digest {
+        let actual = format!("sha256:{:x}", <sha2::Sha256 as sha2::Digest>::digest(&body));
+        if actual != digest {
+            return Err(Error::CorruptArtifact(format!(
+                "manifest of {} hashes to {}",
+                reference, actual
+            )));
+        }
+    }
+    Ok(body)
+}
+
+/// When `image_ref` names a manifest list, the digest of this host's
+/// image in it (see `platform`).
+async fn platform_manifest(
+    config: &Config,
+    oras: &crate::oras::Oras,
+    image_ref: &ImageRef,
+) -> Result<Option<String>> {
+    let body = fetch_manifest(config, oras, &image_ref.url(), image_ref.digest.as_deref()).await?;
+    let manifest: serde_json::Value = serde_json::from_slice(&body)?;
+    if !crate::platform::is_index(&manifest) {
+        return Ok(None);
+    }
+    crate::platform::select(&manifest, crate::platform::host_arch())
+        .map(Some)
+        .map_err(|e| Error::Other(format!("{}: {}", image_ref.url(), e)))
+}
+
+/// Check what ORAS pulled from `reference` into `dir` against `digest`:
+/// the manifest must hash to it, and every file to the digest its layer
+/// has in that manifest.
+async fn verify_pulled(
+    config: &Config,
+    oras: &crate::oras::Oras,
+    reference: &str,
+    digest: &str,
+    dir: &Path,
+) -> Result<()> {
+    let body = fetch_manifest(config, oras, reference, Some(digest)).await?;
+    let manifest: serde_json::Value = serde_json::from_slice(&body)?;
+    for layer in manifest["layers"].as_array().into_iter().flatten() {
+        let expected = layer["digest"].as_str().unwrap_or_default();
+        let Some(title) = layer["annotations"]["org.opencontainers.image.title"].as_str() else {
+            continue;
+        };
+        let path = dir.join(title);
+        if !path.is_file() {
+            return Err(Error::CorruptArtifact(format!(
+                "{}: layer {} was not downloaded",
+                reference, title
+            )));
+        }
+        let actual = format!("sha256:{}", crate::chunking::sha256_file(&path)?);
+        if actual != expected {
+            return Err(Error::CorruptArtifact(format!(
+                "{}: {} hashes to {}, manifest says {}",
+                reference, title, actual, expected
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Credentials for the registry of `reference`, if there are any.
+fn oras_auth_args(config: &Config, cmd: &mut tokio::process::Command, reference: &str) {
+    let registry = crate::credentials::registry_of(reference);
+    if let Some(credentials) = crate::credentials::lookup(config, registry) {
+        cmd.args(credentials.oras_args());
+    }
+}
+
+/// Run ORAS `action` without blocking the runtime. Its output is
+/// logged line by line as it comes (at info level when `echo`, so users
+/// see transfers progress; debug otherwise) and passed to `on_line`. A
+/// failure carries everything ORAS printed.
+async fn run_oras(
+    cmd: &mut tokio::process::Command,
+    action: &str,
+    echo: bool,
+    mut on_line: impl FnMut(&str),
+) -> Result<crate::util::StreamedOutput> {
+    let output = crate::util::run_streamed(cmd, |line| {
+        if echo {
+            info!("{}", line);
+        } else {
+            log::debug!("oras {}: {}", action, line);
+        }
+        on_line(line);
+    })
+    .await?;
+    if !output.status.success() {
+        return Err(Error::Other(format!(
+            "ORAS {} failed:\nSTDOUT: {}\nSTDERR: {}",
+            action, output.stdout, output.stderr
+        )));
+    }
+    Ok(output)
+}
+
+/// Pull only some of an image's artifacts (e.g. just `base_image` when
+/// the host already has the hypervisor binaries). Layers are selected
+/// by media type from the registry manifest and fetched one blob at a
+/// time, so nothing else is downloaded. The local manifest lists what
+/// is present; `available_artifacts` and `partial` record what isn't,
+/// and a later plain `meda pull` completes the image.
+pub async fn pull_artifacts(
+    config: &Config,
+    image: &str,
+    registry: Option<&str>,
+    org: Option<&str>,
+    artifacts: &[String],
+    json: bool,
+) -> Result<()> {
+    let image_ref = ImageRef::parse(
+        image,
+        registry.unwrap_or(&config.default_registry),
+        org.unwrap_or(&config.default_org),
+    )?;
+    let image_dir = image_ref.local_dir(config);
+    let existing = ImageManifest::load(&image_dir).ok();
+    let present: Vec<String> = existing
+        .iter()
+        .flat_map(|m| m.artifacts.keys().map(|k| normalize_artifact(k)))
+        .collect();
+    let wanted: Vec<String> = artifacts
+        .iter()
+        .map(|a| normalize_artifact(a))
+        .filter(|a| !present.contains(a))
+        .collect();
+
+    if wanted.is_empty() {
+        let message = format!(
+            "Image {} already has {}",
+            image_ref.url(),
+            artifacts.join(", ")
+        );
+        if json {
+            let result = ImageResult {
+                success: true,
+                message,
+            };
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        } else {
+            println!("✅ {}", message);
+        }
+        return Ok(());
+    }
+
+    let oras = crate::oras::ensure(config).await?;
+    let image_ref_str = image_ref.url();
+    if !json {
+        println!("📥 Pulling {} from {}", wanted.join(", "), image_ref_str);
+    }
+
+    let remote = match platform_manifest(config, &oras, &image_ref).await? {
+        Some(digest) => {
+            let reference = format!("{}@{}", image_ref.repository(), digest);
+            fetch_manifest(config, &oras, &reference, Some(&digest)).await?
+        }
+        None => fetch_manifest(config, &oras, &image_ref_str, image_ref.digest.as_deref()).await?,
+    };
+    let remote: serde_json::Value = serde_json::from_slice(&remote)?;
+    let layers = remote["layers"].as_array().cloned().unwrap_or_default();
+
+    let mut available: Vec<String> = layers
+        .iter()
+        .filter_map(|l| layer_artifact(l["mediaType"].as_str().unwrap_or_default()))
+        .collect();
+    available.sort();
+    available.dedup();
+    let unknown: Vec<&String> = wanted.iter().filter(|w| !available.contains(w)).collect();
+    if !unknown.is_empty() {
+        return Err(Error::Other(format!(
+            "Image {} has no {} artifact (available: {})",
+            image_ref_str,
+            unknown
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            available.join(", ")
+        )));
+    }
+
+    let temp_dir = tempfile::Builder::new().prefix("meda-pull-").tempdir()?;
+    let repository = &image_ref.repository();
+    for layer in &layers {
+        let Some(artifact) = layer_artifact(layer["mediaType"].as_str().unwrap_or_default()) else {
+            continue;
+        };
+        if !wanted.contains(&artifact) {
+            continue;
+        }
+        let digest = layer["digest"].as_str().unwrap_or_default();
+        let title = layer["annotations"]["org.opencontainers.image.title"]
+            .as_str()
+            .ok_or_else(|| Error::Other(format!("layer {} has no file name", digest)))?;
+        // Titles are relative paths chosen at push time; keep only the
+        // file name so a hostile manifest can't write outside temp_dir.
+        let file_name = Path::new(title)
+            .file_name()
+            .ok_or_else(|| Error::Other(format!("invalid layer title '{}'", title)))?;
+        let dest = temp_dir.path().join(file_name);
+        if !json {
+            println!("🔽 {} ({})", title, digest);
+        }
+        let transfer = Progress::bytes("oras-pull", &artifact, layer["size"].as_u64());
+        let mut cmd = oras.command();
+        cmd.args(["blob", "fetch", "--output"])
+            .arg(&dest)
+            .arg(format!("{}@{}", repository, digest));
+        oras_auth_args(config, &mut cmd, repository);
+        run_oras(&mut cmd, &format!("blob fetch {}", digest), false, |_| {}).await?;
+        transfer.finish(None);
+    }
+
+    convert_oras_artifacts_to_meda(temp_dir.path(), &image_dir, &image_ref, json).await?;
+
+    // Conversion wrote a manifest with just the new files; fold the
+    // previously present ones back in.
+    let mut manifest = ImageManifest::load(&image_dir)?;
+    if let Some(old) = existing {
+        for (artifact, file) in old.artifacts {
+            manifest.artifacts.entry(artifact).or_insert(file);
+        }
+    }
+    let have: Vec<String> = manifest
+        .artifacts
+        .keys()
+        .map(|k| normalize_artifact(k))
+        .collect();
+    manifest
+        .metadata
+        .insert("available_artifacts".to_string(), available.join(","));
+    if available.iter().all(|a| have.contains(a)) {
+        manifest.metadata.remove("partial");
+    } else {
+        manifest
+            .metadata
+            .insert("partial".to_string(), "true".to_string());
+    }
+    manifest.save(&image_dir)?;
 
+    let message = format!("Pulled {} of image {}", wanted.join(", "), image_ref_str);
     if json {
         let result = ImageResult {
             success: true,
@@ -462,10 +1179,32 @@ pub async fn pull(
     } else {
         println!("✅ {}", message);
     }
-
     Ok(())
 }
 
+/// What `push` adds beyond the image's artifacts (see `provenance`).
+#[derive(Debug, Clone, Default)]
+pub struct PushOptions {
+    /// Annotate the manifest with where the image came from
+    pub provenance: bool,
+    /// Sign the pushed digest and attach the signature to it
+    pub sign: bool,
+    /// More tags in the target repository for the same manifest
+    pub also_tags: Vec<String>,
+}
+
+/// An OCI tag: up to 128 of `[A-Za-z0-9_.-]`, not starting with `.`/`-`.
+fn is_valid_tag(tag: &str) -> bool {
+    tag.len() <= 128
+        && tag
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
 /// Push an image to a registry using OCI client
 pub async fn push(
     config: &Config,
@@ -473,12 +1212,26 @@ pub async fn push(
     image: &str,
     registry: Option<&str>,
     dry_run: bool,
+    options: PushOptions,
     json: bool,
 ) -> Result<()> {
-    let default_registry = registry.unwrap_or("ghcr.io");
+    let default_registry = registry.unwrap_or(&config.default_registry);
 
     // Parse the target image reference
-    let target_ref = ImageRef::parse(image, default_registry, "cirunlabs")?;
+    let target_ref = ImageRef::parse(image, default_registry, &config.default_org)?;
+    if target_ref.digest.is_some() {
+        return Err(Error::Other(format!(
+            "Push to a tag, not a digest: {}",
+            image
+        )));
+    }
+    if let Some(tag) = options.also_tags.iter().find(|t| !is_valid_tag(t)) {
+        return Err(Error::InvalidImageName(format!(
+            "{}:{}",
+            target_ref.repository(),
+            tag
+        )));
+    }
 
     if !json {
         info!("Push target: {}", target_ref.url());
@@ -529,15 +1282,43 @@ pub async fn push(
     let source_dir = found_image
         .ok_or_else(|| Error::ImageNotFound(format!("Local image '{}' not found", name)))?;
 
+    let _image_lock = lock_image_shared(&source_dir, name)?;
     let manifest = ImageManifest::load(&source_dir)?;
+    let mut annotations = if options.provenance {
+        crate::provenance::annotations(&manifest, |var| std::env::var(var).ok())
+    } else {
+        BTreeMap::new()
+    };
+    let arch = manifest
+        .metadata
+        .get("arch")
+        .map_or(crate::platform::host_arch(), |a| {
+            crate::platform::oci_arch(a)
+        });
+    annotations.insert(
+        crate::platform::ARCH_ANNOTATION.to_string(),
+        arch.to_string(),
+    );
+    let also_tagged = if options.also_tags.is_empty() {
+        String::new()
+    } else {
+        format!(" (also tagged {})", options.also_tags.join(", "))
+    };
 
     if dry_run {
         let message = format!(
-            "Would push image {} (created: {}) to {}",
+            "Would push image {} (created: {}) to {}{}{}",
             name,
             manifest.created,
-            target_ref.url()
+            target_ref.url(),
+            also_tagged,
+            if options.sign { ", signed" } else { "" }
         );
+        if !json {
+            for (key, value) in &annotations {
+                info!("  {}={}", key, value);
+            }
+        }
         if json {
             let result = ImageResult {
                 success: true,
@@ -550,15 +1331,19 @@ pub async fn push(
         return Ok(());
     }
 
-    // Get GitHub token from environment
-    let github_token = env::var("GITHUB_TOKEN").map_err(|_| {
-        Error::Other("GITHUB_TOKEN environment variable not set. Please set it with: export GITHUB_TOKEN=your_token".to_string())
-    })?;
+    let credentials =
+        crate::credentials::lookup(config, &target_ref.registry).ok_or_else(|| {
+            Error::Other(format!(
+                "No credentials for {}. Run `meda login {}` or set GITHUB_TOKEN",
+                target_ref.registry, target_ref.registry
+            ))
+        })?;
 
     if !json {
         info!(
-            "Pushing to {} using GitHub token authentication",
-            target_ref.url()
+            "Pushing to {} as {}",
+            target_ref.url(),
+            credentials.username
         );
     }
 
@@ -568,13 +1353,32 @@ pub async fn push(
         &source_dir,
         &manifest,
         &target_ref,
-        &github_token,
+        &credentials,
+        &annotations,
         json,
     )
     .await
     {
-        Ok(_) => {
-            let message = format!("Successfully pushed image {} to {}", name, target_ref.url());
+        Ok(mut pushed) => {
+            pushed.tag(&options.also_tags).await?;
+            let mut message = format!(
+                "Successfully pushed image {} to {}{}",
+                name,
+                target_ref.url(),
+                also_tagged
+            );
+            if options.sign {
+                sign_pushed(
+                    config,
+                    &target_ref,
+                    &credentials,
+                    &pushed.digest,
+                    &annotations,
+                    json,
+                )
+                .await?;
+                message.push_str(&format!(", signed {}", pushed.digest));
+            }
             if json {
                 let result = ImageResult {
                     success: true,
@@ -602,239 +1406,112 @@ pub async fn push(
     Ok(())
 }
 
-/// Push image artifacts to OCI registry using ORAS with chunking support
+/// Push image artifacts to the OCI registry, streamed (see `push`), with
+/// meda's annotations on the manifest.
 async fn push_to_oci_registry(
     config: &Config,
     source_dir: &Path,
     manifest: &ImageManifest,
     target_ref: &ImageRef,
-    github_token: &str,
+    credentials: &crate::credentials::Credentials,
+    annotations: &BTreeMap<String, String>,
     json: bool,
-) -> Result<()> {
-    if !json {
-        println!("🔧 Using ORAS to push to registry with chunking support");
+) -> Result<crate::push::Pushed> {
+    // Per-file messages would interleave with --progress json events
+    let quiet = json || crate::progress::json_mode();
+    if !quiet {
+        println!(
+            "🚀 Pushing VM artifacts to {} ({}x concurrency)",
+            target_ref.url(),
+            config.chunking.get_push_concurrency()
+        );
     }
 
-    // Ensure ORAS is available
-    let oras_path = ensure_oras_available(config).await?;
-
-    // Target image reference
-    let image_ref_str = format!(
-        "{}/{}/{}:{}",
-        target_ref.registry, target_ref.org, target_ref.name, target_ref.tag
-    );
-
-    // Initialize file chunker
-    let chunker = FileChunker::with_config(config.chunking.clone());
-
-    // Create temporary directory for chunks
-    let temp_dir = std::env::temp_dir().join(format!(
-        "meda-push-chunks-{}",
+    let mut all = BTreeMap::new();
+    for (key, value) in &manifest.metadata {
+        all.insert(format!("meda.metadata.{}", key), value.clone());
+    }
+    all.insert("meda.created".to_string(), manifest.created.to_string());
+    all.insert("meda.name".to_string(), manifest.name.clone());
+    all.insert("meda.tag".to_string(), manifest.tag.clone());
+    all.extend(annotations.clone());
+    all.insert(
+        "org.cirunlabs.meda.upload-time".to_string(),
         std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap_or_default()
             .as_secs()
-    ));
-    fs::create_dir_all(&temp_dir)?;
-
-    // Process artifacts: analyze sizes, create chunks for large files
-    let mut files_to_push = Vec::new();
-    let mut chunk_metadata = HashMap::new();
-    let mut total_size = 0u64;
-
-    if !json {
-        println!("🚀 Preparing VM artifacts for {}", image_ref_str);
-    }
-
-    for (artifact_type, artifact_file) in &manifest.artifacts {
-        let artifact_path = source_dir.join(artifact_file);
-        if artifact_path.exists() {
-            let size = fs::metadata(&artifact_path)?.len();
-            total_size += size;
-
-            if !json {
-                println!(
-                    "📁 {}: {:.2} MB",
-                    artifact_type,
-                    size as f64 / 1024.0 / 1024.0
-                );
-            }
-
-            // Check if file should be chunked
-            if chunker.should_chunk_file(&artifact_path)? {
-                if !json {
-                    println!("🔪 File {} will be chunked", artifact_file);
-                }
-
-                // Chunk the file
-                let (metadata, chunks) = chunker.chunk_file(&artifact_path, &temp_dir, json)?;
-
-                // Add chunk files to push list with relative paths
-                for chunk in &chunks {
-                    let relative_path = chunk.chunk_path.strip_prefix(&temp_dir).unwrap();
-                    let file_arg = format!(
-                        "{}:application/vnd.cirunlabs.meda.{}-chunk.v1",
-                        relative_path.to_str().unwrap(),
-                        artifact_type.replace("_", "-")
-                    );
-                    files_to_push.push(file_arg);
-                }
-
-                // Store chunk metadata for annotations
-                chunk_metadata.insert(artifact_file.clone(), metadata);
-            } else {
-                // Create symlink in temp directory so it can be pushed with relative path
-                let temp_file_path = temp_dir.join(artifact_file);
-                if let Some(parent) = temp_file_path.parent() {
-                    fs::create_dir_all(parent)?;
-                }
-
-                // Remove existing symlink if any
-                if temp_file_path.exists() {
-                    fs::remove_file(&temp_file_path)?;
-                }
-
-                std::os::unix::fs::symlink(&artifact_path, &temp_file_path)?;
-
-                // Add file with relative path
-                let file_arg = format!(
-                    "{}:application/vnd.cirunlabs.meda.{}.v1",
-                    artifact_file,
-                    artifact_type.replace("_", "-")
-                );
-                files_to_push.push(file_arg);
-            }
-        }
-    }
+            .to_string(),
+    );
 
-    if !json {
-        println!(
-            "📊 Total size: {:.2} GB ({} files/chunks to upload)",
-            total_size as f64 / 1024.0 / 1024.0 / 1024.0,
-            files_to_push.len()
-        );
+    let pushed = crate::push::push(
+        config,
+        source_dir,
+        manifest,
+        target_ref,
+        credentials,
+        &all,
+        quiet,
+    )
+    .await?;
+    if !quiet {
+        println!("✅ Successfully pushed image to registry");
     }
+    Ok(pushed)
+}
 
-    // Build ORAS push command with all artifacts, chunks, and enhanced concurrency
-    let mut cmd = std::process::Command::new(&oras_path);
-    cmd.args([
-        "push",
-        &image_ref_str,
-        "--username",
-        "token",
-        "--password",
-        github_token,
-        "--artifact-type",
-        "application/vnd.cirunlabs.meda.vm.v1",
-        "--disable-path-validation",
-        "--concurrency",
-        &config.chunking.get_push_concurrency().to_string(),
-    ]);
-
-    // Set working directory to temp_dir so all file paths are relative
-    cmd.current_dir(&temp_dir);
+/// Sign manifest `digest`, just pushed as `target_ref`, and attach the
+/// signature to it as a cosign signature artifact.
+async fn sign_pushed(
+    config: &Config,
+    target_ref: &ImageRef,
+    credentials: &crate::credentials::Credentials,
+    digest: &str,
+    provenance: &BTreeMap<String, String>,
+    json: bool,
+) -> Result<()> {
+    use crate::provenance::{PAYLOAD_MEDIA_TYPE, SIGNATURE_ARTIFACT_TYPE};
 
-    // Add progress and verbose flags
+    let oras = crate::oras::ensure(config).await?;
+    let repository = target_ref.repository();
     if !json {
-        cmd.arg("--verbose");
-    } else {
-        cmd.arg("--no-tty");
+        println!("🔏 Signing {}@{}", repository, digest);
     }
 
-    // Add all files (original + chunks)
-    for file_arg in &files_to_push {
-        cmd.arg(file_arg);
-    }
+    // Removed on drop; unique per push so concurrent pushes don't share one.
+    let work_guard = tempfile::Builder::new().prefix("meda-sign-").tempdir()?;
+    let work_dir = work_guard.path();
+    let payload = crate::provenance::signing_payload(&repository, digest, provenance);
+    let payload_path = work_dir.join("payload.json");
+    fs::write(&payload_path, serde_json::to_vec(&payload)?)?;
+    let signature =
+        crate::provenance::sign_blob(&payload_path, |var| std::env::var(var).ok()).await?;
+    fs::write(
+        work_dir.join("annotations.json"),
+        serde_json::to_vec(&serde_json::json!({
+            "payload.json": signature.annotations()
+        }))?,
+    )?;
 
-    // Add manifest metadata as annotations
-    for (key, value) in &manifest.metadata {
-        cmd.args(["--annotation", &format!("meda.metadata.{}={}", key, value)]);
-    }
-
-    // Add chunking metadata as annotations
-    for filename in chunk_metadata.keys() {
-        cmd.args([
-            "--annotation",
-            &format!("org.cirunlabs.meda.original-filename={}", filename),
-        ]);
-        cmd.args([
-            "--annotation",
-            &format!(
-                "org.cirunlabs.meda.chunked-files={}",
-                chunk_metadata
-                    .keys()
-                    .map(|s| s.as_str())
-                    .collect::<Vec<_>>()
-                    .join(",")
-            ),
-        ]);
-    }
-
-    // Add creation timestamp
-    cmd.args([
-        "--annotation",
-        &format!("meda.created={}", manifest.created),
-    ]);
-    cmd.args(["--annotation", &format!("meda.name={}", manifest.name)]);
-    cmd.args(["--annotation", &format!("meda.tag={}", manifest.tag)]);
+    let mut cmd = oras.command();
     cmd.args([
-        "--annotation",
-        &format!(
-            "org.cirunlabs.meda.upload-time={}",
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs()
-        ),
+        "attach",
+        "--artifact-type",
+        SIGNATURE_ARTIFACT_TYPE,
+        "--annotation-file",
+        "annotations.json",
     ]);
-
+    cmd.args(credentials.oras_args());
+    cmd.arg(format!("{}@{}", repository, digest));
+    cmd.arg(format!("payload.json:{}", PAYLOAD_MEDIA_TYPE));
+    cmd.current_dir(work_dir);
+    run_oras(&mut cmd, "attach", false, |_| {}).await?;
     if !json {
-        println!(
-            "🔄 Uploading artifacts with ORAS ({}x concurrency, leveraging concurrent chunk uploads)...",
-            config.chunking.get_push_concurrency()
-        );
-
-        // Use spawn to show real-time progress
-        let mut child = cmd.spawn()?;
-        let status = child.wait()?;
-
-        if !status.success() {
-            // Clean up temp directory on failure
-            fs::remove_dir_all(&temp_dir).ok();
-            return Err(Error::Other("ORAS push failed".to_string()));
-        }
-
-        println!("✅ Successfully pushed image to registry");
-    } else {
-        let output = cmd.output()?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            // Clean up temp directory on failure
-            fs::remove_dir_all(&temp_dir).ok();
-            return Err(Error::Other(format!(
-                "ORAS push failed:\nSTDOUT: {}\nSTDERR: {}",
-                stdout, stderr
-            )));
-        }
+        println!("✅ Attached signature to {}@{}", repository, digest);
     }
-
-    // Clean up temporary chunk files
-    fs::remove_dir_all(&temp_dir).ok();
-
     Ok(())
 }
 
-/// Ensure ORAS binary is available, using existing one if present
-async fn ensure_oras_available(config: &Config) -> Result<PathBuf> {
-    // Bootstrap binaries which will download ORAS if needed
-    crate::vm::bootstrap_binaries_only(config).await?;
-
-    // Return the path to the ORAS binary
-    Ok(config.oras_bin.clone())
-}
-
 /// Convert ORAS downloaded artifacts to Meda image format with chunk reassembly
 async fn convert_oras_artifacts_to_meda(
     scan_dir: &Path,
@@ -892,6 +1569,10 @@ async fn convert_oras_artifacts_to_meda(
         chunker.cleanup_chunks(chunks)?;
     }
 
+    // Artifacts pushed with MEDA_IMAGE_COMPRESSION=zstd, reassembled or not
+    crate::compression::decompress_all(image_dir, false)?;
+    crate::compression::decompress_all(scan_dir, true)?;
+
     // Scan for regular (non-chunked) files and process them
     let mut artifacts = HashMap::new();
     let mut total_size = 0u64;
@@ -993,6 +1674,9 @@ async fn convert_oras_artifacts_to_meda(
 
     // Add reassembled files to artifacts
     for (original_filename, (metadata, _)) in &detected_chunks {
+        let original_filename = original_filename
+            .strip_suffix(".zst")
+            .unwrap_or(original_filename);
         let artifact_type = if original_filename.contains("base")
             || original_filename.ends_with(".raw")
         {
@@ -1018,7 +1702,9 @@ async fn convert_oras_artifacts_to_meda(
         };
 
         artifacts.insert(artifact_type.to_string(), dest_file.to_string());
-        total_size += metadata.total_size;
+        total_size += fs::metadata(image_dir.join(original_filename))
+            .map(|m| m.len())
+            .unwrap_or(metadata.total_size);
     }
 
     // Check if we found any artifacts
@@ -1071,6 +1757,7 @@ async fn convert_oras_artifacts_to_meda(
 
     // Create Meda manifest
     let manifest = ImageManifest {
+        schema_version: crate::schema::IMAGE_SCHEMA_VERSION,
         name: image_ref.name.clone(),
         tag: image_ref.tag.clone(),
         registry: image_ref.registry.clone(),
@@ -1081,6 +1768,7 @@ async fn convert_oras_artifacts_to_meda(
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap_or_default()
             .as_secs(),
+        history: Vec::new(),
     };
 
     // Save manifest
@@ -1150,6 +1838,8 @@ async fn create_manifest_from_tag_directory(
         chunker.cleanup_chunks(chunks)?;
     }
 
+    crate::compression::decompress_all(image_dir, false)?;
+
     let mut artifacts = HashMap::new();
     let mut total_size = 0u64;
 
@@ -1203,6 +1893,9 @@ async fn create_manifest_from_tag_directory(
     for (original_filename, (_metadata, _)) in &detected_chunks {
         // The reassembled files should have already been counted in the scan above,
         // but let's make sure the total size is correct
+        let original_filename = original_filename
+            .strip_suffix(".zst")
+            .unwrap_or(original_filename);
         let artifact_type = if original_filename.contains("base")
             || original_filename.ends_with(".raw")
         {
@@ -1253,6 +1946,7 @@ async fn create_manifest_from_tag_directory(
 
     // Create Meda manifest
     let manifest = ImageManifest {
+        schema_version: crate::schema::IMAGE_SCHEMA_VERSION,
         name: image_ref.name.clone(),
         tag: image_ref.tag.clone(),
         registry: image_ref.registry.clone(),
@@ -1263,6 +1957,7 @@ async fn create_manifest_from_tag_directory(
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap_or_default()
             .as_secs(),
+        history: Vec::new(),
     };
 
     // Save manifest
@@ -1294,8 +1989,8 @@ pub async fn remove(
     force: bool,
     json: bool,
 ) -> Result<()> {
-    let default_registry = registry.unwrap_or("ghcr.io");
-    let default_org = org.unwrap_or("cirunlabs");
+    let default_registry = registry.unwrap_or(&config.default_registry);
+    let default_org = org.unwrap_or(&config.default_org);
 
     let image_ref = ImageRef::parse(image, default_registry, default_org)?;
     let image_dir = image_ref.local_dir(config);
@@ -1344,13 +2039,24 @@ pub async fn remove(
     }
 
     // Remove the entire image directory
+    let lock_path = tag_lock_path(&image_dir);
+    let _image_lock = lock_image_exclusive(&image_dir, &image_ref.url())?;
+    let freed = freed_size(&[&image_dir])?;
     fs::remove_dir_all(&image_dir)?;
+    let _ = fs::remove_file(&lock_path);
 
-    let message = format!(
-        "Removed image {} ({:.2} MB)",
-        image_ref.url(),
-        total_size as f64 / 1024.0 / 1024.0
-    );
+    let message = if freed < total_size {
+        format!(
+            "Untagged image {} (artifacts are still used by other tags)",
+            image_ref.url()
+        )
+    } else {
+        format!(
+            "Removed image {} ({:.2} MB)",
+            image_ref.url(),
+            freed as f64 / 1024.0 / 1024.0
+        )
+    };
 
     if json {
         let result = ImageResult {
@@ -1365,23 +2071,17 @@ pub async fn remove(
     Ok(())
 }
 
-/// List cached images
-pub async fn list(config: &Config, json: bool) -> Result<()> {
+/// All locally cached images (`images/<registry>/<org>/<name>/<tag>`),
+/// as `meda images` prints them and `GET /api/v1/images` returns them.
+pub fn collect_images(config: &Config) -> Result<Vec<ImageInfo>> {
     config.ensure_dirs()?;
 
     let images_dir = config.asset_dir.join("images");
-
+    let mut images = Vec::new();
     if !images_dir.exists() {
-        if json {
-            println!("[]");
-        } else {
-            info!("No images found");
-        }
-        return Ok(());
+        return Ok(images);
     }
 
-    let mut images = Vec::new();
-
     // Walk through registry/org/name/tag structure
     for registry_entry in fs::read_dir(&images_dir)? {
         let registry_entry = registry_entry?;
@@ -1427,6 +2127,7 @@ pub async fn list(config: &Config, json: bool) -> Result<()> {
                                             crate::util::format_timestamp(manifest.created);
 
                                         images.push(ImageInfo {
+                                            digest: manifest.digest().cloned(),
                                             name: manifest.name,
                                             tag: manifest.tag,
                                             registry: registry_name.clone(),
@@ -1443,20 +2144,45 @@ pub async fn list(config: &Config, json: bool) -> Result<()> {
         }
     }
 
+    Ok(images)
+}
+
+/// List cached images
+pub async fn list(config: &Config, digests: bool, json: bool) -> Result<()> {
+    print_list(&collect_images(config)?, digests, json)
+}
+
+/// `meda images`' output for `images`, with a digest column if `digests`.
+pub fn print_list(images: &[ImageInfo], digests: bool, json: bool) -> Result<()> {
     if json {
         println!("{}", serde_json::to_string_pretty(&images)?);
     } else if images.is_empty() {
         info!("No images found");
     } else {
+        let digest = |image: Option<&ImageInfo>| match image {
+            _ if !digests => String::new(),
+            None => format!(" {:<71}", "digest"),
+            Some(image) => format!(" {:<71}", image.digest.as_deref().unwrap_or("<none>")),
+        };
         println!(
-            "{:<20} {:<10} {:<15} {:<12} {:<20}",
-            "name", "tag", "registry", "size", "created"
+            "{:<20} {:<10}{} {:<15} {:<12} {:<20}",
+            "name",
+            "tag",
+            digest(None),
+            "registry",
+            "size",
+            "created"
         );
-        println!("{}", "-".repeat(85));
+        println!("{}", "-".repeat(if digests { 157 } else { 85 }));
         for image in images {
             println!(
-                "{:<20} {:<10} {:<15} {:<12} {:<20}",
-                image.name, image.tag, image.registry, image.size, image.created
+                "{:<20} {:<10}{} {:<15} {:<12} {:<20}",
+                image.name,
+                image.tag,
+                digest(Some(image)),
+                image.registry,
+                image.size,
+                image.created
             );
         }
     }
@@ -1464,67 +2190,227 @@ pub async fn list(config: &Config, json: bool) -> Result<()> {
     Ok(())
 }
 
-/// Remove unused images
-pub async fn prune(config: &Config, all: bool, force: bool, json: bool) -> Result<()> {
-    config.ensure_dirs()?;
+/// An image `meda prune` removes (or would remove).
+#[derive(Debug, Serialize)]
+pub struct PrunedImage {
+    pub image: String,
+    pub size_bytes: u64,
+    pub created: u64,
+    #[serde(skip)]
+    dir: PathBuf,
+}
 
-    let images_dir = config.asset_dir.join("images");
+#[derive(Debug, Serialize)]
+pub struct PruneReport {
+    success: bool,
+    pub message: String,
+    pub dry_run: bool,
+    freed_bytes: u64,
+    images: Vec<PrunedImage>,
+}
 
-    if !images_dir.exists() {
-        let message = "No images directory found".to_string();
-        if json {
-            let result = ImageResult {
-                success: true,
-                message,
-            };
-            println!("{}", serde_json::to_string_pretty(&result)?);
-        } else {
-            info!("{}", message);
+/// Image tag dirs some VM still uses: recorded in its metadata, or in
+/// the backing chain of its root disk (VMs from before images were
+/// recorded, and clones of templates). Paths are canonical.
+fn images_in_use(config: &Config, image_dirs: &[PathBuf]) -> HashSet<PathBuf> {
+    let image_dirs: Vec<PathBuf> = image_dirs
+        .iter()
+        .filter_map(|d| fs::canonicalize(d).ok())
+        .collect();
+    let mut in_use = HashSet::new();
+    let Ok(entries) = fs::read_dir(&config.vm_root) else {
+        return in_use;
+    };
+    for vm_dir in entries.flatten().map(|e| e.path()) {
+        if let Some(image) = crate::labels::VmMetadata::load(&vm_dir)
+            .ok()
+            .and_then(|m| m.image)
+        {
+            if let Ok(image_ref) =
+                ImageRef::parse(&image, &config.default_registry, &config.default_org)
+            {
+                if let Ok(dir) = fs::canonicalize(image_ref.local_dir(config)) {
+                    in_use.insert(dir);
+                }
+            }
+        }
+        if let Some((rootfs, _)) = DiskFormat::detect(&vm_dir) {
+            for backing in crate::util::backing_chain(&rootfs) {
+                let Ok(backing) = fs::canonicalize(&backing) else {
+                    continue;
+                };
+                if let Some(dir) = image_dirs.iter().find(|d| backing.starts_with(d)) {
+                    in_use.insert(dir.clone());
+                }
+            }
         }
-        return Ok(());
     }
+    in_use
+}
 
-    let mut removed_count = 0;
-    let mut total_size = 0u64;
+/// Images to prune: unreferenced ones (every one with `all`), created
+/// at least `older_than` seconds before `now`.
+fn prune_candidates(
+    config: &Config,
+    all: bool,
+    older_than: Option<u64>,
+    now: u64,
+) -> Result<Vec<PrunedImage>> {
+    let images_dir = config.asset_dir.join("images");
+    let image_dirs = collect_image_dirs(&images_dir);
+    let in_use = if all {
+        HashSet::new()
+    } else {
+        images_in_use(config, &image_dirs)
+    };
 
-    // For now, if --all is specified, remove all images
-    // TODO: Implement logic to detect unused images (not referenced by any VM)
-    if all {
-        if !force && !json {
-            info!("Use --force to actually remove all images");
-            return Ok(());
+    let mut candidates = Vec::new();
+    for dir in image_dirs {
+        if fs::canonicalize(&dir).is_ok_and(|d| in_use.contains(&d)) {
+            continue;
+        }
+        let manifest = ImageManifest::load(&dir).ok();
+        let created = manifest.as_ref().map(|m| m.created).unwrap_or_else(|| {
+            fs::metadata(&dir)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+        if older_than.is_some_and(|age| now.saturating_sub(created) < age) {
+            continue;
         }
+        let image = match manifest {
+            Some(m) => format!("{}/{}/{}:{}", m.registry, m.org, m.name, m.tag),
+            None => dir
+                .strip_prefix(&images_dir)
+                .unwrap_or(&dir)
+                .display()
+                .to_string(),
+        };
+        candidates.push(PrunedImage {
+            image,
+            size_bytes: calculate_directory_size(&dir)?,
+            created,
+            dir,
+        });
+    }
+    candidates.sort_by(|a, b| a.image.cmp(&b.image));
+    Ok(candidates)
+}
 
-        // Remove entire images directory
-        if let Ok(_metadata) = fs::metadata(&images_dir) {
-            total_size = calculate_directory_size(&images_dir)?;
+/// Remove images no VM was created from (every image with `all`),
+/// optionally only those older than `older_than` seconds. Without
+/// `force`, interactive runs only report what would go.
+/// `meda prune`: remove unused images with `force`, otherwise only
+/// report what would go.
+pub async fn prune(
+    config: &Config,
+    all: bool,
+    older_than: Option<u64>,
+    force: bool,
+    json: bool,
+) -> Result<()> {
+    let report = prune_images(config, all, older_than, force)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for image in &report.images {
+            println!(
+                "  {:<50} {:>10.2} MB",
+                image.image,
+                image.size_bytes as f64 / 1024.0 / 1024.0
+            );
         }
+        println!("{}", report.message);
+        if report.dry_run && !report.images.is_empty() {
+            println!("Use --force to remove them");
+        }
+    }
+    Ok(())
+}
 
-        fs::remove_dir_all(&images_dir)?;
-        removed_count = 1; // Simplified count
+/// Remove the images `prune` picks, or with `force` unset only say
+/// which it would.
+pub fn prune_images(
+    config: &Config,
+    all: bool,
+    older_than: Option<u64>,
+    force: bool,
+) -> Result<PruneReport> {
+    config.ensure_dirs()?;
 
-        if !json {
-            info!("Removed all images");
+    let images_dir = config.asset_dir.join("images");
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let images = prune_candidates(config, all, older_than, now)?;
+    let dirs: Vec<&Path> = images.iter().map(|i| i.dir.as_path()).collect();
+    let freed_bytes = freed_size(&dirs)?;
+    let dry_run = !force;
+
+    if !dry_run {
+        // Hold every pruned tag's writer lock for the duration so no run
+        // or push can start reading while it disappears.
+        let mut locks = Vec::new();
+        let mut in_use = Vec::new();
+        for image in &images {
+            match lock_image_exclusive(&image.dir, &image.image) {
+                Ok(lock) => locks.push(lock),
+                Err(Error::ImageInUse(..)) => in_use.push(image.image.clone()),
+                Err(e) => return Err(e),
+            }
+        }
+        if !in_use.is_empty() {
+            return Err(Error::ImageInUse(
+                in_use.join(", "),
+                "a run or push is reading it; nothing was pruned".to_string(),
+            ));
+        }
+
+        for image in &images {
+            fs::remove_dir_all(&image.dir)?;
+            let _ = fs::remove_file(tag_lock_path(&image.dir));
+            // Drop now-empty name/org/registry dirs.
+            for parent in image.dir.ancestors().skip(1) {
+                if parent == images_dir || fs::remove_dir(parent).is_err() {
+                    break;
+                }
+            }
         }
     }
 
     let message = format!(
-        "Removed {} image(s), freed {:.2} MB",
-        removed_count,
-        total_size as f64 / 1024.0 / 1024.0
+        "{} {} image(s), {} {:.2} MB",
+        if dry_run { "Would remove" } else { "Removed" },
+        images.len(),
+        if dry_run { "freeing" } else { "freed" },
+        freed_bytes as f64 / 1024.0 / 1024.0
     );
 
-    if json {
-        let result = ImageResult {
-            success: true,
-            message,
-        };
-        println!("{}", serde_json::to_string_pretty(&result)?);
-    } else {
-        info!("{}", message);
-    }
+    Ok(PruneReport {
+        success: true,
+        message,
+        dry_run,
+        freed_bytes,
+        images,
+    })
+}
 
-    Ok(())
+/// Every `<registry>/<org>/<name>/<tag>` directory under `images_dir`.
+fn collect_image_dirs(images_dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![images_dir.to_path_buf()];
+    for _ in 0..4 {
+        dirs = dirs
+            .iter()
+            .filter_map(|d| fs::read_dir(d).ok())
+            .flat_map(|entries| entries.flatten().map(|e| e.path()))
+            .filter(|p| p.is_dir())
+            .collect();
+    }
+    dirs
 }
 
 fn calculate_directory_size(dir: &Path) -> Result<u64> {
@@ -1544,7 +2430,221 @@ fn calculate_directory_size(dir: &Path) -> Result<u64> {
     Ok(size)
 }
 
+/// Bytes that removing all of `dirs` gives back. Files hard-linked
+/// from a tag outside `dirs` (see [`tag`]) stay allocated, so they only
+/// count once every one of their links is among `dirs`.
+fn freed_size(dirs: &[&Path]) -> Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    fn walk(dir: &Path, seen: &mut HashMap<(u64, u64), (u64, u64)>) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let meta = fs::symlink_metadata(&path)?;
+            if meta.is_dir() {
+                walk(&path, seen)?;
+            } else if meta.is_file() {
+                let links = seen
+                    .entry((meta.dev(), meta.ino()))
+                    .or_insert((meta.nlink(), meta.len()));
+                links.0 -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    let mut seen = HashMap::new();
+    for dir in dirs {
+        walk(dir, &mut seen)?;
+    }
+    Ok(seen
+        .values()
+        .filter(|(remaining, _)| *remaining == 0)
+        .map(|(_, len)| len)
+        .sum())
+}
+
+/// Hard-link every file under `from` into `to`, recreating subdirs.
+fn link_tree(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let dest = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            link_tree(&entry.path(), &dest)?;
+        } else if entry.file_name() != "manifest.json" {
+            fs::hard_link(entry.path(), dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// `meda tag`: give a local image another name. The new tag hard-links
+/// the source's artifacts, so it costs no space and the blobs are only
+/// freed once the last tag sharing them is removed.
+pub fn tag(
+    config: &Config,
+    source: &str,
+    target: &str,
+    registry: Option<&str>,
+    org: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let default_registry = registry.unwrap_or(&config.default_registry);
+    let default_org = org.unwrap_or(&config.default_org);
+    let source_ref = ImageRef::parse(source, default_registry, default_org)?;
+    let target_ref = ImageRef::parse(target, default_registry, default_org)?;
+    let source_dir = source_ref.local_dir(config);
+    let target_dir = target_ref.local_dir(config);
+
+    let _source_lock = lock_image_shared(&source_dir, &source_ref.url())?;
+    let manifest =
+        ImageManifest::load(&source_dir).map_err(|_| Error::ImageNotFound(source_ref.url()))?;
+    if manifest.metadata.contains_key("partial") {
+        return Err(Error::Other(format!(
+            "Image {} is only partially pulled; run `meda pull {}` first",
+            source_ref.url(),
+            source
+        )));
+    }
+    if target_dir.exists() {
+        return Err(Error::ImageAlreadyExists(target_ref.url()));
+    }
+
+    let tagged = link_tree(&source_dir, &target_dir).and_then(|_| {
+        let mut metadata = manifest.metadata;
+        metadata.insert("tagged_from".to_string(), source_ref.url());
+        ImageManifest {
+            schema_version: crate::schema::IMAGE_SCHEMA_VERSION,
+            name: target_ref.name.clone(),
+            tag: target_ref.tag.clone(),
+            registry: target_ref.registry.clone(),
+            org: target_ref.org.clone(),
+            metadata,
+            ..manifest
+        }
+        .save(&target_dir)
+    });
+    if let Err(e) = tagged {
+        let _ = fs::remove_dir_all(&target_dir);
+        return Err(e);
+    }
+
+    let message = format!("Tagged {} as {}", source_ref.url(), target_ref.url());
+    if json {
+        let result = ImageResult {
+            success: true,
+            message,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        println!("✅ {}", message);
+    }
+    Ok(())
+}
+
+/// Format of a disk image file, from its header: `qcow2` or `raw`.
+fn disk_file_format(path: &Path) -> Result<&'static str> {
+    use std::io::Read;
+
+    let mut header = [0u8; 4];
+    let mut file = fs::File::open(path)?;
+    match file.read_exact(&mut header) {
+        Ok(()) if &header == b"QFI\xfb" => Ok("qcow2"),
+        Ok(()) => Ok("raw"),
+        Err(_) => Err(Error::Other(format!(
+            "{} is too small to be a disk image",
+            path.display()
+        ))),
+    }
+}
+
+/// `meda image import`: turn a qcow2 or raw disk built elsewhere
+/// (packer, virt-builder, a cloud image) into local image `name`.
+/// qcow2 disks are flattened to raw, backing files included, so the
+/// image doesn't depend on the original file.
+pub async fn import_disk(
+    config: &Config,
+    path: &Path,
+    name: &str,
+    os: Option<&str>,
+    arch: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let source = fs::canonicalize(path)
+        .map_err(|e| Error::Other(format!("Cannot read {}: {}", path.display(), e)))?;
+    if !source.is_file() {
+        return Err(Error::Other(format!("{} is not a file", source.display())));
+    }
+    let format = disk_file_format(&source)?;
+    let image_ref = ImageRef::parse(name, &config.default_registry, &config.default_org)?;
+    let image_dir = image_ref.local_dir(config);
+    if image_dir.exists() {
+        return Err(Error::ImageAlreadyExists(image_ref.url()));
+    }
+
+    if !json {
+        info!(
+            "Importing {} ({}) as {}",
+            source.display(),
+            format,
+            image_ref.url()
+        );
+    }
+    fs::create_dir_all(&image_dir)?;
+    // Converting raw to raw too: qemu-img writes it sparse.
+    let converted =
+        crate::qemu_img::convert(&source, format, &image_dir.join("base.raw"), "raw", json).await;
+    if let Err(e) = converted {
+        let _ = fs::remove_dir_all(&image_dir);
+        return Err(e);
+    }
+
+    let mut artifacts = HashMap::new();
+    artifacts.insert("base_image".to_string(), "base.raw".to_string());
+    let mut metadata = HashMap::new();
+    metadata.insert("created_by".to_string(), "meda".to_string());
+    metadata.insert("type".to_string(), "disk_import".to_string());
+    metadata.insert("source".to_string(), source.display().to_string());
+    metadata.insert("source_format".to_string(), format.to_string());
+    metadata.insert(
+        "arch".to_string(),
+        arch.unwrap_or(crate::platform::host_arch()).to_string(),
+    );
+    if let Some(os) = os {
+        metadata.insert("os".to_string(), os.to_string());
+    }
+    ImageManifest {
+        schema_version: crate::schema::IMAGE_SCHEMA_VERSION,
+        name: image_ref.name.clone(),
+        tag: image_ref.tag.clone(),
+        registry: image_ref.registry.clone(),
+        org: image_ref.org.clone(),
+        artifacts,
+        metadata,
+        created: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        history: Vec::new(),
+    }
+    .save(&image_dir)?;
+
+    let message = format!("Imported {} as {}", source.display(), image_ref.url());
+    if json {
+        let result = ImageResult {
+            success: true,
+            message,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!("{}", message);
+        info!("💡 Run it with 'meda run {}'", name);
+    }
+    Ok(())
+}
+
 /// Create an image from an existing VM
+#[allow(clippy::too_many_arguments)]
 pub async fn create_from_vm(
     config: &Config,
     vm_name: &str,
@@ -1552,6 +2652,7 @@ pub async fn create_from_vm(
     tag: &str,
     registry: &str,
     org: &str,
+    live: Option<LiveMode>,
     json: bool,
 ) -> Result<()> {
     let vm_dir = config.vm_dir(vm_name);
@@ -1559,125 +2660,553 @@ pub async fn create_from_vm(
         return Err(Error::VmNotFound(vm_name.to_string()));
     }
 
-    let vm_rootfs = if vm_dir.join("rootfs.qcow2").exists() {
-        vm_dir.join("rootfs.qcow2")
-    } else {
-        vm_dir.join("rootfs.raw")
-    };
-    if !vm_rootfs.exists() {
-        return Err(Error::Other(format!("VM {} rootfs not found", vm_name)));
+    let Some((mut vm_rootfs, rootfs_format)) = DiskFormat::detect(&vm_dir) else {
+        return Err(Error::Other(format!("VM {} rootfs not found", vm_name)));
+    };
+
+    // Check if VM is running and stop it if necessary, unless the caller
+    // asked for a live copy.
+    let mut consistency = "offline";
+    let mut live_copy = None;
+    if vm::check_vm_running(config, vm_name)? {
+        match live {
+            Some(mode) => {
+                // Snapshot next to the source so a reflink is possible.
+                let copy = vm_dir.join(format!(".live-{}", rootfs_format.rootfs_name()));
+                live_copy_rootfs(config, vm_name, &vm_rootfs, &copy, mode, json).await?;
+                vm_rootfs = copy.clone();
+                live_copy = Some(copy);
+                consistency = mode.consistency();
+            }
+            None => {
+                if !json {
+                    info!("Stopping VM {} before creating image...", vm_name);
+                }
+                vm::stop(config, vm_name, json).await?;
+
+                // Wait a moment for the VM to fully shut down
+                tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+            }
+        }
+    }
+
+    if !json {
+        info!("Creating image from VM: {}", vm_name);
+    }
+
+    let image_ref = ImageRef {
+        registry: registry.to_string(),
+        org: org.to_string(),
+        name: image_name.to_string(),
+        tag: tag.to_string(),
+        digest: None,
+    };
+
+    let image_dir = image_ref.local_dir(config);
+    // Replacing a tag: start from an empty dir rather than writing into
+    // files that may be hard links shared with other tags.
+    let _image_lock = if image_dir.exists() {
+        let lock = lock_image_exclusive(&image_dir, &image_ref.url())?;
+        fs::remove_dir_all(&image_dir)?;
+        Some(lock)
+    } else {
+        None
+    };
+    fs::create_dir_all(&image_dir)?;
+
+    // Convert VM rootfs to a standalone raw base image.
+    // If the rootfs is a qcow2 overlay, this flattens it (merges backing + overlay)
+    // so the image is self-contained. For raw rootfs this is a format-preserving copy.
+    let image_raw = image_dir.join("base.raw");
+    let input_format = rootfs_format.as_str();
+    let converted =
+        crate::qemu_img::convert(&vm_rootfs, input_format, &image_raw, "raw", json).await;
+    if let Some(copy) = &live_copy {
+        let _ = fs::remove_file(copy);
+    }
+    converted?;
+
+    // Note: VM disk is converted to raw to preserve all customizations.
+    // Machine-specific data like hostname and network config are handled
+    // when creating new VMs from the image.
+
+    let mut artifacts = HashMap::new();
+    artifacts.insert("base_image".to_string(), "base.raw".to_string());
+
+    // Copy other VM artifacts if they exist
+    if let Ok(entries) = fs::read_dir(&vm_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let file_name = path.file_name().unwrap().to_string_lossy();
+
+            match file_name.as_ref() {
+                "user-data" | "meta-data" | "network-config" => {
+                    let dest = image_dir.join(&*file_name);
+                    fs::copy(&path, &dest)?;
+                    artifacts.insert(file_name.to_string(), file_name.to_string());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Create metadata
+    let history = image_lineage(config, &vm_dir);
+    let mut metadata = HashMap::new();
+    if let Some(parent) = history.first() {
+        metadata.insert("parent".to_string(), parent.image.clone());
+    }
+    metadata.insert("source_vm".to_string(), vm_name.to_string());
+    metadata.insert("created_by".to_string(), "meda".to_string());
+    metadata.insert("type".to_string(), "vm_snapshot".to_string());
+    metadata.insert("consistency".to_string(), consistency.to_string());
+
+    let manifest = ImageManifest {
+        schema_version: crate::schema::IMAGE_SCHEMA_VERSION,
+        name: image_name.to_string(),
+        tag: tag.to_string(),
+        registry: registry.to_string(),
+        org: org.to_string(),
+        artifacts,
+        metadata,
+        created: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        history,
+    };
+
+    manifest.save(&image_dir)?;
+
+    let message = format!(
+        "Successfully created image {} from VM {}",
+        image_ref.url(),
+        vm_name
+    );
+    if json {
+        let result = ImageResult {
+            success: true,
+            message,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!("{}", message);
+    }
+
+    Ok(())
+}
+
+/// Lineage of the image a VM was created from: that image first, then
+/// its own recorded ancestors. Empty for VMs not created from an image.
+fn image_lineage(config: &Config, vm_dir: &Path) -> Vec<ImageAncestor> {
+    let Some(parent) = crate::labels::VmMetadata::load(vm_dir)
+        .ok()
+        .and_then(|m| m.image)
+    else {
+        return Vec::new();
+    };
+    let manifest = ImageRef::parse(&parent, &config.default_registry, &config.default_org)
+        .ok()
+        .and_then(|r| ImageManifest::load(&r.local_dir(config)).ok());
+    match manifest {
+        Some(manifest) => {
+            let mut lineage = vec![manifest.ancestor_entry(parent)];
+            lineage.extend(manifest.history);
+            lineage
+        }
+        // Pruned since; the reference is all that is left.
+        None => vec![ImageAncestor {
+            image: parent,
+            digest: None,
+            created: None,
+            source_vm: None,
+        }],
+    }
+}
+
+/// `meda image history`: an image and its ancestors, newest first.
+pub fn history(
+    config: &Config,
+    image: &str,
+    registry: Option<&str>,
+    org: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let image_ref = ImageRef::parse(
+        image,
+        registry.unwrap_or(&config.default_registry),
+        org.unwrap_or(&config.default_org),
+    )?;
+    let manifest = ImageManifest::load(&image_ref.local_dir(config))
+        .map_err(|_| Error::ImageNotFound(image_ref.url()))?;
+    let mut entries = vec![manifest.ancestor_entry(image_ref.url())];
+    entries.extend(manifest.history);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+    println!(
+        "{:<45} {:<20} {:<20} {:<20}",
+        "image", "created", "source vm", "digest"
+    );
+    println!("{}", "-".repeat(105));
+    for entry in &entries {
+        let digest = entry.digest.as_deref().unwrap_or("-");
+        println!(
+            "{:<45} {:<20} {:<20} {:<20}",
+            entry.image,
+            entry
+                .created
+                .map(crate::util::format_timestamp)
+                .unwrap_or_else(|| "unknown".to_string()),
+            entry.source_vm.as_deref().unwrap_or("-"),
+            // sha256: plus 12 hex digits, like `docker history`
+            &digest[..digest.len().min(19)]
+        );
+    }
+    Ok(())
+}
+
+/// Artifact digests computed by `meda image inspect`, cached in the tag
+/// dir and keyed by file so unchanged multi-GB disks aren't rehashed.
+pub(crate) const DIGEST_CACHE: &str = ".digests.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedDigest {
+    size: u64,
+    modified: u64,
+    sha256: String,
+}
+
+/// One artifact of an inspected image.
+#[derive(Debug, Serialize)]
+pub struct ArtifactInspect {
+    pub artifact: String,
+    pub file: String,
+    pub present: bool,
+    pub size_bytes: Option<u64>,
+    pub sha256: Option<String>,
+}
+
+/// Chunks of an artifact left in the tag dir (an interrupted pull).
+#[derive(Debug, Serialize)]
+pub struct LocalChunks {
+    pub file: String,
+    pub total_chunks: usize,
+    pub present_chunks: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChunkingInspect {
+    /// Artifacts that were pushed as chunks and reassembled on pull
+    pub chunked_files: Vec<String>,
+    pub local_chunks: Vec<LocalChunks>,
+}
+
+/// Everything `meda image inspect` (and `GET /api/v1/images/{image}`)
+/// reports about a local image.
+#[derive(Debug, Serialize)]
+pub struct ImageInspect {
+    pub image: String,
+    pub name: String,
+    pub tag: String,
+    pub registry: String,
+    pub org: String,
+    pub path: PathBuf,
+    pub created: u64,
+    pub digest: Option<String>,
+    pub size_bytes: u64,
+    pub artifacts: Vec<ArtifactInspect>,
+    pub metadata: BTreeMap<String, String>,
+    pub chunking: ChunkingInspect,
+    pub history: Vec<ImageAncestor>,
+}
+
+fn mtime_secs(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// SHA256 of each present artifact, reusing cached digests of files
+/// whose size and mtime haven't changed.
+fn artifact_digests(tag_dir: &Path, files: &[&str]) -> Result<HashMap<String, String>> {
+    let cache_path = tag_dir.join(DIGEST_CACHE);
+    let mut cache: HashMap<String, CachedDigest> = fs::read_to_string(&cache_path)
+        .ok()
+        .and_then(|body| serde_json::from_str(&body).ok())
+        .unwrap_or_default();
+    let mut changed = false;
+    let mut digests = HashMap::new();
+    for file in files {
+        let Ok(metadata) = fs::metadata(tag_dir.join(file)) else {
+            continue;
+        };
+        let (size, modified) = (metadata.len(), mtime_secs(&metadata));
+        let cached = cache
+            .get(*file)
+            .filter(|c| c.size == size && c.modified == modified);
+        let sha256 = match cached {
+            Some(c) => c.sha256.clone(),
+            None => {
+                let sha256 = format!(
+                    "sha256:{}",
+                    crate::chunking::sha256_file(&tag_dir.join(file))?
+                );
+                cache.insert(
+                    file.to_string(),
+                    CachedDigest {
+                        size,
+                        modified,
+                        sha256: sha256.clone(),
+                    },
+                );
+                changed = true;
+                sha256
+            }
+        };
+        digests.insert(file.to_string(), sha256);
+    }
+    cache.retain(|file, _| files.contains(&file.as_str()));
+    if changed {
+        // Only an optimization; a read-only image dir just means rehashing.
+        let _ = fs::write(&cache_path, serde_json::to_string_pretty(&cache)?);
+    }
+    Ok(digests)
+}
+
+/// Inspect a local image: its manifest plus the size, presence and
+/// SHA256 of every artifact, and any chunks left from a partial pull.
+pub fn inspect_image(
+    config: &Config,
+    image: &str,
+    registry: Option<&str>,
+    org: Option<&str>,
+) -> Result<ImageInspect> {
+    let image_ref = ImageRef::parse(
+        image,
+        registry.unwrap_or(&config.default_registry),
+        org.unwrap_or(&config.default_org),
+    )?;
+    let tag_dir = image_ref.local_dir(config);
+    let manifest =
+        ImageManifest::load(&tag_dir).map_err(|_| Error::ImageNotFound(image_ref.url()))?;
+    let _lock = lock_image_shared(&tag_dir, &image_ref.url())?;
+
+    let mut entries: Vec<(&String, &String)> = manifest.artifacts.iter().collect();
+    entries.sort();
+    let files: Vec<&str> = entries.iter().map(|(_, f)| f.as_str()).collect();
+    let digests = artifact_digests(&tag_dir, &files)?;
+    let artifacts: Vec<ArtifactInspect> = entries
+        .iter()
+        .map(|(artifact, file)| {
+            let size_bytes = fs::metadata(tag_dir.join(file)).ok().map(|m| m.len());
+            ArtifactInspect {
+                artifact: artifact.to_string(),
+                file: file.to_string(),
+                present: size_bytes.is_some(),
+                size_bytes,
+                sha256: digests.get(file.as_str()).cloned(),
+            }
+        })
+        .collect();
+
+    let mut local_chunks: Vec<LocalChunks> = FileChunker::new()
+        .detect_chunks(&tag_dir)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(file, (metadata, chunks))| LocalChunks {
+            file,
+            total_chunks: metadata.total_chunks,
+            present_chunks: chunks.len(),
+        })
+        .collect();
+    local_chunks.sort_by(|a, b| a.file.cmp(&b.file));
+
+    Ok(ImageInspect {
+        image: image_ref.url(),
+        digest: manifest
+            .metadata
+            .get("digest")
+            .or_else(|| manifest.metadata.get("oci_digest"))
+            .cloned(),
+        size_bytes: artifacts.iter().filter_map(|a| a.size_bytes).sum(),
+        chunking: ChunkingInspect {
+            chunked_files: manifest
+                .metadata
+                .get("chunked_files")
+                .map(|f| f.split(',').map(String::from).collect())
+                .unwrap_or_default(),
+            local_chunks,
+        },
+        path: tag_dir,
+        name: manifest.name,
+        tag: manifest.tag,
+        registry: manifest.registry,
+        org: manifest.org,
+        created: manifest.created,
+        artifacts,
+        metadata: manifest.metadata.into_iter().collect(),
+        history: manifest.history,
+    })
+}
+
+/// `meda image inspect`
+pub fn inspect(
+    config: &Config,
+    image: &str,
+    registry: Option<&str>,
+    org: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let report = inspect_image(config, image, registry, org)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    let mb = |bytes: u64| format!("{:.2} MB", bytes as f64 / 1024.0 / 1024.0);
+    println!("Image:    {}", report.image);
+    println!(
+        "Created:  {}",
+        crate::util::format_timestamp(report.created)
+    );
+    println!("Digest:   {}", report.digest.as_deref().unwrap_or("-"));
+    println!("Path:     {}", report.path.display());
+    println!("Size:     {}", mb(report.size_bytes));
+
+    println!("\nArtifacts:");
+    println!("  {:<18} {:<24} {:>12}  sha256", "artifact", "file", "size");
+    for a in &report.artifacts {
+        println!(
+            "  {:<18} {:<24} {:>12}  {}",
+            a.artifact,
+            a.file,
+            a.size_bytes
+                .map(mb)
+                .unwrap_or_else(|| "missing".to_string()),
+            a.sha256.as_deref().unwrap_or("-")
+        );
+    }
+
+    if !report.chunking.chunked_files.is_empty() || !report.chunking.local_chunks.is_empty() {
+        println!("\nChunking:");
+        if !report.chunking.chunked_files.is_empty() {
+            println!(
+                "  pulled as chunks: {}",
+                report.chunking.chunked_files.join(", ")
+            );
+        }
+        for c in &report.chunking.local_chunks {
+            println!(
+                "  {}: {}/{} chunks present locally",
+                c.file, c.present_chunks, c.total_chunks
+            );
+        }
+    }
+
+    if !report.metadata.is_empty() {
+        println!("\nMetadata:");
+        for (key, value) in &report.metadata {
+            println!("  {}: {}", key, value);
+        }
     }
-
-    // Check if VM is running and stop it if necessary
-    if vm::check_vm_running(config, vm_name)? {
-        if !json {
-            info!("Stopping VM {} before creating image...", vm_name);
+    if !report.history.is_empty() {
+        println!("\nHistory:");
+        for entry in &report.history {
+            println!("  {}", entry.image);
         }
-        vm::stop(config, vm_name, json).await?;
-
-        // Wait a moment for the VM to fully shut down
-        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
     }
+    Ok(())
+}
 
-    if !json {
-        info!("Creating image from VM: {}", vm_name);
+/// Copy a running VM's rootfs to `dest` without shutting it down.
+///
+/// The VM is paused (vCPUs and device I/O) only for the duration of
+/// the copy, which is a reflink where the filesystem supports it. For
+/// qcow2 overlays only the overlay is copied; its backing image is
+/// immutable. With [`LiveMode::Freeze`] the guest's root filesystem is
+/// also frozen so the copy is clean rather than merely crash-consistent.
+async fn live_copy_rootfs(
+    config: &Config,
+    vm_name: &str,
+    rootfs: &Path,
+    dest: &Path,
+    mode: LiveMode,
+    json: bool,
+) -> Result<()> {
+    let sock = config.vm_dir(vm_name).join("api.sock");
+    if !sock.exists() {
+        return Err(Error::Other(format!(
+            "VM {} has no API socket; a live copy needs ch-remote access (omit --live to stop it instead)",
+            vm_name
+        )));
     }
+    let cr = config.cr_bin.to_string_lossy();
+    let sock = sock.to_str().unwrap();
 
-    let image_ref = ImageRef {
-        registry: registry.to_string(),
-        org: org.to_string(),
-        name: image_name.to_string(),
-        tag: tag.to_string(),
-    };
-
-    let image_dir = image_ref.local_dir(config);
-    fs::create_dir_all(&image_dir)?;
-
-    // Convert VM rootfs to a standalone raw base image.
-    // If the rootfs is a qcow2 overlay, this flattens it (merges backing + overlay)
-    // so the image is self-contained. For raw rootfs this is a format-preserving copy.
-    let image_raw = image_dir.join("base.raw");
-    let input_format = if vm_rootfs.extension().and_then(|e| e.to_str()) == Some("qcow2") {
-        "qcow2"
-    } else {
-        "raw"
-    };
-    crate::util::run_command(
-        "qemu-img",
-        &[
-            "convert",
-            "-f",
-            input_format,
-            "-O",
-            "raw",
-            vm_rootfs.to_str().unwrap(),
-            image_raw.to_str().unwrap(),
-        ],
-    )?;
-
-    // Note: VM disk is converted to raw to preserve all customizations.
-    // Machine-specific data like hostname and network config are handled
-    // when creating new VMs from the image.
-
-    let mut artifacts = HashMap::new();
-    artifacts.insert("base_image".to_string(), "base.raw".to_string());
-
-    // Copy other VM artifacts if they exist
-    if let Ok(entries) = fs::read_dir(&vm_dir) {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            let file_name = path.file_name().unwrap().to_string_lossy();
-
-            match file_name.as_ref() {
-                "user-data" | "meta-data" | "network-config" => {
-                    let dest = image_dir.join(&*file_name);
-                    fs::copy(&path, &dest)?;
-                    artifacts.insert(file_name.to_string(), file_name.to_string());
-                }
-                _ => {}
+    let frozen = match mode {
+        LiveMode::Freeze => {
+            let host = vm::get_routable_ip(config, vm_name)?;
+            if !json {
+                info!("Freezing guest filesystem on {}", host);
             }
+            Some(FrozenGuest::freeze(config, &host).map_err(|e| {
+                Error::Other(format!(
+                    "could not freeze the guest filesystem ({}); use --live crash for a crash-consistent copy",
+                    e
+                ))
+            })?)
         }
-    }
-
-    // Create metadata
-    let mut metadata = HashMap::new();
-    metadata.insert("source_vm".to_string(), vm_name.to_string());
-    metadata.insert("created_by".to_string(), "meda".to_string());
-    metadata.insert("type".to_string(), "vm_snapshot".to_string());
-
-    let manifest = ImageManifest {
-        name: image_name.to_string(),
-        tag: tag.to_string(),
-        registry: registry.to_string(),
-        org: org.to_string(),
-        artifacts,
-        metadata,
-        created: std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs(),
+        LiveMode::Crash => None,
     };
 
-    manifest.save(&image_dir)?;
-
-    let message = format!(
-        "Successfully created image {} from VM {}",
-        image_ref.url(),
-        vm_name
-    );
-    if json {
-        let result = ImageResult {
-            success: true,
-            message,
-        };
-        println!("{}", serde_json::to_string_pretty(&result)?);
+    let started = std::time::Instant::now();
+    let paused = crate::util::run_command(&cr, &["--api-socket", sock, "pause"]).await;
+    let was_paused = paused.is_ok();
+    let copied = match paused {
+        Ok(()) => {
+            crate::util::run_command(
+                "cp",
+                &[
+                    "--reflink=auto",
+                    "--sparse=always",
+                    rootfs.to_str().unwrap(),
+                    dest.to_str().unwrap(),
+                ],
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    // Always give the VM back, whatever happened above.
+    let resumed = if was_paused {
+        crate::util::run_command(&cr, &["--api-socket", sock, "resume"]).await
     } else {
-        info!("{}", message);
+        Ok(())
+    };
+    let thawed = frozen.map_or(Ok(()), FrozenGuest::thaw);
+    if !json {
+        info!(
+            "VM {} was paused for {} ms while copying its disk",
+            vm_name,
+            started.elapsed().as_millis()
+        );
     }
 
+    if let Err(e) = copied.and(resumed).and(thawed) {
+        let _ = fs::remove_file(dest);
+        return Err(e);
+    }
     Ok(())
 }
 
+/// Prefix of the hidden template VM dirs `meda run` keeps per image.
+pub const TEMPLATE_PREFIX: &str = "__tpl_";
+
 /// Run a VM from a local image
 /// `meda run <image>` with auto-caching snapshot → clone → restore.
 /// First call for a given image pays the full cold-boot cost and builds
@@ -1702,7 +3231,8 @@ pub async fn run_instant(
         // level (info! is silenced without RUST_LOG set).
         let vm = out["vm"].as_str().unwrap_or("?");
         let host = out["host"].as_str().unwrap_or("?");
-        eprintln!("✅ VM {vm} ready\n   ssh -i ~/.meda/ssh/id_ed25519 cirun@{host}");
+        let key = config.ssh_dir().join("id_ed25519");
+        eprintln!("✅ VM {vm} ready\n   ssh -i {} cirun@{host}", key.display());
     }
     Ok(())
 }
@@ -1725,18 +3255,22 @@ async fn run_instant_core(
     image: &str,
     options: RunOptions<'_>,
 ) -> Result<serde_json::Value> {
-    let default_registry = options.registry.unwrap_or("ghcr.io");
-    let default_org = options.org.unwrap_or("cirunlabs");
+    let default_registry = options.registry.unwrap_or(&config.default_registry);
+    let default_org = options.org.unwrap_or(&config.default_org);
     let image_ref = ImageRef::parse(image, default_registry, default_org)?;
+    options.resources.check_network()?;
 
     if !image_ref.local_dir(config).exists() {
         pull(config, image, options.registry, options.org, true).await?;
     }
 
     let slug = image_slug(&image_ref);
-    let template_name = format!("__tpl_{}", slug);
+    let template_name = format!("{}{}", TEMPLATE_PREFIX, slug);
     let template_dir = config.vm_dir(&template_name);
-    let has_template = template_dir.join("snapshot").join("config.json").exists();
+    // A template left from before MEDA_RNG_SOURCE changed would hand
+    // its clones the old source, so it's rebuilt.
+    let has_template = template_dir.join("snapshot").join("config.json").exists()
+        && crate::rng::RngSource::load(&template_dir) == config.rng_source;
 
     if !has_template {
         if template_dir.exists() {
@@ -1748,8 +3282,14 @@ async fn run_instant_core(
             registry: options.registry,
             org: options.org,
             user_data_path: Some(user_data_path.to_str().unwrap()),
+            ssh_keys: &[],
+            labels: &Default::default(),
             no_start: false,
-            resources: options.resources.clone(),
+            resources: options
+                .resources
+                .clone()
+                .with_rng(Some(config.rng_source.clone())),
+            ttl: None,
         };
         run_from_image(config, image, tpl_opts, true).await?;
         wait_template_ssh(config, &template_name).await?;
@@ -1769,10 +3309,40 @@ async fn run_instant_core(
         ),
     };
 
-    crate::snapshot::clone_template(config, &template_name, &instance, false).await?;
+    // Resolve keys and check labels up front so bad input fails before cloning.
+    let extra_keys = crate::ssh::resolve_extra_keys(options.ssh_keys)?;
+    crate::labels::validate_labels(options.labels)?;
+    let pool = crate::storage::select_pool(
+        config,
+        options.resources.storage.as_deref(),
+        options.labels,
+        &options.resources.disk_size,
+    )?;
+
+    crate::snapshot::clone_template(config, &template_name, &instance, pool, false).await?;
+    crate::labels::write_labels(&config.vm_dir(&instance), options.labels)?;
+    crate::labels::record_image(&config.vm_dir(&instance), &image_ref.url())?;
+    if let Some(ttl) = options.ttl {
+        crate::ttl::set(&config.vm_dir(&instance), ttl)?;
+    }
+    options
+        .resources
+        .forward_policy()
+        .save(&config.vm_dir(&instance))?;
     crate::snapshot::restore(config, &instance, false).await?;
 
     let netns_spec = crate::netns::NetnsSpec::for_vm(&instance);
+    // The clone resumes after cloud-init has already run in the
+    // template, so extra keys go in over SSH once sshd answers.
+    if !extra_keys.is_empty() {
+        wait_for_sshd(&netns_spec.netns_ip, &instance)?;
+        crate::ssh::guest_exec(
+            config,
+            &netns_spec.netns_ip,
+            &crate::ssh::authorize_keys_command(&extra_keys),
+        )?;
+    }
+    crate::inventory::notify(config, &instance, crate::inventory::Event::Created).await;
     Ok(serde_json::json!({
         "vm": instance,
         "ssh": format!("cirun@{}", netns_spec.netns_ip),
@@ -1797,34 +3367,44 @@ fn image_slug(image_ref: &ImageRef) -> String {
             .collect::<String>(),
         image_ref.org,
         image_ref.name,
-        image_ref.tag,
+        // A digest prefix keeps the template's socket paths short.
+        match &image_ref.digest {
+            Some(digest) => &digest["sha256:".len()..][..12],
+            None => &image_ref.tag,
+        },
     )
 }
 
 /// Wait for the template VM's SSH to come up (bounded, single-shot
 /// probe per try, 120s total). Used once per image-template build.
 async fn wait_template_ssh(config: &Config, vm_name: &str) -> Result<()> {
+    let ip = vm::get_vm_ip(config, vm_name)?;
+    wait_for_sshd(&ip, vm_name)
+}
+
+fn wait_for_sshd(ip: &str, vm_name: &str) -> Result<()> {
     use std::io::Read;
     use std::net::{SocketAddr, TcpStream};
     use std::time::{Duration, Instant};
 
-    let ip = vm::get_vm_ip(config, vm_name)?;
     let addr: SocketAddr = format!("{ip}:22")
         .parse()
-        .map_err(|e| Error::Other(format!("bad template IP {ip}: {e}")))?;
+        .map_err(|e| Error::Other(format!("bad IP {ip} for {vm_name}: {e}")))?;
+    let wait = Progress::step("ssh-wait", vm_name);
     let deadline = Instant::now() + Duration::from_secs(120);
     while Instant::now() < deadline {
         if let Ok(mut s) = TcpStream::connect_timeout(&addr, Duration::from_secs(1)) {
             s.set_read_timeout(Some(Duration::from_secs(2))).ok();
             let mut buf = [0u8; 1];
             if s.read(&mut buf).ok() == Some(1) {
+                wait.finish(None);
                 return Ok(());
             }
         }
         std::thread::sleep(Duration::from_millis(200));
     }
     Err(Error::Other(format!(
-        "template VM {vm_name} never reached SSH within 120s"
+        "VM {vm_name} never reached SSH within 120s"
     )))
 }
 
@@ -1848,16 +3428,29 @@ fn write_default_fast_user_data(config: &Config) -> Result<PathBuf> {
     Ok(path)
 }
 
+/// Name `meda run` gives a VM of `image_ref` when none is asked for.
+pub fn generated_vm_name(image_ref: &ImageRef) -> String {
+    format!(
+        "{}-{}",
+        image_ref.name,
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    )
+}
+
 pub async fn run_from_image(
     config: &Config,
     image: &str,
     options: RunOptions<'_>,
     json: bool,
 ) -> Result<()> {
-    let default_registry = options.registry.unwrap_or("ghcr.io");
-    let default_org = options.org.unwrap_or("cirunlabs");
+    let default_registry = options.registry.unwrap_or(&config.default_registry);
+    let default_org = options.org.unwrap_or(&config.default_org);
 
     let image_ref = ImageRef::parse(image, default_registry, default_org)?;
+    options.resources.check_network()?;
 
     if !json {
         info!("🚀 Running VM from image: {}", image_ref.url());
@@ -1875,25 +3468,43 @@ pub async fn run_from_image(
         pull(config, image, options.registry, options.org, json).await?;
     }
 
+    // Held until the VM is built so rmi/prune can't pull the image out
+    // from under a half-copied rootfs.
+    let _image_lock = lock_image_shared(&image_dir, &image_ref.url())?;
+
     // Load image manifest
     let manifest = ImageManifest::load(&image_dir)?;
 
     // Generate VM name if not provided
-    let generated_name = format!(
-        "{}-{}",
-        image_ref.name,
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs()
-    );
+    let generated_name = generated_vm_name(&image_ref);
     let vm_name = options.vm_name.unwrap_or(&generated_name);
 
+    // Released before the VM is started below, which takes it itself.
+    let vm_lock = vm::lock(config, vm_name, "run")?;
     let vm_dir = config.vm_dir(vm_name);
 
     if vm_dir.exists() {
         return Err(Error::VmAlreadyExists(vm_name.to_string()));
     }
+    crate::labels::validate_labels(options.labels)?;
+    let extra_keys = crate::ssh::resolve_extra_keys(options.ssh_keys)?;
+    // Fail before anything is made if the keys can't go in the user-data.
+    let given_user_data = match options.user_data_path {
+        Some(path) => Some(fs::read_to_string(path)?),
+        None => manifest
+            .artifacts
+            .get("user-data")
+            .and_then(|file| fs::read_to_string(image_dir.join(file)).ok()),
+    };
+    if let Some(user_data) = given_user_data.filter(|_| !extra_keys.is_empty()) {
+        crate::ssh::add_keys_to_user_data(&user_data, &extra_keys)?;
+    }
+    let pool = crate::storage::select_pool(
+        config,
+        options.resources.storage.as_deref(),
+        options.labels,
+        &options.resources.disk_size,
+    )?;
 
     if !json {
         info!(
@@ -1906,20 +3517,35 @@ pub async fn run_from_image(
     // Bootstrap only the hypervisor binaries (we already have the image)
     vm::bootstrap_binaries_only(config).await?;
 
-    // Create VM directory
-    fs::create_dir_all(&vm_dir)?;
+    // Create VM directory (on its storage pool, linked from vm_root)
+    let quota = crate::quota::before_create(config, vm_name, &options.resources.disk_size)?;
+    crate::storage::create_vm_dir(config, vm_name, pool)?;
+    drop(quota);
+    crate::labels::write_labels(&vm_dir, options.labels)?;
+    crate::labels::record_image(&vm_dir, &image_ref.url())?;
+    if let Some(ttl) = options.ttl {
+        crate::ttl::set(&vm_dir, ttl)?;
+    }
+    options.resources.rng.save(&vm_dir)?;
+    if let Some(boot) = &options.resources.boot {
+        boot.save(&vm_dir)?;
+    }
 
-    // Copy base image from the cached image
+    // Provision the root disk from the cached image
+    let root_format =
+        crate::immutable::root_format(options.resources.immutable_root, config.disk_format);
     if let Some(base_image_file) = manifest.artifacts.get("base_image") {
         let source_image = image_dir.join(base_image_file);
-        let vm_rootfs = vm_dir.join("rootfs.qcow2");
 
         if source_image.exists() {
             if !json {
-                info!(
-                    "Creating qcow2 overlay (backing: {})",
-                    source_image.display()
-                );
+                match root_format {
+                    DiskFormat::Qcow2 => info!(
+                        "Creating qcow2 overlay (backing: {})",
+                        source_image.display()
+                    ),
+                    DiskFormat::Raw => info!("Copying base image {}", source_image.display()),
+                }
             }
             // Only override size if user requested non-default.
             // Otherwise inherit backing file size (matches old raw copy behavior).
@@ -1928,7 +3554,17 @@ pub async fn run_from_image(
             } else {
                 None
             };
-            crate::util::create_qcow2_overlay(&source_image, &vm_rootfs, overlay_size)?;
+            let rootfs =
+                crate::util::provision_rootfs(&source_image, &vm_dir, root_format, overlay_size)
+                    .await?;
+            crate::immutable::prepare(
+                &vm_dir,
+                &rootfs,
+                options.resources.immutable_root,
+                options.resources.data_disk.as_ref(),
+            )?;
+            crate::guest_env::prepare(&vm_dir, &options.resources.env)?;
+            crate::secrets::prepare(&vm_dir, &options.resources.secrets)?;
         } else {
             return Err(Error::Other(format!(
                 "Base image artifact '{}' not found in image",
@@ -1957,21 +3593,6 @@ pub async fn run_from_image(
         }
     }
 
-    // Reap any tap devices leaked by a prior delete so we don't pick a subnet
-    // that still has a stale connected route via a linkdown orphan.
-    if let Err(e) = crate::network::cleanup_orphaned_tap_devices(config).await {
-        log::warn!("orphan tap reap before VM run failed: {}", e);
-    }
-
-    // Generate network config with a unique subnet
-    let subnet = crate::network::generate_unique_subnet(config).await?;
-    // Generate unique TAP device name
-    let tap_name = crate::network::generate_unique_tap_name(config, vm_name).await?;
-
-    // Store network config
-    crate::util::write_string_to_file(&vm_dir.join("subnet"), &subnet)?;
-    crate::util::write_string_to_file(&vm_dir.join("tapdev"), &tap_name)?;
-
     // Store VM resource configuration
     crate::util::write_string_to_file(&vm_dir.join("memory"), &options.resources.memory)?;
     crate::util::write_string_to_file(&vm_dir.join("cpus"), &options.resources.cpus.to_string())?;
@@ -1984,159 +3605,53 @@ pub async fn run_from_image(
             &options.resources.devices.join("\n"),
         )?;
     }
+    options.resources.forward_policy().save(&vm_dir)?;
 
-    // Create or use provided cloud-init files
-    if !vm_dir.join("meta-data").exists() {
-        let meta_data = format!("instance-id: {}\nlocal-hostname: {}\n", vm_name, vm_name);
-        crate::util::write_string_to_file(&vm_dir.join("meta-data"), &meta_data)?;
-    }
-
-    // User data - use provided or default
+    // User data - use provided, the image's, or default; --ssh-key
+    // values are added to whichever it is.
+    let user_data = vm_dir.join("user-data");
     if let Some(path) = options.user_data_path {
-        fs::copy(path, vm_dir.join("user-data"))?;
-    } else if !vm_dir.join("user-data").exists() {
+        fs::copy(path, &user_data)?;
+    }
+    if !user_data.exists() {
         let keypair = crate::ssh::ensure_ssh_keypair(config)?;
-        let default_user_data = format!(
-            r#"#cloud-config
-users:
-  - name: cirun
-    sudo: ALL=(ALL) NOPASSWD:ALL
-    passwd: $6$ep7LxhhmhQHf.TiY$qPJVJQCnPMnyFdmD0ymP7CH2dos0awET8JlSzDqoiK6AOQwDpx8fCLJ1C5c7nvkVJbIpQCOalC8l2BGkRzogM.
-    lock_passwd: false
-    inactive: false
-    groups: sudo
-    shell: /bin/bash
-    ssh_authorized_keys:
-      - {}
-ssh_pwauth: true
-"#,
-            keypair.public_key
-        );
-        crate::util::write_string_to_file(&vm_dir.join("user-data"), &default_user_data)?;
-    }
-
-    // Generate MAC address
-    let mac = crate::network::generate_random_mac();
-    crate::util::write_string_to_file(&vm_dir.join("mac"), &mac)?;
-
-    // Create cloud-init ISO
-    let ci_dir = vm_dir.join("ci");
-    fs::create_dir_all(&ci_dir)?;
-
-    // Copy cloud-init files to ci directory
-    for file in ["meta-data", "user-data"] {
-        let src = vm_dir.join(file);
-        let dst = ci_dir.join(file);
-        if src.exists() {
-            fs::copy(&src, &dst)?;
-        }
-    }
-
-    // Add network-config if it doesn't exist
-    if !ci_dir.join("network-config").exists() {
-        let network_config = format!(
-            r#"version: 2
-ethernets:
-  ens4:
-    match:
-       macaddress: {}
-    addresses: [{}.2/24]
-    gateway4: {}.1
-    set-name: ens4
-    nameservers:
-      addresses: [8.8.8.8, 1.1.1.1]
-"#,
-            mac, subnet, subnet
-        );
-        crate::util::write_string_to_file(&ci_dir.join("network-config"), &network_config)?;
+        let default_user_data = crate::ssh::default_user_data(&keypair.public_key, &extra_keys);
+        crate::util::write_string_to_file(&user_data, &default_user_data)?;
+    } else if !extra_keys.is_empty() {
+        let merged =
+            crate::ssh::add_keys_to_user_data(&fs::read_to_string(&user_data)?, &extra_keys)?;
+        crate::util::write_string_to_file(&user_data, &merged)?;
     }
 
-    // Create cloud-init ISO
-    let ci_iso = vm_dir.join("ci.iso");
-    if !json {
-        info!("Creating cloud-init configuration");
-    }
-    crate::util::run_command_quietly(
-        "genisoimage",
-        &[
-            "-output",
-            ci_iso.to_str().unwrap(),
-            "-volid",
-            "cidata",
-            "-joliet",
-            "-rock",
-            ci_dir.to_str().unwrap(),
-        ],
-    )?;
+    // Fresh subnet, TAP, MAC, hostname and cloud-init ISO
+    let identity = vm::assign_identity(config, vm_name, json).await?;
 
     // Setup networking
     if !json {
         info!("🌐 Setting up host networking");
     }
-    crate::network::setup_networking(config, vm_name, &tap_name, &subnet).await?;
-
-    // Build device passthrough flags
-    let device_section = if options.resources.devices.is_empty() {
-        String::new()
-    } else {
-        let args: Vec<String> = options
-            .resources
-            .devices
-            .iter()
-            .map(|d| format!("  --device path={}", d))
-            .collect();
-        format!(" \\\n{}", args.join(" \\\n"))
-    };
-
-    // Create start script
-    let start_script = format!(
-        r#"#!/bin/bash
-cd "{}"
-{} \
-  --api-socket path={}/api.sock \
-  --console off \
-  --serial tty \
-  --kernel "{}" \
-  --cpus boot={} \
-  --memory size={} \
-  --disk path={}/rootfs.qcow2,image_type=qcow2,backing_files=on path="{}/ci.iso" \
-  --net tap={},mac={} \
-  --rng src=/dev/urandom{} \
-  > "{}/ch.log" 2>&1 &
-echo $! > "{}/pid"
-
-# Check if command started successfully
-sleep 2
-if ! ps -p $(cat "{}/pid" 2>/dev/null) &>/dev/null; then
-  echo "ERROR: Cloud Hypervisor failed to start. Check log: {}/ch.log" >&2
-  exit 1
-fi
-"#,
-        vm_dir.display(),
-        config.ch_bin.display(),
-        vm_dir.display(),
-        config.fw_bin.display(),
-        options.resources.cpus,
-        options.resources.memory,
-        vm_dir.display(),
-        vm_dir.display(),
-        tap_name,
-        mac,
-        device_section,
-        vm_dir.display(),
-        vm_dir.display(),
-        vm_dir.display(),
-        vm_dir.display()
+    crate::network::setup_networking(config, vm_name, &identity.tap_name, &identity.subnet).await?;
+
+    // CH runs as the user on the host-side tap; dnsmasq for DHCP
+    // guests runs as root first.
+    let dhcp_commands = crate::dhcp::start_commands(
+        &vm_dir,
+        None,
+        &identity.tap_name,
+        &identity.subnet,
+        &identity.mac,
     );
-
-    let start_script_path = vm_dir.join("start.sh");
-    crate::util::write_string_to_file(&start_script_path, &start_script)?;
-
-    // Make start script executable
-    use std::os::unix::fs::PermissionsExt;
-    let mut perms = fs::metadata(&start_script_path)?.permissions();
-    perms.set_mode(0o755);
-    fs::set_permissions(&start_script_path, perms)?;
+    vm::launch_spec(
+        config,
+        &vm_dir,
+        &options.resources,
+        &identity,
+        dhcp_commands,
+        None,
+        false,
+    )?
+    .save(&vm_dir)?;
+    drop(vm_lock);
 
     let message = if options.no_start {
         format!(
@@ -2153,6 +3668,7 @@ fi
             image_ref.url()
         )
     };
+    crate::inventory::notify(config, vm_name, crate::inventory::Event::Created).await;
 
     if json {
         let result = crate::vm::VmResult {
@@ -2182,6 +3698,34 @@ mod tests {
     use std::env;
     use tempfile::TempDir;
 
+    #[test]
+    fn test_frozen_guest_session() {
+        let session = |script: &str| {
+            std::process::Command::new("sh")
+                .args(["-c", script])
+                .stdin(std::process::Stdio::piped())
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped())
+                .spawn()
+                .unwrap()
+        };
+        // The thaw comes from the session that froze, once told to.
+        let frozen = FrozenGuest::attach(session("echo frozen; read _; echo thawed")).unwrap();
+        frozen.thaw().unwrap();
+
+        let err = FrozenGuest::attach(session("echo 'fsfreeze: /: not permitted' >&2; exit 1"))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("not permitted"));
+
+        let frozen = FrozenGuest::attach(session("echo frozen; read _; exit 1")).unwrap();
+        assert!(frozen.thaw().is_err());
+
+        // The watchdog thawed the guest mid-copy: not a frozen copy.
+        let frozen = FrozenGuest::attach(session("echo frozen; read _; echo expired")).unwrap();
+        assert!(frozen.thaw().unwrap_err().to_string().contains("expired"));
+    }
+
     #[test]
     fn test_image_ref_parse_simple() {
         let image_ref = ImageRef::parse("ubuntu", "ghcr.io", "cirunlabs").unwrap();
@@ -2219,6 +3763,15 @@ mod tests {
         assert_eq!(image_ref.tag, "v1.0");
     }
 
+    #[test]
+    fn test_parse_image_list() {
+        let body = "# runner images\nubuntu:22.04\n\n  ghcr.io/acme/runner:2.328.0-r1  # pinned\n";
+        assert_eq!(
+            parse_image_list(body),
+            vec!["ubuntu:22.04", "ghcr.io/acme/runner:2.328.0-r1"]
+        );
+    }
+
     #[test]
     fn test_image_ref_parse_registry_detection() {
         let image_ref =
@@ -2229,6 +3782,46 @@ mod tests {
         assert_eq!(image_ref.tag, "latest");
     }
 
+    #[test]
+    fn test_image_ref_parse_digest() {
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        for image in [
+            format!("cirunlabs/ubuntu@{}", digest),
+            format!("cirunlabs/ubuntu:22.04@{}", digest),
+        ] {
+            let image_ref = ImageRef::parse(&image, "ghcr.io", "default").unwrap();
+            assert_eq!(image_ref.name, "ubuntu");
+            assert_eq!(image_ref.digest.as_deref(), Some(digest.as_str()));
+            assert_eq!(image_ref.tag, format!("sha256-{}", "ab".repeat(32)));
+            assert_eq!(
+                image_ref.url(),
+                format!("ghcr.io/cirunlabs/ubuntu@{}", digest)
+            );
+            assert_eq!(image_ref.repository(), "ghcr.io/cirunlabs/ubuntu");
+        }
+        assert!(image_slug(
+            &ImageRef::parse(&format!("ubuntu@{}", digest), "ghcr.io", "c").unwrap()
+        )
+        .ends_with("_ubuntu_abababababab"));
+
+        for bad in ["ubuntu@latest", "ubuntu@sha256:abc", "ubuntu@md5:00"] {
+            assert!(matches!(
+                ImageRef::parse(bad, "ghcr.io", "cirunlabs"),
+                Err(Error::InvalidImageName(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_is_valid_tag() {
+        for tag in ["latest", "v1.0", "22.04-runner_2", "_x"] {
+            assert!(is_valid_tag(tag), "{}", tag);
+        }
+        for tag in ["", ".hidden", "-x", "a/b", "a:b", &"x".repeat(129)] {
+            assert!(!is_valid_tag(tag), "{}", tag);
+        }
+    }
+
     #[test]
     fn test_image_ref_url() {
         let image_ref = ImageRef {
@@ -2236,6 +3829,7 @@ mod tests {
             org: "cirunlabs".to_string(),
             name: "ubuntu".to_string(),
             tag: "v1.0".to_string(),
+            digest: None,
         };
         assert_eq!(image_ref.url(), "ghcr.io/cirunlabs/ubuntu:v1.0");
     }
@@ -2252,6 +3846,7 @@ mod tests {
             org: "cirunlabs".to_string(),
             name: "ubuntu".to_string(),
             tag: "v1.0".to_string(),
+            digest: None,
         };
 
         let local_dir = image_ref.local_dir(&config);
@@ -2273,6 +3868,7 @@ mod tests {
         metadata.insert("os".to_string(), "ubuntu".to_string());
 
         let manifest = ImageManifest {
+            schema_version: crate::schema::IMAGE_SCHEMA_VERSION,
             name: "test".to_string(),
             tag: "latest".to_string(),
             registry: "ghcr.io".to_string(),
@@ -2280,6 +3876,7 @@ mod tests {
             artifacts,
             metadata,
             created: 1234567890,
+            history: Vec::new(),
         };
 
         // Save manifest
@@ -2342,7 +3939,7 @@ mod tests {
         env::remove_var("MEDA_ASSET_DIR");
 
         // Should not error when images directory doesn't exist
-        let result = list(&config, true).await;
+        let result = list(&config, false, true).await;
         assert!(result.is_ok());
     }
 
@@ -2355,10 +3952,68 @@ mod tests {
         env::remove_var("MEDA_ASSET_DIR");
 
         // Should not error when images directory doesn't exist
-        let result = prune(&config, false, false, true).await;
+        let result = prune(&config, false, None, false, true).await;
         assert!(result.is_ok());
     }
 
+    fn fake_image(config: &Config, image: &str, created: u64) -> PathBuf {
+        let image_ref = ImageRef::parse(image, "ghcr.io", "cirunlabs").unwrap();
+        let dir = image_ref.local_dir(config);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("base.raw"), vec![0u8; 1024]).unwrap();
+        ImageManifest {
+            schema_version: crate::schema::IMAGE_SCHEMA_VERSION,
+            name: image_ref.name.clone(),
+            tag: image_ref.tag.clone(),
+            registry: image_ref.registry.clone(),
+            org: image_ref.org.clone(),
+            artifacts: HashMap::new(),
+            metadata: HashMap::new(),
+            created,
+            history: Vec::new(),
+        }
+        .save(&dir)
+        .unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_prune_keeps_images_in_use() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.asset_dir = temp_dir.path().join("assets");
+        config.vm_root = temp_dir.path().join("vms");
+
+        let day = 24 * 60 * 60;
+        let now = 100 * day;
+        fake_image(&config, "used:v1", now - 30 * day);
+        fake_image(&config, "old:v1", now - 30 * day);
+        let new_dir = fake_image(&config, "new:v1", now - day);
+
+        let vm_dir = config.vm_root.join("vm1");
+        fs::create_dir_all(&vm_dir).unwrap();
+        crate::labels::record_image(&vm_dir, "ghcr.io/cirunlabs/used:v1").unwrap();
+
+        let names = |c: Vec<PrunedImage>| c.into_iter().map(|i| i.image).collect::<Vec<_>>();
+        assert_eq!(
+            names(prune_candidates(&config, false, None, now).unwrap()),
+            vec!["ghcr.io/cirunlabs/new:v1", "ghcr.io/cirunlabs/old:v1"]
+        );
+        assert_eq!(
+            names(prune_candidates(&config, false, Some(7 * day), now).unwrap()),
+            vec!["ghcr.io/cirunlabs/old:v1"]
+        );
+        assert_eq!(prune_candidates(&config, true, None, now).unwrap().len(), 3);
+
+        prune(&config, false, None, true, true).await.unwrap();
+        assert!(!new_dir.exists());
+        assert!(!new_dir.parent().unwrap().exists());
+        assert_eq!(
+            names(prune_candidates(&config, true, None, now).unwrap()),
+            vec!["ghcr.io/cirunlabs/used:v1"]
+        );
+    }
+
     #[tokio::test]
     async fn test_remove_nonexistent_image() {
         let temp_dir = TempDir::new().unwrap();
@@ -2371,4 +4026,211 @@ mod tests {
         let result = remove(&config, "nonexistent", None, None, true, true).await;
         assert!(result.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_tag_shares_artifacts() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.asset_dir = temp_dir.path().to_path_buf();
+
+        let source_dir = fake_image(&config, "ubuntu:latest", 100);
+        tag(&config, "ubuntu:latest", "golden:v1", None, None, true).unwrap();
+        let target_dir = ImageRef::parse("golden:v1", "ghcr.io", "cirunlabs")
+            .unwrap()
+            .local_dir(&config);
+
+        let manifest = ImageManifest::load(&target_dir).unwrap();
+        assert_eq!(
+            (manifest.name.as_str(), manifest.tag.as_str()),
+            ("golden", "v1")
+        );
+        assert_eq!(
+            manifest.metadata.get("tagged_from").map(String::as_str),
+            Some("ghcr.io/cirunlabs/ubuntu:latest")
+        );
+        let source_raw = fs::metadata(source_dir.join("base.raw")).unwrap();
+        let target_raw = fs::metadata(target_dir.join("base.raw")).unwrap();
+        assert_eq!(source_raw.ino(), target_raw.ino());
+        assert_eq!(collect_images(&config).unwrap().len(), 2);
+
+        assert!(matches!(
+            tag(&config, "ubuntu:latest", "golden:v1", None, None, true),
+            Err(Error::ImageAlreadyExists(_))
+        ));
+        assert!(matches!(
+            tag(&config, "missing:v1", "other:v1", None, None, true),
+            Err(Error::ImageNotFound(_))
+        ));
+
+        // The blobs survive until the last tag goes.
+        assert!(freed_size(&[&source_dir]).unwrap() < source_raw.len());
+        assert!(freed_size(&[&source_dir, &target_dir]).unwrap() > source_raw.len());
+        remove(&config, "ubuntu:latest", None, None, true, true)
+            .await
+            .unwrap();
+        assert!(target_dir.join("base.raw").exists());
+        assert!(freed_size(&[&target_dir]).unwrap() > source_raw.len());
+    }
+
+    #[test]
+    fn test_inspect_image() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.asset_dir = temp_dir.path().join("assets");
+
+        let dir = fake_image(&config, "golden:v1", 100);
+        let mut manifest = ImageManifest::load(&dir).unwrap();
+        manifest
+            .artifacts
+            .insert("base_image".to_string(), "base.raw".to_string());
+        manifest
+            .artifacts
+            .insert("user_data".to_string(), "user-data".to_string());
+        manifest
+            .metadata
+            .insert("chunked_files".to_string(), "base.raw".to_string());
+        manifest.save(&dir).unwrap();
+
+        let report = inspect_image(&config, "golden:v1", None, None).unwrap();
+        assert_eq!(report.image, "ghcr.io/cirunlabs/golden:v1");
+        assert_eq!(report.size_bytes, 1024);
+        assert_eq!(report.chunking.chunked_files, vec!["base.raw"]);
+        let base = &report.artifacts[0];
+        assert_eq!(base.artifact, "base_image");
+        assert!(base.present);
+        // SHA256 of 1024 zero bytes
+        assert_eq!(
+            base.sha256.as_deref(),
+            Some("sha256:5f70bf18a086007016e948b04aed3b82103a36bea41755b6cddfaf10ace3c6ef")
+        );
+        assert!(!report.artifacts[1].present);
+        assert!(dir.join(DIGEST_CACHE).exists());
+
+        // Served from the cache the second time.
+        let report = inspect_image(&config, "golden:v1", None, None).unwrap();
+        assert_eq!(report.artifacts[0].sha256, base.sha256);
+        assert!(matches!(
+            inspect_image(&config, "missing:v1", None, None),
+            Err(Error::ImageNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_image_lineage() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.asset_dir = temp_dir.path().join("assets");
+        config.vm_root = temp_dir.path().join("vms");
+
+        let base_dir = fake_image(&config, "base:v1", 100);
+        let mut base = ImageManifest::load(&base_dir).unwrap();
+        base.metadata
+            .insert("digest".to_string(), "sha256:abc".to_string());
+        base.save(&base_dir).unwrap();
+
+        let golden_dir = fake_image(&config, "golden:v1", 200);
+        let mut golden = ImageManifest::load(&golden_dir).unwrap();
+        golden
+            .metadata
+            .insert("source_vm".to_string(), "builder".to_string());
+        golden.history = vec![base.ancestor_entry("ghcr.io/cirunlabs/base:v1".to_string())];
+        golden.save(&golden_dir).unwrap();
+
+        let vm_dir = config.vm_root.join("vm1");
+        fs::create_dir_all(&vm_dir).unwrap();
+        assert!(image_lineage(&config, &vm_dir).is_empty());
+
+        crate::labels::record_image(&vm_dir, "ghcr.io/cirunlabs/golden:v1").unwrap();
+        let lineage = image_lineage(&config, &vm_dir);
+        assert_eq!(lineage.len(), 2);
+        assert_eq!(lineage[0].image, "ghcr.io/cirunlabs/golden:v1");
+        assert_eq!(lineage[0].created, Some(200));
+        assert_eq!(lineage[0].source_vm.as_deref(), Some("builder"));
+        assert_eq!(lineage[1].image, "ghcr.io/cirunlabs/base:v1");
+        assert_eq!(lineage[1].digest.as_deref(), Some("sha256:abc"));
+
+        fs::remove_dir_all(&golden_dir).unwrap();
+        let lineage = image_lineage(&config, &vm_dir);
+        assert_eq!(lineage.len(), 1);
+        assert_eq!(lineage[0].created, None);
+    }
+
+    #[test]
+    fn test_tag_lock_path() {
+        let tag_dir = Path::new("/x/images/ghcr_io/cirunlabs/ubuntu/latest");
+        assert_eq!(
+            tag_lock_path(tag_dir),
+            Path::new("/x/images/ghcr_io/cirunlabs/ubuntu/.latest.lock")
+        );
+    }
+
+    #[test]
+    fn test_disk_file_format() {
+        let dir = TempDir::new().unwrap();
+        let qcow2 = dir.path().join("disk.qcow2");
+        fs::write(&qcow2, b"QFI\xfb\x00\x00\x00\x03").unwrap();
+        assert_eq!(disk_file_format(&qcow2).unwrap(), "qcow2");
+        let raw = dir.path().join("disk.img");
+        fs::write(&raw, vec![0u8; 512]).unwrap();
+        assert_eq!(disk_file_format(&raw).unwrap(), "raw");
+        let tiny = dir.path().join("tiny");
+        fs::write(&tiny, b"x").unwrap();
+        assert!(disk_file_format(&tiny).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remove_refuses_image_in_use() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.asset_dir = temp_dir.path().to_path_buf();
+
+        let image_ref = ImageRef::parse("ubuntu", "ghcr.io", "cirunlabs").unwrap();
+        let image_dir = image_ref.local_dir(&config);
+        fs::create_dir_all(&image_dir).unwrap();
+
+        let reader = lock_image_shared(&image_dir, "ubuntu").unwrap();
+        let err = remove(&config, "ubuntu", None, None, true, true)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ImageInUse(..)));
+        assert!(image_dir.exists());
+
+        drop(reader);
+        remove(&config, "ubuntu", None, None, true, true)
+            .await
+            .unwrap();
+        assert!(!image_dir.exists());
+        assert!(!tag_lock_path(&image_dir).exists());
+        assert!(matches!(
+            lock_image_shared(&image_dir, "ubuntu"),
+            Err(Error::ImageNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_layer_artifact() {
+        assert_eq!(
+            layer_artifact("application/vnd.cirunlabs.meda.base-image.v1").as_deref(),
+            Some("base-image")
+        );
+        assert_eq!(
+            layer_artifact("application/vnd.cirunlabs.meda.base-image-chunk.v1").as_deref(),
+            Some("base-image")
+        );
+        assert_eq!(
+            layer_artifact("application/vnd.cirunlabs.meda.base-image-chunk.v1+zstd").as_deref(),
+            Some("base-image")
+        );
+        assert_eq!(
+            layer_artifact("application/vnd.cirunlabs.meda.user-data.v1").as_deref(),
+            Some("user-data")
+        );
+        assert_eq!(
+            layer_artifact("application/vnd.oci.image.layer.v1.tar"),
+            None
+        );
+        assert_eq!(normalize_artifact("base_image"), "base-image");
+    }
 }
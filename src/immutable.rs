@@ -0,0 +1,186 @@
+//! Immutable root disks and persistent data disks.
+//!
+//! `--immutable-root` gives a VM a root disk that comes back from its
+//! image on every start: the image is only ever read (a qcow2 overlay
+//! takes the writes) and the overlay is thrown away before each boot,
+//! so nothing a job leaves on `/` survives a restart. `--data-disk 20G`
+//! adds a second disk that persists, formatted once by cloud-init and
+//! mounted at `--data-mount` (default `/data`): the usual shape of an
+//! immutable CI runner with a persistent cache.
+//!
+//! The freshly provisioned overlay is kept as `rootfs.pristine` and
+//! copied over the root disk before each start; its presence is what
+//! makes a VM immutable. The data disk is `data.raw`, attached with the
+//! serial `meda-data`. Its filesystem and mount come from cloud-init
+//! vendor-data, so they apply whatever user-data the VM has.
+
+use crate::config::DiskFormat;
+use crate::error::{Error, Result};
+use std::fs::{self, File};
+use std::path::Path;
+
+/// Copy of the root overlay as first provisioned.
+pub const PRISTINE_ROOT: &str = "rootfs.pristine";
+
+/// Persistent data disk, in the VM dir.
+pub const DATA_DISK: &str = "data.raw";
+
+/// Cloud-init vendor-data, in the VM dir and on the seed ISO.
+pub const VENDOR_DATA: &str = "vendor-data";
+
+/// Block device serial and filesystem label of the data disk.
+const DATA_LABEL: &str = "meda-data";
+
+pub const DEFAULT_DATA_MOUNT: &str = "/data";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataDisk {
+    pub size: String,
+    pub mount: String,
+}
+
+impl DataDisk {
+    /// `--data-disk` / `--data-mount` values.
+    pub fn new(size: &str, mount: Option<&str>) -> Result<Self> {
+        if crate::util::parse_size_bytes(size).is_none_or(|b| b == 0) {
+            return Err(Error::Other(format!(
+                "Invalid data disk size '{}' (expected e.g. 20G)",
+                size
+            )));
+        }
+        let mount = mount.unwrap_or(DEFAULT_DATA_MOUNT);
+        let safe = mount.starts_with('/')
+            && mount != "/"
+            && mount
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "/-_.".contains(c));
+        if !safe {
+            return Err(Error::Other(format!(
+                "Invalid data disk mount point '{}' (expected an absolute path like /data)",
+                mount
+            )));
+        }
+        Ok(Self {
+            size: size.to_string(),
+            mount: mount.to_string(),
+        })
+    }
+}
+
+/// Root disk format for a new VM: immutable roots are always overlays.
+pub fn root_format(immutable: bool, configured: DiskFormat) -> DiskFormat {
+    if immutable {
+        DiskFormat::Qcow2
+    } else {
+        configured
+    }
+}
+
+pub fn is_immutable(vm_dir: &Path) -> bool {
+    vm_dir.join(PRISTINE_ROOT).exists()
+}
+
+/// Set up a new VM's immutable root and data disk, once its root disk
+/// is provisioned.
+pub fn prepare(
+    vm_dir: &Path,
+    rootfs: &Path,
+    immutable: bool,
+    data_disk: Option<&DataDisk>,
+) -> Result<()> {
+    if immutable {
+        fs::copy(rootfs, vm_dir.join(PRISTINE_ROOT))?;
+    }
+    if let Some(disk) = data_disk {
+        // Sparse: blocks are only allocated as the guest writes them.
+        let bytes = crate::util::parse_size_bytes(&disk.size).unwrap_or_default();
+        File::create(vm_dir.join(DATA_DISK))?.set_len(bytes)?;
+        fs::write(vm_dir.join(VENDOR_DATA), vendor_data(&disk.mount))?;
+    }
+    Ok(())
+}
+
+/// Put back the pristine root disk of an immutable VM; a no-op for
+/// other VMs. Must only run while the VM is stopped.
+pub fn reset_root(vm_dir: &Path) -> Result<bool> {
+    if !is_immutable(vm_dir) {
+        return Ok(false);
+    }
+    let rootfs = vm_dir.join(DiskFormat::Qcow2.rootfs_name());
+    let tmp = rootfs.with_extension("qcow2.tmp");
+    fs::copy(vm_dir.join(PRISTINE_ROOT), &tmp)?;
+    fs::rename(&tmp, &rootfs)?;
+    Ok(true)
+}
+
+/// `--disk` value of the data disk, if the VM has one.
+pub fn disk_value(vm_dir: &Path) -> Option<String> {
+    let data = vm_dir.join(DATA_DISK);
+    data.exists()
+        .then(|| format!("path={},serial={}", data.display(), DATA_LABEL))
+}
+
+/// Formats the data disk on first boot (never over an existing
+/// filesystem) and mounts it at `mount`.
+fn vendor_data(mount: &str) -> String {
+    format!(
+        "#cloud-config\n\
+         fs_setup:\n  \
+           - label: {label}\n    \
+             filesystem: ext4\n    \
+             device: /dev/disk/by-id/virtio-{label}\n    \
+             partition: none\n    \
+             overwrite: false\n\
+         mounts:\n  \
+           - [LABEL={label}, {mount}, ext4, \"defaults,nofail\", \"0\", \"2\"]\n",
+        label = DATA_LABEL,
+        mount = mount
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_data_disk_new() {
+        let disk = DataDisk::new("20G", None).unwrap();
+        assert_eq!(disk.mount, "/data");
+        assert_eq!(
+            DataDisk::new("1G", Some("/var/cache/ci")).unwrap().mount,
+            "/var/cache/ci"
+        );
+        assert!(DataDisk::new("lots", None).is_err());
+        assert!(DataDisk::new("0", None).is_err());
+        assert!(DataDisk::new("1G", Some("data")).is_err());
+        assert!(DataDisk::new("1G", Some("/")).is_err());
+        assert!(DataDisk::new("1G", Some("/data, x")).is_err());
+    }
+
+    #[test]
+    fn test_prepare_and_reset() {
+        let dir = TempDir::new().unwrap();
+        let rootfs = dir.path().join(DiskFormat::Qcow2.rootfs_name());
+        fs::write(&rootfs, b"fresh").unwrap();
+        assert!(!reset_root(dir.path()).unwrap());
+
+        let disk = DataDisk::new("1G", Some("/cache")).unwrap();
+        prepare(dir.path(), &rootfs, true, Some(&disk)).unwrap();
+        assert!(is_immutable(dir.path()));
+        assert_eq!(
+            fs::metadata(dir.path().join(DATA_DISK)).unwrap().len(),
+            1 << 30
+        );
+        let vendor = fs::read_to_string(dir.path().join(VENDOR_DATA)).unwrap();
+        assert!(vendor.contains("overwrite: false"));
+        assert!(vendor.contains("[LABEL=meda-data, /cache, ext4,"));
+        assert!(disk_value(dir.path())
+            .unwrap()
+            .ends_with("data.raw,serial=meda-data"));
+
+        fs::write(&rootfs, b"dirty").unwrap();
+        assert!(reset_root(dir.path()).unwrap());
+        assert_eq!(fs::read(&rootfs).unwrap(), b"fresh");
+    }
+}
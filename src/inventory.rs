@@ -0,0 +1,252 @@
+//! Tell an external inventory (IPAM, CMDB, monitoring) about VMs as
+//! they come and go.
+//!
+//! After a VM is created — by `create`, `clone`, `run` or `up` — and
+//! after it is deleted, meda hands a JSON record of it to the
+//! configured exporters:
+//!
+//! - `MEDA_INVENTORY_COMMAND`: run with `sh -c`, the record on stdin
+//!   and `MEDA_INVENTORY_EVENT` / `MEDA_INVENTORY_VM` in the environment;
+//! - `MEDA_INVENTORY_URL`: the record is POSTed as
+//!   `application/json`.
+//!
+//! The record is what `meda get -o json` shows, plus the VM manifest,
+//! the image it came from and the host it lives on; for `deleted` it is
+//! taken just before the VM goes. Exporters get `EXPORT_TIMEOUT` each,
+//! and a failing one is logged, never fails the VM operation: the
+//! inventory catches up on the next event, or from `meda list`.
+//! `meda run`'s hidden templates are never exported.
+
+use crate::config::Config;
+use crate::schema::VmManifest;
+use crate::vm::VmDetailedInfo;
+use log::{debug, warn};
+use serde::Serialize;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Created,
+    Deleted,
+}
+
+impl Event {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Deleted => "deleted",
+        }
+    }
+}
+
+/// Where records go (from the environment).
+#[derive(Debug, Clone, Default)]
+pub struct Exporters {
+    pub command: Option<String>,
+    pub url: Option<String>,
+}
+
+impl Exporters {
+    pub fn from_env(get: impl Fn(&str) -> Option<String>) -> Self {
+        let non_empty = |var: &str| get(var).filter(|v| !v.trim().is_empty());
+        Self {
+            command: non_empty("MEDA_INVENTORY_COMMAND"),
+            url: non_empty("MEDA_INVENTORY_URL"),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.command.is_none() && self.url.is_none()
+    }
+}
+
+#[derive(Serialize)]
+pub struct Record {
+    pub event: Event,
+    /// Unix time of the event.
+    pub timestamp: u64,
+    /// Host the VM lives on.
+    pub host: String,
+    pub vm: VmDetailedInfo,
+    pub manifest: Option<VmManifest>,
+    /// Image the VM was created from.
+    pub image: Option<String>,
+}
+
+pub fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Inventory record of VM `name` as it is now; `None` when no exporter
+/// is configured, the VM is a template or it can't be read.
+pub fn record(config: &Config, name: &str, event: Event) -> Option<Record> {
+    if config.inventory.is_empty() || crate::vm::is_template(name) {
+        return None;
+    }
+    let vm_dir = config.vm_dir(name);
+    let vm = match crate::vm::vm_details(config, name) {
+        Ok(vm) => vm,
+        Err(e) => {
+            warn!("inventory: cannot describe VM {}: {}", name, e);
+            return None;
+        }
+    };
+    Some(Record {
+        event,
+        timestamp: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        host: hostname(),
+        vm,
+        manifest: VmManifest::load(&vm_dir).ok(),
+        image: crate::labels::VmMetadata::load(&vm_dir)
+            .ok()
+            .and_then(|m| m.image),
+    })
+}
+
+/// Hand `record` to every exporter.
+pub async fn export(config: &Config, record: Option<Record>) {
+    let Some(record) = record else {
+        return;
+    };
+    let body = match serde_json::to_string(&record) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("inventory: cannot serialize record: {}", e);
+            return;
+        }
+    };
+    let exporters = &config.inventory;
+    if let Some(command) = &exporters.command {
+        let result =
+            tokio::time::timeout(EXPORT_TIMEOUT, run_command(command, &record, &body)).await;
+        match result {
+            Ok(Ok(())) => debug!(
+                "inventory: {} {} exported",
+                record.event.as_str(),
+                record.vm.name
+            ),
+            Ok(Err(e)) => warn!("inventory command failed for {}: {}", record.vm.name, e),
+            Err(_) => warn!(
+                "inventory command for {} timed out after {}s",
+                record.vm.name,
+                EXPORT_TIMEOUT.as_secs()
+            ),
+        }
+    }
+    if let Some(url) = &exporters.url {
+        if let Err(e) = post(url, body).await {
+            warn!(
+                "inventory POST to {} failed for {}: {}",
+                url, record.vm.name, e
+            );
+        }
+    }
+}
+
+/// `record` then `export`, for VMs that still exist.
+pub async fn notify(config: &Config, name: &str, event: Event) {
+    export(config, record(config, name, event)).await;
+}
+
+async fn run_command(command: &str, record: &Record, body: &str) -> Result<(), String> {
+    let mut child = tokio::process::Command::new("sh")
+        .args(["-c", command])
+        .env("MEDA_INVENTORY_EVENT", record.event.as_str())
+        .env("MEDA_INVENTORY_VM", &record.vm.name)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command that ignores its input closes the pipe early; that's fine.
+        let _ = stdin.write_all(body.as_bytes()).await;
+    }
+    let output = child.wait_with_output().await.map_err(|e| e.to_string())?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+async fn post(url: &str, body: String) -> Result<(), String> {
+    let response = crate::proxy::client()
+        .map_err(|e| e.to_string())?
+        .post(url)
+        .timeout(EXPORT_TIMEOUT)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("HTTP {}", response.status()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_exporters_from_env() {
+        let exporters = Exporters::from_env(|var| match var {
+            "MEDA_INVENTORY_COMMAND" => Some("cmdb-register".to_string()),
+            "MEDA_INVENTORY_URL" => Some(" ".to_string()),
+            _ => None,
+        });
+        assert_eq!(exporters.command.as_deref(), Some("cmdb-register"));
+        assert!(exporters.url.is_none());
+        assert!(Exporters::from_env(|_| None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_command_gets_record() {
+        let dir = TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.vm_root = dir.path().join("vms");
+        let out = dir.path().join("out.json");
+        config.inventory.command = Some(format!(
+            "{{ cat; echo; echo \"$MEDA_INVENTORY_EVENT $MEDA_INVENTORY_VM\"; }} > {}",
+            out.display()
+        ));
+        let vm_dir = config.vm_dir("web");
+        std::fs::create_dir_all(&vm_dir).unwrap();
+        VmManifest::new("web").save(&vm_dir).unwrap();
+
+        notify(&config, "web", Event::Created).await;
+
+        let written = std::fs::read_to_string(&out).unwrap();
+        let (json, env) = written.trim_end().rsplit_once('\n').unwrap();
+        let record: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(record["event"], "created");
+        assert_eq!(record["vm"]["name"], "web");
+        assert_eq!(record["manifest"]["name"], "web");
+        assert_eq!(env, "created web");
+
+        let template = format!("{}ubuntu", crate::image::TEMPLATE_PREFIX);
+        let template_dir = config.vm_dir(&template);
+        std::fs::create_dir_all(&template_dir).unwrap();
+        VmManifest::new(&template).save(&template_dir).unwrap();
+        assert!(super::record(&config, &template, Event::Created).is_none());
+    }
+}
@@ -0,0 +1,481 @@
+//! The cloud-init seed ISO (`ci.iso`), written without genisoimage.
+//!
+//! cloud-init's NoCloud datasource looks for a volume labelled `cidata`
+//! holding `meta-data`, `user-data` and friends. That takes very little
+//! of ISO 9660: one root directory of small files. [`image`] writes just
+//! that, with a Joliet tree so Linux sees the files under their real
+//! names (the ISO 9660 names are upper case, with `_` for `-`).
+//!
+//! Built with the `genisoimage` feature, meda falls back to the external
+//! tool for a seed the built-in writer can't handle, such as one with
+//! subdirectories.
+
+use crate::error::{Error, Result};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use std::fs;
+use std::path::Path;
+
+const SECTOR: usize = 2048;
+
+/// Volume descriptors start after the 16-sector system area.
+const PVD_SECTOR: usize = 16;
+const SVD_SECTOR: usize = 17;
+const TERMINATOR_SECTOR: usize = 18;
+/// Path tables: primary L and M, then Joliet L and M.
+const PATH_TABLE_SECTOR: usize = 19;
+const FIRST_DIR_SECTOR: usize = 23;
+
+/// Joliet names are at most 64 UCS-2 characters.
+const MAX_NAME: usize = 64;
+
+/// The ISO 9660 volume label cloud-init looks for.
+pub const CIDATA: &str = "cidata";
+
+struct Entry<'a> {
+    name: &'a str,
+    data: &'a [u8],
+    sector: usize,
+}
+
+fn sectors(bytes: usize) -> usize {
+    bytes.div_ceil(SECTOR)
+}
+
+fn both_u16(value: u16) -> [u8; 4] {
+    let [a, b] = value.to_le_bytes();
+    [a, b, b, a]
+}
+
+fn both_u32(value: u32) -> [u8; 8] {
+    let le = value.to_le_bytes();
+    let be = value.to_be_bytes();
+    [le[0], le[1], le[2], le[3], be[0], be[1], be[2], be[3]]
+}
+
+/// `name` as an ISO 9660 file identifier: d-characters, with a version.
+fn primary_name(name: &str) -> Vec<u8> {
+    let mut id: Vec<u8> = name
+        .bytes()
+        .map(|b| match b.to_ascii_uppercase() {
+            c @ (b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.') => c,
+            _ => b'_',
+        })
+        .take(30)
+        .collect();
+    if !id.contains(&b'.') {
+        id.push(b'.');
+    }
+    id.extend_from_slice(b";1");
+    id
+}
+
+fn joliet_name(name: &str) -> Vec<u8> {
+    name.encode_utf16().flat_map(u16::to_be_bytes).collect()
+}
+
+/// Text field of `len` bytes: ASCII padded with spaces, or UCS-2 padded
+/// with UCS-2 spaces for Joliet.
+fn text(value: &str, len: usize, joliet: bool) -> Vec<u8> {
+    let mut field = if joliet {
+        joliet_name(value)
+    } else {
+        value.as_bytes().to_vec()
+    };
+    let pad: &[u8] = if joliet { &[0, b' '] } else { b" " };
+    while field.len() < len {
+        field.extend_from_slice(pad);
+    }
+    field.truncate(len);
+    field
+}
+
+/// Directory record date: years since 1900, month, day, time, UTC.
+fn record_date(now: DateTime<Utc>) -> [u8; 7] {
+    [
+        (now.year() - 1900) as u8,
+        now.month() as u8,
+        now.day() as u8,
+        now.hour() as u8,
+        now.minute() as u8,
+        now.second() as u8,
+        0,
+    ]
+}
+
+/// Volume descriptor date: `YYYYMMDDHHMMSScc` and a UTC offset.
+fn volume_date(now: Option<DateTime<Utc>>) -> [u8; 17] {
+    let mut date = [b'0'; 17];
+    if let Some(now) = now {
+        date[..16].copy_from_slice(now.format("%Y%m%d%H%M%S00").to_string().as_bytes());
+    }
+    date[16] = 0;
+    date
+}
+
+fn dir_record(id: &[u8], sector: usize, size: usize, dir: bool, now: DateTime<Utc>) -> Vec<u8> {
+    let len = 33 + id.len() + (id.len() + 1) % 2;
+    let mut record = Vec::with_capacity(len);
+    record.push(len as u8);
+    record.push(0);
+    record.extend_from_slice(&both_u32(sector as u32));
+    record.extend_from_slice(&both_u32(size as u32));
+    record.extend_from_slice(&record_date(now));
+    record.push(if dir { 2 } else { 0 });
+    record.extend_from_slice(&[0, 0]);
+    record.extend_from_slice(&both_u16(1));
+    record.push(id.len() as u8);
+    record.extend_from_slice(id);
+    record.resize(len, 0);
+    record
+}
+
+/// The root directory's records, each within one sector, padded to
+/// whole sectors.
+fn directory(records: &[Vec<u8>]) -> Vec<u8> {
+    let mut dir = Vec::new();
+    for record in records {
+        let used = dir.len() % SECTOR;
+        if used + record.len() > SECTOR {
+            dir.resize(dir.len() + SECTOR - used, 0);
+        }
+        dir.extend_from_slice(record);
+    }
+    dir.resize(sectors(dir.len()) * SECTOR, 0);
+    dir
+}
+
+/// Root directory of one tree: `.`, `..` and the files sorted by
+/// identifier.
+fn root_records(
+    entries: &[Entry],
+    name: fn(&str) -> Vec<u8>,
+    sector: usize,
+    size: usize,
+    now: DateTime<Utc>,
+) -> Vec<Vec<u8>> {
+    let mut files: Vec<(Vec<u8>, &Entry)> = entries.iter().map(|e| (name(e.name), e)).collect();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut records = vec![
+        dir_record(&[0], sector, size, true, now),
+        dir_record(&[1], sector, size, true, now),
+    ];
+    records.extend(
+        files
+            .iter()
+            .map(|(id, e)| dir_record(id, e.sector, e.data.len(), false, now)),
+    );
+    records
+}
+
+/// Path table with just the root, in little- (L) or big-endian (M) order.
+fn path_table(root_sector: usize, big_endian: bool) -> Vec<u8> {
+    let mut table = vec![1, 0];
+    if big_endian {
+        table.extend_from_slice(&(root_sector as u32).to_be_bytes());
+        table.extend_from_slice(&1u16.to_be_bytes());
+    } else {
+        table.extend_from_slice(&(root_sector as u32).to_le_bytes());
+        table.extend_from_slice(&1u16.to_le_bytes());
+    }
+    table.extend_from_slice(&[0, 0]);
+    table
+}
+
+struct Volume<'a> {
+    label: &'a str,
+    total_sectors: usize,
+    path_table_sector: usize,
+    root: Vec<u8>,
+    joliet: bool,
+    now: DateTime<Utc>,
+}
+
+/// A primary (or, for Joliet, supplementary) volume descriptor.
+fn volume_descriptor(volume: &Volume) -> Vec<u8> {
+    let joliet = volume.joliet;
+    let mut d = vec![0u8; SECTOR];
+    d[0] = if joliet { 2 } else { 1 };
+    d[1..6].copy_from_slice(b"CD001");
+    d[6] = 1;
+    d[8..40].copy_from_slice(&text("LINUX", 32, joliet));
+    d[40..72].copy_from_slice(&text(volume.label, 32, joliet));
+    d[80..88].copy_from_slice(&both_u32(volume.total_sectors as u32));
+    if joliet {
+        // UCS-2 level 3.
+        d[88..91].copy_from_slice(b"%/E");
+    }
+    d[120..124].copy_from_slice(&both_u16(1));
+    d[124..128].copy_from_slice(&both_u16(1));
+    d[128..132].copy_from_slice(&both_u16(SECTOR as u16));
+    d[132..140].copy_from_slice(&both_u32(path_table(0, false).len() as u32));
+    d[140..144].copy_from_slice(&(volume.path_table_sector as u32).to_le_bytes());
+    d[148..152].copy_from_slice(&(volume.path_table_sector as u32 + 1).to_be_bytes());
+    d[156..190].copy_from_slice(&volume.root);
+    let mut offset = 190;
+    for len in [128, 128, 128, 128, 37, 37, 37] {
+        d[offset..offset + len].copy_from_slice(&text("", len, joliet));
+        offset += len;
+    }
+    let now = Some(volume.now);
+    d[813..830].copy_from_slice(&volume_date(now));
+    d[830..847].copy_from_slice(&volume_date(now));
+    d[847..864].copy_from_slice(&volume_date(None));
+    d[864..881].copy_from_slice(&volume_date(None));
+    d[881] = 1;
+    d
+}
+
+/// An ISO 9660 image labelled `label` with `files` in its root.
+pub fn image(label: &str, files: &[(String, Vec<u8>)], now: DateTime<Utc>) -> Result<Vec<u8>> {
+    for (name, _) in files {
+        if name.is_empty() || name.chars().count() > MAX_NAME || name.contains('/') {
+            return Err(Error::Other(format!(
+                "can't put {:?} in an ISO image: names must be 1 to {} characters",
+                name, MAX_NAME
+            )));
+        }
+    }
+    let mut primary: Vec<Vec<u8>> = files.iter().map(|(name, _)| primary_name(name)).collect();
+    primary.sort();
+    primary.dedup();
+    if primary.len() != files.len() {
+        return Err(Error::Other(
+            "file names in the ISO image collide once upper-cased".to_string(),
+        ));
+    }
+
+    // The directories' sizes don't depend on where the files go, so lay
+    // out the directories with placeholder locations first.
+    let placeholder: Vec<Entry> = files
+        .iter()
+        .map(|(name, data)| Entry {
+            name,
+            data,
+            sector: 0,
+        })
+        .collect();
+    let dir_len =
+        |name: fn(&str) -> Vec<u8>| directory(&root_records(&placeholder, name, 0, 0, now)).len();
+    let primary_dir_sector = FIRST_DIR_SECTOR;
+    let primary_dir_len = dir_len(primary_name);
+    let joliet_dir_sector = primary_dir_sector + sectors(primary_dir_len);
+    let joliet_dir_len = dir_len(joliet_name);
+    let mut next = joliet_dir_sector + sectors(joliet_dir_len);
+    let entries: Vec<Entry> = files
+        .iter()
+        .map(|(name, data)| {
+            let entry = Entry {
+                name,
+                data,
+                sector: next,
+            };
+            next += sectors(data.len());
+            entry
+        })
+        .collect();
+    let total_sectors = next;
+
+    let mut iso = vec![0u8; total_sectors * SECTOR];
+    let mut put = |sector: usize, bytes: &[u8]| {
+        iso[sector * SECTOR..sector * SECTOR + bytes.len()].copy_from_slice(bytes);
+    };
+    for (joliet, dir_sector, dir_len, name) in [
+        (
+            false,
+            primary_dir_sector,
+            primary_dir_len,
+            primary_name as fn(&str) -> Vec<u8>,
+        ),
+        (true, joliet_dir_sector, joliet_dir_len, joliet_name),
+    ] {
+        let path_table_sector = PATH_TABLE_SECTOR + if joliet { 2 } else { 0 };
+        put(path_table_sector, &path_table(dir_sector, false));
+        put(path_table_sector + 1, &path_table(dir_sector, true));
+        put(
+            dir_sector,
+            &directory(&root_records(&entries, name, dir_sector, dir_len, now)),
+        );
+        let volume = Volume {
+            label,
+            total_sectors,
+            path_table_sector,
+            root: dir_record(&[0], dir_sector, dir_len, true, now),
+            joliet,
+            now,
+        };
+        put(
+            if joliet { SVD_SECTOR } else { PVD_SECTOR },
+            &volume_descriptor(&volume),
+        );
+    }
+    let mut terminator = vec![255u8];
+    terminator.extend_from_slice(b"CD001\x01");
+    put(TERMINATOR_SECTOR, &terminator);
+    for entry in &entries {
+        put(entry.sector, entry.data);
+    }
+    Ok(iso)
+}
+
+/// Write the files in `ci_dir` to `iso` as a `cidata` seed, readable
+/// by its owner only.
+pub fn write_cidata(ci_dir: &Path, iso: &Path) -> Result<()> {
+    let mut files = Vec::new();
+    let mut nested = false;
+    for entry in fs::read_dir(ci_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            nested = true;
+            continue;
+        }
+        files.push((
+            entry.file_name().to_string_lossy().into_owned(),
+            fs::read(entry.path())?,
+        ));
+    }
+    files.sort();
+    let built = if nested {
+        Err(Error::Other(format!(
+            "{} has subdirectories, which the built-in ISO writer doesn't support",
+            ci_dir.display()
+        )))
+    } else {
+        image(CIDATA, &files, Utc::now())
+    };
+    let written = match built {
+        Ok(bytes) => Ok(fs::write(iso, bytes)?),
+        #[cfg(feature = "genisoimage")]
+        Err(e) => {
+            log::debug!("Falling back to genisoimage: {}", e);
+            crate::util::ensure_dependency("genisoimage", "genisoimage")?;
+            crate::util::run_command(
+                "genisoimage",
+                &[
+                    "-output",
+                    iso.to_str().unwrap(),
+                    "-volid",
+                    CIDATA,
+                    "-joliet",
+                    "-rock",
+                    ci_dir.to_str().unwrap(),
+                ],
+            )
+        }
+        #[cfg(not(feature = "genisoimage"))]
+        Err(e) => Err(e),
+    };
+    written?;
+    // The seed can carry secrets (see `secrets`); owner only.
+    crate::secrets::restrict(iso)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn u32_at(iso: &[u8], offset: usize) -> usize {
+        u32::from_le_bytes(iso[offset..offset + 4].try_into().unwrap()) as usize
+    }
+
+    /// (identifier, contents) of the files in the root directory of the
+    /// volume described at `descriptor`.
+    fn read_root(iso: &[u8], descriptor: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let root = descriptor * SECTOR + 156;
+        let (dir, len) = (u32_at(iso, root + 2), u32_at(iso, root + 10));
+        let mut files = Vec::new();
+        let mut offset = dir * SECTOR;
+        while offset < dir * SECTOR + len {
+            let record_len = iso[offset] as usize;
+            if record_len == 0 {
+                offset = (offset / SECTOR + 1) * SECTOR;
+                continue;
+            }
+            let id_len = iso[offset + 32] as usize;
+            let id = iso[offset + 33..offset + 33 + id_len].to_vec();
+            if iso[offset + 25] & 2 == 0 {
+                let (start, size) = (u32_at(iso, offset + 2), u32_at(iso, offset + 10));
+                files.push((id, iso[start * SECTOR..start * SECTOR + size].to_vec()));
+            }
+            offset += record_len;
+        }
+        files
+    }
+
+    #[test]
+    fn test_image() {
+        let big = vec![b'x'; 5000];
+        let files = vec![
+            ("user-data".to_string(), b"#cloud-config\n".to_vec()),
+            ("meta-data".to_string(), b"instance-id: vm\n".to_vec()),
+            ("network-config".to_string(), big.clone()),
+            ("vendor-data".to_string(), Vec::new()),
+        ];
+        let iso = image(CIDATA, &files, Utc::now()).unwrap();
+        assert_eq!(iso.len() % SECTOR, 0);
+        assert_eq!(
+            &iso[PVD_SECTOR * SECTOR + 1..PVD_SECTOR * SECTOR + 6],
+            b"CD001"
+        );
+        assert_eq!(
+            &iso[PVD_SECTOR * SECTOR + 40..PVD_SECTOR * SECTOR + 47],
+            b"cidata "
+        );
+        assert_eq!(
+            &iso[SVD_SECTOR * SECTOR + 88..SVD_SECTOR * SECTOR + 91],
+            b"%/E"
+        );
+        assert_eq!(iso[TERMINATOR_SECTOR * SECTOR], 255);
+        assert_eq!(u32_at(&iso, PVD_SECTOR * SECTOR + 80) * SECTOR, iso.len());
+
+        let joliet = read_root(&iso, SVD_SECTOR);
+        let names: Vec<String> = joliet
+            .iter()
+            .map(|(id, _)| {
+                let units: Vec<u16> = id
+                    .chunks(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+                String::from_utf16(&units).unwrap()
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec!["meta-data", "network-config", "user-data", "vendor-data"]
+        );
+        assert_eq!(joliet[0].1, b"instance-id: vm\n");
+        assert_eq!(joliet[1].1, big);
+        assert!(joliet[3].1.is_empty());
+
+        let primary = read_root(&iso, PVD_SECTOR);
+        assert_eq!(primary[0].0, b"META_DATA.;1");
+        assert_eq!(primary[2].1, b"#cloud-config\n");
+    }
+
+    #[test]
+    fn test_image_rejects_bad_names() {
+        let now = Utc::now();
+        assert!(image(CIDATA, &[("a/b".to_string(), Vec::new())], now).is_err());
+        assert!(image(CIDATA, &[("x".repeat(65), Vec::new())], now).is_err());
+        // Same ISO 9660 name.
+        let clash = vec![
+            ("user-data".to_string(), Vec::new()),
+            ("user_data".to_string(), Vec::new()),
+        ];
+        assert!(image(CIDATA, &clash, now).is_err());
+    }
+
+    #[test]
+    fn test_write_cidata() {
+        let dir = TempDir::new().unwrap();
+        let ci = dir.path().join("ci");
+        fs::create_dir_all(&ci).unwrap();
+        fs::write(ci.join("meta-data"), "instance-id: vm\n").unwrap();
+        let iso = dir.path().join("ci.iso");
+        write_cidata(&ci, &iso).unwrap();
+        assert_eq!(read_root(&fs::read(&iso).unwrap(), SVD_SECTOR).len(), 1);
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&iso).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
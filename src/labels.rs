@@ -0,0 +1,201 @@
+//! VM labels and `meda list --filter` selectors.
+//!
+//! Labels are free-form `key=value` pairs set at create/run time and
+//! kept in `<vmdir>/metadata.json`, so orchestration layers can tag the
+//! VMs they own and select them again later:
+//!
+//! - `label=ci=true` — label `ci` is set to `true`
+//! - `label=ci` — label `ci` is set, to anything
+//! - `state=running` — VM state (`running`, `stopped`, `error`, or one of
+//!   the transient `creating`, `starting`, `stopping`)
+//!
+//! Several filters must all match.
+//!
+//! The same file records the image a VM was created from, which is
+//! what `meda prune` checks before removing an image, and the expiry
+//! time `meda reap` checks.
+
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+pub const METADATA_FILE: &str = "metadata.json";
+
+pub type Labels = BTreeMap<String, String>;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct VmMetadata {
+    #[serde(default)]
+    pub labels: Labels,
+    /// Image the VM was created from (`registry/org/name:tag`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// When `meda reap` deletes the VM (`meda run --ttl`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Guest environment variables, secret values redacted (see `guest_env`).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+impl VmMetadata {
+    /// Metadata of the VM in `vm_dir`; empty when none was written.
+    pub fn load(vm_dir: &Path) -> Result<Self> {
+        match fs::read_to_string(vm_dir.join(METADATA_FILE)) {
+            Ok(body) => Ok(serde_json::from_str(&body)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, vm_dir: &Path) -> Result<()> {
+        fs::write(
+            vm_dir.join(METADATA_FILE),
+            serde_json::to_string_pretty(self)?,
+        )?;
+        Ok(())
+    }
+}
+
+pub fn validate_labels(labels: &Labels) -> Result<()> {
+    labels.keys().try_for_each(|key| validate_key(key))
+}
+
+/// Validate `labels` and record them for the VM in `vm_dir`.
+pub fn write_labels(vm_dir: &Path, labels: &Labels) -> Result<()> {
+    validate_labels(labels)?;
+    let mut metadata = VmMetadata::load(vm_dir)?;
+    metadata.labels = labels.clone();
+    metadata.save(vm_dir)
+}
+
+/// Record that the VM in `vm_dir` was created from `image`.
+pub fn record_image(vm_dir: &Path, image: &str) -> Result<()> {
+    let mut metadata = VmMetadata::load(vm_dir)?;
+    metadata.image = Some(image.to_string());
+    metadata.save(vm_dir)
+}
+
+fn validate_key(key: &str) -> Result<()> {
+    let valid = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'));
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::Other(format!(
+            "Invalid label key '{}' (use letters, digits, '-', '_', '.' and '/')",
+            key
+        )))
+    }
+}
+
+/// Parse a `--label key=value` argument.
+pub fn parse_label(s: &str) -> Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| Error::Other(format!("Invalid label '{}' (expected key=value)", s)))?;
+    validate_key(key)?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmFilter {
+    Label(String, Option<String>),
+    State(String),
+}
+
+impl FromStr for VmFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some(("label", selector)) => Ok(match selector.split_once('=') {
+                Some((key, value)) => Self::Label(key.to_string(), Some(value.to_string())),
+                None => Self::Label(selector.to_string(), None),
+            }),
+            Some(("state", state)) => Ok(Self::State(state.to_string())),
+            _ => Err(Error::Other(format!(
+                "Unknown filter '{}' (expected label=key[=value] or state=running|stopped|error|creating|starting|stopping)",
+                s
+            ))),
+        }
+    }
+}
+
+impl VmFilter {
+    pub fn matches(&self, state: &str, labels: &Labels) -> bool {
+        match self {
+            Self::Label(key, None) => labels.contains_key(key),
+            Self::Label(key, Some(value)) => labels.get(key) == Some(value),
+            Self::State(wanted) => state == wanted,
+        }
+    }
+}
+
+/// True when every filter matches.
+pub fn matches_all(filters: &[VmFilter], state: &str, labels: &Labels) -> bool {
+    filters.iter().all(|f| f.matches(state, labels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(
+            parse_label("ci=true").unwrap(),
+            ("ci".to_string(), "true".to_string())
+        );
+        assert_eq!(
+            parse_label("url=a=b").unwrap(),
+            ("url".to_string(), "a=b".to_string())
+        );
+        assert!(parse_label("novalue").is_err());
+        assert!(parse_label("bad key=x").is_err());
+        assert!(parse_label("=x").is_err());
+    }
+
+    #[test]
+    fn test_filters() {
+        let labels: Labels = [("ci".to_string(), "true".to_string())].into();
+        let f = |s: &str| s.parse::<VmFilter>().unwrap();
+
+        assert!(f("label=ci=true").matches("stopped", &labels));
+        assert!(!f("label=ci=false").matches("stopped", &labels));
+        assert!(f("label=ci").matches("stopped", &labels));
+        assert!(!f("label=team").matches("stopped", &labels));
+        assert!(f("state=running").matches("running", &labels));
+        assert!(!matches_all(
+            &[f("label=ci=true"), f("state=running")],
+            "stopped",
+            &labels
+        ));
+        assert!(matches_all(&[], "stopped", &Labels::new()));
+        assert!("name=x".parse::<VmFilter>().is_err());
+    }
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let dir = TempDir::new().unwrap();
+        assert!(VmMetadata::load(dir.path()).unwrap().labels.is_empty());
+
+        let labels: Labels = [("team".to_string(), "infra".to_string())].into();
+        write_labels(dir.path(), &labels).unwrap();
+        assert_eq!(VmMetadata::load(dir.path()).unwrap().labels, labels);
+
+        record_image(dir.path(), "ghcr.io/cirunlabs/ubuntu:latest").unwrap();
+        let metadata = VmMetadata::load(dir.path()).unwrap();
+        assert_eq!(metadata.labels, labels);
+        assert_eq!(
+            metadata.image.as_deref(),
+            Some("ghcr.io/cirunlabs/ubuntu:latest")
+        );
+    }
+}
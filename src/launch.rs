@@ -0,0 +1,307 @@
+//! Launching a VM's cloud-hypervisor process.
+//!
+//! Creating a VM records how to start it in `launch.json` in its dir:
+//! the root commands that prepare the host side (tap, dnsmasq,
+//! virtiofsd), the netns and CPUs CH runs in, and CH's arguments, with
+//! the ones meda changes later (vCPUs, memory, disks) kept as fields.
+//! `meda start` spawns CH from it directly, redirecting its output to
+//! `ch.log` and recording its pid; `resize` and `disk attach` edit the
+//! fields, and nothing has to parse a shell script to find out how a VM
+//! was configured.
+//!
+//! VMs created before launch.json have a `start.sh` instead, which
+//! `meda start` still runs until the VM is recreated.
+
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+/// File in a VM dir with its launch spec.
+pub const LAUNCH_FILE: &str = "launch.json";
+
+/// What VMs created by older versions of meda have instead.
+pub const LEGACY_START_SCRIPT: &str = "start.sh";
+
+/// How long CH gets to open its API socket before it counts as failed.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sockets CH creates as root, which the host user talks to.
+const SOCKETS: &[&str] = &[
+    "api.sock",
+    crate::console::SERIAL_SOCKET,
+    crate::console::CH_SERIAL_SOCKET,
+    crate::agent::VSOCK_SOCKET,
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LaunchSpec {
+    pub ch_bin: PathBuf,
+    /// Run CH as root through sudo. Only VMs on the host-tap path of
+    /// `meda run` run it as the user.
+    pub privileged: bool,
+    /// Network namespace to run CH in; `None` for the host's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub netns: Option<String>,
+    /// Host CPU list CH is pinned to with taskset (see `cpu_affinity`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_affinity: Option<String>,
+    /// Shell lines run as root before CH starts.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub setup: String,
+    pub cpus: u8,
+    pub memory: String,
+    /// vCPUs and memory the VM can be hotplugged up to (see `hotplug`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_cpus: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_memory: Option<String>,
+    /// `--memory` options after the size, e.g. `hugepages=on`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub memory_options: Vec<String>,
+    /// `--disk` values: root disk, cloud-init ISO, then any others.
+    pub disks: Vec<String>,
+    /// All other CH arguments.
+    pub args: Vec<String>,
+}
+
+impl LaunchSpec {
+    pub fn load(vm_dir: &Path) -> Option<Self> {
+        let body = fs::read_to_string(vm_dir.join(LAUNCH_FILE)).ok()?;
+        serde_json::from_str(&body).ok()
+    }
+
+    pub fn save(&self, vm_dir: &Path) -> Result<()> {
+        let path = vm_dir.join(LAUNCH_FILE);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// The spec with every occurrence of `from` in the setup, disks and
+    /// arguments replaced by `to`: moves it to another VM dir or tap.
+    pub fn replace(&self, from: &str, to: &str) -> Self {
+        let replace_all = |values: &[String]| -> Vec<String> {
+            values.iter().map(|v| v.replace(from, to)).collect()
+        };
+        Self {
+            setup: self.setup.replace(from, to),
+            disks: replace_all(&self.disks),
+            args: replace_all(&self.args),
+            ..self.clone()
+        }
+    }
+
+    /// The full command line, wrappers included.
+    pub fn command(&self) -> Vec<String> {
+        let mut argv: Vec<String> = Vec::new();
+        if self.privileged {
+            argv.push("sudo".to_string());
+        }
+        if let Some(netns) = &self.netns {
+            argv.extend(["ip", "netns", "exec", netns].map(String::from));
+        }
+        if let Some(cpus) = &self.cpu_affinity {
+            argv.extend(["taskset", "-c", cpus].map(String::from));
+        }
+        argv.push(self.ch_bin.display().to_string());
+        argv.push("--cpus".to_string());
+        argv.push(match self.max_cpus.filter(|max| *max > self.cpus) {
+            Some(max) => format!("boot={},max={}", self.cpus, max),
+            None => format!("boot={}", self.cpus),
+        });
+        let hotplug_size = self
+            .max_memory
+            .as_deref()
+            .and_then(|max| crate::hotplug::hotplug_size(&self.memory, max))
+            .map(|size| format!("hotplug_size={}", size));
+        argv.push("--memory".to_string());
+        argv.push(
+            std::iter::once(format!("size={}", self.memory))
+                .chain(self.memory_options.iter().cloned())
+                .chain(hotplug_size)
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        argv.push("--disk".to_string());
+        argv.extend(self.disks.iter().cloned());
+        argv.extend(self.args.iter().cloned());
+        argv
+    }
+}
+
+/// The pid of the process sudo started: sudo stays around as its parent
+/// (with `use_pty`, as the parent of a sudo monitor that is the parent),
+/// but stats and stop want CH itself. `ip netns exec` and taskset exec
+/// into CH, so it is the first process below sudo that isn't sudo.
+async fn sudo_child(sudo_pid: u32) -> Option<u32> {
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let mut pid = sudo_pid;
+    loop {
+        let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+        if comm.trim() != "sudo" {
+            return Some(pid);
+        }
+        let children = format!("/proc/{0}/task/{0}/children", pid);
+        loop {
+            let body = fs::read_to_string(&children).ok()?;
+            if let Some(child) = body.split_whitespace().next() {
+                pid = child.parse().ok()?;
+                break;
+            }
+            if Instant::now() > deadline {
+                return None;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+/// Start CH for the VM in `vm_dir` as `spec` says and wait for its API
+/// socket. The pid goes to `<vm_dir>/pid`; CH keeps running after meda
+/// exits.
+pub async fn spawn(vm_dir: &Path, spec: &LaunchSpec) -> Result<()> {
+    // Sockets from the last run are root-owned and make CH fail to bind.
+    let stale: Vec<String> = SOCKETS
+        .iter()
+        .map(|s| vm_dir.join(s).display().to_string())
+        .collect();
+    if spec.privileged || !spec.setup.is_empty() {
+        let setup = format!("rm -f {}\n{}", stale.join(" "), spec.setup);
+        crate::util::run_command("sudo", &["bash", "-c", &setup]).await?;
+    } else {
+        for path in &stale {
+            fs::remove_file(path).ok();
+        }
+    }
+
+    let log_path = vm_dir.join("ch.log");
+    let log = fs::File::create(&log_path)?;
+    let command = spec.command();
+    log::debug!("Launching: {}", command.join(" "));
+    let mut child = tokio::process::Command::new(&command[0])
+        .args(&command[1..])
+        .current_dir(vm_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(log.try_clone()?))
+        .stderr(Stdio::from(log))
+        .spawn()
+        .map_err(|e| Error::CommandFailed(format!("spawn {}: {}", command[0], e)))?;
+    let launched = child
+        .id()
+        .ok_or_else(|| Error::CommandFailed("cloud-hypervisor exited at once".to_string()))?;
+    let pid = if spec.privileged {
+        sudo_child(launched).await.unwrap_or(launched)
+    } else {
+        launched
+    };
+    fs::write(vm_dir.join("pid"), pid.to_string())?;
+
+    let api_sock = vm_dir.join("api.sock");
+    let deadline = Instant::now() + SOCKET_TIMEOUT;
+    while !api_sock.exists() {
+        if let Some(status) = child.try_wait()? {
+            fs::remove_file(vm_dir.join("pid")).ok();
+            return Err(Error::CommandFailed(format!(
+                "Cloud Hypervisor failed to start ({}). Check log: {}",
+                status,
+                log_path.display()
+            )));
+        }
+        if Instant::now() > deadline {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    // CH ran as root, so its sockets are root's. Relax perms so later
+    // ch-remote calls from the unprivileged user (meda snapshot, meda
+    // get, etc.) can talk to it.
+    if spec.privileged {
+        let sockets: Vec<&str> = stale
+            .iter()
+            .map(String::as_str)
+            .filter(|s| Path::new(s).exists())
+            .collect();
+        if !sockets.is_empty() {
+            let mut args = vec!["chmod", "0666"];
+            args.extend(sockets);
+            crate::util::run_command("sudo", &args).await.ok();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn spec() -> LaunchSpec {
+        LaunchSpec {
+            ch_bin: PathBuf::from("/assets/cloud-hypervisor"),
+            privileged: true,
+            netns: Some("meda-1234".to_string()),
+            cpu_affinity: Some("2-3".to_string()),
+            setup: String::new(),
+            cpus: 2,
+            memory: "1G".to_string(),
+            max_cpus: None,
+            max_memory: None,
+            memory_options: vec!["hugepages=on".to_string()],
+            disks: vec![
+                "path=/vms/a/rootfs.raw,image_type=raw".to_string(),
+                "path=/vms/a/ci.iso".to_string(),
+            ],
+            args: vec!["--console".to_string(), "off".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_command() {
+        assert_eq!(
+            spec().command().join(" "),
+            "sudo ip netns exec meda-1234 taskset -c 2-3 /assets/cloud-hypervisor \
+             --cpus boot=2 --memory size=1G,hugepages=on \
+             --disk path=/vms/a/rootfs.raw,image_type=raw path=/vms/a/ci.iso --console off"
+        );
+
+        let plain = LaunchSpec {
+            privileged: false,
+            netns: None,
+            cpu_affinity: None,
+            memory_options: Vec::new(),
+            ..spec()
+        };
+        assert_eq!(plain.command()[0], "/assets/cloud-hypervisor");
+        assert!(plain.command().contains(&"size=1G".to_string()));
+
+        let hotplug = LaunchSpec {
+            max_cpus: Some(8),
+            max_memory: Some("4G".to_string()),
+            ..plain
+        };
+        let command = hotplug.command();
+        assert!(command.contains(&"boot=2,max=8".to_string()));
+        assert!(command.contains(&"size=1G,hotplug_size=3072M".to_string()));
+    }
+
+    #[test]
+    fn test_replace() {
+        let moved = spec().replace("/vms/a/", "/vms/b/");
+        assert_eq!(moved.disks[1], "path=/vms/b/ci.iso");
+        assert_eq!(moved.ch_bin, spec().ch_bin);
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(LaunchSpec::load(dir.path()), None);
+        spec().save(dir.path()).unwrap();
+        assert_eq!(LaunchSpec::load(dir.path()), Some(spec()));
+        assert!(!dir.path().join("launch.json.tmp").exists());
+    }
+}
@@ -0,0 +1,293 @@
+//! Where meda keeps its files, and `meda migrate-dirs`.
+//!
+//! Two layouts exist:
+//!
+//! - `legacy` — everything under `~/.meda` (`ssh/`, `assets/`, `vms/`).
+//! - `xdg` — config (SSH keys, API tokens) in `$XDG_CONFIG_HOME/meda`,
+//!   VM disks in `$XDG_DATA_HOME/meda/vms`, and downloaded images and
+//!   binaries in `$XDG_CACHE_HOME/meda/assets`, so backups can skip the
+//!   cache and cleanup tools can wipe it.
+//!
+//! `MEDA_LAYOUT` picks one explicitly. Otherwise hosts that still have
+//! an unmigrated `~/.meda` keep the legacy layout and everything else
+//! gets XDG. `meda migrate-dirs` moves a legacy tree over, leaving
+//! symlinks behind so absolute paths baked into launch specs and qcow2
+//! backing files keep resolving.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const LEGACY_DIR: &str = ".meda";
+
+/// Written into `~/.meda` once its contents moved to the XDG dirs.
+const MIGRATED_MARKER: &str = "MIGRATED";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirLayout {
+    Legacy,
+    Xdg,
+}
+
+impl DirLayout {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "legacy" => Some(Self::Legacy),
+            "xdg" => Some(Self::Xdg),
+            _ => None,
+        }
+    }
+
+    /// Layout for a host whose home is `home`, given `MEDA_LAYOUT`.
+    pub fn detect(home: &Path, requested: Option<&str>) -> Self {
+        if let Some(value) = requested {
+            match Self::parse(value) {
+                Some(layout) => return layout,
+                None => log::warn!(
+                    "Ignoring invalid MEDA_LAYOUT '{}' (expected legacy or xdg)",
+                    value
+                ),
+            }
+        }
+        let legacy = home.join(LEGACY_DIR);
+        if legacy.is_dir() && !legacy.join(MIGRATED_MARKER).exists() {
+            Self::Legacy
+        } else {
+            Self::Xdg
+        }
+    }
+}
+
+/// Default config, asset (cache) and VM (data) dirs of a layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutDirs {
+    pub config: PathBuf,
+    pub assets: PathBuf,
+    pub vms: PathBuf,
+}
+
+impl LayoutDirs {
+    /// `env` looks up `XDG_*` variables; relative values are ignored as
+    /// the XDG spec requires.
+    pub fn resolve(layout: DirLayout, home: &Path, env: impl Fn(&str) -> Option<String>) -> Self {
+        match layout {
+            DirLayout::Legacy => {
+                let root = home.join(LEGACY_DIR);
+                Self {
+                    assets: root.join("assets"),
+                    vms: root.join("vms"),
+                    config: root,
+                }
+            }
+            DirLayout::Xdg => {
+                let base = |var: &str, default: &str| {
+                    env(var)
+                        .map(PathBuf::from)
+                        .filter(|p| p.is_absolute())
+                        .unwrap_or_else(|| home.join(default))
+                        .join("meda")
+                };
+                Self {
+                    config: base("XDG_CONFIG_HOME", ".config"),
+                    assets: base("XDG_CACHE_HOME", ".cache").join("assets"),
+                    vms: base("XDG_DATA_HOME", ".local/share").join("vms"),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrationReport {
+    pub dry_run: bool,
+    /// `(from, to)` pairs, in the order they are (or would be) moved.
+    pub moves: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Moves needed to turn `legacy` into `dirs`: `assets/` and `vms/` go
+/// to the cache and data dirs, everything else to the config dir.
+/// Entries that already are symlinks (moved before) are skipped.
+fn plan_moves(legacy: &Path, dirs: &LayoutDirs) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut moves = Vec::new();
+    let mut entries: Vec<_> = fs::read_dir(legacy)?.collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        if name == MIGRATED_MARKER || entry.file_type()?.is_symlink() {
+            continue;
+        }
+        let to = match name.to_str() {
+            Some("assets") => dirs.assets.clone(),
+            Some("vms") => dirs.vms.clone(),
+            _ => dirs.config.join(&name),
+        };
+        moves.push((entry.path(), to));
+    }
+    Ok(moves)
+}
+
+/// Move `~/.meda` into the XDG dirs.
+pub fn migrate(config: &Config, dry_run: bool) -> Result<MigrationReport> {
+    let home = dirs::home_dir().ok_or(Error::HomeDirNotFound)?;
+    let legacy = home.join(LEGACY_DIR);
+    if !legacy.is_dir() {
+        return Err(Error::Other(format!(
+            "{} does not exist; nothing to migrate",
+            legacy.display()
+        )));
+    }
+    let dirs = LayoutDirs::resolve(DirLayout::Xdg, &home, |v| std::env::var(v).ok());
+    let moves = plan_moves(&legacy, &dirs)?;
+
+    if !dry_run {
+        // Running VMs hold open files and sockets under the tree.
+        let running: Vec<String> = fs::read_dir(&config.vm_root)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter(|name| crate::vm::check_vm_running(config, name).unwrap_or(false))
+            .collect();
+        if !running.is_empty() {
+            return Err(Error::Other(format!(
+                "Stop these VMs before migrating: {}",
+                running.join(", ")
+            )));
+        }
+        for (from, to) in &moves {
+            move_and_link(from, to)?;
+        }
+        fs::write(
+            legacy.join(MIGRATED_MARKER),
+            format!(
+                "Moved to the XDG layout:\n  config: {}\n  cache:  {}\n  data:   {}\n",
+                dirs.config.display(),
+                dirs.assets.display(),
+                dirs.vms.display()
+            ),
+        )?;
+    }
+    Ok(MigrationReport { dry_run, moves })
+}
+
+fn move_and_link(from: &Path, to: &Path) -> Result<()> {
+    if to.exists() {
+        return Err(Error::Other(format!(
+            "Cannot move {} to {}: destination already exists",
+            from.display(),
+            to.display()
+        )));
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(from, to).map_err(|e| {
+        Error::Other(format!(
+            "Cannot move {} to {}: {} (across filesystems, move it by hand and re-run)",
+            from.display(),
+            to.display(),
+            e
+        ))
+    })?;
+    std::os::unix::fs::symlink(to, from)?;
+    Ok(())
+}
+
+pub fn migrate_command(config: &Config, dry_run: bool, json: bool) -> Result<()> {
+    let report = migrate(config, dry_run)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    if report.moves.is_empty() {
+        println!("Nothing to migrate");
+        return Ok(());
+    }
+    let verb = if dry_run { "Would move" } else { "Moved" };
+    for (from, to) in &report.moves {
+        println!("{} {} -> {}", verb, from.display(), to.display());
+    }
+    if !dry_run {
+        println!("✅ Migrated to the XDG layout; old paths are symlinks to the new ones");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_detect() {
+        let home = TempDir::new().unwrap();
+        assert_eq!(DirLayout::detect(home.path(), None), DirLayout::Xdg);
+        assert_eq!(
+            DirLayout::detect(home.path(), Some("legacy")),
+            DirLayout::Legacy
+        );
+
+        let legacy = home.path().join(LEGACY_DIR);
+        fs::create_dir(&legacy).unwrap();
+        assert_eq!(DirLayout::detect(home.path(), None), DirLayout::Legacy);
+        assert_eq!(DirLayout::detect(home.path(), Some("xdg")), DirLayout::Xdg);
+        assert_eq!(
+            DirLayout::detect(home.path(), Some("bogus")),
+            DirLayout::Legacy
+        );
+
+        fs::write(legacy.join(MIGRATED_MARKER), "").unwrap();
+        assert_eq!(DirLayout::detect(home.path(), None), DirLayout::Xdg);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let home = Path::new("/home/u");
+        let legacy = LayoutDirs::resolve(DirLayout::Legacy, home, |_| None);
+        assert_eq!(legacy.config, PathBuf::from("/home/u/.meda"));
+        assert_eq!(legacy.assets, PathBuf::from("/home/u/.meda/assets"));
+        assert_eq!(legacy.vms, PathBuf::from("/home/u/.meda/vms"));
+
+        let xdg = LayoutDirs::resolve(DirLayout::Xdg, home, |_| None);
+        assert_eq!(xdg.config, PathBuf::from("/home/u/.config/meda"));
+        assert_eq!(xdg.assets, PathBuf::from("/home/u/.cache/meda/assets"));
+        assert_eq!(xdg.vms, PathBuf::from("/home/u/.local/share/meda/vms"));
+
+        let xdg = LayoutDirs::resolve(DirLayout::Xdg, home, |var| match var {
+            "XDG_DATA_HOME" => Some("/srv/data".into()),
+            "XDG_CACHE_HOME" => Some("relative".into()),
+            _ => None,
+        });
+        assert_eq!(xdg.vms, PathBuf::from("/srv/data/meda/vms"));
+        assert_eq!(xdg.assets, PathBuf::from("/home/u/.cache/meda/assets"));
+    }
+
+    #[test]
+    fn test_plan_and_move() {
+        let home = TempDir::new().unwrap();
+        let legacy = home.path().join(LEGACY_DIR);
+        fs::create_dir_all(legacy.join("assets")).unwrap();
+        fs::create_dir_all(legacy.join("vms/vm1")).unwrap();
+        fs::create_dir_all(legacy.join("ssh")).unwrap();
+        let dirs = LayoutDirs::resolve(DirLayout::Xdg, home.path(), |_| None);
+
+        let moves = plan_moves(&legacy, &dirs).unwrap();
+        assert_eq!(
+            moves,
+            vec![
+                (legacy.join("assets"), dirs.assets.clone()),
+                (legacy.join("ssh"), dirs.config.join("ssh")),
+                (legacy.join("vms"), dirs.vms.clone()),
+            ]
+        );
+
+        for (from, to) in &moves {
+            move_and_link(from, to).unwrap();
+        }
+        assert!(dirs.vms.join("vm1").is_dir());
+        assert!(legacy.join("vms/vm1").is_dir());
+        assert!(plan_moves(&legacy, &dirs).unwrap().is_empty());
+    }
+}
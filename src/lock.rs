@@ -0,0 +1,108 @@
+//! Advisory `flock(2)` locks shared between meda processes.
+//!
+//! Locks are non-blocking: callers get `None` back when someone else
+//! holds a conflicting lock and decide themselves how to report it.
+//! The exception is [`FileLock::exclusive`], for short critical
+//! sections (ledger updates) where waiting is the right answer.
+//! The lock is released when the returned guard is dropped (the fd is
+//! closed), including when the process dies.
+//!
+//! A holder can leave a note in the lock file saying who it is, so the
+//! process that loses the race can tell the user what it is waiting on.
+
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+#[derive(Debug)]
+pub struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// Take a shared (reader) lock; any number may be held at once.
+    pub fn try_shared(path: &Path) -> io::Result<Option<Self>> {
+        Self::try_lock(path, FlockArg::LockSharedNonblock)
+    }
+
+    /// Take an exclusive (writer) lock; fails while any other lock is held.
+    pub fn try_exclusive(path: &Path) -> io::Result<Option<Self>> {
+        Self::try_lock(path, FlockArg::LockExclusiveNonblock)
+    }
+
+    /// Take an exclusive lock, waiting for other holders to let go.
+    pub fn exclusive(path: &Path) -> io::Result<Self> {
+        Self::try_lock(path, FlockArg::LockExclusive)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))
+    }
+
+    /// Record who holds the lock, for [`FileLock::holder`].
+    pub fn set_holder(&self, holder: &str) -> io::Result<()> {
+        self._file.set_len(0)?;
+        self._file.write_all_at(holder.as_bytes(), 0)
+    }
+
+    /// The note the current (or last) holder of `path` left, if any.
+    pub fn holder(path: &Path) -> Option<String> {
+        let note = fs::read_to_string(path).ok()?;
+        let note = note.trim();
+        (!note.is_empty()).then(|| note.to_string())
+    }
+
+    fn try_lock(path: &Path, arg: FlockArg) -> io::Result<Option<Self>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        match flock(file.as_raw_fd(), arg) {
+            Ok(()) => Ok(Some(Self { _file: file })),
+            Err(Errno::EWOULDBLOCK) => Ok(None),
+            Err(e) => Err(io::Error::from(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_shared_and_exclusive() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("x.lock");
+
+        let r1 = FileLock::try_shared(&path).unwrap().unwrap();
+        let r2 = FileLock::try_shared(&path).unwrap();
+        assert!(r2.is_some());
+        assert!(FileLock::try_exclusive(&path).unwrap().is_none());
+
+        drop(r1);
+        drop(r2);
+        let w = FileLock::try_exclusive(&path).unwrap().unwrap();
+        assert!(FileLock::try_shared(&path).unwrap().is_none());
+        drop(w);
+        assert!(FileLock::try_shared(&path).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_holder() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("x.lock");
+
+        let w = FileLock::try_exclusive(&path).unwrap().unwrap();
+        assert_eq!(FileLock::holder(&path), None);
+        w.set_holder("meda start (pid 42)").unwrap();
+        w.set_holder("meda stop (pid 42)").unwrap();
+        assert_eq!(
+            FileLock::holder(&path).as_deref(),
+            Some("meda stop (pid 42)")
+        );
+    }
+}
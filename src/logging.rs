@@ -0,0 +1,214 @@
+//! Log output: stderr when `RUST_LOG` is set, as before, plus an
+//! optional log file with size-based rotation.
+//!
+//! `meda serve` logs to `<config dir>/logs/meda.log` by default, since
+//! under systemd stderr is all there is otherwise; other commands only
+//! write a file when `MEDA_LOG_FILE` names one. `MEDA_LOG_FORMAT=json`
+//! switches both outputs to one JSON object per line.
+//!
+//! Every CLI command and API request runs in an `op` span with a short
+//! random `id` (API responses carry it as `X-Request-Id`), so all the
+//! lines of one failed create can be pulled out of a busy server's log
+//! with a grep.
+
+use crate::config::Config;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing_subscriber::{
+    fmt::MakeWriter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+const FORMAT_ENV: &str = "MEDA_LOG_FORMAT";
+const FILE_ENV: &str = "MEDA_LOG_FILE";
+const MAX_SIZE_ENV: &str = "MEDA_LOG_MAX_SIZE";
+const MAX_FILES_ENV: &str = "MEDA_LOG_MAX_FILES";
+
+/// Size a log file may grow to before it is rotated.
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Rotated files kept next to the live one (`meda.log.1` ...).
+const DEFAULT_MAX_FILES: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Short ID of a new operation, for its `op` span.
+pub fn op_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()[..8].to_string()
+}
+
+/// Where to write the log file, if anywhere: `MEDA_LOG_FILE` (`off`
+/// for none), else `<config dir>/logs/meda.log` for the API server.
+fn log_file(setting: Option<&str>, ch_home: &Path, daemon: bool) -> Option<PathBuf> {
+    match setting.map(str::trim) {
+        Some("" | "off" | "0" | "none") => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None => daemon.then(|| ch_home.join("logs").join("meda.log")),
+    }
+}
+
+fn layer<W>(format: Format, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        Format::Json => layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_filter(filter)
+            .boxed(),
+        Format::Text => layer.with_filter(filter).boxed(),
+    }
+}
+
+/// Set up logging for this process; `daemon` is true for `meda serve`.
+/// A log file that can't be opened is reported and skipped.
+pub fn init(config: &Config, daemon: bool) {
+    let format = match std::env::var(FORMAT_ENV).as_deref() {
+        Ok("json") => Format::Json,
+        _ => Format::Text,
+    };
+    let mut layers: Vec<BoxedLayer> = Vec::new();
+    if std::env::var("RUST_LOG").is_ok() {
+        layers.push(layer(format, io::stderr, io::stderr().is_terminal()));
+    }
+    let setting = std::env::var(FILE_ENV).ok();
+    if let Some(path) = log_file(setting.as_deref(), &config.ch_home, daemon) {
+        let max_size = std::env::var(MAX_SIZE_ENV)
+            .ok()
+            .and_then(|s| crate::util::parse_size_bytes(&s))
+            .unwrap_or(DEFAULT_MAX_SIZE);
+        let keep = crate::admission::env_u64(MAX_FILES_ENV, DEFAULT_MAX_FILES);
+        match RotatingFile::open(path.clone(), max_size, keep) {
+            Ok(file) => layers.push(layer(format, Mutex::new(file), false)),
+            Err(e) => eprintln!("Warning: cannot log to {}: {}", path.display(), e),
+        }
+    }
+    if !layers.is_empty() {
+        tracing_subscriber::registry().with(layers).init();
+    }
+}
+
+/// A log file that is moved aside to `<path>.1` once it reaches
+/// `max_size`, shifting older ones up to `<path>.<keep>`.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: u64,
+    file: File,
+    size: u64,
+}
+
+fn numbered(path: &Path, n: u64) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, keep: u64) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = Self::open_file(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn open_file(path: &Path) -> io::Result<File> {
+        // Logs name VMs, images and registries: owner only, like the
+        // rest of the config dir.
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path).ok();
+        } else {
+            for n in (1..self.keep).rev() {
+                fs::rename(numbered(&self.path, n), numbered(&self.path, n + 1)).ok();
+            }
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        }
+        self.file = Self::open_file(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_log_file() {
+        let home = Path::new("/home/ci/.meda");
+        assert_eq!(log_file(None, home, true), Some(home.join("logs/meda.log")));
+        assert_eq!(log_file(None, home, false), None);
+        assert_eq!(log_file(Some("off"), home, true), None);
+        assert_eq!(
+            log_file(Some("/var/log/meda.log"), home, false),
+            Some(PathBuf::from("/var/log/meda.log"))
+        );
+        assert_eq!(op_id().len(), 8);
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("logs").join("meda.log");
+        let mut log = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(numbered(&path, 1)).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(numbered(&path, 2)).unwrap(), "second\n");
+        assert!(!numbered(&path, 3).exists());
+
+        // Reopening carries on from the existing size.
+        let mut log = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        log.write_all(b"fifth\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fifth\n");
+        assert_eq!(fs::read_to_string(numbered(&path, 1)).unwrap(), "fourth\n");
+    }
+}
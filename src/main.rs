@@ -1,38 +1,98 @@
 mod admission;
+mod agent;
 mod api;
+mod apply;
+mod archive;
+mod assets;
+mod base_image;
+mod batch;
+mod boot;
+mod bridge;
+mod build;
 mod chunking;
 mod cli;
+mod completions;
+mod compression;
 mod config;
+mod console;
+mod context;
+mod cpu_affinity;
+mod credentials;
+mod dhcp;
+mod disk_usage;
+mod disks;
+mod doctor;
+mod egress;
 mod error;
 mod gpt;
+mod guest_env;
 mod host_capacity;
+mod hotplug;
 mod image;
+mod immutable;
+mod inventory;
+mod iso;
+mod labels;
+mod launch;
+mod layout;
+mod lock;
+mod logging;
+mod memory_backing;
+mod metrics;
+mod migrate;
 mod netns;
 mod network;
+mod nocloud;
+mod oci;
+mod oras;
+mod output;
+mod platform;
+mod preflight;
+mod progress;
+mod provenance;
+mod proxy;
+mod push;
+mod qemu_img;
+mod quota;
+mod remote;
+mod retention;
+mod rng;
+mod runner_image;
+mod schema;
+mod secrets;
 mod snapshot;
 mod ssh;
+mod state;
+mod stats;
+mod storage;
+mod subnets;
+mod system_info;
+mod ttl;
+mod up;
+mod uplink;
+mod usernet;
 mod util;
+mod virtiofs;
 mod vm;
+mod wait;
 
 use clap::Parser;
-use cli::{Cli, Commands};
-use config::Config;
+use cli::{
+    Cli, Commands, ContextCommands, DepsCommands, DiskCommands, ImageCommands, NetworkCommands,
+    RunnerImageArgs, RunnerImageCommands, SnapshotCommands, SnapshotPolicyCommands, SystemCommands,
+};
+use config::{Config, DiskFormat};
 use error::Result;
 use log::{error, info};
 use std::sync::Arc;
+use tracing::Instrument;
 
 #[tokio::main]
 async fn main() {
-    // Only initialize logger if RUST_LOG is set to avoid polluting stderr in tests/json mode
-    if std::env::var("RUST_LOG").is_ok() {
-        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-    }
-
     if let Err(e) = run().await {
-        // Only use logger if it was initialized, otherwise use eprintln
-        if std::env::var("RUST_LOG").is_ok() {
-            error!("{}", e);
-        } else {
+        // Goes to the log file, if any; stderr only has a logger with RUST_LOG.
+        error!("{}", e);
+        if std::env::var("RUST_LOG").is_err() {
             eprintln!("Error: {}", e);
         }
         std::process::exit(1);
@@ -41,7 +101,33 @@ async fn main() {
 
 async fn run() -> Result<()> {
     let cli = Cli::parse();
-    let config = Config::new()?;
+    progress::set_mode(cli.progress);
+    let mut config = Config::new()?;
+    config.offline |= cli.offline;
+    proxy::set(config.proxy.clone());
+    logging::init(&config, matches!(cli.command, Commands::Serve { .. }));
+
+    let op = tracing::info_span!("op", id = %logging::op_id());
+    run_command(cli, config).instrument(op).await
+}
+
+async fn run_command(cli: Cli, mut config: Config) -> Result<()> {
+    // Managing contexts, and the helpers meda starts itself, always run here.
+    let local_only = matches!(
+        cli.command,
+        Commands::Context { .. }
+            | Commands::Completions { .. }
+            | Commands::CompleteVms
+            | Commands::SerialRelay { .. }
+            | Commands::NocloudServe { .. }
+            | Commands::MigrateReceive { .. }
+    );
+    if !local_only {
+        if let Some(active) = context::active(&config, cli.context.as_deref())? {
+            info!("Running against context {}", active.name);
+            return remote::run(&active, cli.command, cli.json).await;
+        }
+    }
 
     info!("Meda - Cloud-Hypervisor VM Manager");
     info!("Working with VMs in: {}", config.vm_root.display());
@@ -50,29 +136,44 @@ async fn run() -> Result<()> {
         Commands::Create {
             name,
             user_data,
+            ssh_key,
+            label,
+            env,
+            secret_env,
+            secret,
+            storage,
+            isolate,
+            egress,
+            egress_interface,
+            rng,
+            network,
+            bridge,
             force,
             memory,
             cpus,
+            shared_memory,
+            hugepages,
+            prefault,
+            balloon,
+            cpu_affinity,
+            max_cpus,
+            max_memory,
             disk,
             device,
+            cow,
+            immutable_root,
+            data_disk,
+            data_mount,
+            mount,
+            vsock,
+            no_iso,
+            boot,
         } => {
+            if cow {
+                config.disk_format = DiskFormat::Qcow2;
+            }
             if force {
-                if !cli.json {
-                    info!("Force flag set, removing existing VM if present");
-                }
-                let vm_dir = config.vm_dir(&name);
-                if vm_dir.exists() {
-                    if vm::check_vm_running(&config, &name)? {
-                        if !cli.json {
-                            info!("Stopping existing VM: {}", name);
-                        }
-                        vm::stop(&config, &name, cli.json).await?;
-                    }
-                    if !cli.json {
-                        info!("Deleting existing VM: {}", name);
-                    }
-                    vm::delete(&config, &name, cli.json).await?;
-                }
+                vm::delete_existing(&config, &name, cli.json).await?;
             }
             let resources = vm::VmResources::from_config_with_overrides(
                 &config,
@@ -80,26 +181,154 @@ async fn run() -> Result<()> {
                 cpus,
                 disk.as_deref(),
                 device,
-            );
-            vm::create(&config, &name, user_data.as_deref(), &resources, cli.json).await?;
+            )
+            .with_storage(storage)
+            .with_isolation(isolate)
+            .with_egress(
+                egress
+                    .map(|e| egress::EgressPolicy::resolve(&config, &e))
+                    .transpose()?,
+            )
+            .with_egress_interface(egress_interface)
+            .with_rng(rng)
+            .with_network(crate::bridge::NetworkMode::new(
+                network.as_deref(),
+                bridge.as_deref(),
+            )?)
+            .with_memory_backing(memory_backing::MemoryBacking {
+                shared: shared_memory,
+                hugepages,
+                prefault,
+            })
+            .with_balloon(balloon)
+            .with_cpu_affinity(cpu_affinity)
+            .with_headroom(hotplug::Headroom::new(max_cpus, max_memory)?)
+            .with_immutable_root(immutable_root)
+            .with_data_disk(
+                data_disk
+                    .map(|size| immutable::DataDisk::new(&size, data_mount.as_deref()))
+                    .transpose()?,
+            )
+            .with_shares(mount)
+            .with_env(guest_env::GuestEnv::new(env, secret_env)?)
+            .with_secrets(secret)
+            .with_vsock(vsock)
+            .with_no_iso(no_iso)
+            .with_boot(boot.direct_boot()?);
+            vm::create(
+                &config,
+                &name,
+                user_data.as_deref(),
+                &ssh_key,
+                &label.into_iter().collect(),
+                &resources,
+                cli.json,
+            )
+            .await?;
+        }
+        Commands::List { output, filter } => match output {
+            Some(format) => {
+                output::print(&vm::filter_vms(vm::collect_vms(&config)?, &filter), &format)?
+            }
+            None => vm::list(&config, &filter, cli.json).await?,
+        },
+        Commands::Get { name, output } => match output {
+            Some(format) => output::print(&vm::vm_details(&config, &name)?, &format)?,
+            None => vm::get(&config, &name, cli.json).await?,
+        },
+        Commands::Wait {
+            name,
+            stage,
+            timeout,
+        } => {
+            wait::wait(
+                &config,
+                &name,
+                stage,
+                std::time::Duration::from_secs(timeout),
+                cli.json,
+            )
+            .await?;
+        }
+        Commands::Exec {
+            name,
+            timeout,
+            command,
+        } => {
+            let code = agent::exec(
+                &config,
+                &name,
+                &command.join(" "),
+                std::time::Duration::from_secs(timeout),
+                cli.json,
+            )?;
+            if code != 0 {
+                std::process::exit(code);
+            }
         }
-        Commands::List => {
-            vm::list(&config, cli.json).await?;
+        Commands::Ip { name, check, port } => {
+            if check {
+                let status = vm::ip_check(&config, &name, port, cli.json).await?;
+                if status != vm::IpStatus::Ready {
+                    std::process::exit(status.exit_code());
+                }
+            } else {
+                vm::ip(&config, &name, cli.json).await?;
+            }
         }
-        Commands::Get { name } => {
-            vm::get(&config, &name, cli.json).await?;
+        Commands::ConsoleDump { name, tail } => {
+            let serial = console::dump(&config, &name, tail)?;
+            if cli.json {
+                let result = serde_json::json!({"vm": name, "serial": serial});
+                println!("{}", serde_json::to_string_pretty(&result)?);
+            } else {
+                println!("{}", serial);
+            }
         }
-        Commands::Ip { name } => {
-            vm::ip(&config, &name, cli.json).await?;
+        Commands::Start { vms } => {
+            batch::run(
+                &config,
+                &vms.selection(),
+                batch::Operation::Start,
+                vms.parallel,
+                cli.json,
+            )
+            .await?;
         }
-        Commands::Start { name } => {
-            vm::start(&config, &name, cli.json).await?;
+        Commands::Stop {
+            vms,
+            timeout,
+            force,
+        } => {
+            let options = vm::StopOptions::from_flags(&config, timeout, force);
+            batch::run(
+                &config,
+                &vms.selection(),
+                batch::Operation::Stop(options),
+                vms.parallel,
+                cli.json,
+            )
+            .await?;
         }
-        Commands::Stop { name } => {
-            vm::stop(&config, &name, cli.json).await?;
+        Commands::Restart {
+            name,
+            timeout,
+            force,
+        } => {
+            let options = vm::StopOptions::from_flags(&config, timeout, force);
+            vm::restart(&config, &name, options, cli.json).await?;
         }
-        Commands::Delete { name } => {
-            vm::delete(&config, &name, cli.json).await?;
+        Commands::Delete { vms, force } => {
+            let selection = vms.selection();
+            selection.confirm_delete(force)?;
+            batch::run(
+                &config,
+                &selection,
+                batch::Operation::Delete,
+                vms.parallel,
+                cli.json,
+            )
+            .await?;
         }
         Commands::PortForward {
             name,
@@ -126,24 +355,60 @@ async fn run() -> Result<()> {
             }
         }
         Commands::Pull {
-            image,
+            mut images,
+            file,
+            parallel,
             registry,
             org,
+            artifacts,
         } => {
-            image::pull(
-                &config,
-                &image,
-                registry.as_deref(),
-                org.as_deref(),
-                cli.json,
-            )
-            .await?;
+            if let Some(file) = file {
+                images.extend(image::read_image_list(&file)?);
+            }
+            if images.len() != 1 {
+                if !artifacts.is_empty() {
+                    return Err(error::Error::Other(
+                        "--artifacts works with a single image".to_string(),
+                    ));
+                }
+                image::pull_many(
+                    &config,
+                    &images,
+                    registry.as_deref(),
+                    org.as_deref(),
+                    parallel,
+                    cli.json,
+                )
+                .await?;
+            } else if artifacts.is_empty() {
+                image::pull(
+                    &config,
+                    &images[0],
+                    registry.as_deref(),
+                    org.as_deref(),
+                    cli.json,
+                )
+                .await?;
+            } else {
+                image::pull_artifacts(
+                    &config,
+                    &images[0],
+                    registry.as_deref(),
+                    org.as_deref(),
+                    &artifacts,
+                    cli.json,
+                )
+                .await?;
+            }
         }
         Commands::Push {
             name,
             image,
             registry,
             dry_run,
+            provenance,
+            sign,
+            also_tag,
         } => {
             image::push(
                 &config,
@@ -151,13 +416,44 @@ async fn run() -> Result<()> {
                 &image,
                 registry.as_deref(),
                 dry_run,
+                image::PushOptions {
+                    provenance,
+                    sign,
+                    also_tags: also_tag,
+                },
                 cli.json,
             )
             .await?;
         }
-        Commands::Images => {
-            image::list(&config, cli.json).await?;
+        Commands::Login {
+            registry,
+            username,
+            password,
+            password_stdin,
+        } => {
+            let password = match password {
+                Some(password) => password,
+                None if password_stdin => {
+                    let mut password = String::new();
+                    std::io::stdin().read_line(&mut password)?;
+                    password.trim_end_matches(['\r', '\n']).to_string()
+                }
+                None => {
+                    return Err(error::Error::Other(
+                        "Give the password with --password-stdin (or --password)".to_string(),
+                    ))
+                }
+            };
+            let credentials = credentials::Credentials { username, password };
+            credentials::login(&config, &registry, credentials, cli.json).await?;
+        }
+        Commands::Logout { registry } => {
+            credentials::logout(&config, &registry, cli.json)?;
         }
+        Commands::Images { output, digests } => match output {
+            Some(format) => output::print(&image::collect_images(&config)?, &format)?,
+            None => image::list(&config, digests, cli.json).await?,
+        },
         Commands::Rmi {
             image,
             registry,
@@ -174,8 +470,106 @@ async fn run() -> Result<()> {
             )
             .await?;
         }
-        Commands::Prune { all, force } => {
-            image::prune(&config, all, force, cli.json).await?;
+        Commands::Tag {
+            source,
+            target,
+            registry,
+            org,
+        } => {
+            image::tag(
+                &config,
+                &source,
+                &target,
+                registry.as_deref(),
+                org.as_deref(),
+                cli.json,
+            )?;
+        }
+        Commands::Image { command } => match command {
+            ImageCommands::History {
+                image,
+                registry,
+                org,
+            } => {
+                image::history(
+                    &config,
+                    &image,
+                    registry.as_deref(),
+                    org.as_deref(),
+                    cli.json,
+                )?;
+            }
+            ImageCommands::Inspect {
+                image,
+                registry,
+                org,
+            } => {
+                image::inspect(
+                    &config,
+                    &image,
+                    registry.as_deref(),
+                    org.as_deref(),
+                    cli.json,
+                )?;
+            }
+            ImageCommands::Import {
+                path,
+                name,
+                os,
+                arch,
+            } => {
+                image::import_disk(
+                    &config,
+                    &path,
+                    &name,
+                    os.as_deref(),
+                    arch.as_deref(),
+                    cli.json,
+                )
+                .await?;
+            }
+            ImageCommands::Save {
+                image,
+                output,
+                registry,
+                org,
+            } => {
+                archive::save(
+                    &config,
+                    &image,
+                    &output,
+                    registry.as_deref(),
+                    org.as_deref(),
+                    cli.json,
+                )?;
+            }
+            ImageCommands::Load { archive } => {
+                archive::load(&config, &archive, cli.json)?;
+            }
+        },
+        Commands::Prune {
+            all,
+            older_than,
+            force,
+        } => {
+            let older_than = older_than
+                .map(|d| {
+                    util::parse_duration_secs(&d).ok_or_else(|| {
+                        error::Error::Other(format!(
+                            "Invalid --older-than '{}' (expected e.g. 7d, 12h, 30m)",
+                            d
+                        ))
+                    })
+                })
+                .transpose()?;
+            image::prune(&config, all, older_than, force, cli.json).await?;
+        }
+        Commands::ImportOci {
+            source,
+            name,
+            no_install,
+        } => {
+            oci::import(&config, &source, &name, !no_install, cli.json).await?;
         }
         Commands::CreateImage {
             name,
@@ -183,9 +577,10 @@ async fn run() -> Result<()> {
             registry,
             org,
             from_vm,
+            live,
         } => {
-            let default_registry = registry.as_deref().unwrap_or("ghcr.io");
-            let default_org = org.as_deref().unwrap_or("cirunlabs");
+            let default_registry = registry.as_deref().unwrap_or(&config.default_registry);
+            let default_org = org.as_deref().unwrap_or(&config.default_org);
 
             if let Some(vm_name) = from_vm {
                 image::create_from_vm(
@@ -195,6 +590,7 @@ async fn run() -> Result<()> {
                     &tag,
                     default_registry,
                     default_org,
+                    live,
                     cli.json,
                 )
                 .await?;
@@ -216,49 +612,116 @@ async fn run() -> Result<()> {
             registry,
             org,
             user_data,
+            ssh_key,
+            label,
+            env,
+            secret_env,
+            secret,
+            storage,
+            isolate,
+            egress,
+            egress_interface,
+            rng,
             no_start,
             memory,
             cpus,
             disk,
             device,
+            cow,
+            immutable_root,
+            data_disk,
+            data_mount,
+            ttl,
             cold,
             ssh,
+            boot,
         } => {
+            if cow {
+                config.disk_format = DiskFormat::Qcow2;
+            }
             let resources = vm::VmResources::from_config_with_overrides(
                 &config,
                 memory.as_deref(),
                 cpus,
                 disk.as_deref(),
                 device,
-            );
+            )
+            .with_storage(storage)
+            .with_isolation(isolate)
+            .with_egress(
+                egress
+                    .map(|e| egress::EgressPolicy::resolve(&config, &e))
+                    .transpose()?,
+            )
+            .with_egress_interface(egress_interface)
+            .with_rng(rng)
+            .with_immutable_root(immutable_root)
+            .with_data_disk(
+                data_disk
+                    .map(|size| immutable::DataDisk::new(&size, data_mount.as_deref()))
+                    .transpose()?,
+            )
+            .with_env(guest_env::GuestEnv::new(env, secret_env)?)
+            .with_secrets(secret)
+            .with_boot(boot.direct_boot()?);
+            let labels: labels::Labels = label.into_iter().collect();
+            let cold = cold || resources.needs_cold_boot(&config);
+            // A cold boot with --ssh needs the VM's name up front to find
+            // it once it's up.
+            let name = match name {
+                None if ssh && cold => Some(image::generated_vm_name(&image::ImageRef::parse(
+                    &image,
+                    registry.as_deref().unwrap_or(&config.default_registry),
+                    org.as_deref().unwrap_or(&config.default_org),
+                )?)),
+                name => name,
+            };
             let options = image::RunOptions {
                 vm_name: name.as_deref(),
                 registry: registry.as_deref(),
                 org: org.as_deref(),
                 user_data_path: user_data.as_deref(),
+                ssh_keys: &ssh_key,
+                labels: &labels,
                 no_start,
                 resources,
+                ttl,
             };
             // `run_instant` allocates a timestamped VM name when
             // none is provided. With --ssh we need to know that
             // name *after* run returns (to feed to `ssh`), so run
             // the path in --json mode under the hood, parse the
-            // result, and then exec ssh.
+            // result, and then exec ssh. Options the template can't
+            // honour cold-boot the VM and wait for its SSH instead.
             if ssh {
-                let json_out = image::run_instant_capture(&config, &image, options).await?;
-                let host = json_out
-                    .get("host")
-                    .and_then(|v| v.as_str())
-                    .ok_or_else(|| error::Error::Other("no host IP in run output".into()))?;
-                let vm_name = json_out
-                    .get("vm")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("<vm>");
+                let (vm_name, host) = if cold {
+                    let vm_name = name.clone().unwrap_or_default();
+                    image::run_from_image(&config, &image, options, cli.json).await?;
+                    let ready = wait::until(
+                        &config,
+                        &vm_name,
+                        wait::Stage::Ssh,
+                        std::time::Duration::from_secs(300),
+                    )
+                    .await?;
+                    (vm_name, ready.ip)
+                } else {
+                    let json_out = image::run_instant_capture(&config, &image, options).await?;
+                    let host = json_out
+                        .get("host")
+                        .and_then(|v| v.as_str())
+                        .ok_or_else(|| error::Error::Other("no host IP in run output".into()))?;
+                    let vm_name = json_out
+                        .get("vm")
+                        .and_then(|v| v.as_str())
+                        .unwrap_or("<vm>");
+                    (vm_name.to_string(), host.to_string())
+                };
                 eprintln!("→ ssh cirun@{host}  (VM {vm_name}; keeps running after exit)");
                 let status = std::process::Command::new("ssh")
+                    .arg("-i")
+                    .arg(config.ssh_dir().join("id_ed25519"))
                     .args([
-                        "-i",
-                        "/home/ubuntu/.meda/ssh/id_ed25519",
                         "-o",
                         "StrictHostKeyChecking=no",
                         "-o",
@@ -282,53 +745,346 @@ async fn run() -> Result<()> {
                 image::run_instant(&config, &image, options, cli.json).await?;
             }
         }
-        Commands::Serve { port, host } => {
-            info!("Starting Meda API server on {}:{}", host, port);
+        Commands::Serve {
+            port,
+            bind,
+            reap_interval,
+        } => {
+            info!("Starting Meda API server on {}:{}", bind, port);
             let config_arc = Arc::new(config);
-            let app = api::create_router(config_arc, &host, port);
+            if let Some(secs) = reap_interval {
+                info!("Reaping expired VMs every {}s", secs);
+                tokio::spawn(ttl::reap_periodically(
+                    config_arc.clone(),
+                    std::time::Duration::from_secs(secs),
+                ));
+            }
+            let app = api::create_router(config_arc, &bind, port)?;
 
-            let listener = tokio::net::TcpListener::bind(format!("{}:{}", host, port)).await?;
-            info!("API server running on http://{}:{}", host, port);
-            info!(
-                "Swagger UI available at http://{}:{}/swagger-ui",
-                host, port
-            );
+            let listener = tokio::net::TcpListener::bind(format!("{}:{}", bind, port)).await?;
+            info!("API server listening on http://{}", listener.local_addr()?);
+            info!("Swagger UI available at http://{}:{}/docs", bind, port);
             info!(
                 "OpenAPI spec available at http://{}:{}/api/v1/openapi.json",
-                host, port
+                bind, port
             );
 
-            axum::serve(listener, app).await?;
+            axum::serve(listener, app)
+                .with_graceful_shutdown(api::shutdown_signal())
+                .await?;
+            info!("API server stopped");
+        }
+        Commands::Migrate {
+            name,
+            to,
+            remote_meda,
+            keep_source,
+        } => {
+            migrate::migrate(&config, &name, &to, &remote_meda, keep_source, cli.json).await?;
         }
-        Commands::Snapshot { name } => {
-            snapshot::snapshot(&config, &name, cli.json).await?;
+        Commands::MigrateReceive {
+            name,
+            check,
+            start,
+            disk,
+            memory,
+            cpus,
+        } => {
+            if check {
+                migrate::check_receive(&config, &name, &disk, &memory, cpus, start)?;
+            } else {
+                migrate::receive(&config, &name, start).await?;
+            }
         }
-        Commands::Restore { name } => {
-            snapshot::restore(&config, &name, cli.json).await?;
+        Commands::Snapshot { name, command } => match command {
+            None => {
+                let name = name.expect("clap requires a VM name without a subcommand");
+                snapshot::snapshot(&config, &name, cli.json).await?;
+            }
+            Some(SnapshotCommands::List { vm }) => retention::list(&config, &vm, cli.json)?,
+            Some(SnapshotCommands::Policy { command }) => match command {
+                SnapshotPolicyCommands::Set {
+                    vm,
+                    keep_last,
+                    keep_daily,
+                    keep_weekly,
+                    keep_monthly,
+                } => {
+                    let policy = retention::Policy {
+                        keep_last,
+                        keep_daily,
+                        keep_weekly,
+                        keep_monthly,
+                    };
+                    retention::set_policy(&config, &vm, policy, cli.json)?;
+                }
+                SnapshotPolicyCommands::Show { vm } => {
+                    retention::show_policy(&config, &vm, cli.json)?
+                }
+            },
+            Some(SnapshotCommands::Gc { vm, dry_run }) => {
+                retention::gc(&config, vm.as_deref(), dry_run, cli.json)?
+            }
+        },
+        Commands::Restore {
+            name,
+            new_identity,
+            snapshot: id,
+        } => {
+            if let Some(id) = id {
+                retention::promote(&config, &name, &id).await?;
+            }
+            if new_identity {
+                snapshot::restore_as_new(&config, &name, cli.json).await?;
+            } else {
+                snapshot::restore(&config, &name, cli.json).await?;
+            }
         }
         Commands::Templates => {
             snapshot::templates(&config, cli.json)?;
         }
-        Commands::Clone { template, new_name } => {
-            snapshot::clone_template(&config, &template, &new_name, cli.json).await?;
+        Commands::Clone {
+            template,
+            new_name,
+            storage,
+            disk,
+            cow,
+            start,
+        } => {
+            let pool = storage::select_pool(
+                &config,
+                storage.as_deref(),
+                &Default::default(),
+                &config.disk_size,
+            )?;
+            if disk || cow || start || !snapshot::has_snapshot(&config, &template) {
+                vm::clone(&config, &template, &new_name, pool, cow, start, cli.json).await?;
+            } else {
+                snapshot::clone_template(&config, &template, &new_name, pool, cli.json).await?;
+            }
         }
-        Commands::Cleanup { dry_run } => {
-            let cleaned_up = crate::network::cleanup_orphaned_tap_devices(&config).await?;
-
-            if cleaned_up.is_empty() {
-                info!("No orphaned TAP devices found");
-            } else if dry_run {
-                info!("Would clean up {} orphaned TAP devices:", cleaned_up.len());
-                for tap_name in cleaned_up {
-                    info!("  - {}", tap_name);
+        Commands::Resize {
+            name,
+            memory,
+            cpus,
+            disk,
+        } => {
+            vm::resize(
+                &config,
+                &name,
+                memory.as_deref(),
+                cpus,
+                disk.as_deref(),
+                cli.json,
+            )
+            .await?;
+        }
+        Commands::Hotplug { name, cpus, memory } => {
+            hotplug::hotplug(&config, &name, cpus, memory.as_deref(), cli.json).await?;
+        }
+        Commands::Capacity => {
+            host_capacity::capacity_command(&config, cli.json)?;
+        }
+        Commands::Quota => {
+            quota::quota_command(&config, cli.json)?;
+        }
+        Commands::SystemInfo => {
+            system_info::system_info_command(&config, cli.json)?;
+        }
+        Commands::Reap { dry_run } => {
+            ttl::reap_command(&config, dry_run, cli.json).await?;
+        }
+        Commands::Chunk {
+            file,
+            output,
+            chunk_size,
+        } => {
+            chunking::chunk_command(
+                config.chunking.clone(),
+                &file,
+                output.as_deref(),
+                chunk_size,
+                cli.json,
+            )?;
+        }
+        Commands::Assemble {
+            dir,
+            output,
+            verify,
+        } => {
+            chunking::assemble_command(
+                config.chunking.clone(),
+                &dir,
+                output.as_deref(),
+                verify,
+                cli.json,
+            )?;
+        }
+        Commands::Context { command } => match command {
+            ContextCommands::Add {
+                name,
+                url,
+                token,
+                token_stdin,
+            } => {
+                let token = match token {
+                    Some(token) => Some(token),
+                    None if token_stdin => {
+                        let mut token = String::new();
+                        std::io::stdin().read_line(&mut token)?;
+                        Some(token.trim_end_matches(['\r', '\n']).to_string())
+                    }
+                    None => None,
+                };
+                context::add(&config, &name, &url, token, cli.json)?;
+            }
+            ContextCommands::List => context::list(&config, cli.context.as_deref(), cli.json)?,
+            ContextCommands::Use { name } => context::use_context(&config, &name, cli.json)?,
+            ContextCommands::Remove { name } => context::remove(&config, &name, cli.json)?,
+        },
+        Commands::Completions { shell } => {
+            print!("{}", completions::script(shell));
+        }
+        Commands::CompleteVms => {
+            for name in vm::vm_names(&config) {
+                println!("{}", name);
+            }
+        }
+        Commands::SerialRelay { vm_dir } => console::relay(&vm_dir).await?,
+        Commands::NocloudServe { vm_dir, addr } => nocloud::serve(&vm_dir, &addr).await?,
+        Commands::Du { sort } => {
+            disk_usage::du_command(&config, sort, cli.json)?;
+        }
+        Commands::Stats { name, interval } => {
+            stats::stats_command(
+                &config,
+                name.as_deref(),
+                std::time::Duration::from_secs(interval.max(1)),
+                cli.json,
+            )
+            .await?;
+        }
+        Commands::Metrics {
+            once,
+            interval,
+            output,
+        } => {
+            metrics::run(&config, once, interval, output.as_deref()).await?;
+        }
+        Commands::Build {
+            file,
+            timeout,
+            force,
+            keep_on_failure,
+        } => {
+            build::build(
+                &config,
+                &file,
+                std::time::Duration::from_secs(timeout),
+                force,
+                keep_on_failure,
+                cli.json,
+            )
+            .await?;
+        }
+        Commands::Up {
+            file,
+            parallel,
+            rollback_on_failure,
+        } => {
+            up::up(&config, &file, parallel, rollback_on_failure, cli.json).await?;
+        }
+        Commands::Apply { file, dry_run } => {
+            apply::apply(&config, &file, dry_run, cli.json).await?;
+        }
+        Commands::Disk { command } => match command {
+            DiskCommands::Add {
+                vm,
+                size,
+                name,
+                format,
+            } => {
+                let format = DiskFormat::parse(&format).ok_or_else(|| {
+                    error::Error::Other(format!(
+                        "Invalid disk format '{}' (expected raw or qcow2)",
+                        format
+                    ))
+                })?;
+                disks::add(&config, &vm, &size, name.as_deref(), format, cli.json).await?;
+            }
+            DiskCommands::List { vm } => disks::list_command(&config, &vm, cli.json)?,
+            DiskCommands::Remove { vm, name } => {
+                disks::remove(&config, &vm, &name, cli.json).await?
+            }
+        },
+        Commands::Network { command } => match command {
+            NetworkCommands::Inspect { name } => {
+                network::inspect(&config, &name, cli.json).await?;
+            }
+            NetworkCommands::Prune { dry_run } => {
+                network::prune_command(&config, dry_run, cli.json).await?;
+            }
+        },
+        Commands::RunnerImage { command } => {
+            let runner_image = |args: &RunnerImageArgs| runner_image::RunnerImage {
+                name: args.name.clone(),
+                runner_version: args.runner_version.clone(),
+                registry: args
+                    .registry
+                    .clone()
+                    .unwrap_or_else(|| config.default_registry.clone()),
+                org: args
+                    .org
+                    .clone()
+                    .unwrap_or_else(|| config.default_org.clone()),
+            };
+            match command {
+                RunnerImageCommands::Build { args, force } => {
+                    let timeout = std::time::Duration::from_secs(args.timeout_minutes * 60);
+                    runner_image::build(&config, &runner_image(&args), timeout, force, cli.json)
+                        .await?;
                 }
-            } else {
-                info!("Cleaned up {} orphaned TAP devices:", cleaned_up.len());
-                for tap_name in cleaned_up {
-                    info!("  - Removed {}", tap_name);
+                RunnerImageCommands::Update { args } => {
+                    let timeout = std::time::Duration::from_secs(args.timeout_minutes * 60);
+                    runner_image::update(&config, &runner_image(&args), timeout, cli.json).await?;
                 }
             }
         }
+        Commands::Doctor => {
+            doctor::doctor_command(&config, cli.json)?;
+        }
+        Commands::Bootstrap { refresh } => {
+            vm::bootstrap(&config).await?;
+            if refresh {
+                base_image::refresh(&config, true).await?;
+            }
+        }
+        Commands::Deps { command } => match command {
+            DepsCommands::List => {
+                oras::deps_command(&config, cli.json).await?;
+            }
+            DepsCommands::Oras { version } => {
+                oras::install_command(&config, version, cli.json).await?;
+            }
+        },
+        Commands::System { command } => match command {
+            SystemCommands::Info => {
+                assets::system_info_command(&config, cli.json)?;
+            }
+            SystemCommands::Host => {
+                system_info::system_info_command(&config, cli.json)?;
+            }
+        },
+        // Kept for existing scripts; same as `meda network prune`.
+        Commands::Cleanup { dry_run } => {
+            network::prune_command(&config, dry_run, cli.json).await?;
+        }
+        Commands::MigrateDirs { dry_run } => {
+            layout::migrate_command(&config, dry_run, cli.json)?;
+        }
+        Commands::MigrateState { dry_run } => {
+            schema::migrate_state_command(&config, dry_run, cli.json)?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,272 @@
+//! How cloud-hypervisor backs a VM's guest memory.
+//!
+//! By default guest RAM is private anonymous memory, which the kernel
+//! may back with transparent huge pages (THP) if the host allows it.
+//! `meda create` can ask instead for:
+//!
+//! - `--shared-memory`: a shared mapping, needed by vhost-user devices
+//!   such as virtiofs. THP does not apply to it.
+//! - `--hugepages`: memory from the host's preallocated hugetlbfs pool.
+//!   The pool must have enough free pages when the VM starts, or CH
+//!   fails with an mmap error in ch.log.
+//! - `--prefault`: touch every page at boot, trading a slower start for
+//!   no page faults later.
+//!
+//! The choice is recorded in the VM dir and turned into `--memory`
+//! options in the VM's launch spec. Separately, `--balloon` adds a virtio-balloon
+//! device that starts deflated, hands pages the guest frees back to the
+//! host and gives way when the guest runs low; grow it with
+//! `ch-remote resize --balloon` to reclaim memory from an idle guest. `meda doctor` reports when the host can't honor
+//! it and what THP is set to.
+
+use crate::error::{Error, Result};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+/// File in a VM dir listing its non-default memory options.
+pub const MEMORY_BACKING_FILE: &str = "memory-backing";
+
+/// File in a VM dir whose presence adds a balloon device.
+pub const BALLOON_FILE: &str = "balloon";
+
+const THP_ENABLED: &str = "/sys/kernel/mm/transparent_hugepage/enabled";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryBacking {
+    pub shared: bool,
+    pub hugepages: bool,
+    pub prefault: bool,
+}
+
+impl MemoryBacking {
+    pub fn options(&self) -> Vec<&'static str> {
+        [
+            (self.shared, "shared"),
+            (self.hugepages, "hugepages"),
+            (self.prefault, "prefault"),
+        ]
+        .into_iter()
+        .filter_map(|(on, option)| on.then_some(option))
+        .collect()
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.options().is_empty()
+    }
+
+    pub fn load(vm_dir: &Path) -> Self {
+        let body = fs::read_to_string(vm_dir.join(MEMORY_BACKING_FILE)).unwrap_or_default();
+        let has = |option: &str| body.split(',').any(|o| o.trim() == option);
+        Self {
+            shared: has("shared"),
+            hugepages: has("hugepages"),
+            prefault: has("prefault"),
+        }
+    }
+
+    pub fn save(&self, vm_dir: &Path) -> Result<()> {
+        let path = vm_dir.join(MEMORY_BACKING_FILE);
+        if self.is_default() {
+            fs::remove_file(path).ok();
+        } else {
+            fs::write(path, self.options().join(","))?;
+        }
+        Ok(())
+    }
+
+    /// Options after the size in CH's `--memory size=…`.
+    pub fn ch_options(&self) -> Vec<String> {
+        self.options()
+            .iter()
+            .map(|option| format!("{}=on", option))
+            .collect()
+    }
+
+    /// Reasons a VM with `memory_bytes` of RAM and this backing can't
+    /// start on a host with `pool`; empty when it can.
+    pub fn problems(&self, memory_bytes: u64, pool: Option<&HugePages>) -> Vec<String> {
+        if !self.hugepages {
+            return Vec::new();
+        }
+        let Some(pool) = pool.filter(|p| p.total > 0) else {
+            return vec![
+                "hugepages requested but the host has no hugepage pool; reserve one with `sysctl vm.nr_hugepages=<pages>`".to_string(),
+            ];
+        };
+        let mut problems = Vec::new();
+        if memory_bytes % pool.page_bytes != 0 {
+            problems.push(format!(
+                "memory is not a multiple of the {} KiB hugepage size",
+                pool.page_bytes / 1024
+            ));
+        }
+        let needed = memory_bytes.div_ceil(pool.page_bytes);
+        if needed > pool.free {
+            problems.push(format!(
+                "needs {} free {} KiB hugepages, the host has {} (raise vm.nr_hugepages)",
+                needed,
+                pool.page_bytes / 1024,
+                pool.free
+            ));
+        }
+        problems
+    }
+}
+
+pub fn has_balloon(vm_dir: &Path) -> bool {
+    vm_dir.join(BALLOON_FILE).exists()
+}
+
+pub fn enable_balloon(vm_dir: &Path) -> Result<()> {
+    fs::write(vm_dir.join(BALLOON_FILE), "")?;
+    Ok(())
+}
+
+/// `--balloon` arguments for the launch spec; none for VMs without the
+/// device.
+pub fn balloon_ch_args(vm_dir: &Path) -> Vec<String> {
+    if !has_balloon(vm_dir) {
+        return Vec::new();
+    }
+    [
+        "--balloon",
+        "size=0,deflate_on_oom=on,free_page_reporting=on",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// The host's default-size hugetlbfs pool.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HugePages {
+    pub page_bytes: u64,
+    pub total: u64,
+    pub free: u64,
+}
+
+pub fn host_hugepages() -> Option<HugePages> {
+    parse_meminfo(&fs::read_to_string("/proc/meminfo").ok()?)
+}
+
+fn parse_meminfo(meminfo: &str) -> Option<HugePages> {
+    let field = |name: &str| {
+        meminfo.lines().find_map(|line| {
+            line.strip_prefix(name)?
+                .split_whitespace()
+                .next()?
+                .parse::<u64>()
+                .ok()
+        })
+    };
+    Some(HugePages {
+        page_bytes: field("Hugepagesize:")? * 1024,
+        total: field("HugePages_Total:")?,
+        free: field("HugePages_Free:")?,
+    })
+}
+
+/// The active THP mode (`always`, `madvise` or `never`), if the kernel
+/// has THP.
+pub fn thp_mode() -> Option<String> {
+    parse_thp_mode(&fs::read_to_string(THP_ENABLED).ok()?)
+}
+
+/// The sysfs file lists every mode with the active one in brackets.
+fn parse_thp_mode(enabled: &str) -> Option<String> {
+    let start = enabled.find('[')? + 1;
+    let end = start + enabled[start..].find(']')?;
+    Some(enabled[start..end].to_string())
+}
+
+/// Fail before launching CH when the host can't back the VM's memory.
+pub fn preflight(vm_dir: &Path, memory: &str) -> Result<()> {
+    let backing = MemoryBacking::load(vm_dir);
+    let Some(memory_bytes) = crate::util::parse_size_bytes(memory) else {
+        return Ok(());
+    };
+    let problems = backing.problems(memory_bytes, host_hugepages().as_ref());
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Other(format!(
+            "can't back {} of guest memory with hugepages: {}",
+            memory,
+            problems.join("; ")
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_save_load_and_ch_options() {
+        let dir = TempDir::new().unwrap();
+        assert!(MemoryBacking::load(dir.path()).is_default());
+        assert!(MemoryBacking::default().ch_options().is_empty());
+
+        let backing = MemoryBacking {
+            shared: true,
+            hugepages: true,
+            prefault: false,
+        };
+        backing.save(dir.path()).unwrap();
+        assert_eq!(MemoryBacking::load(dir.path()), backing);
+        assert_eq!(backing.ch_options(), ["shared=on", "hugepages=on"]);
+
+        MemoryBacking::default().save(dir.path()).unwrap();
+        assert!(!dir.path().join(MEMORY_BACKING_FILE).exists());
+    }
+
+    #[test]
+    fn test_balloon() {
+        let dir = TempDir::new().unwrap();
+        assert!(balloon_ch_args(dir.path()).is_empty());
+        enable_balloon(dir.path()).unwrap();
+        assert_eq!(
+            balloon_ch_args(dir.path()),
+            [
+                "--balloon",
+                "size=0,deflate_on_oom=on,free_page_reporting=on"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_host_parsing() {
+        let meminfo = "MemTotal:       16314604 kB\nHugePages_Total:     512\nHugePages_Free:      500\nHugepagesize:       2048 kB\n";
+        assert_eq!(
+            parse_meminfo(meminfo),
+            Some(HugePages {
+                page_bytes: 2 * 1024 * 1024,
+                total: 512,
+                free: 500
+            })
+        );
+        assert_eq!(
+            parse_thp_mode("always [madvise] never\n").as_deref(),
+            Some("madvise")
+        );
+    }
+
+    #[test]
+    fn test_problems() {
+        let hugepages = MemoryBacking {
+            hugepages: true,
+            ..Default::default()
+        };
+        let pool = HugePages {
+            page_bytes: 2 * 1024 * 1024,
+            total: 1024,
+            free: 512,
+        };
+        let gib = 1024 * 1024 * 1024;
+        assert!(hugepages.problems(gib, Some(&pool)).is_empty());
+        assert!(hugepages.problems(2 * gib, Some(&pool))[0].contains("needs 1024 free"));
+        assert!(hugepages.problems(gib, None)[0].contains("no hugepage pool"));
+        assert!(MemoryBacking::default().problems(2 * gib, None).is_empty());
+    }
+}
@@ -0,0 +1,380 @@
+//! OpenMetrics exposition of meda state.
+//!
+//! Everything is read straight from disk (VM dirs, image manifests), a
+//! `ps` probe per VM and the per-VM usage from `stats`, so the same
+//! payload can be produced by the API server's `/metrics` route and by
+//! `meda metrics` from cron for node_exporter's textfile collector — no
+//! daemon required.
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::image::ImageManifest;
+use crate::stats::VmStats;
+use crate::util::parse_size_bytes;
+use crate::{host_capacity, vm};
+use log::info;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+struct VmSample {
+    name: String,
+    running: bool,
+    memory_bytes: Option<u64>,
+    vcpus: Option<u64>,
+    disk_bytes: Option<u64>,
+    usage: VmStats,
+}
+
+struct ImageSample {
+    image: String,
+    size_bytes: u64,
+}
+
+/// Escape a label value per the OpenMetrics ABNF (`\`, `"`, newline).
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+fn collect_vms(config: &Config) -> Vec<VmSample> {
+    let mut vms = Vec::new();
+    let Ok(entries) = fs::read_dir(&config.vm_root) else {
+        return vms;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        vms.push(VmSample {
+            running: vm::check_vm_running(config, &name).unwrap_or(false),
+            memory_bytes: read_trimmed(&path.join("memory")).and_then(|m| parse_size_bytes(&m)),
+            vcpus: read_trimmed(&path.join("cpus")).and_then(|c| c.parse().ok()),
+            disk_bytes: read_trimmed(&path.join("disk_size")).and_then(|d| parse_size_bytes(&d)),
+            usage: crate::stats::collect(config, &name),
+            name,
+        });
+    }
+    vms.sort_by(|a, b| a.name.cmp(&b.name));
+    vms
+}
+
+/// Every `manifest.json` under `images/<registry>/<org>/<name>/<tag>/`.
+fn collect_images(config: &Config) -> Vec<ImageSample> {
+    fn walk(dir: &Path, depth: usize, out: &mut Vec<ImageSample>) {
+        if depth == 0 {
+            if let Ok(manifest) = ImageManifest::load(dir) {
+                let size_bytes = manifest
+                    .artifacts
+                    .values()
+                    .filter_map(|f| fs::metadata(dir.join(f)).ok())
+                    .map(|m| m.len())
+                    .sum();
+                out.push(ImageSample {
+                    image: format!(
+                        "{}/{}/{}:{}",
+                        manifest.registry, manifest.org, manifest.name, manifest.tag
+                    ),
+                    size_bytes,
+                });
+            }
+            return;
+        }
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            if entry.path().is_dir() {
+                walk(&entry.path(), depth - 1, out);
+            }
+        }
+    }
+
+    let mut images = Vec::new();
+    walk(&config.asset_dir.join("images"), 4, &mut images);
+    images.sort_by(|a, b| a.image.cmp(&b.image));
+    images
+}
+
+fn family(out: &mut String, name: &str, help: &str, unit: Option<&str>) {
+    family_of(out, "gauge", name, help, unit);
+}
+
+/// Counter samples are named `<name>_total`.
+fn family_of(out: &mut String, kind: &str, name: &str, help: &str, unit: Option<&str>) {
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    if let Some(unit) = unit {
+        let _ = writeln!(out, "# UNIT {} {}", name, unit);
+    }
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+}
+
+fn render_samples(
+    vms: &[VmSample],
+    images: &[ImageSample],
+    host_mem_gb: u64,
+    host_cpus: u32,
+    host_disk_gb: u64,
+) -> String {
+    let mut out = String::new();
+
+    let running = vms.iter().filter(|v| v.running).count();
+    family(&mut out, "meda_vms", "Number of VMs by state.", None);
+    let _ = writeln!(out, "meda_vms{{state=\"running\"}} {}", running);
+    let _ = writeln!(out, "meda_vms{{state=\"stopped\"}} {}", vms.len() - running);
+
+    family(
+        &mut out,
+        "meda_vm_up",
+        "Whether the VM process is running.",
+        None,
+    );
+    for v in vms {
+        let _ = writeln!(
+            out,
+            "meda_vm_up{{vm=\"{}\"}} {}",
+            escape_label(&v.name),
+            u8::from(v.running)
+        );
+    }
+
+    type Field = fn(&VmSample) -> Option<f64>;
+    let per_vm: [(&str, &str, &str, Option<&str>, Field); 10] = [
+        (
+            "gauge",
+            "meda_vm_memory_bytes",
+            "Configured guest memory.",
+            Some("bytes"),
+            |v| v.memory_bytes.map(|b| b as f64),
+        ),
+        ("gauge", "meda_vm_vcpus", "Configured vCPUs.", None, |v| {
+            v.vcpus.map(|c| c as f64)
+        }),
+        (
+            "gauge",
+            "meda_vm_disk_bytes",
+            "Configured root disk size.",
+            Some("bytes"),
+            |v| v.disk_bytes.map(|b| b as f64),
+        ),
+        (
+            "counter",
+            "meda_vm_cpu_seconds",
+            "CPU time used by the VM's hypervisor process.",
+            Some("seconds"),
+            |v| v.usage.cpu_seconds,
+        ),
+        (
+            "gauge",
+            "meda_vm_memory_rss_bytes",
+            "Resident memory of the VM's hypervisor process.",
+            Some("bytes"),
+            |v| v.usage.rss_bytes.map(|b| b as f64),
+        ),
+        (
+            "gauge",
+            "meda_vm_uptime_seconds",
+            "Time since the VM's hypervisor process started.",
+            Some("seconds"),
+            |v| v.usage.uptime_seconds.map(|s| s as f64),
+        ),
+        (
+            "gauge",
+            "meda_vm_disk_allocated_bytes",
+            "Host space taken by the root disk file.",
+            Some("bytes"),
+            |v| v.usage.disk_allocated_bytes.map(|b| b as f64),
+        ),
+        (
+            "gauge",
+            "meda_vm_disk_virtual_bytes",
+            "Root disk size as the guest sees it.",
+            Some("bytes"),
+            |v| v.usage.disk_virtual_bytes.map(|b| b as f64),
+        ),
+        (
+            "counter",
+            "meda_vm_network_receive_bytes",
+            "Bytes the guest received on its network link.",
+            Some("bytes"),
+            |v| v.usage.rx_bytes.map(|b| b as f64),
+        ),
+        (
+            "counter",
+            "meda_vm_network_transmit_bytes",
+            "Bytes the guest sent on its network link.",
+            Some("bytes"),
+            |v| v.usage.tx_bytes.map(|b| b as f64),
+        ),
+    ];
+    for (kind, name, help, unit, field) in per_vm {
+        family_of(&mut out, kind, name, help, unit);
+        let sample = if kind == "counter" {
+            format!("{name}_total")
+        } else {
+            name.to_string()
+        };
+        for v in vms {
+            if let Some(value) = field(v) {
+                let _ = writeln!(
+                    out,
+                    "{}{{vm=\"{}\"}} {}",
+                    sample,
+                    escape_label(&v.name),
+                    value
+                );
+            }
+        }
+    }
+
+    family(
+        &mut out,
+        "meda_image_size_bytes",
+        "On-disk size of a local image's artifacts.",
+        Some("bytes"),
+    );
+    for i in images {
+        let _ = writeln!(
+            out,
+            "meda_image_size_bytes{{image=\"{}\"}} {}",
+            escape_label(&i.image),
+            i.size_bytes
+        );
+    }
+
+    family(
+        &mut out,
+        "meda_host_memory_bytes",
+        "Total host memory.",
+        Some("bytes"),
+    );
+    let _ = writeln!(out, "meda_host_memory_bytes {}", host_mem_gb * GIB);
+    family(
+        &mut out,
+        "meda_host_cpus",
+        "Logical CPUs on the host.",
+        None,
+    );
+    let _ = writeln!(out, "meda_host_cpus {}", host_cpus);
+    family(
+        &mut out,
+        "meda_host_disk_bytes",
+        "Capacity of the filesystem holding the VM dir.",
+        Some("bytes"),
+    );
+    let _ = writeln!(out, "meda_host_disk_bytes {}", host_disk_gb * GIB);
+
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Current metrics payload, as served on `/metrics`.
+pub fn render(config: &Config) -> String {
+    render_samples(
+        &collect_vms(config),
+        &collect_images(config),
+        host_capacity::total_mem_gb(),
+        host_capacity::total_cpu(),
+        host_capacity::total_disk_gb(&config.vm_root),
+    )
+}
+
+/// Write via a temp file + rename so a scraper never reads a
+/// half-written file.
+fn write_atomic(path: &Path, body: &str) -> Result<()> {
+    let tmp = path.with_extension("prom.tmp");
+    fs::write(&tmp, body)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// `meda metrics`: print (or write to `output`) once, or keep refreshing
+/// every `interval` until interrupted.
+pub async fn run(config: &Config, once: bool, interval: u64, output: Option<&Path>) -> Result<()> {
+    loop {
+        let body = render(config);
+        match output {
+            Some(path) => write_atomic(path, &body)?,
+            None => print!("{}", body),
+        }
+        if once {
+            return Ok(());
+        }
+        if let Some(path) = output {
+            info!("Wrote metrics to {}", path.display());
+        }
+        tokio::time::sleep(Duration::from_secs(interval.max(1))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_samples() {
+        let vms = vec![
+            VmSample {
+                name: "a".to_string(),
+                running: true,
+                memory_bytes: Some(1 << 30),
+                vcpus: Some(2),
+                disk_bytes: Some(10 << 30),
+                usage: VmStats {
+                    cpu_seconds: Some(12.5),
+                    rx_bytes: Some(2048),
+                    ..Default::default()
+                },
+            },
+            VmSample {
+                name: "b\"q".to_string(),
+                running: false,
+                memory_bytes: None,
+                vcpus: None,
+                disk_bytes: None,
+                usage: VmStats::default(),
+            },
+        ];
+        let images = vec![ImageSample {
+            image: "ghcr.io/cirunlabs/ubuntu:latest".to_string(),
+            size_bytes: 42,
+        }];
+        let out = render_samples(&vms, &images, 16, 8, 100);
+
+        assert!(out.contains("meda_vms{state=\"running\"} 1\n"));
+        assert!(out.contains("meda_vms{state=\"stopped\"} 1\n"));
+        assert!(out.contains("meda_vm_up{vm=\"a\"} 1\n"));
+        assert!(out.contains("meda_vm_up{vm=\"b\\\"q\"} 0\n"));
+        assert!(out.contains("meda_vm_memory_bytes{vm=\"a\"} 1073741824\n"));
+        assert!(!out.contains("meda_vm_vcpus{vm=\"b"));
+        assert!(out.contains("# UNIT meda_vm_disk_bytes bytes\n"));
+        assert!(out.contains("# TYPE meda_vm_cpu_seconds counter\n"));
+        assert!(out.contains("meda_vm_cpu_seconds_total{vm=\"a\"} 12.5\n"));
+        assert!(out.contains("meda_vm_network_receive_bytes_total{vm=\"a\"} 2048\n"));
+        assert!(!out.contains("meda_vm_memory_rss_bytes{"));
+        assert!(
+            out.contains("meda_image_size_bytes{image=\"ghcr.io/cirunlabs/ubuntu:latest\"} 42\n")
+        );
+        assert!(out.contains("meda_host_cpus 8\n"));
+        assert!(out.contains(&format!("meda_host_memory_bytes {}\n", 16 * GIB)));
+        assert!(out.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("plain"), "plain");
+        assert_eq!(escape_label("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+}
@@ -0,0 +1,509 @@
+//! `meda migrate` — move a VM to another host, e.g. before host
+//! maintenance.
+//!
+//! Migration is cold: meda asks `meda migrate-receive --check` (hidden)
+//! on the destination whether it has room for the VM, adds the key that
+//! host's meda logs into guests with to the running guest, stops the VM,
+//! streams its dir as a sparse tar over SSH to `meda migrate-receive`,
+//! and starts it there if it was running. The receiver unpacks it into its
+//! own VM root and gives it a tap, netns and launch spec of that host's
+//! (see `vm::adopt`); subnet, MAC and cloud-init identity travel with the
+//! VM, so the guest comes back with the same address and host keys. Once
+//! the destination has it, the source copy is deleted unless
+//! `--keep-source`. If anything fails first, the VM is started again
+//! where it was.
+//!
+//! A qcow2 root disk backed by this host's base image is flattened into
+//! a standalone one for the trip. Snapshots stay behind: their memory
+//! state names this host's paths and tap. VMs with host directory shares,
+//! passed-through devices or an immutable root can't be moved, since
+//! those depend on this host.
+//!
+//! Live migration (cloud-hypervisor's send/receive-migration) needs the
+//! disks on storage both hosts see and isn't supported yet.
+
+use crate::config::{Config, DiskFormat};
+use crate::error::{Error, Result};
+use crate::vm;
+use log::{info, warn};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Name the flattened root disk travels under; the receiver renames it.
+const FLAT_ROOTFS: &str = ".migrate-rootfs.qcow2";
+
+/// Top-level entries of a VM dir that stay behind: rebuilt by the
+/// receiver, or tied to this host.
+const LEFT_BEHIND: &[&str] = &[
+    crate::snapshot::SNAPSHOT_DIR,
+    crate::retention::HISTORY_DIR,
+    crate::launch::LAUNCH_FILE,
+    "tapdev",
+    "pid",
+];
+
+/// Where `--to` points: `ssh://[user@]host[:port]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination {
+    /// `[user@]host`, as ssh takes it.
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl Destination {
+    pub fn parse(url: &str) -> Result<Self> {
+        let invalid = || {
+            Error::Other(format!(
+                "invalid destination '{}' (expected ssh://[user@]host[:port])",
+                url
+            ))
+        };
+        let rest = url.strip_prefix("ssh://").ok_or_else(invalid)?;
+        let rest = rest.strip_suffix('/').unwrap_or(rest);
+        let (host, port) = match rest.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port.parse().map_err(|_| invalid())?)),
+            None => (rest, None),
+        };
+        let valid = |s: &str| {
+            !s.is_empty()
+                && !s.starts_with('-')
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        };
+        let (user, hostname) = match host.split_once('@') {
+            Some((user, hostname)) => (Some(user), hostname),
+            None => (None, host),
+        };
+        if !valid(hostname) || user.is_some_and(|u| !valid(u)) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    fn url(&self) -> String {
+        match self.port {
+            Some(port) => format!("ssh://{}:{}", self.host, port),
+            None => format!("ssh://{}", self.host),
+        }
+    }
+
+    /// `ssh` running `remote` on the destination.
+    fn ssh(&self, remote: &str) -> Command {
+        let mut ssh = Command::new("ssh");
+        ssh.args(["-o", "BatchMode=yes"]);
+        if let Some(port) = self.port {
+            ssh.arg("-p").arg(port.to_string());
+        }
+        ssh.arg(&self.host).arg(remote);
+        ssh
+    }
+}
+
+/// VM names go into the destination's shell command line.
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\'']) {
+        return Err(Error::Other(format!(
+            "VM name '{}' can't be migrated; rename it first",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Why VM `name` can't leave this host, if it can't.
+fn check_movable(config: &Config, name: &str) -> Result<()> {
+    let vm_dir = config.vm_dir(name);
+    let blocker = if crate::immutable::is_immutable(&vm_dir) {
+        Some("has an immutable root")
+    } else if !crate::virtiofs::load(&vm_dir).is_empty() {
+        Some("shares host directories")
+    } else if !vm::get_vm_devices(config, name).is_empty() {
+        Some("has passed-through devices")
+    } else {
+        None
+    };
+    match blocker {
+        Some(why) => Err(Error::Other(format!(
+            "VM {} {}, which only this host can provide; it can't be migrated",
+            name, why
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// The top-level entries of `vm_dir` that travel.
+fn entries_to_send(vm_dir: &Path, flattened: bool) -> Result<Vec<String>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(vm_dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        let runtime = name.ends_with(".sock") || name.ends_with(".pid");
+        // With a flattened root disk, it goes instead of the original.
+        let replaced = if flattened {
+            name == DiskFormat::Qcow2.rootfs_name()
+        } else {
+            name == FLAT_ROOTFS
+        };
+        if !runtime && !replaced && !LEFT_BEHIND.contains(&name.as_str()) {
+            entries.push(name);
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrateResult {
+    pub vm: String,
+    pub destination: String,
+    pub started: bool,
+    pub source_deleted: bool,
+}
+
+/// Move VM `name` to `to`, where `remote_meda` is the meda binary.
+pub async fn migrate(
+    config: &Config,
+    name: &str,
+    to: &str,
+    remote_meda: &str,
+    keep_source: bool,
+    json: bool,
+) -> Result<()> {
+    let destination = Destination::parse(to)?;
+    if !config.vm_dir(name).exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+    check_name(name)?;
+    check_movable(config, name)?;
+    if remote_meda.contains('\'') {
+        return Err(Error::Other(format!(
+            "invalid --remote-meda '{}'",
+            remote_meda
+        )));
+    }
+
+    // Fail before the VM goes down if the destination can't take it.
+    let was_running = vm::check_vm_running(config, name)?;
+    let mib = |size: String| crate::util::parse_size_bytes(&size).unwrap_or(0) >> 20;
+    let mut check = format!(
+        "'{}' migrate-receive --check '{}' --disk {}M --memory {}M --cpus {}",
+        remote_meda,
+        name,
+        mib(vm::get_vm_disk_size(config, name)?),
+        mib(vm::get_vm_memory(config, name)?),
+        vm::get_vm_cpus(config, name)?.parse::<u8>().unwrap_or(1)
+    );
+    if was_running {
+        check.push_str(" --start");
+    }
+    let output = destination.ssh(&check).stdin(Stdio::null()).output()?;
+    if !output.status.success() {
+        return Err(Error::CommandFailed(format!(
+            "{} can't receive VM {}: {}",
+            destination.url(),
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let key = crate::ssh::find_key(&String::from_utf8_lossy(&output.stdout));
+    authorize_destination(config, name, &destination, key, was_running)?;
+
+    if was_running {
+        if !json {
+            info!("Stopping VM {} for migration", name);
+        }
+        vm::stop(config, name, json).await?;
+    }
+    let sent = send(config, name, &destination, remote_meda, was_running, json).await;
+    if let Err(e) = sent {
+        if was_running {
+            info!("Migration failed; starting VM {} here again", name);
+            vm::start(config, name, json).await.ok();
+        }
+        return Err(e);
+    }
+
+    if !keep_source {
+        vm::delete(config, name, json).await?;
+    }
+    let result = MigrateResult {
+        vm: name.to_string(),
+        destination: destination.url(),
+        started: was_running,
+        source_deleted: !keep_source,
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!("Migrated VM {} to {}", name, result.destination);
+    }
+    Ok(())
+}
+
+/// Add `key`, the destination meda's SSH key, to VM `name`'s guest while
+/// this host's key still gets in, so meda there can reach it.
+fn authorize_destination(
+    config: &Config,
+    name: &str,
+    destination: &Destination,
+    key: Option<String>,
+    running: bool,
+) -> Result<()> {
+    let Some(key) = key else {
+        warn!(
+            "{} did not report its meda SSH key; it may not be able to log into VM {}",
+            destination.url(),
+            name
+        );
+        return Ok(());
+    };
+    let own = crate::ssh::ensure_ssh_keypair(config)?.public_key;
+    let fields = |key: &str| key.split_whitespace().take(2).collect::<Vec<_>>().join(" ");
+    if fields(&key) == fields(&own) {
+        return Ok(());
+    }
+    if !running {
+        warn!(
+            "VM {} is stopped, so the meda SSH key of {} can't be added to it; meda there won't be able to log in",
+            name,
+            destination.url()
+        );
+        return Ok(());
+    }
+    let ip = vm::get_routable_ip(config, name)?;
+    crate::ssh::guest_exec(config, &ip, &crate::ssh::add_keys_command(&[key])).map_err(|e| {
+        Error::Other(format!(
+            "Failed to add the meda SSH key of {} to VM {}: {}",
+            destination.url(),
+            name,
+            e
+        ))
+    })
+}
+
+/// Stream stopped VM `name` to the destination's receiver.
+async fn send(
+    config: &Config,
+    name: &str,
+    destination: &Destination,
+    remote_meda: &str,
+    start: bool,
+    json: bool,
+) -> Result<()> {
+    let _lock = vm::lock(config, name, "migrate")?;
+    let vm_dir = config.vm_dir(name);
+    let flat = vm_dir.join(FLAT_ROOTFS);
+    let rootfs = vm_dir.join(DiskFormat::Qcow2.rootfs_name());
+    let flattened = rootfs.exists() && !crate::util::backing_chain(&rootfs).is_empty();
+    if flattened {
+        if !json {
+            info!("Flattening the root disk of VM {}", name);
+        }
+        crate::qemu_img::convert(&rootfs, "qcow2", &flat, "qcow2", json).await?;
+    }
+
+    let entries = entries_to_send(&vm_dir, flattened)?;
+    if !json {
+        info!("Sending VM {} to {}", name, destination.url());
+    }
+    let streamed = stream(&vm_dir, &entries, destination, remote_meda, name, start);
+    fs::remove_file(&flat).ok();
+    streamed
+}
+
+fn stream(
+    vm_dir: &Path,
+    entries: &[String],
+    destination: &Destination,
+    remote_meda: &str,
+    name: &str,
+    start: bool,
+) -> Result<()> {
+    let mut tar = Command::new("tar")
+        .arg("-C")
+        .arg(vm_dir)
+        .args(["--sparse", "-cf", "-", "--"])
+        .args(entries)
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::CommandFailed(format!("spawn tar: {}", e)))?;
+    let mut remote = format!("'{}' migrate-receive '{}'", remote_meda, name);
+    if start {
+        remote.push_str(" --start");
+    }
+    let receiver = destination
+        .ssh(&remote)
+        .stdin(Stdio::from(tar.stdout.take().expect("piped stdout")))
+        .status();
+    let tar_status = tar.wait()?;
+    let receiver = receiver?;
+    if !tar_status.success() {
+        return Err(Error::CommandFailed(format!(
+            "tar of {} failed ({})",
+            vm_dir.display(),
+            tar_status
+        )));
+    }
+    if !receiver.success() {
+        return Err(Error::CommandFailed(format!(
+            "{} did not take VM {} ({})",
+            destination.url(),
+            name,
+            receiver
+        )));
+    }
+    Ok(())
+}
+
+/// `meda migrate-receive --check`: whether VM `name`, with `disk`, and
+/// `memory` and `cpus` if it will `start`, fits here: the quotas, the
+/// admission budget and free disk. Prints this host's meda SSH key for
+/// the source to add to the guest.
+pub fn check_receive(
+    config: &Config,
+    name: &str,
+    disk: &str,
+    memory: &str,
+    cpus: u8,
+    start: bool,
+) -> Result<()> {
+    check_name(name)?;
+    if config.vm_dir(name).exists() {
+        return Err(Error::VmAlreadyExists(name.to_string()));
+    }
+    crate::quota::before_receive(config, name, disk, memory, cpus, start)?;
+    crate::preflight::before_create(
+        config,
+        &config.vm_root,
+        crate::util::parse_size_bytes(disk).unwrap_or(0),
+    )?;
+    println!("{}", crate::ssh::ensure_ssh_keypair(config)?.public_key);
+    Ok(())
+}
+
+/// `meda migrate-receive`: take VM `name` as a tar on stdin, make it
+/// runnable here and start it if `start`.
+pub async fn receive(config: &Config, name: &str, start: bool) -> Result<()> {
+    check_name(name)?;
+    let vm_dir = config.vm_dir(name);
+    if vm_dir.exists() {
+        return Err(Error::VmAlreadyExists(name.to_string()));
+    }
+
+    let lock = vm::lock(config, name, "migrate")?;
+    let staging = config.vm_root.join(format!(".migrate-{}", name));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+    let status = Command::new("tar")
+        .arg("-C")
+        .arg(&staging)
+        .args(["--sparse", "-xf", "-"])
+        .stdin(Stdio::inherit())
+        .status()?;
+    if !status.success() {
+        fs::remove_dir_all(&staging).ok();
+        return Err(Error::CommandFailed(format!(
+            "unpacking VM {} failed ({})",
+            name, status
+        )));
+    }
+    if staging.join(FLAT_ROOTFS).exists() {
+        fs::rename(
+            staging.join(FLAT_ROOTFS),
+            staging.join(DiskFormat::Qcow2.rootfs_name()),
+        )?;
+    }
+    let disk = fs::read_to_string(staging.join("disk_size")).unwrap_or_default();
+    let quota = match crate::quota::before_create(config, name, disk.trim()) {
+        Ok(quota) => quota,
+        Err(e) => {
+            fs::remove_dir_all(&staging).ok();
+            return Err(e);
+        }
+    };
+    fs::rename(&staging, &vm_dir)?;
+    drop(quota);
+
+    let adopted = crate::state::transition(
+        &vm_dir,
+        crate::state::VmState::Creating,
+        crate::state::VmState::Stopped,
+        vm::adopt(config, name, false),
+    )
+    .await;
+    if let Err(e) = adopted {
+        drop(lock);
+        vm::delete(config, name, false).await.ok();
+        return Err(e);
+    }
+    crate::inventory::notify(config, name, crate::inventory::Event::Created).await;
+    drop(lock);
+    info!("Received VM {}", name);
+    if start {
+        vm::start(config, name, false).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_destination() {
+        assert_eq!(
+            Destination::parse("ssh://ops@host-2.lan:2222").unwrap(),
+            Destination {
+                host: "ops@host-2.lan".to_string(),
+                port: Some(2222),
+            }
+        );
+        assert_eq!(Destination::parse("ssh://host2/").unwrap().port, None);
+        for bad in [
+            "host2",
+            "http://host2",
+            "ssh://",
+            "ssh://-oProxyCommand=x",
+            "ssh://host2:ssh",
+            "ssh://a b",
+            "ssh://@host2",
+        ] {
+            assert!(Destination::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_entries_to_send() {
+        let dir = TempDir::new().unwrap();
+        for file in [
+            "rootfs.qcow2",
+            "subnet",
+            "mac",
+            "launch.json",
+            "tapdev",
+            "api.sock",
+            "dnsmasq.pid",
+            FLAT_ROOTFS,
+        ] {
+            fs::write(dir.path().join(file), "").unwrap();
+        }
+        fs::create_dir(dir.path().join("snapshot")).unwrap();
+        fs::create_dir(dir.path().join("disks")).unwrap();
+
+        assert_eq!(
+            entries_to_send(dir.path(), true).unwrap(),
+            vec![FLAT_ROOTFS, "disks", "mac", "subnet"]
+        );
+        assert_eq!(
+            entries_to_send(dir.path(), false).unwrap(),
+            vec!["disks", "mac", "rootfs.qcow2", "subnet"]
+        );
+    }
+}
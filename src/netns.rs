@@ -19,6 +19,7 @@
 //! other.
 
 use crate::error::Result;
+use crate::network::ForwardPolicy;
 use crate::util::run_command;
 use log::debug;
 use serde::{Deserialize, Serialize};
@@ -105,12 +106,21 @@ impl NetnsSpec {
 ///
 /// All sudo'd work is folded into a single `sudo bash -c` so per-VM
 /// fork cost is ~1 sudo round-trip, not ~15.
-pub fn create(spec: &NetnsSpec, guest_subnet: &str, tap_name: &str) -> Result<()> {
+///
+/// `policy` adds the VM's `--isolate`, `--egress` and
+/// `--egress-interface` rules on the host side of its veth (see
+/// `network::ForwardPolicy`).
+pub async fn create(
+    spec: &NetnsSpec,
+    guest_subnet: &str,
+    tap_name: &str,
+    policy: &ForwardPolicy,
+) -> Result<()> {
     // Make sure the shared host-wide rules (ip_forward, MASQUERADE
     // for 10.99.0.0/16) exist before we wire this VM. Idempotent +
     // flock-guarded, so concurrent `meda run`s from a clean host
     // converge on a single MASQUERADE entry instead of N duplicates.
-    bootstrap_host()?;
+    bootstrap_host().await?;
 
     debug!(
         "netns::create {} veth {}/{} tap {} guest {}.0/24",
@@ -189,7 +199,8 @@ ip netns exec "$NS" iptables -w -C FORWARD -o "$TAP" -m conntrack --ctstate RELA
 # unique `$VETH_H` so the -C / -A pair is race-free.
 iptables -w -C FORWARD -i "$VETH_H" -j ACCEPT 2>/dev/null || iptables -w -A FORWARD -i "$VETH_H" -j ACCEPT
 iptables -w -C FORWARD -o "$VETH_H" -j ACCEPT 2>/dev/null || iptables -w -A FORWARD -o "$VETH_H" -j ACCEPT
-"#,
+{policy}"#,
+        policy = policy.script(&spec.veth_host, &format!("{}/32", spec.netns_ip)),
         netns = spec.netns,
         veth_host = spec.veth_host,
         veth_netns = spec.veth_netns,
@@ -199,19 +210,21 @@ iptables -w -C FORWARD -o "$VETH_H" -j ACCEPT 2>/dev/null || iptables -w -A FORW
         subnet = guest_subnet,
     );
 
-    run_command("sudo", &["bash", "-c", &script])?;
+    run_command("sudo", &["bash", "-c", &script]).await?;
     Ok(())
 }
 
-/// Tear down the netns, veth pair, and per-VM FORWARD rules. Leaves
+/// Tear down the netns, veth pair, and per-VM FORWARD rules (including
+/// isolation drops, the egress chain and egress-interface routing,
+/// whether or not the VM had them). Leaves
 /// the shared `10.99.0.0/16` MASQUERADE in place — other VMs still
 /// need it. Idempotent: every step ignores "doesn't exist" errors.
-pub fn destroy(spec: &NetnsSpec) -> Result<()> {
+pub async fn destroy(spec: &NetnsSpec) -> Result<()> {
     let script = format!(
         r#"set +e
 iptables -w -D FORWARD -i {veth_host} -j ACCEPT 2>/dev/null
 iptables -w -D FORWARD -o {veth_host} -j ACCEPT 2>/dev/null
-# Deleting the netns destroys anything inside it (tap, iptables,
+{isolation}# Deleting the netns destroys anything inside it (tap, iptables,
 # veth-netns end, default route, …), so teardown is just these two
 # calls.
 ip link del {veth_host} 2>/dev/null
@@ -220,9 +233,11 @@ exit 0
 "#,
         veth_host = spec.veth_host,
         netns = spec.netns,
+        isolation =
+            ForwardPolicy::removal_script(&spec.veth_host, &format!("{}/32", spec.netns_ip)),
     );
 
-    run_command("sudo", &["bash", "-c", &script])?;
+    run_command("sudo", &["bash", "-c", &script]).await?;
     Ok(())
 }
 
@@ -235,7 +250,7 @@ exit 0
 /// in lock-step and end up with N duplicate rules. iptables's `-w`
 /// is a kernel xtables lock, not a check-then-add atomicity
 /// guarantee, so the userspace flock is the actual safety belt.
-pub fn bootstrap_host() -> Result<()> {
+pub async fn bootstrap_host() -> Result<()> {
     // Lock file lives in /var/run because anyone running meda
     // already has sudo (we use it for ip/iptables); /tmp is
     // world-writable which would let a hostile local user race us.
@@ -247,7 +262,7 @@ sysctl -qw net.ipv4.ip_forward=1
 iptables -w -t nat -C POSTROUTING -s 10.99.0.0/16 ! -d 10.99.0.0/16 -j MASQUERADE 2>/dev/null \
   || iptables -w -t nat -A POSTROUTING -s 10.99.0.0/16 ! -d 10.99.0.0/16 -j MASQUERADE
 "#;
-    run_command("sudo", &["bash", "-c", script])?;
+    run_command("sudo", &["bash", "-c", script]).await?;
     Ok(())
 }
 
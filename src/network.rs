@@ -1,10 +1,28 @@
 use crate::config::Config;
+use crate::egress::EgressPolicy;
 use crate::error::{Error, Result};
-use crate::util::{run_command, run_command_quietly, run_command_with_output};
+use crate::lock::FileLock;
+use crate::netns::NetnsSpec;
+use crate::subnets::{Lease, SubnetPool};
+use crate::util::{run_command, run_command_with_output};
 use log::{debug, info, warn};
 use rand::Rng;
+use serde::Serialize;
 use std::collections::HashSet;
 use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+/// Held while a VM's subnet and TAP name are picked and recorded.
+const ALLOCATION_LOCK: &str = ".network.lock";
+
+/// Serialize network identity allocation across meda processes, so two
+/// concurrent creates can't both pick the same free subnet or TAP name.
+/// Blocks: allocation takes milliseconds.
+pub fn lock_allocation(config: &Config) -> Result<FileLock> {
+    fs::create_dir_all(&config.vm_root)?;
+    Ok(FileLock::exclusive(&config.vm_root.join(ALLOCATION_LOCK))?)
+}
 
 pub fn generate_random_mac() -> String {
     let mut rng = rand::thread_rng();
@@ -17,96 +35,74 @@ pub fn generate_random_mac() -> String {
     )
 }
 
-pub fn generate_random_octet() -> u8 {
-    let mut rng = rand::thread_rng();
-    16 + rng.gen::<u8>() % 200
-}
-
-/// Parse the kernel routing table for `192.168.X.0/24` connected routes and
-/// return the set of third-octet values already claimed by the kernel.
+/// Third octets of the pool's subnets that overlap a network the host
+/// already routes (`ip route`) or has an address in (`ip addr`).
 ///
-/// Used as a second source of truth alongside on-disk VM dirs when choosing a
-/// subnet for a new VM. A previous `cleanup_networking` that failed to run
-/// `ip link del` leaves a stale tap device plus its connected route in the
-/// kernel even though the VM dir is gone; reusing that subnet would silently
-/// route new traffic via the stale (linkdown) tap.
+/// Used alongside the subnet ledger when choosing a subnet for a new VM.
+/// Another service on one of those ranges (docker, a VPN, the LAN) would
+/// silently break the VM's networking. This also catches a previous
+/// `cleanup_networking` that failed to run `ip link del`: the stale tap
+/// and its connected route outlive the VM dir, and reusing that subnet
+/// would route new traffic via the stale (linkdown) tap.
 ///
 /// Returns an empty set if `ip` is unavailable (macOS dev machines) — the
-/// on-disk scan is still consulted by `generate_unique_subnet`.
-fn kernel_subnet_octets_in_use() -> HashSet<u8> {
-    let mut used = HashSet::new();
-    let Ok(output) = run_command_with_output("ip", &["-o", "route", "show"]) else {
-        return used;
-    };
-    if !output.status.success() {
-        return used;
-    }
-    let out = String::from_utf8_lossy(&output.stdout);
-    for line in out.lines() {
-        // Destination is the first whitespace-separated field, e.g.
-        // "192.168.26.0/24 dev tap-66c39bfa proto kernel scope link ..."
-        let Some(dest) = line.split_whitespace().next() else {
+/// ledger is still consulted by `generate_unique_subnet`.
+fn host_subnet_conflicts(pool: &SubnetPool) -> HashSet<u8> {
+    let mut networks = Vec::new();
+    for args in [["-4", "-o", "route", "show"], ["-4", "-o", "addr", "show"]] {
+        let Ok(output) = run_command_with_output("ip", &args) else {
             continue;
         };
-        if let Some(octet) = parse_192_168_slash_24_octet(dest) {
-            used.insert(octet);
+        if output.status.success() {
+            let out = String::from_utf8_lossy(&output.stdout);
+            networks.extend(out.lines().filter_map(host_network));
         }
     }
-    used
+    pool.octets()
+        .filter(|octet| networks.iter().any(|n| overlaps(pool.network(*octet), n)))
+        .collect()
 }
 
-/// Parse a CIDR string of the form "192.168.X.0/24" and return X, or None.
-fn parse_192_168_slash_24_octet(dest: &str) -> Option<u8> {
-    let net = dest.strip_suffix("/24")?;
-    let rest = net.strip_prefix("192.168.")?;
-    let third = rest.strip_suffix(".0")?;
-    third.parse::<u8>().ok()
+/// The network a line of `ip -o route` or `ip -o addr` is about: a
+/// route's destination ("10.8.0.0/16 dev tun0 ...", "10.1.2.3 via ..."),
+/// or an address with its prefix ("2: eth0    inet 192.168.1.5/24 ...").
+fn host_network(line: &str) -> Option<(Ipv4Addr, u32)> {
+    let mut tokens = line.split_whitespace();
+    let net = if line.contains(" inet ") {
+        tokens.find(|t| *t == "inet");
+        tokens.next()?
+    } else {
+        tokens.next()?
+    };
+    parse_ipv4_net(net)
 }
 
-pub async fn generate_unique_subnet(config: &Config) -> Result<String> {
-    // Start with subnets the kernel still has a connected route for. This
-    // catches leaks from earlier delete attempts that failed to remove a tap
-    // device — the VM dir is gone but the route survives, and picking that
-    // subnet would break the new VM's networking.
-    let mut used_subnets: HashSet<u8> = kernel_subnet_octets_in_use();
-
-    // Union with subnets claimed by existing VM dirs on disk.
-    if let Ok(entries) = fs::read_dir(&config.vm_root) {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if path.is_dir() {
-                let subnet_file = path.join("subnet");
-                if subnet_file.exists() {
-                    if let Ok(subnet) = fs::read_to_string(subnet_file) {
-                        let subnet = subnet.trim();
-                        if let Some(octet_str) = subnet.strip_prefix("192.168.") {
-                            if let Ok(octet) = octet_str.parse::<u8>() {
-                                used_subnets.insert(octet);
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    let mut attempts = 0;
-    let max_attempts = 200;
+/// Parse `A.B.C.D/len`, or a bare address as a /32.
+fn parse_ipv4_net(s: &str) -> Option<(Ipv4Addr, u32)> {
+    let (addr, len) = s.split_once('/').unwrap_or((s, "32"));
+    let len = len.parse().ok().filter(|l| *l <= 32)?;
+    Some((addr.parse().ok()?, len))
+}
 
-    while attempts < max_attempts {
-        let octet = generate_random_octet();
-        if !used_subnets.contains(&octet) {
-            return Ok(format!("192.168.{}", octet));
-        }
-        attempts += 1;
+/// Whether the /24 at `subnet` overlaps `network`. Networks wider than
+/// /8 don't count: VPNs route `0.0.0.0/1` and `128.0.0.0/1` to override
+/// the default route, not because they use every address.
+fn overlaps(subnet: Ipv4Addr, &(addr, len): &(Ipv4Addr, u32)) -> bool {
+    if len < 8 {
+        return false;
     }
+    let mask = u32::MAX << (32 - len.min(24));
+    u32::from(subnet) & mask == u32::from(addr) & mask
+}
 
-    Err(Error::Other(
-        "Could not generate a unique subnet after multiple attempts".to_string(),
-    ))
+/// Lease a subnet for `vm_name` from the ledger (which also accounts
+/// for subnets of existing VM dirs), skipping ones the host uses.
+pub async fn generate_unique_subnet(config: &Config, vm_name: &str) -> Result<String> {
+    let conflicts = host_subnet_conflicts(&config.subnet_pool);
+    crate::subnets::allocate(config, vm_name, &conflicts)
 }
 
-pub async fn generate_unique_tap_name(_config: &Config, vm_name: &str) -> Result<String> {
+pub async fn generate_unique_tap_name(config: &Config, vm_name: &str) -> Result<String> {
     // Get all currently active TAP devices on the system (authoritative source)
     let mut used_tap_names = std::collections::HashSet::new();
 
@@ -127,6 +123,15 @@ pub async fn generate_unique_tap_name(_config: &Config, vm_name: &str) -> Result
         }
     }
 
+    // Stopped VMs have no device up but still own their name.
+    if let Ok(entries) = fs::read_dir(&config.vm_root) {
+        for entry in entries.flatten() {
+            if let Ok(tap) = fs::read_to_string(entry.path().join("tapdev")) {
+                used_tap_names.insert(tap.trim().to_string());
+            }
+        }
+    }
+
     // Use a deterministic approach: hash of VM name + timestamp for uniqueness
     use std::collections::hash_map::DefaultHasher;
     use std::hash::{Hash, Hasher};
@@ -207,8 +212,8 @@ pub async fn cleanup_orphaned_tap_devices(config: &Config) -> Result<Vec<String>
         if vm_taps.contains(&tap_name) {
             continue;
         }
-        let _ = run_command_quietly("sudo", &["ip", "route", "flush", "dev", &tap_name]);
-        if delete_tap_device_verified(&tap_name).is_ok() {
+        let _ = run_command("sudo", &["ip", "route", "flush", "dev", &tap_name]).await;
+        if delete_tap_device_verified(&tap_name).await.is_ok() {
             cleaned_up.push(tap_name);
         }
     }
@@ -217,7 +222,7 @@ pub async fn cleanup_orphaned_tap_devices(config: &Config) -> Result<Vec<String>
 }
 
 pub async fn setup_networking(
-    _config: &Config,
+    config: &Config,
     name: &str,
     tap_name: &str,
     subnet: &str,
@@ -258,15 +263,128 @@ iptables -w -C FORWARD -i {tap_name} -j ACCEPT 2>/dev/null \
 
 iptables -w -C FORWARD -o {tap_name} -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT 2>/dev/null \
   || iptables -w -A FORWARD -o {tap_name} -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT
-"#,
+
+# 5) --isolate drops and --egress chain, if any.
+{policy}"#,
         tap_name = tap_name,
         subnet = subnet,
+        policy =
+            ForwardPolicy::load(&config.vm_dir(name)).script(tap_name, &format!("{subnet}.0/24")),
     );
 
-    run_command("sudo", &["bash", "-c", &script])?;
+    run_command("sudo", &["bash", "-c", &script]).await?;
     Ok(())
 }
 
+/// Marker file in a VM dir: the VM was created with `--isolate`.
+pub const ISOLATE_FILE: &str = "isolate";
+
+pub fn is_isolated(vm_dir: &Path) -> bool {
+    vm_dir.join(ISOLATE_FILE).exists()
+}
+
+/// Record whether a VM gets isolation rules when its network comes up.
+pub fn set_isolated(vm_dir: &Path, isolated: bool) -> Result<()> {
+    let marker = vm_dir.join(ISOLATE_FILE);
+    if isolated {
+        fs::write(marker, "")?;
+    } else if marker.exists() {
+        fs::remove_file(marker)?;
+    }
+    Ok(())
+}
+
+/// Host FORWARD rules (minus the chain) that cut `link` — a VM's host
+/// tap or netns veth — off from every other meda tap and veth, in both
+/// directions. Traffic to anything else, including the internet and
+/// port forwards from outside, still reaches the ACCEPT rules below.
+pub fn isolation_rules(link: &str) -> Vec<String> {
+    ["vmh+", "tap+"]
+        .iter()
+        .flat_map(|others| {
+            [
+                format!("-i {link} -o {others} -j DROP"),
+                format!("-i {others} -o {link} -j DROP"),
+            ]
+        })
+        .collect()
+}
+
+/// Shell lines adding (inserted first in FORWARD, so they win over the
+/// per-VM ACCEPTs) or removing the isolation rules for `link`.
+pub fn isolation_script(link: &str, add: bool) -> String {
+    isolation_rules(link)
+        .iter()
+        .map(|rule| {
+            if add {
+                format!(
+                    "iptables -w -C FORWARD {rule} 2>/dev/null || iptables -w -I FORWARD {rule}\n"
+                )
+            } else {
+                format!("iptables -w -D FORWARD {rule} 2>/dev/null || true\n")
+            }
+        })
+        .collect()
+}
+
+/// Host rules a VM gets on top of the default ACCEPTs and NAT: the
+/// `--isolate` drops, its `--egress` chain and its
+/// `--egress-interface` routing.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardPolicy {
+    pub isolated: bool,
+    pub egress: Option<EgressPolicy>,
+    pub egress_interface: Option<String>,
+}
+
+impl ForwardPolicy {
+    pub fn load(vm_dir: &Path) -> Self {
+        Self {
+            isolated: is_isolated(vm_dir),
+            egress: EgressPolicy::load(vm_dir),
+            egress_interface: crate::uplink::load(vm_dir),
+        }
+    }
+
+    pub fn save(&self, vm_dir: &Path) -> Result<()> {
+        set_isolated(vm_dir, self.isolated)?;
+        match &self.egress {
+            Some(egress) => egress.save(vm_dir)?,
+            None => {
+                let _ = fs::remove_file(vm_dir.join(crate::egress::EGRESS_FILE));
+            }
+        }
+        crate::uplink::save(vm_dir, self.egress_interface.as_deref())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.isolated && self.egress.is_none() && self.egress_interface.is_none()
+    }
+
+    /// Shell lines installing the rules for the VM behind `link`, whose
+    /// traffic reaches the host from `source`.
+    pub fn script(&self, link: &str, source: &str) -> String {
+        let mut script = String::new();
+        if self.isolated {
+            script.push_str(&isolation_script(link, true));
+        }
+        if let Some(egress) = &self.egress {
+            script.push_str(&egress.script(link));
+        }
+        if let Some(iface) = &self.egress_interface {
+            script.push_str(&crate::uplink::script(source, iface));
+        }
+        script
+    }
+
+    /// Shell lines removing whatever rules `script` may have installed.
+    pub fn removal_script(link: &str, source: &str) -> String {
+        isolation_script(link, false)
+            + &crate::egress::removal_script(link)
+            + &crate::uplink::removal_script(source)
+    }
+}
+
 pub async fn port_forward(
     config: &Config,
     name: &str,
@@ -278,6 +396,29 @@ pub async fn port_forward(
     if !vm_dir.exists() {
         return Err(Error::VmNotFound(name.to_string()));
     }
+    if let Some(bridge) = crate::bridge::bridge_of(&vm_dir) {
+        return Err(Error::Other(format!(
+            "VM {} is bridged onto {}; reach it at its LAN address instead of forwarding a port",
+            name, bridge
+        )));
+    }
+    if let Some(mut net) = crate::usernet::UserNet::load(&vm_dir) {
+        // passt forwards the port; it can't take new ones while running.
+        net.add_forward(host_port, guest_port)?;
+        net.save(&vm_dir)?;
+        if crate::vm::check_vm_running(config, name)? {
+            info!(
+                "Port forward saved: *:{} -> guest:{}; it applies from {}'s next start",
+                host_port, guest_port, name
+            );
+        } else {
+            info!(
+                "Port forwarding set up: *:{} -> guest:{}",
+                host_port, guest_port
+            );
+        }
+        return Ok(());
+    }
 
     let subnet_file = vm_dir.join("subnet");
     if !subnet_file.exists() {
@@ -306,7 +447,8 @@ pub async fn port_forward(
             "--to",
             &format!("{}.2:{}", subnet, guest_port),
         ],
-    );
+    )
+    .await;
 
     // Add new port forward
     run_command(
@@ -327,7 +469,8 @@ pub async fn port_forward(
             "--to",
             &format!("{}.2:{}", subnet, guest_port),
         ],
-    )?;
+    )
+    .await?;
 
     // Save port forwarding info
     fs::write(
@@ -348,17 +491,23 @@ pub async fn port_forward(
 /// Treats "already absent" as success regardless of how `ip link del` exited,
 /// and retries once after a brief pause to tolerate a race where qemu has not
 /// yet released its tun fd.
-fn delete_tap_device_verified(tap_name: &str) -> Result<()> {
-    if run_command_quietly("sudo", &["ip", "link", "del", tap_name]).is_ok() {
+async fn delete_tap_device_verified(tap_name: &str) -> Result<()> {
+    if run_command("sudo", &["ip", "link", "del", tap_name])
+        .await
+        .is_ok()
+    {
         return Ok(());
     }
     if !tap_exists(tap_name) {
         return Ok(());
     }
 
-    std::thread::sleep(std::time::Duration::from_millis(500));
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
 
-    if run_command_quietly("sudo", &["ip", "link", "del", tap_name]).is_ok() {
+    if run_command("sudo", &["ip", "link", "del", tap_name])
+        .await
+        .is_ok()
+    {
         return Ok(());
     }
     if !tap_exists(tap_name) {
@@ -385,23 +534,29 @@ fn tap_exists(tap_name: &str) -> bool {
 pub async fn cleanup_networking(config: &Config, name: &str) -> Result<()> {
     let vm_dir = config.vm_dir(name);
 
-    // Clean up iptables FORWARD rules for this VM's TAP device
+    // Clean up iptables FORWARD rules for this VM's TAP device. Bridged
+    // VMs never had any; their tap only has to go.
+    let bridged = crate::bridge::bridge_of(&vm_dir).is_some();
     if let Ok(tap_name) = fs::read_to_string(vm_dir.join("tapdev")) {
         let tap_name = tap_name.trim();
+        if bridged {
+            return delete_tap_device_verified(tap_name).await;
+        }
 
         // Remove FORWARD rules referencing this TAP device (inbound and outbound).
         // Best-effort: the rule may have already been reaped by an earlier pass
         // (e.g. the per-VM netns went down and took its iptables chains with
-        // it). Use the _quietly variant so the harmless "Bad rule" stderr
-        // doesn't spam meda-stderr.log on every delete — when 50 VMs tear
+        // it). run_command only logs the child's output at debug level, so
+        // the harmless "Bad rule" stderr doesn't spam meda-stderr.log on every delete — when 50 VMs tear
         // down at once the noise drowns out real errors.
-        let _ = run_command_quietly(
+        let _ = run_command(
             "sudo",
             &[
                 "iptables", "-w", "-D", "FORWARD", "-i", tap_name, "-j", "ACCEPT",
             ],
-        );
-        let _ = run_command_quietly(
+        )
+        .await;
+        let _ = run_command(
             "sudo",
             &[
                 "iptables",
@@ -417,12 +572,27 @@ pub async fn cleanup_networking(config: &Config, name: &str) -> Result<()> {
                 "-j",
                 "ACCEPT",
             ],
-        );
+        )
+        .await;
+
+        if !ForwardPolicy::load(&vm_dir).is_empty() {
+            let subnet = fs::read_to_string(vm_dir.join("subnet")).unwrap_or_default();
+            let source = format!("{}.0/24", subnet.trim());
+            let _ = run_command(
+                "sudo",
+                &[
+                    "bash",
+                    "-c",
+                    &ForwardPolicy::removal_script(tap_name, &source),
+                ],
+            )
+            .await;
+        }
 
         // Flush connected routes pointing at this tap before deleting the
         // device. `ip link del` normally auto-removes them, but being explicit
         // means a half-successful delete cannot leave a stale route behind.
-        let _ = run_command_quietly("sudo", &["ip", "route", "flush", "dev", tap_name]);
+        let _ = run_command("sudo", &["ip", "route", "flush", "dev", tap_name]).await;
 
         // Delete the tap device and verify it is actually gone. Previously
         // this call was `let _ = run_command(...)`, which silently swallowed
@@ -430,37 +600,24 @@ pub async fn cleanup_networking(config: &Config, name: &str) -> Result<()> {
         // — orphaning the tap + its connected route in the kernel. The next
         // VM that generated the same subnet (disk-only check) would then
         // route via the stale linkdown tap and fail with "No route to host".
-        delete_tap_device_verified(tap_name)?;
+        delete_tap_device_verified(tap_name).await?;
     }
 
     // Clean up iptables MASQUERADE rule if this is the last VM using this subnet
     if let Ok(subnet) = fs::read_to_string(vm_dir.join("subnet")) {
         let subnet = subnet.trim();
 
-        // Check if any other VM is using this subnet
-        let mut found = false;
-        for entry in fs::read_dir(&config.vm_root)? {
-            let entry = entry?;
-            let path = entry.path();
+        // Keep the rule if the ledger can't say no one else uses it.
+        let shared = crate::subnets::shared(config, name, subnet).unwrap_or_else(|e| {
+            warn!("Failed to read subnet leases: {}", e);
+            true
+        });
 
-            if path != vm_dir && path.is_dir() {
-                let subnet_file = path.join("subnet");
-                if subnet_file.exists() {
-                    if let Ok(other_subnet) = fs::read_to_string(subnet_file) {
-                        if other_subnet.trim() == subnet {
-                            found = true;
-                            break;
-                        }
-                    }
-                }
-            }
-        }
-
-        if !found {
-            // Remove MASQUERADE rule. _quietly because the netns destroy may
+        if !shared {
+            // Remove MASQUERADE rule. Best-effort because the netns destroy may
             // have already torn down the per-netns nat table (see comment
             // above on the FORWARD pair).
-            let _ = run_command_quietly(
+            let _ = run_command(
                 "sudo",
                 &[
                     "iptables",
@@ -474,10 +631,698 @@ pub async fn cleanup_networking(config: &Config, name: &str) -> Result<()> {
                     "-j",
                     "MASQUERADE",
                 ],
-            );
+            )
+            .await;
+        }
+    }
+
+    // A lease left behind is reclaimed by the next allocation or prune.
+    if let Err(e) = crate::subnets::release(config, name) {
+        warn!("Failed to release subnet lease of {}: {}", name, e);
+    }
+
+    Ok(())
+}
+
+/// One rule meda expects to own for a VM. A live rule satisfies it when
+/// every fragment appears as whole tokens in the `iptables -S` line — iptables rewrites
+/// rules on the way in (adds `-m tcp`, `/32`, `--to-destination`), so
+/// exact string equality would report drift on healthy VMs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedRule {
+    /// `host` or the netns name the rule lives in.
+    pub namespace: String,
+    pub table: String,
+    pub fragments: Vec<String>,
+}
+
+impl ExpectedRule {
+    fn new(namespace: &str, table: &str, fragments: &[&str]) -> Self {
+        Self {
+            namespace: namespace.to_string(),
+            table: table.to_string(),
+            fragments: fragments.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    fn matches(&self, rule: &ObservedRule) -> bool {
+        rule.namespace == self.namespace
+            && rule.table == self.table
+            && self.fragments.iter().all(|f| {
+                // Pad both sides so `--dport 22` doesn't match `--dport 2222`.
+                format!(" {} ", rule.rule).contains(&format!(" {f} "))
+            })
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "[{}/{}] {}",
+            self.namespace,
+            self.table,
+            self.fragments.join(" ")
+        )
+    }
+}
+
+/// A live iptables rule attributed to a VM.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ObservedRule {
+    pub namespace: String,
+    pub table: String,
+    pub rule: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TapState {
+    pub name: String,
+    pub present: bool,
+    pub up: bool,
+    pub addresses: Vec<String>,
+}
+
+/// Output of `meda network inspect`.
+#[derive(Debug, Serialize)]
+pub struct NetworkInspection {
+    pub vm: String,
+    pub subnet: String,
+    /// Created with `--isolate`: no traffic to or from other VMs.
+    pub isolated: bool,
+    /// `--egress` policy, if any.
+    pub egress: Option<String>,
+    /// `--egress-interface`: host interface the VM's NAT is pinned to.
+    pub egress_interface: Option<String>,
+    pub netns: Option<NetnsSpec>,
+    pub tap: TapState,
+    pub routes: Vec<String>,
+    pub rules: Vec<ObservedRule>,
+    pub drift: Vec<String>,
+}
+
+/// Rules meda installs for a VM, derived from what's on disk in the VM
+/// dir. Mirrors `netns::create` for netns-backed VMs and
+/// `setup_networking` for the legacy host-tap layout, plus one DNAT per
+/// recorded `port-forward` and whatever its [`ForwardPolicy`] adds.
+pub fn expected_rules(
+    spec: Option<&NetnsSpec>,
+    subnet: &str,
+    tap_name: &str,
+    ports: &[(u16, u16)],
+    policy: &ForwardPolicy,
+) -> Vec<ExpectedRule> {
+    let mut rules = Vec::new();
+    let guest_net = format!("{subnet}.0/24");
+    match spec {
+        Some(spec) => {
+            let ns = spec.netns.as_str();
+            rules.push(ExpectedRule::new(
+                ns,
+                "nat",
+                &[
+                    "-A POSTROUTING",
+                    &format!("-s {guest_net}"),
+                    "-j MASQUERADE",
+                ],
+            ));
+            rules.push(ExpectedRule::new(
+                ns,
+                "nat",
+                &[
+                    "-A PREROUTING",
+                    // iptables -S always prints the host mask.
+                    &format!("-d {}/32", spec.netns_ip),
+                    "-j DNAT",
+                    &format!("{subnet}.2"),
+                ],
+            ));
+            rules.push(ExpectedRule::new(
+                ns,
+                "filter",
+                &["-A FORWARD", &format!("-i {tap_name}"), "-j ACCEPT"],
+            ));
+            rules.push(ExpectedRule::new(
+                ns,
+                "filter",
+                &[
+                    "-A FORWARD",
+                    &format!("-o {tap_name}"),
+                    "RELATED,ESTABLISHED",
+                ],
+            ));
+            rules.push(ExpectedRule::new(
+                "host",
+                "filter",
+                &["-A FORWARD", &format!("-i {}", spec.veth_host), "-j ACCEPT"],
+            ));
+            rules.push(ExpectedRule::new(
+                "host",
+                "filter",
+                &["-A FORWARD", &format!("-o {}", spec.veth_host), "-j ACCEPT"],
+            ));
+            rules.push(ExpectedRule::new(
+                "host",
+                "nat",
+                &["-A POSTROUTING", "-s 10.99.0.0/16", "-j MASQUERADE"],
+            ));
+        }
+        None => {
+            rules.push(ExpectedRule::new(
+                "host",
+                "nat",
+                &[
+                    "-A POSTROUTING",
+                    &format!("-s {guest_net}"),
+                    "-j MASQUERADE",
+                ],
+            ));
+            rules.push(ExpectedRule::new(
+                "host",
+                "filter",
+                &["-A FORWARD", &format!("-i {tap_name}"), "-j ACCEPT"],
+            ));
+            rules.push(ExpectedRule::new(
+                "host",
+                "filter",
+                &[
+                    "-A FORWARD",
+                    &format!("-o {tap_name}"),
+                    "RELATED,ESTABLISHED",
+                ],
+            ));
+        }
+    }
+    let link = spec.map_or(tap_name, |s| s.veth_host.as_str());
+    if policy.isolated {
+        for rule in isolation_rules(link) {
+            let rule = format!("-A FORWARD {rule}");
+            rules.push(ExpectedRule::new("host", "filter", &[&rule]));
+        }
+    }
+    if let Some(egress) = &policy.egress {
+        for jump in crate::egress::jump_rules(link) {
+            let jump = format!("-A {jump}");
+            rules.push(ExpectedRule::new("host", "filter", &[&jump]));
+        }
+        for rule in egress.chain_rules(link) {
+            rules.push(ExpectedRule::new("host", "filter", &[&rule]));
+        }
+    }
+    if let Some(iface) = &policy.egress_interface {
+        let source = spec.map_or(guest_net.clone(), |s| format!("{}/32", s.netns_ip));
+        let rule = format!("-A {}", crate::uplink::masquerade_rule(&source, iface));
+        rules.push(ExpectedRule::new("host", "nat", &[&rule]));
+    }
+    for (host_port, guest_port) in ports {
+        rules.push(ExpectedRule::new(
+            "host",
+            "nat",
+            &[
+                "-A PREROUTING",
+                &format!("--dport {host_port}"),
+                "-j DNAT",
+                &format!("{subnet}.2:{guest_port}"),
+            ],
+        ));
+    }
+    rules
+}
+
+/// Compare expected vs. observed rules. Reports rules that are missing
+/// and rules that appear more than once (the concurrent `-C || -A`
+/// race documented in `netns::bootstrap_host`).
+pub fn rule_drift(expected: &[ExpectedRule], observed: &[ObservedRule]) -> Vec<String> {
+    let mut drift = Vec::new();
+    for exp in expected {
+        let hits = observed.iter().filter(|r| exp.matches(r)).count();
+        if hits == 0 {
+            drift.push(format!("missing rule {}", exp.describe()));
+        } else if hits > 1 {
+            drift.push(format!("duplicate rule ({hits}x) {}", exp.describe()));
+        }
+    }
+    drift
+}
+
+/// Parse the `ports` file written by `port_forward` (`host->guest`).
+fn parse_port_forwards(body: &str) -> Vec<(u16, u16)> {
+    body.lines()
+        .filter_map(|line| {
+            let (h, g) = line.trim().split_once("->")?;
+            Some((h.trim().parse().ok()?, g.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Run `iptables -S` for one table, inside a netns when given, and keep
+/// only lines that reference one of `needles`.
+fn collect_rules(namespace: Option<&str>, table: &str, needles: &[String]) -> Vec<ObservedRule> {
+    let mut args: Vec<&str> = Vec::new();
+    if let Some(ns) = namespace {
+        args.extend(["ip", "netns", "exec", ns]);
+    }
+    args.extend(["iptables", "-w", "-t", table, "-S"]);
+    let Ok(output) = run_command_with_output("sudo", &args) else {
+        return Vec::new();
+    };
+    if !output.status.success() {
+        return Vec::new();
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|l| l.starts_with("-A "))
+        .filter(|l| needles.iter().any(|n| l.contains(n.as_str())))
+        .map(|l| ObservedRule {
+            namespace: namespace.unwrap_or("host").to_string(),
+            table: table.to_string(),
+            rule: l.to_string(),
+        })
+        .collect()
+}
+
+/// Run an `ip` subcommand, optionally inside a netns, returning stdout
+/// lines or `None` if the command failed.
+fn ip_lines(namespace: Option<&str>, args: &[&str]) -> Option<Vec<String>> {
+    let mut full: Vec<&str> = vec!["ip"];
+    if let Some(ns) = namespace {
+        full.extend(["-n", ns]);
+    }
+    full.extend(args);
+    let output = run_command_with_output("sudo", &full).ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect(),
+    )
+}
+
+/// Collect the live tap/address/route/iptables state for a VM and diff
+/// it against what the VM dir says meda set up.
+pub async fn inspect(config: &Config, name: &str, json: bool) -> Result<()> {
+    let vm_dir = config.vm_dir(name);
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+    if crate::usernet::is_enabled(&vm_dir) {
+        return Err(Error::Other(format!(
+            "VM {} uses user-mode networking: it has no tap, netns or firewall rules on the host to inspect; see its passt.log",
+            name
+        )));
+    }
+    let subnet = fs::read_to_string(vm_dir.join("subnet"))
+        .map_err(|_| Error::NetworkConfigMissing(name.to_string()))?
+        .trim()
+        .to_string();
+    let tap_name = fs::read_to_string(vm_dir.join("tapdev"))
+        .map_err(|_| Error::NetworkConfigMissing(name.to_string()))?
+        .trim()
+        .to_string();
+    let ports = fs::read_to_string(vm_dir.join("ports"))
+        .map(|b| parse_port_forwards(&b))
+        .unwrap_or_default();
+    // Only VMs that actually went through `netns::create` have the file;
+    // cold-path `meda run` VMs use host-level tap networking.
+    let spec = vm_dir
+        .join("netns.json")
+        .exists()
+        .then(|| NetnsSpec::load_or_compute(&vm_dir, name));
+    let ns = spec.as_ref().map(|s| s.netns.as_str());
+
+    let mut drift = Vec::new();
+    let running = crate::vm::check_vm_running(config, name)?;
+
+    let link = ip_lines(ns, &["-o", "link", "show", "dev", &tap_name]);
+    let addresses: Vec<String> = ip_lines(ns, &["-o", "-4", "addr", "show", "dev", &tap_name])
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|l| {
+            let mut it = l.split_whitespace();
+            it.position(|t| t == "inet")?;
+            it.next().map(String::from)
+        })
+        .collect();
+    let tap = TapState {
+        name: tap_name.clone(),
+        present: link.is_some(),
+        up: link.as_ref().is_some_and(|l| {
+            l.iter()
+                .any(|s| s.contains("state UP") || s.contains(",UP"))
+        }),
+        addresses,
+    };
+    if !tap.present {
+        drift.push(format!("tap device {} not found", tap_name));
+    } else {
+        if running && !tap.up {
+            drift.push(format!("tap device {} is down", tap_name));
+        }
+        let gateway = format!("{subnet}.1/24");
+        if !tap.addresses.contains(&gateway) {
+            drift.push(format!("tap device {} lacks gateway {}", tap_name, gateway));
+        }
+    }
+    if let Some(spec) = &spec {
+        if ip_lines(None, &["-o", "link", "show", "dev", &spec.veth_host]).is_none() {
+            drift.push(format!("host veth {} not found", spec.veth_host));
+        }
+    }
+
+    let routes = ip_lines(ns, &["route", "show"]).unwrap_or_default();
+    if !routes
+        .iter()
+        .any(|r| r.starts_with(&format!("{subnet}.0/24")))
+    {
+        drift.push(format!("no route for {subnet}.0/24"));
+    }
+
+    let mut needles = vec![format!("{subnet}."), tap_name.clone()];
+    if let Some(spec) = &spec {
+        needles.push(spec.veth_host.clone());
+        needles.push(spec.netns_ip.clone());
+        needles.push("10.99.0.0/16".to_string());
+    }
+    let mut rules = Vec::new();
+    for table in ["filter", "nat"] {
+        rules.extend(collect_rules(None, table, &needles));
+        if let Some(ns) = ns {
+            rules.extend(collect_rules(Some(ns), table, &needles));
+        }
+    }
+    let policy = ForwardPolicy::load(&vm_dir);
+    let expected = expected_rules(spec.as_ref(), &subnet, &tap_name, &ports, &policy);
+    drift.extend(rule_drift(&expected, &rules));
+
+    let report = NetworkInspection {
+        vm: name.to_string(),
+        subnet,
+        isolated: policy.isolated,
+        egress: policy.egress.as_ref().map(EgressPolicy::to_spec),
+        egress_interface: policy.egress_interface.clone(),
+        netns: spec,
+        tap,
+        routes,
+        rules,
+        drift,
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!("VM: {}", report.vm);
+    println!("Subnet: {}.0/24", report.subnet);
+    println!("Isolated: {}", if report.isolated { "yes" } else { "no" });
+    println!(
+        "Egress: {}",
+        report.egress.as_deref().unwrap_or("allow all")
+    );
+    println!(
+        "Egress interface: {}",
+        report
+            .egress_interface
+            .as_deref()
+            .unwrap_or("- (host default route)")
+    );
+    match &report.netns {
+        Some(spec) => println!(
+            "Netns: {} (veth {} {} <-> {})",
+            spec.netns, spec.veth_host, spec.host_ip, spec.netns_ip
+        ),
+        None => println!("Netns: - (host tap networking)"),
+    }
+    println!(
+        "Tap: {} present={} up={} addresses={}",
+        report.tap.name,
+        report.tap.present,
+        report.tap.up,
+        if report.tap.addresses.is_empty() {
+            "-".to_string()
+        } else {
+            report.tap.addresses.join(",")
+        }
+    );
+    println!("Routes:");
+    for route in &report.routes {
+        println!("  {}", route);
+    }
+    println!("Rules:");
+    for rule in &report.rules {
+        println!("  [{}/{}] {}", rule.namespace, rule.table, rule.rule);
+    }
+    if report.drift.is_empty() {
+        println!("Drift: none");
+    } else {
+        println!("Drift:");
+        for d in &report.drift {
+            println!("  ⚠️  {}", d);
+        }
+    }
+
+    Ok(())
+}
+
+/// Network state still owned by a VM dir (subnets: by a lease in the
+/// subnet ledger); anything meda-shaped that isn't in here is left over
+/// from a VM that's gone.
+#[derive(Debug, Default)]
+struct LiveNetwork {
+    taps: HashSet<String>,
+    subnets: HashSet<String>,
+    /// Rules for subnets outside it aren't meda's.
+    pool: SubnetPool,
+    veths: HashSet<String>,
+    namespaces: HashSet<String>,
+}
+
+impl LiveNetwork {
+    fn collect(config: &Config) -> Result<Self> {
+        let mut live = Self {
+            pool: config.subnet_pool.clone(),
+            ..Self::default()
+        };
+        if !config.vm_root.exists() {
+            return Ok(live);
+        }
+        live.subnets = crate::subnets::in_use(config)?;
+        for entry in fs::read_dir(&config.vm_root)? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            if let Ok(tap) = fs::read_to_string(path.join("tapdev")) {
+                live.taps.insert(tap.trim().to_string());
+            }
+            if path.join("netns.json").exists() {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                let spec = NetnsSpec::load_or_compute(&path, &name);
+                live.veths.insert(spec.veth_host);
+                live.namespaces.insert(spec.netns);
+            }
+        }
+        Ok(live)
+    }
+
+    fn owns_link(&self, name: &str) -> bool {
+        self.taps.contains(name) || self.veths.contains(name)
+    }
+}
+
+/// Interface names from `ip -o link show` (`5: tap-ab12: <...>`, with
+/// veths printed as `vmh-ab12@if4:`).
+fn link_names(lines: &[String]) -> Vec<String> {
+    lines
+        .iter()
+        .filter_map(|l| l.split_whitespace().nth(1))
+        .map(|n| n.trim_end_matches(':'))
+        .map(|n| n.split('@').next().unwrap_or(n).to_string())
+        .collect()
+}
+
+/// Whether a host `iptables -S` line is one meda installs for a VM whose
+/// tap, veth or subnet no longer belongs to any VM dir. Only the exact
+/// shapes meda writes are matched, so unrelated user rules survive.
+fn is_stale_rule(table: &str, rule: &str, live: &LiveNetwork) -> bool {
+    let tokens: Vec<&str> = rule.split_whitespace().collect();
+    let meda_link = |name: &str| name.starts_with("tap-") || name.starts_with("vmh-");
+    let guest_subnet = |addr: &str, host: &str| {
+        addr.rsplit_once('.')
+            .filter(|(subnet, tail)| *tail == host && live.pool.octet(subnet).is_some())
+            .map(|(subnet, _)| subnet.to_string())
+    };
+    match (table, tokens.as_slice()) {
+        // Isolation drops name a wildcard (`vmh+`) next to the VM's own
+        // link, so look at every interface match, not just the first.
+        ("filter", ["-A", "FORWARD", rest @ ..]) => rest
+            .windows(2)
+            .filter(|w| w[0] == "-i" || w[0] == "-o")
+            .any(|w| meda_link(w[1]) && !live.owns_link(w[1])),
+        // Egress chains are named after the link (`meda-eg-<link>`).
+        ("filter", ["-A" | "-N", chain, ..]) => chain
+            .strip_prefix("meda-eg-")
+            .is_some_and(|link| meda_link(link) && !live.owns_link(link)),
+        ("nat", ["-A", "POSTROUTING", "-s", src, "-j", "MASQUERADE"]) => src
+            .strip_suffix("/24")
+            .and_then(|net| guest_subnet(net, "0"))
+            .is_some_and(|subnet| !live.subnets.contains(&subnet)),
+        ("nat", ["-A", "PREROUTING", .., "-j", "DNAT", "--to-destination", to]) => {
+            let (addr, _port) = to.split_once(':').unwrap_or((to, ""));
+            guest_subnet(addr, "2").is_some_and(|subnet| !live.subnets.contains(&subnet))
+        }
+        _ => false,
+    }
+}
+
+/// Output of `meda network prune`.
+#[derive(Debug, Default, Serialize)]
+pub struct NetworkPruneReport {
+    pub dry_run: bool,
+    pub tap_devices: Vec<String>,
+    pub veth_devices: Vec<String>,
+    pub namespaces: Vec<String>,
+    pub rules: Vec<ObservedRule>,
+    /// Subnet leases of VMs that no longer use them.
+    pub subnet_leases: Vec<Lease>,
+    /// Things that should have been removed but couldn't be.
+    pub errors: Vec<String>,
+}
+
+impl NetworkPruneReport {
+    fn is_empty(&self) -> bool {
+        self.tap_devices.is_empty()
+            && self.veth_devices.is_empty()
+            && self.namespaces.is_empty()
+            && self.rules.is_empty()
+            && self.subnet_leases.is_empty()
+    }
+}
+
+/// Find (and unless `dry_run`, remove) tap devices, host veths, netns,
+/// host iptables rules and subnet leases left behind by VMs that no
+/// longer exist.
+pub async fn prune(config: &Config, dry_run: bool) -> Result<NetworkPruneReport> {
+    let live = LiveNetwork::collect(config)?;
+    let mut report = NetworkPruneReport {
+        dry_run,
+        ..Default::default()
+    };
+
+    // Rules first: they reference the devices removed below.
+    for table in ["filter", "nat"] {
+        let Ok(output) = run_command_with_output("sudo", &["iptables", "-w", "-t", table, "-S"])
+        else {
+            continue;
+        };
+        let output = String::from_utf8_lossy(&output.stdout);
+        let mut lines: Vec<&str> = output.lines().collect();
+        // Chains go once their rules (listed after them) are gone.
+        lines.sort_by_key(|l| l.starts_with("-N"));
+        for rule in lines {
+            if !is_stale_rule(table, rule, &live) {
+                continue;
+            }
+            if !dry_run {
+                let delete = if rule.starts_with("-N") { "-X" } else { "-D" };
+                let mut args = vec!["iptables", "-w", "-t", table, delete];
+                args.extend(rule.split_whitespace().skip(1));
+                if let Err(e) = run_command("sudo", &args).await {
+                    report.errors.push(format!("{table}: {rule}: {e}"));
+                    continue;
+                }
+            }
+            report.rules.push(ObservedRule {
+                namespace: "host".to_string(),
+                table: table.to_string(),
+                rule: rule.to_string(),
+            });
         }
     }
 
+    let links = ip_lines(None, &["-o", "link", "show"]).unwrap_or_default();
+    for link in link_names(&links) {
+        let is_tap = link.starts_with("tap-");
+        if !(is_tap || link.starts_with("vmh-")) || live.owns_link(&link) {
+            continue;
+        }
+        if !dry_run {
+            let removed = if is_tap {
+                let _ = run_command("sudo", &["ip", "route", "flush", "dev", &link]).await;
+                delete_tap_device_verified(&link).await
+            } else {
+                run_command("sudo", &["ip", "link", "del", &link]).await
+            };
+            if let Err(e) = removed {
+                report.errors.push(format!("{link}: {e}"));
+                continue;
+            }
+        }
+        if is_tap {
+            report.tap_devices.push(link);
+        } else {
+            report.veth_devices.push(link);
+        }
+    }
+
+    let namespaces = ip_lines(None, &["netns", "list"]).unwrap_or_default();
+    for ns in namespaces
+        .iter()
+        .filter_map(|l| l.split_whitespace().next())
+    {
+        if !ns.starts_with("meda-") || live.namespaces.contains(ns) {
+            continue;
+        }
+        if !dry_run {
+            if let Err(e) = run_command("sudo", &["ip", "netns", "del", ns]).await {
+                report.errors.push(format!("{ns}: {e}"));
+                continue;
+            }
+        }
+        report.namespaces.push(ns.to_string());
+    }
+
+    match crate::subnets::reclaim(config, dry_run) {
+        Ok(leases) => report.subnet_leases = leases,
+        Err(e) => report.errors.push(format!("subnet leases: {e}")),
+    }
+
+    Ok(report)
+}
+
+/// `meda network prune`: prune and print what was (or would be) removed.
+pub async fn prune_command(config: &Config, dry_run: bool, json: bool) -> Result<()> {
+    let report = prune(config, dry_run).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    if report.is_empty() {
+        println!("No orphaned network state found");
+    } else {
+        let verb = if dry_run { "Would remove" } else { "Removed" };
+        for tap in &report.tap_devices {
+            println!("{verb} tap device {tap}");
+        }
+        for veth in &report.veth_devices {
+            println!("{verb} veth {veth}");
+        }
+        for ns in &report.namespaces {
+            println!("{verb} netns {ns}");
+        }
+        for rule in &report.rules {
+            println!("{verb} rule [{}] {}", rule.table, rule.rule);
+        }
+        for lease in &report.subnet_leases {
+            let subnet = config.subnet_pool.subnet(lease.octet);
+            println!("{verb} lease on {subnet}.0/24 (VM {})", lease.vm);
+        }
+    }
+    for err in &report.errors {
+        println!("⚠️  could not remove {err}");
+    }
     Ok(())
 }
 
@@ -495,13 +1340,6 @@ mod tests {
         assert_eq!(mac.chars().filter(|&c| c == ':').count(), 5);
     }
 
-    #[test]
-    fn test_generate_random_octet() {
-        let octet = generate_random_octet();
-        assert!(octet >= 16);
-        assert!(octet <= 215); // 16 + 199
-    }
-
     #[tokio::test]
     async fn test_generate_unique_subnet_empty_dir() {
         let temp_dir = TempDir::new().unwrap();
@@ -510,7 +1348,7 @@ mod tests {
         let config = Config::new().unwrap();
         env::remove_var("MEDA_VM_DIR");
 
-        let subnet = generate_unique_subnet(&config).await.unwrap();
+        let subnet = generate_unique_subnet(&config, "test-vm").await.unwrap();
         assert!(subnet.starts_with("192.168."));
 
         let parts: Vec<&str> = subnet.split('.').collect();
@@ -535,7 +1373,7 @@ mod tests {
         let config = Config::new().unwrap();
         env::remove_var("MEDA_VM_DIR");
 
-        let subnet = generate_unique_subnet(&config).await.unwrap();
+        let subnet = generate_unique_subnet(&config, "test-vm").await.unwrap();
         assert!(subnet.starts_with("192.168."));
         assert_ne!(subnet, "192.168.100");
     }
@@ -551,14 +1389,6 @@ mod tests {
         }
     }
 
-    #[test]
-    fn test_octet_range() {
-        for _ in 0..1000 {
-            let octet = generate_random_octet();
-            assert!((16..=215).contains(&octet));
-        }
-    }
-
     #[tokio::test]
     async fn test_cleanup_networking_missing_vm() {
         let temp_dir = TempDir::new().unwrap();
@@ -572,18 +1402,254 @@ mod tests {
     }
 
     #[test]
-    fn test_parse_192_168_slash_24_octet() {
-        assert_eq!(parse_192_168_slash_24_octet("192.168.26.0/24"), Some(26));
-        assert_eq!(parse_192_168_slash_24_octet("192.168.0.0/24"), Some(0));
-        assert_eq!(parse_192_168_slash_24_octet("192.168.255.0/24"), Some(255));
-
-        // Reject non-/24, non-192.168, or non-.0 destinations so we never
-        // falsely claim an octet from an unrelated route.
-        assert_eq!(parse_192_168_slash_24_octet("10.0.0.0/24"), None);
-        assert_eq!(parse_192_168_slash_24_octet("192.168.26.0/16"), None);
-        assert_eq!(parse_192_168_slash_24_octet("192.168.26.1/32"), None);
-        assert_eq!(parse_192_168_slash_24_octet("192.168.26.0"), None);
-        assert_eq!(parse_192_168_slash_24_octet("192.168.999.0/24"), None);
-        assert_eq!(parse_192_168_slash_24_octet(""), None);
+    fn test_host_network_overlaps() {
+        let net = |line| host_network(line).unwrap();
+        let route =
+            net("192.168.26.0/24 dev tap-66c39bfa proto kernel scope link src 192.168.26.1");
+        assert_eq!(route, (Ipv4Addr::new(192, 168, 26, 0), 24));
+        assert_eq!(net("10.1.2.3 via 10.0.0.1 dev eth0").1, 32);
+        let addr = net("2: eth0    inet 192.168.1.5/24 brd 192.168.1.255 scope global eth0");
+        assert_eq!(addr, (Ipv4Addr::new(192, 168, 1, 5), 24));
+        assert_eq!(host_network("default via 10.0.0.1 dev eth0"), None);
+        assert_eq!(host_network("192.168.999.0/24 dev x"), None);
+        assert_eq!(host_network(""), None);
+
+        let subnet = Ipv4Addr::new(192, 168, 26, 0);
+        assert!(overlaps(subnet, &route));
+        assert!(overlaps(subnet, &net("192.168.26.9 dev wg0 scope link")));
+        assert!(overlaps(
+            subnet,
+            &net("192.168.0.0/16 via 10.0.0.1 dev tun0")
+        ));
+        assert!(!overlaps(subnet, &addr));
+        assert!(!overlaps(subnet, &net("10.0.0.0/8 dev tun0")));
+        // A VPN's half of the default route isn't a conflict.
+        assert!(!overlaps(subnet, &net("128.0.0.0/1 via 10.8.0.1 dev tun0")));
+    }
+
+    fn observed(namespace: &str, table: &str, rule: &str) -> ObservedRule {
+        ObservedRule {
+            namespace: namespace.to_string(),
+            table: table.to_string(),
+            rule: rule.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_rule_drift_netns_healthy() {
+        let spec = NetnsSpec::for_vm("inspect-vm");
+        let ns = spec.netns.as_str();
+        let rules = vec![
+            observed(
+                ns,
+                "nat",
+                "-A POSTROUTING -s 192.168.40.0/24 ! -d 192.168.40.0/24 -j MASQUERADE",
+            ),
+            observed(
+                ns,
+                "nat",
+                &format!(
+                    "-A PREROUTING -d {}/32 -j DNAT --to-destination 192.168.40.2",
+                    spec.netns_ip
+                ),
+            ),
+            observed(ns, "filter", "-A FORWARD -i tap-1234 -j ACCEPT"),
+            observed(
+                ns,
+                "filter",
+                "-A FORWARD -o tap-1234 -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT",
+            ),
+            observed(
+                "host",
+                "filter",
+                &format!("-A FORWARD -i {} -j ACCEPT", spec.veth_host),
+            ),
+            observed(
+                "host",
+                "filter",
+                &format!("-A FORWARD -o {} -j ACCEPT", spec.veth_host),
+            ),
+            observed(
+                "host",
+                "nat",
+                "-A POSTROUTING -s 10.99.0.0/16 ! -d 10.99.0.0/16 -j MASQUERADE",
+            ),
+        ];
+        let expected = expected_rules(
+            Some(&spec),
+            "192.168.40",
+            "tap-1234",
+            &[],
+            &ForwardPolicy::default(),
+        );
+        assert!(rule_drift(&expected, &rules).is_empty());
+    }
+
+    #[test]
+    fn test_rule_drift_reports_missing_and_duplicates() {
+        let rules = vec![
+            observed("host", "filter", "-A FORWARD -i tap-1234 -j ACCEPT"),
+            observed("host", "filter", "-A FORWARD -i tap-1234 -j ACCEPT"),
+            // Right rule, wrong table — must not count.
+            observed(
+                "host",
+                "filter",
+                "-A POSTROUTING -s 192.168.40.0/24 -j MASQUERADE",
+            ),
+        ];
+        let expected = expected_rules(
+            None,
+            "192.168.40",
+            "tap-1234",
+            &[(2222, 22)],
+            &ForwardPolicy::default(),
+        );
+        let drift = rule_drift(&expected, &rules);
+        assert_eq!(drift.len(), 4, "{drift:?}");
+        assert!(drift.iter().any(|d| d.starts_with("duplicate rule (2x)")));
+        assert!(drift
+            .iter()
+            .any(|d| d.contains("MASQUERADE") && d.starts_with("missing")));
+        assert!(drift.iter().any(|d| d.contains("--dport 2222")));
+    }
+
+    #[test]
+    fn test_isolation_rules() {
+        let spec = NetnsSpec::for_vm("iso");
+        let rules: Vec<ObservedRule> = isolation_rules(&spec.veth_host)
+            .iter()
+            .map(|r| observed("host", "filter", &format!("-A FORWARD {r}")))
+            .collect();
+        assert_eq!(rules.len(), 4);
+        assert!(rules
+            .iter()
+            .any(|r| r.rule == format!("-A FORWARD -i vmh+ -o {} -j DROP", spec.veth_host)));
+
+        let policy = ForwardPolicy {
+            isolated: true,
+            egress: None,
+            egress_interface: None,
+        };
+        let expected = expected_rules(Some(&spec), "192.168.40", "tap-1234", &[], &policy);
+        let drift = rule_drift(&expected, &rules);
+        // Only the non-isolation rules are missing.
+        assert_eq!(drift.len(), expected.len() - 4, "{drift:?}");
+        assert!(drift.iter().all(|d| !d.contains("DROP")));
+
+        let script = isolation_script("tap-1234", true);
+        assert_eq!(script.lines().count(), 4);
+        assert!(script.contains("iptables -w -I FORWARD -i tap-1234 -o tap+ -j DROP"));
+        assert!(isolation_script("tap-1234", false).contains("-D FORWARD -i tap+ -o tap-1234"));
+    }
+
+    #[test]
+    fn test_parse_port_forwards() {
+        assert_eq!(parse_port_forwards("2222->22"), vec![(2222, 22)]);
+        assert_eq!(parse_port_forwards("8080->80\ngarbage\n"), vec![(8080, 80)]);
+        assert!(parse_port_forwards("").is_empty());
+    }
+
+    fn live_with(taps: &[&str], subnets: &[&str]) -> LiveNetwork {
+        LiveNetwork {
+            taps: taps.iter().map(|s| s.to_string()).collect(),
+            subnets: subnets.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_is_stale_rule() {
+        let live = live_with(&["tap-live"], &["192.168.40"]);
+
+        assert!(is_stale_rule(
+            "filter",
+            "-A FORWARD -i tap-gone -j ACCEPT",
+            &live
+        ));
+        assert!(is_stale_rule(
+            "filter",
+            "-A FORWARD -o tap-gone -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT",
+            &live
+        ));
+        assert!(is_stale_rule(
+            "filter",
+            "-A FORWARD -i vmh-abc123 -j ACCEPT",
+            &live
+        ));
+        assert!(!is_stale_rule(
+            "filter",
+            "-A FORWARD -i tap-live -j ACCEPT",
+            &live
+        ));
+        assert!(!is_stale_rule(
+            "filter",
+            "-A FORWARD -i docker0 -j ACCEPT",
+            &live
+        ));
+        assert!(is_stale_rule(
+            "filter",
+            "-A FORWARD -i vmh+ -o tap-gone -j DROP",
+            &live
+        ));
+        assert!(!is_stale_rule(
+            "filter",
+            "-A FORWARD -i tap-live -o vmh+ -j DROP",
+            &live
+        ));
+        assert!(is_stale_rule("filter", "-N meda-eg-tap-gone", &live));
+        assert!(is_stale_rule(
+            "filter",
+            "-A meda-eg-tap-gone -d 10.0.0.0/8 -j RETURN",
+            &live
+        ));
+        assert!(!is_stale_rule("filter", "-N meda-eg-tap-live", &live));
+        assert!(!is_stale_rule("filter", "-N DOCKER", &live));
+
+        assert!(is_stale_rule(
+            "nat",
+            "-A POSTROUTING -s 192.168.41.0/24 -j MASQUERADE",
+            &live
+        ));
+        assert!(!is_stale_rule(
+            "nat",
+            "-A POSTROUTING -s 192.168.40.0/24 -j MASQUERADE",
+            &live
+        ));
+        assert!(!is_stale_rule(
+            "nat",
+            "-A POSTROUTING -s 10.99.0.0/16 ! -d 10.99.0.0/16 -j MASQUERADE",
+            &live
+        ));
+        assert!(!is_stale_rule(
+            "nat",
+            "-A POSTROUTING -s 192.168.0.0/16 -j MASQUERADE",
+            &live
+        ));
+
+        assert!(is_stale_rule(
+            "nat",
+            "-A PREROUTING -p tcp -m tcp --dport 8080 -j DNAT --to-destination 192.168.41.2:80",
+            &live
+        ));
+        assert!(!is_stale_rule(
+            "nat",
+            "-A PREROUTING -p tcp -m tcp --dport 8080 -j DNAT --to-destination 192.168.40.2:80",
+            &live
+        ));
+        assert!(!is_stale_rule(
+            "nat",
+            "-A PREROUTING -p tcp -m tcp --dport 53 -j DNAT --to-destination 192.168.41.7:53",
+            &live
+        ));
+    }
+
+    #[test]
+    fn test_link_names() {
+        let lines = vec![
+            "1: lo: <LOOPBACK,UP,LOWER_UP> mtu 65536".to_string(),
+            "7: tap-1a2b3c4d: <BROADCAST,MULTICAST> mtu 1500".to_string(),
+            "9: vmh-abc123@if8: <BROADCAST,MULTICAST,UP> mtu 1500".to_string(),
+        ];
+        assert_eq!(link_names(&lines), vec!["lo", "tap-1a2b3c4d", "vmh-abc123"]);
     }
 }
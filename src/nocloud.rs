@@ -0,0 +1,240 @@
+//! Cloud-init data over HTTP instead of a seed ISO (`meda create --no-iso`).
+//!
+//! By default cloud-init reads a VM's meta-data, user-data and
+//! network-config from `ci.iso` (see `iso`). A `--no-iso` VM has no ISO:
+//! meda serves the same files from its `ci` dir over HTTP on the host
+//! side of its tap, `<subnet>.1:8775`, and points the guest there through
+//! the SMBIOS serial number (`ds=nocloud-net;s=<url>`), which cloud-init's
+//! NoCloud datasource reads. Fetching them needs the guest's network up before cloud-init
+//! has configured it, so these VMs take their address over DHCP (see
+//! `dhcp`).
+//!
+//! The responder is meda itself (`meda nocloud-serve`, hidden), run as
+//! root inside the VM's netns whenever the VM starts and stopped with
+//! it, like its dnsmasq.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use log::debug;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Marker file in a VM dir: the VM's cloud-init data is served over HTTP.
+pub const NOCLOUD_FILE: &str = "nocloud";
+
+/// Port the responder listens on, on the host side of the VM's tap.
+pub const PORT: u16 = 8775;
+
+const PID_FILE: &str = "nocloud.pid";
+const LOG_FILE: &str = "nocloud.log";
+
+/// Files in the `ci` dir the guest may fetch.
+const SERVED: &[&str] = &["meta-data", "user-data", "vendor-data", "network-config"];
+
+/// How long the responder gets to start listening.
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests longer than this are cut off; cloud-init's are a few lines.
+const MAX_REQUEST: usize = 8192;
+
+pub fn is_enabled(vm_dir: &Path) -> bool {
+    vm_dir.join(NOCLOUD_FILE).exists()
+}
+
+pub fn enable(vm_dir: &Path) -> Result<()> {
+    fs::write(vm_dir.join(NOCLOUD_FILE), "")?;
+    Ok(())
+}
+
+/// Where the guest on `subnet` finds its cloud-init data.
+fn seed_url(subnet: &str) -> String {
+    format!("http://{}.1:{}/", subnet, PORT)
+}
+
+fn subnet(vm_dir: &Path) -> Option<String> {
+    fs::read_to_string(vm_dir.join("subnet"))
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+/// CH args pointing cloud-init at the responder. Empty for VMs with a
+/// seed ISO.
+pub fn ch_args(vm_dir: &Path) -> Vec<String> {
+    match subnet(vm_dir) {
+        Some(subnet) if is_enabled(vm_dir) => vec![
+            "--platform".to_string(),
+            format!("serial_number=ds=nocloud-net;s={}", seed_url(&subnet)),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+/// (Re)start VM `name`'s responder in its netns and wait until it
+/// listens. Does nothing for VMs with a seed ISO.
+pub fn start(config: &Config, name: &str) -> Result<()> {
+    let vm_dir = config.vm_dir(name);
+    if !is_enabled(&vm_dir) {
+        return Ok(());
+    }
+    stop(&vm_dir);
+    let subnet = subnet(&vm_dir).ok_or_else(|| Error::NetworkConfigMissing(name.to_string()))?;
+    let netns = crate::netns::NetnsSpec::load_or_compute(&vm_dir, name).netns;
+    let log_path = vm_dir.join(LOG_FILE);
+    let log = fs::File::create(&log_path)?;
+    let mut child = Command::new("sudo")
+        .args(["ip", "netns", "exec", &netns])
+        .arg(std::env::current_exe()?)
+        .arg("nocloud-serve")
+        .arg(&vm_dir)
+        .arg(format!("{}.1:{}", subnet, PORT))
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(log.try_clone()?))
+        .stderr(Stdio::from(log))
+        .spawn()
+        .map_err(|e| Error::CommandFailed(format!("spawn nocloud responder: {}", e)))?;
+
+    let deadline = Instant::now() + START_TIMEOUT;
+    while !vm_dir.join(PID_FILE).exists() {
+        if child.try_wait()?.is_some() || Instant::now() > deadline {
+            return Err(Error::CommandFailed(format!(
+                "cloud-init responder did not start; check {}",
+                log_path.display()
+            )));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    // Reaped whenever it exits, if this process is still around then
+    // (`meda serve`).
+    std::thread::spawn(move || child.wait());
+    Ok(())
+}
+
+/// Stop the VM's responder, if it has one running.
+pub fn stop(vm_dir: &Path) {
+    let pid_file = vm_dir.join(PID_FILE);
+    let Some(pid) = fs::read_to_string(&pid_file)
+        .ok()
+        .and_then(|p| p.trim().parse::<u32>().ok())
+    else {
+        return;
+    };
+    // Started as root, inside the netns.
+    let _ = Command::new("sudo")
+        .args(["kill", &pid.to_string()])
+        .output();
+    fs::remove_file(pid_file).ok();
+}
+
+/// Serve the `ci` dir of the VM in `vm_dir` on `addr` until killed.
+pub async fn serve(vm_dir: &Path, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    fs::write(vm_dir.join(PID_FILE), std::process::id().to_string())?;
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let ci_dir = vm_dir.join("ci");
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &ci_dir).await {
+                debug!("nocloud request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, ci_dir: &Path) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let request_line = request.lines().next().unwrap_or_default();
+    stream.write_all(&response(ci_dir, request_line)).await?;
+    stream.shutdown().await
+}
+
+/// The HTTP response to `request_line`: one of the [`SERVED`] files in
+/// `ci_dir`, or 404.
+fn response(ci_dir: &Path, request_line: &str) -> Vec<u8> {
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let name = path
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches('/');
+    let body = match method {
+        "GET" | "HEAD" if SERVED.contains(&name) => fs::read(ci_dir.join(name)).ok(),
+        _ => None,
+    };
+    let Some(body) = body else {
+        return b"HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_vec();
+    };
+    let mut response = format!(
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    if method == "GET" {
+        response.extend_from_slice(&body);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_ch_args() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("subnet"), "192.168.7\n").unwrap();
+        assert!(ch_args(dir.path()).is_empty());
+        enable(dir.path()).unwrap();
+        assert_eq!(
+            ch_args(dir.path()),
+            vec![
+                "--platform",
+                "serial_number=ds=nocloud-net;s=http://192.168.7.1:8775/"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_response() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("meta-data"), "instance-id: vm\n").unwrap();
+        fs::write(dir.path().join("secret"), "nope").unwrap();
+
+        let ok = String::from_utf8(response(dir.path(), "GET /meta-data HTTP/1.1")).unwrap();
+        assert!(ok.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(ok.contains("Content-Length: 16\r\n"));
+        assert!(ok.ends_with("\r\n\r\ninstance-id: vm\n"));
+
+        let head = String::from_utf8(response(dir.path(), "HEAD /meta-data HTTP/1.1")).unwrap();
+        assert!(head.ends_with("\r\n\r\n"));
+
+        // Only the cloud-init files, and nothing outside the dir.
+        for request in [
+            "GET /secret HTTP/1.1",
+            "GET /../subnet HTTP/1.1",
+            "GET /vendor-data HTTP/1.1",
+            "POST /meta-data HTTP/1.1",
+            "",
+        ] {
+            let not_found = String::from_utf8(response(dir.path(), request)).unwrap();
+            assert!(not_found.starts_with("HTTP/1.0 404"), "{}", request);
+        }
+    }
+}
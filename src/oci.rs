@@ -0,0 +1,1040 @@
+//! `meda import-oci` — turn a Docker/OCI container image into a VM image.
+//!
+//! The container's layers are fetched straight from the registry (v2
+//! distribution API, anonymous bearer tokens), flattened into a single
+//! tar with whiteouts applied, and unpacked over the root partition of
+//! a copy of the Ubuntu base disk. The base disk supplies what a
+//! container image lacks: partition table, bootloader, kernel, its
+//! modules and `/etc/fstab`. If the container has no init, cloud-init
+//! or sshd and ships `apt-get`, those are installed in a chroot so the
+//! result boots and accepts meda's cloud-init seed like any other image.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::image::{ImageManifest, ImageRef, ImageResult};
+use crate::platform::{host_arch, INDEX_MEDIA_TYPES};
+use crate::util::{ensure_dependency, run_command};
+use crate::vm;
+use flate2::read::GzDecoder;
+use futures_util::StreamExt;
+use log::{info, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MANIFEST_TYPES: &[&str] = &[
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+/// A container image reference, normalised the way `docker pull` does:
+/// `ubuntu` is `docker.io/library/ubuntu:latest`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OciRef {
+    pub registry: String,
+    pub repository: String,
+    /// Tag or `sha256:` digest.
+    pub reference: String,
+}
+
+impl OciRef {
+    pub fn parse(source: &str) -> Result<Self> {
+        let bad = || Error::InvalidImageName(source.to_string());
+        let (first, rest) = match source.split_once('/') {
+            Some((first, rest))
+                if first.contains('.') || first.contains(':') || first == "localhost" =>
+            {
+                (first, rest)
+            }
+            _ => ("docker.io", source),
+        };
+
+        let (repository, reference) = if let Some((repo, digest)) = rest.split_once('@') {
+            (repo, digest)
+        } else {
+            // A ':' after the last '/' is a tag, not a port.
+            let last = rest.rsplit('/').next().unwrap_or(rest);
+            match last.split_once(':') {
+                Some((_, tag)) => (&rest[..rest.len() - tag.len() - 1], tag),
+                None => (rest, "latest"),
+            }
+        };
+        if repository.is_empty() || reference.is_empty() {
+            return Err(bad());
+        }
+
+        let repository = if first == "docker.io" && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository.to_string()
+        };
+
+        Ok(Self {
+            registry: first.to_string(),
+            repository,
+            reference: reference.to_string(),
+        })
+    }
+
+    /// Host that actually serves the v2 API (Docker Hub's differs from its name).
+    fn api_host(&self) -> &str {
+        if self.registry == "docker.io" {
+            "registry-1.docker.io"
+        } else {
+            &self.registry
+        }
+    }
+}
+
+impl std::fmt::Display for OciRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sep = if self.reference.starts_with("sha256:") {
+            '@'
+        } else {
+            ':'
+        };
+        write!(
+            f,
+            "{}/{}{}{}",
+            self.registry, self.repository, sep, self.reference
+        )
+    }
+}
+
+/// Parse a `WWW-Authenticate: Bearer realm="...",service="...",scope="..."` challenge.
+pub(crate) fn parse_bearer_challenge(header: &str) -> Option<HashMap<String, String>> {
+    let params = header.strip_prefix("Bearer ")?;
+    let mut out = HashMap::new();
+    let mut rest = params.trim();
+    while !rest.is_empty() {
+        let (key, after) = rest.split_once('=')?;
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        out.insert(key.trim().to_string(), value.to_string());
+        rest = after.trim_start_matches(',').trim();
+    }
+    out.contains_key("realm").then_some(out)
+}
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    digest: String,
+    platform: Option<Platform>,
+}
+
+#[derive(Deserialize)]
+struct Platform {
+    os: String,
+    architecture: String,
+}
+
+#[derive(Deserialize)]
+struct Descriptor {
+    #[serde(rename = "mediaType", default)]
+    media_type: String,
+    digest: String,
+}
+
+#[derive(Deserialize)]
+struct ImageManifestDoc {
+    layers: Vec<Descriptor>,
+}
+
+/// Pick the linux/`arch` entry out of a manifest list / image index.
+fn select_platform(index: &[u8], arch: &str) -> Result<String> {
+    #[derive(Deserialize)]
+    struct Index {
+        manifests: Vec<IndexEntry>,
+    }
+    let index: Index = serde_json::from_slice(index)?;
+    index
+        .manifests
+        .into_iter()
+        .find(|m| {
+            m.platform
+                .as_ref()
+                .is_some_and(|p| p.os == "linux" && p.architecture == arch)
+        })
+        .map(|m| m.digest)
+        .ok_or_else(|| Error::Other(format!("image has no linux/{} variant", arch)))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// A v2 distribution API client for one repository: anonymous or bearer
+/// tokens, or basic auth where the registry asks for it.
+#[derive(Clone)]
+pub(crate) struct Registry {
+    client: reqwest::Client,
+    image: OciRef,
+    /// `https://<api host>`
+    base_url: String,
+    token: Option<String>,
+    /// Sent to the token service, for private repositories.
+    credentials: Option<crate::credentials::Credentials>,
+    /// The registry wants basic auth rather than tokens.
+    basic: bool,
+    /// Ask for push access, not just pull.
+    push: bool,
+}
+
+impl Registry {
+    pub(crate) fn new(
+        image: OciRef,
+        credentials: Option<crate::credentials::Credentials>,
+    ) -> Result<Self> {
+        Ok(Self {
+            client: crate::proxy::client()?,
+            base_url: format!("https://{}", image.api_host()),
+            image,
+            token: None,
+            credentials,
+            basic: false,
+            push: false,
+        })
+    }
+
+    /// A client that can also upload, authenticating as `credentials`.
+    pub(crate) fn for_push(
+        image: OciRef,
+        credentials: crate::credentials::Credentials,
+    ) -> Result<Self> {
+        let mut registry = Self::new(image, Some(credentials))?;
+        registry.push = true;
+        Ok(registry)
+    }
+
+    /// Talk to `base_url` (e.g. `http://127.0.0.1:5000`) instead.
+    #[cfg(test)]
+    pub(crate) fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.to_string();
+        self
+    }
+
+    pub(crate) fn image(&self) -> &OciRef {
+        &self.image
+    }
+
+    fn url(&self, kind: &str, reference: &str) -> String {
+        format!(
+            "{}/v2/{}/{}/{}",
+            self.base_url, self.image.repository, kind, reference
+        )
+    }
+
+    async fn fetch_token(&mut self, challenge: &str) -> Result<()> {
+        let params = parse_bearer_challenge(challenge).ok_or_else(|| {
+            Error::Other(format!(
+                "{} requires authentication ({})",
+                self.image.registry, challenge
+            ))
+        })?;
+        let actions = if self.push { "pull,push" } else { "pull" };
+        let mut query: Vec<(&str, String)> = Vec::new();
+        if let Some(service) = params.get("service") {
+            query.push(("service", service.clone()));
+        }
+        query.push((
+            "scope",
+            match params.get("scope") {
+                Some(scope) if !self.push => scope.clone(),
+                _ => format!("repository:{}:{}", self.image.repository, actions),
+            },
+        ));
+
+        #[derive(Deserialize)]
+        struct TokenResponse {
+            token: Option<String>,
+            access_token: Option<String>,
+        }
+        let mut request = self.client.get(&params["realm"]).query(&query);
+        if let Some(credentials) = &self.credentials {
+            request = request.basic_auth(&credentials.username, Some(&credentials.password));
+        }
+        let response: TokenResponse = request.send().await?.error_for_status()?.json().await?;
+        self.token = response.token.or(response.access_token);
+        if self.token.is_none() {
+            return Err(Error::Other(format!(
+                "{} returned no token",
+                self.image.registry
+            )));
+        }
+        Ok(())
+    }
+
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match (&self.token, &self.credentials) {
+            (Some(token), _) => request.bearer_auth(token),
+            (None, Some(credentials)) if self.basic => {
+                request.basic_auth(&credentials.username, Some(&credentials.password))
+            }
+            _ => request,
+        }
+    }
+
+    /// Send what `build` makes with the current credentials, getting
+    /// (or, once expired, renewing) some on the first 401. `build` runs
+    /// again for that retry, so it must not stream its body.
+    async fn send(
+        &mut self,
+        build: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response> {
+        let mut retried = false;
+        loop {
+            let response = self.authorize(build(&self.client)).send().await?;
+            if response.status() != reqwest::StatusCode::UNAUTHORIZED || retried || self.basic {
+                return Ok(response);
+            }
+            retried = true;
+            let challenge = response
+                .headers()
+                .get("www-authenticate")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            if challenge.starts_with("Basic") && self.credentials.is_some() {
+                self.basic = true;
+            } else {
+                self.fetch_token(&challenge).await?;
+            }
+        }
+    }
+
+    /// GET with the current token, fetching one on the first 401.
+    async fn get(&mut self, url: &str, accept: &str) -> Result<reqwest::Response> {
+        let response = self
+            .send(|client| client.get(url).header("Accept", accept))
+            .await?;
+        if !response.status().is_success() {
+            return Err(Error::DownloadFailed(
+                url.to_string(),
+                format!("HTTP status: {}", response.status()),
+            ));
+        }
+        Ok(response)
+    }
+
+    /// Fail with what the registry said about a `what` request.
+    async fn check(response: reqwest::Response, what: &str) -> Result<reqwest::Response> {
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        Err(Error::Other(format!(
+            "{} failed: HTTP {}: {}",
+            what,
+            status,
+            body.trim()
+        )))
+    }
+
+    /// The upload URL in `response`'s Location, which may be relative to
+    /// `base`.
+    fn location(response: &reqwest::Response, base: &str) -> Result<reqwest::Url> {
+        let location = response
+            .headers()
+            .get("location")
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| Error::Other("Registry sent no upload location".to_string()))?;
+        reqwest::Url::parse(base)
+            .and_then(|base| base.join(location))
+            .map_err(|e| Error::Other(format!("Bad upload location {}: {}", location, e)))
+    }
+
+    async fn start_upload(&mut self) -> Result<reqwest::Url> {
+        let url = self.url("blobs", "uploads/");
+        let response = self
+            .send(|client| client.post(&url).header("Content-Length", "0"))
+            .await?;
+        let response = Self::check(response, "Starting a blob upload").await?;
+        Self::location(&response, &url)
+    }
+
+    async fn finish_upload(
+        &mut self,
+        mut location: reqwest::Url,
+        digest: &str,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        location.query_pairs_mut().append_pair("digest", digest);
+        let response = self
+            .authorize(self.client.put(location).body(data))
+            .send()
+            .await?;
+        Self::check(response, &format!("Uploading {}", digest)).await?;
+        Ok(())
+    }
+
+    /// Whether the repository already has blob `digest`.
+    pub(crate) async fn has_blob(&mut self, digest: &str) -> Result<bool> {
+        let url = self.url("blobs", digest);
+        let response = self.send(|client| client.head(&url)).await?;
+        Ok(response.status().is_success())
+    }
+
+    /// Upload a small blob held in memory; returns its digest.
+    pub(crate) async fn upload_bytes(&mut self, data: Vec<u8>) -> Result<String> {
+        let digest = format!("sha256:{}", sha256_hex(&data));
+        if !self.has_blob(&digest).await? {
+            let location = self.start_upload().await?;
+            self.finish_upload(location, &digest, data).await?;
+        }
+        Ok(digest)
+    }
+
+    /// Upload a blob as `body` produces it. `produced` resolves once it
+    /// is done, to the blob's digest and whatever else the producer
+    /// returns; a producer error wins over the upload failing because
+    /// of it.
+    pub(crate) async fn upload_stream<T>(
+        &mut self,
+        body: reqwest::Body,
+        produced: impl std::future::Future<Output = Result<(String, T)>>,
+    ) -> Result<(String, T)> {
+        let location = self.start_upload().await?;
+        let response = self
+            .authorize(
+                self.client
+                    .patch(location.clone())
+                    .header("Content-Type", "application/octet-stream")
+                    .body(body),
+            )
+            .send()
+            .await;
+        let produced = produced.await;
+        let response = match response {
+            Ok(response) => Self::check(response, "Streaming a blob").await?,
+            Err(e) => return Err(produced.err().unwrap_or(e.into())),
+        };
+        let (digest, rest) = produced?;
+        let location = Self::location(&response, location.as_str())?;
+        self.finish_upload(location, &digest, Vec::new()).await?;
+        Ok((digest, rest))
+    }
+
+    /// Store `body` as manifest `reference` (a tag); returns its digest.
+    pub(crate) async fn put_manifest(
+        &mut self,
+        reference: &str,
+        media_type: &str,
+        body: &[u8],
+    ) -> Result<String> {
+        let url = self.url("manifests", reference);
+        let response = self
+            .send(|client| {
+                client
+                    .put(&url)
+                    .header("Content-Type", media_type)
+                    .body(body.to_vec())
+            })
+            .await?;
+        Self::check(response, &format!("Pushing manifest {}", reference)).await?;
+        Ok(format!("sha256:{}", sha256_hex(body)))
+    }
+
+    /// Resolve the reference to a single-platform manifest. Returns the
+    /// manifest's digest and its layers, bottom first.
+    async fn resolve(&mut self) -> Result<(String, Vec<Descriptor>)> {
+        let accept = [INDEX_MEDIA_TYPES, MANIFEST_TYPES].concat().join(", ");
+        let mut reference = self.image.reference.clone();
+        for _ in 0..2 {
+            let url = self.url("manifests", &reference);
+            let response = self.get(&url, &accept).await?;
+            let media_type = response
+                .headers()
+                .get("content-type")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            let body = response.bytes().await?;
+            if INDEX_MEDIA_TYPES.contains(&media_type.as_str()) {
+                reference = select_platform(&body, host_arch())?;
+                continue;
+            }
+            let doc: ImageManifestDoc = serde_json::from_slice(&body)?;
+            return Ok((format!("sha256:{}", sha256_hex(&body)), doc.layers));
+        }
+        Err(Error::Other(format!(
+            "{}: nested manifest lists are not supported",
+            self.image
+        )))
+    }
+
+    /// Stream a blob to `dest`, verifying its sha256 digest.
+    async fn download_blob(&mut self, digest: &str, dest: &Path) -> Result<()> {
+        let expected = digest
+            .strip_prefix("sha256:")
+            .ok_or_else(|| Error::Other(format!("unsupported digest algorithm in {}", digest)))?;
+        let url = self.url("blobs", digest);
+        let response = self.get(&url, "*/*").await?;
+        let mut file = File::create(dest)?;
+        let mut hasher = Sha256::new();
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            hasher.update(&chunk);
+            file.write_all(&chunk)?;
+        }
+        let actual = format!("{:x}", hasher.finalize());
+        if actual != expected {
+            return Err(Error::Other(format!(
+                "digest mismatch for {}: got sha256:{}",
+                digest, actual
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Open a layer blob as a tar stream (gzip or uncompressed).
+fn open_layer(path: &Path) -> Result<tar::Archive<Box<dyn Read>>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 2];
+    let n = file.read(&mut magic)?;
+    let head = std::io::Cursor::new(magic[..n].to_vec()).chain(file);
+    let reader: Box<dyn Read> = if magic[..n] == [0x1f, 0x8b] {
+        Box::new(GzDecoder::new(head))
+    } else {
+        Box::new(head)
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+fn normalize(path: &Path) -> String {
+    let s = path.to_string_lossy();
+    s.trim_start_matches("./")
+        .trim_start_matches('/')
+        .trim_end_matches('/')
+        .to_string()
+}
+
+fn parent_of(path: &str) -> &str {
+    path.rsplit_once('/').map(|(p, _)| p).unwrap_or("")
+}
+
+/// Proper ancestors of `path`, nearest first (`a/b/c` → `a/b`, `a`).
+fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/').map(move |(i, _)| &path[..i]).rev()
+}
+
+/// Whether symlink `path` -> `target` points outside the root it is
+/// unpacked in: an absolute target, or one climbing above the root.
+fn escapes(path: &str, target: &Path) -> bool {
+    let mut depth = parent_of(path).split('/').filter(|c| !c.is_empty()).count();
+    for component in target.components() {
+        match component {
+            std::path::Component::Normal(_) => depth += 1,
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return true,
+        }
+    }
+    false
+}
+
+/// Merge `layers` (bottom first) into one tar at `out`, the way an
+/// overlay mount would present them: upper entries shadow lower ones,
+/// `.wh.<name>` deletes `<name>` from lower layers and `.wh..wh..opq`
+/// hides everything a lower layer put in that directory.
+///
+/// Top-level symlinks (`lib -> usr/lib`) must stay inside the image:
+/// the import writes through `etc` and `usr` as root, so `etc -> /etc`
+/// would land on the host.
+pub fn flatten_layers(layers: &[PathBuf], out: &Path) -> Result<()> {
+    // Pass 1, top layer down: decide which entries survive.
+    let mut shadowed: HashSet<String> = HashSet::new();
+    // Hides the path and everything below it.
+    let mut removed: HashSet<String> = HashSet::new();
+    // Hides everything below the path, but not the path itself.
+    let mut opaque: HashSet<String> = HashSet::new();
+    let mut keep: Vec<HashSet<usize>> = vec![HashSet::new(); layers.len()];
+
+    for (layer_idx, layer) in layers.iter().enumerate().rev() {
+        let mut new_shadowed = Vec::new();
+        let mut new_removed = Vec::new();
+        let mut new_opaque = Vec::new();
+        let mut archive = open_layer(layer)?;
+        for (entry_idx, entry) in archive.entries()?.enumerate() {
+            let entry = entry?;
+            let path = normalize(&entry.path()?);
+            if path.is_empty() {
+                continue;
+            }
+            let name = path.rsplit('/').next().unwrap_or(&path);
+            if name == ".wh..wh..opq" {
+                new_opaque.push(parent_of(&path).to_string());
+                continue;
+            }
+            if let Some(target) = name.strip_prefix(".wh.") {
+                let parent = parent_of(&path);
+                new_removed.push(if parent.is_empty() {
+                    target.to_string()
+                } else {
+                    format!("{}/{}", parent, target)
+                });
+                continue;
+            }
+
+            let hidden = shadowed.contains(&path)
+                || removed.contains(&path)
+                || ancestors(&path).any(|a| removed.contains(a) || opaque.contains(a));
+            if hidden {
+                continue;
+            }
+            if entry.header().entry_type().is_symlink() && !path.contains('/') {
+                if let Some(target) = entry.link_name()? {
+                    if escapes(&path, &target) {
+                        return Err(Error::Other(format!(
+                            "Image layer links /{} to {}, outside the image",
+                            path,
+                            target.display()
+                        )));
+                    }
+                }
+            }
+            keep[layer_idx].insert(entry_idx);
+            if entry.header().entry_type().is_dir() {
+                new_shadowed.push(path);
+            } else {
+                // A file replacing a lower directory hides its contents too.
+                new_removed.push(path);
+            }
+        }
+        shadowed.extend(new_shadowed);
+        removed.extend(new_removed);
+        opaque.extend(new_opaque);
+    }
+
+    // Pass 2, bottom up so directories and hardlink targets come first.
+    let mut builder = tar::Builder::new(File::create(out)?);
+    for (layer_idx, layer) in layers.iter().enumerate() {
+        let mut archive = open_layer(layer)?;
+        for (entry_idx, entry) in archive.entries()?.enumerate() {
+            let mut entry = entry?;
+            if !keep[layer_idx].contains(&entry_idx) {
+                continue;
+            }
+            let path = entry.path()?.into_owned();
+            let mut header = entry.header().clone();
+            match entry.link_name()? {
+                Some(target) => {
+                    let target = target.into_owned();
+                    builder.append_link(&mut header, &path, &target)?;
+                }
+                None => builder.append_data(&mut header, &path, &mut entry)?,
+            }
+        }
+    }
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// Shell run as root: unpack the flattened rootfs over the base disk's
+/// root partition, keeping the base's /boot, kernel modules and fstab,
+/// then install whatever the container lacks to boot under meda.
+fn install_script(disk: &Path, rootfs_tar: &Path, work: &Path, install_packages: bool) -> String {
+    format!(
+        r#"set -euo pipefail
+DISK="{disk}"
+FLAT="{flat}"
+WORK="{work}"
+MNT="$WORK/mnt"
+LOOP=$(losetup -fP --show "$DISK")
+cleanup() {{
+    umount -R "$MNT" 2>/dev/null || true
+    losetup -d "$LOOP"
+}}
+trap cleanup EXIT
+udevadm settle 2>/dev/null || true
+
+ROOT=$(lsblk -nrpo NAME,LABEL "$LOOP" | awk '$2=="cloudimg-rootfs" {{ print $1; exit }}')
+[ -n "$ROOT" ] || ROOT="${{LOOP}}p1"
+mkdir -p "$MNT"
+MNT=$(realpath "$MNT")
+mount "$ROOT" "$MNT"
+
+# The container owns everything under $MNT, symlinks included: refuse
+# to write through a path that resolves outside it.
+inside() {{
+    case "$(realpath -m "$1")" in
+        "$MNT"/*) ;;
+        *) echo "$1 resolves outside the image root" >&2; exit 1 ;;
+    esac
+}}
+
+mkdir -p "$WORK/keep"
+cp -a "$MNT/usr/lib/modules" "$WORK/keep/modules"
+cp -a "$MNT/etc/fstab" "$WORK/keep/fstab"
+find "$MNT" -mindepth 1 -maxdepth 1 ! -name boot ! -name lost+found -exec rm -rf {{}} +
+tar -xpf "$FLAT" --numeric-owner --anchored --exclude=boot --exclude=./boot -C "$MNT"
+
+if [ -L "$MNT/lib" ]; then MODDIR="$MNT/usr/lib/modules"; else MODDIR="$MNT/lib/modules"; fi
+inside "$(dirname "$MODDIR")"
+inside "$MNT/etc"
+mkdir -p "$(dirname "$MODDIR")" "$MNT/etc"
+rm -rf "$MODDIR"
+cp -a "$WORK/keep/modules" "$MODDIR"
+rm -f "$MNT/etc/fstab"
+cp "$WORK/keep/fstab" "$MNT/etc/fstab"
+
+PKGS=""
+chroot "$MNT" test -e /sbin/init 2>/dev/null || PKGS="$PKGS systemd-sysv udev"
+chroot "$MNT" test -e /usr/bin/cloud-init 2>/dev/null || PKGS="$PKGS cloud-init"
+chroot "$MNT" test -e /usr/sbin/sshd 2>/dev/null || PKGS="$PKGS openssh-server"
+chroot "$MNT" test -e /usr/bin/sudo 2>/dev/null || PKGS="$PKGS sudo"
+if [ -n "$PKGS" ] && [ "{install}" = 1 ] && chroot "$MNT" test -x /usr/bin/apt-get 2>/dev/null; then
+    mount --bind /dev "$MNT/dev"
+    mount -t proc proc "$MNT/proc"
+    mount -t sysfs sys "$MNT/sys"
+    rm -f "$MNT/etc/resolv.conf"
+    cp -L /etc/resolv.conf "$MNT/etc/resolv.conf"
+    chroot "$MNT" env DEBIAN_FRONTEND=noninteractive apt-get update
+    chroot "$MNT" env DEBIAN_FRONTEND=noninteractive apt-get install -y --no-install-recommends $PKGS iproute2
+    chroot "$MNT" apt-get clean
+    PKGS=""
+fi
+echo "$PKGS" > "$WORK/missing"
+"#,
+        disk = disk.display(),
+        flat = rootfs_tar.display(),
+        work = work.display(),
+        install = u8::from(install_packages),
+    )
+}
+
+/// Import `source` (a container image reference) as local image `name`.
+pub async fn import(
+    config: &Config,
+    source: &str,
+    name: &str,
+    install_packages: bool,
+    json: bool,
+) -> Result<()> {
+    let oci_ref = OciRef::parse(source)?;
+    let image_ref = ImageRef::parse(name, &config.default_registry, &config.default_org)?;
+    let image_dir = image_ref.local_dir(config);
+    if image_dir.exists() {
+        return Err(Error::ImageAlreadyExists(image_ref.url()));
+    }
+    for (program, package) in [
+        ("losetup", "util-linux"),
+        ("lsblk", "util-linux"),
+        ("chroot", "coreutils"),
+        ("tar", "tar"),
+    ] {
+        ensure_dependency(program, package)?;
+    }
+
+    // The base disk provides kernel, bootloader and partition layout.
+    vm::bootstrap(config).await?;
+
+    let work = tempfile::Builder::new()
+        .prefix("import-oci-")
+        .tempdir_in(&config.asset_dir)?;
+
+    if !json {
+        info!("Resolving {}", oci_ref);
+    }
+    config.require_network(&format!("Importing {}", oci_ref))?;
+    let mut registry = Registry::new(
+        oci_ref.clone(),
+        crate::credentials::lookup(config, &oci_ref.registry),
+    )?;
+    let (manifest_digest, descriptors) = registry.resolve().await?;
+    if let Some(d) = descriptors
+        .iter()
+        .find(|d| d.media_type.contains("zstd") || d.media_type.contains("nondistributable"))
+    {
+        return Err(Error::Other(format!(
+            "unsupported layer type {} in {}",
+            d.media_type, oci_ref
+        )));
+    }
+
+    let mut layers = Vec::new();
+    for (i, desc) in descriptors.iter().enumerate() {
+        if !json {
+            info!(
+                "Fetching layer {}/{} ({})",
+                i + 1,
+                descriptors.len(),
+                desc.digest
+            );
+        }
+        let path = work.path().join(format!("layer-{}", i));
+        registry.download_blob(&desc.digest, &path).await?;
+        layers.push(path);
+    }
+
+    if !json {
+        info!("Flattening {} layers", layers.len());
+    }
+    let rootfs_tar = work.path().join("rootfs.tar");
+    flatten_layers(&layers, &rootfs_tar)?;
+    for layer in &layers {
+        let _ = fs::remove_file(layer);
+    }
+
+    fs::create_dir_all(&image_dir)?;
+    let result = build_disk(
+        config,
+        &image_dir,
+        &rootfs_tar,
+        work.path(),
+        install_packages,
+    );
+    let missing = match result.await {
+        Ok(missing) => missing,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&image_dir);
+            return Err(e);
+        }
+    };
+    if !missing.is_empty() {
+        warn!(
+            "{} lacks {} and has no apt-get{}; the image may not boot or accept cloud-init",
+            oci_ref,
+            missing,
+            if install_packages {
+                ""
+            } else {
+                " (or --no-install was given)"
+            }
+        );
+    }
+
+    let mut artifacts = HashMap::new();
+    artifacts.insert("base_image".to_string(), "base.raw".to_string());
+    let mut metadata = HashMap::new();
+    metadata.insert("created_by".to_string(), "meda".to_string());
+    metadata.insert("type".to_string(), "oci_import".to_string());
+    metadata.insert("oci_source".to_string(), oci_ref.to_string());
+    metadata.insert("oci_digest".to_string(), manifest_digest);
+    ImageManifest {
+        schema_version: crate::schema::IMAGE_SCHEMA_VERSION,
+        name: image_ref.name.clone(),
+        tag: image_ref.tag.clone(),
+        registry: image_ref.registry.clone(),
+        org: image_ref.org.clone(),
+        artifacts,
+        metadata,
+        created: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        history: Vec::new(),
+    }
+    .save(&image_dir)?;
+
+    let message = format!("Imported {} as {}", oci_ref, image_ref.url());
+    if json {
+        let result = ImageResult {
+            success: true,
+            message,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!("{}", message);
+        info!("💡 Run it with 'meda run {}'", name);
+    }
+    Ok(())
+}
+
+/// Copy the base disk into the image dir and populate it. Returns the
+/// packages that were missing and could not be installed.
+async fn build_disk(
+    config: &Config,
+    image_dir: &Path,
+    rootfs_tar: &Path,
+    work: &Path,
+    install_packages: bool,
+) -> Result<String> {
+    let disk = image_dir.join("base.raw");
+    let base_image = crate::base_image::current(config);
+    run_command(
+        "cp",
+        &[
+            "--sparse=always",
+            "--reflink=auto",
+            base_image.to_str().unwrap(),
+            disk.to_str().unwrap(),
+        ],
+    )
+    .await?;
+    let script = install_script(&disk, rootfs_tar, work, install_packages);
+    run_command("sudo", &["bash", "-c", &script]).await?;
+    Ok(fs::read_to_string(work.join("missing"))
+        .unwrap_or_default()
+        .trim()
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_oci_ref_parse() {
+        let r = OciRef::parse("ubuntu").unwrap();
+        assert_eq!(r.registry, "docker.io");
+        assert_eq!(r.repository, "library/ubuntu");
+        assert_eq!(r.reference, "latest");
+        assert_eq!(r.to_string(), "docker.io/library/ubuntu:latest");
+
+        let r = OciRef::parse("docker.io/library/ubuntu:24.04").unwrap();
+        assert_eq!(r.repository, "library/ubuntu");
+        assert_eq!(r.reference, "24.04");
+
+        let r = OciRef::parse("localhost:5000/team/app").unwrap();
+        assert_eq!(r.registry, "localhost:5000");
+        assert_eq!(r.repository, "team/app");
+        assert_eq!(r.reference, "latest");
+
+        let r = OciRef::parse("ghcr.io/org/img@sha256:abc").unwrap();
+        assert_eq!(r.reference, "sha256:abc");
+        assert_eq!(r.to_string(), "ghcr.io/org/img@sha256:abc");
+        assert_eq!(r.api_host(), "ghcr.io");
+
+        assert!(OciRef::parse("ubuntu:").is_err());
+    }
+
+    #[test]
+    fn test_parse_bearer_challenge() {
+        let c = parse_bearer_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/ubuntu:pull""#,
+        )
+        .unwrap();
+        assert_eq!(c["realm"], "https://auth.docker.io/token");
+        assert_eq!(c["service"], "registry.docker.io");
+        assert_eq!(c["scope"], "repository:library/ubuntu:pull");
+        assert!(parse_bearer_challenge("Basic realm=\"x\"").is_none());
+    }
+
+    #[test]
+    fn test_select_platform() {
+        let index = br#"{"manifests":[
+            {"digest":"sha256:arm","platform":{"os":"linux","architecture":"arm64"}},
+            {"digest":"sha256:amd","platform":{"os":"linux","architecture":"amd64"}},
+            {"digest":"sha256:att"}
+        ]}"#;
+        assert_eq!(select_platform(index, "amd64").unwrap(), "sha256:amd");
+        assert!(select_platform(index, "riscv64").is_err());
+    }
+
+    fn write_layer(dir: &Path, name: &str, entries: &[(&str, Option<&str>)]) -> PathBuf {
+        let path = dir.join(name);
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+        for (entry_path, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            match content {
+                Some(data) => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_size(data.len() as u64);
+                    header.set_mode(0o644);
+                    builder
+                        .append_data(&mut header, entry_path, data.as_bytes())
+                        .unwrap();
+                }
+                None => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    header.set_mode(0o755);
+                    builder
+                        .append_data(&mut header, entry_path, std::io::empty())
+                        .unwrap();
+                }
+            }
+        }
+        builder.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn test_flatten_layers_whiteouts() {
+        let dir = TempDir::new().unwrap();
+        let lower = write_layer(
+            dir.path(),
+            "l0",
+            &[
+                ("etc/", None),
+                ("etc/os-release", Some("old")),
+                ("etc/gone", Some("x")),
+                ("var/", None),
+                ("var/cache/", None),
+                ("var/cache/a", Some("a")),
+            ],
+        );
+        let upper = write_layer(
+            dir.path(),
+            "l1",
+            &[
+                ("etc/os-release", Some("new")),
+                ("etc/.wh.gone", Some("")),
+                ("var/cache/.wh..wh..opq", Some("")),
+                ("var/cache/b", Some("b")),
+            ],
+        );
+        let out = dir.path().join("flat.tar");
+        flatten_layers(&[lower, upper], &out).unwrap();
+
+        let mut files = HashMap::new();
+        let mut archive = tar::Archive::new(File::open(&out).unwrap());
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = normalize(&entry.path().unwrap());
+            let mut body = String::new();
+            entry.read_to_string(&mut body).unwrap();
+            assert!(files.insert(path, body).is_none(), "duplicate entry");
+        }
+        assert_eq!(files["etc/os-release"], "new");
+        assert_eq!(files["var/cache/b"], "b");
+        assert!(files.contains_key("etc"));
+        assert!(!files.contains_key("etc/gone"));
+        assert!(!files.contains_key("var/cache/a"));
+        assert!(!files.keys().any(|k| k.contains(".wh.")));
+    }
+
+    fn write_symlink_layer(dir: &Path, name: &str, path: &str, target: &str) -> PathBuf {
+        let layer = dir.join(name);
+        let mut builder = tar::Builder::new(File::create(&layer).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder.append_link(&mut header, path, target).unwrap();
+        builder.finish().unwrap();
+        layer
+    }
+
+    #[test]
+    fn test_flatten_layers_refuses_escaping_symlinks() {
+        let dir = TempDir::new().unwrap();
+        let out = dir.path().join("flat.tar");
+        for (path, target) in [("etc", "/etc"), ("usr", "../usr"), ("lib", "usr/../../lib")] {
+            let layer = write_symlink_layer(dir.path(), "l0", path, target);
+            let err = flatten_layers(&[layer], &out).unwrap_err();
+            assert!(err.to_string().contains("outside the image"), "{}", err);
+        }
+
+        // usrmerge links, and absolute links further down, are fine.
+        let lib = write_symlink_layer(dir.path(), "l1", "lib", "usr/lib");
+        let localtime =
+            write_symlink_layer(dir.path(), "l2", "etc/localtime", "/usr/share/zoneinfo/UTC");
+        flatten_layers(&[lib, localtime], &out).unwrap();
+    }
+}
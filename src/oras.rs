@@ -0,0 +1,424 @@
+//! The ORAS CLI that image push/pull shells out to.
+//!
+//! meda installs a pinned ORAS release into the asset dir
+//! (`MEDA_ORAS_VERSION`, default [`DEFAULT_VERSION`]); `meda deps oras`
+//! installs another one in its place. `MEDA_ORAS_BIN` points meda at an
+//! ORAS of your own instead (a path, or a name looked up on `PATH`).
+//!
+//! Flags differ between ORAS releases, so meda asks the binary for its
+//! version before using it and builds arguments from that: `resolve`
+//! and `--no-tty` need 1.1, and 1.3 prints transfer details without
+//! `--verbose` (which it deprecates). Anything outside 1.x, or older
+//! than [`MIN_VERSION`], is refused with a message saying how to fix it
+//! rather than failing halfway through a push.
+
+use crate::assets::{self, Asset};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use log::{info, warn};
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_VERSION: Version = Version(1, 2, 3);
+
+/// Oldest ORAS whose push/pull flags meda knows.
+pub const MIN_VERSION: Version = Version(1, 0, 0);
+
+/// `oras resolve` and `--no-tty` arrived in 1.1.
+const RESOLVE_VERSION: Version = Version(1, 1, 0);
+
+/// 1.3 shows per-file transfer output by default and deprecates
+/// `--verbose`.
+const QUIET_VERBOSE_VERSION: Version = Version(1, 3, 0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Version(pub u32, pub u32, pub u32);
+
+impl Version {
+    /// `1.2.3`, `v1.2.3` or a prerelease such as `1.3.0-beta.1`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim().trim_start_matches('v');
+        let core = s.split(['-', '+']).next()?;
+        let mut parts = core.split('.').map(|p| p.parse::<u32>().ok());
+        let version = Version(parts.next()??, parts.next()??, parts.next()??);
+        parts.next().is_none().then_some(version)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+pub fn parse_version_arg(s: &str) -> std::result::Result<Version, String> {
+    Version::parse(s).ok_or_else(|| format!("invalid ORAS version '{}' (expected e.g. 1.2.3)", s))
+}
+
+pub fn release_url(version: Version) -> String {
+    format!(
+        "https://github.com/oras-project/oras/releases/download/v{v}/oras_{v}_linux_amd64.tar.gz",
+        v = version
+    )
+}
+
+/// `MEDA_ORAS_BIN`: a path, or a bare name found on `PATH`.
+pub fn resolve_bin(spec: &str) -> PathBuf {
+    if spec.contains('/') {
+        return PathBuf::from(spec);
+    }
+    std::env::var_os("PATH")
+        .iter()
+        .flat_map(std::env::split_paths)
+        .map(|dir| dir.join(spec))
+        .find(|candidate| candidate.is_file())
+        .unwrap_or_else(|| PathBuf::from(spec))
+}
+
+/// A probed ORAS binary.
+#[derive(Debug, Clone)]
+pub struct Oras {
+    pub path: PathBuf,
+    pub version: Version,
+}
+
+impl Oras {
+    /// Ask the binary at `path` for its version and refuse it if meda
+    /// can't drive it.
+    pub async fn probe(path: &Path) -> Result<Self> {
+        let version = match version_output(path, &["version", "--format", "json"]).await {
+            Some(out) => parse_version_output(&out),
+            None => None,
+        };
+        let version = match version {
+            Some(version) => Some(version),
+            None => version_output(path, &["version"])
+                .await
+                .and_then(|out| parse_version_output(&out)),
+        };
+        let Some(version) = version else {
+            return Err(Error::Other(format!(
+                "could not run {} to determine its ORAS version",
+                path.display()
+            )));
+        };
+        let oras = Self {
+            path: path.to_path_buf(),
+            version,
+        };
+        oras.check()?;
+        Ok(oras)
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.version < MIN_VERSION || self.version.0 != MIN_VERSION.0 {
+            return Err(Error::Other(format!(
+                "ORAS {} at {} is not supported (meda needs {}.x, at least {}); \
+                 unset MEDA_ORAS_BIN or run `meda deps oras` to install a supported release",
+                self.version,
+                self.path.display(),
+                MIN_VERSION.0,
+                MIN_VERSION
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn command(&self) -> tokio::process::Command {
+        let mut cmd = tokio::process::Command::new(&self.path);
+        cmd.envs(crate::proxy::env());
+        cmd
+    }
+
+    pub fn has_resolve(&self) -> bool {
+        self.version >= RESOLVE_VERSION
+    }
+
+    /// Output flags for `push`/`pull`: per-file lines when `verbose`,
+    /// and no progress bars (meda reports progress itself).
+    pub fn output_args(&self, cmd: &mut tokio::process::Command, verbose: bool) {
+        if verbose && self.version < QUIET_VERBOSE_VERSION {
+            cmd.arg("--verbose");
+        }
+        if self.version >= RESOLVE_VERSION {
+            cmd.arg("--no-tty");
+        }
+    }
+}
+
+async fn version_output(path: &Path, args: &[&str]) -> Option<String> {
+    let output = tokio::process::Command::new(path)
+        .args(args)
+        .output()
+        .await
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// `oras version --format json` (`{"version": "1.3.0", …}`) or the
+/// plain `Version:  1.2.3` lines older releases print.
+fn parse_version_output(out: &str) -> Option<Version> {
+    if let Ok(value) = serde_json::from_str::<serde_json::Value>(out) {
+        return value["version"]
+            .as_str()
+            .or(value["Version"].as_str())
+            .and_then(Version::parse);
+    }
+    out.lines()
+        .find_map(|line| line.trim().strip_prefix("Version:"))
+        .and_then(Version::parse)
+}
+
+/// The ORAS to use: `MEDA_ORAS_BIN` if set, else the managed one
+/// (installed on first use).
+pub async fn ensure(config: &Config) -> Result<Oras> {
+    config.require_network("Registry access")?;
+    crate::vm::bootstrap_binaries_only(config).await?;
+    if let Some(path) = &config.oras_override {
+        return Oras::probe(path).await;
+    }
+    let oras = Oras::probe(&config.oras_bin).await?;
+    if oras.version < config.oras_version {
+        warn!(
+            "ORAS {} is older than MEDA_ORAS_VERSION {}; run `meda deps oras` to upgrade it",
+            oras.version, config.oras_version
+        );
+    }
+    Ok(oras)
+}
+
+/// Install ORAS `version` as the managed binary, replacing any other
+/// release only once the new one has been probed.
+pub async fn install(config: &Config, version: Version) -> Result<Oras> {
+    config.require_network(&format!("Installing ORAS {}", version))?;
+    let tar = config.asset_dir.join("oras.tar.gz");
+    if version == config.oras_version {
+        assets::fetch(config, Asset::Oras, &tar).await?;
+    } else {
+        crate::util::download_with_mirrors(&[release_url(version)], &tar).await?;
+    }
+    let staged = config.oras_bin.with_extension("new");
+    let extracted = extract_binary(&tar, &staged);
+    fs::remove_file(&tar).ok();
+    extracted?;
+
+    let probed = Oras::probe(&staged).await.and_then(|oras| {
+        if oras.version == version {
+            Ok(oras)
+        } else {
+            Err(Error::Other(format!(
+                "the ORAS {} release contains ORAS {}",
+                version, oras.version
+            )))
+        }
+    });
+    if let Err(e) = probed {
+        fs::remove_file(&staged).ok();
+        return Err(e);
+    }
+    fs::rename(&staged, &config.oras_bin)?;
+    Ok(Oras {
+        path: config.oras_bin.clone(),
+        version,
+    })
+}
+
+fn extract_binary(tar_path: &Path, dest_path: &Path) -> Result<()> {
+    let tar_file = fs::File::open(tar_path)?;
+    let tar = flate2::read::GzDecoder::new(tar_file);
+    let mut archive = tar::Archive::new(tar);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?;
+
+        // The release tarball has `oras` at its root, next to the license.
+        if path.file_name() == Some(std::ffi::OsStr::new("oras")) {
+            let mut buffer = Vec::new();
+            entry.read_to_end(&mut buffer)?;
+            fs::write(dest_path, buffer)?;
+
+            let mut perms = fs::metadata(dest_path)?.permissions();
+            perms.set_mode(0o755);
+            fs::set_permissions(dest_path, perms)?;
+            return Ok(());
+        }
+    }
+
+    Err(Error::Other(
+        "ORAS binary not found in tar archive".to_string(),
+    ))
+}
+
+#[derive(Debug, Serialize)]
+struct Dependency {
+    name: &'static str,
+    path: PathBuf,
+    version: Option<String>,
+    note: String,
+}
+
+async fn first_line(path: &Path, args: &[&str]) -> Option<String> {
+    let out = version_output(path, args).await?;
+    out.lines().next().map(|line| line.trim().to_string())
+}
+
+/// `meda deps`: the external binaries meda runs and their versions.
+pub async fn deps_command(config: &Config, json: bool) -> Result<()> {
+    let mut deps = Vec::new();
+    for (name, path) in [
+        ("cloud-hypervisor", &config.ch_bin),
+        ("ch-remote", &config.cr_bin),
+    ] {
+        deps.push(Dependency {
+            name,
+            path: path.clone(),
+            version: first_line(path, &["--version"]).await,
+            note: if path.exists() {
+                "managed".to_string()
+            } else {
+                "not installed (meda bootstrap)".to_string()
+            },
+        });
+    }
+
+    let (oras_path, source) = match &config.oras_override {
+        Some(path) => (path.clone(), "MEDA_ORAS_BIN"),
+        None => (config.oras_bin.clone(), "managed"),
+    };
+    deps.push(match Oras::probe(&oras_path).await {
+        Ok(oras) => Dependency {
+            name: "oras",
+            path: oras_path,
+            version: Some(oras.version.to_string()),
+            note: if config.oras_override.is_none() && oras.version != config.oras_version {
+                format!("{}; MEDA_ORAS_VERSION is {}", source, config.oras_version)
+            } else {
+                source.to_string()
+            },
+        },
+        Err(_) if config.oras_override.is_none() && !oras_path.exists() => Dependency {
+            name: "oras",
+            path: oras_path,
+            version: None,
+            note: "not installed (meda deps oras)".to_string(),
+        },
+        Err(e) => Dependency {
+            name: "oras",
+            path: oras_path,
+            version: None,
+            note: e.to_string(),
+        },
+    });
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&deps)?);
+        return Ok(());
+    }
+    println!("{:<18} {:<12} {:<40} NOTE", "NAME", "VERSION", "PATH");
+    for dep in &deps {
+        println!(
+            "{:<18} {:<12} {:<40} {}",
+            dep.name,
+            dep.version.as_deref().unwrap_or("-"),
+            dep.path.display(),
+            dep.note
+        );
+    }
+    Ok(())
+}
+
+/// `meda deps oras [--version]`: install or replace the managed ORAS.
+pub async fn install_command(config: &Config, version: Option<Version>, json: bool) -> Result<()> {
+    config.ensure_dirs()?;
+    let version = version.unwrap_or(config.oras_version);
+    if !json {
+        info!("Installing ORAS {}", version);
+    }
+    let oras = install(config, version).await?;
+    if config.oras_override.is_some() {
+        warn!("MEDA_ORAS_BIN is set, so meda keeps using that ORAS instead");
+    }
+
+    let message = format!("Installed ORAS {} at {}", oras.version, oras.path.display());
+    if json {
+        let result = serde_json::json!({ "success": true, "message": message });
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!("{}", message);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_parsing() {
+        assert_eq!(Version::parse("v1.2.3"), Some(Version(1, 2, 3)));
+        assert_eq!(Version::parse("1.3.0-beta.1"), Some(Version(1, 3, 0)));
+        assert_eq!(Version::parse("1.2"), None);
+        assert_eq!(Version::parse("1.2.3.4"), None);
+        assert!(Version(1, 10, 0) > Version(1, 9, 9));
+
+        assert_eq!(
+            parse_version_output(
+                "\nVersion:        1.2.3\nGo version:     go1.23.4\nGit commit:     abc\n"
+            ),
+            Some(Version(1, 2, 3))
+        );
+        assert_eq!(
+            parse_version_output(r#"{"version":"1.3.0","goVersion":"go1.24"}"#),
+            Some(Version(1, 3, 0))
+        );
+        assert_eq!(parse_version_output("oras: unknown command"), None);
+    }
+
+    #[test]
+    fn test_compatibility_and_args() {
+        let oras = |version| Oras {
+            path: PathBuf::from("/usr/bin/oras"),
+            version,
+        };
+        assert!(oras(Version(0, 16, 0)).check().is_err());
+        assert!(oras(Version(2, 0, 0)).check().is_err());
+        assert!(oras(DEFAULT_VERSION).check().is_ok());
+
+        let args = |version| {
+            let mut cmd = tokio::process::Command::new("oras");
+            oras(version).output_args(&mut cmd, true);
+            cmd.as_std()
+                .get_args()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(args(Version(1, 0, 1)), vec!["--verbose"]);
+        assert_eq!(args(DEFAULT_VERSION), vec!["--verbose", "--no-tty"]);
+        assert_eq!(args(Version(1, 3, 0)), vec!["--no-tty"]);
+        assert!(!oras(Version(1, 0, 1)).has_resolve());
+    }
+
+    #[tokio::test]
+    async fn test_probe_script() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let fake = dir.path().join("oras");
+        fs::write(
+            &fake,
+            "#!/bin/sh\n[ \"$2\" = --format ] && { echo 'unknown flag: --format' >&2; exit 1; }\necho 'Version: 0.16.0'\n",
+        )
+        .unwrap();
+        fs::set_permissions(&fake, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let err = Oras::probe(&fake).await.unwrap_err().to_string();
+        assert!(err.contains("ORAS 0.16.0"), "{}", err);
+        assert!(err.contains("meda deps oras"), "{}", err);
+    }
+}
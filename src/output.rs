@@ -0,0 +1,358 @@
+//! `-o` output selectors for read commands (`get`, `list`, `images`).
+//!
+//! Mirrors the kubectl forms CI scripts already know, so a single field
+//! can be pulled out without piping through jq:
+//!
+//! - `-o json` — same as `--json`
+//! - `-o jsonpath='{.ip}'` — kubectl-style JSONPath subset: `.field`,
+//!   `[N]`, `[*]`, `{"literal"}` and `{range ...}...{end}`
+//! - `-o template='{{.name}} {{.ip}}'` — placeholder substitution; for
+//!   list output the template is applied once per item, one per line
+
+use crate::error::{Error, Result};
+use serde_json::Value;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    Json,
+    JsonPath(String),
+    Template(String),
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "json" {
+            Ok(Self::Json)
+        } else if let Some(expr) = s.strip_prefix("jsonpath=") {
+            Ok(Self::JsonPath(expr.to_string()))
+        } else if let Some(tpl) = s.strip_prefix("template=") {
+            Ok(Self::Template(tpl.to_string()))
+        } else {
+            Err(Error::Other(format!(
+                "Unknown output format '{}' (expected json, jsonpath=... or template=...)",
+                s
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Field(String),
+    Index(i64),
+    Wildcard,
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Path(Vec<Segment>),
+    Range(Vec<Segment>, Vec<Node>),
+}
+
+fn parse_path(expr: &str) -> Result<Vec<Segment>> {
+    let bad = || Error::Other(format!("Invalid jsonpath expression '{}'", expr));
+    let expr = expr.trim();
+    let expr = expr.strip_prefix('$').unwrap_or(expr);
+    let mut segments = Vec::new();
+    let mut rest = expr;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(bad)?;
+            let inner = after[..end].trim();
+            segments.push(if inner == "*" {
+                Segment::Wildcard
+            } else {
+                Segment::Index(inner.parse().map_err(|_| bad())?)
+            });
+            rest = &after[end + 1..];
+        } else if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            let field = &after[..end];
+            if field == "*" {
+                segments.push(Segment::Wildcard);
+            } else if !field.is_empty() {
+                segments.push(Segment::Field(field.to_string()));
+            }
+            rest = &after[end..];
+        } else {
+            return Err(bad());
+        }
+    }
+    Ok(segments)
+}
+
+fn eval<'a>(path: &[Segment], value: &'a Value) -> Result<Vec<&'a Value>> {
+    let mut current = vec![value];
+    for seg in path {
+        let mut next = Vec::new();
+        for v in current {
+            match seg {
+                Segment::Field(name) => match v.get(name) {
+                    Some(child) => next.push(child),
+                    None => {
+                        return Err(Error::Other(format!("jsonpath: {} is not found", name)));
+                    }
+                },
+                Segment::Index(i) => {
+                    let arr = v
+                        .as_array()
+                        .ok_or_else(|| Error::Other("jsonpath: index on non-array".to_string()))?;
+                    let idx = if *i < 0 { arr.len() as i64 + i } else { *i };
+                    let child = usize::try_from(idx)
+                        .ok()
+                        .and_then(|idx| arr.get(idx))
+                        .ok_or_else(|| {
+                            Error::Other(format!("jsonpath: index {} out of range", i))
+                        })?;
+                    next.push(child);
+                }
+                Segment::Wildcard => match v {
+                    Value::Array(items) => next.extend(items.iter()),
+                    Value::Object(map) => next.extend(map.values()),
+                    _ => {}
+                },
+            }
+        }
+        current = next;
+    }
+    Ok(current)
+}
+
+/// Strings print raw (no quotes), null prints empty, everything else
+/// as compact JSON — the kubectl behavior scripts rely on.
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+fn unescape_literal(lit: &str) -> String {
+    let mut out = String::new();
+    let mut chars = lit.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+fn parse_jsonpath(template: &str) -> Result<Vec<Node>> {
+    // Stack of (range path, body) frames; the bottom frame is the root.
+    let mut stack: Vec<(Vec<Segment>, Vec<Node>)> = vec![(Vec::new(), Vec::new())];
+    let mut rest = template;
+    while !rest.is_empty() {
+        let Some(open) = rest.find('{') else {
+            stack
+                .last_mut()
+                .unwrap()
+                .1
+                .push(Node::Text(rest.to_string()));
+            break;
+        };
+        if open > 0 {
+            stack
+                .last_mut()
+                .unwrap()
+                .1
+                .push(Node::Text(rest[..open].to_string()));
+        }
+        let after = &rest[open + 1..];
+        // A quoted literal may itself contain braces; find the closing
+        // quote first in that case.
+        let close = if after.trim_start().starts_with('"') {
+            let q = after.find('"').unwrap();
+            let mut end = None;
+            let mut escaped = false;
+            for (i, c) in after[q + 1..].char_indices() {
+                match c {
+                    '\\' if !escaped => escaped = true,
+                    '"' if !escaped => {
+                        end = Some(q + 1 + i);
+                        break;
+                    }
+                    _ => escaped = false,
+                }
+            }
+            let end = end.ok_or_else(|| Error::Other("jsonpath: unterminated string".into()))?;
+            after[end..].find('}').map(|i| end + i)
+        } else {
+            after.find('}')
+        }
+        .ok_or_else(|| Error::Other(format!("jsonpath: unclosed '{{' in '{}'", template)))?;
+        let inner = after[..close].trim();
+        rest = &after[close + 1..];
+
+        if let Some(lit) = inner.strip_prefix('"').and_then(|l| l.strip_suffix('"')) {
+            stack
+                .last_mut()
+                .unwrap()
+                .1
+                .push(Node::Text(unescape_literal(lit)));
+        } else if let Some(path) = inner.strip_prefix("range ") {
+            stack.push((parse_path(path)?, Vec::new()));
+        } else if inner == "end" {
+            if stack.len() == 1 {
+                return Err(Error::Other("jsonpath: {end} without {range}".into()));
+            }
+            let (path, body) = stack.pop().unwrap();
+            stack.last_mut().unwrap().1.push(Node::Range(path, body));
+        } else {
+            stack
+                .last_mut()
+                .unwrap()
+                .1
+                .push(Node::Path(parse_path(inner)?));
+        }
+    }
+    if stack.len() != 1 {
+        return Err(Error::Other("jsonpath: {range} without {end}".into()));
+    }
+    Ok(stack.pop().unwrap().1)
+}
+
+fn render_nodes(nodes: &[Node], value: &Value, out: &mut String) -> Result<()> {
+    for node in nodes {
+        match node {
+            Node::Text(t) => out.push_str(t),
+            Node::Path(path) => {
+                let parts: Vec<String> = eval(path, value)?.into_iter().map(scalar).collect();
+                out.push_str(&parts.join(" "));
+            }
+            Node::Range(path, body) => {
+                for item in eval(path, value)? {
+                    render_nodes(body, item, out)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn render_template(template: &str, value: &Value) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find("{{") {
+        out.push_str(&rest[..open]);
+        let after = &rest[open + 2..];
+        let close = after
+            .find("}}")
+            .ok_or_else(|| Error::Other(format!("template: unclosed '{{{{' in '{}'", template)))?;
+        let parts: Vec<String> = eval(&parse_path(&after[..close])?, value)?
+            .into_iter()
+            .map(scalar)
+            .collect();
+        out.push_str(&parts.join(" "));
+        rest = &after[close + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Render `value` in the requested format. The result never has a
+/// trailing newline added; `print` takes care of that.
+pub fn render(value: &Value, format: &OutputFormat) -> Result<String> {
+    match format {
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(value)?),
+        OutputFormat::JsonPath(expr) => {
+            let mut out = String::new();
+            render_nodes(&parse_jsonpath(expr)?, value, &mut out)?;
+            Ok(out)
+        }
+        OutputFormat::Template(tpl) => match value {
+            Value::Array(items) => Ok(items
+                .iter()
+                .map(|item| render_template(tpl, item))
+                .collect::<Result<Vec<_>>>()?
+                .join("\n")),
+            other => render_template(tpl, other),
+        },
+    }
+}
+
+/// Serialize `data`, render it and print it to stdout.
+pub fn print<T: serde::Serialize>(data: &T, format: &OutputFormat) -> Result<()> {
+    let rendered = render(&serde_json::to_value(data)?, format)?;
+    if rendered.ends_with('\n') {
+        print!("{}", rendered);
+    } else {
+        println!("{}", rendered);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn jp(expr: &str, v: &Value) -> String {
+        render(v, &OutputFormat::JsonPath(expr.to_string())).unwrap()
+    }
+
+    #[test]
+    fn test_parse_output_format() {
+        assert_eq!("json".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert_eq!(
+            "jsonpath={.ip}".parse::<OutputFormat>().unwrap(),
+            OutputFormat::JsonPath("{.ip}".to_string())
+        );
+        assert_eq!(
+            "template={{.name}}".parse::<OutputFormat>().unwrap(),
+            OutputFormat::Template("{{.name}}".to_string())
+        );
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_jsonpath_fields() {
+        let v = json!({"name": "vm1", "ip": "10.99.0.2", "details": {"subnet": "192.168.40"}, "n": 3, "gone": null});
+        assert_eq!(jp("{.ip}", &v), "10.99.0.2");
+        assert_eq!(jp("{.details.subnet}", &v), "192.168.40");
+        assert_eq!(jp("ip={.ip} n={.n}", &v), "ip=10.99.0.2 n=3");
+        assert_eq!(jp("{.gone}", &v), "");
+        assert!(render(&v, &OutputFormat::JsonPath("{.nope}".into())).is_err());
+    }
+
+    #[test]
+    fn test_jsonpath_arrays_and_range() {
+        let v = json!([{"name": "a", "ip": "1"}, {"name": "b", "ip": "2"}]);
+        assert_eq!(jp("{[*].name}", &v), "a b");
+        assert_eq!(jp("{[1].ip}", &v), "2");
+        assert_eq!(jp("{[-1].name}", &v), "b");
+        assert_eq!(
+            jp(r#"{range [*]}{.name}{"\t"}{.ip}{"\n"}{end}"#, &v),
+            "a\t1\nb\t2\n"
+        );
+        assert!(render(&v, &OutputFormat::JsonPath("{range [*]}{.name}".into())).is_err());
+        assert!(render(&v, &OutputFormat::JsonPath("{[5].name}".into())).is_err());
+    }
+
+    #[test]
+    fn test_template() {
+        let list = json!([{"name": "a", "ip": "1"}, {"name": "b", "ip": "2"}]);
+        let tpl = OutputFormat::Template("{{.name}}={{.ip}}".to_string());
+        assert_eq!(render(&list, &tpl).unwrap(), "a=1\nb=2");
+        assert_eq!(
+            render(
+                &json!({"name": "x"}),
+                &OutputFormat::Template("vm {{ .name }}".into())
+            )
+            .unwrap(),
+            "vm x"
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! Image architectures, and the OCI image indexes (manifest lists) that
+//! let one reference serve several of them.
+//!
+//! meda only boots x86_64 guests today, but `meda push` records the
+//! architecture an image was built for (`org.cirunlabs.meda.arch`), and
+//! pull and run pick this host's entry when a reference names an index.
+//! So `ubuntu:22.04` can cover amd64 and arm64 hosts alike once arm64
+//! support lands, with no change for references to a single image.
+
+use crate::error::{Error, Result};
+use serde_json::Value;
+
+/// Manifest annotation carrying the architecture of a pushed image.
+pub const ARCH_ANNOTATION: &str = "org.cirunlabs.meda.arch";
+
+/// Media types of an image index, OCI or Docker's manifest list.
+pub const INDEX_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+/// OCI name of an architecture as Rust (`std::env::consts::ARCH`) or
+/// uname spell it.
+pub fn oci_arch(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        other => other,
+    }
+}
+
+/// This host's architecture, by its OCI name.
+pub fn host_arch() -> &'static str {
+    oci_arch(std::env::consts::ARCH)
+}
+
+/// Whether `manifest` is an index of per-platform manifests.
+pub fn is_index(manifest: &Value) -> bool {
+    manifest["mediaType"]
+        .as_str()
+        .is_some_and(|t| INDEX_MEDIA_TYPES.contains(&t))
+        || manifest["manifests"].is_array()
+}
+
+/// Digest of `arch`'s manifest in `index`, going by each entry's
+/// `platform.architecture`, or the meda annotation without one.
+pub fn select(index: &Value, arch: &str) -> Result<String> {
+    let entries = index["manifests"].as_array().cloned().unwrap_or_default();
+    let arch_of = |entry: &Value| {
+        entry["platform"]["architecture"]
+            .as_str()
+            .or_else(|| entry["annotations"][ARCH_ANNOTATION].as_str())
+            .map(|a| oci_arch(a).to_string())
+    };
+    entries
+        .iter()
+        .find(|entry| arch_of(entry).as_deref() == Some(arch))
+        .and_then(|entry| entry["digest"].as_str())
+        .map(String::from)
+        .ok_or_else(|| {
+            let available: Vec<String> = entries.iter().filter_map(arch_of).collect();
+            Error::Other(format!(
+                "No {} image in this manifest list (available: {})",
+                arch,
+                if available.is_empty() {
+                    "none".to_string()
+                } else {
+                    available.join(", ")
+                }
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_select() {
+        let index = json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [
+                {"digest": "sha256:aaa", "platform": {"os": "linux", "architecture": "amd64"}},
+                {"digest": "sha256:bbb", "annotations": {ARCH_ANNOTATION: "aarch64"}},
+            ],
+        });
+        assert!(is_index(&index));
+        assert_eq!(select(&index, "amd64").unwrap(), "sha256:aaa");
+        assert_eq!(select(&index, "arm64").unwrap(), "sha256:bbb");
+        let err = select(&index, "riscv64").unwrap_err().to_string();
+        assert!(err.contains("available: amd64, arm64"), "{}", err);
+
+        assert!(!is_index(&json!({"layers": []})));
+        assert_eq!(oci_arch("x86_64"), "amd64");
+    }
+}
@@ -0,0 +1,280 @@
+//! Host checks: can this machine run a VM at all, and this one in
+//! particular?
+//!
+//! `meda doctor` runs all of them. `create` and `start` run the few
+//! that matter for the VM at hand and fail with the fix in the error,
+//! instead of leaving the user to find an mmap or ioctl failure in
+//! ch.log: `/dev/kvm`, the binaries involved, the VM's RNG source,
+//! direct-boot kernel and pinned CPUs, free memory for the guest and free disk for its root disk. `MEDA_PREFLIGHT=off` skips
+//! them, e.g. on hosts that deliberately overcommit memory.
+
+use crate::config::Config;
+use crate::doctor::{Finding, Status};
+use crate::error::{Error, Result};
+use crate::memory_backing::MemoryBacking;
+use nix::unistd::{access, AccessFlags};
+use std::fs;
+use std::path::Path;
+
+const KVM_DEVICE: &str = "/dev/kvm";
+
+/// Below this much free space a VM's disk can't grow far enough to boot.
+pub const MIN_FREE_DISK: u64 = 1024 * 1024 * 1024;
+
+/// Host tools and the package that provides each.
+const CREATE_TOOLS: &[(&str, &str)] = &[("qemu-img", "qemu-utils")];
+const START_TOOLS: &[(&str, &str)] = &[("sudo", "sudo"), ("ip", "iproute2")];
+/// What a user-mode VM needs instead: no sudo, just passt.
+const USERNET_START_TOOLS: &[(&str, &str)] = &[("passt", "passt")];
+const ALL_TOOLS: &[(&str, &str)] = &[
+    ("sudo", "sudo"),
+    ("ip", "iproute2"),
+    ("iptables", "iptables"),
+    ("qemu-img", "qemu-utils"),
+];
+
+fn gib(bytes: u64) -> String {
+    format!("{:.1} GiB", bytes as f64 / (1024.0 * 1024.0 * 1024.0))
+}
+
+pub fn kvm() -> Finding {
+    let device = Path::new(KVM_DEVICE);
+    if !device.exists() {
+        return Finding::new(
+            "kvm",
+            Status::Fail,
+            "/dev/kvm is missing: enable virtualization in the firmware (or nested virtualization for a cloud VM) and load kvm_intel or kvm_amd",
+        );
+    }
+    if access(device, AccessFlags::R_OK | AccessFlags::W_OK).is_err() {
+        // CH runs under sudo, so this only bites when running it by hand.
+        return Finding::new(
+            "kvm",
+            Status::Warn,
+            "/dev/kvm is not read-write for this user (meda runs cloud-hypervisor with sudo, so VMs still start); `sudo usermod -aG kvm $USER` fixes it",
+        );
+    }
+    Finding::new("kvm", Status::Ok, "/dev/kvm is available")
+}
+
+fn tools(tools: &[(&str, &str)]) -> Vec<Finding> {
+    tools
+        .iter()
+        .map(|&(program, package)| {
+            if crate::util::check_dependency(program).is_ok() {
+                Finding::new("tools", Status::Ok, format!("{} found", program))
+            } else {
+                Finding::new(
+                    "tools",
+                    Status::Fail,
+                    format!(
+                        "{} not found; install the {} package (e.g. `sudo apt install {}`)",
+                        program, package, package
+                    ),
+                )
+            }
+        })
+        .collect()
+}
+
+/// cloud-hypervisor and the firmware, which bootstrap installs.
+fn hypervisor(config: &Config, missing: Status) -> Vec<Finding> {
+    [
+        ("cloud-hypervisor", &config.ch_bin),
+        ("firmware", &config.fw_bin),
+    ]
+    .into_iter()
+    .map(|(name, path)| {
+        if path.exists() {
+            Finding::new("binaries", Status::Ok, format!("{} installed", name))
+        } else {
+            Finding::new(
+                "binaries",
+                missing,
+                format!(
+                    "{} is not installed at {}; run `meda bootstrap`",
+                    name,
+                    path.display()
+                ),
+            )
+        }
+    })
+    .collect()
+}
+
+/// Guest RAM of `needed` bytes against what the host has available.
+fn memory(needed: u64, available: Option<u64>) -> Finding {
+    match available {
+        None => Finding::new(
+            "memory",
+            Status::Warn,
+            "could not read MemAvailable from /proc/meminfo",
+        ),
+        Some(available) if available < needed => Finding::new(
+            "memory",
+            Status::Fail,
+            format!(
+                "{} needed for guest memory but only {} available; stop other VMs or lower --memory",
+                gib(needed),
+                gib(available)
+            ),
+        ),
+        Some(available) => Finding::new(
+            "memory",
+            Status::Ok,
+            format!("{} available", gib(available)),
+        ),
+    }
+}
+
+/// `needed` bytes of free space on the filesystem holding `path`.
+fn disk(path: &Path, needed: u64) -> Finding {
+    let free = crate::storage::free_bytes(path);
+    if free < needed {
+        Finding::new(
+            "disk",
+            Status::Fail,
+            format!(
+                "{} free under {} but {} needed; free up space or use another storage pool",
+                gib(free),
+                path.display(),
+                gib(needed)
+            ),
+        )
+    } else {
+        Finding::new(
+            "disk",
+            Status::Ok,
+            format!("{} free under {}", gib(free), path.display()),
+        )
+    }
+}
+
+/// Everything `meda doctor` checks about the host itself.
+pub fn host_findings(config: &Config) -> Vec<Finding> {
+    let mut findings = vec![kvm()];
+    findings.extend(hypervisor(config, Status::Warn));
+    findings.extend(tools(ALL_TOOLS));
+    findings.push(config.rng_source.host_finding());
+    findings.push(crate::rng::entropy_finding());
+
+    let default_memory = crate::util::parse_size_bytes(&config.mem).unwrap_or(0);
+    let mut memory = memory(default_memory, crate::host_capacity::available_mem_bytes());
+    if memory.status == Status::Fail {
+        // No VM asked for anything yet; say so, but don't fail.
+        memory.status = Status::Warn;
+        memory.message = format!("not enough for a default VM: {}", memory.message);
+    }
+    findings.push(memory);
+
+    findings.push(disk(&config.vm_root, MIN_FREE_DISK));
+    for pool in &config.storage_pools {
+        findings.push(disk(&pool.root, MIN_FREE_DISK));
+    }
+    findings
+}
+
+fn enforce(config: &Config, findings: Vec<Finding>) -> Result<()> {
+    if !config.preflight {
+        return Ok(());
+    }
+    let failures: Vec<String> = findings
+        .into_iter()
+        .filter(|f| f.status == Status::Fail)
+        .map(|f| f.message)
+        .collect();
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Other(format!(
+            "preflight failed (MEDA_PREFLIGHT=off skips it):\n  {}",
+            failures.join("\n  ")
+        )))
+    }
+}
+
+/// Before provisioning a root disk that needs `disk_bytes` under `root`.
+pub fn before_create(config: &Config, root: &Path, disk_bytes: u64) -> Result<()> {
+    let mut findings = vec![kvm()];
+    findings.extend(tools(CREATE_TOOLS));
+    findings.push(disk(root, disk_bytes.max(MIN_FREE_DISK)));
+    enforce(config, findings)
+}
+
+/// Before launching the VM in `vm_dir` with `memory` of RAM.
+pub fn before_start(config: &Config, vm_dir: &Path, memory: &str) -> Result<()> {
+    let user_net = crate::usernet::is_enabled(vm_dir);
+    let mut kvm = kvm();
+    if user_net && kvm.status == Status::Warn {
+        // Without sudo, CH opens /dev/kvm as this user.
+        kvm = Finding::new(
+            "kvm",
+            Status::Fail,
+            "/dev/kvm is not read-write for this user, and user-mode VMs run cloud-hypervisor without sudo; `sudo usermod -aG kvm $USER` fixes it",
+        );
+    }
+    let mut findings = vec![kvm];
+    findings.extend(hypervisor(config, Status::Fail));
+    findings.extend(tools(if user_net {
+        USERNET_START_TOOLS
+    } else {
+        START_TOOLS
+    }));
+    // Hugepage-backed memory comes out of the reserved pool, which
+    // memory_backing::preflight checks.
+    if !MemoryBacking::load(vm_dir).hugepages {
+        if let Some(needed) = crate::util::parse_size_bytes(memory) {
+            findings.push(self::memory(
+                needed,
+                crate::host_capacity::available_mem_bytes(),
+            ));
+        }
+    }
+    findings.push(crate::rng::RngSource::load(vm_dir).host_finding());
+    if let Some(boot) = crate::boot::DirectBoot::load(vm_dir) {
+        findings.push(boot.host_finding());
+    }
+    if let Some(cpus) = crate::cpu_affinity::CpuSet::load(vm_dir) {
+        let vcpus = fs::read_to_string(vm_dir.join("cpus"))
+            .ok()
+            .and_then(|c| c.trim().parse().ok());
+        findings.push(cpus.host_finding(vcpus));
+    }
+    let root = fs::canonicalize(vm_dir).unwrap_or_else(|_| vm_dir.to_path_buf());
+    findings.push(disk(&root, MIN_FREE_DISK));
+    enforce(config, findings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    #[test]
+    fn test_memory() {
+        assert_eq!(memory(2 * GIB, Some(4 * GIB)).status, Status::Ok);
+        let short = memory(8 * GIB, Some(3 * GIB));
+        assert_eq!(short.status, Status::Fail);
+        assert!(short.message.contains("8.0 GiB needed"));
+        assert!(short.message.contains("3.0 GiB available"));
+        assert_eq!(memory(GIB, None).status, Status::Warn);
+    }
+
+    #[test]
+    fn test_disk_and_enforce() {
+        let dir = tempfile::TempDir::new().unwrap();
+        assert_eq!(disk(dir.path(), 1).status, Status::Ok);
+        let full = disk(dir.path(), u64::MAX);
+        assert_eq!(full.status, Status::Fail);
+
+        let mut config = Config::new().unwrap();
+        config.preflight = true;
+        let err = enforce(&config, vec![full]).unwrap_err().to_string();
+        assert!(err.contains("MEDA_PREFLIGHT=off"));
+        assert!(err.contains("needed; free up space"));
+
+        config.preflight = false;
+        assert!(enforce(&config, vec![disk(dir.path(), u64::MAX)]).is_ok());
+    }
+}
@@ -0,0 +1,228 @@
+//! `--progress json`: machine-readable progress for long operations.
+//!
+//! Bootstrap downloads, disk conversions, chunking, ORAS transfers and VM boot waits
+//! report through [`Progress`]. In the default `human` mode that is a
+//! no-op and the usual emoji lines and progress bars are shown; with
+//! `--progress json` every update is one JSON object per line on
+//! stderr, e.g.
+//!
+//! ```text
+//! {"phase":"download","artifact":"cloud-hypervisor","bytes_done":1048576,"bytes_total":4194304,"done":false}
+//! ```
+//!
+//! `bytes_done`/`bytes_total` are `null` where a phase has no byte
+//! count (boot waits, ORAS pulls of unknown size). Events go to stderr
+//! so stdout keeps carrying `--json` results.
+//!
+//! An API job (`?async=true`) also keeps the latest event of the request
+//! it runs, whatever the mode, through [`record`]. A [`Progress`] holds
+//! on to the job it was started under, so the blocking threads that
+//! chunk, convert and upload report to it too; tasks spawned along the
+//! way are wrapped in [`inherit`].
+
+use serde::Serialize;
+use std::future::Future;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Minimum gap between two in-flight events of the same operation.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ProgressMode {
+    #[default]
+    Human,
+    Json,
+}
+
+static MODE: OnceLock<ProgressMode> = OnceLock::new();
+
+/// The latest event of an operation, as JSON.
+pub type Latest = Arc<Mutex<Option<serde_json::Value>>>;
+
+tokio::task_local! {
+    static LATEST: Latest;
+}
+
+/// Run `operation`, keeping its latest event in `latest`.
+pub async fn record<F: Future>(latest: Latest, operation: F) -> F::Output {
+    LATEST.scope(latest, operation).await
+}
+
+/// Where the current task's events are kept, if it runs under [`record`].
+fn current() -> Option<Latest> {
+    LATEST.try_with(Latest::clone).ok()
+}
+
+/// `operation` reporting to the caller's job, for a task about to be
+/// spawned.
+pub fn inherit<F: Future>(operation: F) -> impl Future<Output = F::Output> {
+    let latest = current();
+    async move {
+        match latest {
+            Some(latest) => record(latest, operation).await,
+            None => operation.await,
+        }
+    }
+}
+
+/// Select the mode for this process; only the first call counts.
+pub fn set_mode(mode: ProgressMode) {
+    let _ = MODE.set(mode);
+}
+
+/// True when events replace the human-oriented progress bars.
+pub fn json_mode() -> bool {
+    MODE.get() == Some(&ProgressMode::Json)
+}
+
+#[derive(Debug, Serialize)]
+struct ProgressEvent<'a> {
+    phase: &'a str,
+    artifact: &'a str,
+    bytes_done: Option<u64>,
+    bytes_total: Option<u64>,
+    done: bool,
+}
+
+/// One operation being reported, e.g. a single file download.
+pub struct Progress {
+    phase: &'static str,
+    artifact: String,
+    bytes_total: Option<u64>,
+    tracks_bytes: bool,
+    last: Instant,
+    /// The job it was started under, kept for updates from other threads.
+    latest: Option<Latest>,
+}
+
+impl Progress {
+    /// An operation that moves `bytes_total` bytes (`None` if unknown).
+    pub fn bytes(phase: &'static str, artifact: &str, bytes_total: Option<u64>) -> Self {
+        Self::begin(phase, artifact, bytes_total, true)
+    }
+
+    /// An operation without a byte count, such as waiting for a VM.
+    pub fn step(phase: &'static str, artifact: &str) -> Self {
+        Self::begin(phase, artifact, None, false)
+    }
+
+    fn begin(
+        phase: &'static str,
+        artifact: &str,
+        bytes_total: Option<u64>,
+        tracks_bytes: bool,
+    ) -> Self {
+        let progress = Self {
+            phase,
+            artifact: artifact.to_string(),
+            bytes_total,
+            tracks_bytes,
+            last: Instant::now(),
+            latest: current(),
+        };
+        progress.emit(tracks_bytes.then_some(0), false);
+        progress
+    }
+
+    /// Report `bytes_done` so far; rate-limited to one event per 250ms.
+    pub fn update(&mut self, bytes_done: u64) {
+        if self.last.elapsed() >= UPDATE_INTERVAL {
+            self.last = Instant::now();
+            self.emit(Some(bytes_done), false);
+        }
+    }
+
+    /// Report completion, with the final byte count when known.
+    pub fn finish(self, bytes_done: Option<u64>) {
+        let bytes_done = bytes_done.or(self.bytes_total.filter(|_| self.tracks_bytes));
+        self.emit(bytes_done, true);
+    }
+
+    fn emit(&self, bytes_done: Option<u64>, done: bool) {
+        if json_mode() {
+            eprintln!("{}", self.event_line(bytes_done, done));
+        }
+        if let Some(mut latest) = self.latest.as_ref().and_then(|l| l.lock().ok()) {
+            *latest = serde_json::to_value(self.event(bytes_done, done)).ok();
+        }
+    }
+
+    fn event(&self, bytes_done: Option<u64>, done: bool) -> ProgressEvent<'_> {
+        ProgressEvent {
+            phase: self.phase,
+            artifact: &self.artifact,
+            bytes_done,
+            bytes_total: self.bytes_total,
+            done,
+        }
+    }
+
+    fn event_line(&self, bytes_done: Option<u64>, done: bool) -> String {
+        serde_json::to_string(&self.event(bytes_done, done)).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_line() {
+        let p = Progress::bytes("download", "ch", Some(10));
+        let v: serde_json::Value = serde_json::from_str(&p.event_line(Some(4), false)).unwrap();
+        assert_eq!(v["phase"], "download");
+        assert_eq!(v["artifact"], "ch");
+        assert_eq!(v["bytes_done"], 4);
+        assert_eq!(v["bytes_total"], 10);
+        assert_eq!(v["done"], false);
+
+        let p = Progress::step("boot", "vm1");
+        let v: serde_json::Value = serde_json::from_str(&p.event_line(None, true)).unwrap();
+        assert!(v["bytes_done"].is_null());
+        assert!(v["bytes_total"].is_null());
+        assert_eq!(v["done"], true);
+    }
+
+    #[test]
+    fn test_update_is_rate_limited() {
+        let mut p = Progress::bytes("chunk", "rootfs.raw", Some(100));
+        let before = p.last;
+        p.update(1);
+        assert_eq!(p.last, before);
+        p.last -= UPDATE_INTERVAL;
+        p.update(2);
+        assert!(p.last >= before);
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let latest = Latest::default();
+        record(latest.clone(), async {
+            Progress::bytes("pull", "ubuntu", Some(8)).finish(None);
+        })
+        .await;
+        let event = latest.lock().unwrap().clone().unwrap();
+        assert_eq!(event["phase"], "pull");
+        assert_eq!(event["bytes_done"], 8);
+        assert_eq!(event["done"], true);
+
+        // Updates from other threads and spawned tasks count too.
+        let latest = Latest::default();
+        record(latest.clone(), async {
+            let mut chunking = Progress::bytes("chunk", "rootfs.raw", Some(8));
+            chunking.last -= UPDATE_INTERVAL;
+            std::thread::scope(|scope| {
+                scope.spawn(|| chunking.update(4));
+            });
+            assert_eq!(latest.lock().unwrap().clone().unwrap()["bytes_done"], 4);
+            tokio::spawn(inherit(async {
+                Progress::step("boot", "vm1").finish(None);
+            }))
+            .await
+            .unwrap();
+        })
+        .await;
+        assert_eq!(latest.lock().unwrap().clone().unwrap()["phase"], "boot");
+    }
+}
@@ -0,0 +1,331 @@
+//! Provenance annotations and signatures for pushed images.
+//!
+//! `meda push --provenance` adds OCI annotations saying where an image
+//! came from: when it was built, the VM it was captured from, the base
+//! image (and its digest) that VM ran, and the commit and repository of
+//! the build when CI exposes them (`MEDA_GIT_SHA`, or `GITHUB_SHA`,
+//! `CI_COMMIT_SHA`, `GIT_COMMIT`; `MEDA_SOURCE_URL`, or the GitHub and
+//! GitLab equivalents).
+//!
+//! `meda push --sign` signs the pushed manifest digest the way cosign
+//! does: a "simple signing" payload naming the repository and digest is
+//! signed with `cosign sign-blob` (key from `MEDA_SIGNING_KEY`, keyless
+//! otherwise) and attached to the image with `oras attach` as a cosign
+//! signature artifact. `cosign verify --experimental-oci11` checks it.
+//!
+//! Keyless signing needs an OIDC token cosign can pick up without a
+//! browser (`SIGSTORE_ID_TOKEN`, or GitHub Actions' ID token), since it
+//! may run inside `meda serve`. Its Fulcio certificate and Rekor bundle
+//! are attached beside the signature, as cosign itself does.
+
+use crate::error::{Error, Result};
+use crate::image::ImageManifest;
+use chrono::{TimeZone, Utc};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Artifact type of the signature manifest cosign looks for among an
+/// image's referrers.
+pub const SIGNATURE_ARTIFACT_TYPE: &str = "application/vnd.dev.cosign.artifact.sig.v1+json";
+
+/// Media type of the signed payload layer.
+pub const PAYLOAD_MEDIA_TYPE: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+
+/// Layer annotation carrying the base64 signature of the payload.
+pub const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+
+/// Layer annotation carrying a keyless signature's Fulcio certificate.
+pub const CERTIFICATE_ANNOTATION: &str = "dev.sigstore.cosign/certificate";
+
+/// Layer annotation carrying a keyless signature's Rekor bundle.
+pub const BUNDLE_ANNOTATION: &str = "dev.sigstore.cosign/bundle";
+
+/// Variables cosign can take a keyless OIDC token from without a browser.
+const KEYLESS_TOKEN_VARS: &[&str] = &["SIGSTORE_ID_TOKEN", "ACTIONS_ID_TOKEN_REQUEST_URL"];
+
+/// Provenance of `manifest` as OCI annotations, read from the image
+/// and from CI variables through `get`.
+pub fn annotations(
+    manifest: &ImageManifest,
+    get: impl Fn(&str) -> Option<String>,
+) -> BTreeMap<String, String> {
+    let first = |vars: &[&str]| {
+        vars.iter()
+            .filter_map(|var| get(var))
+            .map(|v| v.trim().to_string())
+            .find(|v| !v.is_empty())
+    };
+    let mut annotations = BTreeMap::new();
+    if let Some(created) = Utc.timestamp_opt(manifest.created as i64, 0).single() {
+        annotations.insert(
+            "org.opencontainers.image.created".to_string(),
+            created.to_rfc3339(),
+        );
+    }
+    if let Some(vm) = manifest.metadata.get("source_vm") {
+        annotations.insert("org.cirunlabs.meda.source-vm".to_string(), vm.clone());
+    }
+    if let Some(parent) = manifest.history.first() {
+        annotations.insert(
+            "org.opencontainers.image.base.name".to_string(),
+            parent.image.clone(),
+        );
+        if let Some(digest) = &parent.digest {
+            annotations.insert(
+                "org.opencontainers.image.base.digest".to_string(),
+                digest.clone(),
+            );
+        }
+    }
+    if let Some(sha) = first(&["MEDA_GIT_SHA", "GITHUB_SHA", "CI_COMMIT_SHA", "GIT_COMMIT"]) {
+        annotations.insert("org.opencontainers.image.revision".to_string(), sha);
+    }
+    let github = match (get("GITHUB_SERVER_URL"), get("GITHUB_REPOSITORY")) {
+        (Some(server), Some(repo)) => Some(format!("{}/{}", server.trim_end_matches('/'), repo)),
+        _ => None,
+    };
+    if let Some(source) = first(&["MEDA_SOURCE_URL"])
+        .or(github)
+        .or_else(|| first(&["CI_PROJECT_URL"]))
+    {
+        annotations.insert("org.opencontainers.image.source".to_string(), source);
+    }
+    annotations
+}
+
+/// The cosign "simple signing" payload for `digest` in `repository`
+/// (registry/org/name, without a tag).
+pub fn signing_payload(
+    repository: &str,
+    digest: &str,
+    optional: &BTreeMap<String, String>,
+) -> serde_json::Value {
+    let mut payload = serde_json::json!({
+        "critical": {
+            "identity": { "docker-reference": repository },
+            "image": { "docker-manifest-digest": digest },
+            "type": "cosign container image signature",
+        },
+        "optional": null,
+    });
+    if !optional.is_empty() {
+        payload["optional"] = serde_json::json!(optional);
+    }
+    payload
+}
+
+/// A cosign signature, with the certificate and Rekor bundle of a
+/// keyless one.
+#[derive(Debug, Default, PartialEq)]
+pub struct Signature {
+    pub signature: String,
+    pub certificate: Option<String>,
+    pub bundle: Option<String>,
+}
+
+impl Signature {
+    /// The payload layer's annotations cosign verifies against.
+    pub fn annotations(&self) -> serde_json::Value {
+        let mut annotations = serde_json::Map::new();
+        annotations.insert(SIGNATURE_ANNOTATION.into(), self.signature.clone().into());
+        if let Some(certificate) = &self.certificate {
+            annotations.insert(CERTIFICATE_ANNOTATION.into(), certificate.clone().into());
+        }
+        if let Some(bundle) = &self.bundle {
+            annotations.insert(BUNDLE_ANNOTATION.into(), bundle.clone().into());
+        }
+        serde_json::Value::Object(annotations)
+    }
+}
+
+/// The signing key from `MEDA_SIGNING_KEY`, or `None` for keyless
+/// signing when a token for it is at hand.
+fn signing_key(get: &impl Fn(&str) -> Option<String>) -> Result<Option<String>> {
+    if let Some(key) = get("MEDA_SIGNING_KEY").filter(|k| !k.trim().is_empty()) {
+        return Ok(Some(key.trim().to_string()));
+    }
+    if KEYLESS_TOKEN_VARS.iter().any(|var| get(var).is_some()) {
+        return Ok(None);
+    }
+    Err(Error::Other(format!(
+        "Signing needs MEDA_SIGNING_KEY, or an OIDC token in {} for keyless signing",
+        KEYLESS_TOKEN_VARS.join(" or ")
+    )))
+}
+
+/// The Rekor bundle in a `cosign sign-blob --bundle` file, as cosign
+/// annotates it.
+fn rekor_bundle(bundle: &str) -> Option<String> {
+    let bundle: serde_json::Value = serde_json::from_str(bundle).ok()?;
+    let rekor = bundle.get("rekorBundle").filter(|b| !b.is_null())?;
+    serde_json::to_string(rekor).ok()
+}
+
+/// Sign `payload_path` with cosign.
+pub async fn sign_blob(
+    payload_path: &Path,
+    get: impl Fn(&str) -> Option<String>,
+) -> Result<Signature> {
+    let key = signing_key(&get)?;
+    crate::util::check_dependency("cosign")?;
+    let signature_path = payload_path.with_extension("sig");
+    let certificate_path = payload_path.with_extension("pem");
+    let bundle_path = payload_path.with_extension("bundle");
+    let mut cmd = tokio::process::Command::new("cosign");
+    cmd.args(["sign-blob", "--yes", "--output-signature"])
+        .arg(&signature_path);
+    match &key {
+        Some(key) => {
+            cmd.args(["--key", key]);
+        }
+        None => {
+            cmd.arg("--output-certificate")
+                .arg(&certificate_path)
+                .arg("--bundle")
+                .arg(&bundle_path);
+        }
+    }
+    cmd.arg(payload_path);
+    let output = cmd.output().await?;
+    if !output.status.success() {
+        return Err(Error::Other(format!(
+            "cosign sign-blob failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let mut signature = Signature {
+        signature: std::fs::read_to_string(&signature_path)?.trim().to_string(),
+        ..Default::default()
+    };
+    if key.is_none() {
+        signature.certificate = Some(std::fs::read_to_string(&certificate_path)?);
+        signature.bundle = rekor_bundle(&std::fs::read_to_string(&bundle_path)?);
+        if signature.bundle.is_none() {
+            return Err(Error::Other(
+                "cosign sign-blob wrote no Rekor bundle for the keyless signature".to_string(),
+            ));
+        }
+    }
+    Ok(signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::ImageAncestor;
+    use std::collections::HashMap;
+
+    fn manifest() -> ImageManifest {
+        ImageManifest {
+            schema_version: crate::schema::IMAGE_SCHEMA_VERSION,
+            name: "runner".to_string(),
+            tag: "v1".to_string(),
+            registry: "ghcr.io".to_string(),
+            org: "acme".to_string(),
+            artifacts: HashMap::new(),
+            metadata: HashMap::from([("source_vm".to_string(), "builder".to_string())]),
+            created: 1_700_000_000,
+            history: vec![ImageAncestor {
+                image: "ghcr.io/cirunlabs/ubuntu:22.04".to_string(),
+                digest: Some("sha256:abc".to_string()),
+                created: None,
+                source_vm: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_annotations() {
+        let env = HashMap::from([
+            ("GITHUB_SHA", "deadbeef"),
+            ("GITHUB_SERVER_URL", "https://github.com/"),
+            ("GITHUB_REPOSITORY", "acme/images"),
+        ]);
+        let found = annotations(&manifest(), |var| env.get(var).map(|v| v.to_string()));
+        assert_eq!(
+            found["org.opencontainers.image.created"],
+            "2023-11-14T22:13:20+00:00"
+        );
+        assert_eq!(found["org.cirunlabs.meda.source-vm"], "builder");
+        assert_eq!(
+            found["org.opencontainers.image.base.name"],
+            "ghcr.io/cirunlabs/ubuntu:22.04"
+        );
+        assert_eq!(found["org.opencontainers.image.base.digest"], "sha256:abc");
+        assert_eq!(found["org.opencontainers.image.revision"], "deadbeef");
+        assert_eq!(
+            found["org.opencontainers.image.source"],
+            "https://github.com/acme/images"
+        );
+
+        // MEDA_* wins over what CI sets.
+        let env = HashMap::from([("MEDA_GIT_SHA", "cafe"), ("GITHUB_SHA", "deadbeef")]);
+        let found = annotations(&manifest(), |var| env.get(var).map(|v| v.to_string()));
+        assert_eq!(found["org.opencontainers.image.revision"], "cafe");
+        assert!(!found.contains_key("org.opencontainers.image.source"));
+    }
+
+    #[test]
+    fn test_signing_mode() {
+        let env = HashMap::from([
+            ("MEDA_SIGNING_KEY", " cosign.key "),
+            ("SIGSTORE_ID_TOKEN", "t"),
+        ]);
+        let get = |var: &str| env.get(var).map(|v| v.to_string());
+        assert_eq!(signing_key(&get).unwrap(), Some("cosign.key".to_string()));
+
+        let env = HashMap::from([("ACTIONS_ID_TOKEN_REQUEST_URL", "https://token")]);
+        let get = |var: &str| env.get(var).map(|v| v.to_string());
+        assert_eq!(signing_key(&get).unwrap(), None);
+
+        // No key and no token would start a browser login.
+        let err = signing_key(&|_: &str| None).unwrap_err();
+        assert!(err.to_string().contains("MEDA_SIGNING_KEY"));
+    }
+
+    #[test]
+    fn test_signature_annotations() {
+        let keyed = Signature {
+            signature: "c2ln".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            keyed.annotations(),
+            serde_json::json!({ SIGNATURE_ANNOTATION: "c2ln" })
+        );
+
+        let bundle = r#"{"base64Signature":"c2ln","cert":"Y2VydA==","rekorBundle":{"SignedEntryTimestamp":"c2V0","Payload":{"logIndex":7}}}"#;
+        let keyless = Signature {
+            signature: "c2ln".to_string(),
+            certificate: Some("-----BEGIN CERTIFICATE-----".to_string()),
+            bundle: rekor_bundle(bundle),
+        };
+        let annotations = keyless.annotations();
+        assert_eq!(
+            annotations[CERTIFICATE_ANNOTATION],
+            "-----BEGIN CERTIFICATE-----"
+        );
+        let rekor: serde_json::Value =
+            serde_json::from_str(annotations[BUNDLE_ANNOTATION].as_str().unwrap()).unwrap();
+        assert_eq!(rekor["Payload"]["logIndex"], 7);
+        assert_eq!(rekor_bundle(r#"{"base64Signature":"c2ln"}"#), None);
+    }
+
+    #[test]
+    fn test_signing_payload() {
+        let payload = signing_payload("ghcr.io/acme/runner", "sha256:abc", &BTreeMap::new());
+        assert_eq!(
+            payload["critical"]["image"]["docker-manifest-digest"],
+            "sha256:abc"
+        );
+        assert_eq!(
+            payload["critical"]["identity"]["docker-reference"],
+            "ghcr.io/acme/runner"
+        );
+        assert!(payload["optional"].is_null());
+
+        let optional = BTreeMap::from([("a".to_string(), "b".to_string())]);
+        let payload = signing_payload("ghcr.io/acme/runner", "sha256:abc", &optional);
+        assert_eq!(payload["optional"]["a"], "b");
+    }
+}
@@ -0,0 +1,166 @@
+//! The proxy meda's HTTP traffic goes through.
+//!
+//! Bootstrap downloads, registry logins, image pulls and inventory posts
+//! are all sent with [`client`], and ORAS gets the same settings in its
+//! environment (see [`env`]). Without any meda setting that is the usual
+//! `HTTPS_PROXY`/`HTTP_PROXY`/`ALL_PROXY`/`NO_PROXY` handling. `MEDA_PROXY`,
+//! or `proxy` in the settings file, sends everything through one proxy
+//! instead, `http://`, `https://`, `socks5://` or `socks5h://`, still
+//! skipping the hosts in `NO_PROXY` (or `no_proxy` in the settings file).
+
+use crate::error::{Error, Result};
+use std::sync::OnceLock;
+
+/// Proxy URL schemes reqwest and ORAS both speak.
+const SCHEMES: &[&str] = &["http", "https", "socks5", "socks5h"];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxySettings {
+    /// Proxy for all traffic; `None` leaves it to the environment.
+    pub url: Option<String>,
+    /// Hosts, domains and CIDRs reached directly, comma-separated.
+    pub no_proxy: Option<String>,
+}
+
+impl ProxySettings {
+    /// Settings from `MEDA_PROXY` (else `file_url`) and `NO_PROXY` (else
+    /// `file_no_proxy`). A URL of another scheme is warned about and
+    /// ignored, like other invalid settings; the proxy's host is only
+    /// looked up once a client is built.
+    pub fn resolve(
+        env: impl Fn(&str) -> Option<String>,
+        file_url: Option<String>,
+        file_no_proxy: Option<String>,
+    ) -> Self {
+        let non_empty =
+            |v: Option<String>| v.map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+        let url = non_empty(env("MEDA_PROXY")).or_else(|| non_empty(file_url));
+        let url = url.filter(|url| match reqwest::Url::parse(url) {
+            Ok(parsed) if SCHEMES.contains(&parsed.scheme()) => true,
+            _ => {
+                log::warn!("Ignoring proxy {}: not an {} URL", url, SCHEMES.join(", "));
+                false
+            }
+        });
+        let no_proxy = non_empty(env("NO_PROXY"))
+            .or_else(|| non_empty(env("no_proxy")))
+            .or_else(|| non_empty(file_no_proxy));
+        Self { url, no_proxy }
+    }
+
+    fn client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(url) = &self.url {
+            let proxy = reqwest::Proxy::all(url.as_str())
+                .map_err(|e| Error::Other(format!("Proxy {}: {}", url, e)))?;
+            builder = builder.proxy(
+                proxy.no_proxy(
+                    self.no_proxy
+                        .as_deref()
+                        .and_then(reqwest::NoProxy::from_string),
+                ),
+            );
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Variables that point a child process at the proxy.
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let Some(url) = &self.url else {
+            return Vec::new();
+        };
+        let mut vars = vec![
+            ("HTTPS_PROXY", url.clone()),
+            ("HTTP_PROXY", url.clone()),
+            ("ALL_PROXY", url.clone()),
+        ];
+        if let Some(no_proxy) = &self.no_proxy {
+            vars.push(("NO_PROXY", no_proxy.clone()));
+        }
+        vars
+    }
+}
+
+static SETTINGS: OnceLock<ProxySettings> = OnceLock::new();
+
+/// Use `settings` for this process; only the first call counts.
+pub fn set(settings: ProxySettings) {
+    let _ = SETTINGS.set(settings);
+}
+
+fn settings() -> &'static ProxySettings {
+    SETTINGS.get_or_init(ProxySettings::default)
+}
+
+/// An HTTP client going through the configured proxy. Fails when the
+/// proxy's host can't be resolved (SOCKS proxies are resolved up front)
+/// rather than connecting without it.
+pub fn client() -> Result<reqwest::Client> {
+    settings().client()
+}
+
+/// Environment for child processes that make their own HTTP requests.
+pub fn env() -> Vec<(&'static str, String)> {
+    settings().env()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_resolve() {
+        let vars = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let resolve = |env: HashMap<String, String>, url: Option<&str>, no_proxy: Option<&str>| {
+            ProxySettings::resolve(
+                |var| env.get(var).cloned(),
+                url.map(String::from),
+                no_proxy.map(String::from),
+            )
+        };
+
+        assert_eq!(resolve(vars(&[]), None, None), ProxySettings::default());
+        assert!(resolve(vars(&[]), None, None).env().is_empty());
+
+        let settings = resolve(
+            vars(&[
+                ("MEDA_PROXY", "socks5h://proxy.invalid:1080"),
+                ("NO_PROXY", "localhost"),
+            ]),
+            Some("http://file-proxy:3128"),
+            Some("10.0.0.0/8"),
+        );
+        assert_eq!(
+            settings.url.as_deref(),
+            Some("socks5h://proxy.invalid:1080")
+        );
+        assert_eq!(settings.no_proxy.as_deref(), Some("localhost"));
+        assert!(settings
+            .env()
+            .contains(&("HTTPS_PROXY", "socks5h://proxy.invalid:1080".to_string())));
+        // `.invalid` never resolves (RFC 6761).
+        assert!(settings.client().is_err());
+
+        let from_file = resolve(
+            vars(&[]),
+            Some("http://file-proxy:3128"),
+            Some("10.0.0.0/8"),
+        );
+        assert_eq!(from_file.url.as_deref(), Some("http://file-proxy:3128"));
+        assert_eq!(from_file.no_proxy.as_deref(), Some("10.0.0.0/8"));
+        assert!(from_file.client().is_ok());
+
+        // Not a proxy URL: ignored.
+        assert_eq!(resolve(vars(&[("MEDA_PROXY", "::")]), None, None).url, None);
+        assert_eq!(
+            resolve(vars(&[("MEDA_PROXY", "ftp://proxy")]), None, None).url,
+            None
+        );
+    }
+}
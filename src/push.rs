@@ -0,0 +1,776 @@
+//! Streaming `meda push`.
+//!
+//! Artifacts go from the image dir straight to the registry, through the
+//! in-process OCI client: each blob is read (and, with
+//! `MEDA_IMAGE_COMPRESSION=zstd`, compressed) in [`BLOCK`]-sized pieces
+//! that are hashed and sent as they are produced, at most [`BUFFERS`] of
+//! them queued per upload. Nothing is staged on disk, so pushing a 20GB
+//! disk needs no free space and a few MB of memory per concurrent upload.
+//! Each blob is hashed before it goes up, so one the registry already
+//! has (an unchanged chunk of a re-pushed disk) is skipped, and a failed
+//! upload is produced again and retried up to [`UPLOAD_ATTEMPTS`] times.
+//!
+//! The registry gets what the ORAS-based push produced, so pulls are
+//! unchanged: files of `min_chunk_threshold` or more go up as
+//! `<file>.chunk.NNN` blobs plus a `<file>.chunk.index` with their
+//! digests (see `chunking`). Chunks are independent ranges of the file,
+//! compressed into a zstd frame each, and upload
+//! `MEDA_ORAS_PUSH_CONCURRENCY` at a time.
+
+use crate::chunking::{self, ChunkMetadata, ChunkingConfig, FileChunker};
+use crate::compression::{Compression, ZSTD_EXTENSION};
+use crate::config::Config;
+use crate::credentials::Credentials;
+use crate::error::{Error, Result};
+use crate::image::{ImageManifest, ImageRef};
+use crate::oci::{OciRef, Registry};
+use crate::progress::Progress;
+use futures_util::{StreamExt, TryStreamExt};
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Size of the pieces blobs are read, hashed and sent in.
+const BLOCK: usize = 1024 * 1024;
+
+/// Pieces an upload may have queued ahead of the registry.
+const BUFFERS: usize = 4;
+
+/// Tries at each blob upload before the push fails.
+const UPLOAD_ATTEMPTS: u32 = 3;
+
+/// Wait before the first retry of an upload; later ones wait longer.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const ARTIFACT_TYPE: &str = "application/vnd.cirunlabs.meda.vm.v1";
+const EMPTY_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+type Block = io::Result<Vec<u8>>;
+
+/// Artifact bytes pushed so far, for progress.
+struct Counter {
+    done: AtomicU64,
+    events: Mutex<Progress>,
+}
+
+impl Counter {
+    fn add(&self, n: usize) {
+        let done = self.done.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+        self.events.lock().unwrap().update(done);
+    }
+}
+
+struct Layer {
+    media_type: String,
+    digest: String,
+    size: u64,
+    title: String,
+}
+
+impl Layer {
+    fn descriptor(&self) -> serde_json::Value {
+        serde_json::json!({
+            "mediaType": self.media_type,
+            "digest": self.digest,
+            "size": self.size,
+            "annotations": { TITLE_ANNOTATION: self.title },
+        })
+    }
+}
+
+/// A pushed manifest.
+pub struct Pushed {
+    pub digest: String,
+    registry: Registry,
+    manifest: Vec<u8>,
+}
+
+impl Pushed {
+    /// Point `tags` at the pushed manifest as well.
+    pub async fn tag(&mut self, tags: &[String]) -> Result<()> {
+        for tag in tags {
+            self.registry
+                .put_manifest(tag, MANIFEST_MEDIA_TYPE, &self.manifest)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Push the artifacts of `manifest` in `source_dir` as `target`, with
+/// `annotations` on the manifest.
+pub async fn push(
+    config: &Config,
+    source_dir: &Path,
+    manifest: &ImageManifest,
+    target: &ImageRef,
+    credentials: &Credentials,
+    annotations: &BTreeMap<String, String>,
+    quiet: bool,
+) -> Result<Pushed> {
+    let image = OciRef {
+        registry: target.registry.clone(),
+        repository: format!("{}/{}", target.org, target.name),
+        reference: target.tag.clone(),
+    };
+    let registry = Registry::for_push(image, credentials.clone())?;
+    push_to(
+        registry,
+        &config.chunking,
+        source_dir,
+        manifest,
+        annotations,
+        quiet,
+    )
+    .await
+}
+
+async fn push_to(
+    mut registry: Registry,
+    chunking: &ChunkingConfig,
+    source_dir: &Path,
+    manifest: &ImageManifest,
+    annotations: &BTreeMap<String, String>,
+    quiet: bool,
+) -> Result<Pushed> {
+    // Goes first so the chunk uploads start from an authenticated client.
+    let config_digest = registry.upload_bytes(b"{}".to_vec()).await?;
+
+    let mut artifacts = Vec::new();
+    for (artifact_type, file) in &manifest.artifacts {
+        let path = source_dir.join(file);
+        if path.exists() {
+            let size = path.metadata()?.len();
+            artifacts.push((artifact_type, file, path, size));
+        }
+    }
+    artifacts.sort();
+    let total: u64 = artifacts.iter().map(|(_, _, _, size)| size).sum();
+    let counter = Arc::new(Counter {
+        done: AtomicU64::new(0),
+        events: Mutex::new(Progress::bytes(
+            "push",
+            &registry.image().to_string(),
+            Some(total),
+        )),
+    });
+
+    let mut layers = Vec::new();
+    let mut chunked = Vec::new();
+    for (artifact_type, file, path, size) in &artifacts {
+        if !quiet {
+            println!(
+                "📁 {}: {:.2} MB",
+                artifact_type,
+                *size as f64 / 1024.0 / 1024.0
+            );
+        }
+        let name = match chunking.compression {
+            Compression::None => file.to_string(),
+            Compression::Zstd { .. } => format!("{}.{}", file, ZSTD_EXTENSION),
+        };
+        let kind = format!(
+            "application/vnd.cirunlabs.meda.{}",
+            artifact_type.replace('_', "-")
+        );
+        let pushed = if *size < chunking.min_chunk_threshold {
+            vec![push_file(&mut registry, chunking, path, &kind, &name, &counter).await?]
+        } else {
+            chunked.push(name.clone());
+            push_chunked(&mut registry, chunking, path, &kind, &name, &counter, quiet).await?
+        };
+        layers.extend(pushed);
+    }
+
+    let mut annotations = annotations.clone();
+    if let Some(last) = chunked.last() {
+        annotations.insert(
+            "org.cirunlabs.meda.original-filename".to_string(),
+            last.clone(),
+        );
+        annotations.insert(
+            "org.cirunlabs.meda.chunked-files".to_string(),
+            chunked.join(","),
+        );
+    }
+    annotations
+        .entry("org.opencontainers.image.created".to_string())
+        .or_insert_with(|| chrono::Utc::now().to_rfc3339());
+    let document = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": MANIFEST_MEDIA_TYPE,
+        "artifactType": ARTIFACT_TYPE,
+        "config": { "mediaType": EMPTY_MEDIA_TYPE, "digest": config_digest, "size": 2 },
+        "layers": layers.iter().map(Layer::descriptor).collect::<Vec<_>>(),
+        "annotations": annotations,
+    });
+    let body = serde_json::to_vec(&document)?;
+    let tag = registry.image().reference.clone();
+    let digest = registry
+        .put_manifest(&tag, MANIFEST_MEDIA_TYPE, &body)
+        .await?;
+
+    if let Ok(counter) = Arc::try_unwrap(counter) {
+        counter.events.into_inner().unwrap().finish(Some(total));
+    }
+    Ok(Pushed {
+        digest,
+        registry,
+        manifest: body,
+    })
+}
+
+/// Push a file below the chunking threshold as one blob.
+async fn push_file(
+    registry: &mut Registry,
+    chunking: &ChunkingConfig,
+    path: &Path,
+    kind: &str,
+    name: &str,
+    counter: &Arc<Counter>,
+) -> Result<Layer> {
+    let file = Arc::new(File::open(path)?);
+    let size = file.metadata()?.len();
+    let compression = chunking.compression;
+    let (hex, len) = {
+        let file = file.clone();
+        let span = tracing::Span::current();
+        tokio::task::spawn_blocking(move || {
+            span.in_scope(|| stream(&mut chunk(&file, 0, size, compression)?, |_| Ok(())))
+        })
+        .await
+        .map_err(|e| Error::Other(format!("Hashing {} failed: {}", name, e)))??
+    };
+    let piece = Piece {
+        offset: 0,
+        len: size,
+        digest: format!("sha256:{}", hex),
+    };
+    put(registry, &file, compression, &piece, counter).await?;
+    Ok(Layer {
+        media_type: format!("{}.v1{}", kind, compression.media_suffix()),
+        digest: piece.digest,
+        size: len,
+        title: name.to_string(),
+    })
+}
+
+/// Push a large file as chunks followed by their index. Chunks are
+/// ranges of the file before compression; each is hashed first, then
+/// uploaded unless the registry has it already.
+async fn push_chunked(
+    registry: &mut Registry,
+    chunking: &ChunkingConfig,
+    path: &Path,
+    kind: &str,
+    name: &str,
+    counter: &Arc<Counter>,
+    quiet: bool,
+) -> Result<Vec<Layer>> {
+    let size = path.metadata()?.len();
+    let chunk_size = FileChunker::with_config(chunking.clone()).get_chunk_size(size);
+    if !quiet {
+        println!(
+            "🔪 {} goes up in {:.2} MB chunks",
+            name,
+            chunk_size as f64 / 1024.0 / 1024.0
+        );
+    }
+    let media_type = format!("{}-chunk.v1{}", kind, chunking.compression.media_suffix());
+    let title = |index: usize| format!("{}.chunk.{:03}", name, index);
+    let compression = chunking.compression;
+    let file = Arc::new(File::open(path)?);
+
+    // Chunks are hashed in order, for the digest of them all, and each
+    // one's upload starts as soon as it is hashed.
+    let (tx, mut rx) = mpsc::channel::<Result<(Piece, u64)>>(BUFFERS);
+    let hashing = {
+        let file = file.clone();
+        let span = tracing::Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _span = span.enter();
+            let mut whole = Sha256::new();
+            for offset in (0..size).step_by(chunk_size as usize) {
+                let len = chunk_size.min(size - offset);
+                let hashed = chunk(&file, offset, len, compression).and_then(|mut reader| {
+                    stream(&mut reader, |block| {
+                        whole.update(&block);
+                        Ok(())
+                    })
+                });
+                let failed = hashed.is_err();
+                let hashed = hashed.map(|(hex, size)| {
+                    let digest = format!("sha256:{}", hex);
+                    (
+                        Piece {
+                            offset,
+                            len,
+                            digest,
+                        },
+                        size,
+                    )
+                });
+                if tx.blocking_send(hashed).is_err() || failed {
+                    break;
+                }
+            }
+            format!("{:x}", whole.finalize())
+        })
+    };
+    let chunks: Vec<(String, u64)> = futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx))
+        .enumerate()
+        .map(|(index, hashed)| {
+            let mut registry = registry.clone();
+            let file = file.clone();
+            let counter = counter.clone();
+            async move {
+                let (piece, size) = hashed?;
+                let uploaded = put(&mut registry, &file, compression, &piece, &counter).await?;
+                if !quiet {
+                    let verb = if uploaded {
+                        "Uploaded"
+                    } else {
+                        "Already pushed"
+                    };
+                    println!(
+                        "📤 {} {} ({:.2} MB)",
+                        verb,
+                        title(index),
+                        size as f64 / 1024.0 / 1024.0
+                    );
+                }
+                Ok::<_, Error>((piece.digest, size))
+            }
+        })
+        .buffered(chunking.get_push_concurrency().max(1) as usize)
+        .try_collect()
+        .await?;
+    let sha256 = hashing
+        .await
+        .map_err(|e| Error::Other(format!("Hashing {} failed: {}", name, e)))?;
+
+    let index = ChunkMetadata {
+        original_filename: name.to_string(),
+        total_chunks: chunks.len(),
+        chunk_size,
+        total_size: chunks.iter().map(|(_, len)| len).sum(),
+        sha256: Some(sha256),
+        chunk_sha256: chunks
+            .iter()
+            .map(|(digest, _)| digest.trim_start_matches("sha256:").to_string())
+            .collect(),
+    };
+    let index = serde_json::to_vec_pretty(&index)?;
+    let mut layers: Vec<Layer> = chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, (digest, size))| Layer {
+            media_type: media_type.clone(),
+            digest,
+            size,
+            title: title(index),
+        })
+        .collect();
+    layers.push(Layer {
+        media_type,
+        size: index.len() as u64,
+        digest: registry.upload_bytes(index).await?,
+        title: chunking::index_path(Path::new(""), name)
+            .display()
+            .to_string(),
+    });
+    Ok(layers)
+}
+
+/// A range of an artifact and the digest of the blob it goes up as.
+struct Piece {
+    offset: u64,
+    len: u64,
+    digest: String,
+}
+
+/// Upload `piece` of `file` unless the registry has it already,
+/// retrying a failed upload; false when it was already there.
+async fn put(
+    registry: &mut Registry,
+    file: &Arc<File>,
+    compression: Compression,
+    piece: &Piece,
+    counter: &Counter,
+) -> Result<bool> {
+    let mut attempt = 1;
+    let uploaded = loop {
+        let result = match registry.has_blob(&piece.digest).await {
+            Ok(true) => break false,
+            Ok(false) => {
+                let (file, offset, len) = (file.clone(), piece.offset, piece.len);
+                upload(registry, move |tx| {
+                    stream(&mut chunk(&file, offset, len, compression)?, |block| {
+                        send(tx, block)
+                    })
+                })
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok((digest, _)) if digest == piece.digest => break true,
+            Ok((digest, _)) => {
+                return Err(Error::Other(format!(
+                    "Artifact changed while pushing: expected {}, uploaded {}",
+                    piece.digest, digest
+                )))
+            }
+            Err(e) if attempt < UPLOAD_ATTEMPTS => {
+                warn!(
+                    "Uploading {} failed (attempt {}/{}), retrying: {}",
+                    piece.digest, attempt, UPLOAD_ATTEMPTS, e
+                );
+                tokio::time::sleep(RETRY_DELAY * attempt).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    counter.add(piece.len as usize);
+    Ok(uploaded)
+}
+
+/// Bytes `offset..offset + len` of `file`, as they go up: themselves,
+/// or compressed into a zstd frame of their own. Frames decompress
+/// back to back, so chunks still join into one stream on pull.
+fn chunk(
+    file: &File,
+    offset: u64,
+    len: u64,
+    compression: Compression,
+) -> Result<Box<dyn Read + '_>> {
+    let range = Range {
+        file,
+        pos: offset,
+        end: offset + len,
+    };
+    Ok(match compression {
+        Compression::None => Box::new(range),
+        Compression::Zstd { level } => Box::new(zstd::stream::read::Encoder::new(range, level)?),
+    })
+}
+
+/// Part of a file, read at its position so several can share the file.
+struct Range<'a> {
+    file: &'a File,
+    pos: u64,
+    end: u64,
+}
+
+impl Read for Range<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let want = buf.len().min((self.end - self.pos) as usize);
+        if want == 0 {
+            return Ok(0);
+        }
+        let n = self.file.read_at(&mut buf[..want], self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Upload the blob `produce` sends, produced on a blocking thread.
+/// `produce` returns the blob's SHA256 (hex) and whatever the caller
+/// wants back.
+async fn upload<T: Send + 'static>(
+    registry: &mut Registry,
+    produce: impl FnOnce(&mpsc::Sender<Block>) -> Result<(String, T)> + Send + 'static,
+) -> Result<(String, T)> {
+    let (tx, mut rx) = mpsc::channel::<Block>(BUFFERS);
+    let span = tracing::Span::current();
+    let producer = tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        let produced = produce(&tx);
+        if let Err(e) = &produced {
+            // Fail the request instead of ending the blob early.
+            let _ = tx.blocking_send(Err(io::Error::other(e.to_string())));
+        }
+        produced
+    });
+    let body =
+        reqwest::Body::wrap_stream(futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx)));
+    registry
+        .upload_stream(body, async move {
+            let (hex, rest) = producer
+                .await
+                .map_err(|e| Error::Other(format!("Upload failed: {}", e)))??;
+            Ok((format!("sha256:{}", hex), rest))
+        })
+        .await
+}
+
+/// Hand `block` to the upload; fails once the upload has given up.
+fn send(tx: &mpsc::Sender<Block>, block: Vec<u8>) -> Result<()> {
+    tx.blocking_send(Ok(block))
+        .map_err(|_| Error::Other("Registry upload was cut short".to_string()))
+}
+
+/// Read `reader` to the end in [`BLOCK`]-sized pieces, handing each to
+/// `sink`; returns their SHA256 (hex) and how many bytes there were.
+fn stream(
+    reader: &mut impl Read,
+    mut sink: impl FnMut(Vec<u8>) -> Result<()>,
+) -> Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let mut sent = 0u64;
+    loop {
+        let mut block = Vec::with_capacity(BLOCK);
+        if reader.by_ref().take(BLOCK as u64).read_to_end(&mut block)? == 0 {
+            break;
+        }
+        hasher.update(&block);
+        sent += block.len() as u64;
+        sink(block)?;
+    }
+    Ok((format!("{:x}", hasher.finalize()), sent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use axum::extract::{Path as UrlPath, Query, State};
+    use axum::http::{header, Method, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    #[derive(Default)]
+    struct Store {
+        uploads: HashMap<String, Vec<u8>>,
+        blobs: HashMap<String, Vec<u8>>,
+        manifests: HashMap<String, Vec<u8>>,
+        /// Uploads started.
+        started: usize,
+        /// Streamed uploads still to fail when they are finished.
+        fail: usize,
+    }
+
+    type Shared = Arc<Mutex<Store>>;
+
+    /// Just enough of the distribution API for a push.
+    async fn registry(
+        State(store): State<Shared>,
+        method: Method,
+        UrlPath(path): UrlPath<String>,
+        Query(query): Query<HashMap<String, String>>,
+        body: Bytes,
+    ) -> Response {
+        let mut store = store.lock().unwrap();
+        let location = |id: &str| {
+            let upload = format!(
+                "{}/blobs/uploads/{}",
+                path.split("/blobs/").next().unwrap(),
+                id
+            );
+            [(header::LOCATION, format!("/v2/{}", upload))]
+        };
+        if let Some((_, tag)) = path.split_once("/manifests/") {
+            store.manifests.insert(tag.to_string(), body.to_vec());
+            return StatusCode::CREATED.into_response();
+        }
+        if let Some((_, id)) = path.split_once("/blobs/uploads/") {
+            if id.is_empty() {
+                store.started += 1;
+                let id = store.started.to_string();
+                store.uploads.insert(id.clone(), Vec::new());
+                return (StatusCode::ACCEPTED, location(&id)).into_response();
+            }
+            store.uploads.get_mut(id).unwrap().extend_from_slice(&body);
+            if method == Method::PATCH {
+                return (StatusCode::ACCEPTED, location(id)).into_response();
+            }
+            if body.is_empty() && store.fail > 0 {
+                store.fail -= 1;
+                store.uploads.remove(id);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+            let data = store.uploads.remove(id).unwrap();
+            let digest = format!("sha256:{:x}", Sha256::digest(&data));
+            if query.get("digest") != Some(&digest) {
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+            store.blobs.insert(digest, data);
+            return StatusCode::CREATED.into_response();
+        }
+        let (_, digest) = path.split_once("/blobs/").unwrap();
+        if store.blobs.contains_key(digest) {
+            StatusCode::OK.into_response()
+        } else {
+            StatusCode::NOT_FOUND.into_response()
+        }
+    }
+
+    async fn serve() -> (String, Shared) {
+        let store = Shared::default();
+        let app = axum::Router::new()
+            .route("/v2/*path", axum::routing::any(registry))
+            .with_state(store.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, store)
+    }
+
+    /// An image dir with a disk of a few pseudo-random blocks.
+    fn image() -> (TempDir, ImageManifest, Vec<u8>) {
+        let mut x = 1u64;
+        let disk: Vec<u8> = (0..3 * BLOCK + 12345)
+            .map(|_| {
+                x = x.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (x >> 33) as u8
+            })
+            .collect();
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join("base.raw"), &disk).unwrap();
+        std::fs::write(dir.path().join("user-data"), b"#cloud-config\n").unwrap();
+        let manifest = ImageManifest {
+            schema_version: crate::schema::IMAGE_SCHEMA_VERSION,
+            name: "runner".to_string(),
+            tag: "v1".to_string(),
+            registry: "localhost".to_string(),
+            org: "acme".to_string(),
+            artifacts: HashMap::from([
+                ("base_image".to_string(), "base.raw".to_string()),
+                ("user_data".to_string(), "user-data".to_string()),
+            ]),
+            metadata: HashMap::new(),
+            created: 0,
+            history: Vec::new(),
+        };
+        (dir, manifest, disk)
+    }
+
+    fn chunked(compression: Compression) -> ChunkingConfig {
+        ChunkingConfig {
+            min_chunk_threshold: BLOCK as u64,
+            small_chunk_size: BLOCK as u64 + 7,
+            compression,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_streams_chunks() {
+        let (dir, manifest, disk) = image();
+        for compression in [Compression::None, Compression::Zstd { level: 3 }] {
+            let (url, store) = serve().await;
+            let image = OciRef::parse("localhost/acme/runner:v1").unwrap();
+            let registry = Registry::new(image, None).unwrap().with_base_url(&url);
+            let chunking = chunked(compression);
+            let annotations = BTreeMap::from([("meda.name".to_string(), "runner".to_string())]);
+            let mut pushed = push_to(
+                registry,
+                &chunking,
+                dir.path(),
+                &manifest,
+                &annotations,
+                true,
+            )
+            .await
+            .unwrap();
+            pushed.tag(&["latest".to_string()]).await.unwrap();
+
+            let store = store.lock().unwrap();
+            let body = &store.manifests["v1"];
+            assert_eq!(store.manifests["latest"], *body);
+            assert_eq!(pushed.digest, format!("sha256:{:x}", Sha256::digest(body)));
+            let document: serde_json::Value = serde_json::from_slice(body).unwrap();
+            assert!(store
+                .blobs
+                .contains_key(document["config"]["digest"].as_str().unwrap()));
+            let suffix = compression.media_suffix();
+            let name = match compression {
+                Compression::None => "base.raw".to_string(),
+                Compression::Zstd { .. } => format!("base.raw.{}", ZSTD_EXTENSION),
+            };
+            assert_eq!(
+                document["annotations"]["org.cirunlabs.meda.chunked-files"],
+                name.as_str()
+            );
+            assert_eq!(document["annotations"]["meda.name"], "runner");
+
+            let layers = document["layers"].as_array().unwrap();
+            let blob = |title: &str| {
+                let layer = layers
+                    .iter()
+                    .find(|l| l["annotations"][TITLE_ANNOTATION] == title)
+                    .unwrap_or_else(|| panic!("no layer {}", title));
+                store.blobs[layer["digest"].as_str().unwrap()].clone()
+            };
+            let user_data = layers
+                .iter()
+                .find(|l| {
+                    l["mediaType"]
+                        == format!("application/vnd.cirunlabs.meda.user-data.v1{}", suffix)
+                })
+                .unwrap();
+            assert!(user_data["annotations"][TITLE_ANNOTATION]
+                .as_str()
+                .unwrap()
+                .starts_with("user-data"));
+
+            let index: ChunkMetadata =
+                serde_json::from_slice(&blob(&format!("{}.chunk.index", name))).unwrap();
+            assert!(index.total_chunks > 1);
+            let mut joined = Vec::new();
+            for (i, sha256) in index.chunk_sha256.iter().enumerate() {
+                let chunk = blob(&format!("{}.chunk.{:03}", name, i));
+                assert_eq!(*sha256, format!("{:x}", Sha256::digest(&chunk)));
+                joined.extend(chunk);
+            }
+            assert_eq!(index.total_size, joined.len() as u64);
+            assert_eq!(index.sha256, Some(format!("{:x}", Sha256::digest(&joined))));
+            if let Compression::Zstd { .. } = compression {
+                joined = zstd::stream::decode_all(&joined[..]).unwrap();
+            }
+            assert_eq!(joined, disk);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_retries_and_skips_pushed_blobs() {
+        let (dir, manifest, _) = image();
+        let (url, store) = serve().await;
+        let chunking = chunked(Compression::Zstd { level: 3 });
+        let annotations = BTreeMap::new();
+        let push = || {
+            let image = OciRef::parse("localhost/acme/runner:v1").unwrap();
+            let registry = Registry::new(image, None).unwrap().with_base_url(&url);
+            push_to(
+                registry,
+                &chunking,
+                dir.path(),
+                &manifest,
+                &annotations,
+                true,
+            )
+        };
+
+        store.lock().unwrap().fail = 1;
+        push().await.unwrap();
+        let started = {
+            let store = store.lock().unwrap();
+            assert_eq!(store.fail, 0);
+            store.started
+        };
+        push().await.unwrap();
+        assert_eq!(store.lock().unwrap().started, started);
+    }
+}
@@ -0,0 +1,219 @@
+//! `qemu-img convert` with progress and cancellation.
+//!
+//! Flattening or converting a multi-GB disk takes minutes. [`convert`]
+//! runs qemu-img with `-p`, turns its `(NN.NN/100%)` updates into a
+//! progress bar (or `convert` events with `--progress json`), and on
+//! Ctrl-C or SIGTERM stops qemu-img and removes the half-written target
+//! instead of leaving a truncated image behind for the next run to trip
+//! over.
+
+use crate::error::{Error, Result};
+use crate::progress::{json_mode, Progress};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::debug;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::fs;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_cancel(_: nix::libc::c_int) {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+/// Routes SIGINT/SIGTERM to [`request_cancel`] while alive. Signals
+/// someone else already handles (the API server's graceful shutdown)
+/// are left alone.
+struct CancelOnSignal {
+    previous: Vec<(Signal, SigAction)>,
+}
+
+impl CancelOnSignal {
+    fn install() -> Self {
+        CANCELLED.store(false, Ordering::SeqCst);
+        let action = SigAction::new(
+            SigHandler::Handler(request_cancel),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        let mut previous = Vec::new();
+        for signal in [Signal::SIGINT, Signal::SIGTERM] {
+            // SAFETY: the handler only stores to an atomic.
+            let Ok(old) = (unsafe { sigaction(signal, &action) }) else {
+                continue;
+            };
+            if old.handler() != SigHandler::SigDfl {
+                // SAFETY: puts back the disposition we just replaced.
+                let _ = unsafe { sigaction(signal, &old) };
+                continue;
+            }
+            previous.push((signal, old));
+        }
+        Self { previous }
+    }
+}
+
+impl Drop for CancelOnSignal {
+    fn drop(&mut self) {
+        for (signal, old) in &self.previous {
+            // SAFETY: restores the default disposition saved in install().
+            let _ = unsafe { sigaction(*signal, old) };
+        }
+    }
+}
+
+/// Percentage from one of qemu-img's `-p` updates, e.g. `    (42.17/100%)`.
+fn parse_percent(update: &str) -> Option<f64> {
+    update
+        .trim()
+        .strip_prefix('(')?
+        .strip_suffix("/100%)")?
+        .parse()
+        .ok()
+}
+
+/// `qemu-img convert -f src_fmt -O dst_fmt src dst`. On failure or
+/// cancellation `dst` is removed.
+pub async fn convert(
+    src: &Path,
+    src_fmt: &str,
+    dst: &Path,
+    dst_fmt: &str,
+    json: bool,
+) -> Result<()> {
+    let args = [
+        "convert",
+        "-p",
+        "-f",
+        src_fmt,
+        "-O",
+        dst_fmt,
+        src.to_str().unwrap(),
+        dst.to_str().unwrap(),
+    ];
+    debug!("Running command: qemu-img {}", args.join(" "));
+
+    let total = crate::util::disk_virtual_size(src);
+    let artifact = dst.file_name().unwrap_or_default().to_string_lossy();
+    let mut events = Progress::bytes("convert", &artifact, total);
+    let bar = (!json && !json_mode()).then(|| {
+        let bar = ProgressBar::new(100);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}% ({eta})",
+                )
+                .unwrap()
+                .progress_chars("#>-"),
+        );
+        bar
+    });
+
+    let _signals = CancelOnSignal::install();
+    let mut child = Command::new("qemu-img")
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| Error::CommandFailed(format!("qemu-img {}: {}", args.join(" "), e)))?;
+
+    let mut stderr = child.stderr.take().unwrap();
+    let errors = tokio::spawn(async move {
+        let mut out = String::new();
+        let _ = stderr.read_to_string(&mut out).await;
+        out
+    });
+
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stdout_open = true;
+    let mut pending = String::new();
+    let mut buf = [0u8; 256];
+    let mut tick = tokio::time::interval(Duration::from_millis(100));
+    let status = loop {
+        tokio::select! {
+            status = child.wait() => break status?,
+            read = stdout.read(&mut buf), if stdout_open => {
+                let n = read.unwrap_or(0);
+                if n == 0 {
+                    stdout_open = false;
+                    continue;
+                }
+                pending.push_str(&String::from_utf8_lossy(&buf[..n]));
+                // Updates end in '\r' (or '\n' for the last one).
+                while let Some(end) = pending.find(['\r', '\n']) {
+                    if let Some(p) = parse_percent(&pending[..end]) {
+                        if let Some(bar) = &bar {
+                            bar.set_position(p as u64);
+                        }
+                        if let Some(total) = total {
+                            events.update((total as f64 * p / 100.0) as u64);
+                        }
+                    }
+                    pending.drain(..=end);
+                }
+            }
+            _ = tick.tick() => {
+                if CANCELLED.load(Ordering::SeqCst) {
+                    let _ = child.start_kill();
+                }
+            }
+        }
+    };
+    let stderr = errors.await.unwrap_or_default();
+    if let Some(bar) = &bar {
+        bar.finish_and_clear();
+    }
+
+    if CANCELLED.load(Ordering::SeqCst) {
+        let _ = fs::remove_file(dst);
+        return Err(Error::Other(format!(
+            "Cancelled converting {}; removed the partial {}",
+            src.display(),
+            dst.display()
+        )));
+    }
+    if !status.success() {
+        let _ = fs::remove_file(dst);
+        return Err(Error::CommandFailed(format!(
+            "qemu-img {} failed with exit code: {:?}: {}",
+            args.join(" "),
+            status.code(),
+            stderr.trim()
+        )));
+    }
+    events.finish(total);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_percent() {
+        assert_eq!(parse_percent("    (0.00/100%)"), Some(0.0));
+        assert_eq!(parse_percent("    (42.17/100%)"), Some(42.17));
+        assert_eq!(parse_percent("(100.00/100%)"), Some(100.0));
+        assert_eq!(parse_percent(""), None);
+        assert_eq!(parse_percent("qemu-img: error"), None);
+    }
+
+    #[test]
+    fn test_signal_handler_restored() {
+        {
+            let _guard = CancelOnSignal::install();
+            request_cancel(0);
+            assert!(CANCELLED.load(Ordering::SeqCst));
+        }
+        let action = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+        // SAFETY: reads back the SIGTERM disposition and puts it back.
+        let current = unsafe { sigaction(Signal::SIGTERM, &action) }.unwrap();
+        assert_eq!(current.handler(), SigHandler::SigDfl);
+    }
+}
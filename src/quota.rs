@@ -0,0 +1,483 @@
+//! Host-wide resource quotas: `MEDA_MAX_VMS`, `MEDA_MAX_TOTAL_MEMORY`
+//! and `MEDA_MAX_TOTAL_DISK`.
+//!
+//! Admission (see `admission`) keeps the API from overcommitting the
+//! host; quotas cap how much of it meda may use at all, e.g. when several
+//! CI jobs share one big host. They hold for the CLI and the API alike:
+//!
+//! - `MEDA_MAX_VMS` counts the VMs on the host, and is checked when one
+//!   is created (`create`, `run`, `clone`, a received `migrate`).
+//! - `MEDA_MAX_TOTAL_DISK` sums the disk size of every VM, running or
+//!   not, checked at the same points.
+//! - `MEDA_MAX_TOTAL_MEMORY` sums the memory of running (and starting)
+//!   VMs, checked when one starts (`start`, `restart`, `run`, `restore`).
+//!
+//! Growing a VM (`resize`, `hotplug`, `apply`) checks the memory of a
+//! running VM and the disk of any against the same limits, and against
+//! the host's admission budget like an API create.
+//!
+//! Sizes are the declared ones (`--memory`, `--disk`), as for admission.
+//! The per-image templates behind `meda run` don't count as VMs. Going
+//! over a limit fails with [`Error::QuotaExceeded`] (409 `QUOTA_EXCEEDED`
+//! from the API); `meda quota` shows usage against the limits.
+
+use crate::admission::{self, VmRequest};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::lock::FileLock;
+use crate::util::parse_size_bytes;
+use crate::vm::{self, VmInfo};
+use serde::Serialize;
+
+/// Lock in the config dir held from a check until what it admitted is
+/// on disk (a VM dir, a starting state, new sizes), so concurrent
+/// operations can't both take the last slot.
+const LOCK_FILE: &str = "quota.lock";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub max_vms: Option<u64>,
+    /// Bytes of memory across running VMs.
+    pub max_memory: Option<u64>,
+    /// Bytes of disk across all VMs.
+    pub max_disk: Option<u64>,
+}
+
+impl Limits {
+    /// Limits from the environment; invalid values are warned about and
+    /// ignored, like other settings.
+    pub fn from_env(get: impl Fn(&str) -> Option<String>) -> Self {
+        let value = |var: &str, parse: fn(&str) -> Option<u64>| {
+            let raw = get(var).filter(|v| !v.trim().is_empty())?;
+            let parsed = parse(raw.trim());
+            if parsed.is_none() {
+                log::warn!("Ignoring invalid {} '{}'", var, raw);
+            }
+            parsed
+        };
+        Self {
+            max_vms: value("MEDA_MAX_VMS", |v| v.parse().ok()),
+            max_memory: value("MEDA_MAX_TOTAL_MEMORY", parse_size_bytes),
+            max_disk: value("MEDA_MAX_TOTAL_DISK", parse_size_bytes),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// What the VMs on the host count against the limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub vms: u64,
+    /// Bytes of memory of running and starting VMs.
+    pub memory: u64,
+    /// Bytes of disk of all VMs.
+    pub disk: u64,
+}
+
+impl Usage {
+    /// Usage of `vms`, leaving out `except` (the VM being checked) and
+    /// hidden dirs such as a `migrate` still being unpacked.
+    pub fn of(vms: &[VmInfo], except: Option<&str>) -> Self {
+        let mut usage = Self::default();
+        for vm in vms.iter().filter(|vm| Some(vm.name.as_str()) != except) {
+            if !vm::is_template(&vm.name) {
+                usage.vms += 1;
+            }
+            usage.disk += parse_size_bytes(&vm.disk).unwrap_or(0);
+            if matches!(vm.state.as_str(), "running" | "starting") {
+                usage.memory += parse_size_bytes(&vm.memory).unwrap_or(0);
+            }
+        }
+        usage
+    }
+}
+
+fn gib(bytes: u64) -> String {
+    format!("{:.1} GiB", bytes as f64 / (1024.0 * 1024.0 * 1024.0))
+}
+
+/// Whether VM `name` with a disk of `disk` bytes fits beside `usage`.
+fn check_create(limits: &Limits, usage: &Usage, name: &str, disk: u64) -> Result<()> {
+    if let Some(max) = limits.max_vms {
+        if !vm::is_template(name) && usage.vms >= max {
+            return Err(Error::QuotaExceeded(format!(
+                "{} VMs exist and MEDA_MAX_VMS is {}; delete one to create {}",
+                usage.vms, max, name
+            )));
+        }
+    }
+    check_disk(limits, usage, name, disk)
+}
+
+/// Whether VM `name` with a disk of `disk` bytes fits beside `usage`'s disks.
+fn check_disk(limits: &Limits, usage: &Usage, name: &str, disk: u64) -> Result<()> {
+    if let Some(max) = limits.max_disk {
+        if usage.disk.saturating_add(disk) > max {
+            return Err(Error::QuotaExceeded(format!(
+                "VM {} needs {} of disk, but VMs already use {} of MEDA_MAX_TOTAL_DISK ({})",
+                name,
+                gib(disk),
+                gib(usage.disk),
+                gib(max)
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether VM `name` with `memory` bytes can run beside `usage`.
+fn check_start(limits: &Limits, usage: &Usage, name: &str, memory: u64) -> Result<()> {
+    if let Some(max) = limits.max_memory {
+        if usage.memory.saturating_add(memory) > max {
+            return Err(Error::QuotaExceeded(format!(
+                "VM {} needs {} of memory, but running VMs already use {} of MEDA_MAX_TOTAL_MEMORY ({})",
+                name,
+                gib(memory),
+                gib(usage.memory),
+                gib(max)
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn lock(config: &Config) -> Result<FileLock> {
+    std::fs::create_dir_all(&config.ch_home)?;
+    Ok(FileLock::exclusive(&config.ch_home.join(LOCK_FILE))?)
+}
+
+/// Before creating VM `name` with a `disk`-sized root disk. Keep the
+/// returned guard until the VM dir exists.
+pub fn before_create(config: &Config, name: &str, disk: &str) -> Result<Option<FileLock>> {
+    let limits = &config.quota;
+    if limits.max_vms.is_none() && limits.max_disk.is_none() {
+        return Ok(None);
+    }
+    let lock = lock(config)?;
+    let usage = Usage::of(&vm::collect_vms_with_templates(config)?, Some(name));
+    check_create(limits, &usage, name, parse_size_bytes(disk).unwrap_or(0))?;
+    Ok(Some(lock))
+}
+
+/// Before starting VM `name` with `memory` of RAM. Keep the returned
+/// guard until the VM is recorded as starting.
+pub fn before_start(config: &Config, name: &str, memory: &str) -> Result<Option<FileLock>> {
+    if config.quota.max_memory.is_none() {
+        return Ok(None);
+    }
+    let lock = lock(config)?;
+    let usage = Usage::of(&vm::collect_vms_with_templates(config)?, Some(name));
+    check_start(
+        &config.quota,
+        &usage,
+        name,
+        parse_size_bytes(memory).unwrap_or(0),
+    )?;
+    Ok(Some(lock))
+}
+
+/// Before receiving VM `name` from another host (`migrate-receive
+/// --check`): it needs a VM slot and `disk` under the quotas and the
+/// host's admission budget, and `memory` and `cpus` too if it will be
+/// started.
+pub fn before_receive(
+    config: &Config,
+    name: &str,
+    disk: &str,
+    memory: &str,
+    cpus: u8,
+    start: bool,
+) -> Result<()> {
+    drop(before_create(config, name, disk)?);
+    if start {
+        drop(before_start(config, name, memory)?);
+    }
+    let request = VmRequest {
+        mem_gb: if start {
+            admission::parse_size_gb(memory)
+        } else {
+            0
+        },
+        cpu: if start { cpus.into() } else { 0 },
+        disk_gb: admission::parse_size_gb(disk),
+    };
+    let budget = crate::host_capacity::budget(config);
+    check_budget(
+        &budget,
+        &vm::collect_vms_with_templates(config)?,
+        name,
+        &request,
+    )
+}
+
+/// What growing a VM adds: only the sizes that grow, and memory and
+/// vCPUs only while it runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Growth {
+    memory: Option<u64>,
+    cpus: Option<u32>,
+    disk: Option<u64>,
+}
+
+impl Growth {
+    fn of(
+        current: Option<&VmInfo>,
+        running: bool,
+        memory: Option<&str>,
+        cpus: Option<u8>,
+        disk: Option<&str>,
+    ) -> Self {
+        let bytes = |size: &str| parse_size_bytes(size).unwrap_or(0);
+        let grows = |new: Option<&str>, old: Option<&str>| {
+            new.map(bytes).filter(|new| *new > old.map_or(0, bytes))
+        };
+        Self {
+            memory: grows(memory, current.map(|vm| vm.memory.as_str())).filter(|_| running),
+            cpus: cpus.map(u32::from).filter(|new| {
+                running && *new > current.map_or(0, |vm| vm.vcpus.trim().parse().unwrap_or(0))
+            }),
+            disk: grows(disk, current.map(|vm| vm.disk.as_str())),
+        }
+    }
+}
+
+/// Whether growing VM `name` by `growth` fits the quotas and the host's
+/// admission `budget`, beside the other `vms`.
+fn check_grow(
+    limits: &Limits,
+    budget: &admission::Budget,
+    vms: &[VmInfo],
+    name: &str,
+    growth: &Growth,
+) -> Result<()> {
+    let usage = Usage::of(vms, Some(name));
+    if let Some(memory) = growth.memory {
+        check_start(limits, &usage, name, memory)?;
+    }
+    if let Some(disk) = growth.disk {
+        check_disk(limits, &usage, name, disk)?;
+    }
+    const GIB: u64 = 1024 * 1024 * 1024;
+    let request = VmRequest {
+        mem_gb: growth.memory.map_or(0, |b| b / GIB),
+        cpu: growth.cpus.unwrap_or(0),
+        disk_gb: growth.disk.map_or(0, |b| b / GIB),
+    };
+    check_budget(budget, vms, name, &request)
+}
+
+/// Whether the host's admission `budget` has room for VM `name` to take
+/// `request` beside the other `vms`, as the API checks new VMs.
+fn check_budget(
+    budget: &admission::Budget,
+    vms: &[VmInfo],
+    name: &str,
+    request: &VmRequest,
+) -> Result<()> {
+    let committed = crate::host_capacity::committed(vms, Some(name));
+    admission::can_admit(request, &committed, budget)
+        .map_err(|denied| Error::QuotaExceeded(format!("VM {}: {}", name, denied.message())))
+}
+
+/// Before growing VM `name` to `memory`, `cpus` and/or a `disk`-sized
+/// disk. Keep the returned guard until the new sizes are recorded.
+pub fn before_grow(
+    config: &Config,
+    name: &str,
+    memory: Option<&str>,
+    cpus: Option<u8>,
+    disk: Option<&str>,
+) -> Result<FileLock> {
+    let lock = lock(config)?;
+    let vms = vm::collect_vms_with_templates(config)?;
+    let current = vms.iter().find(|vm| vm.name == name);
+    let running = current.is_some_and(|vm| vm.state == "running");
+    let growth = Growth::of(current, running, memory, cpus, disk);
+    if growth != Growth::default() {
+        let budget = crate::host_capacity::budget(config);
+        check_grow(&config.quota, &budget, &vms, name, &growth)?;
+    }
+    Ok(lock)
+}
+
+#[derive(Serialize)]
+struct Line {
+    used: u64,
+    limit: Option<u64>,
+}
+
+/// `meda quota`: usage against each limit.
+pub fn quota_command(config: &Config, json: bool) -> Result<()> {
+    let usage = Usage::of(&vm::collect_vms_with_templates(config)?, None);
+    let limits = &config.quota;
+
+    if json {
+        let report = serde_json::json!({
+            "vms": Line { used: usage.vms, limit: limits.max_vms },
+            "memory_bytes": Line { used: usage.memory, limit: limits.max_memory },
+            "disk_bytes": Line { used: usage.disk, limit: limits.max_disk },
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    let limit =
+        |limit: Option<u64>, show: fn(u64) -> String| limit.map_or("unlimited".to_string(), show);
+    println!(
+        "VMs:     {} / {}",
+        usage.vms,
+        limit(limits.max_vms, |n| n.to_string())
+    );
+    println!(
+        "Memory:  {} / {} (running VMs)",
+        gib(usage.memory),
+        limit(limits.max_memory, gib)
+    );
+    println!(
+        "Disk:    {} / {}",
+        gib(usage.disk),
+        limit(limits.max_disk, gib)
+    );
+    if limits.is_empty() {
+        println!("No quotas set (MEDA_MAX_VMS, MEDA_MAX_TOTAL_MEMORY, MEDA_MAX_TOTAL_DISK)");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    fn vm(name: &str, state: &str, memory: &str, disk: &str) -> VmInfo {
+        VmInfo {
+            name: name.to_string(),
+            state: state.to_string(),
+            ip: "-".to_string(),
+            vcpus: "2".to_string(),
+            memory: memory.to_string(),
+            disk: disk.to_string(),
+            devices: Vec::new(),
+            created: "unknown".to_string(),
+            labels: Default::default(),
+            env: Default::default(),
+            storage: "default".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_limits_from_env() {
+        let limits = Limits::from_env(|var| match var {
+            "MEDA_MAX_VMS" => Some("10".to_string()),
+            "MEDA_MAX_TOTAL_MEMORY" => Some("64G".to_string()),
+            "MEDA_MAX_TOTAL_DISK" => Some("lots".to_string()),
+            _ => None,
+        });
+        assert_eq!(limits.max_vms, Some(10));
+        assert_eq!(limits.max_memory, Some(64 * GIB));
+        assert_eq!(limits.max_disk, None);
+        assert!(Limits::from_env(|_| None).is_empty());
+    }
+
+    #[test]
+    fn test_usage_and_checks() {
+        let vms = [
+            vm("a", "running", "4G", "20G"),
+            vm("b", "stopped", "8G", "20G"),
+            vm("c", "starting", "2048M", "10G"),
+            vm("__tpl_ubuntu", "stopped", "1G", "10G"),
+        ];
+        let usage = Usage::of(&vms, Some("c"));
+        assert_eq!(
+            usage,
+            Usage {
+                vms: 2,
+                memory: 4 * GIB,
+                disk: 50 * GIB
+            }
+        );
+
+        let limits = Limits {
+            max_vms: Some(2),
+            max_memory: Some(8 * GIB),
+            max_disk: Some(60 * GIB),
+        };
+        let err = check_create(&limits, &usage, "d", GIB).unwrap_err();
+        assert!(matches!(err, Error::QuotaExceeded(_)));
+        assert!(err.to_string().contains("MEDA_MAX_VMS is 2"));
+        // Templates don't take a VM slot, but their disks count.
+        assert!(check_create(&limits, &usage, "__tpl_debian", 10 * GIB).is_ok());
+        assert!(check_create(&limits, &usage, "__tpl_debian", 11 * GIB).is_err());
+
+        assert!(check_start(&limits, &usage, "c", 4 * GIB).is_ok());
+        assert!(check_start(&limits, &usage, "c", 5 * GIB)
+            .unwrap_err()
+            .to_string()
+            .contains("MEDA_MAX_TOTAL_MEMORY"));
+        assert!(check_start(&Limits::default(), &usage, "c", 512 * GIB).is_ok());
+    }
+
+    #[test]
+    fn test_grow_checks() {
+        let vms = [
+            vm("a", "running", "4G", "20G"),
+            vm("b", "stopped", "8G", "20G"),
+        ];
+        let running = Growth::of(Some(&vms[0]), true, Some("8G"), Some(4), Some("10G"));
+        assert_eq!(
+            running,
+            Growth {
+                memory: Some(8 * GIB),
+                cpus: Some(4),
+                disk: None
+            }
+        );
+        // A stopped VM's memory and vCPUs count when it starts.
+        let stopped = Growth::of(Some(&vms[1]), false, Some("16G"), Some(8), Some("40G"));
+        assert_eq!(
+            stopped,
+            Growth {
+                disk: Some(40 * GIB),
+                ..Default::default()
+            }
+        );
+
+        let limits = Limits {
+            max_memory: Some(10 * GIB),
+            max_disk: Some(50 * GIB),
+            ..Default::default()
+        };
+        let budget = admission::Budget {
+            total_mem_gb: 64,
+            total_cpu: 16,
+            total_disk_gb: 500,
+            reserve_mem_gb: 1,
+            reserve_cpu: 1,
+            reserve_disk_gb: 1,
+        };
+        assert!(check_grow(&limits, &budget, &vms, "a", &running).is_ok());
+        let more = Growth {
+            memory: Some(12 * GIB),
+            ..Default::default()
+        };
+        assert!(check_grow(&limits, &budget, &vms, "a", &more)
+            .unwrap_err()
+            .to_string()
+            .contains("MEDA_MAX_TOTAL_MEMORY"));
+        assert!(check_grow(&limits, &budget, &vms, "b", &stopped)
+            .unwrap_err()
+            .to_string()
+            .contains("MEDA_MAX_TOTAL_DISK"));
+
+        let small = admission::Budget {
+            total_cpu: 4,
+            ..budget
+        };
+        let err = check_grow(&Limits::default(), &small, &vms, "a", &running).unwrap_err();
+        assert!(matches!(err, Error::QuotaExceeded(_)));
+    }
+}
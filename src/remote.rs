@@ -0,0 +1,571 @@
+//! Commands run against a remote context's API instead of this host.
+//!
+//! While a context is active (see `context`), the commands the REST API
+//! covers are sent to it through `meda-client`, with the context's
+//! bearer token: `list`, `get`, `ip`, `console-dump`, `start`, `stop`,
+//! `restart`, `delete`, `create`, `port-forward`, `images`, `pull`,
+//! `push`, `tag`, `rmi`, `prune`, `create-image`, `run` and `capacity`.
+//! Anything else needs a shell on the host and fails with an error
+//! saying so.
+//!
+//! Globs, `--all` and `--filter` are resolved here against the server's
+//! VM list, then each VM is acted on with its own request. Paths given
+//! to `create` and `run` (`--user-data`, `--mount`, `--device`,
+//! `--kernel`) name files on the server; `--ssh-key` files are read
+//! here and sent as keys.
+
+use crate::batch;
+use crate::cli::{Commands, SystemCommands, VmSelection};
+use crate::context::Active;
+use crate::error::{Error, Result};
+use crate::output::{self, OutputFormat};
+use crate::{image, ssh, vm};
+use log::info;
+use meda_client::models::{
+    ImageCreateRequest, ImagePruneRequest, ImagePullRequest, ImagePushRequest, ImageRunRequest,
+    ImageTagRequest, PortForwardRequest, VmCreateRequest, VmResponse, VmStopQuery,
+};
+use reqwest::StatusCode;
+use std::future::Future;
+
+/// A context's API, with errors that say which context failed.
+#[derive(Clone)]
+struct Remote {
+    name: String,
+    url: String,
+    api: meda_client::Client,
+}
+
+impl Remote {
+    fn new(active: &Active) -> Result<Self> {
+        let mut api = meda_client::Client::new(&active.context.url)
+            .map_err(|e| Error::Other(format!("Context {}: {}", active.name, e)))?
+            .with_http_client(crate::proxy::client()?);
+        if let Some(token) = &active.context.token {
+            api = api.with_token(token);
+        }
+        Ok(Self {
+            name: active.name.clone(),
+            url: active.context.url.clone(),
+            api,
+        })
+    }
+
+    async fn call<T>(&self, request: impl Future<Output = meda_client::Result<T>>) -> Result<T> {
+        request.await.map_err(|e| self.error(e))
+    }
+
+    fn error(&self, error: meda_client::Error) -> Error {
+        match error {
+            meda_client::Error::Api {
+                status: StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN,
+                error,
+                ..
+            } => Error::Other(format!(
+                "{} (context {}); set its token with `meda context add {} --url {} --token-stdin`",
+                error, self.name, self.name, self.url
+            )),
+            error => Error::Other(format!("{} (context {})", error, self.name)),
+        }
+    }
+
+    async fn vms(&self) -> Result<Vec<vm::VmInfo>> {
+        let list = self.call(self.api.list_vms(&Default::default())).await?;
+        Ok(list.vms.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<meda_client::models::VmInfo> for vm::VmInfo {
+    fn from(info: meda_client::models::VmInfo) -> Self {
+        Self {
+            name: info.name,
+            state: info.state,
+            ip: info.ip,
+            vcpus: info.vcpus,
+            memory: info.memory,
+            disk: info.disk,
+            devices: info.devices,
+            created: info.created,
+            labels: info.labels,
+            env: info.env,
+            storage: info.storage,
+        }
+    }
+}
+
+impl From<meda_client::models::ImageInfo> for image::ImageInfo {
+    fn from(info: meda_client::models::ImageInfo) -> Self {
+        Self {
+            name: info.name,
+            tag: info.tag,
+            registry: info.registry,
+            size: info.size,
+            created: info.created,
+            digest: info.digest,
+        }
+    }
+}
+
+/// Print the server's result: the body with `--json`, else its
+/// message, logged as the local commands do.
+fn report(response: &VmResponse, json: bool) -> Result<()> {
+    if json {
+        print_json(response)
+    } else {
+        info!("{}", response.message);
+        Ok(())
+    }
+}
+
+fn print_json(value: &impl serde::Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn unsupported(what: &str) -> Error {
+    Error::Other(format!(
+        "{} isn't available against a remote context; run it on the host (or pass --context default)",
+        what
+    ))
+}
+
+#[derive(Clone, Copy)]
+enum VmAction {
+    Start,
+    Stop(Option<u64>, bool),
+    Delete,
+}
+
+impl VmAction {
+    fn verb(self) -> &'static str {
+        match self {
+            Self::Start => "start",
+            Self::Stop(..) => "stop",
+            Self::Delete => "delete",
+        }
+    }
+
+    async fn run(self, remote: &Remote, name: &str) -> Result<VmResponse> {
+        match self {
+            Self::Start => remote.call(remote.api.start_vm(name)).await,
+            Self::Stop(timeout, force) => {
+                let query = VmStopQuery { timeout, force };
+                remote.call(remote.api.stop_vm(name, &query)).await
+            }
+            Self::Delete => remote.call(remote.api.delete_vm(name)).await,
+        }
+    }
+}
+
+/// `action` on the VMs `vms` picks on the server: one VM as the local
+/// command would, several through `batch::run_each`.
+async fn each_vm(remote: &Remote, vms: &VmSelection, action: VmAction, json: bool) -> Result<()> {
+    let selection = vms.selection();
+    if let Some(name) = selection.single() {
+        return report(&action.run(remote, name).await?, json);
+    }
+    let names = selection.resolve_in(remote.vms().await?)?;
+    let remote = remote.clone();
+    batch::run_each(names, action.verb(), vms.parallel, json, move |name| {
+        let remote = remote.clone();
+        async move { action.run(&remote, &name).await.map(|_| ()) }
+    })
+    .await
+}
+
+/// Run `command` against `active`'s API.
+pub async fn run(active: &Active, command: Commands, json: bool) -> Result<()> {
+    let remote = Remote::new(active)?;
+    let api = &remote.api;
+    match command {
+        Commands::List { output, filter } => {
+            let vms = vm::filter_vms(remote.vms().await?, &filter);
+            match output {
+                Some(format) => output::print(&vms, &format)?,
+                None => vm::print_list(&vms, json)?,
+            }
+        }
+        Commands::Get { name, output } => {
+            let details = remote.call(api.get_vm(&name)).await?;
+            print_value(&serde_json::to_value(details)?, output.as_ref(), json)?;
+        }
+        Commands::Ip { name, check, .. } => {
+            if check {
+                return Err(unsupported("`meda ip --check`"));
+            }
+            let response = remote.call(api.get_vm_ip(&name)).await?;
+            if json {
+                print_json(&response)?;
+            } else {
+                println!("{}", response.ip);
+            }
+        }
+        Commands::ConsoleDump { name, tail } => {
+            let response = remote.call(api.get_vm_serial(&name, Some(tail))).await?;
+            if json {
+                print_json(&response)?;
+            } else {
+                println!("{}", response.serial);
+            }
+        }
+        Commands::Start { vms } => each_vm(&remote, &vms, VmAction::Start, json).await?,
+        Commands::Stop {
+            vms,
+            timeout,
+            force,
+        } => each_vm(&remote, &vms, VmAction::Stop(timeout, force), json).await?,
+        Commands::Restart {
+            name,
+            timeout,
+            force,
+        } => {
+            let query = VmStopQuery { timeout, force };
+            report(&remote.call(api.restart_vm(&name, &query)).await?, json)?;
+        }
+        Commands::Delete { vms, force } => {
+            vms.selection().confirm_delete(force)?;
+            each_vm(&remote, &vms, VmAction::Delete, json).await?;
+        }
+        Commands::Create {
+            name,
+            user_data,
+            ssh_key,
+            label,
+            env,
+            secret_env,
+            secret,
+            storage,
+            isolate,
+            egress,
+            egress_interface,
+            rng,
+            network,
+            bridge,
+            force,
+            memory,
+            cpus,
+            shared_memory,
+            hugepages,
+            prefault,
+            balloon,
+            cpu_affinity,
+            max_cpus,
+            max_memory,
+            disk,
+            device,
+            cow,
+            immutable_root,
+            data_disk,
+            data_mount,
+            mount,
+            vsock,
+            no_iso,
+            boot,
+        } => {
+            if cow {
+                return Err(unsupported("`meda create --cow`"));
+            }
+            let path = |p: Option<std::path::PathBuf>| p.map(|p| p.display().to_string());
+            let request = VmCreateRequest {
+                name,
+                user_data,
+                ssh_keys: ssh::resolve_extra_keys(&ssh_key)?,
+                labels: label.into_iter().collect(),
+                env: env.into_iter().collect(),
+                secret_env: secret_env.into_iter().collect(),
+                secrets: secret.iter().map(|s| s.to_string()).collect(),
+                storage,
+                isolate,
+                egress,
+                egress_interface,
+                rng: rng.map(|r| r.to_string()),
+                network,
+                bridge,
+                force,
+                memory,
+                cpus,
+                shared_memory,
+                hugepages,
+                prefault,
+                balloon,
+                no_iso,
+                cpu_affinity: cpu_affinity.map(|c| c.to_string()),
+                max_cpus,
+                max_memory,
+                disk,
+                devices: device,
+                immutable_root,
+                data_disk,
+                data_mount,
+                mounts: mount.iter().map(|m| m.to_string()).collect(),
+                vsock,
+                kernel: path(boot.kernel),
+                initramfs: path(boot.initramfs),
+                cmdline: boot.cmdline,
+            };
+            report(&remote.call(api.create_vm(&request)).await?, json)?;
+        }
+        Commands::PortForward {
+            name,
+            host_port,
+            guest_port,
+        } => {
+            let request = PortForwardRequest {
+                host_port,
+                guest_port,
+            };
+            report(&remote.call(api.port_forward(&name, &request)).await?, json)?;
+        }
+        Commands::Images { output, digests } => {
+            let images: Vec<image::ImageInfo> = remote
+                .call(api.list_images())
+                .await?
+                .images
+                .into_iter()
+                .map(Into::into)
+                .collect();
+            match output {
+                Some(format) => output::print(&images, &format)?,
+                None => image::print_list(&images, digests, json)?,
+            }
+        }
+        Commands::Pull {
+            mut images,
+            file,
+            registry,
+            org,
+            artifacts,
+            ..
+        } => {
+            if let Some(file) = file {
+                images.extend(image::read_image_list(&file)?);
+            }
+            if images.len() != 1 && !artifacts.is_empty() {
+                return Err(Error::Other(
+                    "--artifacts works with a single image".to_string(),
+                ));
+            }
+            for image in images {
+                let request = ImagePullRequest {
+                    image,
+                    registry: registry.clone(),
+                    org: org.clone(),
+                    artifacts: artifacts.clone(),
+                };
+                report(&remote.call(api.pull_image(&request)).await?, json)?;
+            }
+        }
+        Commands::Push {
+            name,
+            image,
+            registry,
+            dry_run,
+            provenance,
+            sign,
+            also_tag,
+        } => {
+            let request = ImagePushRequest {
+                name,
+                image,
+                registry,
+                dry_run,
+                provenance,
+                sign,
+                also_tags: also_tag,
+            };
+            report(&remote.call(api.push_image(&request)).await?, json)?;
+        }
+        Commands::Tag {
+            source,
+            target,
+            registry,
+            org,
+        } => {
+            let request = ImageTagRequest {
+                source,
+                target,
+                registry,
+                org,
+            };
+            report(&remote.call(api.tag_image(&request)).await?, json)?;
+        }
+        Commands::Rmi {
+            image,
+            registry,
+            org,
+            ..
+        } => {
+            if registry.is_some() || org.is_some() {
+                return Err(Error::Other(
+                    "Give the full image reference instead of --registry/--org with a remote context"
+                        .to_string(),
+                ));
+            }
+            report(&remote.call(api.remove_image(&image)).await?, json)?;
+        }
+        Commands::Prune {
+            all,
+            older_than,
+            force,
+        } => {
+            let request = ImagePruneRequest {
+                all,
+                older_than,
+                force,
+            };
+            let response = remote.call(api.prune_images(&request)).await?;
+            if json {
+                print_json(&response)?;
+            } else {
+                println!("{}", response.message);
+            }
+        }
+        Commands::CreateImage {
+            name,
+            tag,
+            registry,
+            org,
+            from_vm,
+            live,
+        } => {
+            let request = ImageCreateRequest {
+                name,
+                tag,
+                registry,
+                org,
+                from_vm,
+                live: live.map(|live| match live {
+                    image::LiveMode::Crash => meda_client::models::LiveMode::Crash,
+                    image::LiveMode::Freeze => meda_client::models::LiveMode::Freeze,
+                }),
+            };
+            report(&remote.call(api.create_image(&request)).await?, json)?;
+        }
+        Commands::Run {
+            image,
+            name,
+            registry,
+            org,
+            user_data,
+            ssh_key,
+            label,
+            env,
+            secret_env,
+            secret,
+            storage,
+            isolate,
+            egress,
+            egress_interface,
+            rng,
+            no_start,
+            memory,
+            cpus,
+            disk,
+            device,
+            cow,
+            immutable_root,
+            data_disk,
+            data_mount,
+            ttl,
+            cold,
+            ssh,
+            boot,
+        } => {
+            if cow || cold || ssh || boot.kernel.is_some() {
+                return Err(unsupported(
+                    "`meda run` with --cow, --cold, --ssh or --kernel",
+                ));
+            }
+            let request = ImageRunRequest {
+                image,
+                name,
+                registry,
+                org,
+                user_data,
+                ssh_keys: ssh::resolve_extra_keys(&ssh_key)?,
+                labels: label.into_iter().collect(),
+                env: env.into_iter().collect(),
+                secret_env: secret_env.into_iter().collect(),
+                secrets: secret.iter().map(|s| s.to_string()).collect(),
+                storage,
+                isolate,
+                egress,
+                egress_interface,
+                rng: rng.map(|r| r.to_string()),
+                no_start,
+                memory,
+                cpus,
+                disk,
+                devices: device,
+                immutable_root,
+                data_disk,
+                data_mount,
+                ttl: ttl.map(|secs| format!("{}s", secs)),
+            };
+            report(&remote.call(api.run_image(&request)).await?, json)?;
+        }
+        Commands::Capacity => print_json(&remote.call(api.get_capacity()).await?)?,
+        Commands::SystemInfo
+        | Commands::System {
+            command: SystemCommands::Host,
+        } => print_json(&remote.call(api.get_system_info()).await?)?,
+        _ => return Err(unsupported("This command")),
+    }
+    Ok(())
+}
+
+/// `meda get`'s output: the requested format, JSON, or a short summary
+/// followed by the details the server reported.
+fn print_value(value: &serde_json::Value, format: Option<&OutputFormat>, json: bool) -> Result<()> {
+    if let Some(format) = format {
+        return output::print(value, format);
+    }
+    if json {
+        return print_json(value);
+    }
+    for field in ["name", "state", "ip"] {
+        println!("{}: {}", field, value[field].as_str().unwrap_or("-"));
+    }
+    if let Some(details) = value.get("details").filter(|d| !d.is_null()) {
+        print_json(details)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use meda_client::models::ApiError;
+
+    #[test]
+    fn test_error() {
+        let remote = Remote::new(&Active {
+            name: "lab".to_string(),
+            context: Context {
+                url: "http://10.0.0.5:7777".to_string(),
+                token: None,
+            },
+        })
+        .unwrap();
+        let api_error = |status, error: &str| meda_client::Error::Api {
+            status,
+            error: ApiError {
+                error: error.to_string(),
+                code: "X".to_string(),
+                details: Some(serde_json::json!({"message": "VM web does not exist"})),
+            },
+            retry_after: None,
+        };
+        assert_eq!(
+            remote
+                .error(api_error(StatusCode::NOT_FOUND, "Failed to start VM"))
+                .to_string(),
+            "Failed to start VM: VM web does not exist (context lab)"
+        );
+        assert!(remote
+            .error(api_error(StatusCode::FORBIDDEN, "Invalid API token"))
+            .to_string()
+            .ends_with("set its token with `meda context add lab --url http://10.0.0.5:7777 --token-stdin`"));
+    }
+}
@@ -0,0 +1,548 @@
+//! Snapshot retention: keep a history of a VM's snapshots and prune it
+//! by policy (`meda snapshot policy set`, `meda snapshot gc`).
+//!
+//! Without a policy a VM has one snapshot, `snapshot/`, replaced by each
+//! `meda snapshot`. With one, `meda snapshot` also copies the root disk
+//! into the snapshot while the VM is paused, so the snapshot is a
+//! complete restore point, and moves the one it replaces to
+//! `snapshots/<id>/` instead of deleting it. `meda restore --snapshot
+//! <id>` brings one of those back.
+//!
+//! `gc` keeps the newest `keep_last` snapshots, plus the newest one of
+//! each of the last `keep_daily` days, `keep_weekly` ISO weeks and
+//! `keep_monthly` months that have one (in UTC), like restic's `forget`.
+//! The current `snapshot/` is always kept: restore and clone use it.
+
+use crate::config::{Config, DiskFormat};
+use crate::error::{Error, Result};
+use crate::snapshot::{dir_size, SNAPSHOT_DIR};
+use crate::vm;
+use chrono::{DateTime, Datelike, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File in a VM dir with its retention policy.
+pub const POLICY_FILE: &str = "snapshot-policy.json";
+
+/// Directory in a VM dir with the snapshots `snapshot/` replaced.
+pub const HISTORY_DIR: &str = "snapshots";
+
+/// File in a snapshot dir with the time it was taken.
+const CREATED_FILE: &str = "created";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Policy {
+    #[serde(default)]
+    pub keep_last: u32,
+    #[serde(default)]
+    pub keep_daily: u32,
+    #[serde(default)]
+    pub keep_weekly: u32,
+    #[serde(default)]
+    pub keep_monthly: u32,
+}
+
+impl Policy {
+    pub fn load(vm_dir: &Path) -> Option<Self> {
+        let body = fs::read_to_string(vm_dir.join(POLICY_FILE)).ok()?;
+        serde_json::from_str(&body).ok()
+    }
+
+    fn save(&self, vm_dir: &Path) -> Result<()> {
+        fs::write(
+            vm_dir.join(POLICY_FILE),
+            serde_json::to_string_pretty(self)?,
+        )?;
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Which of `taken`, newest first, the policy keeps.
+    fn select(&self, taken: &[DateTime<Utc>]) -> Vec<bool> {
+        let mut keep = vec![false; taken.len()];
+        for k in keep.iter_mut().take(self.keep_last as usize) {
+            *k = true;
+        }
+        let buckets: [(u32, Period); 3] = [
+            (self.keep_daily, |t| (t.year(), t.ordinal())),
+            (self.keep_weekly, |t| {
+                let week = t.iso_week();
+                (week.year(), week.week())
+            }),
+            (self.keep_monthly, |t| (t.year(), t.month())),
+        ];
+        for (count, bucket) in buckets {
+            let mut left = count;
+            let mut last = None;
+            for (i, time) in taken.iter().enumerate() {
+                if left == 0 {
+                    break;
+                }
+                let key = bucket(time);
+                if last != Some(key) {
+                    keep[i] = true;
+                    last = Some(key);
+                    left -= 1;
+                }
+            }
+        }
+        keep
+    }
+}
+
+/// The day, week or month a snapshot falls in, as (year, n).
+type Period = fn(&DateTime<Utc>) -> (i32, u32);
+
+/// One of a VM's snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Taken {
+    id: String,
+    time: DateTime<Utc>,
+    dir: PathBuf,
+}
+
+fn id(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// When the snapshot in `dir` was taken: its `created` file, or for
+/// snapshots from before retention, when CH wrote its config.
+fn taken_at(dir: &Path) -> Option<DateTime<Utc>> {
+    if let Ok(body) = fs::read_to_string(dir.join(CREATED_FILE)) {
+        if let Ok(time) = DateTime::parse_from_rfc3339(body.trim()) {
+            return Some(time.with_timezone(&Utc));
+        }
+    }
+    fs::metadata(dir.join("config.json"))
+        .and_then(|m| m.modified())
+        .ok()
+        .map(DateTime::<Utc>::from)
+}
+
+/// The VM's retained snapshots, newest first; not the current one.
+fn history(vm_dir: &Path) -> Vec<Taken> {
+    let mut taken: Vec<Taken> = fs::read_dir(vm_dir.join(HISTORY_DIR))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|e| e.path().is_dir())
+        .filter_map(|e| {
+            let dir = e.path();
+            Some(Taken {
+                id: e.file_name().into_string().ok()?,
+                time: taken_at(&dir)?,
+                dir,
+            })
+        })
+        .collect();
+    taken.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| b.id.cmp(&a.id)));
+    taken
+}
+
+/// Called by `meda snapshot` before it writes a new `snapshot/`: with a
+/// policy, the current one moves into the history; without, it goes.
+pub fn replace_current(vm_dir: &Path) -> Result<()> {
+    let current = vm_dir.join(SNAPSHOT_DIR);
+    if !current.exists() {
+        return Ok(());
+    }
+    match (Policy::load(vm_dir), taken_at(&current)) {
+        (Some(_), Some(time)) => {
+            fs::create_dir_all(vm_dir.join(HISTORY_DIR))?;
+            let mut dest = vm_dir.join(HISTORY_DIR).join(id(time));
+            // Two snapshots within a second.
+            let mut n = 1;
+            while dest.exists() {
+                dest = vm_dir.join(HISTORY_DIR).join(format!("{}-{}", id(time), n));
+                n += 1;
+            }
+            fs::rename(&current, &dest)?;
+        }
+        // ch-remote refuses to write into a non-empty destination.
+        _ => fs::remove_dir_all(&current)?,
+    }
+    Ok(())
+}
+
+/// Called by `meda snapshot` while the VM is paused, after CH wrote
+/// `snap_dir`: record the time and, with a policy, copy the root disk.
+pub async fn complete(vm_dir: &Path, snap_dir: &Path) -> Result<()> {
+    fs::write(snap_dir.join(CREATED_FILE), Utc::now().to_rfc3339())?;
+    if Policy::load(vm_dir).is_none() {
+        return Ok(());
+    }
+    let Some((rootfs, format)) = DiskFormat::detect(vm_dir) else {
+        return Ok(());
+    };
+    copy_disk(&rootfs, &snap_dir.join(format.rootfs_name())).await
+}
+
+async fn copy_disk(src: &Path, dest: &Path) -> Result<()> {
+    crate::util::run_command(
+        "cp",
+        &[
+            "--sparse=always",
+            "--reflink=auto",
+            src.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ],
+    )
+    .await
+}
+
+/// Make retained snapshot `id` of stopped VM `name` current, with its
+/// root disk, and keep the current one in the history; `meda restore`
+/// then resumes it.
+pub async fn promote(config: &Config, name: &str, id: &str) -> Result<()> {
+    let _lock = vm::lock(config, name, "restore")?;
+    let vm_dir = config.vm_dir(name);
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+    if vm::check_vm_running(config, name)? {
+        return Err(Error::VmAlreadyRunning(name.to_string()));
+    }
+    promote_in(&vm_dir, id).await
+}
+
+async fn promote_in(vm_dir: &Path, id: &str) -> Result<()> {
+    let chosen = history(vm_dir)
+        .into_iter()
+        .find(|t| t.id == id)
+        .ok_or_else(|| {
+            Error::Other(format!(
+                "no snapshot '{}'; `meda snapshot list <vm>` shows them",
+                id
+            ))
+        })?;
+    let Some((rootfs, format)) = DiskFormat::detect(vm_dir) else {
+        return Err(Error::Other(format!("{} has no rootfs", vm_dir.display())));
+    };
+    let disk = chosen.dir.join(format.rootfs_name());
+    if !disk.exists() {
+        return Err(Error::Other(format!(
+            "snapshot '{}' has no copy of the root disk to restore",
+            id
+        )));
+    }
+    replace_current(vm_dir)?;
+    fs::rename(&chosen.dir, vm_dir.join(SNAPSHOT_DIR))?;
+    copy_disk(
+        &vm_dir.join(SNAPSHOT_DIR).join(format.rootfs_name()),
+        &rootfs,
+    )
+    .await
+}
+
+#[derive(Debug, Serialize)]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub taken_at: DateTime<Utc>,
+    pub size_bytes: u64,
+    pub current: bool,
+}
+
+/// `meda snapshot list <vm>`.
+pub fn list(config: &Config, name: &str, json: bool) -> Result<()> {
+    let vm_dir = config.vm_dir(name);
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+    let current = vm_dir.join(SNAPSHOT_DIR);
+    let mut rows = Vec::new();
+    if let Some(time) = taken_at(&current) {
+        rows.push(SnapshotInfo {
+            id: id(time),
+            taken_at: time,
+            size_bytes: dir_size(&current).unwrap_or(0),
+            current: true,
+        });
+    }
+    rows.extend(history(&vm_dir).into_iter().map(|t| SnapshotInfo {
+        size_bytes: dir_size(&t.dir).unwrap_or(0),
+        id: t.id,
+        taken_at: t.time,
+        current: false,
+    }));
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&rows)?);
+    } else if rows.is_empty() {
+        println!("(no snapshots — run `meda snapshot {}` first)", name);
+    } else {
+        println!("{:<20} {:<20} {:>10}", "ID", "TAKEN", "SIZE");
+        for row in rows {
+            println!(
+                "{:<20} {:<20} {:>10}{}",
+                row.id,
+                row.taken_at.format("%Y-%m-%d %H:%M:%S"),
+                crate::stats::human_bytes(Some(row.size_bytes)),
+                if row.current { "  (current)" } else { "" }
+            );
+        }
+    }
+    Ok(())
+}
+
+/// `meda snapshot policy set`; an all-zero policy removes it, and with
+/// it the history (`gc` would no longer prune it).
+pub fn set_policy(config: &Config, name: &str, policy: Policy, json: bool) -> Result<()> {
+    let _lock = vm::lock(config, name, "snapshot policy")?;
+    let vm_dir = config.vm_dir(name);
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+    if policy.is_empty() {
+        fs::remove_file(vm_dir.join(POLICY_FILE)).ok();
+        if vm_dir.join(HISTORY_DIR).exists() {
+            fs::remove_dir_all(vm_dir.join(HISTORY_DIR))?;
+        }
+    } else {
+        policy.save(&vm_dir)?;
+    }
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "vm": name,
+                "policy": (!policy.is_empty()).then_some(policy),
+            }))?
+        );
+    } else if policy.is_empty() {
+        info!("Removed the snapshot retention policy of VM {}", name);
+    } else {
+        info!(
+            "VM {} keeps its last {}, {} daily, {} weekly and {} monthly snapshots",
+            name, policy.keep_last, policy.keep_daily, policy.keep_weekly, policy.keep_monthly
+        );
+    }
+    Ok(())
+}
+
+/// `meda snapshot policy show`.
+pub fn show_policy(config: &Config, name: &str, json: bool) -> Result<()> {
+    let vm_dir = config.vm_dir(name);
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+    let policy = Policy::load(&vm_dir);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&policy)?);
+    } else {
+        match policy {
+            Some(p) => println!(
+                "keep-last: {}\nkeep-daily: {}\nkeep-weekly: {}\nkeep-monthly: {}",
+                p.keep_last, p.keep_daily, p.keep_weekly, p.keep_monthly
+            ),
+            None => println!("VM {} has no snapshot retention policy", name),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct GcResult {
+    pub vm: String,
+    pub kept: Vec<String>,
+    pub removed: Vec<String>,
+    pub reclaimed_bytes: u64,
+}
+
+/// Prune the history of VM `name`, or of every VM with a policy.
+/// `dry_run` only reports what would go.
+pub fn gc(config: &Config, name: Option<&str>, dry_run: bool, json: bool) -> Result<()> {
+    let names = match name {
+        Some(name) if !config.vm_dir(name).exists() => {
+            return Err(Error::VmNotFound(name.to_string()))
+        }
+        Some(name) => vec![name.to_string()],
+        None => crate::vm::vm_names(config),
+    };
+
+    let mut results = Vec::new();
+    for name in names {
+        let vm_dir = config.vm_dir(&name);
+        let Some(policy) = Policy::load(&vm_dir) else {
+            continue;
+        };
+        let _lock = match vm::lock(config, &name, "snapshot gc") {
+            Ok(lock) => lock,
+            Err(e) => {
+                warn!("Skipping VM {}: {}", name, e);
+                continue;
+            }
+        };
+        let history = history(&vm_dir);
+        let mut taken: Vec<DateTime<Utc>> = Vec::new();
+        // The current snapshot counts towards the policy.
+        taken.extend(taken_at(&vm_dir.join(SNAPSHOT_DIR)));
+        let offset = taken.len();
+        taken.extend(history.iter().map(|t| t.time));
+        let keep = policy.select(&taken);
+
+        let mut result = GcResult {
+            vm: name.clone(),
+            kept: Vec::new(),
+            removed: Vec::new(),
+            reclaimed_bytes: 0,
+        };
+        for (t, keep) in history.iter().zip(&keep[offset..]) {
+            if *keep {
+                result.kept.push(t.id.clone());
+                continue;
+            }
+            let size = dir_size(&t.dir).unwrap_or(0);
+            if !dry_run {
+                fs::remove_dir_all(&t.dir)?;
+            }
+            result.removed.push(t.id.clone());
+            result.reclaimed_bytes += size;
+        }
+        results.push(result);
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+        return Ok(());
+    }
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    for result in &results {
+        info!(
+            "VM {}: {} {} snapshot(s) ({}), kept {}",
+            result.vm,
+            verb,
+            result.removed.len(),
+            crate::stats::human_bytes(Some(result.reclaimed_bytes)),
+            result.kept.len()
+        );
+    }
+    let total: u64 = results.iter().map(|r| r.reclaimed_bytes).sum();
+    info!(
+        "{} {} in total",
+        if dry_run {
+            "Would reclaim"
+        } else {
+            "Reclaimed"
+        },
+        crate::stats::human_bytes(Some(total))
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use tempfile::TempDir;
+
+    fn at(y: i32, m: u32, d: u32, h: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_select() {
+        // Newest first: two a day, 2026-03-02 (a Monday) back to 02-26.
+        let taken: Vec<_> = [(3, 2), (3, 1), (2, 28), (2, 27), (2, 26)]
+            .into_iter()
+            .flat_map(|(m, d)| [at(2026, m, d, 18), at(2026, m, d, 12)])
+            .collect();
+
+        let kept = |policy: Policy| -> Vec<usize> {
+            let keep = policy.select(&taken);
+            (0..taken.len()).filter(|i| keep[*i]).collect()
+        };
+        assert_eq!(kept(Policy::default()), Vec::<usize>::new());
+        assert_eq!(
+            kept(Policy {
+                keep_last: 3,
+                ..Default::default()
+            }),
+            vec![0, 1, 2]
+        );
+        // The newest of each of the last three days.
+        assert_eq!(
+            kept(Policy {
+                keep_daily: 3,
+                ..Default::default()
+            }),
+            vec![0, 2, 4]
+        );
+        // 2026-03-02 starts ISO week 10; the rest are week 9.
+        let weekly = Policy {
+            keep_weekly: 4,
+            ..Default::default()
+        };
+        assert_eq!(kept(weekly), vec![0, 2]);
+        let policy = Policy {
+            keep_last: 1,
+            keep_daily: 2,
+            keep_monthly: 2,
+            ..Default::default()
+        };
+        assert_eq!(kept(policy), vec![0, 2, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_history_and_gc() {
+        let dir = TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.vm_root = dir.path().to_path_buf();
+        let vm_dir = config.vm_dir("web");
+        let snapshot = |time: DateTime<Utc>| {
+            let current = vm_dir.join(SNAPSHOT_DIR);
+            replace_current(&vm_dir).unwrap();
+            fs::create_dir_all(&current).unwrap();
+            fs::write(current.join("memory-ranges"), vec![0u8; 100]).unwrap();
+            fs::write(current.join(CREATED_FILE), time.to_rfc3339()).unwrap();
+        };
+        fs::create_dir_all(&vm_dir).unwrap();
+
+        // No policy: each snapshot replaces the last.
+        snapshot(at(2026, 3, 1, 12));
+        snapshot(at(2026, 3, 2, 12));
+        assert!(history(&vm_dir).is_empty());
+
+        set_policy(
+            &config,
+            "web",
+            Policy {
+                keep_daily: 2,
+                ..Default::default()
+            },
+            true,
+        )
+        .unwrap();
+        snapshot(at(2026, 3, 3, 12));
+        snapshot(at(2026, 3, 3, 18));
+        snapshot(at(2026, 3, 4, 12));
+        let ids: Vec<_> = history(&vm_dir).into_iter().map(|t| t.id).collect();
+        assert_eq!(
+            ids,
+            vec!["20260303T180000Z", "20260303T120000Z", "20260302T120000Z"]
+        );
+
+        // Current (03-04) and the newest of 03-03 stay.
+        gc(&config, Some("web"), true, true).unwrap();
+        assert_eq!(history(&vm_dir).len(), 3);
+        gc(&config, None, false, true).unwrap();
+        let ids: Vec<_> = history(&vm_dir).into_iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec!["20260303T180000Z"]);
+        assert!(vm_dir.join(SNAPSHOT_DIR).join("memory-ranges").exists());
+
+        // Promoting needs the root disk copy.
+        fs::write(vm_dir.join("rootfs.raw"), b"now").unwrap();
+        assert!(promote_in(&vm_dir, "20260303T180000Z").await.is_err());
+        assert!(promote_in(&vm_dir, "nope").await.is_err());
+        let kept = vm_dir.join(HISTORY_DIR).join("20260303T180000Z");
+        fs::write(kept.join("rootfs.raw"), b"then").unwrap();
+        promote_in(&vm_dir, "20260303T180000Z").await.unwrap();
+        assert_eq!(fs::read(vm_dir.join("rootfs.raw")).unwrap(), b"then");
+        let ids: Vec<_> = history(&vm_dir).into_iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec!["20260304T120000Z"]);
+    }
+}
@@ -0,0 +1,248 @@
+//! Where a VM's virtio-rng device gets its entropy.
+//!
+//! Cloud Hypervisor feeds the guest's `/dev/hwrng` from a host file,
+//! `/dev/urandom` by default. `MEDA_RNG_SOURCE` (or `--rng` per VM)
+//! picks another device, e.g. `/dev/hwrng` on hosts with a hardware
+//! RNG, or `none` for no rng device at all — for hardened hosts that
+//! don't let the hypervisor read random devices, and guests that bring
+//! their own entropy. The choice is recorded in the VM's `rng` file.
+//!
+//! A guest only benefits from the device if its kernel has the
+//! virtio-rng driver; `meda doctor` asks running guests whether it is
+//! bound, and checks the host side: the source exists and the kernel's
+//! entropy pool isn't starved.
+
+use crate::config::Config;
+use crate::doctor::{Finding, Status};
+use crate::error::Result;
+use nix::unistd::{access, AccessFlags};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Per-VM entropy source: a device path, or `none`.
+pub const RNG_FILE: &str = "rng";
+
+pub const DEFAULT_SOURCE: &str = "/dev/urandom";
+
+/// Below this many bits in the host's pool, reads from `/dev/random`
+/// style sources can stall. Kernels since 5.18 always report 256.
+const LOW_ENTROPY_BITS: u64 = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RngSource {
+    Device(PathBuf),
+    Disabled,
+}
+
+impl Default for RngSource {
+    fn default() -> Self {
+        Self::Device(PathBuf::from(DEFAULT_SOURCE))
+    }
+}
+
+impl fmt::Display for RngSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Device(path) => write!(f, "{}", path.display()),
+            Self::Disabled => write!(f, "none"),
+        }
+    }
+}
+
+impl RngSource {
+    /// `none`/`off`, or an absolute path. Paths go into the start
+    /// script, so only plain path characters are allowed.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let s = s.trim();
+        if matches!(s, "none" | "off") {
+            return Ok(Self::Disabled);
+        }
+        let valid = s.starts_with('/')
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '-' | '_' | '.'));
+        if valid {
+            Ok(Self::Device(PathBuf::from(s)))
+        } else {
+            Err(format!(
+                "invalid RNG source '{}' (expected an absolute device path such as {} or none)",
+                s, DEFAULT_SOURCE
+            ))
+        }
+    }
+
+    /// Source of the VM in `vm_dir`. VMs from before the choice was
+    /// recorded use the old fixed `/dev/urandom`.
+    pub fn load(vm_dir: &Path) -> Self {
+        fs::read_to_string(vm_dir.join(RNG_FILE))
+            .ok()
+            .and_then(|s| Self::parse(&s).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, vm_dir: &Path) -> Result<()> {
+        fs::write(vm_dir.join(RNG_FILE), self.to_string())?;
+        Ok(())
+    }
+
+    /// Cloud Hypervisor arguments for the device, if there is one.
+    pub fn ch_args(&self) -> Vec<String> {
+        match self {
+            Self::Device(path) => vec!["--rng".to_string(), format!("src={}", path.display())],
+            Self::Disabled => Vec::new(),
+        }
+    }
+
+    /// Can the hypervisor read this source?
+    pub fn host_finding(&self) -> Finding {
+        let Self::Device(path) = self else {
+            return Finding::new(
+                "rng",
+                Status::Ok,
+                "no virtio-rng device; guests rely on their own entropy",
+            );
+        };
+        if !path.exists() {
+            return Finding::new(
+                "rng",
+                Status::Fail,
+                format!(
+                    "RNG source {} does not exist; set MEDA_RNG_SOURCE (or --rng) to another device, or to none",
+                    path.display()
+                ),
+            );
+        }
+        if access(path, AccessFlags::R_OK).is_err() {
+            // CH runs under sudo, so this only bites when running it by hand.
+            return Finding::new(
+                "rng",
+                Status::Warn,
+                format!(
+                    "RNG source {} is not readable by this user (cloud-hypervisor runs with sudo, so VMs still start)",
+                    path.display()
+                ),
+            );
+        }
+        Finding::new(
+            "rng",
+            Status::Ok,
+            format!("guests get entropy from {}", path.display()),
+        )
+    }
+}
+
+/// The host kernel's entropy estimate.
+pub fn entropy_finding() -> Finding {
+    let available = fs::read_to_string("/proc/sys/kernel/random/entropy_avail")
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok());
+    entropy(available)
+}
+
+fn entropy(available: Option<u64>) -> Finding {
+    match available {
+        None => Finding::new(
+            "entropy",
+            Status::Warn,
+            "could not read /proc/sys/kernel/random/entropy_avail",
+        ),
+        Some(bits) if bits < LOW_ENTROPY_BITS => Finding::new(
+            "entropy",
+            Status::Warn,
+            format!(
+                "host entropy pool is low ({} bits); guests booting off it may stall, install rng-tools or haveged",
+                bits
+            ),
+        ),
+        Some(bits) => Finding::new(
+            "entropy",
+            Status::Ok,
+            format!("{} bits of host entropy available", bits),
+        ),
+    }
+}
+
+/// Ask running VMs with an rng device whether their kernel bound the
+/// virtio-rng driver to it. VMs that can't be reached over SSH are
+/// skipped.
+pub fn guest_findings(config: &Config) -> Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    for vm in crate::vm::collect_vms(config)? {
+        if vm.state != "running" || RngSource::load(&config.vm_dir(&vm.name)) == RngSource::Disabled
+        {
+            continue;
+        }
+        let Ok(ip) = crate::vm::get_routable_ip(config, &vm.name) else {
+            continue;
+        };
+        let Ok(available) = crate::ssh::guest_output(
+            config,
+            &ip,
+            "cat /sys/class/misc/hw_random/rng_available 2>/dev/null || true",
+        ) else {
+            continue;
+        };
+        findings.push(guest(&vm.name, &available));
+    }
+    Ok(findings)
+}
+
+fn guest(name: &str, rng_available: &str) -> Finding {
+    if rng_available.contains("virtio_rng") {
+        Finding::new(
+            "rng",
+            Status::Ok,
+            format!("{}: guest uses the virtio-rng device", name),
+        )
+    } else {
+        Finding::new(
+            "rng",
+            Status::Warn,
+            format!(
+                "{}: guest kernel has no virtio-rng driver, so the device is unused; load virtio_rng or use an image built with CONFIG_HW_RANDOM_VIRTIO",
+                name
+            ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_args() {
+        assert_eq!(RngSource::parse("none").unwrap(), RngSource::Disabled);
+        assert_eq!(
+            RngSource::parse("/dev/hwrng").unwrap(),
+            RngSource::Device(PathBuf::from("/dev/hwrng"))
+        );
+        assert!(RngSource::parse("dev/urandom").is_err());
+        assert!(RngSource::parse("/dev/x' ; reboot").is_err());
+
+        assert_eq!(
+            RngSource::default().ch_args(),
+            ["--rng", "src=/dev/urandom"]
+        );
+        assert!(RngSource::Disabled.ch_args().is_empty());
+
+        let dir = tempfile::TempDir::new().unwrap();
+        assert_eq!(RngSource::load(dir.path()), RngSource::default());
+        RngSource::Disabled.save(dir.path()).unwrap();
+        assert_eq!(RngSource::load(dir.path()), RngSource::Disabled);
+    }
+
+    #[test]
+    fn test_findings() {
+        let missing = RngSource::Device(PathBuf::from("/nonexistent/rng")).host_finding();
+        assert_eq!(missing.status, Status::Fail);
+        assert!(missing.message.contains("MEDA_RNG_SOURCE"));
+        assert_eq!(RngSource::Disabled.host_finding().status, Status::Ok);
+
+        assert_eq!(entropy(Some(40)).status, Status::Warn);
+        assert_eq!(entropy(Some(256)).status, Status::Ok);
+
+        assert_eq!(guest("a", "virtio_rng.0 \n").status, Status::Ok);
+        assert_eq!(guest("a", "").status, Status::Warn);
+    }
+}
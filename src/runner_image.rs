@@ -0,0 +1,305 @@
+//! `meda runner-image build|update` — GitHub-runner-ready Ubuntu images
+//! from a recipe that ships with meda.
+//!
+//! The recipe (`runner_image/user-data.yaml`) is cloud-init user-data
+//! for a throwaway build VM: it installs Docker, the runner agent and
+//! build tools, wipes the VM's identity and powers it off. meda waits
+//! for the power-off, turns the VM's disk into an image and deletes the
+//! VM. The image gets meda's default user-data rather than the recipe,
+//! so VMs run from it boot normally and `meda ssh` can log in. Images
+//! are tagged `<runner version>-r<recipe revision>`, so a tag
+//! always names the same recipe and runner agent and cirun can pin it;
+//! `<name>:latest` follows the newest build.
+//!
+//! If the build script fails the VM never powers off. meda gives up
+//! after `--timeout-minutes` and leaves the VM for inspection.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::image::{self, ImageRef};
+use crate::vm;
+use log::info;
+use std::fs;
+use std::time::{Duration, Instant};
+
+const RECIPE: &str = include_str!("runner_image/user-data.yaml");
+
+/// Bump on every change to the recipe.
+pub const RECIPE_REVISION: u32 = 2;
+
+/// actions/runner release installed unless `--runner-version` says otherwise.
+pub const DEFAULT_RUNNER_VERSION: &str = "2.328.0";
+
+/// Builds need room for Docker and toolchains on top of the base image.
+const BUILD_MEMORY: &str = "4G";
+const BUILD_CPUS: u8 = 2;
+const BUILD_DISK: &str = "30G";
+
+pub struct RunnerImage {
+    pub name: String,
+    pub runner_version: String,
+    pub registry: String,
+    pub org: String,
+}
+
+impl RunnerImage {
+    pub fn tag(&self) -> String {
+        format!("{}-r{}", self.runner_version, RECIPE_REVISION)
+    }
+
+    fn image_ref(&self, tag: &str) -> ImageRef {
+        ImageRef {
+            registry: self.registry.clone(),
+            org: self.org.clone(),
+            name: self.name.clone(),
+            tag: tag.to_string(),
+            digest: None,
+        }
+    }
+
+    fn exists(&self, config: &Config) -> bool {
+        self.image_ref(&self.tag())
+            .local_dir(config)
+            .join("manifest.json")
+            .exists()
+    }
+
+    fn user_data(&self) -> String {
+        RECIPE
+            .replace("{{RUNNER_VERSION}}", &self.runner_version)
+            .replace("{{RECIPE_REVISION}}", &RECIPE_REVISION.to_string())
+    }
+}
+
+/// Runner versions end up in a download URL and an image tag.
+pub fn parse_runner_version(s: &str) -> std::result::Result<String, String> {
+    let s = s.trim_start_matches('v');
+    let parts: Vec<&str> = s.split('.').collect();
+    if parts.len() == 3 && parts.iter().all(|p| p.parse::<u32>().is_ok()) {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "invalid runner version '{}' (expected e.g. {})",
+            s, DEFAULT_RUNNER_VERSION
+        ))
+    }
+}
+
+/// Build the image in a throwaway VM. Fails if the tag exists unless
+/// `force`.
+pub async fn build(
+    config: &Config,
+    runner: &RunnerImage,
+    timeout: Duration,
+    force: bool,
+    json: bool,
+) -> Result<()> {
+    let tag = runner.tag();
+    let target = runner.image_ref(&tag);
+    if runner.exists(config) && !force {
+        return Err(Error::Other(format!(
+            "{} already exists; pass --force to rebuild it",
+            target.url()
+        )));
+    }
+
+    let build_vm = format!("runner-image-build-{}", std::process::id());
+    if !json {
+        info!(
+            "Building {} (runner {}, recipe r{}) in VM {}",
+            target.url(),
+            runner.runner_version,
+            RECIPE_REVISION,
+            build_vm
+        );
+    }
+
+    let user_data = config.asset_dir.join(format!("{}.user-data", build_vm));
+    fs::write(&user_data, runner.user_data())?;
+    let resources = vm::VmResources::from_config_with_overrides(
+        config,
+        Some(BUILD_MEMORY),
+        Some(BUILD_CPUS),
+        Some(BUILD_DISK),
+        Vec::new(),
+    );
+    let created = vm::create(
+        config,
+        &build_vm,
+        user_data.to_str(),
+        &[],
+        &Default::default(),
+        &resources,
+        json,
+    )
+    .await;
+    fs::remove_file(&user_data).ok();
+    created?;
+
+    vm::start(config, &build_vm, json).await?;
+    if !json {
+        info!("Waiting for the recipe to finish and power the VM off");
+    }
+    let started = Instant::now();
+    while vm::check_vm_running(config, &build_vm)? {
+        if started.elapsed() > timeout {
+            return Err(Error::Other(format!(
+                "runner image build did not finish within {}s; VM {} is left running for inspection (`meda console {}`, then `meda delete {}`)",
+                timeout.as_secs(),
+                build_vm,
+                build_vm,
+                build_vm
+            )));
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+
+    if runner.exists(config) {
+        image::remove(
+            config,
+            &format!("{}:{}", runner.name, tag),
+            Some(&runner.registry),
+            Some(&runner.org),
+            true,
+            json,
+        )
+        .await?;
+    }
+    reset_user_data(config, &config.vm_dir(&build_vm))?;
+    image::create_from_vm(
+        config,
+        &build_vm,
+        &runner.name,
+        &tag,
+        &runner.registry,
+        &runner.org,
+        None,
+        json,
+    )
+    .await?;
+    vm::delete(config, &build_vm, json).await?;
+    tag_latest(config, runner, json).await?;
+
+    if !json {
+        info!(
+            "Built {}; push it with `meda push {} <registry>/<org>/{}:{}`",
+            target.url(),
+            runner.name,
+            runner.name,
+            tag
+        );
+    }
+    Ok(())
+}
+
+/// Swap the build VM's recipe for meda's default user-data before the
+/// image is made from it. The image keeps the VM's user-data, and every
+/// VM run from it would otherwise rerun the build and power off, with
+/// no user `meda ssh` can log in as.
+fn reset_user_data(config: &Config, vm_dir: &std::path::Path) -> Result<()> {
+    let keypair = crate::ssh::ensure_ssh_keypair(config)?;
+    fs::write(
+        vm_dir.join("user-data"),
+        crate::ssh::default_user_data(&keypair.public_key, &[]),
+    )?;
+    Ok(())
+}
+
+/// Build the image for the current recipe and runner version unless it
+/// is already there.
+pub async fn update(
+    config: &Config,
+    runner: &RunnerImage,
+    timeout: Duration,
+    json: bool,
+) -> Result<()> {
+    if runner.exists(config) {
+        if !json {
+            info!("{} is up to date", runner.image_ref(&runner.tag()).url());
+        }
+        return tag_latest(config, runner, json).await;
+    }
+    build(config, runner, timeout, false, json).await
+}
+
+async fn tag_latest(config: &Config, runner: &RunnerImage, json: bool) -> Result<()> {
+    let latest = format!("{}:latest", runner.name);
+    if runner.image_ref("latest").local_dir(config).exists() {
+        image::remove(
+            config,
+            &latest,
+            Some(&runner.registry),
+            Some(&runner.org),
+            true,
+            json,
+        )
+        .await?;
+    }
+    image::tag(
+        config,
+        &format!("{}:{}", runner.name, runner.tag()),
+        &latest,
+        Some(&runner.registry),
+        Some(&runner.org),
+        json,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runner(version: &str) -> RunnerImage {
+        RunnerImage {
+            name: "runner".to_string(),
+            runner_version: version.to_string(),
+            registry: "ghcr.io".to_string(),
+            org: "cirunlabs".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_tag_and_user_data() {
+        let runner = runner("2.330.1");
+        assert_eq!(runner.tag(), format!("2.330.1-r{}", RECIPE_REVISION));
+
+        let user_data = runner.user_data();
+        assert!(user_data.starts_with("#cloud-config\n"));
+        assert!(user_data.contains("/v2.330.1/actions-runner-linux-x64-2.330.1.tar.gz"));
+        assert!(user_data.contains("cloud-init clean"));
+        assert!(user_data
+            .trim_end()
+            .ends_with("[/usr/local/sbin/meda-runner-image-build]"));
+        assert!(!user_data.contains("{{"));
+    }
+
+    #[test]
+    fn test_reset_user_data() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.ch_home = dir.path().to_path_buf();
+        fs::create_dir_all(config.ssh_dir()).unwrap();
+        fs::write(config.ssh_dir().join("id_ed25519"), "").unwrap();
+        let key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMeda meda@localhost";
+        fs::write(config.ssh_dir().join("id_ed25519.pub"), key).unwrap();
+        let vm_dir = dir.path().join("vm");
+        fs::create_dir_all(&vm_dir).unwrap();
+        fs::write(vm_dir.join("user-data"), runner("2.330.1").user_data()).unwrap();
+
+        // What `create_from_vm` copies into the image as its user-data.
+        reset_user_data(&config, &vm_dir).unwrap();
+        let user_data = fs::read_to_string(vm_dir.join("user-data")).unwrap();
+        assert_eq!(user_data, crate::ssh::default_user_data(key, &[]));
+        assert!(user_data.contains("  - name: cirun\n"));
+        assert!(user_data.contains(key));
+        assert!(!user_data.contains("meda-runner-image-build"));
+        assert!(!user_data.contains("poweroff"));
+    }
+
+    #[test]
+    fn test_parse_runner_version() {
+        assert_eq!(parse_runner_version("v2.328.0").unwrap(), "2.328.0");
+        assert!(parse_runner_version("latest").is_err());
+        assert!(parse_runner_version("2.328.0; rm -rf /").is_err());
+    }
+}
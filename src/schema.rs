@@ -0,0 +1,308 @@
+//! Versions of meda's on-disk manifests, and the code that upgrades
+//! old ones.
+//!
+//! Image manifests (`manifest.json` in a tag dir) and VM manifests
+//! (`vm.json` in a VM dir) carry a `schema_version`. A file without
+//! one — or, for VMs, no `vm.json` at all — predates versioning and is
+//! version 0. Reading an older version upgrades it in memory one step
+//! at a time, so meda keeps working on a tree nobody has migrated, and
+//! `meda migrate-state` writes the upgrades back. A version newer than
+//! this build knows is refused instead of being misread: the tree was
+//! written by a newer meda.
+//!
+//! A format change bumps the version and appends a step to
+//! `IMAGE_STEPS` or `VM_STEPS`. Steps are never edited or removed, so
+//! a user who skips releases still goes through every one of them.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// VM manifest, in every VM dir.
+pub const VM_MANIFEST_FILE: &str = "vm.json";
+
+/// Upgrades a manifest of the dir at `Path` by one version.
+type Step = fn(&mut Map<String, Value>, &Path);
+
+/// `IMAGE_STEPS[n]` takes an image manifest from version `n` to `n + 1`.
+const IMAGE_STEPS: &[Step] = &[image_v0_to_v1];
+/// `VM_STEPS[n]` takes a VM manifest from version `n` to `n + 1`.
+const VM_STEPS: &[Step] = &[vm_v0_to_v1];
+
+pub const IMAGE_SCHEMA_VERSION: u32 = IMAGE_STEPS.len() as u32;
+pub const VM_SCHEMA_VERSION: u32 = VM_STEPS.len() as u32;
+
+/// Version 1 is the unversioned format plus `schema_version` itself.
+fn image_v0_to_v1(_manifest: &mut Map<String, Value>, _dir: &Path) {}
+
+/// Version 0 VMs have no `vm.json`; start one from what the dir says.
+fn vm_v0_to_v1(manifest: &mut Map<String, Value>, dir: &Path) {
+    let name = dir
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let created = fs::metadata(dir)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    manifest.entry("name").or_insert(name.into());
+    manifest.entry("created").or_insert(created.into());
+}
+
+fn version_of(manifest: &Map<String, Value>) -> u32 {
+    manifest
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .unwrap_or(0) as u32
+}
+
+/// Run the `steps` a manifest of the dir at `dir` still needs. Returns
+/// the version it was at.
+fn upgrade(kind: &str, dir: &Path, doc: &mut Value, steps: &[Step]) -> Result<u32> {
+    let Value::Object(manifest) = doc else {
+        return Err(Error::Other(format!(
+            "{} manifest in {} is not a JSON object",
+            kind,
+            dir.display()
+        )));
+    };
+    let from = version_of(manifest);
+    if from as usize > steps.len() {
+        return Err(Error::Other(format!(
+            "{} manifest in {} has schema version {}, but this meda only understands up to {}; upgrade meda",
+            kind,
+            dir.display(),
+            from,
+            steps.len()
+        )));
+    }
+    for step in &steps[from as usize..] {
+        step(manifest, dir);
+    }
+    manifest.insert("schema_version".to_string(), steps.len().into());
+    Ok(from)
+}
+
+/// Bring an image manifest read from `image_dir` up to
+/// [`IMAGE_SCHEMA_VERSION`]. Returns the version it was at.
+pub fn upgrade_image(image_dir: &Path, doc: &mut Value) -> Result<u32> {
+    upgrade("image", image_dir, doc, IMAGE_STEPS)
+}
+
+/// What meda records about a VM beyond its marker files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VmManifest {
+    pub schema_version: u32,
+    pub name: String,
+    /// Unix time the VM dir was created.
+    pub created: u64,
+}
+
+impl VmManifest {
+    pub fn new(name: &str) -> Self {
+        Self {
+            schema_version: VM_SCHEMA_VERSION,
+            name: name.to_string(),
+            created: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    /// Manifest of the VM in `vm_dir`, upgraded to [`VM_SCHEMA_VERSION`].
+    pub fn load(vm_dir: &Path) -> Result<Self> {
+        Ok(Self::load_versioned(vm_dir)?.0)
+    }
+
+    /// The manifest and the version it was stored at.
+    fn load_versioned(vm_dir: &Path) -> Result<(Self, u32)> {
+        let mut doc = match fs::read_to_string(vm_dir.join(VM_MANIFEST_FILE)) {
+            Ok(body) => serde_json::from_str(&body)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Value::Object(Map::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let from = upgrade("VM", vm_dir, &mut doc, VM_STEPS)?;
+        Ok((serde_json::from_value(doc)?, from))
+    }
+
+    pub fn save(&self, vm_dir: &Path) -> Result<()> {
+        write_atomic(
+            &vm_dir.join(VM_MANIFEST_FILE),
+            &serde_json::to_string_pretty(self)?,
+        )
+    }
+}
+
+fn write_atomic(path: &Path, body: &str) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, body)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// One manifest `migrate_state` upgraded (or would).
+#[derive(Debug, Serialize)]
+pub struct Upgrade {
+    pub kind: &'static str,
+    pub path: PathBuf,
+    pub from: u32,
+    pub to: u32,
+}
+
+/// Tag dirs under `images_dir` (`registry/org/name/tag`).
+fn image_dirs(images_dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![images_dir.to_path_buf()];
+    for _ in 0..4 {
+        dirs = dirs
+            .iter()
+            .filter_map(|d| fs::read_dir(d).ok())
+            .flatten()
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect();
+    }
+    dirs.sort();
+    dirs
+}
+
+/// Upgrade every image and VM manifest older than this meda writes.
+/// With `dry_run`, only report what would change.
+pub fn migrate_state(config: &Config, dry_run: bool) -> Result<Vec<Upgrade>> {
+    let mut upgrades = Vec::new();
+
+    for dir in image_dirs(&config.asset_dir.join("images")) {
+        let path = dir.join("manifest.json");
+        let Ok(body) = fs::read_to_string(&path) else {
+            continue;
+        };
+        let mut doc: Value = serde_json::from_str(&body)?;
+        let from = upgrade_image(&dir, &mut doc)?;
+        if from == IMAGE_SCHEMA_VERSION {
+            continue;
+        }
+        if !dry_run {
+            write_atomic(&path, &serde_json::to_string_pretty(&doc)?)?;
+        }
+        upgrades.push(Upgrade {
+            kind: "image",
+            path,
+            from,
+            to: IMAGE_SCHEMA_VERSION,
+        });
+    }
+
+    // Dot-dirs are staging areas (`meda migrate receive`), not VMs.
+    let mut vm_dirs: Vec<PathBuf> = match fs::read_dir(&config.vm_root) {
+        Ok(entries) => entries
+            .flatten()
+            .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    vm_dirs.sort();
+    for dir in vm_dirs {
+        let (manifest, from) = VmManifest::load_versioned(&dir)?;
+        if from == VM_SCHEMA_VERSION {
+            continue;
+        }
+        if !dry_run {
+            manifest.save(&dir)?;
+        }
+        upgrades.push(Upgrade {
+            kind: "vm",
+            path: dir.join(VM_MANIFEST_FILE),
+            from,
+            to: VM_SCHEMA_VERSION,
+        });
+    }
+    Ok(upgrades)
+}
+
+pub fn migrate_state_command(config: &Config, dry_run: bool, json: bool) -> Result<()> {
+    let upgrades = migrate_state(config, dry_run)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&upgrades)?);
+        return Ok(());
+    }
+    if upgrades.is_empty() {
+        println!("Everything is at the current schema version");
+        return Ok(());
+    }
+    let verb = if dry_run { "Would upgrade" } else { "Upgraded" };
+    for upgrade in &upgrades {
+        println!(
+            "{} {} manifest {} from v{} to v{}",
+            verb,
+            upgrade.kind,
+            upgrade.path.display(),
+            upgrade.from,
+            upgrade.to
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_upgrade_and_refuse_newer() {
+        let dir = TempDir::new().unwrap();
+        let mut doc = serde_json::json!({"name": "ubuntu", "tag": "latest"});
+        assert_eq!(upgrade_image(dir.path(), &mut doc).unwrap(), 0);
+        assert_eq!(doc["schema_version"], IMAGE_SCHEMA_VERSION);
+        assert_eq!(doc["name"], "ubuntu");
+
+        let mut newer = serde_json::json!({"schema_version": IMAGE_SCHEMA_VERSION + 1});
+        let err = upgrade_image(dir.path(), &mut newer).unwrap_err();
+        assert!(err.to_string().contains("upgrade meda"));
+    }
+
+    #[test]
+    fn test_migrate_state() {
+        let root = TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.asset_dir = root.path().join("assets");
+        config.vm_root = root.path().join("vms");
+
+        let image_dir = config
+            .asset_dir
+            .join("images/ghcr_io/cirunlabs/ubuntu/latest");
+        fs::create_dir_all(&image_dir).unwrap();
+        fs::write(image_dir.join("manifest.json"), r#"{"name":"ubuntu"}"#).unwrap();
+        let old_vm = config.vm_dir("old");
+        fs::create_dir_all(&old_vm).unwrap();
+        let new_vm = config.vm_dir("new");
+        fs::create_dir_all(&new_vm).unwrap();
+        VmManifest::new("new").save(&new_vm).unwrap();
+        fs::create_dir_all(config.vm_root.join(".migrate-db")).unwrap();
+
+        let planned = migrate_state(&config, true).unwrap();
+        assert_eq!(planned.len(), 2);
+        assert!(!old_vm.join(VM_MANIFEST_FILE).exists());
+
+        let done = migrate_state(&config, false).unwrap();
+        assert_eq!(done.len(), 2);
+        assert_eq!(done[1].kind, "vm");
+        assert_eq!(done[1].from, 0);
+        let manifest = VmManifest::load(&old_vm).unwrap();
+        assert_eq!(manifest.name, "old");
+        assert_eq!(manifest.schema_version, VM_SCHEMA_VERSION);
+        assert!(migrate_state(&config, false).unwrap().is_empty());
+        assert!(!config
+            .vm_root
+            .join(".migrate-db")
+            .join(VM_MANIFEST_FILE)
+            .exists());
+    }
+}
@@ -0,0 +1,247 @@
+//! Host files copied into guests with set permissions
+//! (`meda run --secret /host/key.pem:/etc/runner/key.pem:0600`).
+//!
+//! A secret goes to cloud-init vendor-data `write_files`, base64
+//! encoded, like the `--env` variables (see `guest_env`), so nobody
+//! has to hand-roll user-data that embeds keys. Only the guest path,
+//! mode and owner are ever shown; file contents stay out of logs,
+//! errors and JSON output. The VM's vendor-data, which holds them, and
+//! the seed ISO built from it are readable by their owner only.
+//!
+//! Over the API the server reads the files, so callers may only name
+//! files under the directories in `MEDA_SECRET_ROOTS`; without it the
+//! API takes no secrets at all.
+
+use crate::error::{Error, Result};
+use crate::guest_env::GuestFile;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Mode of a secret that doesn't name one.
+const DEFAULT_MODE: &str = "0600";
+
+/// Colon-separated directories API callers may take secrets from.
+pub const ROOTS_ENV: &str = "MEDA_SECRET_ROOTS";
+
+/// Cloud-init data is read in one go on first boot; keep secrets small.
+const MAX_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Secret {
+    pub host: PathBuf,
+    pub guest: String,
+    pub mode: String,
+    /// `user` or `user:group`; cloud-init's default (root) without one.
+    pub owner: Option<String>,
+}
+
+fn valid_owner(owner: &str) -> bool {
+    owner.split(':').count() <= 2
+        && owner.split(':').all(|name| {
+            name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "_-".contains(c))
+        })
+}
+
+impl Secret {
+    /// `--secret` value: `/host/path:/guest/path[:MODE[:OWNER]]`, e.g.
+    /// `key.pem:/etc/runner/key.pem:0640:runner:runner`.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let invalid = |why: &str| {
+            format!(
+                "invalid secret '{}': {} (expected /host/path:/guest/path[:MODE[:OWNER]])",
+                s, why
+            )
+        };
+        let mut parts = s.trim().splitn(4, ':');
+        let host = parts.next().unwrap_or_default();
+        let guest = parts.next().ok_or_else(|| invalid("no guest path"))?;
+        let mode = parts.next().unwrap_or(DEFAULT_MODE);
+        let owner = parts.next();
+        if host.is_empty() {
+            return Err(invalid("no host path"));
+        }
+        let plain = |c: char| c.is_ascii_alphanumeric() || matches!(c, '/' | '-' | '_' | '.');
+        if !guest.starts_with('/') || guest.ends_with('/') || !guest.chars().all(plain) {
+            return Err(invalid("the guest path must be an absolute file path"));
+        }
+        let octal = (3..=4).contains(&mode.len()) && mode.chars().all(|c| ('0'..='7').contains(&c));
+        if !octal {
+            return Err(invalid("the mode must be octal, like 0600"));
+        }
+        if owner.is_some_and(|owner| !valid_owner(owner)) {
+            return Err(invalid("the owner must be user or user:group"));
+        }
+        Ok(Self {
+            host: PathBuf::from(host),
+            guest: guest.to_string(),
+            mode: format!("{:0>4}", mode),
+            owner: owner.map(String::from),
+        })
+    }
+
+    /// Contents of the host file; errors name the file, never its data.
+    fn read(&self) -> Result<Vec<u8>> {
+        let unreadable = |why: String| {
+            Error::Other(format!(
+                "Cannot read secret {}: {}",
+                self.host.display(),
+                why
+            ))
+        };
+        let meta = fs::metadata(&self.host).map_err(|e| unreadable(e.to_string()))?;
+        if !meta.is_file() {
+            return Err(unreadable("not a file".to_string()));
+        }
+        if meta.len() > MAX_SIZE {
+            return Err(unreadable(format!("larger than {} KiB", MAX_SIZE / 1024)));
+        }
+        fs::read(&self.host).map_err(|e| unreadable(e.to_string()))
+    }
+}
+
+impl std::fmt::Display for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.host.display(), self.guest, self.mode)?;
+        if let Some(owner) = &self.owner {
+            write!(f, ":{}", owner)?;
+        }
+        Ok(())
+    }
+}
+
+/// `secrets` from an API request, with their host paths resolved and
+/// checked against `MEDA_SECRET_ROOTS`.
+pub fn confine(secrets: Vec<Secret>) -> std::result::Result<Vec<Secret>, String> {
+    if secrets.is_empty() {
+        return Ok(secrets);
+    }
+    let roots = crate::util::allowed_roots(ROOTS_ENV);
+    if roots.is_empty() {
+        return Err(format!(
+            "this server takes no secrets; set {} to the directories they may come from",
+            ROOTS_ENV
+        ));
+    }
+    secrets
+        .into_iter()
+        .map(
+            |secret| match crate::util::resolve_under(&secret.host, &roots) {
+                Some(host) => Ok(Secret { host, ..secret }),
+                None => Err(format!(
+                    "{} is not a file under {}",
+                    secret.host.display(),
+                    ROOTS_ENV
+                )),
+            },
+        )
+        .collect()
+}
+
+/// Make `path`, which holds guest secrets, readable by its owner only.
+pub fn restrict(path: &Path) -> Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(())
+}
+
+/// Have cloud-init write a new VM's `secrets`.
+pub fn prepare(vm_dir: &Path, secrets: &[Secret]) -> Result<()> {
+    if secrets.is_empty() {
+        return Ok(());
+    }
+    let contents = secrets
+        .iter()
+        .map(Secret::read)
+        .collect::<Result<Vec<_>>>()?;
+    let files: Vec<GuestFile> = secrets
+        .iter()
+        .zip(&contents)
+        .map(|(secret, content)| GuestFile {
+            path: &secret.guest,
+            permissions: &secret.mode,
+            owner: secret.owner.as_deref(),
+            content,
+        })
+        .collect();
+    let vendor_data = vm_dir.join(crate::immutable::VENDOR_DATA);
+    // Restrict the file before the secrets go in.
+    if !vendor_data.exists() {
+        fs::write(&vendor_data, "")?;
+    }
+    restrict(&vendor_data)?;
+    crate::guest_env::add_files(vm_dir, &files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse() {
+        let secret = Secret::parse("/srv/key.pem:/etc/runner/key.pem").unwrap();
+        assert_eq!(secret.host, PathBuf::from("/srv/key.pem"));
+        assert_eq!(secret.guest, "/etc/runner/key.pem");
+        assert_eq!(secret.mode, "0600");
+        assert_eq!(secret.owner, None);
+
+        let secret = Secret::parse("key.pem:/etc/runner/key.pem:640:runner:ci").unwrap();
+        assert_eq!(secret.host, PathBuf::from("key.pem"));
+        assert_eq!(secret.mode, "0640");
+        assert_eq!(secret.owner.as_deref(), Some("runner:ci"));
+        assert_eq!(Secret::parse(&secret.to_string()), Ok(secret));
+
+        assert!(Secret::parse("/srv/key.pem").is_err());
+        assert!(Secret::parse(":/etc/key").is_err());
+        assert!(Secret::parse("/srv/key.pem:etc/key").is_err());
+        assert!(Secret::parse("/srv/key.pem:/etc/").is_err());
+        assert!(Secret::parse("/srv/key.pem:/etc/my key").is_err());
+        assert!(Secret::parse("/srv/key.pem:/etc/key:0800").is_err());
+        assert!(Secret::parse("/srv/key.pem:/etc/key:rw").is_err());
+        assert!(Secret::parse("/srv/key.pem:/etc/key:0600:Root").is_err());
+        assert!(Secret::parse("/srv/key.pem:/etc/key:0600:a:b:c").is_err());
+    }
+
+    #[test]
+    fn test_prepare() {
+        let dir = TempDir::new().unwrap();
+        let key = dir.path().join("key.pem");
+        fs::write(&key, "-----BEGIN KEY-----").unwrap();
+        let spec = format!("{}:/etc/runner/key.pem:0400:runner", key.display());
+        prepare(dir.path(), &[Secret::parse(&spec).unwrap()]).unwrap();
+
+        let path = dir.path().join(crate::immutable::VENDOR_DATA);
+        let vendor = fs::read_to_string(&path).unwrap();
+        assert!(vendor.contains("  - path: /etc/runner/key.pem\n    permissions: '0400'\n"));
+        assert!(vendor.contains("    owner: runner\n    defer: true\n"));
+        assert!(!vendor.contains("BEGIN KEY"));
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let missing = Secret::parse("/nonexistent/key.pem:/etc/key").unwrap();
+        let err = prepare(dir.path(), &[missing]).unwrap_err().to_string();
+        assert!(err.contains("/nonexistent/key.pem"), "{}", err);
+    }
+
+    #[test]
+    fn test_confine() {
+        let dir = TempDir::new().unwrap();
+        let key = dir.path().join("key.pem");
+        fs::write(&key, "key").unwrap();
+        let secret = |host: &Path| Secret::parse(&format!("{}:/etc/key", host.display())).unwrap();
+
+        assert_eq!(confine(Vec::new()), Ok(Vec::new()));
+        std::env::remove_var(ROOTS_ENV);
+        assert!(confine(vec![secret(&key)]).unwrap_err().contains(ROOTS_ENV));
+
+        std::env::set_var(ROOTS_ENV, dir.path());
+        let confined = confine(vec![secret(&key)]).unwrap();
+        assert_eq!(confined[0].host, fs::canonicalize(&key).unwrap());
+        assert!(confine(vec![secret(Path::new("/etc/shadow"))]).is_err());
+        assert!(confine(vec![secret(&dir.path().join("../etc"))]).is_err());
+        std::env::remove_var(ROOTS_ENV);
+    }
+}
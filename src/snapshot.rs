@@ -10,9 +10,9 @@
 //! and its `snapshot/` subdir), and restore-in-place (start an existing VM
 //! from its own snapshot). Multi-VM-from-one-snapshot is iteration 2.
 
-use crate::config::Config;
+use crate::config::{Config, DiskFormat};
 use crate::error::{Error, Result};
-use crate::util::{run_command, run_command_quietly};
+use crate::util::run_command;
 use crate::vm;
 use log::info;
 use std::fs;
@@ -23,7 +23,7 @@ use std::time::Duration;
 
 /// Directory under a VM dir that holds the ch-remote snapshot artifacts
 /// (config.json, state.json, memory-ranges).
-const SNAPSHOT_DIR: &str = "snapshot";
+pub(crate) const SNAPSHOT_DIR: &str = "snapshot";
 
 fn snapshot_dir(config: &Config, name: &str) -> PathBuf {
     config.vm_dir(name).join(SNAPSHOT_DIR)
@@ -38,6 +38,7 @@ fn api_sock(config: &Config, name: &str) -> PathBuf {
 /// copy that will later be restored. Returns an error if the VM is not
 /// running (no api.sock) or ch-remote rejects the snapshot.
 pub async fn snapshot(config: &Config, name: &str, json: bool) -> Result<()> {
+    let _lock = vm::lock(config, name, "snapshot")?;
     let vm_dir = config.vm_dir(name);
     if !vm_dir.exists() {
         return Err(Error::VmNotFound(name.to_string()));
@@ -53,31 +54,36 @@ pub async fn snapshot(config: &Config, name: &str, json: bool) -> Result<()> {
     }
 
     let snap_dir = snapshot_dir(config, name);
-    if snap_dir.exists() {
-        // Wipe a previous snapshot — ch-remote refuses to write into a
-        // non-empty destination, and the caller clearly wants the fresh one.
-        fs::remove_dir_all(&snap_dir)?;
-    }
+    // Wipe (or, with a retention policy, keep aside) the previous
+    // snapshot — the caller clearly wants the fresh one.
+    crate::retention::replace_current(&vm_dir)?;
     fs::create_dir_all(&snap_dir)?;
 
     info!("pausing VM {} for snapshot", name);
     run_command(
         &config.cr_bin.to_string_lossy(),
         &["--api-socket", sock.to_str().unwrap(), "pause"],
-    )?;
+    )
+    .await?;
 
     info!("writing snapshot to {}", snap_dir.display());
     let url = format!("file://{}", snap_dir.display());
     // Resume-on-failure: if ch-remote snapshot errors out we shouldn't
     // leave the VM paused — that would look like a hang to the caller.
-    let snap_result = run_command(
+    let snap_result = match run_command(
         &config.cr_bin.to_string_lossy(),
         &["--api-socket", sock.to_str().unwrap(), "snapshot", &url],
-    );
-    let resume_result = run_command_quietly(
+    )
+    .await
+    {
+        Ok(()) => crate::retention::complete(&vm_dir, &snap_dir).await,
+        Err(e) => Err(e),
+    };
+    let resume_result = run_command(
         &config.cr_bin.to_string_lossy(),
         &["--api-socket", sock.to_str().unwrap(), "resume"],
-    );
+    )
+    .await;
     snap_result?;
     resume_result?;
 
@@ -103,6 +109,20 @@ pub async fn snapshot(config: &Config, name: &str, json: bool) -> Result<()> {
 /// asynchronously. Returns ~120 ms later — sshd-ready follows in
 /// 1-3 s once CH finishes paging in the snapshot's memory.
 pub async fn restore(config: &Config, name: &str, json: bool) -> Result<()> {
+    restore_with(config, name, false, json).await
+}
+
+/// Restore as a new machine: like `restore`, but the VM first gets a
+/// fresh subnet, tap, MAC and cloud-init seed, and once the guest is
+/// back its interface is moved over to them (see `reidentify_guest`).
+/// For snapshot clones that should stop looking like their template.
+/// Waits for the guest to answer on its new address.
+pub async fn restore_as_new(config: &Config, name: &str, json: bool) -> Result<()> {
+    restore_with(config, name, true, json).await
+}
+
+async fn restore_with(config: &Config, name: &str, new_identity: bool, json: bool) -> Result<()> {
+    let _lock = vm::lock(config, name, "restore")?;
     let vm_dir = config.vm_dir(name);
     if !vm_dir.exists() {
         return Err(Error::VmNotFound(name.to_string()));
@@ -116,6 +136,48 @@ pub async fn restore(config: &Config, name: &str, json: bool) -> Result<()> {
     if vm::check_vm_running(config, name)? {
         return Err(Error::VmAlreadyRunning(name.to_string()));
     }
+    let memory = vm::get_vm_memory(config, name).unwrap_or_else(|_| config.mem.clone());
+    let quota = crate::quota::before_start(config, name, &memory)?;
+    if crate::bridge::bridge_of(&vm_dir).is_some() {
+        return Err(Error::Other(format!(
+            "VM '{name}' is bridged; restoring bridged VMs from a snapshot is not supported, use `meda start {name}`"
+        )));
+    }
+    if crate::usernet::is_enabled(&vm_dir) {
+        return Err(Error::Other(format!(
+            "VM '{name}' uses user-mode networking; restoring it from a snapshot is not supported, use `meda start {name}`"
+        )));
+    }
+    crate::state::transition(
+        &vm_dir,
+        crate::state::VmState::Starting,
+        crate::state::VmState::Running,
+        async {
+            // Recorded as starting now, so other starts count it.
+            drop(quota);
+            resume_snapshot(config, name, new_identity, json).await
+        },
+    )
+    .await
+}
+
+/// Bring VM `name` up from its snapshot; `restore_with` has checked it can.
+async fn resume_snapshot(
+    config: &Config,
+    name: &str,
+    new_identity: bool,
+    json: bool,
+) -> Result<()> {
+    let vm_dir = config.vm_dir(name);
+    let snap_dir = snapshot_dir(config, name);
+
+    // The guest comes back with the snapshot's address; remember it so
+    // the tap can serve it until the guest has moved.
+    let previous_subnet = if new_identity {
+        Some(assign_new_identity(config, name, json).await?)
+    } else {
+        None
+    };
 
     // Per-VM network namespace. Everything — tap, iptables, the CH
     // process itself — lives inside `meda-<hash>` so N concurrent
@@ -130,13 +192,45 @@ pub async fn restore(config: &Config, name: &str, json: bool) -> Result<()> {
     let netns_spec = crate::netns::NetnsSpec::load_or_compute(&vm_dir, name);
     netns_spec.save(&vm_dir)?;
     let t_prep = _t0.elapsed();
-    crate::netns::create(&netns_spec, subnet, tap_name)?;
+    let policy = crate::network::ForwardPolicy::load(&vm_dir);
+    crate::netns::create(&netns_spec, subnet, tap_name, &policy).await?;
+    if let Some(previous) = &previous_subnet {
+        run_command(
+            "sudo",
+            &[
+                "ip",
+                "-n",
+                &netns_spec.netns,
+                "addr",
+                "replace",
+                &format!("{previous}.1/24"),
+                "dev",
+                tap_name,
+            ],
+        )
+        .await?;
+    }
+    // The restored guest keeps its lease, but renews it from here.
+    let dhcp = crate::dhcp::start_commands(
+        &vm_dir,
+        Some(&netns_spec.netns),
+        tap_name,
+        subnet,
+        fs::read_to_string(vm_dir.join("mac"))
+            .unwrap_or_default()
+            .trim(),
+    );
+    if !dhcp.is_empty() {
+        crate::util::run_command("sudo", &["bash", "-c", &dhcp]).await?;
+    }
     let t_netns = _t0.elapsed();
 
     let sock = api_sock(config, name);
     // Stale sockets from a crashed prior run confuse ch-remote: it connects
     // to a nonexistent server. Unlink before starting CH.
     let _ = fs::remove_file(&sock);
+    let _ = fs::remove_file(vm_dir.join(crate::console::SERIAL_SOCKET));
+    let _ = fs::remove_file(vm_dir.join(crate::console::CH_SERIAL_SOCKET));
 
     let ch_log = vm_dir.join("ch.log");
     let restore_url = format!("file://{}", snap_dir.display());
@@ -207,7 +301,7 @@ pub async fn restore(config: &Config, name: &str, json: bool) -> Result<()> {
     // CH ran under `sudo ip netns exec`, so the API socket is owned
     // by root. Relax the perms so ch-remote (and `meda get`) can
     // talk to it from the unprivileged user.
-    let _ = run_command("sudo", &["chmod", "0666", sock.to_str().unwrap()]);
+    let _ = run_command("sudo", &["chmod", "0666", sock.to_str().unwrap()]).await;
     let t_chmod = _t0.elapsed();
 
     // Resume the VM — CH loads the snapshot paused, and the actual
@@ -231,6 +325,11 @@ pub async fn restore(config: &Config, name: &str, json: bool) -> Result<()> {
         .stderr(Stdio::null())
         .spawn()
         .map_err(|e| Error::CommandFailed(format!("spawn ch-remote resume: {e}")))?;
+    if crate::console::uses_relay(&vm_dir) {
+        crate::console::start_relay(&vm_dir)?;
+    }
+    // The guest reads its cloud-init data again when it reboots.
+    crate::nocloud::start(config, name)?;
     let t_resume = _t0.elapsed();
     info!(
         "restore phases: spawn={}ms api_sock={}ms chmod={}ms resume_fire={}ms TOTAL={}ms",
@@ -241,12 +340,35 @@ pub async fn restore(config: &Config, name: &str, json: bool) -> Result<()> {
         t_resume.as_millis()
     );
 
+    if let Some(previous) = &previous_subnet {
+        if !json {
+            info!("moving the guest from {previous}.0/24 to {subnet}.0/24");
+        }
+        let mac = fs::read_to_string(vm_dir.join("mac"))?;
+        reidentify_guest(config, name, &netns_spec, previous, subnet, mac.trim()).await?;
+        run_command(
+            "sudo",
+            &[
+                "ip",
+                "-n",
+                &netns_spec.netns,
+                "addr",
+                "del",
+                &format!("{previous}.1/24"),
+                "dev",
+                tap_name,
+            ],
+        )
+        .await?;
+    }
+
     if json {
         let out = serde_json::json!({
             "vm": name,
             "restored_from": snap_dir,
             "host": netns_spec.netns_ip,
             "ssh": format!("cirun@{}", netns_spec.netns_ip),
+            "new_identity": new_identity,
         });
         println!("{}", serde_json::to_string_pretty(&out)?);
     } else {
@@ -256,6 +378,117 @@ pub async fn restore(config: &Config, name: &str, json: bool) -> Result<()> {
     Ok(())
 }
 
+/// Give a stopped, snapshotted VM a new subnet, tap, MAC and cloud-init
+/// seed, and point its snapshot at the new tap and MAC. Returns the
+/// subnet it had, which the guest in the snapshot still uses.
+async fn assign_new_identity(config: &Config, name: &str, json: bool) -> Result<String> {
+    let vm_dir = config.vm_dir(name);
+    let previous = fs::read_to_string(vm_dir.join("subnet"))?
+        .trim()
+        .to_string();
+    let identity = vm::assign_identity(config, name, json).await?;
+    let config_json = vm_dir.join(SNAPSHOT_DIR).join("config.json");
+    let body = rewrite_net_identity(
+        &fs::read_to_string(&config_json)?,
+        &identity.tap_name,
+        &identity.mac,
+    )?;
+    fs::write(&config_json, body)?;
+    Ok(previous)
+}
+
+/// Point the first `net` device of a snapshot's config.json at `tap`
+/// with `mac`.
+fn rewrite_net_identity(body: &str, tap: &str, mac: &str) -> Result<String> {
+    let mut config: serde_json::Value = serde_json::from_str(body)?;
+    let Some(net) = config
+        .get_mut("net")
+        .and_then(|n| n.as_array_mut())
+        .and_then(|n| n.first_mut())
+        .and_then(|n| n.as_object_mut())
+    else {
+        return Err(Error::Other(
+            "snapshot config has no network device to give a new identity".to_string(),
+        ));
+    };
+    net.insert("tap".to_string(), tap.into());
+    net.insert("mac".to_string(), mac.into());
+    Ok(serde_json::to_string(&config)?)
+}
+
+/// Guest-side half of `restore_as_new`, run over SSH from inside the
+/// VM's netns while the tap still answers on the old subnet: move the
+/// interface holding the old address to `mac` and `subnet`.2, take the
+/// VM's name as hostname, and `cloud-init clean` so the next cold boot
+/// applies the new seed for good. The change runs detached, since it
+/// cuts the SSH session's own route; then wait for sshd on the new
+/// address.
+async fn reidentify_guest(
+    config: &Config,
+    name: &str,
+    netns_spec: &crate::netns::NetnsSpec,
+    previous: &str,
+    subnet: &str,
+    mac: &str,
+) -> Result<()> {
+    let command = reidentify_command(name, previous, subnet, mac);
+    let old_guest = format!("{previous}.2");
+    let deadline = std::time::Instant::now() + Duration::from_secs(60);
+    loop {
+        match crate::ssh::guest_exec_in(config, Some(&netns_spec.netns), &old_guest, &command) {
+            Ok(()) => break,
+            Err(e) if std::time::Instant::now() > deadline => {
+                return Err(Error::Other(format!(
+                    "could not reach {name} at its old address {old_guest} to move it to {subnet}.2: {e}"
+                )));
+            }
+            Err(_) => tokio::time::sleep(Duration::from_secs(1)).await,
+        }
+    }
+
+    let deadline = std::time::Instant::now() + Duration::from_secs(30);
+    while vm::probe_port(&netns_spec.netns_ip, 22).await != vm::IpStatus::Ready {
+        if std::time::Instant::now() > deadline {
+            return Err(Error::Other(format!(
+                "{name} did not come up on {subnet}.2 after moving it; `meda console {name}` shows what the guest did"
+            )));
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    Ok(())
+}
+
+/// Shell line for `reidentify_guest`, run as `cirun`.
+fn reidentify_command(name: &str, previous: &str, subnet: &str, mac: &str) -> String {
+    // VM names aren't restricted; hostnames (and this shell line) are.
+    let hostname: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(63)
+        .collect();
+    let script = format!(
+        "IF=$(ip -o -4 addr show to {previous}.2 | awk \"{{print \\$2; exit}}\"); \
+         [ -n \"$IF\" ] || exit 1; \
+         cloud-init clean >/dev/null 2>&1; \
+         hostnamectl set-hostname {hostname} 2>/dev/null || hostname {hostname}; \
+         ip link set dev \"$IF\" address {mac}; \
+         ip addr flush dev \"$IF\"; \
+         ip addr add {subnet}.2/24 dev \"$IF\"; \
+         ip route replace default via {subnet}.1 dev \"$IF\""
+    );
+    // No single quotes in `script`, so it nests in this one level.
+    format!("sudo nohup sh -c '{script}' >/dev/null 2>&1 &")
+}
+
+/// True when VM `name` has a snapshot to restore or clone from.
+pub fn has_snapshot(config: &Config, name: &str) -> bool {
+    config
+        .vm_dir(name)
+        .join(SNAPSHOT_DIR)
+        .join("config.json")
+        .exists()
+}
+
 /// Clone a snapshotted VM into a new VM name so the caller can fast-restore
 /// a *separate* VM from the template. This is the "create VM from template"
 /// path: takes ~100ms of bookkeeping (no cold boot, no cloud-init) and
@@ -266,19 +499,22 @@ pub async fn restore(config: &Config, name: &str, json: bool) -> Result<()> {
 /// run *simultaneously* — their guests would both claim 192.168.X.2 and
 /// their tap devices would collide on the same subnet. Sequentially,
 /// however, any number of clones work: stop one, restore another.
-/// Per-clone identity (new MAC + in-guest IP rewrite) is iter-6+ work.
+/// `restore_as_new` gives a clone an identity of its own instead.
 pub async fn clone_template(
     config: &Config,
     template: &str,
     new_name: &str,
+    pool: Option<&crate::storage::StoragePool>,
     json: bool,
 ) -> Result<()> {
+    let _template_lock = vm::lock(config, template, "clone")?;
+    let _lock = vm::lock(config, new_name, "clone")?;
     let src = config.vm_dir(template);
     let dst = config.vm_dir(new_name);
     if !src.exists() {
         return Err(Error::VmNotFound(template.to_string()));
     }
-    if !src.join(SNAPSHOT_DIR).join("config.json").exists() {
+    if !has_snapshot(config, template) {
         return Err(Error::Other(format!(
             "{template} has no snapshot — run `meda snapshot {template}` first"
         )));
@@ -287,7 +523,10 @@ pub async fn clone_template(
         return Err(Error::VmAlreadyExists(new_name.to_string()));
     }
 
-    fs::create_dir_all(&dst)?;
+    let disk = vm::get_vm_disk_size(config, template).unwrap_or_else(|_| config.disk_size.clone());
+    let quota = crate::quota::before_create(config, new_name, &disk)?;
+    crate::storage::create_vm_dir(config, new_name, pool)?;
+    drop(quota);
 
     // qcow2 overlay on top of the template's rootfs. The overlay is tiny
     // (~200KB) and writes stay local to this clone — the template's disk
@@ -295,9 +534,32 @@ pub async fn clone_template(
     // because the template's rootfs IS a qcow2 (itself an overlay over
     // base.raw). Passing raw here would make qemu-img read the on-disk
     // size as the virtual size and give the clone a ~60MB disk.
-    let src_rootfs = src.join("rootfs.qcow2");
-    let dst_rootfs = dst.join("rootfs.qcow2");
-    crate::util::create_qcow2_overlay_with_fmt(&src_rootfs, "qcow2", &dst_rootfs, None)?;
+    //
+    // Templates created with MEDA_DISK_FORMAT=raw get a private copy
+    // instead: the snapshot's config.json pins `rootfs.raw` with
+    // image_type=raw, and the clone must keep that shape to restore.
+    let Some((src_rootfs, rootfs_format)) = DiskFormat::detect(&src) else {
+        return Err(Error::Other(format!("{template} has no rootfs")));
+    };
+    let dst_rootfs = dst.join(rootfs_format.rootfs_name());
+    match rootfs_format {
+        DiskFormat::Qcow2 => {
+            crate::util::create_qcow2_overlay_with_fmt(&src_rootfs, "qcow2", &dst_rootfs, None)
+                .await?
+        }
+        DiskFormat::Raw => {
+            run_command(
+                "cp",
+                &[
+                    "--sparse=always",
+                    "--reflink=auto",
+                    src_rootfs.to_str().unwrap(),
+                    dst_rootfs.to_str().unwrap(),
+                ],
+            )
+            .await?
+        }
+    }
 
     // Cloud-init ISO — reuse the template's so cloud-init sees identical
     // metadata. Copying keeps the clone self-contained (template can be
@@ -320,7 +582,6 @@ pub async fn clone_template(
         "disk_size",
         "meta-data",
         "user-data",
-        "start.sh",
         "devices",
     ] {
         let s = src.join(f);
@@ -331,6 +592,23 @@ pub async fn clone_template(
     let template_tap = fs::read_to_string(src.join("tapdev"))?.trim().to_string();
     let clone_tap = unique_tap_name(new_name);
     fs::write(dst.join("tapdev"), &clone_tap)?;
+    // For cold starts: the clone's disks, tap and netns.
+    if let Some(spec) = crate::launch::LaunchSpec::load(&src) {
+        let mut spec = spec
+            .replace(
+                &format!("{}/", src.display()),
+                &format!("{}/", dst.display()),
+            )
+            .replace(&template_tap, &clone_tap);
+        if spec.netns.is_some() {
+            spec.netns = Some(crate::netns::NetnsSpec::for_vm(new_name).netns);
+        }
+        spec.save(&dst)?;
+    }
+    // The clone keeps the template's subnet, inside its own netns.
+    if let Ok(subnet) = fs::read_to_string(dst.join("subnet")) {
+        crate::subnets::lease(config, new_name, &subnet)?;
+    }
 
     // Snapshot files — rewrite disk paths + tap name in config.json so
     // CH reads the clone's disks and opens the clone's tap instead of
@@ -489,7 +767,7 @@ pub fn templates(config: &Config, json: bool) -> Result<()> {
     Ok(())
 }
 
-fn dir_size(path: &Path) -> std::io::Result<u64> {
+pub(crate) fn dir_size(path: &Path) -> std::io::Result<u64> {
     let mut total = 0u64;
     if path.is_dir() {
         for entry in fs::read_dir(path)? {
@@ -509,6 +787,38 @@ fn dir_size(path: &Path) -> std::io::Result<u64> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_rewrite_net_identity() {
+        let body = r#"{"cpus":{"boot_vcpus":2},"net":[{"tap":"tap-1111","ip":"192.168.249.1","mac":"52:54:00:00:00:01","num_queues":2}]}"#;
+        let out = rewrite_net_identity(body, "tap-2222", "52:54:00:aa:bb:cc").unwrap();
+        let value: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(value["net"][0]["tap"], "tap-2222");
+        assert_eq!(value["net"][0]["mac"], "52:54:00:aa:bb:cc");
+        assert_eq!(value["net"][0]["num_queues"], 2);
+        assert_eq!(value["cpus"]["boot_vcpus"], 2);
+        assert!(rewrite_net_identity(r#"{"net":null}"#, "t", "m").is_err());
+    }
+
+    #[test]
+    fn test_reidentify_command() {
+        let command = reidentify_command(
+            "ci runner/1",
+            "192.168.40",
+            "192.168.77",
+            "52:54:00:aa:bb:cc",
+        );
+        let script = command
+            .strip_prefix("sudo nohup sh -c '")
+            .and_then(|c| c.strip_suffix("' >/dev/null 2>&1 &"))
+            .unwrap();
+        assert!(!script.contains('\''));
+        assert!(script.contains("addr show to 192.168.40.2"));
+        assert!(script.contains("address 52:54:00:aa:bb:cc"));
+        assert!(script.contains("ip addr add 192.168.77.2/24"));
+        assert!(script.contains("default via 192.168.77.1"));
+        assert!(script.contains("set-hostname ci-runner-1 "));
+    }
+
     #[test]
     fn dir_size_empty() {
         let tmp = tempfile::tempdir().unwrap();
@@ -3,13 +3,13 @@ use crate::error::{Error, Result};
 use log::info;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
-use std::process::Command;
+use std::process::{Child, Command, Output, Stdio};
 
 pub struct SshKeyPair {
     pub public_key: String,
 }
 
-/// Ensures an ED25519 SSH keypair exists at ~/.meda/ssh/id_ed25519.
+/// Ensures an ED25519 SSH keypair exists at `<config dir>/ssh/id_ed25519`.
 /// Generates one if not present. Returns the key paths and public key content.
 pub fn ensure_ssh_keypair(config: &Config) -> Result<SshKeyPair> {
     let ssh_dir = config.ssh_dir();
@@ -63,6 +63,287 @@ pub fn ensure_ssh_keypair(config: &Config) -> Result<SshKeyPair> {
     })
 }
 
+/// Extra authorized keys from `--ssh-key` values plus `MEDA_SSH_PUBKEY`.
+/// Each value is either a path to a public key (or authorized_keys)
+/// file or a key itself. Duplicates are dropped; order is kept.
+pub fn resolve_extra_keys(values: &[String]) -> Result<Vec<String>> {
+    let mut keys: Vec<String> = Vec::new();
+    let env_value = std::env::var("MEDA_SSH_PUBKEY").ok();
+    for value in values.iter().chain(env_value.iter()) {
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        let path = std::path::Path::new(value);
+        let body = if !looks_like_key(value) && path.is_file() {
+            fs::read_to_string(path)?
+        } else {
+            value.to_string()
+        };
+        for line in body.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if !looks_like_key(line) || line.contains('\'') {
+                return Err(Error::Other(format!(
+                    "'{}' is neither a public key file nor an SSH public key",
+                    value
+                )));
+            }
+            if !keys.iter().any(|k| k == line) {
+                keys.push(line.to_string());
+            }
+        }
+    }
+    Ok(keys)
+}
+
+fn looks_like_key(s: &str) -> bool {
+    let mut fields = s.split_whitespace();
+    let algo = fields.next().unwrap_or_default();
+    (algo.starts_with("ssh-") || algo.starts_with("ecdsa-") || algo.starts_with("sk-"))
+        && fields.next().is_some()
+}
+
+/// Default cloud-config for the `cirun` user. Password login stays on
+/// (the well-known image password) unless the caller brings their own
+/// keys, in which case the account is key-only.
+pub fn default_user_data(meda_key: &str, extra_keys: &[String]) -> String {
+    let mut body =
+        String::from("#cloud-config\nusers:\n  - name: cirun\n    sudo: ALL=(ALL) NOPASSWD:ALL\n");
+    if extra_keys.is_empty() {
+        body.push_str("    passwd: $6$ep7LxhhmhQHf.TiY$qPJVJQCnPMnyFdmD0ymP7CH2dos0awET8JlSzDqoiK6AOQwDpx8fCLJ1C5c7nvkVJbIpQCOalC8l2BGkRzogM.\n    lock_passwd: false\n");
+    } else {
+        body.push_str("    lock_passwd: true\n");
+    }
+    body.push_str(
+        "    inactive: false\n    groups: sudo\n    shell: /bin/bash\n    ssh_authorized_keys:\n",
+    );
+    for key in std::iter::once(meda_key).chain(extra_keys.iter().map(String::as_str)) {
+        body.push_str(&format!("      - {}\n", key));
+    }
+    body.push_str(if extra_keys.is_empty() {
+        "ssh_pwauth: true\n"
+    } else {
+        "ssh_pwauth: false\n"
+    });
+    body
+}
+
+/// `user_data` with `keys` authorized for every user it creates, and
+/// for the image's default user. Only `#cloud-config` user-data can
+/// take them.
+pub fn add_keys_to_user_data(user_data: &str, keys: &[String]) -> Result<String> {
+    use serde_yaml::{Mapping, Value};
+
+    let not_cloud_config = || {
+        Error::Other(
+            "SSH keys can only be added to #cloud-config user-data; put them in the user-data instead"
+                .to_string(),
+        )
+    };
+    if !user_data.trim_start().starts_with("#cloud-config") {
+        return Err(not_cloud_config());
+    }
+    let mut config: Value = serde_yaml::from_str(user_data)
+        .map_err(|e| Error::Other(format!("Failed to parse user-data to add SSH keys: {}", e)))?;
+    if config.is_null() {
+        config = Value::Mapping(Mapping::new());
+    }
+    let authorize = |entry: &mut Mapping| {
+        let list = entry
+            .entry(Value::from("ssh_authorized_keys"))
+            .or_insert_with(|| Value::Sequence(Vec::new()));
+        if let Value::Sequence(list) = list {
+            for key in keys {
+                if !list.iter().any(|k| k.as_str() == Some(key.as_str())) {
+                    list.push(Value::from(key.as_str()));
+                }
+            }
+        }
+    };
+    let top = config.as_mapping_mut().ok_or_else(not_cloud_config)?;
+    if let Some(Value::Sequence(users)) = top.get_mut("users") {
+        users
+            .iter_mut()
+            .filter_map(Value::as_mapping_mut)
+            .for_each(authorize);
+    }
+    authorize(top);
+    let body = serde_yaml::to_string(&config)
+        .map_err(|e| Error::Other(format!("Failed to write user-data: {}", e)))?;
+    Ok(format!("#cloud-config\n{}", body))
+}
+
+/// Shell snippet that adds `keys` to cirun's authorized_keys and turns
+/// off password login, for guests that already ran cloud-init (clones
+/// restored from a template snapshot). Keys come from
+/// [`resolve_extra_keys`], which rejects single quotes.
+pub fn authorize_keys_command(keys: &[String]) -> String {
+    let quoted: Vec<String> = keys.iter().map(|k| format!("'{}'", k)).collect();
+    format!(
+        "umask 077 && mkdir -p ~/.ssh && printf '%s\\n' {} >> ~/.ssh/authorized_keys && \
+         echo 'PasswordAuthentication no' | sudo tee /etc/ssh/sshd_config.d/00-meda.conf >/dev/null && \
+         sudo passwd -l cirun >/dev/null && \
+         (sudo systemctl reload ssh || sudo systemctl reload sshd)",
+        quoted.join(" ")
+    )
+}
+
+/// Shell snippet that adds `keys` to cirun's authorized_keys unless
+/// they're there already, leaving login settings alone. Keys must not
+/// contain single quotes.
+pub fn add_keys_command(keys: &[String]) -> String {
+    let quoted: Vec<String> = keys.iter().map(|k| format!("'{}'", k)).collect();
+    format!(
+        "umask 077 && mkdir -p ~/.ssh && for key in {}; do \
+         grep -qxF \"$key\" ~/.ssh/authorized_keys 2>/dev/null || \
+         printf '%s\\n' \"$key\" >> ~/.ssh/authorized_keys; done",
+        quoted.join(" ")
+    )
+}
+
+/// The first SSH public key in `output`, such as what a remote meda
+/// printed; `None` when there is none safe to quote.
+pub fn find_key(output: &str) -> Option<String> {
+    output
+        .lines()
+        .map(str::trim)
+        .find(|line| looks_like_key(line) && !line.contains('\''))
+        .map(str::to_string)
+}
+
+/// The address and SSH port in `host`, which is an address or, for
+/// user-mode VMs, `127.0.0.1:<port>`.
+pub fn split_port(host: &str) -> (String, u16) {
+    match host.parse::<std::net::SocketAddr>() {
+        Ok(addr) => (addr.ip().to_string(), addr.port()),
+        Err(_) => (host.to_string(), 22),
+    }
+}
+
+/// Run `command` in the guest as `cirun` over SSH with meda's key.
+/// Non-interactive: fails instead of prompting for a password or host key.
+pub fn guest_exec(config: &Config, host: &str, command: &str) -> Result<()> {
+    guest_exec_in(config, None, host, command)
+}
+
+/// `guest_exec` from inside `netns`, for guest addresses only reachable
+/// from the VM's namespace.
+pub fn guest_exec_in(
+    config: &Config,
+    netns: Option<&str>,
+    host: &str,
+    command: &str,
+) -> Result<()> {
+    guest_output_in(config, netns, host, command).map(drop)
+}
+
+/// `guest_exec`, returning what `command` printed.
+pub fn guest_output(config: &Config, host: &str, command: &str) -> Result<String> {
+    guest_output_in(config, None, host, command)
+}
+
+fn guest_output_in(
+    config: &Config,
+    netns: Option<&str>,
+    host: &str,
+    command: &str,
+) -> Result<String> {
+    let output = guest_command_in(config, netns, host, command)?;
+    if !output.status.success() {
+        return Err(Error::CommandFailed(format!(
+            "ssh cirun@{} '{}': {}",
+            host,
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Run `command` like `guest_exec`, and return how it went whatever its
+/// exit status.
+pub fn guest_command(config: &Config, host: &str, command: &str) -> Result<Output> {
+    guest_command_in(config, None, host, command)
+}
+
+fn guest_command_in(
+    config: &Config,
+    netns: Option<&str>,
+    host: &str,
+    command: &str,
+) -> Result<Output> {
+    Ok(ssh_command(config, netns, host).arg(command).output()?)
+}
+
+/// Run `command` like `guest_exec`, with the local file `input` on its
+/// stdin.
+pub fn guest_exec_with_input(
+    config: &Config,
+    host: &str,
+    command: &str,
+    input: &std::path::Path,
+) -> Result<()> {
+    let output = ssh_command(config, None, host)
+        .arg(command)
+        .stdin(Stdio::from(fs::File::open(input)?))
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::CommandFailed(format!(
+            "ssh cirun@{} '{}' < {}: {}",
+            host,
+            command,
+            input.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Start `command` in the guest as `cirun` over SSH with meda's key,
+/// its stdin and stdout piped, for a session held open while meda works.
+pub fn guest_spawn(config: &Config, host: &str, command: &str) -> Result<Child> {
+    Ok(ssh_command(config, None, host)
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?)
+}
+
+/// `ssh` to cirun@`host` with meda's key, for the command to be added.
+fn ssh_command(config: &Config, netns: Option<&str>, host: &str) -> Command {
+    let key = config.ssh_dir().join("id_ed25519");
+    let (host, port) = split_port(host);
+    let mut ssh = match netns {
+        Some(ns) => {
+            let mut sudo = Command::new("sudo");
+            sudo.args(["ip", "netns", "exec", ns, "ssh"]);
+            sudo
+        }
+        None => Command::new("ssh"),
+    };
+    ssh.arg("-i")
+        .arg(&key)
+        .args([
+            "-o",
+            "BatchMode=yes",
+            "-o",
+            "StrictHostKeyChecking=no",
+            "-o",
+            "UserKnownHostsFile=/dev/null",
+            "-o",
+            "ConnectTimeout=10",
+            "-o",
+            "LogLevel=ERROR",
+        ])
+        .arg("-p")
+        .arg(port.to_string())
+        .arg(format!("cirun@{}", host));
+    ssh
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -123,4 +404,108 @@ mod tests {
         std::env::remove_var("MEDA_ASSET_DIR");
         std::env::remove_var("MEDA_VM_DIR");
     }
+
+    const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIExample user@laptop";
+
+    #[test]
+    #[serial]
+    fn test_resolve_extra_keys() {
+        std::env::remove_var("MEDA_SSH_PUBKEY");
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("id.pub");
+        fs::write(
+            &file,
+            "# laptop\nssh-rsa AAAAB3Nza other@host\n\nssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIExample user@laptop\n",
+        )
+        .unwrap();
+
+        let keys =
+            resolve_extra_keys(&[KEY.to_string(), file.to_str().unwrap().to_string()]).unwrap();
+        assert_eq!(
+            keys,
+            vec![KEY.to_string(), "ssh-rsa AAAAB3Nza other@host".into()]
+        );
+
+        std::env::set_var("MEDA_SSH_PUBKEY", "ecdsa-sha2-nistp256 AAAAE2 env@ci");
+        let keys = resolve_extra_keys(&[]).unwrap();
+        assert_eq!(keys, vec!["ecdsa-sha2-nistp256 AAAAE2 env@ci".to_string()]);
+        std::env::remove_var("MEDA_SSH_PUBKEY");
+
+        assert!(resolve_extra_keys(&["/no/such/key.pub".into()]).is_err());
+        assert!(resolve_extra_keys(&["ssh-ed25519 AAAA'; rm -rf /".into()]).is_err());
+    }
+
+    #[test]
+    fn test_add_keys_to_user_data() {
+        let user_data = "#cloud-config\nusers:\n  - default\n  - name: ops\n    ssh_authorized_keys:\n      - ssh-rsa AAAA ops@host\n  - name: ci\npackages: [git]\n";
+        let merged = add_keys_to_user_data(user_data, &[KEY.to_string()]).unwrap();
+        assert!(merged.starts_with("#cloud-config\n"));
+        let config: serde_yaml::Value = serde_yaml::from_str(&merged).unwrap();
+        assert_eq!(config["users"][0], "default");
+        assert_eq!(
+            config["users"][1]["ssh_authorized_keys"][0],
+            "ssh-rsa AAAA ops@host"
+        );
+        assert_eq!(config["users"][1]["ssh_authorized_keys"][1], KEY);
+        assert_eq!(config["users"][2]["ssh_authorized_keys"][0], KEY);
+        assert_eq!(config["ssh_authorized_keys"][0], KEY);
+        assert_eq!(config["packages"][0], "git");
+
+        // Adding them again changes nothing.
+        assert_eq!(
+            add_keys_to_user_data(&merged, &[KEY.to_string()]).unwrap(),
+            merged
+        );
+        let empty = add_keys_to_user_data("#cloud-config\n", &[KEY.to_string()]).unwrap();
+        assert!(empty.contains(KEY));
+        assert!(add_keys_to_user_data("#!/bin/sh\necho hi\n", &[KEY.to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_find_key_and_add_keys() {
+        assert_eq!(
+            find_key("Checked\nssh-ed25519 AAAAC3 meda@localhost\n"),
+            Some("ssh-ed25519 AAAAC3 meda@localhost".to_string())
+        );
+        assert_eq!(find_key("ssh-ed25519 AAAA'x\n"), None);
+        assert_eq!(find_key(""), None);
+
+        let home = TempDir::new().unwrap();
+        let command = add_keys_command(&[KEY.to_string()]);
+        for _ in 0..2 {
+            let status = std::process::Command::new("sh")
+                .args(["-c", &command])
+                .env("HOME", home.path())
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+        let keys = fs::read_to_string(home.path().join(".ssh/authorized_keys")).unwrap();
+        assert_eq!(keys, format!("{}\n", KEY));
+    }
+
+    #[test]
+    fn test_split_port() {
+        assert_eq!(split_port("10.99.3.2"), ("10.99.3.2".to_string(), 22));
+        assert_eq!(
+            split_port("127.0.0.1:40022"),
+            ("127.0.0.1".to_string(), 40022)
+        );
+    }
+
+    #[test]
+    fn test_default_user_data() {
+        let meda = "ssh-ed25519 AAAAmeda meda@localhost";
+
+        let open = default_user_data(meda, &[]);
+        assert!(open.contains("ssh_pwauth: true"));
+        assert!(open.contains("lock_passwd: false"));
+        assert!(open.contains(&format!("      - {}\n", meda)));
+
+        let keyed = default_user_data(meda, &[KEY.to_string()]);
+        assert!(keyed.contains("ssh_pwauth: false"));
+        assert!(keyed.contains("lock_passwd: true"));
+        assert!(!keyed.contains("passwd: $6$"));
+        assert!(keyed.contains(&format!("      - {}\n      - {}\n", meda, KEY)));
+    }
 }
@@ -0,0 +1,245 @@
+//! A VM's lifecycle state.
+//!
+//! Every operation records the state it moves a VM through in
+//! `state.json` in the VM dir: `creating` → `stopped` → `starting` →
+//! `running` → `stopping` → `stopped`, or `error` with the reason when an
+//! operation fails. Records are written to a temp file and renamed, so a
+//! reader never sees half of one.
+//!
+//! The record says what meda last did, not what the host looks like
+//! now, so `meda list` and `meda get` reconcile the two (see
+//! [`observe`]): a VM recorded as running whose cloud-hypervisor has gone
+//! is in `error`, with the end of its `ch.log`, and a VM left in
+//! `creating` or `starting` by a meda process that died is too.
+
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+
+/// File in a VM dir with its state record.
+pub const STATE_FILE: &str = "state.json";
+
+/// Lines of `ch.log` kept with a crash.
+const LOG_TAIL_LINES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VmState {
+    Creating,
+    Stopped,
+    Starting,
+    Running,
+    Stopping,
+    Error,
+}
+
+impl fmt::Display for VmState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Creating => "creating",
+            Self::Stopped => "stopped",
+            Self::Starting => "starting",
+            Self::Running => "running",
+            Self::Stopping => "stopping",
+            Self::Error => "error",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateRecord {
+    pub state: VmState,
+    /// Unix time the VM entered the state.
+    pub since: u64,
+    /// Why the VM is in `error`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl StateRecord {
+    fn new(state: VmState, error: Option<String>) -> Self {
+        Self {
+            state,
+            since: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            error,
+        }
+    }
+}
+
+pub fn load(vm_dir: &Path) -> Option<StateRecord> {
+    let body = fs::read_to_string(vm_dir.join(STATE_FILE)).ok()?;
+    serde_json::from_str(&body).ok()
+}
+
+fn save(vm_dir: &Path, record: &StateRecord) -> Result<()> {
+    let path = vm_dir.join(STATE_FILE);
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(record)?)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// Record that the VM in `vm_dir` is now in `state`.
+pub fn set(vm_dir: &Path, state: VmState) -> Result<()> {
+    save(vm_dir, &StateRecord::new(state, None))
+}
+
+/// Record that an operation on the VM in `vm_dir` failed with `error`.
+/// Best effort: the caller is already reporting a failure.
+pub fn fail(vm_dir: &Path, error: &str) {
+    if let Err(e) = save(
+        vm_dir,
+        &StateRecord::new(VmState::Error, Some(error.to_string())),
+    ) {
+        log::warn!(
+            "Failed to record error state in {}: {}",
+            vm_dir.display(),
+            e
+        );
+    }
+}
+
+/// Run `operation` with the VM in `vm_dir` recorded as `during`, then as
+/// `after` if it succeeds or as `error` if it fails.
+pub async fn transition<T>(
+    vm_dir: &Path,
+    during: VmState,
+    after: VmState,
+    operation: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    set(vm_dir, during)?;
+    match operation.await {
+        Ok(value) => {
+            set(vm_dir, after)?;
+            Ok(value)
+        }
+        Err(e) => {
+            fail(vm_dir, &e.to_string());
+            Err(e)
+        }
+    }
+}
+
+fn log_tail(vm_dir: &Path) -> String {
+    let log = fs::read_to_string(vm_dir.join("ch.log")).unwrap_or_default();
+    let lines: Vec<&str> = log.lines().collect();
+    lines[lines.len().saturating_sub(LOG_TAIL_LINES)..].join("\n")
+}
+
+/// The VM's state as it is now: the record, corrected by whether its
+/// cloud-hypervisor is `running` and whether a meda operation is `busy`
+/// with it (holds its lock). Corrections that find a failure are saved,
+/// so the reason survives the next start truncating `ch.log`.
+pub fn observe(vm_dir: &Path, running: bool, busy: bool) -> StateRecord {
+    let recorded = load(vm_dir);
+    let state = recorded.as_ref().map(|r| r.state);
+    let corrected = match state {
+        // An operation in progress knows better than a process check.
+        Some(VmState::Creating | VmState::Starting | VmState::Stopping) if busy => None,
+        // VMs created before state records, or by a `start.sh` that
+        // forked CH, are what their process says.
+        _ if running => {
+            (state != Some(VmState::Running)).then(|| StateRecord::new(VmState::Running, None))
+        }
+        None | Some(VmState::Stopping) => Some(StateRecord::new(VmState::Stopped, None)),
+        Some(VmState::Running) => {
+            let tail = log_tail(vm_dir);
+            let mut error =
+                "cloud-hypervisor exited while the VM was running (guest shutdown or crash)"
+                    .to_string();
+            if !tail.is_empty() {
+                error.push_str(&format!("; end of ch.log:\n{}", tail));
+            }
+            Some(StateRecord::new(VmState::Error, Some(error)))
+        }
+        Some(state @ (VmState::Creating | VmState::Starting)) => Some(StateRecord::new(
+            VmState::Error,
+            Some(format!(
+                "the meda process {} the VM exited before finishing",
+                state
+            )),
+        )),
+        Some(VmState::Stopped | VmState::Error) => None,
+    };
+    match corrected {
+        Some(record) => {
+            // Transient states are only saved by the operation itself.
+            if record.state == VmState::Error {
+                if let Err(e) = save(vm_dir, &record) {
+                    log::debug!(
+                        "Failed to record error state in {}: {}",
+                        vm_dir.display(),
+                        e
+                    );
+                }
+            }
+            record
+        }
+        None => recorded.unwrap_or_else(|| StateRecord::new(VmState::Stopped, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_transition() {
+        let dir = TempDir::new().unwrap();
+        transition(dir.path(), VmState::Starting, VmState::Running, async {
+            assert_eq!(load(dir.path()).unwrap().state, VmState::Starting);
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(load(dir.path()).unwrap().state, VmState::Running);
+        assert!(!dir.path().join("state.json.tmp").exists());
+
+        let failed: Result<()> =
+            transition(dir.path(), VmState::Starting, VmState::Running, async {
+                Err(crate::error::Error::Other("no kvm".to_string()))
+            })
+            .await;
+        assert!(failed.is_err());
+        let record = load(dir.path()).unwrap();
+        assert_eq!(record.state, VmState::Error);
+        assert_eq!(record.error.as_deref(), Some("no kvm"));
+    }
+
+    #[test]
+    fn test_observe() {
+        let dir = TempDir::new().unwrap();
+        let observe_state = |running, busy| observe(dir.path(), running, busy).state;
+
+        // No record: whatever the process says.
+        assert_eq!(observe_state(false, false), VmState::Stopped);
+        assert_eq!(observe_state(true, false), VmState::Running);
+        assert!(load(dir.path()).is_none());
+
+        set(dir.path(), VmState::Starting).unwrap();
+        assert_eq!(observe_state(false, true), VmState::Starting);
+        assert_eq!(observe_state(false, false), VmState::Error);
+
+        set(dir.path(), VmState::Stopping).unwrap();
+        assert_eq!(observe_state(false, false), VmState::Stopped);
+
+        // CH went away under a running VM: error, with its last words.
+        fs::write(dir.path().join("ch.log"), "boot\nKVM_RUN failed\n").unwrap();
+        set(dir.path(), VmState::Running).unwrap();
+        assert_eq!(observe_state(true, false), VmState::Running);
+        let crashed = observe(dir.path(), false, false);
+        assert_eq!(crashed.state, VmState::Error);
+        assert!(crashed.error.unwrap().ends_with("boot\nKVM_RUN failed"));
+        assert_eq!(load(dir.path()).unwrap().state, VmState::Error);
+
+        // ...until it is started again.
+        assert_eq!(observe_state(true, false), VmState::Running);
+    }
+}
@@ -0,0 +1,293 @@
+//! Per-VM resource usage as the host sees it, for `meda stats` and the
+//! metrics endpoints.
+//!
+//! Everything comes from outside the guest, so no agent is needed:
+//! CPU time, RSS and start time of the cloud-hypervisor process from
+//! `/proc/<pid>`, the root disk's allocated and virtual size, and byte
+//! counters of the VM's host-side link — its tap, or for netns VMs the
+//! host end of the veth, which carries the same traffic. Counters are
+//! reported from the guest's side: `rx` is what the guest received.
+//!
+//! CPU% needs two readings, so only `sample` fills it in; the metrics
+//! export the cumulative CPU seconds instead and leave the rate to
+//! Prometheus.
+
+use crate::config::{Config, DiskFormat};
+use crate::error::{Error, Result};
+use serde::Serialize;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Clock ticks in `/proc/<pid>/stat`. The kernel always reports these
+/// in USER_HZ, which is 100 on every architecture meda runs on.
+const TICKS_PER_SEC: f64 = 100.0;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VmStats {
+    pub name: String,
+    pub running: bool,
+    /// Share of one host CPU over the sampling interval (can exceed
+    /// 100 with several vCPUs busy).
+    pub cpu_percent: Option<f64>,
+    /// CPU time the VM's process has used since it started.
+    pub cpu_seconds: Option<f64>,
+    pub rss_bytes: Option<u64>,
+    pub uptime_seconds: Option<u64>,
+    /// Space the root disk file takes on the host.
+    pub disk_allocated_bytes: Option<u64>,
+    /// Size of the disk as the guest sees it.
+    pub disk_virtual_bytes: Option<u64>,
+    pub rx_bytes: Option<u64>,
+    pub tx_bytes: Option<u64>,
+}
+
+/// The fields of `/proc/<pid>/stat` we use.
+#[derive(Debug, PartialEq)]
+struct ProcStat {
+    cpu_ticks: u64,
+    start_ticks: u64,
+}
+
+fn parse_proc_stat(body: &str) -> Option<ProcStat> {
+    // The command name may contain spaces and parentheses; fields
+    // after it start at the state (field 3).
+    let fields: Vec<&str> = body[body.rfind(')')? + 1..].split_whitespace().collect();
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+    Some(ProcStat {
+        cpu_ticks: field(14)? + field(15)?,
+        start_ticks: field(22)?,
+    })
+}
+
+/// `VmRSS` from `/proc/<pid>/status`, in bytes.
+fn parse_rss(status: &str) -> Option<u64> {
+    status.lines().find_map(|line| {
+        let kib = line
+            .strip_prefix("VmRSS:")?
+            .split_whitespace()
+            .next()?
+            .parse::<u64>()
+            .ok()?;
+        Some(kib * 1024)
+    })
+}
+
+fn host_uptime() -> Option<f64> {
+    fs::read_to_string("/proc/uptime")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn read_u64(path: &Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Host-side link carrying the VM's traffic.
+fn host_link(vm_dir: &Path) -> Option<String> {
+    if vm_dir.join("netns.json").exists() {
+        let body = fs::read_to_string(vm_dir.join("netns.json")).ok()?;
+        let spec: crate::netns::NetnsSpec = serde_json::from_str(&body).ok()?;
+        return Some(spec.veth_host);
+    }
+    fs::read_to_string(vm_dir.join("tapdev"))
+        .ok()
+        .map(|t| t.trim().to_string())
+}
+
+/// Current usage of VM `name`. Process fields are `None` when it is
+/// not running.
+pub fn collect(config: &Config, name: &str) -> VmStats {
+    let vm_dir = config.vm_dir(name);
+    let mut stats = VmStats {
+        name: name.to_string(),
+        ..Default::default()
+    };
+
+    if let Some((rootfs, format)) = DiskFormat::detect(&vm_dir) {
+        if let Ok(meta) = fs::metadata(&rootfs) {
+            stats.disk_allocated_bytes = Some(meta.blocks() * 512);
+            stats.disk_virtual_bytes = match format {
+                DiskFormat::Raw => Some(meta.len()),
+                DiskFormat::Qcow2 => crate::util::disk_virtual_size(&rootfs),
+            };
+        }
+    }
+
+    let pid = read_u64(&vm_dir.join("pid"));
+    let proc_dir = pid.map(|pid| Path::new("/proc").join(pid.to_string()));
+    let proc_stat = proc_dir
+        .as_ref()
+        .and_then(|dir| parse_proc_stat(&fs::read_to_string(dir.join("stat")).ok()?));
+    let (Some(proc_dir), Some(proc_stat)) = (proc_dir, proc_stat) else {
+        return stats;
+    };
+    stats.running = true;
+    stats.cpu_seconds = Some(proc_stat.cpu_ticks as f64 / TICKS_PER_SEC);
+    stats.rss_bytes = fs::read_to_string(proc_dir.join("status"))
+        .ok()
+        .and_then(|s| parse_rss(&s));
+    stats.uptime_seconds =
+        host_uptime().map(|up| (up - proc_stat.start_ticks as f64 / TICKS_PER_SEC).max(0.0) as u64);
+
+    if let Some(link) = host_link(&vm_dir) {
+        let counters = Path::new("/sys/class/net").join(link).join("statistics");
+        // The host end receives what the guest sends.
+        stats.rx_bytes = read_u64(&counters.join("tx_bytes"));
+        stats.tx_bytes = read_u64(&counters.join("rx_bytes"));
+    }
+    stats
+}
+
+/// `collect` twice, `interval` apart, filling in CPU%.
+pub async fn sample(config: &Config, names: &[String], interval: Duration) -> Vec<VmStats> {
+    let before: Vec<VmStats> = names.iter().map(|n| collect(config, n)).collect();
+    let started = Instant::now();
+    tokio::time::sleep(interval).await;
+    let elapsed = started.elapsed().as_secs_f64();
+    names
+        .iter()
+        .zip(before)
+        .map(|(name, before)| {
+            let mut stats = collect(config, name);
+            if let (Some(a), Some(b)) = (before.cpu_seconds, stats.cpu_seconds) {
+                if b >= a {
+                    stats.cpu_percent = Some((b - a) / elapsed * 100.0);
+                }
+            }
+            stats
+        })
+        .collect()
+}
+
+pub(crate) fn human_bytes(bytes: Option<u64>) -> String {
+    let Some(bytes) = bytes else {
+        return "-".to_string();
+    };
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn human_uptime(secs: Option<u64>) -> String {
+    match secs {
+        None => "-".to_string(),
+        Some(s) if s < 3600 => format!("{}m{:02}s", s / 60, s % 60),
+        Some(s) if s < 86400 => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+        Some(s) => format!("{}d{:02}h", s / 86400, s % 86400 / 3600),
+    }
+}
+
+/// `meda stats [name]`: usage of one VM, or of every VM.
+pub async fn stats_command(
+    config: &Config,
+    name: Option<&str>,
+    interval: Duration,
+    json: bool,
+) -> Result<()> {
+    let names: Vec<String> = match name {
+        Some(name) => {
+            if !config.vm_dir(name).exists() {
+                return Err(Error::VmNotFound(name.to_string()));
+            }
+            vec![name.to_string()]
+        }
+        None => {
+            let mut names: Vec<String> = crate::vm::collect_vms(config)?
+                .into_iter()
+                .map(|vm| vm.name)
+                .collect();
+            names.sort();
+            names
+        }
+    };
+    let stats = sample(config, &names, interval).await;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+    println!(
+        "{:<24} {:<8} {:>6} {:>10} {:>21} {:>8} {:>10} {:>10}",
+        "NAME", "STATE", "CPU%", "RSS", "DISK USED/SIZE", "UPTIME", "NET RX", "NET TX"
+    );
+    for s in &stats {
+        println!(
+            "{:<24} {:<8} {:>6} {:>10} {:>21} {:>8} {:>10} {:>10}",
+            s.name,
+            if s.running { "running" } else { "stopped" },
+            s.cpu_percent
+                .map_or("-".to_string(), |c| format!("{:.1}", c)),
+            human_bytes(s.rss_bytes),
+            format!(
+                "{}/{}",
+                human_bytes(s.disk_allocated_bytes),
+                human_bytes(s.disk_virtual_bytes)
+            ),
+            human_uptime(s.uptime_seconds),
+            human_bytes(s.rx_bytes),
+            human_bytes(s.tx_bytes),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc() {
+        let stat = "4242 (cloud-hyper) visor) S 1 4242 4242 0 -1 4194560 100 0 0 0 250 50 0 0 20 0 3 0 123456 1000000 2000 18446744073709551615";
+        assert_eq!(
+            parse_proc_stat(stat),
+            Some(ProcStat {
+                cpu_ticks: 300,
+                start_ticks: 123456
+            })
+        );
+        assert_eq!(parse_proc_stat("garbage"), None);
+
+        let status = "Name:\tcloud-hypervisor\nVmPeak:\t  900 kB\nVmRSS:\t  524288 kB\n";
+        assert_eq!(parse_rss(status), Some(512 * 1024 * 1024));
+        assert_eq!(parse_rss("Name:\tx\n"), None);
+    }
+
+    #[test]
+    fn test_collect_stopped_vm() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.vm_root = dir.path().to_path_buf();
+        let vm_dir = config.vm_dir("idle");
+        fs::create_dir_all(&vm_dir).unwrap();
+        fs::write(vm_dir.join("rootfs.raw"), vec![0u8; 4096]).unwrap();
+
+        let stats = collect(&config, "idle");
+        assert!(!stats.running);
+        assert_eq!(stats.disk_virtual_bytes, Some(4096));
+        assert!(stats.cpu_seconds.is_none());
+        assert!(stats.rx_bytes.is_none());
+    }
+
+    #[test]
+    fn test_human() {
+        assert_eq!(human_bytes(None), "-");
+        assert_eq!(human_bytes(Some(512)), "512 B");
+        assert_eq!(human_bytes(Some(3 * 1024 * 1024 * 1024 / 2)), "1.5 GiB");
+        assert_eq!(human_uptime(Some(65)), "1m05s");
+        assert_eq!(human_uptime(Some(90061)), "1d01h");
+    }
+}
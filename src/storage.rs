@@ -0,0 +1,300 @@
+//! Storage pools for VM directories.
+//!
+//! `vm_root` stays the one index of VMs: a VM placed on another pool
+//! gets its directory at `<pool>/<name>` and a symlink to it at
+//! `<vm_root>/<name>`, so everything that goes through
+//! `Config::vm_dir` keeps working unchanged.
+//!
+//! Pools come from `MEDA_STORAGE_POOLS`, e.g.
+//! `fast=/nvme/meda-vms:20G,bulk=/hdd/meda-vms`, where the optional
+//! `:SIZE` is the largest VM disk the pool takes under the `size`
+//! policy. `vm_root` itself is the pool called `default`.
+//!
+//! A VM's pool is, in order: `--storage <pool>`, its `storage=<pool>`
+//! label, or what `MEDA_STORAGE_POLICY` picks — `default` (vm_root),
+//! `free-space` (the pool with the most free bytes) or `size` (the
+//! first pool whose size limit fits the VM's disk).
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::labels::Labels;
+use crate::schema::VmManifest;
+use crate::util::parse_size_bytes;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_POOL: &str = "default";
+
+/// Label that pins a VM to a pool when `--storage` isn't given.
+const STORAGE_LABEL: &str = "storage";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoragePool {
+    pub name: String,
+    pub root: PathBuf,
+    /// Largest VM disk (bytes) the `size` policy places here.
+    pub max_disk: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PlacementPolicy {
+    #[default]
+    Default,
+    FreeSpace,
+    Size,
+}
+
+impl PlacementPolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "default" => Some(Self::Default),
+            "free-space" | "free_space" => Some(Self::FreeSpace),
+            "size" => Some(Self::Size),
+            _ => None,
+        }
+    }
+}
+
+/// Parse `name=/path[:SIZE]` entries separated by commas.
+pub fn parse_pools(spec: &str) -> Result<Vec<StoragePool>> {
+    let mut pools: Vec<StoragePool> = Vec::new();
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let bad = || {
+            Error::Other(format!(
+                "Invalid storage pool '{}' (expected name=/path[:SIZE])",
+                entry
+            ))
+        };
+        let (name, rest) = entry.split_once('=').ok_or_else(bad)?;
+        let (path, max_disk) = match rest.rsplit_once(':') {
+            Some((path, size)) => (path, Some(parse_size_bytes(size).ok_or_else(bad)?)),
+            None => (rest, None),
+        };
+        if name.is_empty() || name == DEFAULT_POOL || !path.starts_with('/') {
+            return Err(bad());
+        }
+        if pools.iter().any(|p| p.name == name) {
+            return Err(Error::Other(format!(
+                "Storage pool '{}' defined twice",
+                name
+            )));
+        }
+        pools.push(StoragePool {
+            name: name.to_string(),
+            root: PathBuf::from(path),
+            max_disk,
+        });
+    }
+    Ok(pools)
+}
+
+pub fn free_bytes(path: &Path) -> u64 {
+    let probe = path
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("/"));
+    match nix::sys::statvfs::statvfs(probe) {
+        // See host_capacity::total_disk_gb for the cast.
+        #[allow(clippy::unnecessary_cast)]
+        Ok(st) => (st.blocks_available() as u64) * (st.fragment_size() as u64),
+        Err(_) => 0,
+    }
+}
+
+/// Pick the pool for a new VM; `None` means `vm_root`.
+pub fn select_pool<'a>(
+    config: &'a Config,
+    requested: Option<&str>,
+    labels: &Labels,
+    disk_size: &str,
+) -> Result<Option<&'a StoragePool>> {
+    let wanted = requested.or_else(|| labels.get(STORAGE_LABEL).map(String::as_str));
+    if let Some(name) = wanted {
+        if name == DEFAULT_POOL {
+            return Ok(None);
+        }
+        return config
+            .storage_pools
+            .iter()
+            .find(|p| p.name == name)
+            .map(Some)
+            .ok_or_else(|| {
+                let known: Vec<&str> = std::iter::once(DEFAULT_POOL)
+                    .chain(config.storage_pools.iter().map(|p| p.name.as_str()))
+                    .collect();
+                Error::Other(format!(
+                    "Unknown storage pool '{}' (configured: {})",
+                    name,
+                    known.join(", ")
+                ))
+            });
+    }
+
+    match config.placement {
+        PlacementPolicy::Default => Ok(None),
+        PlacementPolicy::FreeSpace => {
+            let default_free = free_bytes(&config.vm_root);
+            Ok(config
+                .storage_pools
+                .iter()
+                .map(|p| (p, free_bytes(&p.root)))
+                .filter(|(_, free)| *free > default_free)
+                .max_by_key(|(_, free)| *free)
+                .map(|(p, _)| p))
+        }
+        PlacementPolicy::Size => {
+            let disk = parse_size_bytes(disk_size).unwrap_or(u64::MAX);
+            Ok(config
+                .storage_pools
+                .iter()
+                .find(|p| p.max_disk.is_none_or(|max| disk <= max)))
+        }
+    }
+}
+
+/// Create the directory of VM `name` on `pool`, with its manifest, and
+/// return its path under `vm_root` (a symlink for anything but the
+/// default pool).
+pub fn create_vm_dir(config: &Config, name: &str, pool: Option<&StoragePool>) -> Result<PathBuf> {
+    let vm_dir = config.vm_dir(name);
+    let Some(pool) = pool else {
+        fs::create_dir_all(&vm_dir)?;
+        VmManifest::new(name).save(&vm_dir)?;
+        return Ok(vm_dir);
+    };
+    let target = pool.root.join(name);
+    if target.exists() {
+        return Err(Error::Other(format!(
+            "{} already exists on storage pool '{}'; remove it or pick another name",
+            target.display(),
+            pool.name
+        )));
+    }
+    fs::create_dir_all(&target)?;
+    fs::create_dir_all(&config.vm_root)?;
+    std::os::unix::fs::symlink(&target, &vm_dir)?;
+    VmManifest::new(name).save(&vm_dir)?;
+    Ok(vm_dir)
+}
+
+/// Name of the pool VM `name` lives on.
+pub fn pool_of(config: &Config, name: &str) -> String {
+    let vm_dir = config.vm_dir(name);
+    match fs::read_link(&vm_dir) {
+        Ok(target) => config
+            .storage_pools
+            .iter()
+            .find(|p| target.parent() == Some(p.root.as_path()))
+            .map(|p| p.name.clone())
+            // Pool since removed from MEDA_STORAGE_POOLS: show where it is.
+            .unwrap_or_else(|| target.parent().unwrap_or(&target).display().to_string()),
+        Err(_) => DEFAULT_POOL.to_string(),
+    }
+}
+
+/// Remove a VM directory, following the `vm_root` symlink to the pool.
+pub fn remove_vm_dir(vm_dir: &Path) -> io::Result<()> {
+    match fs::read_link(vm_dir) {
+        Ok(target) => {
+            match fs::remove_dir_all(&target) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            fs::remove_file(vm_dir)
+        }
+        Err(_) => fs::remove_dir_all(vm_dir),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_pools() {
+        let pools = parse_pools("fast=/nvme/vms:20G, bulk=/hdd/vms").unwrap();
+        assert_eq!(pools.len(), 2);
+        assert_eq!(pools[0].name, "fast");
+        assert_eq!(pools[0].root, PathBuf::from("/nvme/vms"));
+        assert_eq!(pools[0].max_disk, Some(20 * 1024 * 1024 * 1024));
+        assert_eq!(pools[1].max_disk, None);
+
+        assert!(parse_pools("").unwrap().is_empty());
+        assert!(parse_pools("fast").is_err());
+        assert!(parse_pools("fast=relative/path").is_err());
+        assert!(parse_pools("default=/x").is_err());
+        assert!(parse_pools("a=/x,a=/y").is_err());
+        assert!(parse_pools("a=/x:lots").is_err());
+    }
+
+    fn config_with_pools(dir: &Path) -> Config {
+        let mut config = Config::new().unwrap();
+        config.vm_root = dir.join("vms");
+        config.storage_pools = vec![
+            StoragePool {
+                name: "small".into(),
+                root: dir.join("small"),
+                max_disk: Some(10 * 1024 * 1024 * 1024),
+            },
+            StoragePool {
+                name: "bulk".into(),
+                root: dir.join("bulk"),
+                max_disk: None,
+            },
+        ];
+        config
+    }
+
+    #[test]
+    fn test_select_pool() {
+        let dir = TempDir::new().unwrap();
+        let mut config = config_with_pools(dir.path());
+        let none = Labels::new();
+        let pick = |config: &Config, req, labels: &Labels, disk| {
+            select_pool(config, req, labels, disk)
+                .unwrap()
+                .map(|p| p.name.clone())
+        };
+
+        assert_eq!(pick(&config, None, &none, "10G"), None);
+        assert_eq!(
+            pick(&config, Some("bulk"), &none, "10G").as_deref(),
+            Some("bulk")
+        );
+        assert_eq!(pick(&config, Some("default"), &none, "10G"), None);
+        let labelled: Labels = [("storage".to_string(), "small".to_string())].into();
+        assert_eq!(
+            pick(&config, None, &labelled, "10G").as_deref(),
+            Some("small")
+        );
+        assert!(select_pool(&config, Some("nope"), &none, "10G").is_err());
+
+        config.placement = PlacementPolicy::Size;
+        assert_eq!(pick(&config, None, &none, "5G").as_deref(), Some("small"));
+        assert_eq!(pick(&config, None, &none, "50G").as_deref(), Some("bulk"));
+    }
+
+    #[test]
+    fn test_create_and_remove_on_pool() {
+        let dir = TempDir::new().unwrap();
+        let config = config_with_pools(dir.path());
+
+        let vm_dir = create_vm_dir(&config, "vm1", config.storage_pools.get(1)).unwrap();
+        assert_eq!(vm_dir, config.vm_dir("vm1"));
+        assert!(vm_dir.is_dir());
+        assert!(dir.path().join("bulk/vm1").is_dir());
+        assert_eq!(pool_of(&config, "vm1"), "bulk");
+        assert!(create_vm_dir(&config, "vm1", config.storage_pools.get(1)).is_err());
+
+        remove_vm_dir(&vm_dir).unwrap();
+        assert!(!dir.path().join("bulk/vm1").exists());
+        assert!(fs::symlink_metadata(&vm_dir).is_err());
+
+        let vm_dir = create_vm_dir(&config, "vm2", None).unwrap();
+        assert_eq!(pool_of(&config, "vm2"), DEFAULT_POOL);
+        remove_vm_dir(&vm_dir).unwrap();
+        assert!(!vm_dir.exists());
+    }
+}
@@ -0,0 +1,585 @@
+//! Ledger of the `/24` subnets handed out to VMs.
+//!
+//! Every VM gets a /24 from a pool: 200 by default (`192.168.16` –
+//! `192.168.215`), or every /24 of `MEDA_SUBNET_POOL`, a /16 to /24
+//! parent CIDR such as `10.77.0.0/16`. A VM's `subnet` file holds the
+//! first three octets (`192.168.X`). Picking one used to mean scanning VM dirs, so an
+//! allocation whose VM dir never got its `subnet` file (a crash midway
+//! through create) was invisible, and two concurrent creates could pick
+//! the same octet.
+//!
+//! The ledger (`allocations.json` in the VM dir root) records a lease per
+//! VM and is only touched under `.allocations.lock`. It is the record of
+//! which subnets are in use: allocation and network cleanup read it
+//! rather than every VM dir. VM dirs are only scanned to build it the
+//! first time and by `meda network prune`, which adopts subnets of VM
+//! dirs without a lease. Leases are released when the VM is deleted;
+//! prune (and every allocation) also reclaims leases whose VM dir is gone
+//! or no longer uses the subnet. Snapshot clones share their template's
+//! subnet (each runs in its own netns), so one octet can have several
+//! leases.
+//!
+//! Allocation skips subnets the host already routes or has an address
+//! in (see `network::generate_unique_subnet`), which are passed in as
+//! excluded octets.
+//!
+//! Allocation is deterministic. A VM recreated under the same name gets
+//! the subnet it had back if it is still free, so its addresses don't
+//! change. Otherwise it gets the lowest octet never handed out, then the
+//! one released longest ago: a subnet just given up isn't handed to
+//! another VM while clients may still have ARP or route state for it.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::lock::FileLock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+const LEDGER_FILE: &str = "allocations.json";
+const LOCK_FILE: &str = ".allocations.lock";
+/// Where the ledger was kept before it was renamed; read once and
+/// removed on the next save.
+const LEGACY_LEDGER_FILE: &str = ".subnets.json";
+
+/// First third octet of the default pool.
+const POOL_START: u8 = 16;
+/// Number of /24s in the default pool.
+const POOL_SIZE: u8 = 200;
+
+/// Leases younger than this are never reclaimed: the VM dir's `subnet`
+/// file is written just after the lease is taken.
+const LEASE_GRACE_SECS: u64 = 120;
+
+/// The /24s VMs get subnets from: `A.B.X.0/24` for third octets `X`
+/// from `start` on. Leases only record `X`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubnetPool {
+    prefix: [u8; 2],
+    start: u8,
+    size: u16,
+}
+
+impl Default for SubnetPool {
+    fn default() -> Self {
+        Self {
+            prefix: [192, 168],
+            start: POOL_START,
+            size: POOL_SIZE as u16,
+        }
+    }
+}
+
+impl std::fmt::Display for SubnetPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [a, b] = self.prefix;
+        let end = self.start as u16 + self.size - 1;
+        write!(f, "{}.{}.{}-{}", a, b, self.start, end)
+    }
+}
+
+impl SubnetPool {
+    /// `MEDA_SUBNET_POOL`: a /16 to /24 CIDR, e.g. `10.77.0.0/16`.
+    pub fn parse(cidr: &str) -> std::result::Result<Self, String> {
+        let (addr, len) = cidr
+            .trim()
+            .split_once('/')
+            .ok_or_else(|| format!("'{}' is not a CIDR such as 10.77.0.0/16", cidr))?;
+        let addr: Ipv4Addr = addr
+            .parse()
+            .map_err(|_| format!("'{}' is not an IPv4 address", addr))?;
+        let len: u32 = len
+            .parse()
+            .ok()
+            .filter(|l| (16..=24).contains(l))
+            .ok_or_else(|| format!("'{}': the prefix must be /16 to /24", cidr))?;
+        let [a, b, c, d] = addr.octets();
+        let size = 1u16 << (24 - len);
+        if d != 0 || c as u16 % size != 0 {
+            return Err(format!("'{}' has host bits set", cidr));
+        }
+        if [a, b] == [10, 99] {
+            return Err(format!(
+                "'{}' overlaps 10.99.0.0/16, which isolated VMs' veths use",
+                cidr
+            ));
+        }
+        Ok(Self {
+            prefix: [a, b],
+            start: c,
+            size,
+        })
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size as u32
+    }
+
+    fn contains(&self, octet: u8) -> bool {
+        (self.start as u16..self.start as u16 + self.size).contains(&(octet as u16))
+    }
+
+    /// Third octets of the pool's subnets, in order.
+    pub fn octets(&self) -> impl Iterator<Item = u8> {
+        let start = self.start as u16;
+        (start..start + self.size).map(|o| o as u8)
+    }
+
+    /// The subnet (`A.B.X`) with third octet `octet`.
+    pub fn subnet(&self, octet: u8) -> String {
+        let [a, b] = self.prefix;
+        format!("{}.{}.{}", a, b, octet)
+    }
+
+    /// The subnet's network address, `A.B.X.0`.
+    pub fn network(&self, octet: u8) -> Ipv4Addr {
+        let [a, b] = self.prefix;
+        Ipv4Addr::new(a, b, octet, 0)
+    }
+
+    /// Third octet of `subnet` (`A.B.X`), if it is one of the pool's.
+    pub fn octet(&self, subnet: &str) -> Option<u8> {
+        let [a, b] = self.prefix;
+        subnet
+            .trim()
+            .strip_prefix(&format!("{}.{}.", a, b))?
+            .parse()
+            .ok()
+            .filter(|o| self.contains(*o))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    pub octet: u8,
+    pub vm: String,
+    pub leased_at: u64,
+}
+
+/// The last VM a released octet belonged to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Release {
+    pub octet: u8,
+    pub vm: String,
+    pub released_at: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Ledger {
+    leases: Vec<Lease>,
+    /// Oldest release first; at most one per octet and per VM.
+    #[serde(default)]
+    released: Vec<Release>,
+}
+
+/// Subnet pool utilization, as shown by `meda capacity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PoolUsage {
+    pub total: u32,
+    pub leased: u32,
+    pub free: u32,
+}
+
+impl Ledger {
+    /// The ledger, or a new one built from the VM dirs if there is none.
+    fn load(config: &Config) -> Result<Self> {
+        for file in [LEDGER_FILE, LEGACY_LEDGER_FILE] {
+            match fs::read_to_string(config.vm_root.join(file)) {
+                Ok(body) => {
+                    return serde_json::from_str(&body).map_err(|e| {
+                        Error::Other(format!("Corrupt subnet ledger {}: {}", file, e))
+                    })
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut ledger = Self::default();
+        ledger.adopt(&config.vm_root, &config.subnet_pool, now_secs());
+        Ok(ledger)
+    }
+
+    /// Write via a temp file + rename so a crash never leaves half a ledger.
+    fn save(&self, config: &Config) -> Result<()> {
+        let path = config.vm_root.join(LEDGER_FILE);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp, &path)?;
+        fs::remove_file(config.vm_root.join(LEGACY_LEDGER_FILE)).ok();
+        Ok(())
+    }
+
+    fn leased_octets(&self) -> HashSet<u8> {
+        self.leases.iter().map(|l| l.octet).collect()
+    }
+
+    /// Record that `lease` ended.
+    fn release(&mut self, lease: Lease, now: u64) {
+        self.released
+            .retain(|r| r.octet != lease.octet && r.vm != lease.vm);
+        self.released.push(Release {
+            octet: lease.octet,
+            vm: lease.vm,
+            released_at: now,
+        });
+    }
+
+    /// Release leases that expired (see the module docs). Only looks at
+    /// the leased VMs' dirs. Returns the released leases.
+    fn expire(&mut self, vm_root: &Path, pool: &SubnetPool, now: u64) -> Vec<Lease> {
+        let (kept, stale): (Vec<Lease>, Vec<Lease>) =
+            std::mem::take(&mut self.leases).into_iter().partition(|l| {
+                now.saturating_sub(l.leased_at) < LEASE_GRACE_SECS
+                    || vm_subnet_octet(&vm_root.join(&l.vm), pool) == Some(l.octet)
+            });
+        self.leases = kept;
+        for lease in &stale {
+            self.release(lease.clone(), now);
+        }
+        stale
+    }
+
+    /// Lease the subnets of VM dirs that have none.
+    fn adopt(&mut self, vm_root: &Path, pool: &SubnetPool, now: u64) {
+        let Ok(entries) = fs::read_dir(vm_root) else {
+            return;
+        };
+        for path in entries.flatten().map(|e| e.path()) {
+            if !path.is_dir() {
+                continue;
+            }
+            let (Some(octet), Some(vm)) = (vm_subnet_octet(&path, pool), path.file_name()) else {
+                continue;
+            };
+            let vm = vm.to_string_lossy();
+            if !self.leases.iter().any(|l| l.vm == vm && l.octet == octet) {
+                self.leases.push(Lease {
+                    octet,
+                    vm: vm.to_string(),
+                    leased_at: now,
+                });
+            }
+        }
+    }
+
+    /// The octet to lease to `vm` (see the module docs), skipping leased
+    /// ones and those in `exclude`.
+    fn pick(&self, vm: &str, pool: &SubnetPool, exclude: &HashSet<u8>) -> Option<u8> {
+        let taken = self.leased_octets();
+        let free = |octet: &u8| {
+            pool.contains(*octet) && !taken.contains(octet) && !exclude.contains(octet)
+        };
+        let previous = self
+            .released
+            .iter()
+            .find(|r| r.vm == vm)
+            .map(|r| r.octet)
+            .filter(free);
+        previous
+            .or_else(|| {
+                pool.octets()
+                    .find(|o| free(o) && !self.released.iter().any(|r| r.octet == *o))
+            })
+            .or_else(|| self.released.iter().map(|r| r.octet).find(free))
+    }
+
+    fn usage(&self, pool: &SubnetPool) -> PoolUsage {
+        let leased = self
+            .leased_octets()
+            .into_iter()
+            .filter(|o| pool.contains(*o))
+            .count() as u32;
+        PoolUsage {
+            total: pool.size(),
+            leased,
+            free: pool.size() - leased,
+        }
+    }
+}
+
+/// Third octet from a VM dir's `subnet` file (`A.B.X`), if it is in
+/// `pool`.
+fn vm_subnet_octet(vm_dir: &Path, pool: &SubnetPool) -> Option<u8> {
+    pool.octet(&fs::read_to_string(vm_dir.join("subnet")).ok()?)
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Run `f` on the ledger under its lock, saving it afterwards.
+fn with_ledger<T>(config: &Config, f: impl FnOnce(&mut Ledger) -> Result<T>) -> Result<T> {
+    fs::create_dir_all(&config.vm_root)?;
+    let _lock = FileLock::exclusive(&config.vm_root.join(LOCK_FILE))?;
+    let mut ledger = Ledger::load(config)?;
+    let result = f(&mut ledger)?;
+    ledger.save(config)?;
+    Ok(result)
+}
+
+/// Lease a free subnet for `vm`, skipping octets in `exclude` (ones
+/// the host already uses). Returns `A.B.X`.
+pub fn allocate(config: &Config, vm: &str, exclude: &HashSet<u8>) -> Result<String> {
+    let pool = &config.subnet_pool;
+    with_ledger(config, |ledger| {
+        let now = now_secs();
+        ledger.expire(&config.vm_root, pool, now);
+        let Some(octet) = ledger.pick(vm, pool, exclude) else {
+            let conflicts = pool.octets().filter(|o| exclude.contains(o)).count();
+            return Err(Error::Other(format!(
+                "Subnet pool exhausted: all {} subnets ({}) are in use{}. \
+                 Delete unused VMs, run `meda network prune` or set MEDA_SUBNET_POOL",
+                pool.size(),
+                pool,
+                if conflicts > 0 {
+                    format!(" ({} overlap host networks)", conflicts)
+                } else {
+                    String::new()
+                }
+            )));
+        };
+        ledger.leases.retain(|l| l.vm != vm);
+        ledger.released.retain(|r| r.octet != octet && r.vm != vm);
+        ledger.leases.push(Lease {
+            octet,
+            vm: vm.to_string(),
+            leased_at: now,
+        });
+        Ok(pool.subnet(octet))
+    })
+}
+
+/// Record that `vm` uses `subnet` (`A.B.X`), e.g. a snapshot clone
+/// sharing its template's. Subnets outside the pool aren't tracked.
+pub fn lease(config: &Config, vm: &str, subnet: &str) -> Result<()> {
+    let Some(octet) = config.subnet_pool.octet(subnet) else {
+        return Ok(());
+    };
+    with_ledger(config, |ledger| {
+        ledger.leases.retain(|l| l.vm != vm);
+        ledger.released.retain(|r| r.octet != octet);
+        ledger.leases.push(Lease {
+            octet,
+            vm: vm.to_string(),
+            leased_at: now_secs(),
+        });
+        Ok(())
+    })
+}
+
+/// Release `vm`'s lease, if it has one.
+pub fn release(config: &Config, vm: &str) -> Result<()> {
+    if !config.vm_root.join(LEDGER_FILE).exists()
+        && !config.vm_root.join(LEGACY_LEDGER_FILE).exists()
+    {
+        return Ok(());
+    }
+    with_ledger(config, |ledger| {
+        let now = now_secs();
+        let (released, kept) = std::mem::take(&mut ledger.leases)
+            .into_iter()
+            .partition(|l| l.vm == vm);
+        ledger.leases = kept;
+        for lease in released {
+            ledger.release(lease, now);
+        }
+        Ok(())
+    })
+}
+
+/// Find (and unless `dry_run`, drop) leases of VMs that no longer use
+/// their subnet, and adopt VM dirs without a lease.
+pub fn reclaim(config: &Config, dry_run: bool) -> Result<Vec<Lease>> {
+    let reconcile = |ledger: &mut Ledger| {
+        let now = now_secs();
+        let stale = ledger.expire(&config.vm_root, &config.subnet_pool, now);
+        ledger.adopt(&config.vm_root, &config.subnet_pool, now);
+        stale
+    };
+    if dry_run {
+        return Ok(reconcile(&mut Ledger::load(config)?));
+    }
+    with_ledger(config, |ledger| Ok(reconcile(ledger)))
+}
+
+/// The ledger as the next allocation would see it. Read-only: stale
+/// leases dropped here are dropped in memory only.
+fn current(config: &Config) -> Result<Ledger> {
+    let mut ledger = Ledger::load(config)?;
+    ledger.expire(&config.vm_root, &config.subnet_pool, now_secs());
+    Ok(ledger)
+}
+
+/// Current pool utilization.
+pub fn usage(config: &Config) -> Result<PoolUsage> {
+    Ok(current(config)?.usage(&config.subnet_pool))
+}
+
+/// Subnets (`A.B.X`) some VM holds a lease on.
+pub fn in_use(config: &Config) -> Result<HashSet<String>> {
+    Ok(current(config)?
+        .leases
+        .iter()
+        .map(|l| config.subnet_pool.subnet(l.octet))
+        .collect())
+}
+
+/// Does a VM other than `vm` hold a lease on `subnet` (`A.B.X`)?
+pub fn shared(config: &Config, vm: &str, subnet: &str) -> Result<bool> {
+    let pool = &config.subnet_pool;
+    Ok(current(config)?
+        .leases
+        .iter()
+        .any(|l| l.vm != vm && pool.subnet(l.octet) == subnet.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn test_config(root: &Path) -> Config {
+        let mut config = Config::new().unwrap();
+        config.vm_root = root.to_path_buf();
+        config
+    }
+
+    fn write_vm(root: &Path, vm: &str, subnet: &str) {
+        fs::create_dir_all(root.join(vm)).unwrap();
+        fs::write(root.join(vm).join("subnet"), subnet).unwrap();
+    }
+
+    #[test]
+    fn test_allocate_and_release() {
+        let dir = TempDir::new().unwrap();
+        let config = test_config(dir.path());
+        write_vm(dir.path(), "old", "192.168.20");
+
+        let exclude: HashSet<u8> = [21].into();
+        let subnet = allocate(&config, "vm1", &exclude).unwrap();
+        write_vm(dir.path(), "vm1", &subnet);
+        assert_ne!(subnet, "192.168.20");
+        assert_ne!(subnet, "192.168.21");
+
+        // The pre-ledger VM was adopted.
+        let ledger = Ledger::load(&config).unwrap();
+        assert!(ledger.leases.iter().any(|l| l.vm == "old" && l.octet == 20));
+        assert_eq!(usage(&config).unwrap().leased, 2);
+
+        release(&config, "vm1").unwrap();
+        fs::remove_dir_all(dir.path().join("vm1")).unwrap();
+        assert_eq!(usage(&config).unwrap().leased, 1);
+    }
+
+    #[test]
+    fn test_expire_and_adopt() {
+        let dir = TempDir::new().unwrap();
+        write_vm(dir.path(), "live", "192.168.30");
+        write_vm(dir.path(), "moved", "192.168.31");
+        let lease = |octet, vm: &str, leased_at| Lease {
+            octet,
+            vm: vm.to_string(),
+            leased_at,
+        };
+        let mut ledger = Ledger {
+            leases: vec![
+                lease(30, "live", 0),
+                lease(40, "moved", 0),
+                lease(50, "crashed", 0),
+                lease(60, "creating", 1000),
+            ],
+            released: Vec::new(),
+        };
+
+        let pool = SubnetPool::default();
+        let stale = ledger.expire(dir.path(), &pool, 1000);
+        assert_eq!(stale, vec![lease(40, "moved", 0), lease(50, "crashed", 0)]);
+        assert_eq!(ledger.released.len(), 2);
+        ledger.adopt(dir.path(), &pool, 1000);
+        let mut octets: Vec<u8> = ledger.leased_octets().into_iter().collect();
+        octets.sort();
+        assert_eq!(octets, vec![30, 31, 60]);
+        assert_eq!(ledger.usage(&pool).free, POOL_SIZE as u32 - 3);
+    }
+
+    #[test]
+    fn test_deterministic_reuse() {
+        let dir = TempDir::new().unwrap();
+        let config = test_config(dir.path());
+        let none = HashSet::new();
+        fs::write(
+            dir.path().join(LEGACY_LEDGER_FILE),
+            r#"{"leases": [{"octet": 16, "vm": "old", "leased_at": 0}]}"#,
+        )
+        .unwrap();
+        write_vm(dir.path(), "old", "192.168.16");
+
+        // Lowest free octet first; the legacy ledger is carried over.
+        let a = allocate(&config, "a", &none).unwrap();
+        assert_eq!(a, "192.168.17");
+        assert!(!dir.path().join(LEGACY_LEDGER_FILE).exists());
+        assert_eq!(allocate(&config, "b", &none).unwrap(), "192.168.18");
+        write_vm(dir.path(), "a", &a);
+
+        // A released octet goes back to its VM, and to no other while
+        // there are octets never used.
+        release(&config, "a").unwrap();
+        fs::remove_dir_all(dir.path().join("a")).unwrap();
+        assert_eq!(allocate(&config, "c", &none).unwrap(), "192.168.19");
+        assert_eq!(allocate(&config, "a", &none).unwrap(), "192.168.17");
+
+        assert!(shared(&config, "b", "192.168.17").unwrap());
+        assert!(!shared(&config, "a", "192.168.17").unwrap());
+        assert!(in_use(&config).unwrap().contains("192.168.16"));
+    }
+
+    #[test]
+    fn test_subnet_pool() {
+        let pool = SubnetPool::parse("10.77.0.0/16").unwrap();
+        assert_eq!(pool.size(), 256);
+        assert_eq!(pool.to_string(), "10.77.0-255");
+        assert_eq!(pool.octet("10.77.200"), Some(200));
+        assert_eq!(pool.octet("192.168.20"), None);
+        let pool = SubnetPool::parse("172.20.32.0/20").unwrap();
+        assert_eq!(
+            pool.octets().collect::<Vec<_>>(),
+            (32..48).collect::<Vec<_>>()
+        );
+        assert_eq!(pool.octet("172.20.48"), None);
+        assert_eq!(SubnetPool::default().to_string(), "192.168.16-215");
+        for bad in [
+            "10.77.0.0",
+            "10.0.0.0/8",
+            "10.77.1.0/16",
+            "10.99.0.0/16",
+            "x/24",
+        ] {
+            assert!(SubnetPool::parse(bad).is_err(), "{}", bad);
+        }
+
+        let dir = TempDir::new().unwrap();
+        let mut config = test_config(dir.path());
+        config.subnet_pool = SubnetPool::parse("10.77.4.0/23").unwrap();
+        write_vm(dir.path(), "old", "192.168.16");
+        let exclude: HashSet<u8> = [4].into();
+        assert_eq!(allocate(&config, "a", &exclude).unwrap(), "10.77.5");
+        assert!(in_use(&config).unwrap().contains("10.77.5"));
+        assert_eq!(usage(&config).unwrap().total, 2);
+        let err = allocate(&config, "b", &exclude).unwrap_err().to_string();
+        assert!(err.contains("(10.77.4-5)"), "{}", err);
+        assert!(err.contains("1 overlap host networks"), "{}", err);
+    }
+
+    #[test]
+    fn test_allocate_exhausted() {
+        let dir = TempDir::new().unwrap();
+        let config = test_config(dir.path());
+        let exclude: HashSet<u8> = (0..=255).collect();
+        assert!(allocate(&config, "vm1", &exclude).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! `meda system-info` (also `meda system host`) and `GET /api/v1/system`:
+//! the host facts a scheduler checks before placing another VM here.
+//!
+//! Unlike `meda capacity`, which reports the admission budget in whole
+//! GiB, this is the raw picture: memory and disk in bytes as the kernel
+//! reports them right now, whether KVM is usable, and how many VMs run.
+
+use crate::config::Config;
+use crate::error::Result;
+use serde::Serialize;
+use std::path::Path;
+use utoipa::ToSchema;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SystemInfo {
+    /// Host name
+    pub hostname: String,
+    /// Host architecture, by its OCI name (amd64, arm64)
+    pub arch: String,
+    /// meda version
+    pub version: String,
+    /// Logical CPUs
+    pub cpus: u32,
+    /// Physical memory, in bytes
+    pub memory_total_bytes: u64,
+    /// Memory available to new guests without swapping, in bytes
+    pub memory_available_bytes: u64,
+    /// Filesystem holding the VM dirs
+    pub vm_root: DiskSpace,
+    /// Filesystem holding base images and hypervisor binaries
+    pub asset_dir: DiskSpace,
+    /// Whether /dev/kvm is there for cloud-hypervisor to use
+    pub kvm: bool,
+    /// VMs currently running
+    pub running_vms: usize,
+    /// All VMs, in any state; not counting `meda run`'s image templates
+    pub total_vms: usize,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DiskSpace {
+    pub path: String,
+    /// Space available to meda, in bytes
+    pub free_bytes: u64,
+}
+
+impl DiskSpace {
+    fn of(path: &Path) -> Self {
+        Self {
+            path: path.display().to_string(),
+            free_bytes: crate::storage::free_bytes(path),
+        }
+    }
+}
+
+pub fn collect(config: &Config) -> Result<SystemInfo> {
+    let vms = crate::vm::collect_vms(config)?;
+    Ok(SystemInfo {
+        hostname: crate::inventory::hostname(),
+        arch: crate::platform::host_arch().to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        cpus: crate::host_capacity::total_cpu(),
+        memory_total_bytes: crate::host_capacity::total_mem_bytes().unwrap_or(0),
+        memory_available_bytes: crate::host_capacity::available_mem_bytes().unwrap_or(0),
+        vm_root: DiskSpace::of(&config.vm_root),
+        asset_dir: DiskSpace::of(&config.asset_dir),
+        kvm: crate::preflight::kvm().status != crate::doctor::Status::Fail,
+        running_vms: vms.iter().filter(|vm| vm.state == "running").count(),
+        total_vms: vms.len(),
+    })
+}
+
+fn gib(bytes: u64) -> String {
+    format!("{:.1} GiB", bytes as f64 / (1024.0 * 1024.0 * 1024.0))
+}
+
+pub fn system_info_command(config: &Config, json: bool) -> Result<()> {
+    let info = collect(config)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+    println!(
+        "Host:      {} ({}, meda {})",
+        info.hostname, info.arch, info.version
+    );
+    println!("CPUs:      {}", info.cpus);
+    println!(
+        "Memory:    {} available of {}",
+        gib(info.memory_available_bytes),
+        gib(info.memory_total_bytes)
+    );
+    for (label, disk) in [("VM root:", &info.vm_root), ("Assets:", &info.asset_dir)] {
+        println!(
+            "{:<10} {} free ({})",
+            label,
+            gib(disk.free_bytes),
+            disk.path
+        );
+    }
+    println!(
+        "KVM:       {}",
+        if info.kvm { "available" } else { "missing" }
+    );
+    println!(
+        "VMs:       {} running, {} total",
+        info.running_vms, info.total_vms
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_collect() {
+        let dir = TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.vm_root = dir.path().join("vms");
+        config.asset_dir = dir.path().join("assets");
+        std::fs::create_dir_all(&config.vm_root).unwrap();
+
+        let info = collect(&config).unwrap();
+        assert_eq!(info.total_vms, 0);
+        assert_eq!(info.running_vms, 0);
+
+        for name in ["web", "__tpl_ubuntu", ".migrate-db"] {
+            std::fs::create_dir_all(config.vm_dir(name)).unwrap();
+        }
+        assert_eq!(collect(&config).unwrap().total_vms, 1);
+        assert!(info.cpus >= 1);
+        assert!(info.memory_total_bytes >= info.memory_available_bytes);
+        // A dir yet to be created reports its nearest existing parent's space.
+        assert_eq!(info.asset_dir.path, config.asset_dir.display().to_string());
+        assert!(info.asset_dir.free_bytes > 0);
+    }
+}
@@ -0,0 +1,165 @@
+//! Expiry of ephemeral VMs: `meda run --ttl 90m` and `meda reap`.
+//!
+//! A TTL is recorded as an absolute expiry time in the VM's
+//! `metadata.json`. `meda reap` stops and deletes every VM past its
+//! expiry, and `meda serve --reap-interval 1m` does the same in the
+//! background, so CI runners that never get cleaned up by their job
+//! don't hold on to host capacity.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::labels::VmMetadata;
+use crate::vm;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Longest TTL accepted, ten years; far beyond any real use but keeps
+/// expiry times representable.
+const MAX_TTL: u64 = 10 * 365 * 24 * 60 * 60;
+
+/// Clap value parser for `--ttl` (`90m`, `2h`, `1d`, ...), in seconds.
+pub fn parse_ttl(s: &str) -> std::result::Result<u64, String> {
+    crate::util::parse_duration_secs(s)
+        .filter(|secs| *secs > 0 && *secs <= MAX_TTL)
+        .ok_or_else(|| format!("invalid TTL '{}' (e.g. 45s, 90m, 12h, 7d)", s))
+}
+
+/// Record that the VM in `vm_dir` expires `ttl` seconds from now.
+pub fn set(vm_dir: &Path, ttl: u64) -> Result<DateTime<Utc>> {
+    let expires_at = Utc::now() + chrono::Duration::seconds(ttl as i64);
+    let mut metadata = VmMetadata::load(vm_dir)?;
+    metadata.expires_at = Some(expires_at);
+    metadata.save(vm_dir)?;
+    Ok(expires_at)
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Expired {
+    pub name: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// VMs that expired at or before `now`, oldest expiry first.
+pub fn expired(config: &Config, now: DateTime<Utc>) -> Vec<Expired> {
+    let mut expired: Vec<Expired> = vm::vm_names(config)
+        .into_iter()
+        .filter_map(|name| {
+            let expires_at = VmMetadata::load(&config.vm_dir(&name)).ok()?.expires_at?;
+            (expires_at <= now).then_some(Expired { name, expires_at })
+        })
+        .collect();
+    expired.sort_by(|a, b| a.expires_at.cmp(&b.expires_at));
+    expired
+}
+
+/// Output of `meda reap`.
+#[derive(Debug, Default, Serialize)]
+pub struct ReapReport {
+    pub reaped: Vec<Expired>,
+    /// `<vm>: <error>` for VMs that could not be deleted.
+    pub errors: Vec<String>,
+}
+
+/// Stop and delete expired VMs (unless `dry_run`).
+pub async fn reap(config: &Config, dry_run: bool) -> ReapReport {
+    let mut report = ReapReport::default();
+    for vm in expired(config, Utc::now()) {
+        if !dry_run {
+            if let Err(e) = vm::delete(config, &vm.name, false).await {
+                report.errors.push(format!("{}: {}", vm.name, e));
+                continue;
+            }
+        }
+        report.reaped.push(vm);
+    }
+    report
+}
+
+pub async fn reap_command(config: &Config, dry_run: bool, json: bool) -> Result<()> {
+    let report = reap(config, dry_run).await;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report, dry_run);
+    }
+    if !report.errors.is_empty() {
+        return Err(Error::Other(format!(
+            "Failed to delete {} of {} expired VMs",
+            report.errors.len(),
+            report.reaped.len() + report.errors.len()
+        )));
+    }
+    Ok(())
+}
+
+fn print_report(report: &ReapReport, dry_run: bool) {
+    let verb = if dry_run { "Would delete" } else { "Deleted" };
+    for vm in &report.reaped {
+        println!(
+            "{} {} (expired {})",
+            verb,
+            vm.name,
+            vm.expires_at.to_rfc3339()
+        );
+    }
+    if report.reaped.is_empty() && report.errors.is_empty() {
+        println!("No expired VMs");
+    }
+    for err in &report.errors {
+        println!("⚠️  could not delete {}", err);
+    }
+}
+
+/// Reap expired VMs every `interval`, for `meda serve`.
+pub async fn reap_periodically(config: Arc<Config>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let report = reap(&config, false).await;
+        for vm in &report.reaped {
+            info!("Reaped expired VM {} (expired {})", vm.name, vm.expires_at);
+        }
+        for err in &report.errors {
+            warn!("Failed to reap expired VM {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_expired() {
+        let dir = TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.vm_root = dir.path().to_path_buf();
+        for name in ["old", "new", "forever", ".template"] {
+            std::fs::create_dir_all(dir.path().join(name)).unwrap();
+        }
+        set(&dir.path().join("old"), 60).unwrap();
+        let expires_at = set(&dir.path().join("new"), 3600).unwrap();
+        set(&dir.path().join(".template"), 1).unwrap();
+
+        let now = Utc::now();
+        assert!(expired(&config, now).is_empty());
+        let later = now + chrono::Duration::seconds(120);
+        let names: Vec<String> = expired(&config, later)
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, vec!["old"]);
+        let found = expired(&config, expires_at);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[1].name, "new");
+
+        assert_eq!(parse_ttl("90m"), Ok(5400));
+        assert!(parse_ttl("0s").is_err());
+        assert!(parse_ttl("soon").is_err());
+    }
+}
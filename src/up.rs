@@ -0,0 +1,482 @@
+//! `meda up` — create a group of VMs from one file, in parallel.
+//!
+//! The file is JSON:
+//!
+//! ```json
+//! {
+//!   "group": "ci",
+//!   "vms": [
+//!     { "name": "db", "memory": "2G", "cpus": 2 },
+//!     { "name": "web", "image": "ubuntu:latest", "disk": "20G" }
+//!   ]
+//! }
+//! ```
+//!
+//! Units without `image` go through `vm::create` + `vm::start`; units
+//! with one go through the cold `image::run_from_image` path (the
+//! template fast path shares a per-image template VM, which doesn't
+//! mix with per-unit rollback). Each VM created here gets a `group`
+//! file so it can be traced back to the file that produced it; `meda
+//! apply` (see `apply`) uses it to find the VMs a manifest owns.
+//!
+//! With `--rollback-on-failure` the operation is all-or-nothing: the
+//! first failure stops units that haven't started yet, and every VM
+//! this run created — including half-built ones — is deleted again.
+//! VMs that already existed before the run are never touched.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::util::write_string_to_file;
+use crate::{image, vm};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::Instrument;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpFile {
+    /// Group name recorded on every VM. Defaults to the file stem.
+    pub group: Option<String>,
+    pub vms: Vec<UnitSpec>,
+}
+
+/// File in a VM dir naming the group that created it.
+pub const GROUP_FILE: &str = "group";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UnitSpec {
+    pub name: String,
+    /// Image reference; omit to create from the base cloud image.
+    pub image: Option<String>,
+    pub registry: Option<String>,
+    pub org: Option<String>,
+    pub user_data: Option<String>,
+    /// Extra authorized keys (paths or key strings) for `cirun`.
+    #[serde(default)]
+    pub ssh_keys: Vec<String>,
+    /// Labels recorded on the VM (see `meda list --filter`).
+    #[serde(default)]
+    pub labels: crate::labels::Labels,
+    /// Storage pool for the VM dir (see `MEDA_STORAGE_POOLS`).
+    pub storage: Option<String>,
+    pub memory: Option<String>,
+    pub cpus: Option<u8>,
+    pub disk: Option<String>,
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// Block traffic to and from other VMs (see `network::ForwardPolicy`).
+    #[serde(default)]
+    pub isolate: bool,
+    /// Egress policy name or inline rules (see `egress`).
+    pub egress: Option<String>,
+    /// `nat` (default), `bridged` or `user`; only for units without `image`.
+    pub network: Option<String>,
+    /// Host bridge for `network: bridged`.
+    pub bridge: Option<String>,
+    /// Start the VM after creating it (default: true).
+    #[serde(default = "default_start")]
+    pub start: bool,
+}
+
+fn default_start() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize)]
+pub struct FailedUnit {
+    pub name: String,
+    pub error: String,
+}
+
+/// What happened to each unit. A unit appears in exactly one of
+/// `created`, `failed` or `skipped`; `rolled_back` lists the VMs
+/// (created or partially created) that were deleted afterwards.
+#[derive(Debug, Default, Serialize)]
+pub struct UpSummary {
+    pub group: String,
+    pub created: Vec<String>,
+    pub failed: Vec<FailedUnit>,
+    pub skipped: Vec<String>,
+    pub rolled_back: Vec<String>,
+}
+
+impl UpFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let body = fs::read_to_string(path)?;
+        let file: Self = serde_json::from_str(&body)?;
+        file.validate()?;
+        Ok(file)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.vms.is_empty() {
+            return Err(Error::Other("up file lists no VMs".to_string()));
+        }
+        validate_units(&self.vms)
+    }
+}
+
+/// Names must be usable as VM dirs and unique; networking must be
+/// something the unit's create path can set up.
+pub fn validate_units(units: &[UnitSpec]) -> Result<()> {
+    let mut seen = HashSet::new();
+    for unit in units {
+        if unit.name.is_empty() || unit.name.contains('/') {
+            return Err(Error::Other(format!("invalid VM name '{}'", unit.name)));
+        }
+        if !seen.insert(unit.name.as_str()) {
+            return Err(Error::Other(format!(
+                "VM '{}' is listed more than once",
+                unit.name
+            )));
+        }
+        let network =
+            crate::bridge::NetworkMode::new(unit.network.as_deref(), unit.bridge.as_deref())
+                .map_err(|e| Error::Other(format!("VM '{}': {}", unit.name, e)))?;
+        if unit.image.is_some() && network != crate::bridge::NetworkMode::Nat {
+            return Err(Error::Other(format!(
+                "VM '{}': only units without an image can use network '{}'",
+                unit.name,
+                unit.network.as_deref().unwrap_or_default()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// `group`, or the stem of the file that names none.
+pub fn group_name(group: Option<&str>, file: &Path) -> String {
+    group.map(str::to_string).unwrap_or_else(|| {
+        file.file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("default")
+            .to_string()
+    })
+}
+
+/// The group the VM in `vm_dir` was created by, if any.
+pub fn read_group(vm_dir: &Path) -> Option<String> {
+    fs::read_to_string(vm_dir.join(GROUP_FILE))
+        .ok()
+        .map(|group| group.trim().to_string())
+        .filter(|group| !group.is_empty())
+}
+
+enum UnitOutcome {
+    Created,
+    /// Failed; `partial` is true when this run left a VM dir behind.
+    Failed {
+        error: String,
+        partial: bool,
+    },
+    Skipped,
+}
+
+/// Build one unit. Never deletes anything — cleanup is the caller's
+/// decision so that rollback can cover successful siblings too.
+pub async fn create_unit(config: &Config, group: &str, unit: &UnitSpec) -> Result<()> {
+    let resources = vm::VmResources::from_config_with_overrides(
+        config,
+        unit.memory.as_deref(),
+        unit.cpus,
+        unit.disk.as_deref(),
+        unit.devices.clone(),
+    )
+    .with_storage(unit.storage.clone())
+    .with_isolation(unit.isolate)
+    .with_egress(
+        unit.egress
+            .as_deref()
+            .map(|e| crate::egress::EgressPolicy::resolve(config, e))
+            .transpose()?,
+    )
+    .with_network(crate::bridge::NetworkMode::new(
+        unit.network.as_deref(),
+        unit.bridge.as_deref(),
+    )?);
+    match &unit.image {
+        Some(image_name) => {
+            let options = image::RunOptions {
+                vm_name: Some(&unit.name),
+                registry: unit.registry.as_deref(),
+                org: unit.org.as_deref(),
+                user_data_path: unit.user_data.as_deref(),
+                ssh_keys: &unit.ssh_keys,
+                labels: &unit.labels,
+                no_start: !unit.start,
+                resources,
+                ttl: None,
+            };
+            image::run_from_image(config, image_name, options, false).await?;
+        }
+        None => {
+            vm::create(
+                config,
+                &unit.name,
+                unit.user_data.as_deref(),
+                &unit.ssh_keys,
+                &unit.labels,
+                &resources,
+                false,
+            )
+            .await?;
+            if unit.start {
+                vm::start(config, &unit.name, false).await?;
+            }
+        }
+    }
+    write_string_to_file(&config.vm_dir(&unit.name).join(GROUP_FILE), group)?;
+    Ok(())
+}
+
+/// Delete a VM this run created. Falls back to removing the dir when
+/// `vm::delete` trips over a half-built VM (e.g. no network files yet).
+pub async fn rollback_unit(config: &Config, name: &str) -> bool {
+    let vm_dir = config.vm_dir(name);
+    if !vm_dir.exists() {
+        return false;
+    }
+    if let Err(e) = vm::delete(config, name, false).await {
+        warn!("rollback: delete {} failed ({}), removing dir", name, e);
+        if let Err(e) = crate::storage::remove_vm_dir(&vm_dir) {
+            warn!("rollback: could not remove {}: {}", vm_dir.display(), e);
+            return false;
+        }
+    }
+    true
+}
+
+/// Pull every referenced image and fetch the base image once, up front,
+/// so parallel units don't race each other downloading the same files.
+pub async fn prepare(config: &Config, units: &[UnitSpec]) -> Result<()> {
+    if units.iter().any(|u| u.image.is_none()) {
+        vm::bootstrap(config).await?;
+    }
+    let mut pulled = HashSet::new();
+    for unit in units {
+        let Some(image_name) = &unit.image else {
+            continue;
+        };
+        let registry = unit.registry.as_deref().unwrap_or(&config.default_registry);
+        let org = unit.org.as_deref().unwrap_or(&config.default_org);
+        let image_ref = image::ImageRef::parse(image_name, registry, org)?;
+        if image_ref.local_dir(config).exists() || !pulled.insert(image_ref.url()) {
+            continue;
+        }
+        image::pull(
+            config,
+            image_name,
+            unit.registry.as_deref(),
+            unit.org.as_deref(),
+            false,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+pub async fn up(
+    config: &Config,
+    file: &Path,
+    parallel: usize,
+    rollback_on_failure: bool,
+    json: bool,
+) -> Result<()> {
+    let up_file = UpFile::load(file)?;
+    let group = group_name(up_file.group.as_deref(), file);
+
+    if !json {
+        info!(
+            "Bringing up {} VMs in group '{}' ({} at a time)",
+            up_file.vms.len(),
+            group,
+            parallel
+        );
+    }
+
+    prepare(config, &up_file.vms).await?;
+
+    let semaphore = Arc::new(Semaphore::new(parallel.max(1)));
+    let abort = Arc::new(AtomicBool::new(false));
+    let mut handles = Vec::new();
+    for unit in up_file.vms.clone() {
+        let config = config.clone();
+        let group = group.clone();
+        let semaphore = semaphore.clone();
+        let abort = abort.clone();
+        handles.push(tokio::spawn(
+            async move {
+                let _permit = semaphore.acquire_owned().await.ok();
+                if abort.load(Ordering::SeqCst) {
+                    return (unit.name, UnitOutcome::Skipped);
+                }
+                // Never claim a VM we didn't create — rollback would
+                // otherwise delete someone else's machine.
+                if config.vm_dir(&unit.name).exists() {
+                    if rollback_on_failure {
+                        abort.store(true, Ordering::SeqCst);
+                    }
+                    let error = Error::VmAlreadyExists(unit.name.clone()).to_string();
+                    return (
+                        unit.name,
+                        UnitOutcome::Failed {
+                            error,
+                            partial: false,
+                        },
+                    );
+                }
+                match create_unit(&config, &group, &unit).await {
+                    Ok(()) => (unit.name, UnitOutcome::Created),
+                    Err(e) => {
+                        if rollback_on_failure {
+                            abort.store(true, Ordering::SeqCst);
+                        }
+                        let partial = config.vm_dir(&unit.name).exists();
+                        (
+                            unit.name,
+                            UnitOutcome::Failed {
+                                error: e.to_string(),
+                                partial,
+                            },
+                        )
+                    }
+                }
+            }
+            .in_current_span(),
+        ));
+    }
+
+    let mut summary = UpSummary {
+        group,
+        ..Default::default()
+    };
+    let mut partial = Vec::new();
+    for handle in handles {
+        let (name, outcome) = handle
+            .await
+            .map_err(|e| Error::Other(format!("up task panicked: {}", e)))?;
+        match outcome {
+            UnitOutcome::Created => summary.created.push(name),
+            UnitOutcome::Failed {
+                error,
+                partial: is_partial,
+            } => {
+                if is_partial {
+                    partial.push(name.clone());
+                }
+                summary.failed.push(FailedUnit { name, error });
+            }
+            UnitOutcome::Skipped => summary.skipped.push(name),
+        }
+    }
+
+    if rollback_on_failure && !summary.failed.is_empty() {
+        for name in summary.created.iter().chain(partial.iter()) {
+            if !json {
+                info!("Rolling back {}", name);
+            }
+            if rollback_unit(config, name).await {
+                summary.rolled_back.push(name.clone());
+            }
+        }
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    } else {
+        print_summary(&summary);
+    }
+
+    if summary.failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Other(format!(
+            "{} of {} VMs in group '{}' failed",
+            summary.failed.len(),
+            up_file.vms.len(),
+            summary.group
+        )))
+    }
+}
+
+fn print_summary(summary: &UpSummary) {
+    println!("Group: {}", summary.group);
+    let list = |v: &[String]| {
+        if v.is_empty() {
+            "-".to_string()
+        } else {
+            v.join(", ")
+        }
+    };
+    println!("  Created:     {}", list(&summary.created));
+    if !summary.failed.is_empty() {
+        println!("  Failed:");
+        for f in &summary.failed {
+            println!("    {}: {}", f.name, f.error);
+        }
+    }
+    if !summary.skipped.is_empty() {
+        println!("  Skipped:     {}", list(&summary.skipped));
+    }
+    if !summary.rolled_back.is_empty() {
+        println!("  Rolled back: {}", list(&summary.rolled_back));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write(dir: &TempDir, body: &str) -> std::path::PathBuf {
+        let path = dir.path().join("group.json");
+        fs::write(&path, body).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_up_file_load_defaults() {
+        let dir = TempDir::new().unwrap();
+        let path = write(
+            &dir,
+            r#"{"vms":[{"name":"a"},{"name":"b","image":"ubuntu:latest","start":false}]}"#,
+        );
+        let file = UpFile::load(&path).unwrap();
+        assert!(file.group.is_none());
+        assert_eq!(file.vms.len(), 2);
+        assert!(file.vms[0].start);
+        assert!(file.vms[0].image.is_none());
+        assert!(!file.vms[1].start);
+        assert_eq!(file.vms[1].image.as_deref(), Some("ubuntu:latest"));
+    }
+
+    #[test]
+    fn test_up_file_rejects_duplicates_and_empty() {
+        let dir = TempDir::new().unwrap();
+        let dup = write(&dir, r#"{"vms":[{"name":"a"},{"name":"a"}]}"#);
+        assert!(UpFile::load(&dup)
+            .unwrap_err()
+            .to_string()
+            .contains("more than once"));
+
+        let empty = write(&dir, r#"{"vms":[]}"#);
+        assert!(UpFile::load(&empty).is_err());
+
+        let bad_name = write(&dir, r#"{"vms":[{"name":"../x"}]}"#);
+        assert!(UpFile::load(&bad_name).is_err());
+    }
+
+    #[test]
+    fn test_up_file_rejects_unknown_fields() {
+        let dir = TempDir::new().unwrap();
+        let path = write(&dir, r#"{"vms":[{"name":"a","memroy":"2G"}]}"#);
+        assert!(UpFile::load(&path).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! Pin a VM's outbound NAT to one host interface.
+//!
+//! By default a VM's traffic leaves through whatever the host's default
+//! route points at. On hosts with several uplinks, `--egress-interface
+//! eth1` sends the VM's internet-bound traffic out of `eth1` instead,
+//! masqueraded to `eth1`'s address:
+//!
+//! - a routing table of its own, holding a default route through the
+//!   interface (the gateway is taken from the interface's existing
+//!   default route, in any table) and an `unreachable` fallback so
+//!   nothing leaks out of the main uplink if that route goes away;
+//! - two `ip rule`s for the VM's source address: look up `main` for
+//!   anything but the default route (so the host and LAN stay
+//!   reachable), then the VM's table;
+//! - a MASQUERADE rule for the source address leaving that interface,
+//!   ahead of the shared `10.99.0.0/16` one.
+//!
+//! The source address is the netns veth IP (`10.99.N.2/32`), or the
+//! guest subnet for host-tap VMs, and the table number is that address
+//! as a `u32`, so every VM has its own table without bookkeeping.
+
+use crate::error::{Error, Result};
+use crate::util::run_command_with_output;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+/// Host interface a VM's NAT is pinned to, if any.
+pub const UPLINK_FILE: &str = "egress-interface";
+
+/// `ip rule` priorities; below the kernel's `main` rule at 32766.
+const RULE_PREF_MAIN: u32 = 1000;
+const RULE_PREF_TABLE: u32 = 1001;
+
+/// Interface names go into shell scripts and iptables rules.
+pub fn parse_interface(s: &str) -> std::result::Result<String, String> {
+    let valid = !s.is_empty()
+        && s.len() <= 15
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!("invalid interface name '{}'", s))
+    }
+}
+
+/// Fail unless `iface` exists on the host and has a default route to
+/// pin traffic to.
+pub fn check_interface(iface: &str) -> Result<()> {
+    if !Path::new("/sys/class/net").join(iface).exists() {
+        return Err(Error::Other(format!(
+            "interface {} does not exist on this host (see `ip -br link`)",
+            iface
+        )));
+    }
+    if ["vmh-", "tap-"].iter().any(|p| iface.starts_with(p)) {
+        return Err(Error::Other(format!(
+            "{} belongs to a meda VM; pick a host uplink",
+            iface
+        )));
+    }
+    let routes = run_command_with_output(
+        "ip",
+        &[
+            "-4", "route", "show", "table", "all", "default", "dev", iface,
+        ],
+    )
+    .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+    .unwrap_or_default();
+    if routes.is_empty() {
+        return Err(Error::Other(format!(
+            "{} has no IPv4 default route to send VM traffic through; add one, e.g. `ip route add default via <gateway> dev {} table 100`",
+            iface, iface
+        )));
+    }
+    Ok(())
+}
+
+pub fn load(vm_dir: &Path) -> Option<String> {
+    fs::read_to_string(vm_dir.join(UPLINK_FILE))
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+pub fn save(vm_dir: &Path, iface: Option<&str>) -> Result<()> {
+    let path = vm_dir.join(UPLINK_FILE);
+    match iface {
+        Some(iface) => fs::write(path, iface)?,
+        None => {
+            let _ = fs::remove_file(path);
+        }
+    }
+    Ok(())
+}
+
+/// Routing table for traffic from `source` (`a.b.c.d/len`).
+fn table(source: &str) -> u32 {
+    source
+        .split('/')
+        .next()
+        .and_then(|ip| ip.parse::<Ipv4Addr>().ok())
+        .map(u32::from)
+        .unwrap_or(0)
+}
+
+/// MASQUERADE rule (as `iptables -S` prints it, minus `-A`) for
+/// `source` leaving through `iface`.
+pub fn masquerade_rule(source: &str, iface: &str) -> String {
+    format!("POSTROUTING -s {source} -o {iface} -j MASQUERADE")
+}
+
+/// Shell lines routing and masquerading traffic from `source` out of
+/// `iface`. Fails the script if `iface` has lost its default route.
+pub fn script(source: &str, iface: &str) -> String {
+    let table = table(source);
+    let rule = masquerade_rule(source, iface);
+    format!(
+        r#"UPLINK_ROUTE=$(ip -4 route show table all default dev {iface} | head -n1)
+if [ -z "$UPLINK_ROUTE" ]; then
+  echo "egress interface {iface} has no IPv4 default route" >&2
+  exit 1
+fi
+UPLINK_GW=$(echo "$UPLINK_ROUTE" | awk '{{for (i = 1; i < NF; i++) if ($i == "via") {{ print $(i + 1); exit }}}}')
+if [ -n "$UPLINK_GW" ]; then
+  ip route replace default via "$UPLINK_GW" dev {iface} table {table}
+else
+  ip route replace default dev {iface} table {table}
+fi
+ip route replace unreachable default metric 4294967295 table {table}
+while ip rule del from {source} 2>/dev/null; do :; done
+ip rule add pref {pref_main} from {source} lookup main suppress_prefixlength 0
+ip rule add pref {pref_table} from {source} lookup {table}
+iptables -w -t nat -C {rule} 2>/dev/null || iptables -w -t nat -I {rule_at_top}
+"#,
+        pref_main = RULE_PREF_MAIN,
+        pref_table = RULE_PREF_TABLE,
+        rule_at_top = rule.replacen("POSTROUTING", "POSTROUTING 1", 1),
+    )
+}
+
+/// Shell lines removing whatever `script` installed for `source`,
+/// whichever interface it was pinned to.
+pub fn removal_script(source: &str) -> String {
+    format!(
+        r#"while ip rule del from {source} 2>/dev/null; do :; done
+ip route flush table {table} 2>/dev/null || true
+iptables -w -t nat -S POSTROUTING | grep -F -- "-s {source} -o " | grep -F -- "-j MASQUERADE" \
+  | sed 's/^-A /-D /' | while read -r rule; do iptables -w -t nat $rule; done
+"#,
+        table = table(source),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_interface() {
+        assert_eq!(parse_interface("eth1").unwrap(), "eth1");
+        assert_eq!(parse_interface("bond0.100").unwrap(), "bond0.100");
+        assert!(parse_interface("").is_err());
+        assert!(parse_interface("eth1; reboot").is_err());
+        assert!(parse_interface("averyveryverylongname").is_err());
+    }
+
+    #[test]
+    fn test_scripts() {
+        assert_eq!(table("10.99.1.2/32"), 0x0a63_0102);
+        let script = script("10.99.1.2/32", "eth1");
+        assert!(script.contains("table 174260482"));
+        assert!(script.contains("ip rule add pref 1001 from 10.99.1.2/32 lookup 174260482"));
+        assert!(script
+            .contains("iptables -w -t nat -I POSTROUTING 1 -s 10.99.1.2/32 -o eth1 -j MASQUERADE"));
+        assert!(!script.contains("{{"));
+
+        let removal = removal_script("192.168.7.0/24");
+        assert!(removal.contains("ip route flush table 3232237312"));
+        assert!(removal.contains("\"-s 192.168.7.0/24 -o \""));
+    }
+}
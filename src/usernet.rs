@@ -0,0 +1,248 @@
+//! User-mode networking (`meda create --network user`), for hosts where
+//! meda can't use sudo, such as locked-down CI runners.
+//!
+//! A default VM needs root for its netns, tap and iptables rules, and
+//! cloud-hypervisor runs as root to enter the netns. A user-mode VM
+//! instead gets its NIC from passt, run as the invoking user: CH, also
+//! run as the user, connects to it over a vhost-user socket in the VM
+//! dir, and passt carries the guest's traffic over ordinary host sockets.
+//! There is no tap, netns, subnet or iptables rule, and the guest takes
+//! its address from passt's DHCP.
+//!
+//! The host can't route to the guest. It reaches it through TCP ports
+//! passt forwards instead of DNAT: one on 127.0.0.1 picked when the VM
+//! is created, for SSH, and those added with `meda port-forward`. passt
+//! reads its forwards when it starts, which is with the VM, so a forward
+//! added while the VM runs applies from its next start.
+
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// File in a VM dir with the forwards of a user-mode VM.
+pub const USERNET_FILE: &str = "usernet.json";
+
+const SOCKET: &str = "passt.sock";
+const PID_FILE: &str = "passt.pid";
+const LOG_FILE: &str = "passt.log";
+
+/// How long passt gets to create its socket.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Forward {
+    pub host: u16,
+    pub guest: u16,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserNet {
+    /// Port on 127.0.0.1 forwarded to the guest's SSH; 0 until the VM
+    /// has an identity.
+    pub ssh_port: u16,
+    /// Forwards from `meda port-forward`, on all host addresses.
+    #[serde(default)]
+    pub forwards: Vec<Forward>,
+}
+
+impl UserNet {
+    pub fn load(vm_dir: &Path) -> Option<Self> {
+        let body = fs::read_to_string(vm_dir.join(USERNET_FILE)).ok()?;
+        serde_json::from_str(&body).ok()
+    }
+
+    pub fn save(&self, vm_dir: &Path) -> Result<()> {
+        fs::write(
+            vm_dir.join(USERNET_FILE),
+            serde_json::to_string_pretty(self)?,
+        )?;
+        Ok(())
+    }
+
+    /// Forward `host` to `guest`, replacing any forward of `host`.
+    pub fn add_forward(&mut self, host: u16, guest: u16) -> Result<()> {
+        if host == self.ssh_port {
+            return Err(Error::Other(format!(
+                "host port {} already forwards to the VM's SSH",
+                host
+            )));
+        }
+        self.forwards.retain(|f| f.host != host);
+        self.forwards.push(Forward { host, guest });
+        Ok(())
+    }
+
+    /// passt's command line for the VM in `vm_dir`.
+    fn command(&self, vm_dir: &Path) -> Vec<String> {
+        let mut argv: Vec<String> = vec![
+            "passt".to_string(),
+            "--vhost-user".to_string(),
+            "--socket".to_string(),
+            socket(vm_dir).display().to_string(),
+            "--pid".to_string(),
+            vm_dir.join(PID_FILE).display().to_string(),
+            "--log-file".to_string(),
+            vm_dir.join(LOG_FILE).display().to_string(),
+            "-t".to_string(),
+            format!("127.0.0.1/{}:22", self.ssh_port),
+        ];
+        for forward in &self.forwards {
+            argv.push("-t".to_string());
+            argv.push(format!("{}:{}", forward.host, forward.guest));
+        }
+        argv
+    }
+}
+
+pub fn is_enabled(vm_dir: &Path) -> bool {
+    vm_dir.join(USERNET_FILE).exists()
+}
+
+/// Mark the VM in `vm_dir` as user-mode; [`assign`] gives it its port.
+pub fn enable(vm_dir: &Path) -> Result<()> {
+    UserNet::default().save(vm_dir)
+}
+
+/// A port on 127.0.0.1 nothing listens on now.
+fn free_port() -> Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+/// Give a user-mode VM its own SSH port and drop forwards copied from
+/// another VM, whose host ports that VM still owns.
+pub fn assign(vm_dir: &Path) -> Result<()> {
+    UserNet {
+        ssh_port: free_port()?,
+        forwards: Vec::new(),
+    }
+    .save(vm_dir)
+}
+
+/// Give a user-mode VM moved in from another host a free SSH port here,
+/// keeping its forwards.
+pub fn rehome(vm_dir: &Path) -> Result<()> {
+    let mut net = UserNet::load(vm_dir).unwrap_or_default();
+    net.ssh_port = free_port()?;
+    net.save(vm_dir)
+}
+
+fn socket(vm_dir: &Path) -> PathBuf {
+    vm_dir.join(SOCKET)
+}
+
+/// CH's `--net` value for a user-mode VM with `mac`.
+pub fn net_arg(vm_dir: &Path, mac: &str) -> String {
+    format!(
+        "vhost_user=true,socket={},mac={}",
+        socket(vm_dir).display(),
+        mac
+    )
+}
+
+/// Host port to SSH to a user-mode VM on 127.0.0.1.
+pub fn ssh_port(vm_dir: &Path) -> Option<u16> {
+    UserNet::load(vm_dir)
+        .map(|net| net.ssh_port)
+        .filter(|port| *port != 0)
+}
+
+/// (Re)start the VM's passt and wait for its socket. passt puts itself
+/// in the background.
+pub async fn start(vm_dir: &Path) -> Result<()> {
+    let net = UserNet::load(vm_dir).ok_or_else(|| {
+        Error::Other(format!(
+            "{} is missing from {}",
+            USERNET_FILE,
+            vm_dir.display()
+        ))
+    })?;
+    stop(vm_dir);
+    let command = net.command(vm_dir);
+    log::debug!("Launching: {}", command.join(" "));
+    // Output goes where passt logs: the background passt keeps the
+    // descriptors, so a pipe would never be closed.
+    let log = fs::File::create(vm_dir.join(LOG_FILE))?;
+    let status = tokio::process::Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(log.try_clone()?))
+        .stderr(Stdio::from(log))
+        .status()
+        .await
+        .map_err(|e| Error::CommandFailed(format!("spawn passt: {}", e)))?;
+    if !status.success() {
+        return Err(Error::CommandFailed(format!(
+            "passt failed ({}); check {}",
+            status,
+            vm_dir.join(LOG_FILE).display()
+        )));
+    }
+
+    let deadline = Instant::now() + SOCKET_TIMEOUT;
+    while !socket(vm_dir).exists() {
+        if Instant::now() > deadline {
+            return Err(Error::Other(format!(
+                "passt did not create its socket; check {}",
+                vm_dir.join(LOG_FILE).display()
+            )));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
+
+/// Stop the VM's passt, if it is running.
+pub fn stop(vm_dir: &Path) {
+    let pid_file = vm_dir.join(PID_FILE);
+    if let Some(pid) = fs::read_to_string(&pid_file)
+        .ok()
+        .and_then(|p| p.trim().parse::<u32>().ok())
+    {
+        let _ = Command::new("kill").arg(pid.to_string()).output();
+    }
+    fs::remove_file(pid_file).ok();
+    fs::remove_file(socket(vm_dir)).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_forwards_and_command() {
+        let dir = TempDir::new().unwrap();
+        assert!(!is_enabled(dir.path()));
+        enable(dir.path()).unwrap();
+        assert!(is_enabled(dir.path()));
+        assert_eq!(ssh_port(dir.path()), None);
+
+        assign(dir.path()).unwrap();
+        let mut net = UserNet::load(dir.path()).unwrap();
+        assert_ne!(net.ssh_port, 0);
+        assert!(net.add_forward(net.ssh_port, 22).is_err());
+        net.add_forward(8080, 80).unwrap();
+        net.add_forward(8080, 8000).unwrap();
+        assert_eq!(
+            net.forwards,
+            vec![Forward {
+                host: 8080,
+                guest: 8000
+            }]
+        );
+
+        let command = net.command(dir.path()).join(" ");
+        assert!(command.starts_with("passt --vhost-user --socket "));
+        assert!(command.contains(&format!("-t 127.0.0.1/{}:22", net.ssh_port)));
+        assert!(command.ends_with("-t 8080:8000"));
+
+        // A clone gets its own port and none of the forwards.
+        net.save(dir.path()).unwrap();
+        assign(dir.path()).unwrap();
+        assert!(UserNet::load(dir.path()).unwrap().forwards.is_empty());
+    }
+}
@@ -1,32 +1,13 @@
+use crate::config::DiskFormat;
 use crate::error::{Error, Result};
 use indicatif::{ProgressBar, ProgressStyle};
-use log::debug;
+use log::{debug, warn};
 use std::fs;
 use std::io::Write;
-use std::path::Path;
-use std::process::{Command, Output};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub fn run_command(program: &str, args: &[&str]) -> Result<()> {
-    debug!("Running command: {} {}", program, args.join(" "));
-
-    let status = Command::new(program)
-        .args(args)
-        .status()
-        .map_err(|e| Error::CommandFailed(format!("{} {}: {}", program, args.join(" "), e)))?;
-
-    if !status.success() {
-        return Err(Error::CommandFailed(format!(
-            "{} {} failed with exit code: {:?}",
-            program,
-            args.join(" "),
-            status.code()
-        )));
-    }
-
-    Ok(())
-}
-
 pub fn run_command_with_output(program: &str, args: &[&str]) -> Result<Output> {
     debug!(
         "Running command with output: {} {}",
@@ -40,43 +21,243 @@ pub fn run_command_with_output(program: &str, args: &[&str]) -> Result<Output> {
         .map_err(|e| Error::CommandFailed(format!("{} {}: {}", program, args.join(" "), e)))
 }
 
-pub fn run_command_quietly(program: &str, args: &[&str]) -> Result<()> {
-    debug!("Running command quietly: {} {}", program, args.join(" "));
+/// What a child run with [`run_streamed`] printed, and how it exited.
+pub struct StreamedOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
 
-    let output = Command::new(program)
-        .args(args)
-        .output()
-        .map_err(|e| Error::CommandFailed(format!("{} {}: {}", program, args.join(" "), e)))?;
+/// Run `cmd` on the tokio runtime instead of blocking a worker thread on
+/// it, handing each line of its stdout and stderr to `on_line` as it
+/// arrives (so long ORAS and qemu-img runs show up in logs and progress
+/// while they run) and collecting both. A non-zero exit is not an error
+/// here; callers decide what it means.
+pub async fn run_streamed(
+    cmd: &mut tokio::process::Command,
+    mut on_line: impl FnMut(&str),
+) -> Result<StreamedOutput> {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    let program = cmd.as_std().get_program().to_string_lossy().to_string();
+    debug!(
+        "Running command: {}",
+        crate::credentials::redact_command(cmd.as_std())
+    );
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| Error::CommandFailed(format!("{}: {}", program, e)))?;
+
+    let mut out_lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut err_lines = BufReader::new(child.stderr.take().unwrap()).lines();
+    let (mut stdout, mut stderr) = (String::new(), String::new());
+    let (mut out_open, mut err_open) = (true, true);
+    while out_open || err_open {
+        let (line, buffer, open) = tokio::select! {
+            line = out_lines.next_line(), if out_open => (line, &mut stdout, &mut out_open),
+            line = err_lines.next_line(), if err_open => (line, &mut stderr, &mut err_open),
+        };
+        match line? {
+            Some(line) => {
+                on_line(&line);
+                buffer.push_str(&line);
+                buffer.push('\n');
+            }
+            None => *open = false,
+        }
+    }
+    let status = child.wait().await?;
+    Ok(StreamedOutput {
+        status,
+        stdout,
+        stderr,
+    })
+}
 
+/// Run `program` to completion on the tokio runtime. Its output goes to
+/// the debug log, and its stderr into the error if it fails.
+pub async fn run_command(program: &str, args: &[&str]) -> Result<()> {
+    let output = run_streamed(tokio::process::Command::new(program).args(args), |line| {
+        debug!("{}: {}", program, line)
+    })
+    .await?;
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
         return Err(Error::CommandFailed(format!(
             "{} {} failed with exit code: {:?}\nError output: {}",
             program,
             args.join(" "),
             output.status.code(),
-            stderr
+            output.stderr.trim()
         )));
     }
-
     Ok(())
 }
 
-pub async fn download_file(url: &str, dest: &Path) -> Result<()> {
-    debug!("Downloading {} to {}", url, dest.display());
+/// Attempts per URL before falling back to the next mirror. Each retry
+/// resumes from what the previous attempt left in the `.part` file.
+const DOWNLOAD_ATTEMPTS: u32 = 3;
 
-    let response = reqwest::get(url).await?;
+/// Where a download came from.
+pub struct Downloaded {
+    pub url: String,
+    /// ETag or Last-Modified the server sent with the file, to compare
+    /// with [`remote_validator`] later.
+    pub validator: Option<String>,
+}
 
+/// Download `dest` from the first of `urls` that serves it, returning
+/// that URL. The body is written to `<dest>.part` and renamed into place
+/// once complete, so an interrupted download, in this run or an earlier
+/// one, resumes with an HTTP range request instead of starting over.
+/// A partial file is only resumed from the URL it came from.
+pub async fn download_with_mirrors(urls: &[String], dest: &Path) -> Result<Downloaded> {
+    let part = suffixed(dest, ".part");
+    let source = suffixed(dest, ".part.source");
+    let mut last_error = None;
+    for url in urls {
+        if fs::read_to_string(&source).ok().as_deref() != Some(url.as_str()) {
+            fs::remove_file(&part).ok();
+            fs::write(&source, url)?;
+        }
+        for attempt in 1..=DOWNLOAD_ATTEMPTS {
+            match download_part(url, &part).await {
+                Ok(()) => {
+                    fs::rename(&part, dest)?;
+                    fs::remove_file(&source).ok();
+                    let validator_file = suffixed(dest, ".part.validator");
+                    let validator = fs::read_to_string(&validator_file).ok();
+                    fs::remove_file(validator_file).ok();
+                    return Ok(Downloaded {
+                        url: url.clone(),
+                        validator,
+                    });
+                }
+                Err(e) => {
+                    warn!(
+                        "Download from {} failed (attempt {}/{}): {}",
+                        url, attempt, DOWNLOAD_ATTEMPTS, e
+                    );
+                    // A 404 or 403 won't go away by asking again.
+                    let client_error = matches!(&e, Error::DownloadFailed(_, reason) if reason.starts_with("HTTP status: 4"));
+                    last_error = Some(e);
+                    if client_error {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| Error::Other(format!("no download URL for {}", dest.display()))))
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// The ETag, or else Last-Modified, of a response. Weak ETags can't be
+/// used in If-Range, so they are skipped.
+fn validator_of(headers: &reqwest::header::HeaderMap) -> Option<&str> {
+    use reqwest::header::{ETAG, LAST_MODIFIED};
+
+    headers
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.starts_with("W/"))
+        .or_else(|| headers.get(LAST_MODIFIED).and_then(|v| v.to_str().ok()))
+}
+
+/// What `url` would be downloaded as now, by the same validator
+/// [`Downloaded`] records, without downloading it. `None` when the server
+/// sends neither header.
+pub async fn remote_validator(url: &str) -> Result<Option<String>> {
+    let response = crate::proxy::client()?.head(url).send().await?;
     if !response.status().is_success() {
         return Err(Error::DownloadFailed(
             url.to_string(),
             format!("HTTP status: {}", response.status()),
         ));
     }
+    Ok(validator_of(response.headers()).map(String::from))
+}
 
-    let total_size = response.content_length();
+/// Fetch `url` into `part`, continuing after the bytes already there.
+/// Resuming sends the ETag or Last-Modified the server gave for the first
+/// byte as `If-Range`, so a file that changed upstream (e.g. a `latest`
+/// release) is sent whole again rather than spliced onto the old one.
+async fn download_part(url: &str, part: &Path) -> Result<()> {
+    use reqwest::header::{CONTENT_RANGE, IF_RANGE, RANGE};
+    use reqwest::StatusCode;
+
+    debug!("Downloading {} to {}", url, part.display());
+    let validator_file = suffixed(part, ".validator");
+    let offset = fs::metadata(part).map(|m| m.len()).unwrap_or(0);
+    let validator = fs::read_to_string(&validator_file).ok();
+
+    let mut request = crate::proxy::client()?.get(url);
+    let resuming = offset > 0 && validator.is_some();
+    if let (true, Some(validator)) = (resuming, &validator) {
+        debug!("Resuming {} at byte {}", url, offset);
+        request = request
+            .header(RANGE, format!("bytes={}-", offset))
+            .header(IF_RANGE, validator.as_str());
+    }
+    let response = request.send().await?;
+    let status = response.status();
+
+    if resuming && status == StatusCode::RANGE_NOT_SATISFIABLE {
+        // Most likely the part is already the whole file, but nothing
+        // proves it; fetch it again from the start.
+        fs::remove_file(part).ok();
+        fs::remove_file(&validator_file).ok();
+        return Err(Error::DownloadFailed(
+            url.to_string(),
+            "server rejected the resume range".to_string(),
+        ));
+    }
+    if !status.is_success() {
+        return Err(Error::DownloadFailed(
+            url.to_string(),
+            format!("HTTP status: {}", status),
+        ));
+    }
+
+    let appending = status == StatusCode::PARTIAL_CONTENT
+        && response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with(&format!("bytes {}-", offset)));
+    let start = if appending { offset } else { 0 };
+    let mut file = if appending {
+        fs::OpenOptions::new().append(true).open(part)?
+    } else {
+        match validator_of(response.headers()) {
+            Some(v) => fs::write(&validator_file, v)?,
+            None => {
+                fs::remove_file(&validator_file).ok();
+            }
+        }
+        fs::File::create(part)?
+    };
+
+    let total_size = response.content_length().map(|len| len + start);
+    let filename = part
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| n.strip_suffix(".part"))
+        .unwrap_or("file");
+    let mut events = crate::progress::Progress::bytes("download", filename, total_size);
     // Create progress bar if we know the content length and it's a substantial download
-    let pb = if let Some(size) = total_size {
+    let pb = if crate::progress::json_mode() {
+        None
+    } else if let Some(size) = total_size {
         if size > 1_000_000 {
             // Show progress for files > 1MB
             let progress_bar = ProgressBar::new(size);
@@ -87,15 +268,23 @@ pub async fn download_file(url: &str, dest: &Path) -> Result<()> {
                     .progress_chars("#>-")
             );
 
-            // Extract filename from path for display
-            let filename = dest.file_name().and_then(|n| n.to_str()).unwrap_or("file");
             progress_bar.set_message(format!("Downloading {}", filename));
-
-            println!(
-                "📥 Downloading {} ({:.1} MB)...",
-                filename,
-                size as f64 / 1_000_000.0
-            );
+            progress_bar.set_position(start);
+
+            if appending {
+                println!(
+                    "📥 Resuming {} at {:.1} of {:.1} MB...",
+                    filename,
+                    start as f64 / 1_000_000.0,
+                    size as f64 / 1_000_000.0
+                );
+            } else {
+                println!(
+                    "📥 Downloading {} ({:.1} MB)...",
+                    filename,
+                    size as f64 / 1_000_000.0
+                );
+            }
 
             Some(progress_bar)
         } else {
@@ -103,8 +292,6 @@ pub async fn download_file(url: &str, dest: &Path) -> Result<()> {
         }
     } else {
         // No content length available, create a spinner for unknown size downloads
-        let filename = dest.file_name().and_then(|n| n.to_str()).unwrap_or("file");
-
         println!("📥 Downloading {}...", filename);
 
         let progress_bar = ProgressBar::new_spinner();
@@ -119,8 +306,7 @@ pub async fn download_file(url: &str, dest: &Path) -> Result<()> {
     };
 
     // Stream the download
-    let mut file = fs::File::create(dest)?;
-    let mut downloaded = 0u64;
+    let mut downloaded = start;
     let mut stream = response.bytes_stream();
 
     use futures_util::StreamExt;
@@ -133,7 +319,25 @@ pub async fn download_file(url: &str, dest: &Path) -> Result<()> {
         if let Some(ref pb) = pb {
             pb.set_position(downloaded);
         }
+        events.update(downloaded);
+    }
+    file.flush()?;
+
+    if let Some(expected) = total_size {
+        if downloaded != expected {
+            if let Some(pb) = pb {
+                pb.abandon_with_message("Download interrupted");
+            }
+            return Err(Error::DownloadFailed(
+                url.to_string(),
+                format!(
+                    "connection closed after {} of {} bytes",
+                    downloaded, expected
+                ),
+            ));
+        }
     }
+    events.finish(Some(downloaded));
 
     if let Some(pb) = pb {
         pb.finish_with_message("Download complete");
@@ -180,7 +384,7 @@ pub fn check_process_running(pid: u32) -> bool {
 /// # Arguments
 /// * `disk_path` - Path to the raw disk image
 /// * `size` - Target size (e.g., "25G", "1024M")
-pub fn resize_raw_disk(disk_path: &Path, size: &str) -> Result<()> {
+pub async fn resize_raw_disk(disk_path: &Path, size: &str) -> Result<()> {
     run_command(
         "qemu-img",
         &[
@@ -191,7 +395,8 @@ pub fn resize_raw_disk(disk_path: &Path, size: &str) -> Result<()> {
             disk_path.to_str().unwrap(),
             size,
         ],
-    )?;
+    )
+    .await?;
 
     // After resizing the raw file, grow the GPT partition table so the
     // largest Linux partition fills the new disk size. Without this,
@@ -203,12 +408,12 @@ pub fn resize_raw_disk(disk_path: &Path, size: &str) -> Result<()> {
 /// Create a qcow2 overlay image with a raw backing file.
 /// This is instant (no data copy) - the overlay stores only written blocks.
 /// If size is None, the overlay inherits the backing file's virtual size.
-pub fn create_qcow2_overlay(
+pub async fn create_qcow2_overlay(
     backing_file: &Path,
     overlay_path: &Path,
     size: Option<&str>,
 ) -> Result<()> {
-    create_qcow2_overlay_with_fmt(backing_file, "raw", overlay_path, size)
+    create_qcow2_overlay_with_fmt(backing_file, "raw", overlay_path, size).await
 }
 
 /// Create a qcow2 overlay with an explicit backing format. Use `qcow2`
@@ -216,7 +421,7 @@ pub fn create_qcow2_overlay(
 /// `raw` for a qcow2 backing makes qemu-img mis-interpret the backing's
 /// on-disk size as its virtual size, which is how clones ended up with
 /// ~60MB virtual size instead of inheriting the template's 10G.
-pub fn create_qcow2_overlay_with_fmt(
+pub async fn create_qcow2_overlay_with_fmt(
     backing_file: &Path,
     backing_fmt: &str,
     overlay_path: &Path,
@@ -239,7 +444,149 @@ pub fn create_qcow2_overlay_with_fmt(
     // pollutes `meda run --json` and breaks jq. Capture it quietly —
     // we surface only a real error (stderr + non-zero exit) if the
     // create itself fails.
-    run_command_quietly("qemu-img", &args)
+    run_command("qemu-img", &args).await
+}
+
+/// Provision a VM's root disk from a raw base image in the requested
+/// format and return its path. `Qcow2` layers a thin overlay on the
+/// shared base; `Raw` makes a private sparse copy (reflinked where the
+/// filesystem supports it) and resizes it when `size` is given.
+pub async fn provision_rootfs(
+    base_raw: &Path,
+    vm_dir: &Path,
+    format: DiskFormat,
+    size: Option<&str>,
+) -> Result<PathBuf> {
+    let rootfs = vm_dir.join(format.rootfs_name());
+    match format {
+        DiskFormat::Qcow2 => create_qcow2_overlay(base_raw, &rootfs, size).await?,
+        DiskFormat::Raw => {
+            run_command(
+                "cp",
+                &[
+                    "--sparse=always",
+                    "--reflink=auto",
+                    base_raw.to_str().unwrap(),
+                    rootfs.to_str().unwrap(),
+                ],
+            )
+            .await?;
+            if let Some(s) = size {
+                resize_raw_disk(&rootfs, s).await?;
+            }
+        }
+    }
+    Ok(rootfs)
+}
+
+/// Parse a size like `512M`, `10G` or `1T` (qemu-img / Cloud Hypervisor
+/// style, binary units) into bytes. A bare number is taken as bytes.
+pub fn parse_size_bytes(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split_at = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let n: u64 = s[..split_at].trim().parse().ok()?;
+    let shift = match s[split_at..].to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return None,
+    };
+    n.checked_mul(1u64 << shift)
+}
+
+/// Parse an age like `7d`, `12h`, `30m`, `45s` or `2w` into seconds.
+pub fn parse_duration_secs(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split_at = s.find(|c: char| c.is_ascii_alphabetic())?;
+    let n: u64 = s[..split_at].trim().parse().ok()?;
+    let unit = match &s[split_at..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    n.checked_mul(unit)
+}
+
+/// Directories in the colon-separated list in env var `var`, the host
+/// paths API callers may reach. Empty when unset.
+pub fn allowed_roots(var: &str) -> Vec<PathBuf> {
+    std::env::var(var)
+        .unwrap_or_default()
+        .split(':')
+        .filter(|root| !root.trim().is_empty())
+        .filter_map(|root| fs::canonicalize(root.trim()).ok())
+        .collect()
+}
+
+/// `path` with symlinks resolved, if it is one of `roots` or lies
+/// below one.
+pub fn resolve_under(path: &Path, roots: &[PathBuf]) -> Option<PathBuf> {
+    let resolved = fs::canonicalize(path).ok()?;
+    roots
+        .iter()
+        .any(|root| resolved.starts_with(root))
+        .then_some(resolved)
+}
+
+/// Backing file named in a qcow2 header, resolved against the image's
+/// directory. `None` for raw images and qcow2s without a backing file.
+pub fn qcow2_backing_file(path: &Path) -> Option<PathBuf> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = fs::File::open(path).ok()?;
+    let mut header = [0u8; 20];
+    file.read_exact(&mut header).ok()?;
+    if &header[..4] != b"QFI\xfb" {
+        return None;
+    }
+    let offset = u64::from_be_bytes(header[8..16].try_into().ok()?);
+    let len = u32::from_be_bytes(header[16..20].try_into().ok()?);
+    if offset == 0 || len == 0 || len > 1023 {
+        return None;
+    }
+    let mut name = vec![0u8; len as usize];
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    file.read_exact(&mut name).ok()?;
+    let backing = PathBuf::from(String::from_utf8(name).ok()?);
+    Some(match path.parent() {
+        Some(dir) if backing.is_relative() => dir.join(backing),
+        _ => backing,
+    })
+}
+
+/// Every backing file below `path`, nearest first.
+pub fn backing_chain(path: &Path) -> Vec<PathBuf> {
+    let mut chain = Vec::new();
+    let mut current = path.to_path_buf();
+    // Bounded in case of a (corrupt) cycle.
+    while chain.len() < 16 {
+        match qcow2_backing_file(&current) {
+            Some(backing) => {
+                chain.push(backing.clone());
+                current = backing;
+            }
+            None => break,
+        }
+    }
+    chain
+}
+
+/// Virtual size of a disk image in bytes, via `qemu-img info`. `-U`
+/// lets this read images a running VM holds open.
+pub fn disk_virtual_size(path: &Path) -> Option<u64> {
+    let output =
+        run_command_with_output("qemu-img", &["info", "-U", "--output=json", path.to_str()?])
+            .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+    json.get("virtual-size")?.as_u64()
 }
 
 pub fn write_string_to_file(path: &Path, content: &str) -> Result<()> {
@@ -298,15 +645,122 @@ mod tests {
     use tempfile::NamedTempFile;
 
     #[test]
-    fn test_run_command_success() {
+    fn test_resolve_under() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path().join("shared");
+        fs::create_dir_all(root.join("keys")).unwrap();
+        fs::write(root.join("keys/a.pem"), "").unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("etc")).unwrap();
+        let roots = vec![fs::canonicalize(&root).unwrap()];
+
+        assert!(resolve_under(&root.join("keys/a.pem"), &roots).is_some());
+        assert!(resolve_under(&root, &roots).is_some());
+        assert!(resolve_under(&root.join("etc"), &roots).is_none());
+        assert!(resolve_under(&root.join("keys/../../"), &roots).is_none());
+        assert!(resolve_under(&root.join("missing"), &roots).is_none());
+        assert!(resolve_under(Path::new("/etc/passwd"), &[]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_command_success() {
         let result = run_command("echo", &["hello"]);
-        assert!(result.is_ok());
+        assert!(result.await.is_ok());
     }
 
-    #[test]
-    fn test_run_command_failure() {
+    #[tokio::test]
+    async fn test_run_command_failure() {
         let result = run_command("false", &[]);
-        assert!(result.is_err());
+        assert!(result.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_streamed() {
+        let mut lines = Vec::new();
+        let output = run_streamed(
+            tokio::process::Command::new("sh").args(["-c", "echo one; echo two >&2; echo three"]),
+            |line| lines.push(line.to_string()),
+        )
+        .await
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, "one\nthree\n");
+        assert_eq!(output.stderr, "two\n");
+        lines.sort();
+        assert_eq!(lines, ["one", "three", "two"]);
+
+        assert!(run_command("true", &[]).await.is_ok());
+        let err = run_command("sh", &["-c", "echo boom >&2; exit 3"])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("boom"));
+    }
+
+    /// Serves `body`, cutting the first full response off halfway and
+    /// answering range requests with the rest.
+    async fn flaky_server(body: &'static [u8]) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut cut = false;
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = vec![0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_ascii_lowercase();
+                let offset = request
+                    .lines()
+                    .find_map(|l| l.strip_prefix("range: bytes="))
+                    .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+                let response = match offset {
+                    Some(offset) => {
+                        let mut r = format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nETag: \"v1\"\r\n\r\n",
+                            body.len() - offset,
+                            offset,
+                            body.len() - 1,
+                            body.len()
+                        )
+                        .into_bytes();
+                        r.extend_from_slice(&body[offset..]);
+                        r
+                    }
+                    None => {
+                        let mut r = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"v1\"\r\n\r\n",
+                            body.len()
+                        )
+                        .into_bytes();
+                        let len = if cut { body.len() } else { body.len() / 2 };
+                        cut = true;
+                        r.extend_from_slice(&body[..len]);
+                        r
+                    }
+                };
+                socket.write_all(&response).await.unwrap();
+                socket.shutdown().await.ok();
+            }
+        });
+        format!("http://{}/asset", addr)
+    }
+
+    #[tokio::test]
+    async fn test_download_resumes_and_falls_back() {
+        let body: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+        let url = flaky_server(body).await;
+        let dir = tempfile::TempDir::new().unwrap();
+        let dest = dir.path().join("asset");
+
+        // Nothing listens on port 1, so the mirror serves it.
+        let served = download_with_mirrors(
+            &["http://127.0.0.1:1/asset".to_string(), url.clone()],
+            &dest,
+        )
+        .await
+        .unwrap();
+        assert_eq!(served.url, url);
+        assert_eq!(fs::read(&dest).unwrap(), body);
+        assert!(!suffixed(&dest, ".part").exists());
+        assert!(!suffixed(&dest, ".part.validator").exists());
     }
 
     #[test]
@@ -347,6 +801,18 @@ mod tests {
         assert!(!check_process_running(999999));
     }
 
+    #[test]
+    fn test_parse_size_bytes() {
+        assert_eq!(parse_size_bytes("512M"), Some(512 << 20));
+        assert_eq!(parse_size_bytes("10G"), Some(10 << 30));
+        assert_eq!(parse_size_bytes("2gib"), Some(2 << 30));
+        assert_eq!(parse_size_bytes("4096"), Some(4096));
+        assert_eq!(parse_size_bytes("1T"), Some(1 << 40));
+        assert_eq!(parse_size_bytes("10X"), None);
+        assert_eq!(parse_size_bytes("G"), None);
+        assert_eq!(parse_size_bytes(""), None);
+    }
+
     #[test]
     fn test_write_string_to_file() {
         let temp_file = NamedTempFile::new().unwrap();
@@ -359,4 +825,37 @@ mod tests {
         let read_content = fs::read_to_string(path).unwrap();
         assert_eq!(read_content, content);
     }
+
+    #[test]
+    fn test_parse_duration_secs() {
+        assert_eq!(parse_duration_secs("7d"), Some(7 * 86400));
+        assert_eq!(parse_duration_secs("12h"), Some(12 * 3600));
+        assert_eq!(parse_duration_secs("30m"), Some(1800));
+        assert_eq!(parse_duration_secs("2w"), Some(14 * 86400));
+        assert_eq!(parse_duration_secs("7"), None);
+        assert_eq!(parse_duration_secs("7y"), None);
+    }
+
+    #[test]
+    fn test_backing_chain() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let qcow2 = |path: &Path, backing: &str| {
+            let mut header = vec![0u8; 512];
+            header[..4].copy_from_slice(b"QFI\xfb");
+            header[8..16].copy_from_slice(&128u64.to_be_bytes());
+            header[16..20].copy_from_slice(&(backing.len() as u32).to_be_bytes());
+            header[128..128 + backing.len()].copy_from_slice(backing.as_bytes());
+            fs::write(path, header).unwrap();
+        };
+        let base = dir.path().join("base.raw");
+        fs::write(&base, b"raw").unwrap();
+        qcow2(&dir.path().join("tpl.qcow2"), base.to_str().unwrap());
+        qcow2(&dir.path().join("vm.qcow2"), "tpl.qcow2");
+
+        assert_eq!(qcow2_backing_file(&base), None);
+        assert_eq!(
+            backing_chain(&dir.path().join("vm.qcow2")),
+            vec![dir.path().join("tpl.qcow2"), base]
+        );
+    }
 }
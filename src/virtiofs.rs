@@ -0,0 +1,367 @@
+//! Host directories shared into guests over virtiofs
+//! (`meda create --mount /host/path:/guest/path[:ro]`).
+//!
+//! Every share gets its own virtiofsd, started as root before Cloud
+//! Hypervisor, which connects to it with `--fs`. The
+//! daemons are stopped with the VM, like its dnsmasq. vhost-user
+//! devices need guest memory CH can share with another process, so a
+//! VM with shares always has shared memory.
+//!
+//! Shares are tagged `share0`, `share1`, ... in `--mount` order and
+//! mounted by cloud-init from vendor-data, so they come up whatever the
+//! VM's user-data is; by hand it's `mount -t virtiofs share0 /mnt`.
+//! A `:ro` share is read-only in virtiofsd as well as in the guest.
+//!
+//! virtiofsd runs as root, so over the API callers may only share
+//! directories under those in `MEDA_MOUNT_ROOTS`; without it the API
+//! takes no mounts at all.
+
+use crate::error::{Error, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Shares of a VM, one `host:guest[:ro]` per line.
+pub const SHARES_FILE: &str = "shares";
+
+/// Colon-separated directories API callers may share.
+pub const ROOTS_ENV: &str = "MEDA_MOUNT_ROOTS";
+
+/// Where distributions install virtiofsd when it isn't on `PATH`.
+const VIRTIOFSD_PATHS: &[&str] = &["/usr/libexec/virtiofsd", "/usr/lib/qemu/virtiofsd"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    pub host: PathBuf,
+    pub guest: String,
+    pub readonly: bool,
+}
+
+/// Paths end up in a root shell script and in cloud-config, so only
+/// plain path characters are allowed.
+fn plain_path(path: &str) -> bool {
+    path.starts_with('/')
+        && path
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '-' | '_' | '.'))
+}
+
+impl Share {
+    /// `--mount` value: `/host/path:/guest/path[:ro|:rw]`.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let invalid = || {
+            format!(
+                "invalid mount '{}' (expected /host/path:/guest/path[:ro] with absolute paths)",
+                s
+            )
+        };
+        let mut parts = s.trim().split(':');
+        let (Some(host), Some(guest), mode, None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let readonly = match mode {
+            None | Some("rw") => false,
+            Some("ro") => true,
+            Some(_) => return Err(invalid()),
+        };
+        if !plain_path(host) || !plain_path(guest) || guest == "/" {
+            return Err(invalid());
+        }
+        Ok(Self {
+            host: PathBuf::from(host),
+            guest: guest.to_string(),
+            readonly,
+        })
+    }
+}
+
+impl std::fmt::Display for Share {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host.display(), self.guest)?;
+        if self.readonly {
+            write!(f, ":ro")?;
+        }
+        Ok(())
+    }
+}
+
+/// `shares` from an API request, with their host paths resolved and
+/// checked against `MEDA_MOUNT_ROOTS`.
+pub fn confine(shares: Vec<Share>) -> std::result::Result<Vec<Share>, String> {
+    if shares.is_empty() {
+        return Ok(shares);
+    }
+    let roots = crate::util::allowed_roots(ROOTS_ENV);
+    if roots.is_empty() {
+        return Err(format!(
+            "this server shares no directories; set {} to the ones it may share",
+            ROOTS_ENV
+        ));
+    }
+    shares
+        .into_iter()
+        .map(|share| {
+            match crate::util::resolve_under(&share.host, &roots)
+                .filter(|host| host.is_dir() && plain_path(&host.to_string_lossy()))
+            {
+                Some(host) => Ok(Share { host, ..share }),
+                None => Err(format!(
+                    "{} is not a directory under {}",
+                    share.host.display(),
+                    ROOTS_ENV
+                )),
+            }
+        })
+        .collect()
+}
+
+fn tag(index: usize) -> String {
+    format!("share{}", index)
+}
+
+pub fn load(vm_dir: &Path) -> Vec<Share> {
+    fs::read_to_string(vm_dir.join(SHARES_FILE))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| Share::parse(line).ok())
+        .collect()
+}
+
+fn save(vm_dir: &Path, shares: &[Share]) -> Result<()> {
+    let body: Vec<String> = shares.iter().map(Share::to_string).collect();
+    fs::write(vm_dir.join(SHARES_FILE), body.join("\n") + "\n")?;
+    Ok(())
+}
+
+/// Record a new VM's shares and add their mounts to its vendor-data.
+pub fn prepare(vm_dir: &Path, shares: &[Share]) -> Result<()> {
+    if shares.is_empty() {
+        return Ok(());
+    }
+    for share in shares {
+        if !share.host.is_dir() {
+            return Err(Error::Other(format!(
+                "Cannot share {}: not a directory",
+                share.host.display()
+            )));
+        }
+    }
+    save(vm_dir, shares)?;
+    let path = vm_dir.join(crate::immutable::VENDOR_DATA);
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    fs::write(&path, vendor_data(&existing, shares))?;
+    Ok(())
+}
+
+/// Append mounts for `shares` to `existing` vendor-data, whose `mounts`
+/// list (from `--data-disk`) is always its last key.
+fn vendor_data(existing: &str, shares: &[Share]) -> String {
+    let mut out = if existing.is_empty() {
+        "#cloud-config\n".to_string()
+    } else {
+        existing.to_string()
+    };
+    if !out.contains("\nmounts:\n") {
+        out.push_str("mounts:\n");
+    }
+    for (i, share) in shares.iter().enumerate() {
+        let options = if share.readonly {
+            "defaults,nofail,ro"
+        } else {
+            "defaults,nofail"
+        };
+        out.push_str(&format!(
+            "  - [{}, {}, virtiofs, \"{}\", \"0\", \"0\"]\n",
+            tag(i),
+            share.guest,
+            options
+        ));
+    }
+    out
+}
+
+pub fn virtiofsd_bin() -> Result<PathBuf> {
+    if let Ok(output) = Command::new("which").arg("virtiofsd").output() {
+        let found = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if output.status.success() && !found.is_empty() {
+            return Ok(PathBuf::from(found));
+        }
+    }
+    VIRTIOFSD_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .find(|p| p.exists())
+        .ok_or_else(|| {
+            Error::Other(
+                "virtiofsd not found; install it (e.g. `sudo apt install virtiofsd`) to use --mount"
+                    .to_string(),
+            )
+        })
+}
+
+fn socket(vm_dir: &Path, tag: &str) -> PathBuf {
+    vm_dir.join(format!("virtiofs-{}.sock", tag))
+}
+
+fn pid_file(vm_dir: &Path, tag: &str) -> PathBuf {
+    vm_dir.join(format!("virtiofsd-{}.pid", tag))
+}
+
+/// Shell lines for the launch spec's setup (run as root) that (re)start
+/// a virtiofsd per share and wait for its socket. Empty for VMs without
+/// shares.
+pub fn start_commands(vm_dir: &Path) -> Result<String> {
+    let shares = load(vm_dir);
+    if shares.is_empty() {
+        return Ok(String::new());
+    }
+    let bin = virtiofsd_bin()?;
+    let mut commands = String::new();
+    for (i, share) in shares.iter().enumerate() {
+        let tag = tag(i);
+        let pid = pid_file(vm_dir, &tag);
+        let sock = socket(vm_dir, &tag);
+        commands.push_str(&format!(
+            r#"if [ -f "{pid}" ]; then kill "$(cat "{pid}")" 2>/dev/null; rm -f "{pid}"; fi
+rm -f "{sock}"
+{bin} --socket-path="{sock}" --shared-dir="{host}" --cache=never --sandbox=chroot{readonly} > "{log}" 2>&1 &
+echo $! > "{pid}"
+chmod 0644 "{pid}"
+for i in $(seq 50); do [ -S "{sock}" ] && break; sleep 0.1; done
+"#,
+            pid = pid.display(),
+            sock = sock.display(),
+            bin = bin.display(),
+            host = share.host.display(),
+            readonly = if share.readonly { " --readonly" } else { "" },
+            log = vm_dir.join(format!("virtiofsd-{}.log", tag)).display(),
+        ));
+    }
+    Ok(commands)
+}
+
+/// `--fs` arguments for the launch spec.
+pub fn ch_args(vm_dir: &Path) -> Vec<String> {
+    (0..load(vm_dir).len())
+        .flat_map(|i| {
+            let tag = tag(i);
+            [
+                "--fs".to_string(),
+                format!("tag={},socket={}", tag, socket(vm_dir, &tag).display()),
+            ]
+        })
+        .collect()
+}
+
+/// Stop the VM's virtiofsd daemons, if any are running.
+pub fn stop(vm_dir: &Path) {
+    for i in 0..load(vm_dir).len() {
+        let tag = tag(i);
+        let pid_file = pid_file(vm_dir, &tag);
+        if let Some(pid) = fs::read_to_string(&pid_file)
+            .ok()
+            .and_then(|p| p.trim().parse::<u32>().ok())
+        {
+            // Started as root by the launch setup.
+            let _ = Command::new("sudo")
+                .args(["kill", &pid.to_string()])
+                .output();
+        }
+        fs::remove_file(pid_file).ok();
+        fs::remove_file(socket(vm_dir, &tag)).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Share::parse("/srv/src:/mnt/src").unwrap(),
+            Share {
+                host: PathBuf::from("/srv/src"),
+                guest: "/mnt/src".to_string(),
+                readonly: false,
+            }
+        );
+        let share = Share::parse("/srv/src:/mnt/src:ro").unwrap();
+        assert!(share.readonly);
+        assert_eq!(Share::parse(&share.to_string()), Ok(share));
+        assert!(!Share::parse("/srv/src:/mnt/src:rw").unwrap().readonly);
+        assert!(Share::parse("/srv/src:/mnt/src:rx").is_err());
+        assert!(Share::parse("/srv/src:/mnt/src:ro:ro").is_err());
+        assert!(Share::parse("/srv/src").is_err());
+        assert!(Share::parse("srv:/mnt").is_err());
+        assert!(Share::parse("/srv:/").is_err());
+        assert!(Share::parse("/srv/it's:/mnt").is_err());
+    }
+
+    #[test]
+    fn test_prepare() {
+        let dir = TempDir::new().unwrap();
+        let vm_dir = dir.path().join("vm");
+        fs::create_dir(&vm_dir).unwrap();
+        let shares = vec![Share::parse(&format!("{}:/mnt/host", dir.path().display())).unwrap()];
+        crate::immutable::prepare(
+            &vm_dir,
+            &vm_dir.join("missing"),
+            false,
+            Some(&crate::immutable::DataDisk::new("1G", None).unwrap()),
+        )
+        .unwrap();
+        prepare(&vm_dir, &shares).unwrap();
+
+        assert_eq!(load(&vm_dir), shares);
+        let vendor = fs::read_to_string(vm_dir.join(crate::immutable::VENDOR_DATA)).unwrap();
+        assert_eq!(vendor.matches("mounts:").count(), 1);
+        assert!(vendor.ends_with(
+            "ext4, \"defaults,nofail\", \"0\", \"2\"]\n  - [share0, /mnt/host, virtiofs, \"defaults,nofail\", \"0\", \"0\"]\n"
+        ));
+        assert_eq!(
+            ch_args(&vm_dir),
+            [
+                "--fs".to_string(),
+                format!(
+                    "tag=share0,socket={}/virtiofs-share0.sock",
+                    vm_dir.display()
+                )
+            ]
+        );
+
+        let missing = vec![Share::parse("/nonexistent/dir:/mnt").unwrap()];
+        assert!(prepare(&vm_dir, &missing).is_err());
+
+        let readonly = vec![Share::parse(&format!("{}:/mnt/ro:ro", dir.path().display())).unwrap()];
+        assert!(vendor_data("", &readonly)
+            .ends_with("virtiofs, \"defaults,nofail,ro\", \"0\", \"0\"]\n"));
+    }
+
+    #[test]
+    fn test_confine() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        let share = |host: &Path| Share::parse(&format!("{}:/mnt/src", host.display())).unwrap();
+
+        assert_eq!(confine(Vec::new()), Ok(Vec::new()));
+        std::env::remove_var(ROOTS_ENV);
+        assert!(confine(vec![share(dir.path())])
+            .unwrap_err()
+            .contains(ROOTS_ENV));
+
+        std::env::set_var(ROOTS_ENV, dir.path());
+        let confined = confine(vec![share(&dir.path().join("src"))]).unwrap();
+        assert_eq!(
+            confined[0].host,
+            fs::canonicalize(dir.path().join("src")).unwrap()
+        );
+        assert!(confine(vec![share(Path::new("/"))]).is_err());
+        assert!(confine(vec![share(Path::new("/etc"))]).is_err());
+        assert!(confine(vec![share(&dir.path().join("src/../.."))]).is_err());
+        std::env::remove_var(ROOTS_ENV);
+    }
+}
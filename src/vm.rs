@@ -1,18 +1,27 @@
-use crate::config::Config;
+use crate::assets::{self, Asset};
+use crate::config::{Config, DiskFormat};
 use crate::error::{Error, Result};
+use crate::labels::{self, Labels, VmFilter};
+use crate::launch::LaunchSpec;
+use crate::lock::FileLock;
+use crate::memory_backing::MemoryBacking;
 use crate::netns::NetnsSpec;
 use crate::network::{cleanup_networking, generate_random_mac};
+use crate::progress::Progress;
+use crate::state::VmState;
 use crate::util::{
-    check_process_running, download_file, ensure_dependency, run_command, write_string_to_file,
+    check_process_running, disk_virtual_size, ensure_dependency, parse_size_bytes, run_command,
+    write_string_to_file,
 };
 use backon::{BlockingRetryable, ExponentialBuilder};
 use log::{debug, info, warn};
 use serde::Serialize;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 use std::process::Command;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Clone)]
 pub struct VmResources {
@@ -20,6 +29,42 @@ pub struct VmResources {
     pub cpus: u8,
     pub disk_size: String,
     pub devices: Vec<String>,
+    /// Storage pool to place the VM on (see `storage::select_pool`)
+    pub storage: Option<String>,
+    /// Block traffic to and from other VMs (see `network::ForwardPolicy`)
+    pub isolate: bool,
+    /// Outbound destinations the VM may reach (see `egress`)
+    pub egress: Option<crate::egress::EgressPolicy>,
+    /// Host interface the VM's NAT is pinned to (see `uplink`)
+    pub egress_interface: Option<String>,
+    /// Reset the root disk to the image on every start (see `immutable`)
+    pub immutable_root: bool,
+    /// Persistent second disk mounted by cloud-init
+    pub data_disk: Option<crate::immutable::DataDisk>,
+    /// NAT behind the host, or bridged onto the LAN (see `bridge`)
+    pub network: crate::bridge::NetworkMode,
+    /// Shared, hugepage and/or prefaulted guest memory (see `memory_backing`)
+    pub memory_backing: MemoryBacking,
+    /// Entropy source of the virtio-rng device (see `rng`)
+    pub rng: crate::rng::RngSource,
+    /// Host directories shared over virtiofs (see `virtiofs`)
+    pub shares: Vec<crate::virtiofs::Share>,
+    /// Environment variables written by cloud-init (see `guest_env`)
+    pub env: crate::guest_env::GuestEnv,
+    /// Host files written into the guest by cloud-init (see `secrets`)
+    pub secrets: Vec<crate::secrets::Secret>,
+    /// Give the VM a vsock device for the guest agent (see `agent`)
+    pub vsock: bool,
+    /// Host kernel, initramfs and command line to boot (see `boot`)
+    pub boot: Option<crate::boot::DirectBoot>,
+    /// Host CPUs to pin the VM to (see `cpu_affinity`)
+    pub cpu_affinity: Option<crate::cpu_affinity::CpuSet>,
+    /// vCPUs and memory the VM can be hotplugged up to (see `hotplug`)
+    pub headroom: crate::hotplug::Headroom,
+    /// Add a virtio-balloon device (see `memory_backing`)
+    pub balloon: bool,
+    /// Serve cloud-init data over HTTP instead of a seed ISO (see `nocloud`)
+    pub no_iso: bool,
 }
 
 impl VmResources {
@@ -35,6 +80,150 @@ impl VmResources {
             cpus: cpus.unwrap_or(config.cpus as u8),
             disk_size: disk_size.unwrap_or(&config.disk_size).to_string(),
             devices,
+            storage: None,
+            isolate: config.isolate,
+            egress: None,
+            egress_interface: None,
+            immutable_root: false,
+            data_disk: None,
+            network: crate::bridge::NetworkMode::Nat,
+            memory_backing: MemoryBacking::default(),
+            rng: config.rng_source.clone(),
+            shares: Vec::new(),
+            env: Default::default(),
+            secrets: Vec::new(),
+            vsock: false,
+            boot: None,
+            cpu_affinity: None,
+            headroom: Default::default(),
+            balloon: false,
+            no_iso: false,
+        }
+    }
+
+    pub fn with_storage(mut self, storage: Option<String>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// `--isolate` only adds isolation; it can't opt out of `MEDA_ISOLATE`.
+    pub fn with_isolation(mut self, isolate: bool) -> Self {
+        self.isolate |= isolate;
+        self
+    }
+
+    pub fn with_egress(mut self, egress: Option<crate::egress::EgressPolicy>) -> Self {
+        self.egress = egress;
+        self
+    }
+
+    pub fn with_egress_interface(mut self, egress_interface: Option<String>) -> Self {
+        self.egress_interface = egress_interface;
+        self
+    }
+
+    /// Fail unless the host can honor the network options.
+    pub fn check_network(&self) -> Result<()> {
+        if let Some(iface) = &self.egress_interface {
+            // API requests skip clap's parser, and the name ends up in
+            // a root shell script.
+            crate::uplink::parse_interface(iface).map_err(Error::Other)?;
+            crate::uplink::check_interface(iface)?;
+        }
+        Ok(())
+    }
+
+    pub fn with_immutable_root(mut self, immutable_root: bool) -> Self {
+        self.immutable_root = immutable_root;
+        self
+    }
+
+    pub fn with_data_disk(mut self, data_disk: Option<crate::immutable::DataDisk>) -> Self {
+        self.data_disk = data_disk;
+        self
+    }
+
+    pub fn with_network(mut self, network: crate::bridge::NetworkMode) -> Self {
+        self.network = network;
+        self
+    }
+
+    pub fn with_memory_backing(mut self, memory_backing: MemoryBacking) -> Self {
+        self.memory_backing = memory_backing;
+        self
+    }
+
+    /// `--rng`; `None` keeps `MEDA_RNG_SOURCE`.
+    pub fn with_rng(mut self, rng: Option<crate::rng::RngSource>) -> Self {
+        if let Some(rng) = rng {
+            self.rng = rng;
+        }
+        self
+    }
+
+    pub fn with_shares(mut self, shares: Vec<crate::virtiofs::Share>) -> Self {
+        self.shares = shares;
+        self
+    }
+
+    pub fn with_env(mut self, env: crate::guest_env::GuestEnv) -> Self {
+        self.env = env;
+        self
+    }
+
+    pub fn with_secrets(mut self, secrets: Vec<crate::secrets::Secret>) -> Self {
+        self.secrets = secrets;
+        self
+    }
+
+    pub fn with_vsock(mut self, vsock: bool) -> Self {
+        self.vsock = vsock;
+        self
+    }
+
+    pub fn with_boot(mut self, boot: Option<crate::boot::DirectBoot>) -> Self {
+        self.boot = boot;
+        self
+    }
+
+    pub fn with_cpu_affinity(mut self, cpu_affinity: Option<crate::cpu_affinity::CpuSet>) -> Self {
+        self.cpu_affinity = cpu_affinity;
+        self
+    }
+
+    pub fn with_headroom(mut self, headroom: crate::hotplug::Headroom) -> Self {
+        self.headroom = headroom;
+        self
+    }
+
+    pub fn with_balloon(mut self, balloon: bool) -> Self {
+        self.balloon = balloon;
+        self
+    }
+
+    pub fn with_no_iso(mut self, no_iso: bool) -> Self {
+        self.no_iso = no_iso;
+        self
+    }
+
+    /// Set up disks, a kernel or first-boot files the template fast
+    /// path of `meda run` can't give a clone, so the VM has to cold-boot.
+    /// Templates keep the host's `MEDA_RNG_SOURCE`, so another `--rng`
+    /// cold-boots too.
+    pub fn needs_cold_boot(&self, config: &Config) -> bool {
+        self.rng != config.rng_source
+            || self.immutable_root
+            || self.data_disk.is_some()
+            || self.boot.is_some()
+            || !self.env.is_empty()
+            || !self.secrets.is_empty()
+    }
+
+    pub fn forward_policy(&self) -> crate::network::ForwardPolicy {
+        crate::network::ForwardPolicy {
+            isolated: self.isolate,
+            egress: self.egress.clone(),
+            egress_interface: self.egress_interface.clone(),
         }
     }
 }
@@ -68,6 +257,10 @@ pub struct VmInfo {
     pub disk: String,
     pub devices: Vec<String>,
     pub created: String,
+    pub labels: Labels,
+    /// Guest environment, secrets redacted (see `guest_env`)
+    pub env: Labels,
+    pub storage: String,
 }
 
 #[derive(Serialize)]
@@ -91,39 +284,20 @@ pub async fn bootstrap(config: &Config) -> Result<()> {
     info!("Ensuring directories exist");
     config.ensure_dirs()?;
 
-    // Download base image if needed
-    if !config.base_raw.exists() {
+    // Download base image if needed, or a newer one once it is
+    // MEDA_IMAGE_MAX_AGE old
+    let base_image = crate::base_image::current(config);
+    if !base_image.exists() {
         info!("Downloading Ubuntu image");
-        let tmp_file = config.asset_dir.join("img.qcow2");
-        download_file(&config.os_url, &tmp_file).await?;
-
-        ensure_dependency("qemu-img", "qemu-utils")?;
-
-        info!("Converting to raw format");
-        run_command(
-            "qemu-img",
-            &[
-                "convert",
-                "-f",
-                "qcow2",
-                "-O",
-                "raw",
-                tmp_file.to_str().unwrap(),
-                config.base_raw.to_str().unwrap(),
-            ],
-        )?;
-
-        // Resize image
-        crate::util::resize_raw_disk(&config.base_raw, &config.disk_size)?;
-
-        // Remove temporary file
-        fs::remove_file(&tmp_file).ok();
+        crate::base_image::download(config, &base_image).await?;
+    } else {
+        crate::base_image::refresh(config, false).await?;
     }
 
     // Download firmware if needed
     if !config.fw_bin.exists() {
         info!("Downloading firmware");
-        download_file(&config.fw_url, &config.fw_bin).await?;
+        assets::fetch(config, Asset::Firmware, &config.fw_bin).await?;
 
         // Make firmware executable
         let mut perms = fs::metadata(&config.fw_bin)?.permissions();
@@ -134,7 +308,7 @@ pub async fn bootstrap(config: &Config) -> Result<()> {
     // Download cloud-hypervisor if needed
     if !config.ch_bin.exists() {
         info!("Downloading cloud-hypervisor");
-        download_file(&config.ch_url, &config.ch_bin).await?;
+        assets::fetch(config, Asset::CloudHypervisor, &config.ch_bin).await?;
 
         // Make cloud-hypervisor executable
         let mut perms = fs::metadata(&config.ch_bin)?.permissions();
@@ -145,7 +319,7 @@ pub async fn bootstrap(config: &Config) -> Result<()> {
     // Download ch-remote if needed
     if !config.cr_bin.exists() {
         info!("Downloading ch-remote");
-        download_file(&config.cr_url, &config.cr_bin).await?;
+        assets::fetch(config, Asset::ChRemote, &config.cr_bin).await?;
 
         // Make ch-remote executable
         let mut perms = fs::metadata(&config.cr_bin)?.permissions();
@@ -154,21 +328,11 @@ pub async fn bootstrap(config: &Config) -> Result<()> {
     }
 
     // Download ORAS if needed
-    if !config.oras_bin.exists() {
+    if config.oras_override.is_none() && !config.oras_bin.exists() {
         info!("Downloading ORAS");
-        let temp_tar = config.asset_dir.join("oras.tar.gz");
-        download_file(&config.oras_url, &temp_tar).await?;
-
-        // Extract ORAS binary from tar.gz
-        extract_oras_binary(&temp_tar, &config.oras_bin)?;
-
-        // Remove temporary tar file
-        fs::remove_file(&temp_tar).ok();
+        crate::oras::install(config, config.oras_version).await?;
     }
 
-    // Ensure other dependencies
-    ensure_dependency("genisoimage", "genisoimage")?;
-
     info!("Bootstrap complete");
     Ok(())
 }
@@ -181,7 +345,7 @@ pub async fn bootstrap_binaries_only(config: &Config) -> Result<()> {
     // Download firmware if needed
     if !config.fw_bin.exists() {
         info!("Downloading firmware");
-        download_file(&config.fw_url, &config.fw_bin).await?;
+        assets::fetch(config, Asset::Firmware, &config.fw_bin).await?;
 
         // Make firmware executable
         let mut perms = fs::metadata(&config.fw_bin)?.permissions();
@@ -192,7 +356,7 @@ pub async fn bootstrap_binaries_only(config: &Config) -> Result<()> {
     // Download cloud-hypervisor if needed
     if !config.ch_bin.exists() {
         info!("Downloading cloud-hypervisor");
-        download_file(&config.ch_url, &config.ch_bin).await?;
+        assets::fetch(config, Asset::CloudHypervisor, &config.ch_bin).await?;
 
         // Make cloud-hypervisor executable
         let mut perms = fs::metadata(&config.ch_bin)?.permissions();
@@ -203,7 +367,7 @@ pub async fn bootstrap_binaries_only(config: &Config) -> Result<()> {
     // Download ch-remote if needed
     if !config.cr_bin.exists() {
         info!("Downloading ch-remote");
-        download_file(&config.cr_url, &config.cr_bin).await?;
+        assets::fetch(config, Asset::ChRemote, &config.cr_bin).await?;
 
         // Make ch-remote executable
         let mut perms = fs::metadata(&config.cr_bin)?.permissions();
@@ -212,38 +376,132 @@ pub async fn bootstrap_binaries_only(config: &Config) -> Result<()> {
     }
 
     // Download ORAS if needed
-    if !config.oras_bin.exists() {
+    if config.oras_override.is_none() && !config.oras_bin.exists() {
         info!("Downloading ORAS");
-        let temp_tar = config.asset_dir.join("oras.tar.gz");
-        download_file(&config.oras_url, &temp_tar).await?;
-
-        // Extract ORAS binary from tar.gz
-        extract_oras_binary(&temp_tar, &config.oras_bin)?;
-
-        // Remove temporary tar file
-        fs::remove_file(&temp_tar).ok();
+        crate::oras::install(config, config.oras_version).await?;
     }
 
-    // Ensure other dependencies
-    ensure_dependency("genisoimage", "genisoimage")?;
-
     info!("Hypervisor binaries bootstrap complete");
     Ok(())
 }
 
+/// Dir under `vm_root` holding the per-VM lock files.
+const LOCK_DIR: &str = ".locks";
+
+/// Per-VM operation lock. It sits outside the VM dir, so `create` can
+/// take it before the dir exists and `delete` can hold it while removing
+/// the dir. Lock files are never removed: unlinking one while it is held
+/// would let the next process lock a fresh file alongside the holder.
+fn lock_path(config: &Config, name: &str) -> std::path::PathBuf {
+    config.vm_root.join(LOCK_DIR).join(format!("{}.lock", name))
+}
+
+/// Take VM `name`'s operation lock for `operation` (e.g. "start"),
+/// released when the guard drops. Anything that changes a VM's state
+/// holds it, so a second meda process (or the API server) working on
+/// the same VM fails straight away with `VmLocked` instead of racing.
+pub fn lock(config: &Config, name: &str, operation: &str) -> Result<FileLock> {
+    fs::create_dir_all(config.vm_root.join(LOCK_DIR))?;
+    let path = lock_path(config, name);
+    match FileLock::try_exclusive(&path)? {
+        Some(lock) => {
+            let holder = format!("meda {} (pid {})", operation, std::process::id());
+            if let Err(e) = lock.set_holder(&holder) {
+                debug!("could not record lock holder for {}: {}", name, e);
+            }
+            Ok(lock)
+        }
+        None => Err(Error::VmLocked(
+            name.to_string(),
+            FileLock::holder(&path).unwrap_or_else(|| "another meda process".to_string()),
+        )),
+    }
+}
+
 pub async fn create(
     config: &Config,
     name: &str,
     user_data_path: Option<&str>,
+    ssh_keys: &[String],
+    labels: &Labels,
     resources: &VmResources,
     json: bool,
 ) -> Result<()> {
+    let _lock = lock(config, name, "create")?;
     let vm_dir = config.vm_dir(name);
 
     if vm_dir.exists() {
         return Err(Error::VmAlreadyExists(name.to_string()));
     }
 
+    // Resolve keys and check labels before touching disk so bad input fails fast.
+    let extra_keys = crate::ssh::resolve_extra_keys(ssh_keys)?;
+    labels::validate_labels(labels)?;
+    let pool = crate::storage::select_pool(
+        config,
+        resources.storage.as_deref(),
+        labels,
+        &resources.disk_size,
+    )?;
+    if let Some(bridge) = resources.network.bridge() {
+        // Bridged traffic never crosses the host's FORWARD chain.
+        if !resources.forward_policy().is_empty() {
+            return Err(Error::Other(
+                "bridged VMs bypass the host's forwarding rules; isolation, egress policies and egress interfaces (including MEDA_ISOLATE) don't apply to them".to_string(),
+            ));
+        }
+        crate::bridge::check_bridge(bridge)?;
+    }
+    if resources.network == crate::bridge::NetworkMode::User {
+        // Nothing in a user-mode VM's path runs as root.
+        if !resources.forward_policy().is_empty() {
+            return Err(Error::Other(
+                "user-mode VMs have no host firewall rules; isolation, egress policies and egress interfaces (including MEDA_ISOLATE) don't apply to them".to_string(),
+            ));
+        }
+        if !resources.shares.is_empty() || !resources.devices.is_empty() {
+            return Err(Error::Other(
+                "shared directories and VFIO devices need root, which user-mode VMs run without"
+                    .to_string(),
+            ));
+        }
+        ensure_dependency("passt", "passt")?;
+    }
+    if resources.no_iso {
+        // The responder listens on the NAT side of the VM's tap.
+        if resources.network != crate::bridge::NetworkMode::Nat {
+            return Err(Error::Other(
+                "--no-iso needs --network nat; bridged and user-mode VMs have no host address on their network to serve cloud-init data from".to_string(),
+            ));
+        }
+        ensure_dependency("dnsmasq", "dnsmasq")?;
+    }
+    resources.check_network()?;
+    resources
+        .headroom
+        .check(resources.cpus, &resources.memory)?;
+    if let Some(path) = user_data_path.filter(|_| !extra_keys.is_empty()) {
+        // Fail before anything is made if the keys can't go in.
+        crate::ssh::add_keys_to_user_data(&fs::read_to_string(path)?, &extra_keys)?;
+    }
+    // A qcow2 overlay starts out nearly empty; a raw root is a full copy.
+    let root_bytes =
+        match crate::immutable::root_format(resources.immutable_root, config.disk_format) {
+            DiskFormat::Raw => parse_size_bytes(&resources.disk_size).unwrap_or(0),
+            DiskFormat::Qcow2 => 0,
+        };
+    crate::preflight::before_create(
+        config,
+        pool.map_or(&config.vm_root, |p| &p.root),
+        root_bytes,
+    )?;
+    // Fail before bootstrap downloads anything; checked again under the lock.
+    drop(crate::quota::before_create(
+        config,
+        name,
+        &resources.disk_size,
+    )?);
+
     if !json {
         info!("Creating VM: {}", name);
     }
@@ -251,36 +509,86 @@ pub async fn create(
     // Bootstrap to ensure we have the necessary binaries
     bootstrap(config).await?;
 
-    // Create VM directory
-    fs::create_dir_all(&vm_dir)?;
-
-    // Copy base image
-    if !json {
-        info!("Copying base image");
-    }
-    let vm_rootfs = vm_dir.join("rootfs.qcow2");
-    if !json {
-        info!(
-            "Creating qcow2 overlay (backing: {})",
-            config.base_raw.display()
-        );
+    // Create VM directory (on its storage pool, linked from vm_root)
+    let quota = crate::quota::before_create(config, name, &resources.disk_size)?;
+    crate::storage::create_vm_dir(config, name, pool)?;
+    drop(quota);
+    let provisioned = crate::state::transition(
+        &vm_dir,
+        VmState::Creating,
+        VmState::Stopped,
+        provision(
+            config,
+            name,
+            user_data_path,
+            &extra_keys,
+            labels,
+            resources,
+            json,
+        ),
+    )
+    .await;
+    if let Err(e) = provisioned {
+        roll_back(config, name).await;
+        return Err(e);
     }
-    crate::util::create_qcow2_overlay(&config.base_raw, &vm_rootfs, Some(&resources.disk_size))?;
+    crate::inventory::notify(config, name, crate::inventory::Event::Created).await;
 
-    // Reap any tap devices leaked by a prior delete so we don't pick a subnet
-    // that still has a stale connected route via a linkdown orphan.
-    if let Err(e) = crate::network::cleanup_orphaned_tap_devices(config).await {
-        log::warn!("orphan tap reap before VM create failed: {}", e);
+    let message = format!("Successfully created VM: {}", name);
+    if json {
+        let result = VmResult {
+            success: true,
+            message,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!("{}", message);
     }
 
-    // Generate network config with a unique subnet
-    let subnet = crate::network::generate_unique_subnet(config).await?;
-    // Generate unique TAP device name
-    let tap_name = crate::network::generate_unique_tap_name(config, name).await?;
+    Ok(())
+}
+
+/// Fill in a new VM's dir: disks, resource settings, cloud-init and
+/// launch spec.
+async fn provision(
+    config: &Config,
+    name: &str,
+    user_data_path: Option<&str>,
+    extra_keys: &[String],
+    labels: &Labels,
+    resources: &VmResources,
+    json: bool,
+) -> Result<()> {
+    let vm_dir = config.vm_dir(name);
+    labels::write_labels(&vm_dir, labels)?;
 
-    // Store network config
-    write_string_to_file(&vm_dir.join("subnet"), &subnet)?;
-    write_string_to_file(&vm_dir.join("tapdev"), &tap_name)?;
+    // Provision the root disk from the base image
+    let base_image = crate::base_image::current(config);
+    let root_format = crate::immutable::root_format(resources.immutable_root, config.disk_format);
+    if !json {
+        match root_format {
+            DiskFormat::Qcow2 => {
+                info!("Creating qcow2 overlay (backing: {})", base_image.display())
+            }
+            DiskFormat::Raw => info!("Copying base image {}", base_image.display()),
+        }
+    }
+    let rootfs = crate::util::provision_rootfs(
+        &base_image,
+        &vm_dir,
+        root_format,
+        Some(&resources.disk_size),
+    )
+    .await?;
+    crate::immutable::prepare(
+        &vm_dir,
+        &rootfs,
+        resources.immutable_root,
+        resources.data_disk.as_ref(),
+    )?;
+    crate::virtiofs::prepare(&vm_dir, &resources.shares)?;
+    crate::guest_env::prepare(&vm_dir, &resources.env)?;
+    crate::secrets::prepare(&vm_dir, &resources.secrets)?;
 
     // Store VM resource configuration
     write_string_to_file(&vm_dir.join("memory"), &resources.memory)?;
@@ -292,41 +600,143 @@ pub async fn create(
         validate_device_paths(&resources.devices)?;
         write_string_to_file(&vm_dir.join("devices"), &resources.devices.join("\n"))?;
     }
-
-    // Create cloud-init files
-    let meta_data = format!("instance-id: {}\nlocal-hostname: {}\n", name, name);
-    write_string_to_file(&vm_dir.join("meta-data"), &meta_data)?;
+    resources.forward_policy().save(&vm_dir)?;
+    if let Some(bridge) = resources.network.bridge() {
+        crate::bridge::record(&vm_dir, bridge)?;
+    }
+    let user_net = resources.network == crate::bridge::NetworkMode::User;
+    if user_net {
+        crate::usernet::enable(&vm_dir)?;
+    }
+    // virtiofsd and passt are vhost-user backends: they need to map
+    // guest memory.
+    let mut memory_backing = resources.memory_backing;
+    memory_backing.shared |= !resources.shares.is_empty() || user_net;
+    memory_backing.save(&vm_dir)?;
+    resources.rng.save(&vm_dir)?;
+    if let Some(boot) = &resources.boot {
+        boot.save(&vm_dir)?;
+    }
+    if let Some(cpus) = &resources.cpu_affinity {
+        cpus.save(&vm_dir)?;
+    }
+    crate::hotplug::Headroom {
+        booted_cpus: Some(resources.headroom.max_cpus.unwrap_or(resources.cpus)),
+        ..resources.headroom.clone()
+    }
+    .save(&vm_dir)?;
+    if resources.balloon {
+        crate::memory_backing::enable_balloon(&vm_dir)?;
+    }
+    if resources.vsock {
+        crate::agent::enable(&vm_dir)?;
+    }
+    if resources.no_iso {
+        crate::nocloud::enable(&vm_dir)?;
+    }
+    // Only start needs the pages; say now if this host can't provide them.
+    let problems = resources.memory_backing.problems(
+        parse_size_bytes(&resources.memory).unwrap_or(0),
+        crate::memory_backing::host_hugepages().as_ref(),
+    );
+    if !problems.is_empty() {
+        log::warn!(
+            "{} won't start on this host until: {}",
+            name,
+            problems.join("; ")
+        );
+    }
 
     // User data
     if let Some(path) = user_data_path {
-        fs::copy(path, vm_dir.join("user-data"))?;
+        let user_data = fs::read_to_string(path)?;
+        let user_data = if extra_keys.is_empty() {
+            user_data
+        } else {
+            crate::ssh::add_keys_to_user_data(&user_data, extra_keys)?
+        };
+        write_string_to_file(&vm_dir.join("user-data"), &user_data)?;
     } else {
         let keypair = crate::ssh::ensure_ssh_keypair(config)?;
-        let default_user_data = format!(
-            r#"#cloud-config
-users:
-  - name: cirun
-    sudo: ALL=(ALL) NOPASSWD:ALL
-    passwd: $6$ep7LxhhmhQHf.TiY$qPJVJQCnPMnyFdmD0ymP7CH2dos0awET8JlSzDqoiK6AOQwDpx8fCLJ1C5c7nvkVJbIpQCOalC8l2BGkRzogM.
-    lock_passwd: false
-    inactive: false
-    groups: sudo
-    shell: /bin/bash
-    ssh_authorized_keys:
-      - {}
-ssh_pwauth: true
-"#,
-            keypair.public_key
-        );
+        let default_user_data = crate::ssh::default_user_data(&keypair.public_key, extra_keys);
         write_string_to_file(&vm_dir.join("user-data"), &default_user_data)?;
     }
 
+    let identity = assign_identity(config, name, json).await?;
+    write_launch_spec(config, name, resources, &identity, json).await
+}
+
+/// Network identity of a VM. Every new VM and every clone gets its own.
+pub struct VmIdentity {
+    /// Empty for bridged VMs, which take their address from the LAN, and
+    /// user-mode ones, which take it from passt.
+    pub subnet: String,
+    /// Empty for user-mode VMs, which have no tap.
+    pub tap_name: String,
+    pub mac: String,
+}
+
+/// Give VM `name` a fresh machine identity: subnet, TAP name, MAC, and
+/// a cloud-init ISO whose instance-id and hostname are `name` and whose
+/// network-config matches the new MAC and subnet. A user-mode VM gets
+/// its own SSH port instead of a subnet and TAP. The VM's `user-data`
+/// must already be in its dir.
+///
+/// A new instance-id makes cloud-init inside a copied disk treat the
+/// boot as a new instance, so host keys and hostname are regenerated.
+pub async fn assign_identity(config: &Config, name: &str, json: bool) -> Result<VmIdentity> {
+    let vm_dir = config.vm_dir(name);
+
+    let bridged = crate::bridge::bridge_of(&vm_dir).is_some();
+    let user_net = crate::usernet::is_enabled(&vm_dir);
+    let nocloud = crate::nocloud::is_enabled(&vm_dir);
+
+    let (subnet, tap_name) = if user_net {
+        crate::usernet::assign(&vm_dir)?;
+        (String::new(), String::new())
+    } else {
+        // Reap any tap devices leaked by a prior delete so we don't pick a subnet
+        // that still has a stale connected route via a linkdown orphan.
+        if let Err(e) = crate::network::cleanup_orphaned_tap_devices(config).await {
+            log::warn!("orphan tap reap before assigning VM identity failed: {}", e);
+        }
+
+        // Held until both are recorded below, where the next allocation
+        // will see them.
+        let allocation = crate::network::lock_allocation(config)?;
+
+        // Generate network config with a unique subnet; bridged VMs are on
+        // the LAN's instead
+        let subnet = if bridged {
+            String::new()
+        } else {
+            crate::network::generate_unique_subnet(config, name).await?
+        };
+        // Generate unique TAP device name
+        let tap_name = crate::network::generate_unique_tap_name(config, name).await?;
+
+        // Store network config
+        if !bridged {
+            write_string_to_file(&vm_dir.join("subnet"), &subnet)?;
+        }
+        write_string_to_file(&vm_dir.join("tapdev"), &tap_name)?;
+        drop(allocation);
+        (subnet, tap_name)
+    };
+
+    // Create cloud-init files
+    let meta_data = format!("instance-id: {}\nlocal-hostname: {}\n", name, name);
+    write_string_to_file(&vm_dir.join("meta-data"), &meta_data)?;
+
     // Generate MAC address
     let mac = generate_random_mac();
     write_string_to_file(&vm_dir.join("mac"), &mac)?;
 
     // Create cloud-init ISO
     let ci_dir = vm_dir.join("ci");
+    if ci_dir.exists() {
+        fs::remove_dir_all(&ci_dir)?;
+    }
     fs::create_dir_all(&ci_dir)?;
 
     // Copy cloud-init files to ci directory
@@ -335,124 +745,260 @@ ssh_pwauth: true
         let dst = ci_dir.join(file);
         fs::copy(&src, &dst)?;
     }
+    let vendor_data = vm_dir.join(crate::immutable::VENDOR_DATA);
+    if vendor_data.exists() {
+        let copy = ci_dir.join(crate::immutable::VENDOR_DATA);
+        fs::copy(&vendor_data, &copy)?;
+        crate::secrets::restrict(&copy)?;
+    }
 
-    // Create network-config
-    let network_config = format!(
-        r#"version: 2
-ethernets:
-  ens4:
-    match:
-       macaddress: {}
-    addresses: [{}.2/24]
-    gateway4: {}.1
-    set-name: ens4
-    nameservers:
-      addresses: [8.8.8.8, 1.1.1.1]
-"#,
-        mac, subnet, subnet
-    );
+    // Create network-config; clones keep their source's addressing mode.
+    // Bridged guests DHCP from the LAN and user-mode ones from passt, so
+    // meda runs no dnsmasq for them. Guests fetching their cloud-init
+    // data over HTTP need an address before cloud-init runs.
+    if (config.dhcp || nocloud) && !bridged && !user_net {
+        crate::dhcp::enable(&vm_dir)?;
+    }
+    let dhcp = crate::dhcp::is_enabled(&vm_dir);
+    if dhcp {
+        ensure_dependency("dnsmasq", "dnsmasq")?;
+    }
+    let network_config = crate::dhcp::network_config(&mac, &subnet, dhcp || bridged || user_net);
     write_string_to_file(&ci_dir.join("network-config"), &network_config)?;
 
-    // Create cloud-init ISO
+    // Create cloud-init ISO, unless the guest fetches the ci dir over HTTP
     let ci_iso = vm_dir.join("ci.iso");
     if !json {
         info!("Creating cloud-init configuration");
     }
-    crate::util::run_command_quietly(
-        "genisoimage",
-        &[
-            "-output",
-            ci_iso.to_str().unwrap(),
-            "-volid",
-            "cidata",
-            "-joliet",
-            "-rock",
-            ci_dir.to_str().unwrap(),
-        ],
-    )?;
+    fs::remove_file(&ci_iso).ok();
+    if !nocloud {
+        crate::iso::write_cidata(&ci_dir, &ci_iso)?;
+    }
+
+    Ok(VmIdentity {
+        subnet,
+        tap_name,
+        mac,
+    })
+}
+
+/// Set up VM `name`'s network namespace and write its launch spec for
+/// the root disk found in its dir.
+async fn write_launch_spec(
+    config: &Config,
+    name: &str,
+    resources: &VmResources,
+    identity: &VmIdentity,
+    json: bool,
+) -> Result<()> {
+    let vm_dir = config.vm_dir(name);
+    let VmIdentity {
+        subnet,
+        tap_name,
+        mac,
+    } = identity;
 
     // Per-VM network namespace. Everything below — tap, iptables,
     // forwarding, the CH process itself — lives inside a dedicated
     // `meda-<hash>` netns so N concurrent VMs don't collide on the
     // template's baked-in guest IP. Host reaches the guest via the
     // veth pair's netns-side IP; see `src/netns.rs` for the wiring.
-    if !json {
-        info!("Setting up VM network namespace");
-    }
-    let netns_spec = NetnsSpec::for_vm(name);
-    netns_spec.save(&vm_dir)?;
-    crate::netns::create(&netns_spec, &subnet, &tap_name)?;
-
-    // Build device passthrough flags
-    let device_section = if resources.devices.is_empty() {
-        String::new()
-    } else {
-        let args: Vec<String> = resources
-            .devices
-            .iter()
-            .map(|d| format!("  --device path={}", d))
-            .collect();
-        format!(" \\\n{}", args.join(" \\\n"))
+    // Bridged VMs stay in the host netns and their setup attaches the
+    // tap to the bridge instead.
+    if crate::usernet::is_enabled(&vm_dir) {
+        // Nothing to set up as root; CH runs as the user next to passt.
+        return launch_spec(
+            config,
+            &vm_dir,
+            resources,
+            identity,
+            String::new(),
+            None,
+            false,
+        )?
+        .save(&vm_dir);
+    }
+    let (net_setup, netns) = match crate::bridge::bridge_of(&vm_dir) {
+        Some(bridge) => (crate::bridge::tap_commands(tap_name, &bridge), None),
+        None => {
+            if !json {
+                info!("Setting up VM network namespace");
+            }
+            let netns_spec = NetnsSpec::for_vm(name);
+            netns_spec.save(&vm_dir)?;
+            let policy = crate::network::ForwardPolicy::load(&vm_dir);
+            crate::netns::create(&netns_spec, subnet, tap_name, &policy).await?;
+            (
+                crate::dhcp::start_commands(
+                    &vm_dir,
+                    Some(&netns_spec.netns),
+                    tap_name,
+                    subnet,
+                    mac,
+                ),
+                Some(netns_spec.netns),
+            )
+        }
     };
 
-    // Start script. CH runs inside this VM's dedicated netns so the
-    // tap device, iptables rules, and (via the veth pair) the guest
-    // itself live in their own isolated network world. `sudo` on
-    // `ip netns exec` is required because entering a netns needs
-    // CAP_SYS_ADMIN. The child CH process therefore runs as root —
-    // same as with kernel-tap networking before — and tracks its
-    // own pid inside the sudo'd bash so `meda stop`/`delete` can
-    // still signal it directly.
-    let start_script = format!(
-        r#"#!/bin/bash
-cd "{vmdir}"
-sudo bash -c '
-  ip netns exec {netns} {ch} \
-    --api-socket path={vmdir}/api.sock \
-    --console off \
-    --serial tty \
-    --kernel "{fw}" \
-    --cpus boot={cpus} \
-    --memory size={mem} \
-    --disk path={vmdir}/rootfs.qcow2,image_type=qcow2,backing_files=on path="{vmdir}/ci.iso" \
-    --net tap={tap},mac={mac} \
-    --rng src=/dev/urandom{devsec} \
-    > "{vmdir}/ch.log" 2>&1 &
-  echo $! > "{vmdir}/pid"
-  # File is root-owned; relax so the host user can read/delete.
-  chmod 0644 "{vmdir}/pid"
-'
-
-sleep 2
-if ! sudo kill -0 "$(cat "{vmdir}/pid" 2>/dev/null)" 2>/dev/null; then
-  echo "ERROR: Cloud Hypervisor failed to start. Check log: {vmdir}/ch.log" >&2
-  exit 1
-fi
-# CH ran as root under the netns, so its API socket is owned by
-# root. Relax perms so later ch-remote calls from the unprivileged
-# user (meda snapshot, meda get, etc.) can talk to it.
-sudo chmod 0666 "{vmdir}/api.sock" 2>/dev/null || true
-"#,
-        vmdir = vm_dir.display(),
-        netns = netns_spec.netns,
-        ch = config.ch_bin.display(),
-        fw = config.fw_bin.display(),
-        cpus = resources.cpus,
-        mem = resources.memory,
-        tap = tap_name,
-        mac = mac,
-        devsec = device_section,
-    );
+    // CH runs inside this VM's dedicated netns so the tap device,
+    // iptables rules, and (via the veth pair) the guest itself live in
+    // their own isolated network world. Entering a netns needs
+    // CAP_SYS_ADMIN, so CH runs as root — same as with kernel-tap
+    // networking before. A bridged VM's CH runs in the host netns,
+    // where its bridge is.
+    let setup = net_setup + &crate::virtiofs::start_commands(&vm_dir)?;
+    launch_spec(config, &vm_dir, resources, identity, setup, netns, true)?.save(&vm_dir)
+}
 
-    let start_script_path = vm_dir.join("start.sh");
-    write_string_to_file(&start_script_path, &start_script)?;
+/// How to launch the VM in `vm_dir` with the disks found there and
+/// `resources`: CH as root if `privileged`, inside `netns`, after
+/// `setup` has run as root.
+pub(crate) fn launch_spec(
+    config: &Config,
+    vm_dir: &Path,
+    resources: &VmResources,
+    identity: &VmIdentity,
+    setup: String,
+    netns: Option<String>,
+    privileged: bool,
+) -> Result<LaunchSpec> {
+    let (vm_rootfs, rootfs_format) = DiskFormat::detect(vm_dir)
+        .ok_or_else(|| Error::Other(format!("{} has no root disk", vm_dir.display())))?;
+
+    let mut disks = vec![rootfs_format.ch_disk_arg(&vm_rootfs)];
+    if !crate::nocloud::is_enabled(vm_dir) {
+        disks.push(format!("path={}", vm_dir.join("ci.iso").display()));
+    }
+    disks.extend(crate::immutable::disk_value(vm_dir));
+    disks.extend(crate::disks::disk_values(vm_dir));
+
+    let mut args: Vec<String> = vec![
+        "--api-socket".to_string(),
+        format!("path={}", vm_dir.join("api.sock").display()),
+        "--console".to_string(),
+        "off".to_string(),
+        "--serial".to_string(),
+        format!(
+            "socket={}",
+            vm_dir.join(crate::console::CH_SERIAL_SOCKET).display()
+        ),
+        "--net".to_string(),
+        if crate::usernet::is_enabled(vm_dir) {
+            crate::usernet::net_arg(vm_dir, &identity.mac)
+        } else {
+            format!("tap={},mac={}", identity.tap_name, identity.mac)
+        },
+    ];
+    args.extend(crate::boot::ch_args(vm_dir, &config.fw_bin));
+    args.extend(resources.rng.ch_args());
+    args.extend(crate::virtiofs::ch_args(vm_dir));
+    args.extend(crate::agent::ch_args(vm_dir));
+    args.extend(crate::memory_backing::balloon_ch_args(vm_dir));
+    args.extend(crate::nocloud::ch_args(vm_dir));
+    for device in &resources.devices {
+        args.push("--device".to_string());
+        args.push(format!("path={}", device));
+    }
+    let headroom = crate::hotplug::Headroom::load(vm_dir).unwrap_or_default();
+
+    Ok(LaunchSpec {
+        ch_bin: config.ch_bin.clone(),
+        privileged,
+        netns,
+        cpu_affinity: crate::cpu_affinity::CpuSet::load(vm_dir).map(|cpus| cpus.to_string()),
+        setup,
+        cpus: resources.cpus,
+        memory: resources.memory.clone(),
+        max_cpus: headroom.max_cpus,
+        max_memory: headroom.max_memory,
+        memory_options: MemoryBacking::load(vm_dir).ch_options(),
+        disks,
+        args,
+    })
+}
 
-    // Make start script executable
-    let mut perms = fs::metadata(&start_script_path)?.permissions();
-    perms.set_mode(0o755);
-    fs::set_permissions(&start_script_path, perms)?;
+/// Files a disk clone takes over verbatim from its source.
+const CLONED_FILES: &[&str] = &[
+    crate::network::ISOLATE_FILE,
+    crate::egress::EGRESS_FILE,
+    crate::uplink::UPLINK_FILE,
+    crate::dhcp::DHCP_FILE,
+    crate::bridge::BRIDGE_FILE,
+    crate::usernet::USERNET_FILE,
+    crate::nocloud::NOCLOUD_FILE,
+    crate::memory_backing::MEMORY_BACKING_FILE,
+    crate::rng::RNG_FILE,
+    crate::virtiofs::SHARES_FILE,
+    crate::agent::VSOCK_FILE,
+    crate::boot::BOOT_FILE,
+    crate::cpu_affinity::CPU_AFFINITY_FILE,
+    crate::hotplug::HOTPLUG_FILE,
+    crate::memory_backing::BALLOON_FILE,
+    "memory",
+    "cpus",
+    "disk_size",
+    "devices",
+    "user-data",
+    crate::immutable::VENDOR_DATA,
+    crate::immutable::PRISTINE_ROOT,
+    labels::METADATA_FILE,
+];
+
+/// Clone stopped VM `source` into a new VM `dest` by copying its root
+/// disk — or, with `cow`, putting a qcow2 overlay on top of it, which
+/// is instant but requires `source`'s disk to stay unchanged — and
+/// giving the copy its own identity (see [`assign_identity`]).
+pub async fn clone(
+    config: &Config,
+    source: &str,
+    dest: &str,
+    pool: Option<&crate::storage::StoragePool>,
+    cow: bool,
+    start_clone: bool,
+    json: bool,
+) -> Result<()> {
+    let source_lock = lock(config, source, "clone")?;
+    let dest_lock = lock(config, dest, "clone")?;
+    let src = config.vm_dir(source);
+    if !src.exists() {
+        return Err(Error::VmNotFound(source.to_string()));
+    }
+    if config.vm_dir(dest).exists() {
+        return Err(Error::VmAlreadyExists(dest.to_string()));
+    }
+    if check_vm_running(config, source)? {
+        return Err(Error::Other(format!(
+            "Stop {} before cloning it; its disk must not change while it is copied",
+            source
+        )));
+    }
 
-    let message = format!("Successfully created VM: {}", name);
+    let disk = get_vm_disk_size(config, source).unwrap_or_else(|_| config.disk_size.clone());
+    let quota = crate::quota::before_create(config, dest, &disk)?;
+    let dst = crate::storage::create_vm_dir(config, dest, pool)?;
+    drop(quota);
+    let populated = crate::state::transition(
+        &dst,
+        VmState::Creating,
+        VmState::Stopped,
+        populate_clone(config, source, dest, cow, json),
+    )
+    .await;
+    if let Err(e) = populated {
+        roll_back(config, dest).await;
+        return Err(e);
+    }
+    crate::inventory::notify(config, dest, crate::inventory::Event::Created).await;
+
+    drop(source_lock);
+    drop(dest_lock);
+    if start_clone {
+        start(config, dest, json).await?;
+    }
+
+    let message = format!("Successfully cloned VM {} to {}", source, dest);
     if json {
         let result = VmResult {
             success: true,
@@ -462,82 +1008,269 @@ sudo chmod 0666 "{vmdir}/api.sock" 2>/dev/null || true
     } else {
         info!("{}", message);
     }
-
     Ok(())
 }
 
-pub async fn list(config: &Config, json: bool) -> Result<()> {
-    config.ensure_dirs()?;
+async fn populate_clone(
+    config: &Config,
+    source: &str,
+    dest: &str,
+    cow: bool,
+    json: bool,
+) -> Result<()> {
+    let src = config.vm_dir(source);
+    let dst = config.vm_dir(dest);
+    for file in CLONED_FILES {
+        if src.join(file).exists() {
+            fs::copy(src.join(file), dst.join(file))?;
+        }
+    }
+    if dst.join(crate::immutable::VENDOR_DATA).exists() {
+        crate::secrets::restrict(&dst.join(crate::immutable::VENDOR_DATA))?;
+    }
+    // A clone is kept until deleted, whatever its source's `--ttl`.
+    let mut metadata = labels::VmMetadata::load(&dst)?;
+    if metadata.expires_at.take().is_some() {
+        metadata.save(&dst)?;
+    }
 
-    if !config.vm_root.exists() {
-        if json {
-            println!("[]");
-        } else {
-            info!("No VMs found");
+    let Some((src_rootfs, format)) = DiskFormat::detect(&src) else {
+        return Err(Error::Other(format!("VM {} has no root disk", source)));
+    };
+    if cow {
+        if !json {
+            info!("Creating qcow2 overlay (backing: {})", src_rootfs.display());
         }
-        return Ok(());
+        crate::util::create_qcow2_overlay_with_fmt(
+            &src_rootfs,
+            format.as_str(),
+            &dst.join(DiskFormat::Qcow2.rootfs_name()),
+            None,
+        )
+        .await?;
+    } else {
+        if !json {
+            info!("Copying root disk {}", src_rootfs.display());
+        }
+        let dst_rootfs = dst.join(format.rootfs_name());
+        run_command(
+            "cp",
+            &[
+                "--sparse=always",
+                "--reflink=auto",
+                src_rootfs.to_str().unwrap(),
+                dst_rootfs.to_str().unwrap(),
+            ],
+        )
+        .await?;
     }
 
-    let mut vms = Vec::new();
+    // The data disk is the clone's own from here on.
+    let src_data = src.join(crate::immutable::DATA_DISK);
+    if src_data.exists() {
+        run_command(
+            "cp",
+            &[
+                "--sparse=always",
+                "--reflink=auto",
+                src_data.to_str().unwrap(),
+                dst.join(crate::immutable::DATA_DISK).to_str().unwrap(),
+            ],
+        )
+        .await?;
+    }
 
-    for entry in fs::read_dir(&config.vm_root)? {
-        let entry = entry?;
-        let path = entry.path();
-
-        if path.is_dir() {
-            let name = path.file_name().unwrap().to_string_lossy().to_string();
-            let running = check_vm_running(config, &name)?;
-            let state = if running { "running" } else { "stopped" }.to_string();
-
-            // For a running VM, prefer the host-reachable address
-            // (netns veth IP, legacy smoltcp forward, …); fall back
-            // to the baked-in guest IP only as a last resort. For a
-            // stopped VM nothing is reachable, so show a dash —
-            // printing an IP that doesn't actually answer was the
-            // confusing bit users hit (`ssh 192.168.X.2` → No
-            // route to host).
-            let ip = if running {
-                read_display_ip(&path)
-                    .or_else(|| get_vm_ip(config, &name).ok())
-                    .unwrap_or_else(|| "-".to_string())
-            } else {
-                "-".to_string()
-            };
-            let vcpus = get_vm_cpus(config, &name).unwrap_or_else(|_| config.cpus.to_string());
-            let memory = get_vm_memory(config, &name).unwrap_or_else(|_| config.mem.clone());
-            let disk = get_vm_disk_size(config, &name).unwrap_or_else(|_| config.disk_size.clone());
-            let devices = get_vm_devices(config, &name);
-
-            // Get creation time from directory metadata
-            let created = match fs::metadata(&path) {
-                Ok(metadata) => {
-                    if let Ok(created_time) = metadata.created() {
-                        if let Ok(since_epoch) = created_time.duration_since(std::time::UNIX_EPOCH)
-                        {
-                            crate::util::format_timestamp(since_epoch.as_secs())
-                        } else {
-                            "unknown".to_string()
-                        }
+    let resources = stored_resources(config, dest);
+    let identity = assign_identity(config, dest, json).await?;
+    write_launch_spec(config, dest, &resources, &identity, json).await
+}
+
+/// The resources recorded in VM `name`'s dir.
+fn stored_resources(config: &Config, name: &str) -> VmResources {
+    let vm_dir = config.vm_dir(name);
+    VmResources {
+        memory: get_vm_memory(config, name).unwrap_or_else(|_| config.mem.clone()),
+        cpus: get_vm_cpus(config, name)
+            .ok()
+            .and_then(|c| c.parse().ok())
+            .unwrap_or(config.cpus as u8),
+        disk_size: get_vm_disk_size(config, name).unwrap_or_else(|_| config.disk_size.clone()),
+        devices: get_vm_devices(config, name),
+        storage: None,
+        isolate: crate::network::is_isolated(&vm_dir),
+        egress: crate::egress::EgressPolicy::load(&vm_dir),
+        egress_interface: crate::uplink::load(&vm_dir),
+        immutable_root: crate::immutable::is_immutable(&vm_dir),
+        data_disk: None,
+        network: crate::bridge::NetworkMode::load(&vm_dir),
+        memory_backing: MemoryBacking::load(&vm_dir),
+        rng: crate::rng::RngSource::load(&vm_dir),
+        shares: crate::virtiofs::load(&vm_dir),
+        env: Default::default(),
+        secrets: Vec::new(),
+        vsock: crate::agent::is_enabled(&vm_dir),
+        boot: crate::boot::DirectBoot::load(&vm_dir),
+        cpu_affinity: crate::cpu_affinity::CpuSet::load(&vm_dir),
+        headroom: crate::hotplug::Headroom::load(&vm_dir).unwrap_or_default(),
+        balloon: crate::memory_backing::has_balloon(&vm_dir),
+        no_iso: crate::nocloud::is_enabled(&vm_dir),
+    }
+}
+
+/// Make the dir of VM `name`, moved in from another host (see
+/// `migrate`), runnable here: it gets a tap, netns and launch spec of
+/// this host's, and keeps its subnet, MAC and cloud-init identity, so
+/// the guest comes up with the address and host keys it had.
+pub async fn adopt(config: &Config, name: &str, json: bool) -> Result<()> {
+    let vm_dir = config.vm_dir(name);
+    let user_net = crate::usernet::is_enabled(&vm_dir);
+    let subnet = fs::read_to_string(vm_dir.join("subnet"))
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default();
+    let tap_name = if user_net {
+        crate::usernet::rehome(&vm_dir)?;
+        String::new()
+    } else {
+        let allocation = crate::network::lock_allocation(config)?;
+        if !subnet.is_empty() {
+            crate::subnets::lease(config, name, &subnet)?;
+        }
+        let tap_name = crate::network::generate_unique_tap_name(config, name).await?;
+        write_string_to_file(&vm_dir.join("tapdev"), &tap_name)?;
+        drop(allocation);
+        tap_name
+    };
+    let mac = fs::read_to_string(vm_dir.join("mac"))
+        .map(|s| s.trim().to_string())
+        .map_err(|_| Error::Other(format!("VM {} has no MAC address recorded", name)))?;
+    let identity = VmIdentity {
+        subnet,
+        tap_name,
+        mac,
+    };
+    write_launch_spec(
+        config,
+        name,
+        &stored_resources(config, name),
+        &identity,
+        json,
+    )
+    .await
+}
+
+/// Names of the dirs under `vm_root` that hold VMs, sorted. Dot-dirs
+/// (`meda migrate-receive` staging, the lock dir) never do; `meda run`'s hidden
+/// templates only count with `templates`.
+fn vm_dir_names(config: &Config, templates: bool) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(&config.vm_root)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|e| e.path().is_dir())
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter(|n| !n.starts_with('.'))
+        .filter(|n| templates || !is_template(n))
+        .collect();
+    names.sort();
+    names
+}
+
+/// Whether `name` is one of `meda run`'s hidden templates.
+pub fn is_template(name: &str) -> bool {
+    name.starts_with(crate::image::TEMPLATE_PREFIX)
+}
+
+/// Names of the VMs users manage, sorted.
+pub fn vm_names(config: &Config) -> Vec<String> {
+    vm_dir_names(config, false)
+}
+
+/// All VMs users manage, by name.
+pub fn collect_vms(config: &Config) -> Result<Vec<VmInfo>> {
+    collect(config, false)
+}
+
+/// [`collect_vms`] plus `meda run`'s templates, for counting what the
+/// host has committed.
+pub fn collect_vms_with_templates(config: &Config) -> Result<Vec<VmInfo>> {
+    collect(config, true)
+}
+
+fn collect(config: &Config, templates: bool) -> Result<Vec<VmInfo>> {
+    config.ensure_dirs()?;
+
+    let mut vms = Vec::new();
+    for name in vm_dir_names(config, templates) {
+        let path = config.vm_dir(&name);
+        let state = observe_state(config, &name)?.state;
+        let running = state == VmState::Running;
+
+        // For a running VM, prefer the host-reachable address
+        // (netns veth IP, legacy smoltcp forward, …); fall back
+        // to the baked-in guest IP only as a last resort. For a
+        // stopped VM nothing is reachable, so show a dash —
+        // printing an IP that doesn't actually answer was the
+        // confusing bit users hit (`ssh 192.168.X.2` → No
+        // route to host).
+        let ip = if running {
+            read_display_ip(&path)
+                .or_else(|| get_vm_ip(config, &name).ok())
+                .unwrap_or_else(|| "-".to_string())
+        } else {
+            "-".to_string()
+        };
+        let vcpus = get_vm_cpus(config, &name).unwrap_or_else(|_| config.cpus.to_string());
+        let memory = get_vm_memory(config, &name).unwrap_or_else(|_| config.mem.clone());
+        let disk = get_vm_disk_size(config, &name).unwrap_or_else(|_| config.disk_size.clone());
+        let devices = get_vm_devices(config, &name);
+
+        // Get creation time from directory metadata
+        let created = match fs::metadata(&path) {
+            Ok(metadata) => {
+                if let Ok(created_time) = metadata.created() {
+                    if let Ok(since_epoch) = created_time.duration_since(std::time::UNIX_EPOCH) {
+                        crate::util::format_timestamp(since_epoch.as_secs())
                     } else {
                         "unknown".to_string()
                     }
+                } else {
+                    "unknown".to_string()
                 }
-                Err(_) => "unknown".to_string(),
-            };
+            }
+            Err(_) => "unknown".to_string(),
+        };
+
+        let metadata = labels::VmMetadata::load(&path).unwrap_or_default();
+        vms.push(VmInfo {
+            labels: metadata.labels,
+            env: metadata.env,
+            storage: crate::storage::pool_of(config, &name),
+            name,
+            state: state.to_string(),
+            ip,
+            vcpus,
+            memory,
+            disk,
+            devices,
+            created,
+        });
+    }
+
+    Ok(vms)
+}
+
+/// Keep the VMs matching every filter.
+pub fn filter_vms(mut vms: Vec<VmInfo>, filters: &[VmFilter]) -> Vec<VmInfo> {
+    vms.retain(|vm| labels::matches_all(filters, &vm.state, &vm.labels));
+    vms
+}
 
-            vms.push(VmInfo {
-                name,
-                state,
-                ip,
-                vcpus,
-                memory,
-                disk,
-                devices,
-                created,
-            });
-        }
-    }
+pub async fn list(config: &Config, filters: &[VmFilter], json: bool) -> Result<()> {
+    print_list(&filter_vms(collect_vms(config)?, filters), json)
+}
 
+/// `meda list`'s output for `vms`.
+pub fn print_list(vms: &[VmInfo], json: bool) -> Result<()> {
     if json {
         println!("{}", serde_json::to_string_pretty(&vms)?);
     } else if vms.is_empty() {
@@ -553,7 +1286,7 @@ pub async fn list(config: &Config, json: bool) -> Result<()> {
 
         // Print header
         println!(
-            "{:<width$} {:<10} {:<15} {:<7} {:<10} {:<10} {:<10} {:<20}",
+            "{:<width$} {:<10} {:<15} {:<7} {:<10} {:<10} {:<10} {:<10} {:<20}",
             "name",
             "state",
             "ip",
@@ -561,12 +1294,13 @@ pub async fn list(config: &Config, json: bool) -> Result<()> {
             "memory",
             "disk",
             "devices",
+            "storage",
             "created",
             width = max_name_width
         );
 
         // Calculate total width for separator line
-        let total_width = max_name_width + 10 + 15 + 7 + 10 + 10 + 10 + 20 + 7; // +7 for spaces between columns
+        let total_width = max_name_width + 10 + 15 + 7 + 10 + 10 + 10 + 10 + 20 + 8; // +8 for spaces between columns
         println!("{}", "-".repeat(total_width));
 
         // Print VM rows
@@ -577,7 +1311,7 @@ pub async fn list(config: &Config, json: bool) -> Result<()> {
                 format!("{}", vm.devices.len())
             };
             println!(
-                "{:<width$} {:<10} {:<15} {:<7} {:<10} {:<10} {:<10} {:<20}",
+                "{:<width$} {:<10} {:<15} {:<7} {:<10} {:<10} {:<10} {:<10} {:<20}",
                 vm.name,
                 vm.state,
                 vm.ip,
@@ -585,6 +1319,7 @@ pub async fn list(config: &Config, json: bool) -> Result<()> {
                 vm.memory,
                 vm.disk,
                 devices_display,
+                vm.storage,
                 vm.created,
                 width = max_name_width
             );
@@ -594,18 +1329,15 @@ pub async fn list(config: &Config, json: bool) -> Result<()> {
     Ok(())
 }
 
-pub async fn get(config: &Config, name: &str, json: bool) -> Result<()> {
+/// Everything `meda get` reports about one VM.
+pub fn vm_details(config: &Config, name: &str) -> Result<VmDetailedInfo> {
     let vm_dir = config.vm_dir(name);
 
     if !vm_dir.exists() {
         return Err(Error::VmNotFound(name.to_string()));
     }
 
-    let state = if check_vm_running(config, name)? {
-        "running".to_string()
-    } else {
-        "stopped".to_string()
-    };
+    let observed = observe_state(config, name)?;
 
     // Same priority as `meda list` / `meda ip`: netns IP first, then
     // legacy fallbacks, then the (host-unreachable) baked guest IP.
@@ -613,8 +1345,69 @@ pub async fn get(config: &Config, name: &str, json: bool) -> Result<()> {
 
     // Collect additional details
     let mut details = serde_json::Map::new();
+    details.insert(
+        "state_since".to_string(),
+        serde_json::Value::String(crate::util::format_timestamp(observed.since)),
+    );
+    if let Some(error) = observed.error {
+        details.insert("error".to_string(), serde_json::Value::String(error));
+    }
 
     // Add network info
+    if let Some(bridge) = crate::bridge::bridge_of(&vm_dir) {
+        details.insert("bridge".to_string(), serde_json::Value::String(bridge));
+    }
+    let rng = crate::rng::RngSource::load(&vm_dir);
+    if rng != crate::rng::RngSource::default() {
+        details.insert(
+            "rng".to_string(),
+            serde_json::Value::String(rng.to_string()),
+        );
+    }
+    if observed.state == VmState::Running {
+        if let Some((boot, ips)) = crate::agent::details(&vm_dir) {
+            details.insert("agent".to_string(), serde_json::Value::String(boot));
+            if !ips.is_empty() {
+                details.insert(
+                    "guest_ips".to_string(),
+                    serde_json::Value::String(ips.join(", ")),
+                );
+            }
+        }
+    }
+    let shares = crate::virtiofs::load(&vm_dir);
+    if !shares.is_empty() {
+        let mounts: Vec<String> = shares
+            .iter()
+            .map(|s| format!("{}:{}", s.host.display(), s.guest))
+            .collect();
+        details.insert(
+            "mounts".to_string(),
+            serde_json::Value::String(mounts.join(", ")),
+        );
+    }
+    if let Some(boot) = crate::boot::DirectBoot::load(&vm_dir) {
+        details.insert("boot".to_string(), serde_json::to_value(boot)?);
+    }
+    if let Some(cpus) = crate::cpu_affinity::CpuSet::load(&vm_dir) {
+        details.insert(
+            "cpu_affinity".to_string(),
+            serde_json::Value::String(cpus.to_string()),
+        );
+    }
+    if let Some(headroom) = crate::hotplug::Headroom::load(&vm_dir).filter(|h| !h.is_empty()) {
+        details.insert("hotplug".to_string(), serde_json::to_value(headroom)?);
+    }
+    if crate::memory_backing::has_balloon(&vm_dir) {
+        details.insert("balloon".to_string(), serde_json::Value::Bool(true));
+    }
+    let memory_backing = MemoryBacking::load(&vm_dir);
+    if !memory_backing.is_default() {
+        details.insert(
+            "memory_backing".to_string(),
+            serde_json::to_value(memory_backing.options())?,
+        );
+    }
     if let Ok(subnet) = fs::read_to_string(vm_dir.join("subnet")) {
         details.insert(
             "subnet".to_string(),
@@ -664,6 +1457,25 @@ pub async fn get(config: &Config, name: &str, json: bool) -> Result<()> {
         );
     }
 
+    let metadata = labels::VmMetadata::load(&vm_dir).unwrap_or_default();
+    if !metadata.labels.is_empty() {
+        details.insert("labels".to_string(), serde_json::to_value(metadata.labels)?);
+    }
+    if !metadata.env.is_empty() {
+        details.insert("env".to_string(), serde_json::to_value(metadata.env)?);
+    }
+    if let Some(expires_at) = metadata.expires_at {
+        details.insert(
+            "expires_at".to_string(),
+            serde_json::Value::String(expires_at.to_rfc3339()),
+        );
+    }
+
+    details.insert(
+        "storage".to_string(),
+        serde_json::Value::String(crate::storage::pool_of(config, name)),
+    );
+
     // Add VM directory path
     details.insert(
         "vm_dir".to_string(),
@@ -674,14 +1486,18 @@ pub async fn get(config: &Config, name: &str, json: bool) -> Result<()> {
     let memory = get_vm_memory(config, name).unwrap_or_else(|_| config.mem.clone());
     let disk_size = get_vm_disk_size(config, name).unwrap_or_else(|_| config.disk_size.clone());
 
-    let vm_info = VmDetailedInfo {
+    Ok(VmDetailedInfo {
         name: name.to_string(),
-        state,
+        state: observed.state.to_string(),
         ip,
         memory: Some(memory),
         disk: Some(disk_size),
         details: Some(serde_json::Value::Object(details)),
-    };
+    })
+}
+
+pub async fn get(config: &Config, name: &str, json: bool) -> Result<()> {
+    let vm_info = vm_details(config, name)?;
 
     if json {
         println!("{}", serde_json::to_string_pretty(&vm_info)?);
@@ -701,42 +1517,67 @@ pub async fn get(config: &Config, name: &str, json: bool) -> Result<()> {
     Ok(())
 }
 
-pub async fn start(config: &Config, name: &str, json: bool) -> Result<()> {
-    let vm_dir = config.vm_dir(name);
-
-    if !vm_dir.exists() {
-        return Err(Error::VmNotFound(name.to_string()));
-    }
+/// Launch a stopped VM's CH and wait for it to come up.
+/// Start the VM's cloud-hypervisor and wait for it, recording the VM as
+/// starting and then running (or as error, with why).
+async fn launch(config: &Config, name: &str, json: bool) -> Result<()> {
+    let memory = get_vm_memory(config, name)?;
+    let quota = crate::quota::before_start(config, name, &memory)?;
+    crate::state::transition(
+        &config.vm_dir(name),
+        VmState::Starting,
+        VmState::Running,
+        async {
+            // Recorded as starting now, so other starts count it.
+            drop(quota);
+            spawn_and_wait(config, name, json, &memory).await
+        },
+    )
+    .await
+}
 
-    if check_vm_running(config, name)? {
-        let message = format!("VM {} is already running", name);
-        if json {
-            let result = VmResult {
-                success: false,
-                message,
-            };
-            println!("{}", serde_json::to_string_pretty(&result)?);
-        } else {
-            return Err(Error::VmAlreadyRunning(name.to_string()));
-        }
-        return Ok(());
-    }
+async fn spawn_and_wait(config: &Config, name: &str, json: bool, memory: &str) -> Result<()> {
+    let vm_dir = config.vm_dir(name);
 
     if !json {
         info!("Starting VM: {}", name);
     }
 
-    let start_script = vm_dir.join("start.sh");
-    if !start_script.exists() {
+    let spec = LaunchSpec::load(&vm_dir);
+    let start_script = vm_dir.join(crate::launch::LEGACY_START_SCRIPT);
+    if spec.is_none() && !start_script.exists() {
         return Err(Error::Other(format!(
-            "Start script not found for VM: {}",
+            "Launch spec not found for VM: {}",
             name
         )));
     }
 
-    // Run the start script
+    // Refuse VMs written by a newer meda rather than misread them.
+    crate::schema::VmManifest::load(&vm_dir)?;
+
+    crate::preflight::before_start(config, &vm_dir, memory)?;
+    crate::memory_backing::preflight(&vm_dir, memory)?;
+    if crate::immutable::reset_root(&vm_dir)? && !json {
+        info!("Reset immutable root disk of {}", name);
+    }
+
+    if crate::usernet::is_enabled(&vm_dir) {
+        crate::usernet::start(&vm_dir).await?;
+    }
     info!("🚀 Starting VM {} with cloud-hypervisor", name);
-    run_command("bash", &[start_script.to_str().unwrap()])?;
+    match &spec {
+        Some(spec) => {
+            crate::launch::spawn(&vm_dir, spec).await?;
+            crate::hotplug::Headroom::record_boot(&vm_dir, spec.max_cpus.unwrap_or(spec.cpus))?;
+        }
+        None => crate::util::run_command("bash", &[start_script.to_str().unwrap()]).await?,
+    }
+    if crate::console::uses_relay(&vm_dir) {
+        crate::console::start_relay(&vm_dir)?;
+    }
+    crate::nocloud::start(config, name)?;
+
+    let boot = Progress::step("boot", name);
 
     // Give a moment for initial log entries
     thread::sleep(Duration::from_millis(500));
@@ -823,7 +1664,299 @@ pub async fn start(config: &Config, name: &str, json: bool) -> Result<()> {
         )));
     }
 
-    let message = format!("Successfully started VM: {}", name);
+    boot.finish(None);
+    Ok(())
+}
+
+pub async fn start(config: &Config, name: &str, json: bool) -> Result<()> {
+    let _lock = lock(config, name, "start")?;
+    let vm_dir = config.vm_dir(name);
+
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+
+    if check_vm_running(config, name)? {
+        let message = format!("VM {} is already running", name);
+        if json {
+            let result = VmResult {
+                success: false,
+                message,
+            };
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        } else {
+            return Err(Error::VmAlreadyRunning(name.to_string()));
+        }
+        return Ok(());
+    }
+
+    launch(config, name, json).await?;
+
+    let message = format!("Successfully started VM: {}", name);
+    if json {
+        let result = VmResult {
+            success: true,
+            message,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!("{}", message);
+    }
+
+    Ok(())
+}
+
+/// How `stop` brings a VM down.
+#[derive(Debug, Clone, Copy)]
+pub struct StopOptions {
+    /// How long the guest gets to power off after the ACPI power button
+    /// before CH is killed.
+    pub timeout: Duration,
+    /// Skip the power button and kill CH straight away.
+    pub force: bool,
+}
+
+impl StopOptions {
+    pub fn graceful(config: &Config) -> Self {
+        Self {
+            timeout: config.stop_timeout,
+            force: false,
+        }
+    }
+
+    pub fn force() -> Self {
+        Self {
+            timeout: Duration::ZERO,
+            force: true,
+        }
+    }
+
+    /// From `--timeout <secs>` and `--force` (or the API's query).
+    pub fn from_flags(config: &Config, timeout: Option<u64>, force: bool) -> Self {
+        let mut options = if force {
+            Self::force()
+        } else {
+            Self::graceful(config)
+        };
+        if let Some(secs) = timeout {
+            options.timeout = Duration::from_secs(secs);
+        }
+        options
+    }
+}
+
+/// Stop a VM, giving the guest `MEDA_STOP_TIMEOUT` to shut down cleanly.
+pub async fn stop(config: &Config, name: &str, json: bool) -> Result<()> {
+    stop_with(config, name, StopOptions::graceful(config), json).await
+}
+
+/// Press the guest's ACPI power button and wait for CH to exit. Returns
+/// false if the button couldn't be pressed or the guest didn't power off
+/// within `timeout`.
+async fn acpi_shutdown(
+    config: &Config,
+    vm_dir: &std::path::Path,
+    pid: u32,
+    timeout: Duration,
+) -> bool {
+    let sock = vm_dir.join("api.sock");
+    if !sock.exists() {
+        return false;
+    }
+    let pressed = crate::util::run_command(
+        &config.cr_bin.to_string_lossy(),
+        &["--api-socket", sock.to_str().unwrap(), "power-button"],
+    )
+    .await;
+    if let Err(e) = pressed {
+        warn!("ACPI power button failed: {}", e);
+        return false;
+    }
+    let started = Instant::now();
+    while check_process_running(pid) {
+        if started.elapsed() >= timeout {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    true
+}
+
+/// Bring a running VM down as `options` says and clean up what CH
+/// leaves behind.
+async fn power_off(config: &Config, name: &str, options: StopOptions, json: bool) {
+    let vm_dir = config.vm_dir(name);
+
+    if !json {
+        info!("Stopping VM: {}", name);
+    }
+    if let Err(e) = crate::state::set(&vm_dir, VmState::Stopping) {
+        warn!("Failed to record state of {}: {}", name, e);
+    }
+
+    let pid_file = vm_dir.join("pid");
+    if let Ok(pid_str) = fs::read_to_string(&pid_file) {
+        if let Ok(pid) = pid_str.trim().parse::<u32>() {
+            let clean =
+                !options.force && acpi_shutdown(config, &vm_dir, pid, options.timeout).await;
+            if !options.force && !clean && !json {
+                warn!(
+                    "VM {} did not power off within {}s, killing it",
+                    name,
+                    options.timeout.as_secs()
+                );
+            }
+
+            // VMs started under the netns path run as root (via
+            // `sudo ip netns exec`), so a plain `kill` from our
+            // unprivileged user won't work. Use `sudo kill` — it's
+            // NOPASSWD on this box. Keep non-sudo kill as a
+            // fallback for legacy non-netns VMs.
+            let term = |sig: &str, pid: u32| {
+                let _ = Command::new("sudo")
+                    .args(["kill", sig, &pid.to_string()])
+                    .output();
+                let _ = Command::new("kill").args([sig, &pid.to_string()]).output();
+            };
+            if !clean {
+                term("-TERM", pid);
+            }
+
+            for _ in 0..10 {
+                if !check_process_running(pid) {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(500));
+            }
+
+            if check_process_running(pid) {
+                term("-KILL", pid);
+                // `restart` launches straight after; the tap must be
+                // free by then.
+                for _ in 0..20 {
+                    if !check_process_running(pid) {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+        }
+    }
+
+    // Clean up PID file and the sockets CH leaves behind, which would
+    // otherwise make the next start fail to bind.
+    fs::remove_file(&pid_file).ok();
+    fs::remove_file(vm_dir.join(crate::console::SERIAL_SOCKET)).ok();
+    fs::remove_file(vm_dir.join(crate::console::CH_SERIAL_SOCKET)).ok();
+    fs::remove_file(vm_dir.join("api.sock")).ok();
+    fs::remove_file(vm_dir.join(crate::agent::VSOCK_SOCKET)).ok();
+    crate::dhcp::stop(&vm_dir);
+    crate::virtiofs::stop(&vm_dir);
+    crate::usernet::stop(&vm_dir);
+    crate::nocloud::stop(&vm_dir);
+    if let Err(e) = crate::state::set(&vm_dir, VmState::Stopped) {
+        warn!("Failed to record state of {}: {}", name, e);
+    }
+}
+
+pub async fn stop_with(
+    config: &Config,
+    name: &str,
+    options: StopOptions,
+    json: bool,
+) -> Result<()> {
+    let _lock = lock(config, name, "stop")?;
+    let vm_dir = config.vm_dir(name);
+
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+
+    if !check_vm_running(config, name)? {
+        let message = format!("VM {} is not running", name);
+        if json {
+            let result = VmResult {
+                success: false,
+                message,
+            };
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        } else {
+            return Err(Error::VmNotRunning(name.to_string()));
+        }
+        return Ok(());
+    }
+
+    power_off(config, name, options, json).await;
+
+    let message = format!("Successfully stopped VM: {}", name);
+    if json {
+        let result = VmResult {
+            success: true,
+            message,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!("{}", message);
+    }
+
+    Ok(())
+}
+
+/// Stop a VM if it is running and start it again. The VM keeps its tap,
+/// subnet and MAC, so it comes back on the same IP.
+pub async fn restart(config: &Config, name: &str, options: StopOptions, json: bool) -> Result<()> {
+    let _lock = lock(config, name, "restart")?;
+    let vm_dir = config.vm_dir(name);
+
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+
+    if check_vm_running(config, name)? {
+        power_off(config, name, options, json).await;
+    }
+    launch(config, name, json).await?;
+
+    let message = format!("Successfully restarted VM: {}", name);
+    if json {
+        let result = VmResult {
+            success: true,
+            message,
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!("{}", message);
+    }
+
+    Ok(())
+}
+
+pub async fn delete(config: &Config, name: &str, json: bool) -> Result<()> {
+    let _lock = lock(config, name, "delete")?;
+    let vm_dir = config.vm_dir(name);
+
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+
+    let record = crate::inventory::record(config, name, crate::inventory::Event::Deleted);
+
+    // Stop VM if running
+    if check_vm_running(config, name)? {
+        if !json {
+            info!("Stopping VM before deletion");
+        }
+        power_off(config, name, StopOptions::force(), json).await;
+    }
+
+    if !json {
+        info!("Deleting VM: {}", name);
+    }
+
+    teardown(config, name).await?;
+    crate::inventory::export(config, record).await;
+
+    let message = format!("Successfully deleted VM: {}", name);
     if json {
         let result = VmResult {
             success: true,
@@ -837,109 +1970,232 @@ pub async fn start(config: &Config, name: &str, json: bool) -> Result<()> {
     Ok(())
 }
 
-pub async fn stop(config: &Config, name: &str, json: bool) -> Result<()> {
+/// Remove what a stopped VM has on the host: its helper daemons,
+/// network namespace, tap and iptables rules, and its dir.
+async fn teardown(config: &Config, name: &str) -> Result<()> {
     let vm_dir = config.vm_dir(name);
 
-    if !vm_dir.exists() {
-        return Err(Error::VmNotFound(name.to_string()));
+    // Tear down per-VM netns + veth first, then the legacy
+    // host-scoped iptables/tap cleanup in case the VM was created
+    // before netns support shipped.
+    crate::dhcp::stop(&vm_dir);
+    crate::virtiofs::stop(&vm_dir);
+    crate::usernet::stop(&vm_dir);
+    crate::nocloud::stop(&vm_dir);
+    if crate::bridge::NetworkMode::load(&vm_dir) == crate::bridge::NetworkMode::Nat {
+        let netns_spec = NetnsSpec::load_or_compute(&vm_dir, name);
+        if let Err(e) = crate::netns::destroy(&netns_spec).await {
+            log::warn!("netns destroy failed for {}: {}", name, e);
+        }
     }
+    cleanup_networking(config, name).await?;
 
-    if !check_vm_running(config, name)? {
-        let message = format!("VM {} is not running", name);
-        if json {
-            let result = VmResult {
-                success: false,
-                message,
-            };
-            println!("{}", serde_json::to_string_pretty(&result)?);
-        } else {
-            return Err(Error::VmNotRunning(name.to_string()));
-        }
-        return Ok(());
+    // Remove VM directory (and its pool dir when it lives elsewhere)
+    crate::storage::remove_vm_dir(&vm_dir)?;
+    Ok(())
+}
+
+/// Undo a create or clone of `name` that failed partway, so the name can
+/// be used again straight away. A VM whose rollback fails too is left in
+/// the `error` state for `meda delete`.
+async fn roll_back(config: &Config, name: &str) {
+    warn!("Rolling back partially created VM {}", name);
+    if let Err(e) = teardown(config, name).await {
+        warn!("Failed to roll back partially created VM {}: {}", name, e);
     }
+}
 
+/// Delete VM `name` if there is one, running or not: what `create
+/// --force` does before creating.
+pub async fn delete_existing(config: &Config, name: &str, json: bool) -> Result<()> {
+    if !config.vm_dir(name).exists() {
+        return Ok(());
+    }
     if !json {
-        info!("Stopping VM: {}", name);
+        info!("Deleting existing VM: {}", name);
     }
+    delete(config, name, json).await
+}
 
-    let pid_file = vm_dir.join("pid");
-    if let Ok(pid_str) = fs::read_to_string(&pid_file) {
-        if let Ok(pid) = pid_str.trim().parse::<u32>() {
-            // VMs started under the netns path run as root (via
-            // `sudo ip netns exec`), so a plain `kill` from our
-            // unprivileged user won't work. Use `sudo kill` — it's
-            // NOPASSWD on this box. Keep non-sudo kill as a
-            // fallback for legacy non-netns VMs.
-            let term = |sig: &str, pid: u32| {
-                let _ = Command::new("sudo")
-                    .args(["kill", sig, &pid.to_string()])
-                    .output();
-                let _ = Command::new("kill").args([sig, &pid.to_string()]).output();
-            };
-            term("-TERM", pid);
-
-            for _ in 0..10 {
-                if !check_process_running(pid) {
-                    break;
-                }
-                thread::sleep(Duration::from_millis(500));
-            }
-
-            if check_process_running(pid) {
-                term("-KILL", pid);
-            }
-        }
+/// Replace the value of `--cpus boot=` / `--memory size=` in a legacy
+/// start script, leaving everything else (netns wrapper, disks, devices) as
+/// the create path generated it.
+fn rewrite_resource_flags(script: &str, cpus: Option<u8>, memory: Option<&str>) -> String {
+    fn replace_value(script: &str, flag: &str, value: &str) -> String {
+        let Some(start) = script.find(flag) else {
+            return script.to_string();
+        };
+        let value_start = start + flag.len();
+        let value_end = script[value_start..]
+            .find(|c: char| c.is_whitespace() || c == '\\' || c == ',')
+            .map(|i| value_start + i)
+            .unwrap_or(script.len());
+        format!(
+            "{}{}{}",
+            &script[..value_start],
+            value,
+            &script[value_end..]
+        )
     }
 
-    // Clean up PID file
-    fs::remove_file(&pid_file).ok();
+    let mut out = script.to_string();
+    if let Some(cpus) = cpus {
+        out = replace_value(&out, "--cpus boot=", &cpus.to_string());
+    }
+    if let Some(memory) = memory {
+        out = replace_value(&out, "--memory size=", memory);
+    }
+    out
+}
 
-    let message = format!("Successfully stopped VM: {}", name);
-    if json {
-        let result = VmResult {
-            success: true,
-            message,
-        };
-        println!("{}", serde_json::to_string_pretty(&result)?);
+/// Record a VM's new vCPU count and/or memory in its resource files and
+/// launch spec (or legacy start script), for `get`, `list` and the next
+/// start.
+pub(crate) fn record_resources(
+    vm_dir: &Path,
+    cpus: Option<u8>,
+    memory: Option<&str>,
+) -> Result<()> {
+    if let Some(m) = memory {
+        write_string_to_file(&vm_dir.join("memory"), m)?;
+    }
+    if let Some(c) = cpus {
+        write_string_to_file(&vm_dir.join("cpus"), &c.to_string())?;
+    }
+    if let Some(mut spec) = LaunchSpec::load(vm_dir) {
+        spec.cpus = cpus.unwrap_or(spec.cpus);
+        spec.memory = memory.map_or(spec.memory, String::from);
+        spec.save(vm_dir)?;
     } else {
-        info!("{}", message);
+        let start_script = vm_dir.join(crate::launch::LEGACY_START_SCRIPT);
+        if start_script.exists() {
+            let body = fs::read_to_string(&start_script)?;
+            fs::write(&start_script, rewrite_resource_flags(&body, cpus, memory))?;
+        }
     }
-
     Ok(())
 }
 
-pub async fn delete(config: &Config, name: &str, json: bool) -> Result<()> {
+/// Change a VM's memory, vCPU count and/or disk size after creation.
+///
+/// The per-VM resource files and launch spec are always updated, so the
+/// new values take effect on the next start; they must fit the VM's
+/// hotplug headroom, if it has one, or it couldn't boot. For a running
+/// VM memory and vCPUs are also pushed live like `meda hotplug` does
+/// when they pass its checks; otherwise, or when Cloud Hypervisor
+/// refuses, the change is left pending until a restart. Disks can only
+/// grow, and only while the VM is stopped.
+pub async fn resize(
+    config: &Config,
+    name: &str,
+    memory: Option<&str>,
+    cpus: Option<u8>,
+    disk: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let _lock = lock(config, name, "resize")?;
     let vm_dir = config.vm_dir(name);
-
     if !vm_dir.exists() {
         return Err(Error::VmNotFound(name.to_string()));
     }
+    if memory.is_none() && cpus.is_none() && disk.is_none() {
+        return Err(Error::Other(
+            "Nothing to resize: pass --memory, --cpus and/or --disk".to_string(),
+        ));
+    }
+    if let Some(m) = memory {
+        if parse_size_bytes(m).is_none() {
+            return Err(Error::Other(format!("Invalid memory size '{}'", m)));
+        }
+    }
+    if cpus == Some(0) {
+        return Err(Error::Other("--cpus must be at least 1".to_string()));
+    }
 
-    // Stop VM if running
-    if check_vm_running(config, name)? {
+    let running = check_vm_running(config, name)?;
+    let _quota = crate::quota::before_grow(config, name, memory, cpus, disk)?;
+    let mut notes = Vec::new();
+
+    if let Some(disk) = disk {
+        if running {
+            return Err(Error::Other(format!(
+                "Stop VM {} before resizing its disk",
+                name
+            )));
+        }
+        let new_bytes = parse_size_bytes(disk)
+            .ok_or_else(|| Error::Other(format!("Invalid disk size '{}'", disk)))?;
+        let (rootfs, format) = DiskFormat::detect(&vm_dir)
+            .ok_or_else(|| Error::Other(format!("VM {} rootfs not found", name)))?;
+        if let Some(current) = disk_virtual_size(&rootfs) {
+            if new_bytes < current {
+                return Err(Error::Other(format!(
+                    "Shrinking disks is not supported ({} is smaller than the current {} bytes)",
+                    disk, current
+                )));
+            }
+        }
         if !json {
-            info!("Stopping VM before deletion");
+            info!("Growing {} to {}", rootfs.display(), disk);
+        }
+        match format {
+            DiskFormat::Qcow2 => {
+                run_command(
+                    "qemu-img",
+                    &["resize", "-f", "qcow2", rootfs.to_str().unwrap(), disk],
+                )
+                .await?
+            }
+            DiskFormat::Raw => crate::util::resize_raw_disk(&rootfs, disk).await?,
         }
-        stop(config, name, json).await?;
+        write_string_to_file(&vm_dir.join("disk_size"), disk)?;
+        // cloud-init's growpart runs on every boot and extends the root
+        // partition into the new space.
+        notes.push(format!("disk grown to {}", disk));
     }
 
-    if !json {
-        info!("Deleting VM: {}", name);
+    if memory.is_some() || cpus.is_some() {
+        let boot_cpus = match cpus {
+            Some(c) => c,
+            None => get_vm_cpus(config, name)?.parse().unwrap_or(1),
+        };
+        let boot_memory = match memory {
+            Some(m) => m.to_string(),
+            None => get_vm_memory(config, name)?,
+        };
+        crate::hotplug::Headroom::load(&vm_dir)
+            .unwrap_or_default()
+            .check(boot_cpus, &boot_memory)?;
+        // Checked against what the VM runs with now, before it's recorded.
+        let live = running.then(|| crate::hotplug::check_live(config, name, cpus, memory));
+        record_resources(&vm_dir, cpus, memory)?;
+
+        let what = crate::hotplug::describe(cpus, memory);
+        if let Some(live) = live {
+            let resized = match live {
+                Ok(()) => crate::hotplug::live_resize(config, &vm_dir, cpus, memory).await,
+                Err(e) => Err(e),
+            };
+            match resized {
+                Ok(()) => notes.push(format!("{} applied live", what)),
+                Err(e) => {
+                    warn!("live resize of {} failed: {}", name, e);
+                    notes.push(format!("{} takes effect after restart ({})", what, e));
+                }
+            }
+        } else {
+            notes.push(format!("{} takes effect on next start", what));
+        }
     }
 
-    // Tear down per-VM netns + veth first, then the legacy
-    // host-scoped iptables/tap cleanup in case the VM was created
-    // before netns support shipped.
-    let netns_spec = NetnsSpec::load_or_compute(&vm_dir, name);
-    if let Err(e) = crate::netns::destroy(&netns_spec) {
-        log::warn!("netns destroy failed for {}: {}", name, e);
+    if vm_dir.join(crate::snapshot::SNAPSHOT_DIR).exists() {
+        warn!(
+            "VM {} has a snapshot taken with the old resources; re-run `meda snapshot {}`",
+            name, name
+        );
     }
-    cleanup_networking(config, name).await?;
-
-    // Remove VM directory
-    fs::remove_dir_all(&vm_dir)?;
 
-    let message = format!("Successfully deleted VM: {}", name);
+    let message = format!("Resized VM {}: {}", name, notes.join("; "));
     if json {
         let result = VmResult {
             success: true,
@@ -953,19 +2209,28 @@ pub async fn delete(config: &Config, name: &str, json: bool) -> Result<()> {
     Ok(())
 }
 
-pub async fn ip(config: &Config, name: &str, json: bool) -> Result<()> {
+/// The address `meda ip` reports for a VM.
+pub fn display_ip(config: &Config, name: &str) -> Result<String> {
     let vm_dir = config.vm_dir(name);
-
-    if !vm_dir.exists() {
-        return Err(Error::VmNotFound(name.to_string()));
-    }
-
     // Same priority order as `meda list`: prefer the host-routable
     // netns-side IP, fall back to the legacy paths, finally fall
     // back to the guest's baked-in IP. Returning the guest IP for a
     // netns-backed VM was misleading — that address is reachable
     // only from inside the VM's own netns.
     let ip = read_display_ip(&vm_dir).map_or_else(|| get_vm_ip(config, name), Ok)?;
+    // DHCP guests may not have taken their address yet; the netns IP
+    // would not answer until they do.
+    if crate::dhcp::is_enabled(&vm_dir) && crate::dhcp::lease_ip(&vm_dir).is_none() {
+        return Err(Error::Other(format!("VM {} has no DHCP lease yet", name)));
+    }
+    Ok(ip)
+}
+
+pub async fn ip(config: &Config, name: &str, json: bool) -> Result<()> {
+    if !config.vm_dir(name).exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+    let ip = display_ip(config, name)?;
 
     if json {
         let result = serde_json::json!({
@@ -980,6 +2245,112 @@ pub async fn ip(config: &Config, name: &str, json: bool) -> Result<()> {
     Ok(())
 }
 
+/// What `meda ip --check` found. Scripts poll on the exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpStatus {
+    /// The guest accepts connections on the probed port.
+    Ready,
+    /// The VM has an address, but nothing answers on it yet.
+    Unreachable,
+    /// No address yet: the VM is stopped, or has no lease or
+    /// neighbour entry.
+    Unknown,
+}
+
+impl IpStatus {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            IpStatus::Ready => 0,
+            IpStatus::Unreachable => 2,
+            IpStatus::Unknown => 3,
+        }
+    }
+}
+
+/// A TCP connect rather than ping: a netns VM's address is the veth in
+/// front of it, which answers pings whether or not the guest is up.
+pub async fn probe_port(ip: &str, port: u16) -> IpStatus {
+    let Ok(addr) = ip.parse::<std::net::IpAddr>() else {
+        return IpStatus::Unknown;
+    };
+    let connect = tokio::net::TcpStream::connect((addr, port));
+    match tokio::time::timeout(Duration::from_secs(2), connect).await {
+        Ok(Ok(_)) => IpStatus::Ready,
+        _ => IpStatus::Unreachable,
+    }
+}
+
+/// `meda ip --check`: print the address, if any, and whether the guest
+/// answers on `port`.
+pub async fn ip_check(config: &Config, name: &str, port: u16, json: bool) -> Result<IpStatus> {
+    if !config.vm_dir(name).exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+    let ip = if check_vm_running(config, name)? {
+        display_ip(config, name).ok()
+    } else {
+        None
+    };
+    let status = match &ip {
+        // A user-mode VM's address carries the host port of its SSH.
+        Some(ip) => match crate::ssh::split_port(ip) {
+            (host, ssh_port) if port == 22 => probe_port(&host, ssh_port).await,
+            (host, _) => probe_port(&host, port).await,
+        },
+        None => IpStatus::Unknown,
+    };
+
+    if json {
+        let result = serde_json::json!({
+            "vm": name,
+            "ip": ip,
+            "port": port,
+            "status": status,
+        });
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        if let Some(ip) = &ip {
+            println!("{}", ip);
+        }
+        match status {
+            IpStatus::Ready => {}
+            IpStatus::Unreachable => {
+                eprintln!(
+                    "{} has an address but port {} does not answer yet",
+                    name, port
+                )
+            }
+            IpStatus::Unknown => eprintln!("{} has no address yet", name),
+        }
+    }
+    Ok(status)
+}
+
+/// Last `lines` lines of the VM's cloud-hypervisor log (`ch.log`).
+pub fn log_tail(config: &Config, name: &str, lines: usize) -> Result<String> {
+    let vm_dir = config.vm_dir(name);
+    if !vm_dir.exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+    let contents = match fs::read_to_string(vm_dir.join("ch.log")) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let all: Vec<&str> = contents.lines().collect();
+    Ok(all[all.len().saturating_sub(lines)..].join("\n"))
+}
+
+/// VM `name`'s state as it is now: its record reconciled with its
+/// process and its lock (see `state::observe`).
+pub fn observe_state(config: &Config, name: &str) -> Result<crate::state::StateRecord> {
+    let running = check_vm_running(config, name)?;
+    // A lock that can be shared is one no operation holds.
+    let busy = matches!(FileLock::try_shared(&lock_path(config, name)), Ok(None));
+    Ok(crate::state::observe(&config.vm_dir(name), running, busy))
+}
+
 pub fn check_vm_running(config: &Config, name: &str) -> Result<bool> {
     let vm_dir = config.vm_dir(name);
     let pid_file = vm_dir.join("pid");
@@ -1006,6 +2377,10 @@ pub fn check_vm_running(config: &Config, name: &str) -> Result<bool> {
 ///   3. None — caller falls back to the guest IP baked in the VM's
 ///      disk via `get_vm_ip`.
 fn read_display_ip(vm_dir: &std::path::Path) -> Option<String> {
+    // User-mode VMs are only reachable through passt's SSH forward.
+    if let Some(port) = crate::usernet::ssh_port(vm_dir) {
+        return Some(format!("127.0.0.1:{}", port));
+    }
     // Per-VM netns layout (the current default): users reach the
     // guest at the veth's netns-side IP, not the guest's baked-in
     // IP. iptables inside the netns DNATs it to the guest. This is
@@ -1033,12 +2408,26 @@ fn read_display_ip(vm_dir: &std::path::Path) -> Option<String> {
 
 pub fn get_vm_ip(config: &Config, name: &str) -> Result<String> {
     let vm_dir = config.vm_dir(name);
+    if crate::usernet::is_enabled(&vm_dir) {
+        return Ok("127.0.0.1".to_string());
+    }
+    if let Some(bridge) = crate::bridge::bridge_of(&vm_dir) {
+        return crate::bridge::guest_ip(&vm_dir).ok_or_else(|| {
+            Error::Other(format!(
+                "VM {} has no address on {} yet; it gets one from the LAN's DHCP server",
+                name, bridge
+            ))
+        });
+    }
     let subnet_file = vm_dir.join("subnet");
 
     if !subnet_file.exists() {
         return Err(Error::Other("Subnet file not found".to_string()));
     }
 
+    if let Some(ip) = crate::dhcp::lease_ip(&vm_dir) {
+        return Ok(ip);
+    }
     let subnet = fs::read_to_string(subnet_file)?;
     Ok(format!("{}.2", subnet.trim()))
 }
@@ -1056,7 +2445,7 @@ pub fn get_routable_ip(config: &Config, name: &str) -> Result<String> {
     read_display_ip(&vm_dir).map_or_else(|| get_vm_ip(config, name), Ok)
 }
 
-fn get_vm_devices(config: &Config, name: &str) -> Vec<String> {
+pub fn get_vm_devices(config: &Config, name: &str) -> Vec<String> {
     let devices_file = config.vm_dir(name).join("devices");
     if devices_file.exists() {
         if let Ok(content) = fs::read_to_string(devices_file) {
@@ -1070,7 +2459,7 @@ fn get_vm_devices(config: &Config, name: &str) -> Vec<String> {
     Vec::new()
 }
 
-fn get_vm_memory(config: &Config, name: &str) -> Result<String> {
+pub fn get_vm_memory(config: &Config, name: &str) -> Result<String> {
     let vm_dir = config.vm_dir(name);
     let memory_file = vm_dir.join("memory");
 
@@ -1078,33 +2467,10 @@ fn get_vm_memory(config: &Config, name: &str) -> Result<String> {
         return Ok(fs::read_to_string(memory_file)?.trim().to_string());
     }
 
-    // Fallback to extracting from start script for older VMs
-    let start_script = vm_dir.join("start.sh");
-    if !start_script.exists() {
-        return Ok(config.mem.clone());
-    }
-
-    let content = fs::read_to_string(start_script)?;
-
-    // Extract memory from start script
-    for line in content.lines() {
-        if line.contains("--memory size=") {
-            if let Some(start) = line.find("--memory size=") {
-                let after_flag = &line[start + 14..];
-                if let Some(end) = after_flag.find(' ') {
-                    return Ok(after_flag[..end].to_string());
-                } else {
-                    // Handle case where memory is at end of line
-                    return Ok(after_flag.trim().to_string());
-                }
-            }
-        }
-    }
-
-    Ok(config.mem.clone())
+    Ok(LaunchSpec::load(&vm_dir).map_or_else(|| config.mem.clone(), |spec| spec.memory))
 }
 
-fn get_vm_cpus(config: &Config, name: &str) -> Result<String> {
+pub(crate) fn get_vm_cpus(config: &Config, name: &str) -> Result<String> {
     let vm_dir = config.vm_dir(name);
     let cpus_file = vm_dir.join("cpus");
 
@@ -1112,95 +2478,26 @@ fn get_vm_cpus(config: &Config, name: &str) -> Result<String> {
         return Ok(fs::read_to_string(cpus_file)?.trim().to_string());
     }
 
-    // Fallback to extracting from start script for older VMs
-    let start_script = vm_dir.join("start.sh");
-    if !start_script.exists() {
-        return Ok(config.cpus.to_string());
-    }
-
-    let content = fs::read_to_string(start_script)?;
-
-    for line in content.lines() {
-        if line.contains("--cpus boot=") {
-            if let Some(start) = line.find("--cpus boot=") {
-                let after_flag = &line[start + 12..];
-                if let Some(end) = after_flag.find([' ', '\\']) {
-                    return Ok(after_flag[..end].to_string());
-                }
-                return Ok(after_flag.trim().to_string());
-            }
-        }
-    }
-
-    Ok(config.cpus.to_string())
+    Ok(LaunchSpec::load(&vm_dir)
+        .map_or_else(|| config.cpus.to_string(), |spec| spec.cpus.to_string()))
 }
 
-fn get_vm_disk_size(config: &Config, name: &str) -> Result<String> {
+pub fn get_vm_disk_size(config: &Config, name: &str) -> Result<String> {
     let vm_dir = config.vm_dir(name);
-    let rootfs_path = if vm_dir.join("rootfs.qcow2").exists() {
-        vm_dir.join("rootfs.qcow2")
-    } else {
-        vm_dir.join("rootfs.raw")
-    };
-
-    if !rootfs_path.exists() {
+    let Some((rootfs_path, _)) = DiskFormat::detect(&vm_dir) else {
         return Ok(config.disk_size.clone());
-    }
+    };
 
     // Get actual disk size using qemu-img info
-    let output = std::process::Command::new("qemu-img")
-        .args(["info", "-U", "--output=json", rootfs_path.to_str().unwrap()])
-        .output();
-
-    match output {
-        Ok(output) if output.status.success() => {
-            let json_str = String::from_utf8_lossy(&output.stdout);
-            if let Ok(json) = serde_json::from_str::<serde_json::Value>(&json_str) {
-                if let Some(virtual_size) = json.get("virtual-size").and_then(|v| v.as_u64()) {
-                    // Convert bytes to GB
-                    let size_gb = virtual_size / (1024 * 1024 * 1024);
-                    return Ok(format!("{}G", size_gb));
-                }
-            }
-        }
-        _ => {}
+    if let Some(virtual_size) = disk_virtual_size(&rootfs_path) {
+        // Convert bytes to GB
+        let size_gb = virtual_size / (1024 * 1024 * 1024);
+        return Ok(format!("{}G", size_gb));
     }
 
     Ok(config.disk_size.clone())
 }
 
-fn extract_oras_binary(tar_path: &std::path::Path, dest_path: &std::path::Path) -> Result<()> {
-    use std::io::Read;
-
-    let tar_file = fs::File::open(tar_path)?;
-    let tar = flate2::read::GzDecoder::new(tar_file);
-    let mut archive = tar::Archive::new(tar);
-
-    for entry in archive.entries()? {
-        let mut entry = entry?;
-        let path = entry.path()?;
-
-        // Look for the oras binary (it should be just "oras" in the archive)
-        if path.file_name() == Some(std::ffi::OsStr::new("oras")) {
-            let mut buffer = Vec::new();
-            entry.read_to_end(&mut buffer)?;
-
-            fs::write(dest_path, buffer)?;
-
-            // Make executable
-            let mut perms = fs::metadata(dest_path)?.permissions();
-            perms.set_mode(0o755);
-            fs::set_permissions(dest_path, perms)?;
-
-            return Ok(());
-        }
-    }
-
-    Err(Error::Other(
-        "ORAS binary not found in tar archive".to_string(),
-    ))
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1220,6 +2517,28 @@ mod tests {
         (config, temp_dir)
     }
 
+    #[test]
+    fn test_lock_is_exclusive_per_vm() {
+        let (config, _temp_dir) = setup_test_config();
+
+        let held = lock(&config, "busy", "start").unwrap();
+        match lock(&config, "busy", "delete") {
+            Err(Error::VmLocked(name, holder)) => {
+                assert_eq!(name, "busy");
+                assert!(holder.starts_with("meda start (pid "));
+            }
+            other => panic!("expected VmLocked, got {:?}", other),
+        }
+        assert!(lock(&config, "other", "start").is_ok());
+
+        drop(held);
+        let held = lock(&config, "busy", "delete").unwrap();
+        // Lock files stay out of the VM listing, and outlive their VMs.
+        assert!(lock_path(&config, "busy").exists());
+        assert!(vm_names(&config).is_empty());
+        drop(held);
+    }
+
     #[test]
     fn test_check_vm_running_no_pid_file() {
         let (config, _temp_dir) = setup_test_config();
@@ -1309,7 +2628,7 @@ mod tests {
     fn test_get_vm_memory_no_start_script() {
         let (config, _temp_dir) = setup_test_config();
 
-        // Create VM directory without start script
+        // Create VM directory without a launch spec
         let vm_dir = config.vm_dir("test-vm");
         std::fs::create_dir_all(&vm_dir).unwrap();
 
@@ -1318,20 +2637,31 @@ mod tests {
     }
 
     #[test]
-    fn test_get_vm_memory_with_start_script() {
+    fn test_get_vm_memory_with_launch_spec() {
         let (config, _temp_dir) = setup_test_config();
 
-        // Create VM directory with start script containing memory setting
+        // Create VM directory with a launch spec but no memory file
         let vm_dir = config.vm_dir("test-vm");
         std::fs::create_dir_all(&vm_dir).unwrap();
-        let start_script = format!(
-            "#!/bin/bash\n{} --memory size=2048M --cpus boot=4",
-            config.ch_bin.display()
-        );
-        std::fs::write(vm_dir.join("start.sh"), start_script).unwrap();
+        LaunchSpec {
+            ch_bin: config.ch_bin.clone(),
+            privileged: true,
+            netns: None,
+            cpu_affinity: None,
+            setup: String::new(),
+            cpus: 4,
+            memory: "2048M".to_string(),
+            max_cpus: None,
+            max_memory: None,
+            memory_options: Vec::new(),
+            disks: Vec::new(),
+            args: Vec::new(),
+        }
+        .save(&vm_dir)
+        .unwrap();
 
-        let memory = get_vm_memory(&config, "test-vm").unwrap();
-        assert_eq!(memory, "2048M");
+        assert_eq!(get_vm_memory(&config, "test-vm").unwrap(), "2048M");
+        assert_eq!(get_vm_cpus(&config, "test-vm").unwrap(), "4");
     }
 
     #[test]
@@ -1346,15 +2676,68 @@ mod tests {
         assert_eq!(disk_size, config.disk_size);
     }
 
+    #[test]
+    fn test_rewrite_resource_flags() {
+        let script = "ip netns exec ns ch \\\n    --cpus boot=2 \\\n    --memory size=1024M \\\n    --net tap=t\n";
+        let out = rewrite_resource_flags(script, Some(4), Some("4G"));
+        assert!(out.contains("--cpus boot=4 \\\n"));
+        assert!(out.contains("--memory size=4G \\\n"));
+        assert!(out.contains("--net tap=t"));
+
+        let only_mem = rewrite_resource_flags(script, None, Some("2G"));
+        assert!(only_mem.contains("--cpus boot=2 "));
+        assert!(only_mem.contains("--memory size=2G "));
+
+        let backed = "ch \\\n    --memory size=1024M,hugepages=on \\\n";
+        assert!(rewrite_resource_flags(backed, None, Some("2G"))
+            .contains("--memory size=2G,hugepages=on \\\n"));
+    }
+
+    #[tokio::test]
+    async fn test_resize_validation() {
+        let (config, _temp_dir) = setup_test_config();
+
+        let result = resize(&config, "missing", Some("2G"), None, None, true).await;
+        assert!(matches!(result, Err(Error::VmNotFound(_))));
+
+        std::fs::create_dir_all(config.vm_dir("test-vm")).unwrap();
+        assert!(resize(&config, "test-vm", None, None, None, true)
+            .await
+            .is_err());
+        assert!(resize(&config, "test-vm", Some("lots"), None, None, true)
+            .await
+            .is_err());
+        assert!(resize(&config, "test-vm", None, Some(0), None, true)
+            .await
+            .is_err());
+    }
+
     #[tokio::test]
     async fn test_list_empty_vm_dir() {
         let (config, _temp_dir) = setup_test_config();
 
         // Should not error when VM directory doesn't exist
-        let result = list(&config, true).await;
+        let result = list(&config, &[], true).await;
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_collect_vms_skips_staging_and_templates() {
+        let (config, _temp_dir) = setup_test_config();
+        for name in ["web", "db", "__tpl_ubuntu", ".migrate-x"] {
+            fs::create_dir_all(config.vm_dir(name)).unwrap();
+        }
+        let _lock = lock(&config, "web", "start").unwrap();
+
+        assert_eq!(vm_names(&config), ["db", "web"]);
+        let names = |vms: Vec<VmInfo>| vms.into_iter().map(|vm| vm.name).collect::<Vec<_>>();
+        assert_eq!(names(collect_vms(&config).unwrap()), ["db", "web"]);
+        assert_eq!(
+            names(collect_vms_with_templates(&config).unwrap()),
+            ["__tpl_ubuntu", "db", "web"]
+        );
+    }
+
     #[tokio::test]
     async fn test_get_nonexistent_vm() {
         let (config, _temp_dir) = setup_test_config();
@@ -1382,6 +2765,35 @@ mod tests {
         assert!(matches!(result.unwrap_err(), Error::VmNotFound(_)));
     }
 
+    #[tokio::test]
+    async fn test_acpi_shutdown_needs_api_socket() {
+        let (config, temp_dir) = setup_test_config();
+
+        // No api.sock: fall through to signals right away.
+        let started = Instant::now();
+        assert!(!acpi_shutdown(&config, temp_dir.path(), 1, Duration::from_secs(30)).await);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_restart_nonexistent_vm() {
+        let (config, _temp_dir) = setup_test_config();
+
+        let result = restart(&config, "nonexistent-vm", StopOptions::force(), true).await;
+        assert!(matches!(result.unwrap_err(), Error::VmNotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_probe_port() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert_eq!(probe_port("127.0.0.1", port).await, IpStatus::Ready);
+        drop(listener);
+        assert_eq!(probe_port("127.0.0.1", port).await, IpStatus::Unreachable);
+        assert_eq!(probe_port("not-an-ip", 22).await, IpStatus::Unknown);
+        assert_eq!(IpStatus::Unreachable.exit_code(), 2);
+    }
+
     #[tokio::test]
     async fn test_delete_nonexistent_vm() {
         let (config, _temp_dir) = setup_test_config();
@@ -1389,5 +2801,28 @@ mod tests {
         let result = delete(&config, "nonexistent-vm", true).await;
         assert!(result.is_err());
         assert!(matches!(result.unwrap_err(), Error::VmNotFound(_)));
+
+        // Nothing to replace is fine for `create --force`.
+        delete_existing(&config, "nonexistent-vm", true)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_clone_errors() {
+        let (config, _temp_dir) = setup_test_config();
+
+        let result = clone(&config, "missing", "copy", None, false, false, true).await;
+        assert!(matches!(result, Err(Error::VmNotFound(_))));
+
+        fs::create_dir_all(config.vm_dir("src")).unwrap();
+        fs::create_dir_all(config.vm_dir("copy")).unwrap();
+        let result = clone(&config, "src", "copy", None, false, false, true).await;
+        assert!(matches!(result, Err(Error::VmAlreadyExists(_))));
+
+        // No root disk: the partial clone is removed again.
+        let result = clone(&config, "src", "copy2", None, false, false, true).await;
+        assert!(result.is_err());
+        assert!(!config.vm_dir("copy2").exists());
     }
 }
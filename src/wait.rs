@@ -0,0 +1,218 @@
+//! `meda wait`: block until a VM is ready for what comes next.
+//!
+//! Stages build on each other:
+//!
+//! - `ip`: the VM is running and has an address (for DHCP guests, a
+//!   lease);
+//! - `ssh`: that address answers on port 22;
+//! - `cloud-init`: `cloud-init status` in the guest says it is done,
+//!   asked through the guest agent when the VM has one, over SSH
+//!   otherwise.
+//!
+//! Checks are retried with exponential backoff until the timeout. A VM
+//! that stops, or a cloud-init that fails, ends the wait at once.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use backon::{ExponentialBuilder, Retryable};
+use log::{debug, info};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    Ip,
+    Ssh,
+    CloudInit,
+}
+
+impl Stage {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Ip => "ip",
+            Self::Ssh => "ssh",
+            Self::CloudInit => "cloud-init",
+        }
+    }
+}
+
+enum Check {
+    /// Not there yet; try again.
+    NotYet(String),
+    /// Won't get there by waiting.
+    Failed(Error),
+}
+
+#[derive(Debug, Serialize)]
+pub struct WaitResult {
+    pub vm: String,
+    #[serde(rename = "for")]
+    pub stage: Stage,
+    pub ready: bool,
+    pub ip: String,
+    pub elapsed_secs: f64,
+}
+
+/// `cloud-init status` output: `Ok(true)` once it's finished.
+fn cloud_init_done(status: &str) -> std::result::Result<bool, String> {
+    let status = status
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("status:"))
+        .map(str::trim)
+        .unwrap_or_default();
+    match status {
+        "done" | "disabled" => Ok(true),
+        "error" | "degraded error" => Err(format!("cloud-init failed (status: {})", status)),
+        _ => Ok(false),
+    }
+}
+
+async fn check(config: &Config, name: &str, stage: Stage) -> std::result::Result<String, Check> {
+    if !crate::vm::check_vm_running(config, name).map_err(Check::Failed)? {
+        return Err(Check::Failed(Error::Other(format!(
+            "VM {} is not running",
+            name
+        ))));
+    }
+    let ip = crate::vm::display_ip(config, name).map_err(|e| Check::NotYet(e.to_string()))?;
+    if stage == Stage::Ip {
+        return Ok(ip);
+    }
+    let (host, port) = crate::ssh::split_port(&ip);
+    if crate::vm::probe_port(&host, port).await != crate::vm::IpStatus::Ready {
+        return Err(Check::NotYet(format!(
+            "{} does not answer on port {}",
+            host, port
+        )));
+    }
+    if stage == Stage::Ssh {
+        return Ok(ip);
+    }
+
+    let vm_dir = config.vm_dir(name);
+    let request = crate::agent::Request::Exec {
+        command: "cloud-init status".to_string(),
+    };
+    let status = match crate::agent::query(&vm_dir, &request, Duration::from_secs(10)) {
+        Ok(response) => response.stdout,
+        Err(_) => {
+            // `cloud-init status` exits non-zero while it's still running.
+            let output = crate::ssh::guest_command(config, &ip, "cloud-init status")
+                .map_err(|e| Check::NotYet(e.to_string()))?;
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+    };
+    match cloud_init_done(&status) {
+        Ok(true) => Ok(ip),
+        Ok(false) => Err(Check::NotYet(format!(
+            "cloud-init is still running ({})",
+            status.trim()
+        ))),
+        Err(e) => Err(Check::Failed(Error::Other(e))),
+    }
+}
+
+pub async fn wait(
+    config: &Config,
+    name: &str,
+    stage: Stage,
+    timeout: Duration,
+    json: bool,
+) -> Result<()> {
+    let result = until(config, name, stage, timeout).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        info!(
+            "VM {} reached {} after {:.1}s ({})",
+            name,
+            stage.as_str(),
+            result.elapsed_secs,
+            result.ip
+        );
+    }
+    Ok(())
+}
+
+/// Block until VM `name` reaches `stage`, without reporting it.
+pub async fn until(
+    config: &Config,
+    name: &str,
+    stage: Stage,
+    timeout: Duration,
+) -> Result<WaitResult> {
+    if !config.vm_dir(name).exists() {
+        return Err(Error::VmNotFound(name.to_string()));
+    }
+    let started = Instant::now();
+    let last = Mutex::new(String::new());
+    let attempt = || check(config, name, stage);
+    let retried = attempt
+        .retry(
+            ExponentialBuilder::default()
+                .with_min_delay(Duration::from_millis(500))
+                .with_max_delay(Duration::from_secs(5))
+                .without_max_times(),
+        )
+        .when(|e| matches!(e, Check::NotYet(_)))
+        .notify(|e, delay| {
+            if let Check::NotYet(reason) = e {
+                debug!("{} not ready ({}), retrying in {:?}", name, reason, delay);
+                *last.lock().unwrap() = reason.clone();
+            }
+        });
+
+    let ip = match tokio::time::timeout(timeout, retried).await {
+        Ok(Ok(ip)) => ip,
+        Ok(Err(Check::Failed(e))) => return Err(e),
+        Ok(Err(Check::NotYet(reason))) => return Err(Error::Other(reason)),
+        Err(_) => {
+            return Err(Error::Other(format!(
+                "VM {} did not reach {} within {}s: {}",
+                name,
+                stage.as_str(),
+                timeout.as_secs(),
+                last.lock().unwrap()
+            )))
+        }
+    };
+
+    Ok(WaitResult {
+        vm: name.to_string(),
+        stage,
+        ready: true,
+        ip,
+        elapsed_secs: (started.elapsed().as_secs_f64() * 10.0).round() / 10.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cloud_init_done() {
+        assert_eq!(cloud_init_done("status: done\n"), Ok(true));
+        assert_eq!(cloud_init_done("\nstatus: running\n"), Ok(false));
+        assert_eq!(cloud_init_done(""), Ok(false));
+        assert_eq!(cloud_init_done("status: disabled"), Ok(true));
+        assert!(cloud_init_done("status: error\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wait_stopped_vm_fails_fast() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.vm_root = dir.path().to_path_buf();
+        std::fs::create_dir(config.vm_dir("web")).unwrap();
+
+        let started = Instant::now();
+        let err = wait(&config, "web", Stage::Ssh, Duration::from_secs(30), true)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not running"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
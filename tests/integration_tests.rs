@@ -9,8 +9,10 @@ use tempfile::TempDir;
 
 // Helper to set up a clean test environment
 fn setup_test_env() -> TempDir {
-    // Initialize env_logger for tests (only once)
-    let _ = env_logger::try_init();
+    // Initialize logging for tests (only once)
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .try_init();
 
     let temp_dir = TempDir::new().unwrap();
     env::set_var("MEDA_ASSET_DIR", temp_dir.path().join("assets"));